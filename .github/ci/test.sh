@@ -16,6 +16,7 @@ cargo test --manifest-path ./embassy-executor/Cargo.toml
 cargo test --manifest-path ./embassy-futures/Cargo.toml
 cargo test --manifest-path ./embassy-sync/Cargo.toml
 cargo test --manifest-path ./embassy-embedded-hal/Cargo.toml
+cargo test --manifest-path ./embassy-embedded-hal/Cargo.toml --features mock,time
 cargo test --manifest-path ./embassy-hal-internal/Cargo.toml
 cargo test --manifest-path ./embassy-time/Cargo.toml --features mock-driver,embassy-time-queue-utils/generic-queue-8
 cargo test --manifest-path ./embassy-time-driver/Cargo.toml
@@ -23,6 +24,12 @@ cargo test --manifest-path ./embassy-time-driver/Cargo.toml
 cargo test --manifest-path ./embassy-boot/Cargo.toml
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-dalek
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-salty
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features aes
+cargo test --manifest-path ./embassy-boot-cli/Cargo.toml
+cargo test --manifest-path ./embassy-usb-dfu/Cargo.toml --features dfu
+cargo test --manifest-path ./embassy-usb/Cargo.toml --features time
+cargo test --manifest-path ./embassy-usb-host/Cargo.toml
+cargo test --manifest-path ./embassy-usb-usbip/Cargo.toml
 
 cargo test --manifest-path ./embassy-nrf/Cargo.toml --no-default-features --features nrf52840,time-driver-rtc1,gpiote
 
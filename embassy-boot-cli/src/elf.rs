@@ -0,0 +1,142 @@
+//! Conversion of ELF files to flat binaries for the ACTIVE and DFU partitions.
+
+use anyhow::{bail, Context, Result};
+use object::elf::PT_LOAD;
+use object::read::elf::{ElfFile32, ProgramHeader};
+use object::Endianness;
+
+/// Convert the loadable segments of a 32-bit `elf` file to a flat binary, returning its address and
+/// contents.
+///
+/// Segments are placed at their load (physical) address, relative to `base`, or to the lowest
+/// address if `base` is `None`. Gaps between segments are filled with `fill`.
+pub fn to_bin(elf: &[u8], base: Option<u32>, fill: u8) -> Result<(u32, Vec<u8>)> {
+    let file = ElfFile32::<Endianness>::parse(elf).context("not a 32-bit ELF file")?;
+    let endian = file.endian();
+
+    let mut segments = Vec::new();
+    for header in file.elf_program_headers() {
+        if header.p_type(endian) != PT_LOAD || header.p_filesz(endian) == 0 {
+            continue;
+        }
+        let Ok(data) = header.data(endian, elf) else {
+            bail!("segment at {:#010x} is out of the file", header.p_paddr(endian));
+        };
+        segments.push((header.p_paddr(endian), data));
+    }
+    let Some(lowest) = segments.iter().map(|(address, _)| *address).min() else {
+        bail!("no loadable segment");
+    };
+
+    let base = base.unwrap_or(lowest);
+    if lowest < base {
+        bail!("segment at {lowest:#010x} is below the base address {base:#010x}");
+    }
+    let mut bin = Vec::new();
+    for (address, data) in segments {
+        let offset = (address - base) as usize;
+        if bin.len() < offset + data.len() {
+            bin.resize(offset + data.len(), fill);
+        }
+        bin[offset..offset + data.len()].copy_from_slice(data);
+    }
+    Ok((base, bin))
+}
+
+/// Pad `bin` with `fill` to a multiple of `align`, or to `partition_size` if given.
+pub fn pad(bin: &mut Vec<u8>, align: usize, partition_size: Option<usize>, fill: u8) -> Result<()> {
+    if align == 0 {
+        bail!("alignment must not be zero");
+    }
+    let len = match partition_size {
+        Some(size) if bin.len() > size => {
+            bail!(
+                "binary of {} bytes does not fit in a {} byte partition",
+                bin.len(),
+                size
+            )
+        }
+        Some(size) => size,
+        None => bin.len().next_multiple_of(align),
+    };
+    bin.resize(len, fill);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a little-endian ELF32 file with the given `(type, physical address, data)` segments.
+    fn build_elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let phoff = 52;
+        let mut data_offset = phoff + 32 * segments.len();
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend_from_slice(&2u16.to_le_bytes()); // e_type: executable
+        elf.extend_from_slice(&40u16.to_le_bytes()); // e_machine: ARM
+        elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_entry
+        elf.extend_from_slice(&(phoff as u32).to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&52u16.to_le_bytes()); // e_ehsize
+        elf.extend_from_slice(&32u16.to_le_bytes()); // e_phentsize
+        elf.extend_from_slice(&(segments.len() as u16).to_le_bytes()); // e_phnum
+        elf.extend_from_slice(&40u16.to_le_bytes()); // e_shentsize
+        elf.extend_from_slice(&[0; 4]); // e_shnum, e_shstrndx
+
+        for (kind, paddr, data) in segments {
+            for word in [
+                *kind,
+                data_offset as u32,
+                // The virtual address differs, to check that the physical one is used.
+                paddr + 0x1000_0000,
+                *paddr,
+                data.len() as u32,
+                data.len() as u32,
+                5,
+                4,
+            ] {
+                elf.extend_from_slice(&word.to_le_bytes());
+            }
+            data_offset += data.len();
+        }
+        for (_, _, data) in segments {
+            elf.extend_from_slice(data);
+        }
+        elf
+    }
+
+    #[test]
+    fn segments() {
+        let elf = build_elf(&[
+            (PT_LOAD, 0x0800_8010, &[3, 4]),
+            (PT_LOAD, 0x0800_8000, &[1, 2]),
+            // Not loaded.
+            (4, 0x0800_0000, &[9]),
+        ]);
+        let (base, bin) = to_bin(&elf, None, 0xff).unwrap();
+        assert_eq!(base, 0x0800_8000);
+        let mut expected = vec![0xff; 0x12];
+        expected[..2].copy_from_slice(&[1, 2]);
+        expected[0x10..].copy_from_slice(&[3, 4]);
+        assert_eq!(bin, expected);
+
+        let (_, bin) = to_bin(&elf, Some(0x0800_7ff0), 0).unwrap();
+        assert_eq!(bin.len(), 0x22);
+        assert_eq!(&bin[0x10..0x12], &[1, 2]);
+        assert!(to_bin(&elf, Some(0x0800_8004), 0xff).is_err());
+        assert!(to_bin(&[0x7f, b'E', b'L', b'F'], None, 0xff).is_err());
+    }
+
+    #[test]
+    fn padding() {
+        let mut bin = vec![0; 5];
+        pad(&mut bin, 4, None, 0xff).unwrap();
+        assert_eq!(bin, [0, 0, 0, 0, 0, 0xff, 0xff, 0xff]);
+        pad(&mut bin, 4, Some(16), 0xff).unwrap();
+        assert_eq!(bin.len(), 16);
+        assert!(pad(&mut bin, 4, Some(8), 0xff).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Images with an `ImageHeader` and a trailer, as validated by the bootloader's `ImagePolicy`.
+
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::SigningKey;
+use embassy_boot::{ImageHeader, Version, HEADER_LEN, TLV_ED25519, TLV_SHA512, TRAILER_INFO_LEN, TRAILER_MAGIC};
+use sha2::{Digest, Sha512};
+
+use crate::keys;
+
+/// Parse a version written as `major.minor.patch`.
+pub fn parse_version(s: &str) -> Result<Version> {
+    let parts: Vec<&str> = s.split('.').collect();
+    let [major, minor, patch] = parts[..] else {
+        bail!("version {s:?} is not written as major.minor.patch");
+    };
+    Ok(Version::new(
+        major.parse().context("invalid major version")?,
+        minor.parse().context("invalid minor version")?,
+        patch.parse().context("invalid patch version")?,
+    ))
+}
+
+/// Pack `payload` into an image with `header`, whose payload length is set from `payload`.
+///
+/// The trailer holds the SHA-512 hash of the header and payload, and its signature if `key` is
+/// given.
+pub fn pack(header: &ImageHeader, payload: &[u8], key: Option<&SigningKey>) -> Result<Vec<u8>> {
+    let header_size = header.header_size as usize;
+    if header_size < HEADER_LEN {
+        bail!("header size {header_size} is smaller than {HEADER_LEN} bytes");
+    }
+    let Ok(payload_len) = u32::try_from(payload.len()) else {
+        bail!("payload of {} bytes is too large", payload.len());
+    };
+    let mut header = *header;
+    header.payload_len = payload_len;
+
+    let mut image = header.to_bytes().to_vec();
+    image.resize(header_size, 0);
+    image.extend_from_slice(payload);
+
+    let hash: [u8; 64] = Sha512::digest(&image).into();
+    let mut trailer = Vec::new();
+    trailer.extend_from_slice(&TRAILER_MAGIC.to_le_bytes());
+    trailer.extend_from_slice(&[0; 2]);
+    push_tlv(&mut trailer, TLV_SHA512, &hash);
+    if let Some(key) = key {
+        push_tlv(&mut trailer, TLV_ED25519, &keys::sign_hash(key, &hash));
+    }
+    let trailer_len = trailer.len() as u16;
+    trailer[2..TRAILER_INFO_LEN].copy_from_slice(&trailer_len.to_le_bytes());
+
+    image.extend_from_slice(&trailer);
+    Ok(image)
+}
+
+fn push_tlv(trailer: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    trailer.extend_from_slice(&kind.to_le_bytes());
+    trailer.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    trailer.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_boot::{BlockingFirmwareState, BootLoader, BootLoaderConfig, ImagePolicy, State};
+    use embedded_storage::nor_flash::NorFlash;
+
+    use super::*;
+    use crate::ram_flash::RamFlash;
+
+    const PAGE: usize = 4096;
+
+    /// Run the bootloader with `image` in the DFU partition, returning the state it reports.
+    fn boot(image: &[u8], public_key: [u8; 32]) -> State {
+        let mut active = RamFlash::new(2 * PAGE);
+        let mut dfu = RamFlash::new(3 * PAGE);
+        let mut state = RamFlash::new(2 * PAGE);
+        let mut padded = image.to_vec();
+        padded.resize(padded.len().next_multiple_of(4), 0xff);
+        dfu.write(0, &padded).unwrap();
+
+        let mut aligned = [0; 4];
+        BlockingFirmwareState::new(&mut state, &mut aligned)
+            .mark_updated()
+            .unwrap();
+
+        let mut policy = ImagePolicy::new(0x1234);
+        policy.public_key = Some(public_key);
+        let mut page = [0; PAGE];
+        BootLoader::new(BootLoaderConfig {
+            active: &mut active,
+            dfu: &mut dfu,
+            state: &mut state,
+        })
+        .with_image_policy(policy)
+        .prepare_boot(&mut page)
+        .unwrap()
+    }
+
+    #[test]
+    fn version() {
+        assert_eq!(parse_version("1.2.300").unwrap(), Version::new(1, 2, 300));
+        assert!(parse_version("1.2").is_err());
+        assert!(parse_version("1.2.3.4").is_err());
+        assert!(parse_version("256.0.0").is_err());
+    }
+
+    #[test]
+    fn accepted_by_bootloader() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let mut header = ImageHeader::new(Version::new(1, 0, 0), 1, 0x1234, 0);
+        header.header_size = 256;
+
+        let image = pack(&header, &payload, Some(&key)).unwrap();
+        assert_eq!(ImageHeader::parse(&image).unwrap().payload_len, 3000);
+        assert_eq!(&image[256..3256], &payload[..]);
+        assert_eq!(boot(&image, public_key), State::Swap);
+
+        // Unsigned, signed with another key, or built for other hardware.
+        let image = pack(&header, &payload, None).unwrap();
+        assert_eq!(boot(&image, public_key), State::Rejected);
+        let image = pack(&header, &payload, Some(&SigningKey::from_bytes(&[4; 32]))).unwrap();
+        assert_eq!(boot(&image, public_key), State::Rejected);
+        header.hardware_id = 0x4321;
+        let image = pack(&header, &payload, Some(&key)).unwrap();
+        assert_eq!(boot(&image, public_key), State::Rejected);
+
+        header.header_size = 16;
+        assert!(pack(&header, &payload, None).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! ed25519 keys and signatures.
+//!
+//! Firmware is signed the way `FirmwareUpdater::verify_and_mark_updated` verifies it: the signature
+//! is over the SHA-512 hash of the firmware, not over the firmware itself. Keys are stored as raw
+//! 32 byte files, so that the public key can be embedded in the bootloader with `include_bytes!`.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::{Signer, SigningKey};
+use sha2::{Digest, Sha512};
+
+/// Generate a key pair, writing the secret and public keys to `secret` and `public`.
+pub fn keygen(secret: &Path, public: &Path, force: bool) -> Result<SigningKey> {
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    write_new(secret, &key.to_bytes(), force)?;
+    write_new(public, key.verifying_key().as_bytes(), force)?;
+    Ok(key)
+}
+
+/// Read a secret key written by [`keygen`].
+pub fn read_key(path: &Path) -> Result<SigningKey> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read key {}", path.display()))?;
+    let Ok(bytes) = <[u8; 32]>::try_from(bytes.as_slice()) else {
+        bail!("{} is not a 32 byte ed25519 secret key", path.display());
+    };
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Sign the SHA-512 hash of `firmware`.
+pub fn sign(key: &SigningKey, firmware: &[u8]) -> [u8; 64] {
+    sign_hash(key, &Sha512::digest(firmware).into())
+}
+
+/// Sign a SHA-512 hash.
+pub fn sign_hash(key: &SigningKey, hash: &[u8; 64]) -> [u8; 64] {
+    key.sign(hash).to_bytes()
+}
+
+fn write_new(path: &Path, bytes: &[u8], force: bool) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    match force {
+        true => options.create(true).truncate(true),
+        false => options.create_new(true),
+    };
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(bytes))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_boot::{BlockingFirmwareUpdater, FirmwareUpdaterConfig};
+    use embedded_storage::nor_flash::NorFlash;
+
+    use super::*;
+    use crate::ram_flash::RamFlash;
+
+    #[test]
+    fn keygen_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("embassy-boot-keygen-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (secret, public) = (dir.join("key.sec"), dir.join("key.pub"));
+
+        let key = keygen(&secret, &public, true).unwrap();
+        assert!(keygen(&secret, &public, false).is_err());
+        assert_eq!(read_key(&secret).unwrap().to_bytes(), key.to_bytes());
+        assert_eq!(fs::read(&public).unwrap(), key.verifying_key().as_bytes());
+
+        fs::write(&secret, [0; 31]).unwrap();
+        assert!(read_key(&secret).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verified_by_firmware_updater() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let firmware: Vec<u8> = (0..5000).map(|i| (i * 13) as u8).collect();
+        let signature = sign(&key, &firmware);
+
+        let mut dfu = RamFlash::new(8192);
+        let mut state = RamFlash::new(4096);
+        dfu.write(0, &firmware[..4096]).unwrap();
+        dfu.write(4096, &firmware[4096..]).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: &mut dfu,
+                state: &mut state,
+            },
+            &mut aligned,
+        );
+        let public_key = key.verifying_key().to_bytes();
+        assert!(updater
+            .verify_and_mark_updated(&public_key, &signature, firmware.len() as u32 - 1)
+            .is_err());
+        updater
+            .verify_and_mark_updated(&public_key, &signature, firmware.len() as u32)
+            .unwrap();
+    }
+}
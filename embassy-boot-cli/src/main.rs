@@ -0,0 +1,322 @@
+//! Host tool for embassy-boot: generate keys, sign and pack updates, create delta updates, convert
+//! ELF files to binaries and inspect dumps of the state partition.
+#![warn(missing_docs)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+use embassy_boot::{ImageHeader, Version, HEADER_LEN};
+
+mod elf;
+mod image;
+mod keys;
+#[cfg(test)]
+mod ram_flash;
+mod state;
+
+#[derive(Parser)]
+#[command(name = "embassy-boot", version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate an ed25519 key pair, stored as raw 32 byte files.
+    Keygen {
+        /// File to write the secret key to.
+        secret: PathBuf,
+        /// File to write the public key to, the secret key file with `.pub` appended by default.
+        #[arg(long)]
+        public: Option<PathBuf>,
+        /// Overwrite existing key files.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Sign firmware for `FirmwareUpdater::verify_and_mark_updated`.
+    ///
+    /// The signature is appended to the firmware, as expected by embassy-usb-dfu.
+    Sign {
+        /// Secret key generated by `keygen`.
+        #[arg(long)]
+        key: PathBuf,
+        /// Firmware binary to sign.
+        firmware: PathBuf,
+        /// Output file.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Only write the 64 byte signature.
+        #[arg(long)]
+        detached: bool,
+    },
+    /// Pack a binary into an image with a header and a trailer, for `BootLoader::with_image_policy`.
+    Pack {
+        /// Application binary, linked at the start of the ACTIVE partition plus the header size.
+        payload: PathBuf,
+        /// Output file.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Hardware ID the image is built for.
+        #[arg(long, value_parser = parse_int::<u32>)]
+        hardware_id: u32,
+        /// Version of the image, as `major.minor.patch`.
+        #[arg(long = "version", value_parser = parse_version)]
+        image_version: Version,
+        /// Anti-rollback security counter of the image.
+        #[arg(long, value_parser = parse_int::<u32>, default_value_t = 0)]
+        security_counter: u32,
+        /// Size of the header, which the payload follows.
+        #[arg(long, value_parser = parse_int::<u16>, default_value_t = HEADER_LEN as u16)]
+        header_size: u16,
+        /// Secret key to sign the image with.
+        #[arg(long)]
+        key: Option<PathBuf>,
+        /// Size of the ACTIVE partition, to check that the image fits.
+        #[arg(long, value_parser = parse_int::<usize>)]
+        partition_size: Option<usize>,
+    },
+    /// Create a patch turning the active image into an update, for `FirmwareUpdater::write_patch`.
+    Diff {
+        /// Image in the ACTIVE partition the patch is applied to.
+        source: PathBuf,
+        /// Update the patch reconstructs.
+        target: PathBuf,
+        /// Output file.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Apply a patch created by `diff`, checking both images against the hashes in the patch.
+    Patch {
+        /// Image the patch was created for.
+        source: PathBuf,
+        /// Patch to apply.
+        patch: PathBuf,
+        /// Output file.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Convert an ELF file to a flat binary.
+    Elf2bin {
+        /// ELF file to convert.
+        elf: PathBuf,
+        /// Output file.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Address of the start of the binary, the lowest loaded address by default.
+        #[arg(long, value_parser = parse_int::<u32>)]
+        base: Option<u32>,
+        /// Pad the binary to the size of the partition.
+        #[arg(long, value_parser = parse_int::<usize>)]
+        partition_size: Option<usize>,
+        /// Pad the binary to a multiple of this size, such as the write size of the flash.
+        #[arg(long, value_parser = parse_int::<usize>, default_value_t = 1)]
+        align: usize,
+        /// Value to pad with, the value of erased flash.
+        #[arg(long, value_parser = parse_int::<u8>, default_value_t = 0xff)]
+        fill: u8,
+    },
+    /// Decode a dump of the state partition.
+    State {
+        /// Dump of the state partition, or of a whole flash with `--offset` and `--size`.
+        dump: PathBuf,
+        /// Offset of the state partition in the dump.
+        #[arg(long, value_parser = parse_int::<usize>, default_value_t = 0)]
+        offset: usize,
+        /// Size of the state partition, the rest of the dump by default.
+        #[arg(long, value_parser = parse_int::<usize>)]
+        size: Option<usize>,
+        /// Write size of the state partition.
+        #[arg(long, value_parser = parse_int::<usize>)]
+        write_size: usize,
+        /// Read size of the state partition.
+        #[arg(long, value_parser = parse_int::<usize>, default_value_t = 1)]
+        read_size: usize,
+        /// Page size used by the bootloader to swap images, the size of its aligned buffer.
+        #[arg(long, value_parser = parse_int::<usize>)]
+        page_size: usize,
+        /// Size of the ACTIVE partition. With several images, once for all images or once for each.
+        #[arg(long, value_parser = parse_int::<usize>, required = true)]
+        active_size: Vec<usize>,
+        /// Number of trial boots given to updates with `BootLoader::with_trial_boots`.
+        #[arg(long, default_value_t = 1)]
+        trial_boots: u8,
+        /// The bootloader has an image policy, set with `BootLoader::with_image_policy`, and keeps the
+        /// anti-rollback counter in the last erase page of the state partition.
+        #[arg(long)]
+        image_policy: bool,
+        /// Number of images of a `MultiBootLoader`.
+        #[arg(long)]
+        images: Option<usize>,
+        /// Erase size of the state partition.
+        #[arg(long, value_parser = parse_int::<usize>)]
+        erase_size: usize,
+        /// Value of erased flash, 0x00 for bootloaders built with the `flash-erase-zero` feature.
+        #[arg(long, value_parser = parse_int::<u8>, default_value_t = 0xff)]
+        erase_value: u8,
+    },
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Keygen { secret, public, force } => {
+            let public = public.unwrap_or_else(|| {
+                let mut public = secret.clone().into_os_string();
+                public.push(".pub");
+                public.into()
+            });
+            let key = keys::keygen(&secret, &public, force)?;
+            println!("public key: {}", hex(key.verifying_key().as_bytes()));
+        }
+        Command::Sign {
+            key,
+            firmware,
+            output,
+            detached,
+        } => {
+            let key = keys::read_key(&key)?;
+            let mut firmware = read(&firmware)?;
+            let signature = keys::sign(&key, &firmware);
+            match detached {
+                true => write(&output, &signature)?,
+                false => {
+                    firmware.extend_from_slice(&signature);
+                    write(&output, &firmware)?;
+                }
+            }
+        }
+        Command::Pack {
+            payload,
+            output,
+            hardware_id,
+            image_version,
+            security_counter,
+            header_size,
+            key,
+            partition_size,
+        } => {
+            let key = key.map(|key| keys::read_key(&key)).transpose()?;
+            let mut header = ImageHeader::new(image_version, security_counter, hardware_id, 0);
+            header.header_size = header_size;
+            let image = image::pack(&header, &read(&payload)?, key.as_ref())?;
+            if let Some(size) = partition_size {
+                if image.len() > size {
+                    bail!(
+                        "image of {} bytes does not fit in a {} byte partition",
+                        image.len(),
+                        size
+                    );
+                }
+            }
+            write(&output, &image)?;
+        }
+        Command::Diff { source, target, output } => {
+            let target = read(&target)?;
+            let patch = embassy_boot::generate_patch(&read(&source)?, &target);
+            write(&output, &patch)?;
+            println!("{} byte patch for a {} byte update", patch.len(), target.len());
+        }
+        Command::Patch { source, patch, output } => {
+            let target = embassy_boot::apply_patch(&read(&source)?, &read(&patch)?)
+                .map_err(|e| anyhow!("failed to apply patch: {e:?}"))?;
+            write(&output, &target)?;
+        }
+        Command::Elf2bin {
+            elf,
+            output,
+            base,
+            partition_size,
+            align,
+            fill,
+        } => {
+            let (base, mut bin) = elf::to_bin(&read(&elf)?, base, fill)?;
+            elf::pad(&mut bin, align, partition_size, fill)?;
+            write(&output, &bin)?;
+            println!("{} bytes at {:#010x}", bin.len(), base);
+        }
+        Command::State {
+            dump,
+            offset,
+            size,
+            write_size,
+            read_size,
+            page_size,
+            active_size,
+            trial_boots,
+            image_policy,
+            images,
+            erase_size,
+            erase_value,
+        } => {
+            let dump = read(&dump)?;
+            let end = size.map_or(dump.len(), |size| offset + size);
+            let Some(state) = dump.get(offset..end) else {
+                bail!("the state partition is out of the dump of {} bytes", dump.len());
+            };
+            let layout = state::Layout {
+                write_size,
+                read_size,
+                erase_size,
+                page_size,
+                active_size: active_size[0],
+                trial_boots,
+                image_policy,
+                erase_value,
+            };
+            match images {
+                Some(images) => {
+                    let active_sizes = match active_size.len() {
+                        1 => vec![active_size[0]; images],
+                        n if n == images => active_size,
+                        n => bail!("{n} active sizes given for {images} images"),
+                    };
+                    print!("{}", state::decode_set(&layout, &active_sizes, state)?);
+                }
+                _ => print!("{}", state::decode(&layout, state)?),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_int<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    }
+    .map_err(|e| e.to_string())?;
+    T::try_from(value).map_err(|_| format!("{s} is out of range"))
+}
+
+fn parse_version(s: &str) -> Result<Version, String> {
+    image::parse_version(s).map_err(|e| e.to_string())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+fn write(path: &Path, bytes: &[u8]) -> Result<()> {
+    fs::write(path, bytes).with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn cli() {
+        Cli::command().debug_assert();
+        assert_eq!(parse_int::<u32>("0x1000_0000"), Ok(0x1000_0000));
+        assert_eq!(parse_int::<u8>("255"), Ok(255));
+        assert!(parse_int::<u8>("256").is_err());
+    }
+}
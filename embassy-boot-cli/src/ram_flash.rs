@@ -0,0 +1,73 @@
+//! Flash in RAM, to run the bootloader against the files written by the tool.
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+pub struct RamFlash {
+    pub mem: Vec<u8>,
+    erases_left: usize,
+}
+
+#[derive(Debug)]
+pub struct RamFlashError;
+
+impl NorFlashError for RamFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+impl RamFlash {
+    pub fn new(size: usize) -> Self {
+        Self {
+            mem: vec![0xff; size],
+            erases_left: usize::MAX,
+        }
+    }
+
+    /// Fail every erase after the next `erases`.
+    pub fn fail_after(&mut self, erases: usize) {
+        self.erases_left = erases;
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = RamFlashError;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.mem[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.mem.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if self.erases_left == 0 {
+            return Err(RamFlashError);
+        }
+        self.erases_left = self.erases_left.saturating_sub(1);
+        assert!(from as usize % Self::ERASE_SIZE == 0 && to as usize % Self::ERASE_SIZE == 0);
+        self.mem[from as usize..to as usize].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        assert!(offset % Self::WRITE_SIZE == 0 && bytes.len() % Self::WRITE_SIZE == 0);
+        for (mem, byte) in self.mem[offset..offset + bytes.len()].iter_mut().zip(bytes) {
+            *mem &= *byte;
+        }
+        Ok(())
+    }
+}
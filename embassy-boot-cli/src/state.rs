@@ -0,0 +1,525 @@
+//! Decoding of a dumped state partition.
+//!
+//! This mirrors the layout written by `BootLoader` and `MultiBootLoader`, see their documentation for
+//! the meaning of each word.
+
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use embassy_boot::{State, IV_LEN};
+
+/// Geometry of the partitions, as configured in the bootloader.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    /// Write size of the state partition.
+    pub write_size: usize,
+    /// Read size of the state partition.
+    pub read_size: usize,
+    /// Erase size of the state partition.
+    pub erase_size: usize,
+    /// Page size used by the bootloader to swap images.
+    pub page_size: usize,
+    /// Size of the ACTIVE partition.
+    pub active_size: usize,
+    /// Number of trial boots given to updates, 1 without trial boots.
+    pub trial_boots: u8,
+    /// Whether the bootloader has an image policy, keeping the anti-rollback counter in the last
+    /// erase page of the state partition.
+    pub image_policy: bool,
+    /// Value of erased flash.
+    pub erase_value: u8,
+}
+
+impl Layout {
+    fn word_size(&self) -> usize {
+        self.write_size.max(self.read_size)
+    }
+
+    fn counter_size(&self) -> usize {
+        self.word_size().max(8)
+    }
+
+    /// End of the part of the state erased when the state changes, preceding the counter page with
+    /// an image policy.
+    fn state_end(&self, capacity: usize) -> usize {
+        match self.image_policy && capacity > self.erase_size {
+            true => capacity - self.erase_size,
+            false => capacity,
+        }
+    }
+
+    fn iv_size(&self) -> usize {
+        IV_LEN.div_ceil(self.word_size()) * self.word_size()
+    }
+
+    fn counter_backup_offset(&self, capacity: usize) -> usize {
+        self.state_end(capacity) - self.counter_size()
+    }
+
+    fn iv_offset(&self, capacity: usize) -> usize {
+        self.counter_backup_offset(capacity) - self.iv_size()
+    }
+
+    fn attempt_offset(&self, capacity: usize, index: usize) -> usize {
+        self.iv_offset(capacity) - (index + 1) * self.word_size()
+    }
+
+    fn is_erased(&self, bytes: &[u8]) -> bool {
+        bytes.iter().all(|&b| b == self.erase_value)
+    }
+
+    /// A progress or marker word is set once none of its bytes is erased.
+    fn is_set(&self, word: &[u8]) -> bool {
+        !word.contains(&self.erase_value)
+    }
+
+    fn check(&self, state: &[u8]) -> Result<()> {
+        if self.write_size == 0 || self.read_size == 0 || self.erase_size == 0 || self.page_size == 0 {
+            bail!("write, read, erase and page sizes must not be zero");
+        }
+        if self.active_size == 0 || self.active_size % self.page_size != 0 {
+            bail!(
+                "active partition size {} is not a multiple of the page size {}",
+                self.active_size,
+                self.page_size
+            );
+        }
+        let pages = self.active_size / self.page_size;
+        let needed = (2 + 4 * pages) * self.write_size + self.counter_size() + self.iv_size();
+        if self.state_end(state.len()) < needed {
+            bail!(
+                "state partition of {} bytes is too small for {} pages, at least {} bytes are needed",
+                state.len(),
+                pages,
+                needed
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Progress of copying pages between the ACTIVE and DFU partitions.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Progress {
+    /// The progress was invalidated while the state partition was being reset.
+    Invalid,
+    /// No page was copied.
+    NotStarted,
+    /// `done` of `total` page copies of the swap are done.
+    Swapping { done: usize, total: usize },
+    /// The update is swapped in.
+    Swapped,
+    /// `done` of `total` page copies of the revert are done.
+    Reverting { done: usize, total: usize },
+    /// The update is swapped out again.
+    Reverted,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Progress::Invalid => write!(f, "invalidated, the state was being reset"),
+            Progress::NotStarted => write!(f, "not started"),
+            Progress::Swapping { done, total } => write!(f, "swapping, {done} of {total} page copies done"),
+            Progress::Swapped => write!(f, "swapped"),
+            Progress::Reverting { done, total } => write!(f, "reverting, {done} of {total} page copies done"),
+            Progress::Reverted => write!(f, "reverted"),
+        }
+    }
+}
+
+/// Trial boots recorded for a swapped update.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TrialBoots {
+    /// Number of trial boots recorded.
+    pub recorded: usize,
+    /// Number of trial boots left.
+    pub remaining: u8,
+}
+
+/// Contents of the state partition of a `BootLoader`.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Report {
+    /// State set by the application or the bootloader.
+    pub state: State,
+    /// Swap progress.
+    pub progress: Progress,
+    /// Anti-rollback counter, if one was stored.
+    pub counter: Option<u32>,
+    /// Initialization vector of an encrypted update, if one was stored.
+    pub iv: Option<[u8; IV_LEN]>,
+    /// Trial boots, if updates are given more than one.
+    pub trial_boots: Option<TrialBoots>,
+}
+
+/// Decode the state partition of a `BootLoader`.
+pub fn decode(layout: &Layout, state: &[u8]) -> Result<Report> {
+    layout.check(state)?;
+    let write_size = layout.write_size;
+    let word = |index: usize| &state[index * write_size..(index + 1) * write_size];
+
+    // The same bounds as the bootloader, which stops looking for progress at `max_index`.
+    let max_index = (layout.state_end(state.len()) - write_size) / write_size - 2;
+    let index = match layout.is_erased(word(1)) {
+        true => (0..max_index)
+            .find(|&i| !layout.is_set(word(2 + i)))
+            .unwrap_or(max_index),
+        false => max_index,
+    };
+    let total = 2 * (layout.active_size / layout.page_size);
+    let progress = if !layout.is_erased(word(1)) {
+        Progress::Invalid
+    } else if index == 0 {
+        Progress::NotStarted
+    } else if index < total {
+        Progress::Swapping { done: index, total }
+    } else if index == total {
+        Progress::Swapped
+    } else if index < 2 * total {
+        Progress::Reverting {
+            done: index - total,
+            total,
+        }
+    } else {
+        Progress::Reverted
+    };
+
+    // The highest copy of the counter, in the counter page or kept while it is erased, only kept
+    // with an image policy.
+    let counter_at = |offset: usize| {
+        let counter = u32::from_le_bytes(state[offset..offset + 4].try_into().unwrap());
+        let check = u32::from_le_bytes(state[offset + 4..offset + 8].try_into().unwrap());
+        (counter == !check).then_some(counter)
+    };
+    let counter_page = layout.state_end(state.len())..state.len();
+    let counter = counter_page
+        .step_by(layout.counter_size())
+        .take_while(|&offset| offset + layout.counter_size() <= state.len())
+        .take_while(|&offset| !layout.is_erased(&state[offset..offset + layout.counter_size()]))
+        .map(counter_at)
+        .chain(
+            layout
+                .image_policy
+                .then(|| counter_at(layout.counter_backup_offset(state.len()))),
+        )
+        .max()
+        .flatten();
+
+    let iv = &state[layout.iv_offset(state.len())..][..IV_LEN];
+    let iv = match layout.is_erased(iv) {
+        true => None,
+        false => Some(iv.try_into().unwrap()),
+    };
+
+    let trial_boots = (layout.trial_boots > 1).then(|| {
+        // The first trial boot is counted even if it was not recorded.
+        let mut boots = TrialBoots {
+            recorded: 0,
+            remaining: layout.trial_boots - 1,
+        };
+        while boots.recorded < layout.trial_boots as usize {
+            let offset = layout.attempt_offset(state.len(), boots.recorded);
+            let word = &state[offset..offset + layout.word_size()];
+            if layout.is_erased(word) {
+                break;
+            }
+            boots.remaining = word[0] ^ !layout.erase_value;
+            boots.recorded += 1;
+        }
+        boots
+    });
+
+    Ok(Report {
+        state: State::from(word(0)),
+        progress,
+        counter,
+        iv,
+        trial_boots,
+    })
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "state:                 {:?}", self.state)?;
+        writeln!(f, "progress:              {}", self.progress)?;
+        match self.counter {
+            Some(counter) => writeln!(f, "anti-rollback counter: {counter}")?,
+            None => writeln!(f, "anti-rollback counter: none")?,
+        }
+        match &self.iv {
+            Some(iv) => writeln!(f, "encryption IV:         {}", hex(iv))?,
+            None => writeln!(f, "encryption IV:         none")?,
+        }
+        if let Some(boots) = &self.trial_boots {
+            writeln!(
+                f,
+                "trial boots:           {} recorded, {} left",
+                boots.recorded, boots.remaining
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Contents of the state partition of a `MultiBootLoader`.
+#[derive(PartialEq, Eq, Debug)]
+pub struct SetReport {
+    /// State of the update set, set by the application or the bootloader.
+    pub state: State,
+    /// The application was interrupted while marking the update set as booted.
+    pub confirming: bool,
+    /// All images were validated and their progress reset.
+    pub started: bool,
+    /// Images that were swapped.
+    pub swapped: Vec<bool>,
+    /// Images that were reverted.
+    pub reverted: Vec<bool>,
+    /// State of each image.
+    pub images: Vec<Report>,
+}
+
+/// Decode the state partition of a `MultiBootLoader`, with an image for each of `active_sizes`.
+///
+/// The active size of `layout` is ignored.
+pub fn decode_set(layout: &Layout, active_sizes: &[usize], state: &[u8]) -> Result<SetReport> {
+    let images = active_sizes.len();
+    if layout.erase_size == 0 {
+        bail!("erase size must not be zero");
+    }
+    let region_size = state.len() / (images + 1) / layout.erase_size * layout.erase_size;
+    if region_size < (3 + 2 * images) * layout.write_size {
+        bail!(
+            "state partition of {} bytes is too small for {} images",
+            state.len(),
+            images
+        );
+    }
+    let set = &state[..region_size];
+    let word = |index: usize| &set[index * layout.write_size..(index + 1) * layout.write_size];
+
+    let images = active_sizes
+        .iter()
+        .enumerate()
+        .map(|(index, &active_size)| {
+            let layout = Layout {
+                active_size,
+                trial_boots: 1,
+                ..layout.clone()
+            };
+            let region = (index + 1) * region_size;
+            decode(&layout, &state[region..region + region_size]).with_context(|| format!("image {index}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let n = images.len();
+
+    Ok(SetReport {
+        state: State::from(word(0)),
+        confirming: !layout.is_erased(word(1)),
+        started: layout.is_set(word(2)),
+        swapped: (0..n).map(|i| layout.is_set(word(3 + i))).collect(),
+        reverted: (0..n).map(|i| layout.is_set(word(3 + n + i))).collect(),
+        images,
+    })
+}
+
+impl fmt::Display for SetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "update set state:      {:?}", self.state)?;
+        if self.confirming {
+            writeln!(
+                f,
+                "                       interrupted while marking the update set booted"
+            )?;
+        }
+        writeln!(f, "validated:             {}", yes_no(self.started))?;
+        for (index, image) in self.images.iter().enumerate() {
+            writeln!(f)?;
+            writeln!(f, "image {index}")?;
+            writeln!(f, "swapped:               {}", yes_no(self.swapped[index]))?;
+            writeln!(f, "reverted:              {}", yes_no(self.reverted[index]))?;
+            write!(f, "{image}")?;
+        }
+        Ok(())
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    match value {
+        true => "yes",
+        false => "no",
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_boot::{
+        BlockingFirmwareState, BootLoader, BootLoaderConfig, ImageSlot, MultiBootLoader, MultiBootLoaderConfig,
+    };
+    use embedded_storage::nor_flash::NorFlash;
+
+    use super::*;
+    use crate::ram_flash::RamFlash;
+
+    const PAGE: usize = 4096;
+
+    fn layout(trial_boots: u8) -> Layout {
+        Layout {
+            write_size: 4,
+            read_size: 1,
+            erase_size: PAGE,
+            page_size: PAGE,
+            active_size: 2 * PAGE,
+            trial_boots,
+            image_policy: false,
+            erase_value: 0xff,
+        }
+    }
+
+    fn mark_updated(state: &mut RamFlash) {
+        let mut aligned = [0; 4];
+        BlockingFirmwareState::new(state, &mut aligned).mark_updated().unwrap();
+    }
+
+    fn mark_booted(state: &mut RamFlash) {
+        let mut aligned = [0; 4];
+        BlockingFirmwareState::new(state, &mut aligned).mark_booted().unwrap();
+    }
+
+    fn prepare(active: &mut RamFlash, dfu: &mut RamFlash, state: &mut RamFlash, trial_boots: u8) -> State {
+        let mut page = [0; PAGE];
+        BootLoader::new(BootLoaderConfig { active, dfu, state })
+            .with_trial_boots(trial_boots)
+            .prepare_boot(&mut page)
+            .unwrap()
+    }
+
+    #[test]
+    fn decode_single() {
+        let mut active = RamFlash::new(2 * PAGE);
+        let mut dfu = RamFlash::new(3 * PAGE);
+        let mut state = RamFlash::new(2 * PAGE);
+
+        let report = decode(&layout(1), &state.mem).unwrap();
+        assert_eq!(report.state, State::Boot);
+        assert_eq!(report.progress, Progress::NotStarted);
+        assert_eq!(report.counter, None);
+        assert_eq!(report.iv, None);
+        assert_eq!(report.trial_boots, None);
+
+        mark_updated(&mut state);
+        assert_eq!(decode(&layout(1), &state.mem).unwrap().state, State::Swap);
+
+        // Stop the swap before its last page copy, the second one into the active partition.
+        active.fail_after(1);
+        let mut page = [0; PAGE];
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: &mut active,
+            dfu: &mut dfu,
+            state: &mut state,
+        });
+        assert!(bootloader.prepare_boot(&mut page).is_err());
+        active.fail_after(usize::MAX);
+        let report = decode(&layout(1), &state.mem).unwrap();
+        assert_eq!(report.state, State::Swap);
+        assert_eq!(report.progress, Progress::Swapping { done: 3, total: 4 });
+
+        assert_eq!(prepare(&mut active, &mut dfu, &mut state, 3), State::Swap);
+        let report = decode(&layout(3), &state.mem).unwrap();
+        assert_eq!(report.progress, Progress::Swapped);
+        assert_eq!(
+            report.trial_boots,
+            Some(TrialBoots {
+                recorded: 1,
+                remaining: 2
+            })
+        );
+
+        assert_eq!(prepare(&mut active, &mut dfu, &mut state, 3), State::Swap);
+        let report = decode(&layout(3), &state.mem).unwrap();
+        assert_eq!(
+            report.trial_boots,
+            Some(TrialBoots {
+                recorded: 2,
+                remaining: 1
+            })
+        );
+
+        prepare(&mut active, &mut dfu, &mut state, 3);
+        prepare(&mut active, &mut dfu, &mut state, 3);
+        let report = decode(&layout(3), &state.mem).unwrap();
+        assert_eq!(report.state, State::Revert);
+        assert_eq!(report.progress, Progress::NotStarted);
+
+        mark_booted(&mut state);
+        let report = decode(&layout(1), &state.mem).unwrap();
+        assert_eq!(report.state, State::Boot);
+    }
+
+    #[test]
+    fn decode_progress_and_counter() {
+        let layout = Layout {
+            image_policy: true,
+            ..layout(1)
+        };
+        let mut state = vec![0xff; 2 * PAGE];
+        state[..4].fill(0xf0);
+        state[8..8 + 6 * 4].fill(0);
+        let counter = |counter: u32| [counter.to_le_bytes(), (!counter).to_le_bytes()].concat();
+        state[PAGE..PAGE + 8].copy_from_slice(&counter(6));
+        state[PAGE + 8..PAGE + 16].copy_from_slice(&counter(7));
+        let report = decode(&layout, &state).unwrap();
+        assert_eq!(report.progress, Progress::Reverting { done: 2, total: 4 });
+        assert_eq!(report.counter, Some(7));
+
+        // A copy kept while the counter page is erased.
+        state[PAGE..].fill(0xff);
+        state[PAGE - 8..PAGE].copy_from_slice(&counter(8));
+        assert_eq!(decode(&layout, &state).unwrap().counter, Some(8));
+
+        state[4..8].fill(0);
+        assert_eq!(decode(&layout, &state).unwrap().progress, Progress::Invalid);
+
+        assert!(decode(&layout, &state[..32]).is_err());
+    }
+
+    #[test]
+    fn decode_multi() {
+        let mut images: [ImageSlot<RamFlash, RamFlash>; 2] = core::array::from_fn(|_| ImageSlot {
+            active: RamFlash::new(2 * PAGE),
+            dfu: RamFlash::new(3 * PAGE),
+        });
+        let mut state = RamFlash::new(3 * PAGE);
+        for image in images.iter_mut() {
+            image.dfu.erase(0, PAGE as u32).unwrap();
+            image.dfu.write(0, &[0x55; PAGE]).unwrap();
+        }
+
+        let report = decode_set(&layout(1), &[2 * PAGE; 2], &state.mem).unwrap();
+        assert_eq!(report.state, State::Boot);
+        assert!(!report.started);
+
+        mark_updated(&mut state);
+        let mut page = [0; PAGE];
+        let mut bootloader = MultiBootLoader::new(MultiBootLoaderConfig {
+            images,
+            state: &mut state,
+        });
+        assert_eq!(bootloader.prepare_boot(&mut page).unwrap(), State::Swap);
+
+        let report = decode_set(&layout(1), &[2 * PAGE; 2], &state.mem).unwrap();
+        assert_eq!(report.state, State::Swap);
+        assert!(!report.confirming);
+        assert!(report.started);
+        assert_eq!(report.swapped, [true, true]);
+        assert_eq!(report.reverted, [false, false]);
+        assert_eq!(report.images.len(), 2);
+        for image in &report.images {
+            assert_eq!(image.state, State::Swap);
+            assert_eq!(image.progress, Progress::Swapped);
+        }
+    }
+}
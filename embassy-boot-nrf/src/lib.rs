@@ -5,7 +5,7 @@ mod fmt;
 
 pub use embassy_boot::{
     AlignedBuffer, BlockingFirmwareState, BlockingFirmwareUpdater, BootError, BootLoaderConfig, FirmwareState,
-    FirmwareUpdater, FirmwareUpdaterConfig,
+    FirmwareUpdater, FirmwareUpdaterConfig, ImageSlot, MultiBootLoaderConfig,
 };
 use embassy_nrf::nvmc::PAGE_SIZE;
 use embassy_nrf::{wdt, Peri};
@@ -38,6 +38,31 @@ impl<const BUFFER_SIZE: usize> BootLoader<BUFFER_SIZE> {
         Ok(Self)
     }
 
+    /// Inspect the bootloader state and perform actions required before booting, such as swapping the firmware
+    /// of every image of an update set
+    pub fn prepare_multi<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize>(
+        config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>,
+    ) -> Self {
+        if let Ok(loader) = Self::try_prepare_multi::<ACTIVE, DFU, STATE, N>(config) {
+            loader
+        } else {
+            // Use explicit panic instead of .expect() to ensure this gets routed via defmt/etc.
+            // properly
+            panic!("Boot prepare error")
+        }
+    }
+
+    /// Inspect the bootloader state and perform actions required before booting, such as swapping the firmware
+    /// of every image of an update set
+    pub fn try_prepare_multi<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize>(
+        config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>,
+    ) -> Result<Self, BootError> {
+        let mut aligned_buf = AlignedBuffer([0; BUFFER_SIZE]);
+        let mut boot = embassy_boot::MultiBootLoader::new(config);
+        let _state = boot.prepare_boot(aligned_buf.as_mut())?;
+        Ok(Self)
+    }
+
     /// Boots the application without softdevice mechanisms.
     ///
     /// # Safety
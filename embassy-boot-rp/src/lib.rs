@@ -5,7 +5,7 @@ mod fmt;
 
 pub use embassy_boot::{
     AlignedBuffer, BlockingFirmwareState, BlockingFirmwareUpdater, BootError, BootLoaderConfig, FirmwareState,
-    FirmwareUpdater, FirmwareUpdaterConfig, State,
+    FirmwareUpdater, FirmwareUpdaterConfig, ImageSlot, MultiBootLoaderConfig, State,
 };
 use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
 use embassy_rp::peripherals::{FLASH, WATCHDOG};
@@ -44,6 +44,31 @@ impl<const BUFFER_SIZE: usize> BootLoader<BUFFER_SIZE> {
         Ok(Self { state })
     }
 
+    /// Inspect the bootloader state and perform actions required before booting, such as swapping the firmware
+    /// of every image of an update set
+    pub fn prepare_multi<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize>(
+        config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>,
+    ) -> Self {
+        if let Ok(loader) = Self::try_prepare_multi::<ACTIVE, DFU, STATE, N>(config) {
+            loader
+        } else {
+            // Use explicit panic instead of .expect() to ensure this gets routed via defmt/etc.
+            // properly
+            panic!("Boot prepare error")
+        }
+    }
+
+    /// Inspect the bootloader state and perform actions required before booting, such as swapping the firmware
+    /// of every image of an update set
+    pub fn try_prepare_multi<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize>(
+        config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>,
+    ) -> Result<Self, BootError> {
+        let mut aligned_buf = AlignedBuffer([0; BUFFER_SIZE]);
+        let mut boot = embassy_boot::MultiBootLoader::new(config);
+        let state = boot.prepare_boot(aligned_buf.as_mut())?;
+        Ok(Self { state })
+    }
+
     /// Boots the application.
     ///
     /// # Safety
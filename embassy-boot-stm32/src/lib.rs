@@ -5,7 +5,7 @@ mod fmt;
 
 pub use embassy_boot::{
     AlignedBuffer, BlockingFirmwareState, BlockingFirmwareUpdater, BootError, BootLoaderConfig, FirmwareState,
-    FirmwareUpdater, FirmwareUpdaterConfig, State,
+    FirmwareUpdater, FirmwareUpdaterConfig, ImageSlot, MultiBootLoaderConfig, State,
 };
 use embedded_storage::nor_flash::NorFlash;
 
@@ -39,6 +39,37 @@ impl BootLoader {
         Ok(Self { state })
     }
 
+    /// Inspect the bootloader state and perform actions required before booting, such as swapping the firmware
+    /// of every image of an update set
+    pub fn prepare_multi<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize, const BUFFER_SIZE: usize>(
+        config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>,
+    ) -> Self {
+        if let Ok(loader) = Self::try_prepare_multi::<ACTIVE, DFU, STATE, N, BUFFER_SIZE>(config) {
+            loader
+        } else {
+            // Use explicit panic instead of .expect() to ensure this gets routed via defmt/etc.
+            // properly
+            panic!("Boot prepare error")
+        }
+    }
+
+    /// Inspect the bootloader state and perform actions required before booting, such as swapping the firmware
+    /// of every image of an update set
+    pub fn try_prepare_multi<
+        ACTIVE: NorFlash,
+        DFU: NorFlash,
+        STATE: NorFlash,
+        const N: usize,
+        const BUFFER_SIZE: usize,
+    >(
+        config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>,
+    ) -> Result<Self, BootError> {
+        let mut aligned_buf = AlignedBuffer([0; BUFFER_SIZE]);
+        let mut boot = embassy_boot::MultiBootLoader::new(config);
+        let state = boot.prepare_boot(aligned_buf.as_mut())?;
+        Ok(Self { state })
+    }
+
     /// Boots the application.
     ///
     /// # Safety
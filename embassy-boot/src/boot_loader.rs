@@ -5,7 +5,18 @@ use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::blocking_mutex::Mutex;
 use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
 
-use crate::{State, DFU_DETACH_MAGIC, REVERT_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC};
+#[cfg(any(test, feature = "compression"))]
+use crate::compression::{Decoder, Step as DecompressStep};
+use crate::encryption::{self, Decrypt};
+use crate::image::{self, read_unaligned};
+#[cfg(any(test, feature = "compression"))]
+use crate::AlignedBuffer;
+use crate::{
+    attempt_offset, counter_backup_offset, counter_size, decode_attempt, decode_counter, encode_attempt,
+    encode_counter, iv_offset, iv_size, state_end, DecompressError, ImageError, ImageHeader, ImagePolicy, KeyProvider,
+    NoKey, State, BOOT_MAGIC, DECOMPRESS_MAGIC, DFU_DETACH_MAGIC, HEADER_LEN, IV_LEN, MAX_TRIAL_BOOTS, REJECTED_MAGIC,
+    REVERT_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC,
+};
 
 /// Errors returned by bootloader
 #[derive(PartialEq, Eq, Debug)]
@@ -14,6 +25,10 @@ pub enum BootError {
     Flash(NorFlashErrorKind),
     /// Invalid bootloader magic
     BadMagic,
+    /// Invalid image.
+    Image(ImageError),
+    /// Invalid compressed update.
+    Decompress(DecompressError),
 }
 
 #[cfg(feature = "defmt")]
@@ -22,6 +37,8 @@ impl defmt::Format for BootError {
         match self {
             BootError::Flash(_) => defmt::write!(fmt, "BootError::Flash(_)"),
             BootError::BadMagic => defmt::write!(fmt, "BootError::BadMagic"),
+            BootError::Image(e) => defmt::write!(fmt, "BootError::Image({})", e),
+            BootError::Decompress(e) => defmt::write!(fmt, "BootError::Decompress({})", e),
         }
     }
 }
@@ -35,6 +52,18 @@ where
     }
 }
 
+impl From<ImageError> for BootError {
+    fn from(error: ImageError) -> Self {
+        BootError::Image(error)
+    }
+}
+
+impl From<DecompressError> for BootError {
+    fn from(error: DecompressError) -> Self {
+        BootError::Decompress(error)
+    }
+}
+
 /// Bootloader flash configuration holding the three flashes used by the bootloader
 ///
 /// If only a single flash is actually used, then that flash should be partitioned into three partitions before use.
@@ -130,7 +159,7 @@ impl<'a, ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>
 }
 
 /// BootLoader works with any flash implementing embedded_storage.
-pub struct BootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> {
+pub struct BootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, KEY: KeyProvider = NoKey> {
     active: ACTIVE,
     dfu: DFU,
     /// The state partition has the following format:
@@ -138,18 +167,25 @@ pub struct BootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> {
     /// | Range    | Description                                                                      |
     /// | 0..1     | Magic indicating bootloader state. BOOT_MAGIC means boot, SWAP_MAGIC means swap. |
     /// | 1..2     | Progress validity. ERASE_VALUE means valid, !ERASE_VALUE means invalid.          |
-    /// | 2..2 + N | Progress index used while swapping or reverting      
+    /// | 2..2 + N | Progress index used while swapping or reverting                                  |
+    ///
+    /// With an image policy, the last erase page of the partition holds the anti-rollback counter
+    /// and is not erased with the state. Each raise of the counter is appended to the page as a copy
+    /// of `max(8, WRITE_SIZE, READ_SIZE)` bytes, the counter little endian followed by its
+    /// complement, and the highest copy is the counter. Once the page is full, the counter is written
+    /// to the last copy of the rest of the partition before the page is erased, so that it survives
+    /// a power failure during the erase. The initialization vector of an encrypted update precedes
+    /// that copy, padded to a multiple of `max(WRITE_SIZE, READ_SIZE)` bytes. With more than one
+    /// trial boot, the words of `max(WRITE_SIZE, READ_SIZE)` bytes preceding it record the trial
+    /// boots, starting from the end, each holding the number of boots left.
     state: STATE,
+    policy: Option<ImagePolicy>,
+    trial_boots: u8,
+    key: Option<KEY>,
+    iv: Option<[u8; IV_LEN]>,
 }
 
 impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, STATE> {
-    /// Get the page size which is the "unit of operation" within the bootloader.
-    const PAGE_SIZE: u32 = if ACTIVE::ERASE_SIZE > DFU::ERASE_SIZE {
-        ACTIVE::ERASE_SIZE as u32
-    } else {
-        DFU::ERASE_SIZE as u32
-    };
-
     /// Create a new instance of a bootloader with the flash partitions.
     ///
     /// - All partitions must be aligned with the PAGE_SIZE const generic parameter.
@@ -159,9 +195,88 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             active: config.active,
             dfu: config.dfu,
             state: config.state,
+            policy: None,
+            trial_boots: 1,
+            key: None,
+            iv: None,
+        }
+    }
+}
+
+impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, KEY: KeyProvider> BootLoader<ACTIVE, DFU, STATE, KEY> {
+    /// Get the page size which is the "unit of operation" within the bootloader.
+    pub(crate) const PAGE_SIZE: u32 = if ACTIVE::ERASE_SIZE > DFU::ERASE_SIZE {
+        ACTIVE::ERASE_SIZE as u32
+    } else {
+        DFU::ERASE_SIZE as u32
+    };
+
+    /// Allow an update `boots` boots to be marked as booted before it is reverted.
+    ///
+    /// By default, an update is reverted if it is not marked as booted during its first boot. With
+    /// more trial boots, an update that resets before being marked as booted, for example because it
+    /// confirms itself only after a health check, gets more chances. The application can query the
+    /// remaining boots with [`FirmwareState::remaining_boot_attempts`](crate::FirmwareState::remaining_boot_attempts).
+    ///
+    /// `boots` must be between 1 and 127.
+    pub fn with_trial_boots(mut self, boots: u8) -> Self {
+        assert!((1..=MAX_TRIAL_BOOTS).contains(&boots));
+        self.trial_boots = boots;
+        self
+    }
+
+    /// Validate updates against `policy` before swapping them in.
+    ///
+    /// Updates must then be images with an [`ImageHeader`] and trailer. An update that does not
+    /// satisfy the policy is not swapped in, and [`prepare_boot`](Self::prepare_boot) returns
+    /// [`State::Rejected`].
+    ///
+    /// Booting an image with a [`State::Boot`] state, that is an image that has been marked as booted,
+    /// raises the anti-rollback counter stored in the state partition to the security counter of the
+    /// image. Updates with a lower security counter are refused.
+    ///
+    /// The state partition must then have at least two erase pages, the last one holding the
+    /// anti-rollback counter. The application must keep that page when changing the state, by
+    /// creating its [`FirmwareState`](crate::FirmwareState) or [`FirmwareUpdater`](crate::FirmwareUpdater)
+    /// with `with_counter_page`.
+    pub fn with_image_policy(mut self, policy: ImagePolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Decrypt updates with `key` while swapping them in.
+    ///
+    /// Updates must then be encrypted as described in [`apply_image_keystream`](crate::apply_image_keystream),
+    /// and marked with [`FirmwareState::mark_updated_encrypted`](crate::FirmwareState::mark_updated_encrypted)
+    /// to store their initialization vector. Updates marked without one are rejected. The image
+    /// swapped out of the active partition is encrypted as well, and decrypted again on revert.
+    ///
+    /// AES-CTR does not authenticate updates, a modified update decrypts to a modified image. Use an
+    /// [`ImagePolicy`] with a public key to check the signature of the decrypted image.
+    pub fn with_encryption<K: KeyProvider>(self, key: K) -> BootLoader<ACTIVE, DFU, STATE, K> {
+        BootLoader {
+            active: self.active,
+            dfu: self.dfu,
+            state: self.state,
+            policy: self.policy,
+            trial_boots: self.trial_boots,
+            key: Some(key),
+            iv: None,
         }
     }
 
+    /// Read the header of the image in the active partition.
+    pub fn active_image(&mut self, aligned_buf: &mut [u8]) -> Result<ImageHeader, BootError> {
+        let mut bytes = [0; HEADER_LEN];
+        read_unaligned(&mut self.active, 0, &mut bytes, aligned_buf)?;
+        Ok(ImageHeader::parse(&bytes)?)
+    }
+
+    /// Read the anti-rollback counter stored in the state partition, 0 without an image policy.
+    pub fn security_counter(&mut self, aligned_buf: &mut [u8]) -> Result<u32, BootError> {
+        Ok(self.read_counter(aligned_buf)?.unwrap_or(0))
+    }
+
     /// Perform necessary boot preparations like swapping images.
     ///
     /// The DFU partition is assumed to be 1 page bigger than the active partition for the swap
@@ -234,53 +349,502 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
     /// |    Active |            3 |      1 |      2 |      3 |      - |
     /// |       DFU |            3 |      4 |      5 |      6 |      3 |
     ///
+    /// ## DECOMPRESSING
+    ///
+    /// With the `compression` feature, an update marked with `mark_updated_compressed` is
+    /// decompressed from the DFU partition to the active partition, one page at a time. The progress index records the pages
+    /// written: after a power failure, the update is decoded again from its start, and only the pages
+    /// not yet written are written. The active image is overwritten, so the update cannot be reverted
+    /// and is booted as [`State::Boot`]. The DFU partition then only needs to hold the compressed
+    /// update, and updates that are not compressed are rejected if it is smaller than the active
+    /// partition plus one page.
+    ///
+    /// Compressed updates cannot be validated or decrypted before the active image is overwritten, so
+    /// they are rejected with an image policy or encryption.
     pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
-        const {
-            core::assert!(Self::PAGE_SIZE % ACTIVE::WRITE_SIZE as u32 == 0);
-            core::assert!(Self::PAGE_SIZE % ACTIVE::ERASE_SIZE as u32 == 0);
-            core::assert!(Self::PAGE_SIZE % DFU::WRITE_SIZE as u32 == 0);
-            core::assert!(Self::PAGE_SIZE % DFU::ERASE_SIZE as u32 == 0);
-        }
+        self.assert_config(aligned_buf);
 
-        // Ensure we have enough progress pages to store copy progress
-        assert_eq!(0, Self::PAGE_SIZE % aligned_buf.len() as u32);
-        assert!(aligned_buf.len() >= STATE::WRITE_SIZE);
-        assert_eq!(0, aligned_buf.len() % ACTIVE::WRITE_SIZE);
-        assert_eq!(0, aligned_buf.len() % DFU::WRITE_SIZE);
-
-        // Ensure our partitions are able to handle boot operations
-        assert_partitions(&self.active, &self.dfu, &self.state, Self::PAGE_SIZE);
+        if self.is_compressed_update(aligned_buf)? {
+            return self.decompress(aligned_buf);
+        }
 
         // Copy contents from partition N to active
         let state = self.read_state(aligned_buf)?;
         if state == State::Swap {
+            if self.key.is_some() {
+                self.iv = self.read_iv(aligned_buf)?;
+            }
+
             //
             // Check if we already swapped. If we're in the swap state, this means we should revert
             // since the app has failed to mark boot as successful
             //
             if !self.is_swapped(aligned_buf)? {
+                // Only validate before starting, the dfu partition is modified while swapping.
+                if self.current_progress(aligned_buf)? == 0 {
+                    if self.key.is_some() && self.iv.is_none() {
+                        warn!("Update rejected: not encrypted");
+                        return self.reject(aligned_buf);
+                    }
+                    if self.dfu.capacity() < self.active.capacity() + Self::PAGE_SIZE as usize {
+                        warn!("Update rejected: the dfu partition is too small to swap it");
+                        return self.reject(aligned_buf);
+                    }
+                    match self.validate_update(aligned_buf) {
+                        Ok(Some(header)) => trace!("Update to version {:?} accepted", header.version),
+                        Ok(None) => {}
+                        Err(BootError::Image(e)) => {
+                            warn!("Update rejected: {:?}", e);
+                            return self.reject(aligned_buf);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+
                 trace!("Swapping");
                 self.swap(aligned_buf)?;
                 trace!("Swapping done");
+
+                if self.trial_boots > 1 {
+                    self.write_attempt(0, self.trial_boots - 1, aligned_buf)?;
+                }
+            } else if self.next_trial_boot(aligned_buf)? {
+                trace!("Trial boot");
             } else {
                 trace!("Reverting");
                 self.revert(aligned_buf)?;
 
-                let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+                self.reset_state(REVERT_MAGIC, aligned_buf)?;
+            }
+        } else if state == State::Boot && self.policy.is_some() {
+            self.update_counter(aligned_buf)?;
+        }
+        Ok(state)
+    }
+
+    /// Check the partitions and buffer can be used for boot operations.
+    pub(crate) fn assert_config(&self, aligned_buf: &[u8]) {
+        const {
+            core::assert!(Self::PAGE_SIZE % ACTIVE::WRITE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % ACTIVE::ERASE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % DFU::WRITE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % DFU::ERASE_SIZE as u32 == 0);
+        }
+
+        // Ensure we have enough progress pages to store copy progress
+        assert_eq!(0, Self::PAGE_SIZE % aligned_buf.len() as u32);
+        assert!(aligned_buf.len() >= STATE::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % ACTIVE::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % DFU::WRITE_SIZE);
+
+        // Ensure our partitions are able to handle boot operations
+        assert_partitions(
+            &self.active,
+            &self.dfu,
+            &self.state,
+            Self::PAGE_SIZE,
+            self.policy.is_some(),
+        );
+
+        if self.policy.is_some() || self.trial_boots > 1 || self.key.is_some() {
+            // The anti-rollback counter, initialization vector and trial boots must not overlap the
+            // progress of a swap and revert.
+            let page_count = self.active.capacity() / Self::PAGE_SIZE as usize;
+            let end = match self.trial_boots {
+                1 => iv_offset(
+                    self.state.capacity(),
+                    STATE::ERASE_SIZE,
+                    self.policy.is_some(),
+                    STATE::WRITE_SIZE,
+                    STATE::READ_SIZE,
+                ),
+                boots => self.attempt_offset(boots as usize - 1) as usize,
+            };
+            assert!((2 + 4 * page_count) * STATE::WRITE_SIZE <= end);
+            assert!(aligned_buf.len() >= counter_size(STATE::WRITE_SIZE, STATE::READ_SIZE));
+        }
+        if self.policy.is_some() {
+            // The anti-rollback counter needs its own erase page.
+            assert!(self.state.capacity() >= 2 * STATE::ERASE_SIZE);
+        }
+    }
+
+    fn is_compressed_update(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        self.state.read(0, state_word)?;
+        Ok(!state_word.iter().any(|&b| b != DECOMPRESS_MAGIC))
+    }
+
+    #[cfg(not(any(test, feature = "compression")))]
+    fn decompress(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        warn!("Update rejected: compressed updates need the `compression` feature");
+        self.reject(aligned_buf)
+    }
 
-                // Invalidate progress
-                state_word.fill(!STATE_ERASE_VALUE);
-                self.state.write(STATE::WRITE_SIZE as u32, state_word)?;
+    /// Decompress the update in the dfu partition to the active partition.
+    #[cfg(any(test, feature = "compression"))]
+    fn decompress(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        const { core::assert!(ACTIVE::READ_SIZE == 1) };
+        let page_size = Self::PAGE_SIZE as usize;
 
-                // Clear magic and progress
-                self.state.erase(0, self.state.capacity() as u32)?;
+        // Only check before starting, the active partition is overwritten while decompressing.
+        let done = self.current_progress(aligned_buf)?;
+        if done == 0 {
+            if self.policy.is_some() || self.key.is_some() {
+                warn!("Update rejected: compressed updates cannot be validated or decrypted");
+                return self.reject(aligned_buf);
+            }
+            match self.check_compressed() {
+                Ok(len) if len as usize <= self.active.capacity() => {
+                    trace!("Compressed update of {} bytes accepted", len)
+                }
+                Ok(_) => {
+                    warn!("Update rejected: {:?}", DecompressError::TooLarge);
+                    return self.reject(aligned_buf);
+                }
+                Err(BootError::Decompress(e)) => {
+                    warn!("Update rejected: {:?}", e);
+                    return self.reject(aligned_buf);
+                }
+                Err(e) => return Err(e),
+            }
+        }
 
-                // Set magic
-                state_word.fill(REVERT_MAGIC);
-                self.state.write(0, state_word)?;
+        trace!("Decompressing");
+        let mut decoder = Decoder::new();
+        let mut input = AlignedBuffer([0; 64]);
+        const { core::assert!(64 % DFU::READ_SIZE == 0) };
+        let mut offset = 0;
+        // Output of the decoder so far, output written to the active partition, including the pages
+        // written before a power failure, and output assembled in the buffer.
+        let mut produced = 0;
+        let mut written = done * page_size;
+        let mut fill = 0;
+        while !decoder.is_done() {
+            let len = input.0.len().min(self.dfu.capacity() - offset);
+            if len == 0 {
+                return Err(DecompressError::Incomplete.into());
+            }
+            self.dfu.read(offset as u32, &mut input.0[..len])?;
+            offset += len;
+
+            let mut data = &input.0[..len];
+            while let Some(step) = decoder.next(&mut data, aligned_buf.len() - fill)? {
+                let len = match step {
+                    DecompressStep::Literal(bytes) => bytes.len(),
+                    DecompressStep::Match { len, .. } => len,
+                };
+                // Skip the output of the pages already written.
+                let skip = written.saturating_sub(produced).min(len);
+                produced += len;
+                let len = len - skip;
+                if len == 0 {
+                    continue;
+                }
+                if written + fill + len > self.active.capacity() {
+                    return Err(DecompressError::TooLarge.into());
+                }
+
+                match step {
+                    DecompressStep::Literal(bytes) => aligned_buf[fill..fill + len].copy_from_slice(&bytes[skip..]),
+                    DecompressStep::Match { distance, .. } => {
+                        let mut from = written + fill - distance as usize;
+                        let mut to = fill;
+                        // Part of the match already written to the active partition
+                        if from < written {
+                            let n = len.min(written - from);
+                            self.active.read(from as u32, &mut aligned_buf[to..to + n])?;
+                            from += n;
+                            to += n;
+                        }
+                        // Part of the match still in the buffer
+                        if to < fill + len {
+                            let start = from - written;
+                            aligned_buf.copy_within(start..start + fill + len - to, to);
+                        }
+                    }
+                }
+                fill += len;
+                if fill == aligned_buf.len() {
+                    self.write_decompressed(written, fill, aligned_buf)?;
+                    written += fill;
+                    fill = 0;
+                }
             }
         }
-        Ok(state)
+        if fill > 0 {
+            self.write_decompressed(written, fill, aligned_buf)?;
+        }
+        trace!("Decompressing done");
+
+        self.reset_state(BOOT_MAGIC, aligned_buf)?;
+        Ok(State::Boot)
+    }
+
+    /// Write `len` bytes of a decompressed update at `offset` in the active partition, recording the
+    /// progress once a page is complete or the update ends.
+    #[cfg(any(test, feature = "compression"))]
+    fn write_decompressed(&mut self, offset: usize, len: usize, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let page_size = Self::PAGE_SIZE as usize;
+        if offset % page_size == 0 {
+            self.active.erase(offset as u32, (offset + page_size) as u32)?;
+        }
+        let padded = len.div_ceil(ACTIVE::WRITE_SIZE) * ACTIVE::WRITE_SIZE;
+        aligned_buf[len..padded].fill(STATE_ERASE_VALUE);
+        self.active.write(offset as u32, &aligned_buf[..padded])?;
+        if (offset + len) % page_size == 0 || len < aligned_buf.len() {
+            self.update_progress((offset + len).div_ceil(page_size) - 1, aligned_buf)?;
+        }
+        Ok(())
+    }
+
+    /// Check the compressed update in the dfu partition, returning its decompressed length.
+    #[cfg(any(test, feature = "compression"))]
+    fn check_compressed(&mut self) -> Result<u32, BootError> {
+        let mut decoder = Decoder::new();
+        let mut input = AlignedBuffer([0; 64]);
+        let mut offset = 0;
+        while !decoder.is_done() {
+            let len = input.0.len().min(self.dfu.capacity() - offset);
+            if len == 0 {
+                break;
+            }
+            self.dfu.read(offset as u32, &mut input.0[..len])?;
+            offset += len;
+            decoder.skip(&input.0[..len])?;
+        }
+        Ok(decoder.finish()?)
+    }
+
+    /// Refuse the update, keeping the active image.
+    fn reject(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        self.reset_state(REJECTED_MAGIC, aligned_buf)?;
+        Ok(State::Rejected)
+    }
+
+    /// Record another trial boot of a swapped update, returning false if it should be reverted.
+    fn next_trial_boot(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        // Continue a revert that was interrupted.
+        let page_count = self.active.capacity() / Self::PAGE_SIZE as usize;
+        if self.trial_boots == 1 || self.current_progress(aligned_buf)? > page_count * 2 {
+            return Ok(false);
+        }
+
+        let word_size = STATE::WRITE_SIZE.max(STATE::READ_SIZE);
+        // If the first trial boot was not recorded, it is still counted.
+        let (mut index, mut remaining) = (0, self.trial_boots - 1);
+        while index < self.trial_boots as usize {
+            let word = &mut aligned_buf[..word_size];
+            self.state.read(self.attempt_offset(index), word)?;
+            match decode_attempt(word) {
+                Some(r) => remaining = r,
+                None => break,
+            }
+            index += 1;
+        }
+
+        if remaining == 0 || index == self.trial_boots as usize {
+            return Ok(false);
+        }
+        trace!("Trial boot, {} left", remaining - 1);
+        self.write_attempt(index, remaining - 1, aligned_buf)?;
+        Ok(true)
+    }
+
+    fn attempt_offset(&self, index: usize) -> u32 {
+        attempt_offset(
+            self.state.capacity(),
+            STATE::ERASE_SIZE,
+            self.policy.is_some(),
+            STATE::WRITE_SIZE,
+            STATE::READ_SIZE,
+            index,
+        ) as u32
+    }
+
+    fn write_attempt(&mut self, index: usize, remaining: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let word = &mut aligned_buf[..STATE::WRITE_SIZE.max(STATE::READ_SIZE)];
+        encode_attempt(remaining, word);
+        self.state.write(self.attempt_offset(index), word)?;
+        Ok(())
+    }
+
+    /// Validate the update in the dfu partition, if there is an image policy.
+    pub(crate) fn validate_update(&mut self, aligned_buf: &mut [u8]) -> Result<Option<ImageHeader>, BootError> {
+        let Some(policy) = self.policy.clone() else {
+            return Ok(None);
+        };
+        let active = self.active_image(aligned_buf).ok();
+        let stored = self.read_counter(aligned_buf)?.unwrap_or(0);
+        let min_counter = stored.max(active.map_or(0, |h| h.security_counter));
+        let capacity = self.active.capacity();
+        match (&mut self.key, self.iv) {
+            (Some(key), Some(iv)) => {
+                let mut dfu = Decrypt {
+                    flash: &mut self.dfu,
+                    key,
+                    iv,
+                };
+                image::validate(&mut dfu, capacity, &policy, min_counter, active.as_ref(), aligned_buf)
+            }
+            _ => image::validate(
+                &mut self.dfu,
+                capacity,
+                &policy,
+                min_counter,
+                active.as_ref(),
+                aligned_buf,
+            ),
+        }
+        .map(Some)
+    }
+
+    /// Raise the stored anti-rollback counter to the security counter of the confirmed active image.
+    pub(crate) fn update_counter(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let Ok(active) = self.active_image(aligned_buf) else {
+            return Ok(());
+        };
+        let (stored, free) = self.read_counter_page(aligned_buf)?;
+        let backup = self.read_counter_at(self.counter_backup_offset(), aligned_buf)?;
+        let counter = active.security_counter.max(stored.max(backup).unwrap_or(0));
+        if stored == Some(counter) {
+            return Ok(());
+        }
+
+        trace!("Raising security counter to {}", counter);
+        let size = counter_size(STATE::WRITE_SIZE, STATE::READ_SIZE);
+        let page = self.state_end();
+        if free < self.counter_slots() {
+            return self.write_counter((page + free * size) as u32, counter, aligned_buf);
+        }
+
+        // The counter page is full, keep a copy of the counter in the state while it is erased.
+        if backup != Some(counter) {
+            let offset = self.counter_backup_offset();
+            self.state.read(offset, &mut aligned_buf[..size])?;
+            if aligned_buf[..size].iter().any(|&b| b != STATE_ERASE_VALUE) {
+                self.reset_state(BOOT_MAGIC, aligned_buf)?;
+            }
+            self.write_counter(offset, counter, aligned_buf)?;
+        }
+        self.state.erase(page as u32, self.state.capacity() as u32)?;
+        self.write_counter(page as u32, counter, aligned_buf)
+    }
+
+    /// Read the anti-rollback counter, the highest of its copies.
+    pub(crate) fn read_counter(&mut self, aligned_buf: &mut [u8]) -> Result<Option<u32>, BootError> {
+        if self.policy.is_none() {
+            return Ok(None);
+        }
+        let (stored, _) = self.read_counter_page(aligned_buf)?;
+        let backup = self.read_counter_at(self.counter_backup_offset(), aligned_buf)?;
+        Ok(stored.max(backup))
+    }
+
+    /// Read the copies of the anti-rollback counter in the counter page, returning the highest one
+    /// and the index of the first free copy.
+    fn read_counter_page(&mut self, aligned_buf: &mut [u8]) -> Result<(Option<u32>, usize), BootError> {
+        let size = counter_size(STATE::WRITE_SIZE, STATE::READ_SIZE);
+        let page = self.state_end();
+        let mut counter = None;
+        for index in 0..self.counter_slots() {
+            let buf = &mut aligned_buf[..size];
+            self.state.read((page + index * size) as u32, buf)?;
+            if buf.iter().all(|&b| b == STATE_ERASE_VALUE) {
+                return Ok((counter, index));
+            }
+            counter = counter.max(decode_counter(buf));
+        }
+        Ok((counter, self.counter_slots()))
+    }
+
+    fn read_counter_at(&mut self, offset: u32, aligned_buf: &mut [u8]) -> Result<Option<u32>, BootError> {
+        let buf = &mut aligned_buf[..counter_size(STATE::WRITE_SIZE, STATE::READ_SIZE)];
+        self.state.read(offset, buf)?;
+        Ok(decode_counter(buf))
+    }
+
+    /// Number of copies of the anti-rollback counter the counter page holds, 0 without a counter
+    /// page.
+    fn counter_slots(&self) -> usize {
+        let page = self.state.capacity() - self.state_end();
+        page / counter_size(STATE::WRITE_SIZE, STATE::READ_SIZE)
+    }
+
+    /// End of the part of the state partition erased when the state changes, preceding the
+    /// anti-rollback counter page with an image policy.
+    fn state_end(&self) -> usize {
+        state_end(self.state.capacity(), STATE::ERASE_SIZE, self.policy.is_some())
+    }
+
+    fn counter_backup_offset(&self) -> u32 {
+        counter_backup_offset(
+            self.state.capacity(),
+            STATE::ERASE_SIZE,
+            self.policy.is_some(),
+            STATE::WRITE_SIZE,
+            STATE::READ_SIZE,
+        ) as u32
+    }
+
+    /// Read the initialization vector of an encrypted update.
+    fn read_iv(&mut self, aligned_buf: &mut [u8]) -> Result<Option<[u8; IV_LEN]>, BootError> {
+        let word_size = STATE::WRITE_SIZE.max(STATE::READ_SIZE);
+        let offset = iv_offset(
+            self.state.capacity(),
+            STATE::ERASE_SIZE,
+            self.policy.is_some(),
+            STATE::WRITE_SIZE,
+            STATE::READ_SIZE,
+        );
+        let mut iv = [0; IV_LEN];
+        for pos in (0..iv_size(STATE::WRITE_SIZE, STATE::READ_SIZE)).step_by(word_size) {
+            let word = &mut aligned_buf[..word_size];
+            self.state.read((offset + pos) as u32, word)?;
+            if pos < IV_LEN {
+                let len = word_size.min(IV_LEN - pos);
+                iv[pos..pos + len].copy_from_slice(&word[..len]);
+            }
+        }
+        Ok(match iv.iter().all(|&b| b == STATE_ERASE_VALUE) {
+            true => None,
+            false => Some(iv),
+        })
+    }
+
+    /// Encrypt or decrypt `data` at `offset` in an image, if updates are encrypted.
+    fn crypt(&mut self, swapped_out: bool, offset: u32, data: &mut [u8]) {
+        if let (Some(key), Some(iv)) = (&mut self.key, &self.iv) {
+            encryption::crypt(key, iv, swapped_out, offset, data);
+        }
+    }
+
+    fn write_counter(&mut self, offset: u32, counter: u32, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let buf = &mut aligned_buf[..counter_size(STATE::WRITE_SIZE, STATE::READ_SIZE)];
+        encode_counter(counter, buf);
+        self.state.write(offset, buf)?;
+        Ok(())
+    }
+
+    /// Erase the state, keeping the anti-rollback counter page, and set `magic`.
+    pub(crate) fn reset_state(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+
+        // Invalidate progress
+        self.state.read(STATE::WRITE_SIZE as u32, state_word)?;
+        if !state_word.iter().any(|&b| b != STATE_ERASE_VALUE) {
+            state_word.fill(!STATE_ERASE_VALUE);
+            self.state.write(STATE::WRITE_SIZE as u32, state_word)?;
+        }
+
+        // Clear magic and progress
+        let end = self.state_end();
+        self.state.erase(0, end as u32)?;
+
+        // Set magic
+        state_word.fill(magic);
+        self.state.write(0, state_word)?;
+        Ok(())
     }
 
     fn is_swapped(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
@@ -292,7 +856,8 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
     fn current_progress(&mut self, aligned_buf: &mut [u8]) -> Result<usize, BootError> {
         let write_size = STATE::WRITE_SIZE as u32;
-        let max_index = ((self.state.capacity() - STATE::WRITE_SIZE) / STATE::WRITE_SIZE) - 2;
+        let end = self.state_end();
+        let max_index = ((end - STATE::WRITE_SIZE) / STATE::WRITE_SIZE) - 2;
         let state_word = &mut aligned_buf[..write_size as usize];
 
         self.state.read(write_size, state_word)?;
@@ -324,6 +889,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         progress_index: usize,
         from_offset: u32,
         to_offset: u32,
+        swapped_out: bool,
         aligned_buf: &mut [u8],
     ) -> Result<(), BootError> {
         if self.current_progress(aligned_buf)? <= progress_index {
@@ -333,6 +899,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
             for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
                 self.dfu.read(from_offset + offset_in_page as u32, aligned_buf)?;
+                self.crypt(swapped_out, to_offset + offset_in_page, aligned_buf);
                 self.active.write(to_offset + offset_in_page as u32, aligned_buf)?;
             }
 
@@ -346,6 +913,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         progress_index: usize,
         from_offset: u32,
         to_offset: u32,
+        swapped_out: bool,
         aligned_buf: &mut [u8],
     ) -> Result<(), BootError> {
         if self.current_progress(aligned_buf)? <= progress_index {
@@ -355,6 +923,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
             for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
                 self.active.read(from_offset + offset_in_page as u32, aligned_buf)?;
+                self.crypt(swapped_out, from_offset + offset_in_page, aligned_buf);
                 self.dfu.write(to_offset + offset_in_page as u32, aligned_buf)?;
             }
 
@@ -363,7 +932,7 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
         Ok(())
     }
 
-    fn swap(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+    pub(crate) fn swap(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
         let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
         for page_num in 0..page_count {
             let progress_index = (page_num * 2) as usize;
@@ -372,19 +941,25 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             let active_from_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             let dfu_to_offset = (page_count - page_num) * Self::PAGE_SIZE;
             //trace!("Copy active {} to dfu {}", active_from_offset, dfu_to_offset);
-            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, aligned_buf)?;
+            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, true, aligned_buf)?;
 
             // Copy DFU page to the active page
             let active_to_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             let dfu_from_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
             //trace!("Copy dfy {} to active {}", dfu_from_offset, active_to_offset);
-            self.copy_page_once_to_active(progress_index + 1, dfu_from_offset, active_to_offset, aligned_buf)?;
+            self.copy_page_once_to_active(
+                progress_index + 1,
+                dfu_from_offset,
+                active_to_offset,
+                false,
+                aligned_buf,
+            )?;
         }
 
         Ok(())
     }
 
-    fn revert(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+    pub(crate) fn revert(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
         let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
         for page_num in 0..page_count {
             let progress_index = (page_count * 2 + page_num * 2) as usize;
@@ -392,12 +967,12 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             // Copy the bad active page to the DFU page
             let active_from_offset = page_num * Self::PAGE_SIZE;
             let dfu_to_offset = page_num * Self::PAGE_SIZE;
-            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, aligned_buf)?;
+            self.copy_page_once_to_dfu(progress_index, active_from_offset, dfu_to_offset, false, aligned_buf)?;
 
             // Copy the DFU page back to the active page
             let active_to_offset = page_num * Self::PAGE_SIZE;
             let dfu_from_offset = (page_num + 1) * Self::PAGE_SIZE;
-            self.copy_page_once_to_active(progress_index + 1, dfu_from_offset, active_to_offset, aligned_buf)?;
+            self.copy_page_once_to_active(progress_index + 1, dfu_from_offset, active_to_offset, true, aligned_buf)?;
         }
 
         Ok(())
@@ -413,6 +988,8 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             Ok(State::DfuDetach)
         } else if !state_word.iter().any(|&b| b != REVERT_MAGIC) {
             Ok(State::Revert)
+        } else if !state_word.iter().any(|&b| b != REJECTED_MAGIC) {
+            Ok(State::Rejected)
         } else {
             Ok(State::Boot)
         }
@@ -424,12 +1001,16 @@ fn assert_partitions<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>(
     dfu: &DFU,
     state: &STATE,
     page_size: u32,
+    counter_page: bool,
 ) {
     assert_eq!(active.capacity() as u32 % page_size, 0);
     assert_eq!(dfu.capacity() as u32 % page_size, 0);
-    // DFU partition has to be bigger than ACTIVE partition to handle swap algorithm
+    // DFU partition has to be bigger than ACTIVE partition to handle swap algorithm, unless it only
+    // holds compressed updates
+    #[cfg(not(any(test, feature = "compression")))]
     assert!(dfu.capacity() as u32 - active.capacity() as u32 >= page_size);
-    assert!(2 + 2 * (active.capacity() as u32 / page_size) <= state.capacity() as u32 / STATE::WRITE_SIZE as u32);
+    let end = state_end(state.capacity(), STATE::ERASE_SIZE, counter_page) as u32;
+    assert!(2 + 2 * (active.capacity() as u32 / page_size) <= end / STATE::WRITE_SIZE as u32);
 }
 
 #[cfg(test)]
@@ -446,6 +1027,6 @@ mod tests {
         static ACTIVE: MemFlash<ACTIVE_SIZE, 4, 4> = MemFlash::new(0xFF);
         static DFU: MemFlash<DFU_SIZE, 4, 4> = MemFlash::new(0xFF);
         static STATE: MemFlash<STATE_SIZE, 4, 4> = MemFlash::new(0xFF);
-        assert_partitions(&ACTIVE, &DFU, &STATE, 4096);
+        assert_partitions(&ACTIVE, &DFU, &STATE, 4096, false);
     }
 }
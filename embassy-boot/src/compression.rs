@@ -0,0 +1,395 @@
+//! Compressed updates, stored as is in the dfu partition and decompressed by the bootloader.
+use xxhash_rust::xxh32::Xxh32;
+
+use crate::DecompressError;
+
+/// Magic number of an LZ4 frame.
+const LZ4_MAGIC: u32 = 0x184d2204;
+
+const FLG_VERSION_MASK: u8 = 0xc0;
+const FLG_VERSION: u8 = 0x40;
+const FLG_BLOCK_CHECKSUM: u8 = 0x10;
+const FLG_CONTENT_SIZE: u8 = 0x08;
+const FLG_CONTENT_CHECKSUM: u8 = 0x04;
+const FLG_DICT_ID: u8 = 0x01;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Phase {
+    Magic,
+    Descriptor,
+    ContentSize,
+    HeaderChecksum,
+    BlockSize,
+    Raw,
+    Token,
+    LiteralLen,
+    Literals,
+    Offset,
+    MatchLen,
+    Match,
+    BlockChecksum,
+    ContentChecksum,
+    Done,
+}
+
+/// Step of decompression, produced by [`Decoder::next`].
+pub(crate) enum Step<'a> {
+    /// Append bytes.
+    Literal(&'a [u8]),
+    /// Append `len` bytes copied from `distance` bytes back, with `len` at most `distance`.
+    Match { distance: u32, len: usize },
+}
+
+/// Incremental LZ4 frame decoder.
+///
+/// The decoder keeps no history: matches are resolved by the caller from the decompressed output.
+pub(crate) struct Decoder {
+    phase: Phase,
+    fixed: [u8; 8],
+    fixed_len: usize,
+    flags: u8,
+    /// Compressed bytes left in the current block.
+    block_left: u32,
+    /// Literal or raw bytes left.
+    literals: u32,
+    /// Match length nibble of the current token, then match bytes left.
+    match_len: u32,
+    distance: u32,
+    produced: u32,
+    content_size: Option<u64>,
+    /// Checksum of the current block.
+    block_hasher: Xxh32,
+}
+
+impl Decoder {
+    pub(crate) const fn new() -> Self {
+        Self {
+            phase: Phase::Magic,
+            fixed: [0; 8],
+            fixed_len: 0,
+            flags: 0,
+            block_left: 0,
+            literals: 0,
+            match_len: 0,
+            distance: 0,
+            produced: 0,
+            content_size: None,
+            block_hasher: Xxh32::new(0),
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    /// Decode `input` without producing the update, to check it.
+    pub(crate) fn skip(&mut self, mut input: &[u8]) -> Result<(), DecompressError> {
+        while self.next(&mut input, usize::MAX)?.is_some() {}
+        Ok(())
+    }
+
+    /// Check the frame is complete and matches its content size, returning the decompressed length.
+    pub(crate) fn finish(&self) -> Result<u32, DecompressError> {
+        if !self.is_done() {
+            return Err(DecompressError::Incomplete);
+        }
+        if self.content_size.is_some_and(|size| size != self.produced as u64) {
+            return Err(DecompressError::ChecksumMismatch);
+        }
+        Ok(self.produced)
+    }
+
+    /// Collect a little endian value of `n` bytes, counting them in the current block if `in_block`.
+    fn take(&mut self, input: &mut &[u8], n: usize, in_block: bool) -> Result<Option<u64>, DecompressError> {
+        while self.fixed_len < n {
+            let Some((&byte, rest)) = input.split_first() else {
+                return Ok(None);
+            };
+            *input = rest;
+            if in_block {
+                self.block_left = self.block_left.checked_sub(1).ok_or(DecompressError::Malformed)?;
+                self.block_hasher.update(&[byte]);
+            }
+            self.fixed[self.fixed_len] = byte;
+            self.fixed_len += 1;
+        }
+        self.fixed_len = 0;
+        let mut bytes = [0; 8];
+        bytes[..n].copy_from_slice(&self.fixed[..n]);
+        Ok(Some(u64::from_le_bytes(bytes)))
+    }
+
+    /// Phase after a block.
+    fn end_block(&mut self) {
+        self.phase = match self.flags & FLG_BLOCK_CHECKSUM {
+            0 => Phase::BlockSize,
+            _ => Phase::BlockChecksum,
+        };
+    }
+
+    /// Get the next step of at most `max` bytes, consuming from `input`, or `None` if more input
+    /// is needed or the frame is complete.
+    pub(crate) fn next<'a>(&mut self, input: &mut &'a [u8], max: usize) -> Result<Option<Step<'a>>, DecompressError> {
+        macro_rules! take {
+            ($n:expr, $in_block:expr) => {
+                match self.take(input, $n, $in_block)? {
+                    Some(v) => v,
+                    None => return Ok(None),
+                }
+            };
+        }
+
+        loop {
+            match self.phase {
+                Phase::Magic => {
+                    if take!(4, false) as u32 != LZ4_MAGIC {
+                        return Err(DecompressError::BadFormat);
+                    }
+                    self.phase = Phase::Descriptor;
+                }
+                Phase::Descriptor => {
+                    let flags = take!(2, false) as u8;
+                    // Block checksums are required to check the update before it is decompressed.
+                    if flags & FLG_VERSION_MASK != FLG_VERSION
+                        || flags & FLG_DICT_ID != 0
+                        || flags & FLG_BLOCK_CHECKSUM == 0
+                    {
+                        return Err(DecompressError::BadFormat);
+                    }
+                    self.flags = flags;
+                    self.phase = match flags & FLG_CONTENT_SIZE {
+                        0 => Phase::HeaderChecksum,
+                        _ => Phase::ContentSize,
+                    };
+                }
+                Phase::ContentSize => {
+                    self.content_size = Some(take!(8, false));
+                    self.phase = Phase::HeaderChecksum;
+                }
+                Phase::HeaderChecksum => {
+                    take!(1, false);
+                    self.phase = Phase::BlockSize;
+                }
+                Phase::BlockSize => {
+                    let size = take!(4, false) as u32;
+                    if size == 0 {
+                        self.phase = match self.flags & FLG_CONTENT_CHECKSUM {
+                            0 => Phase::Done,
+                            _ => Phase::ContentChecksum,
+                        };
+                    } else if size & 0x8000_0000 != 0 {
+                        self.block_hasher = Xxh32::new(0);
+                        self.literals = size & 0x7fff_ffff;
+                        self.phase = Phase::Raw;
+                    } else {
+                        self.block_hasher = Xxh32::new(0);
+                        self.block_left = size;
+                        self.phase = Phase::Token;
+                    }
+                }
+                Phase::Raw | Phase::Literals if self.literals > 0 => {
+                    let len = (self.literals as usize).min(max).min(input.len());
+                    if len == 0 {
+                        return Ok(None);
+                    }
+                    if self.phase == Phase::Literals {
+                        if self.block_left < len as u32 {
+                            return Err(DecompressError::Malformed);
+                        }
+                        self.block_left -= len as u32;
+                    }
+                    let (bytes, rest) = input.split_at(len);
+                    *input = rest;
+                    self.block_hasher.update(bytes);
+                    self.literals -= len as u32;
+                    self.produced = self.produced.checked_add(len as u32).ok_or(DecompressError::TooLarge)?;
+                    return Ok(Some(Step::Literal(bytes)));
+                }
+                Phase::Raw => self.end_block(),
+                Phase::Literals => match self.block_left {
+                    // The last sequence of a block only has literals.
+                    0 => self.end_block(),
+                    _ => self.phase = Phase::Offset,
+                },
+                Phase::Token => {
+                    let token = take!(1, true) as u8;
+                    self.literals = (token >> 4) as u32;
+                    self.match_len = (token & 0xf) as u32;
+                    self.phase = match self.literals {
+                        15 => Phase::LiteralLen,
+                        _ => Phase::Literals,
+                    };
+                }
+                Phase::LiteralLen => {
+                    let byte = take!(1, true) as u32;
+                    self.literals += byte;
+                    if byte != 255 {
+                        self.phase = Phase::Literals;
+                    }
+                }
+                Phase::Offset => {
+                    self.distance = take!(2, true) as u32;
+                    if self.distance == 0 {
+                        return Err(DecompressError::Malformed);
+                    }
+                    self.phase = match self.match_len {
+                        15 => Phase::MatchLen,
+                        _ => {
+                            self.match_len += 4;
+                            Phase::Match
+                        }
+                    };
+                }
+                Phase::MatchLen => {
+                    let byte = take!(1, true) as u32;
+                    self.match_len += byte;
+                    if byte != 255 {
+                        self.match_len += 4;
+                        self.phase = Phase::Match;
+                    }
+                }
+                Phase::Match if self.match_len > 0 => {
+                    if self.distance > self.produced {
+                        return Err(DecompressError::Malformed);
+                    }
+                    let len = (self.match_len as usize).min(max).min(self.distance as usize);
+                    self.match_len -= len as u32;
+                    self.produced = self.produced.checked_add(len as u32).ok_or(DecompressError::TooLarge)?;
+                    return Ok(Some(Step::Match {
+                        distance: self.distance,
+                        len,
+                    }));
+                }
+                Phase::Match => match self.block_left {
+                    0 => self.end_block(),
+                    _ => self.phase = Phase::Token,
+                },
+                Phase::BlockChecksum => {
+                    if take!(4, false) as u32 != self.block_hasher.digest() {
+                        return Err(DecompressError::ChecksumMismatch);
+                    }
+                    self.phase = Phase::BlockSize;
+                }
+                Phase::ContentChecksum => {
+                    // The content can only be hashed once decompressed, too late to refuse the
+                    // update: the block checksums are checked instead.
+                    take!(4, false);
+                    self.phase = Phase::Done;
+                }
+                // The frame is followed by the rest of the dfu partition.
+                Phase::Done => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use alloc::vec::Vec;
+    use std::io::Write;
+
+    use lz4_flex::frame::{BlockMode, BlockSize, FrameEncoder, FrameInfo};
+
+    use super::*;
+    use crate::delta::tests::firmware;
+
+    /// Compress `data` into an LZ4 frame, with every optional field.
+    pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+        let info = FrameInfo::new()
+            .block_size(BlockSize::Max64KB)
+            .block_mode(BlockMode::Linked)
+            .block_checksums(true)
+            .content_checksum(true)
+            .content_size(Some(data.len() as u64));
+        let mut encoder = FrameEncoder::with_frame_info(info, Vec::new());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Compressible firmware-like data.
+    pub(crate) fn compressible(len: usize) -> Vec<u8> {
+        let words = firmware(3, 512);
+        (0..len).map(|i| words[(i * 7 / 5) % 512] & 0x0f).collect()
+    }
+
+    fn decompress(frame: &[u8], chunk: usize, max: usize) -> Result<Vec<u8>, DecompressError> {
+        let mut decoder = Decoder::new();
+        let mut out = Vec::new();
+        for mut input in frame.chunks(chunk) {
+            while let Some(step) = decoder.next(&mut input, max)? {
+                match step {
+                    Step::Literal(bytes) => out.extend_from_slice(bytes),
+                    Step::Match { distance, len } => {
+                        let start = out.len() - distance as usize;
+                        out.extend_from_within(start..start + len);
+                    }
+                }
+            }
+        }
+        match decoder.is_done() {
+            true => Ok(out),
+            false => Err(DecompressError::Incomplete),
+        }
+    }
+
+    #[test]
+    fn decode() {
+        let data = compressible(200_000);
+        let frame = compress(&data);
+        assert!(frame.len() < data.len() / 2);
+        for (chunk, max) in [(frame.len(), usize::MAX), (1, 3), (100, 256)] {
+            assert_eq!(decompress(&frame, chunk, max).unwrap(), data);
+        }
+
+        // Incompressible data is stored in raw blocks.
+        let data = firmware(4, 100_000);
+        assert_eq!(decompress(&compress(&data), 1000, 512).unwrap(), data);
+
+        let mut frame = compress(&data);
+        frame[0] ^= 1;
+        assert_eq!(decompress(&frame, 100, 100), Err(DecompressError::BadFormat));
+        let frame = compress(&data);
+        assert_eq!(
+            decompress(&frame[..frame.len() - 1], 100, 100),
+            Err(DecompressError::Incomplete)
+        );
+
+        // The rest of the dfu partition follows the frame.
+        let mut padded = compress(&data);
+        padded.extend_from_slice(&[0xff; 100]);
+        assert_eq!(decompress(&padded, 1000, 512).unwrap(), data);
+    }
+
+    #[test]
+    fn check() {
+        let data = compressible(100_000);
+        let frame = compress(&data);
+        let mut decoder = Decoder::new();
+        for chunk in frame.chunks(77) {
+            decoder.skip(chunk).unwrap();
+        }
+        assert_eq!(decoder.finish(), Ok(data.len() as u32));
+
+        // A corrupted block is detected by its checksum.
+        let mut corrupted = frame.clone();
+        corrupted[frame.len() / 2] ^= 0x10;
+        let mut decoder = Decoder::new();
+        assert!(matches!(
+            decoder.skip(&corrupted),
+            Err(DecompressError::ChecksumMismatch | DecompressError::Malformed)
+        ));
+
+        let mut decoder = Decoder::new();
+        decoder.skip(&frame[..frame.len() / 2]).unwrap();
+        assert_eq!(decoder.finish(), Err(DecompressError::Incomplete));
+
+        // Frames without block checksums are refused.
+        let info = FrameInfo::new().block_checksums(false);
+        let mut encoder = FrameEncoder::with_frame_info(info, Vec::new());
+        encoder.write_all(&data).unwrap();
+        let frame = encoder.finish().unwrap();
+        assert_eq!(Decoder::new().skip(&frame), Err(DecompressError::BadFormat));
+    }
+}
@@ -0,0 +1,664 @@
+//! Delta updates, reconstructing an update from the active image and a patch.
+
+/// Magic number at the start of a patch, "EBDP" in little endian.
+pub const PATCH_MAGIC: u32 = u32::from_le_bytes(*b"EBDP");
+/// Length of the patch header.
+pub const PATCH_HEADER_LEN: usize = 140;
+
+/// Patch instruction: copy bytes from the active image.
+pub(crate) const OP_COPY: u8 = 0x01;
+/// Patch instruction: insert literal bytes.
+pub(crate) const OP_INSERT: u8 = 0x02;
+
+/// Value marking a checkpoint slot as completely written.
+const SLOT_MARKER: u8 = 0xa5;
+/// Length of a checkpoint record before padding.
+const RECORD_LEN: usize = 20;
+
+/// Errors while applying a patch.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PatchError {
+    /// The patch does not start with [`PATCH_MAGIC`].
+    BadMagic,
+    /// The patch contains an invalid instruction, or data past the end of the target image.
+    Malformed,
+    /// The active image is not the image the patch was created for.
+    SourceMismatch,
+    /// The source or target image does not fit in its partition.
+    TooLarge,
+    /// The patch was not completely applied.
+    Incomplete,
+    /// The reconstructed image does not match the hash in the patch header.
+    HashMismatch,
+}
+
+/// Header at the start of a patch.
+///
+/// A patch is the header followed by instructions, building the target image in order:
+///
+/// - `0x01`, a zigzag encoded LEB128 seek, and a LEB128 length: move the source position by the
+///   seek, then copy `length` bytes from the source image.
+/// - `0x02`, a LEB128 length and `length` bytes: insert the bytes.
+///
+/// The source position starts at 0 and advances with each copy. The patch ends once the target
+/// image is complete.
+///
+/// | Offset | Size | Description                            |
+/// |--------|------|----------------------------------------|
+/// | 0      | 4    | [`PATCH_MAGIC`], little endian         |
+/// | 4      | 4    | Source image length, little endian     |
+/// | 8      | 4    | Target image length, little endian     |
+/// | 12     | 64   | SHA-512 of the source image            |
+/// | 76     | 64   | SHA-512 of the target image            |
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PatchHeader {
+    /// Length of the source image, at the start of the active partition.
+    pub source_len: u32,
+    /// Length of the target image.
+    pub target_len: u32,
+    /// SHA-512 of the source image.
+    pub source_hash: [u8; 64],
+    /// SHA-512 of the target image.
+    pub target_hash: [u8; 64],
+}
+
+impl PatchHeader {
+    /// Parse a header.
+    pub fn parse(bytes: &[u8; PATCH_HEADER_LEN]) -> Result<Self, PatchError> {
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        if u32_at(0) != PATCH_MAGIC {
+            return Err(PatchError::BadMagic);
+        }
+        let mut header = Self {
+            source_len: u32_at(4),
+            target_len: u32_at(8),
+            source_hash: [0; 64],
+            target_hash: [0; 64],
+        };
+        header.source_hash.copy_from_slice(&bytes[12..76]);
+        header.target_hash.copy_from_slice(&bytes[76..140]);
+        Ok(header)
+    }
+
+    /// Serialize the header.
+    pub fn to_bytes(&self) -> [u8; PATCH_HEADER_LEN] {
+        let mut bytes = [0; PATCH_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&PATCH_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.source_len.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.target_len.to_le_bytes());
+        bytes[12..76].copy_from_slice(&self.source_hash);
+        bytes[76..140].copy_from_slice(&self.target_hash);
+        bytes
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Phase {
+    Header,
+    Op,
+    CopySeek,
+    CopyLen,
+    InsertLen,
+    Copy(u32),
+    Insert(u32),
+}
+
+impl Phase {
+    fn encode(self) -> (u8, u32) {
+        match self {
+            Phase::Op => (0, 0),
+            Phase::Copy(remaining) => (1, remaining),
+            Phase::Insert(remaining) => (2, remaining),
+            // Checkpoints are only taken while applying instructions.
+            _ => (0xff, 0),
+        }
+    }
+
+    fn decode(kind: u8, remaining: u32) -> Option<Self> {
+        match kind {
+            0 => Some(Phase::Op),
+            1 => Some(Phase::Copy(remaining)),
+            2 => Some(Phase::Insert(remaining)),
+            _ => None,
+        }
+    }
+}
+
+/// Step of a patch, produced by [`Decoder::next`].
+pub(crate) enum Step<'a> {
+    /// Copy `len` bytes at `offset` in the source image.
+    Copy { offset: u32, len: usize },
+    /// Insert bytes.
+    Insert(&'a [u8]),
+}
+
+/// Incremental patch parser.
+#[derive(Clone)]
+pub(crate) struct Decoder {
+    phase: Phase,
+    header: [u8; PATCH_HEADER_LEN],
+    /// Bytes of the patch consumed.
+    consumed: u32,
+    /// Bytes of the target image produced.
+    produced: u32,
+    source_pos: u32,
+    varint: u64,
+    shift: u32,
+    seek: i64,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Self {
+            phase: Phase::Header,
+            header: [0; PATCH_HEADER_LEN],
+            consumed: 0,
+            produced: 0,
+            source_pos: 0,
+            varint: 0,
+            shift: 0,
+            seek: 0,
+        }
+    }
+
+    /// Parsed header, once complete.
+    pub(crate) fn header(&self) -> Option<PatchHeader> {
+        match self.phase {
+            Phase::Header => None,
+            _ => PatchHeader::parse(&self.header).ok(),
+        }
+    }
+
+    pub(crate) fn consumed(&self) -> u32 {
+        self.consumed
+    }
+
+    pub(crate) fn produced(&self) -> u32 {
+        self.produced
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.header().is_some_and(|h| self.produced == h.target_len)
+            && matches!(self.phase, Phase::Op | Phase::Copy(0) | Phase::Insert(0))
+    }
+
+    /// Consume header bytes from `input`, returning the header once it is complete.
+    pub(crate) fn feed_header(&mut self, input: &mut &[u8]) -> Result<Option<PatchHeader>, PatchError> {
+        if self.phase != Phase::Header {
+            return Ok(self.header());
+        }
+        let have = self.consumed as usize;
+        let n = input.len().min(PATCH_HEADER_LEN - have);
+        self.header[have..have + n].copy_from_slice(&input[..n]);
+        *input = &input[n..];
+        self.consumed += n as u32;
+        if (self.consumed as usize) < PATCH_HEADER_LEN {
+            return Ok(None);
+        }
+        let header = PatchHeader::parse(&self.header)?;
+        self.phase = Phase::Op;
+        Ok(Some(header))
+    }
+
+    /// Parse a LEB128 varint byte, returning the value once complete.
+    fn varint(&mut self, byte: u8) -> Result<Option<u64>, PatchError> {
+        if self.shift >= 64 {
+            return Err(PatchError::Malformed);
+        }
+        self.varint |= ((byte & 0x7f) as u64) << self.shift;
+        self.shift += 7;
+        if byte & 0x80 != 0 {
+            return Ok(None);
+        }
+        let value = self.varint;
+        self.varint = 0;
+        self.shift = 0;
+        Ok(Some(value))
+    }
+
+    /// Get the next step of at most `max` bytes, consuming from `input`, or `None` if more input
+    /// is needed.
+    pub(crate) fn next<'a>(&mut self, input: &mut &'a [u8], max: usize) -> Result<Option<Step<'a>>, PatchError> {
+        let Some(header) = self.header() else {
+            return Ok(None);
+        };
+        loop {
+            match self.phase {
+                Phase::Header => return Ok(None),
+                Phase::Copy(0) | Phase::Insert(0) => self.phase = Phase::Op,
+                Phase::Copy(remaining) => {
+                    let len = (remaining as usize).min(max);
+                    if self.source_pos as u64 + len as u64 > header.source_len as u64 {
+                        return Err(PatchError::Malformed);
+                    }
+                    let offset = self.source_pos;
+                    self.source_pos += len as u32;
+                    self.produced += len as u32;
+                    self.phase = Phase::Copy(remaining - len as u32);
+                    return Ok(Some(Step::Copy { offset, len }));
+                }
+                Phase::Insert(remaining) => {
+                    let len = (remaining as usize).min(max).min(input.len());
+                    if len == 0 {
+                        return Ok(None);
+                    }
+                    let (bytes, rest) = input.split_at(len);
+                    *input = rest;
+                    self.consumed += len as u32;
+                    self.produced += len as u32;
+                    self.phase = Phase::Insert(remaining - len as u32);
+                    return Ok(Some(Step::Insert(bytes)));
+                }
+                phase => {
+                    let Some((&byte, rest)) = input.split_first() else {
+                        return Ok(None);
+                    };
+                    *input = rest;
+                    self.consumed += 1;
+                    self.phase = match phase {
+                        Phase::Op if self.produced == header.target_len => return Err(PatchError::Malformed),
+                        Phase::Op if byte == OP_COPY => Phase::CopySeek,
+                        Phase::Op if byte == OP_INSERT => Phase::InsertLen,
+                        Phase::Op => return Err(PatchError::Malformed),
+                        Phase::CopySeek => match self.varint(byte)? {
+                            Some(v) => {
+                                self.seek = (v >> 1) as i64 ^ -((v & 1) as i64);
+                                Phase::CopyLen
+                            }
+                            None => Phase::CopySeek,
+                        },
+                        Phase::CopyLen | Phase::InsertLen => match self.varint(byte)? {
+                            Some(len) => {
+                                if len > (header.target_len - self.produced) as u64 {
+                                    return Err(PatchError::Malformed);
+                                }
+                                if phase == Phase::CopyLen {
+                                    let pos = self.source_pos as i64 + self.seek;
+                                    if pos < 0 || pos > header.source_len as i64 {
+                                        return Err(PatchError::Malformed);
+                                    }
+                                    self.source_pos = pos as u32;
+                                    Phase::Copy(len as u32)
+                                } else {
+                                    Phase::Insert(len as u32)
+                                }
+                            }
+                            None => phase,
+                        },
+                        _ => unreachable!(),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// State of a patch being applied by [`FirmwareUpdater::write_patch`](crate::FirmwareUpdater::write_patch)
+/// or [`BlockingFirmwareUpdater::write_patch`](crate::BlockingFirmwareUpdater::write_patch).
+///
+/// The target image is assembled in the buffer before being written to the dfu partition, so RAM
+/// use is bounded by the size of the buffer.
+///
+/// Progress is saved in the last sector of the dfu partition, which is not used by the image: after
+/// a reset, [`resume_patch`](crate::FirmwareUpdater::resume_patch) restores the progress and returns
+/// the offset in the patch to continue from.
+pub struct Patcher<'d> {
+    pub(crate) buf: &'d mut [u8],
+    pub(crate) fill: usize,
+    pub(crate) decoder: Decoder,
+    /// Checkpoint slots written so far.
+    pub(crate) slots: usize,
+    /// Number of sectors between checkpoints.
+    pub(crate) interval: usize,
+    pub(crate) started: bool,
+}
+
+impl<'d> Patcher<'d> {
+    /// Create a patcher using `buf` to assemble the target image.
+    ///
+    /// The buffer must be at least 256 bytes, a multiple of the write size of the dfu partition and
+    /// divide its erase size, and follow the alignment rules of the dfu partition.
+    pub fn new(buf: &'d mut [u8]) -> Self {
+        assert!(buf.len() >= 256);
+        Self {
+            buf,
+            fill: 0,
+            decoder: Decoder::new(),
+            slots: 0,
+            interval: 1,
+            started: false,
+        }
+    }
+
+    /// Get the patch header, once received.
+    pub fn header(&self) -> Option<PatchHeader> {
+        self.decoder.header()
+    }
+
+    /// Get the number of patch bytes consumed.
+    pub fn patch_offset(&self) -> u32 {
+        self.decoder.consumed()
+    }
+
+    /// Get the number of target image bytes produced.
+    pub fn target_offset(&self) -> u32 {
+        self.decoder.produced()
+    }
+
+    /// Whether the whole patch has been received.
+    pub fn is_done(&self) -> bool {
+        self.decoder.is_done()
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.fill = 0;
+        self.decoder = Decoder::new();
+        self.slots = 0;
+        self.interval = 1;
+        self.started = true;
+    }
+}
+
+/// Length of a checkpoint slot holding `len` bytes, including the marker.
+pub(crate) const fn slot_len(len: usize, write_size: usize) -> usize {
+    len.div_ceil(write_size) * write_size + write_size
+}
+
+/// Length of the checkpoint header slot.
+pub(crate) const fn header_slot_len(write_size: usize) -> usize {
+    slot_len(PATCH_HEADER_LEN, write_size)
+}
+
+/// Length of a checkpoint record slot.
+pub(crate) const fn record_slot_len(write_size: usize) -> usize {
+    slot_len(RECORD_LEN, write_size)
+}
+
+/// Number of records fitting in the checkpoint sector.
+pub(crate) const fn record_slots(erase_size: usize, write_size: usize) -> usize {
+    (erase_size - header_slot_len(write_size)) / record_slot_len(write_size)
+}
+
+/// Number of sectors between checkpoints, spreading the available records over the target image.
+pub(crate) fn checkpoint_interval(target_len: u32, erase_size: usize, write_size: usize) -> usize {
+    let sectors = (target_len as usize).div_ceil(erase_size);
+    sectors.div_ceil(record_slots(erase_size, write_size).max(1)).max(1)
+}
+
+/// Fill `slot` with `body` and the marker.
+pub(crate) fn encode_slot(body: &[u8], slot: &mut [u8], write_size: usize) {
+    let (data, marker) = slot.split_at_mut(slot.len() - write_size);
+    data.fill(0);
+    data[..body.len()].copy_from_slice(body);
+    marker.fill(SLOT_MARKER);
+}
+
+/// Check the marker of a slot.
+pub(crate) fn slot_valid(slot: &[u8], write_size: usize) -> bool {
+    slot[slot.len() - write_size..].iter().all(|&b| b == SLOT_MARKER)
+}
+
+/// Encode the progress of `decoder`.
+pub(crate) fn encode_record(decoder: &Decoder) -> [u8; RECORD_LEN] {
+    let (kind, remaining) = decoder.phase.encode();
+    let mut record = [0; RECORD_LEN];
+    record[0] = kind;
+    record[4..8].copy_from_slice(&remaining.to_le_bytes());
+    record[8..12].copy_from_slice(&decoder.consumed.to_le_bytes());
+    record[12..16].copy_from_slice(&decoder.produced.to_le_bytes());
+    record[16..20].copy_from_slice(&decoder.source_pos.to_le_bytes());
+    record
+}
+
+/// Restore the progress of `decoder` from a record.
+pub(crate) fn decode_record(decoder: &mut Decoder, record: &[u8]) -> bool {
+    let u32_at = |i: usize| u32::from_le_bytes([record[i], record[i + 1], record[i + 2], record[i + 3]]);
+    let Some(phase) = Phase::decode(record[0], u32_at(4)) else {
+        return false;
+    };
+    decoder.phase = phase;
+    decoder.consumed = u32_at(8);
+    decoder.produced = u32_at(12);
+    decoder.source_pos = u32_at(16);
+    true
+}
+
+/// Restore a decoder from the header of a patch.
+pub(crate) fn decode_header(decoder: &mut Decoder, header: &[u8]) -> bool {
+    decoder.header.copy_from_slice(&header[..PATCH_HEADER_LEN]);
+    decoder.phase = Phase::Op;
+    decoder.consumed = PATCH_HEADER_LEN as u32;
+    decoder.produced = 0;
+    decoder.source_pos = 0;
+    decoder.header().is_some()
+}
+
+#[cfg(any(test, feature = "alloc"))]
+mod generate {
+    use alloc::vec::Vec;
+
+    use sha2::{Digest, Sha512};
+
+    use super::*;
+
+    /// Matches shorter than this are inserted rather than copied.
+    const MIN_MATCH: usize = 12;
+    /// Length of the keys indexing the source image.
+    const KEY_LEN: usize = 8;
+    /// Candidates considered for each match.
+    const MAX_CANDIDATES: usize = 32;
+
+    fn push_varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn push_insert(out: &mut Vec<u8>, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            out.push(OP_INSERT);
+            push_varint(out, bytes.len() as u64);
+            out.extend_from_slice(bytes);
+        }
+    }
+
+    fn key(bytes: &[u8]) -> u64 {
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&bytes[..KEY_LEN]);
+        u64::from_be_bytes(key)
+    }
+
+    /// Create a patch turning `source` into `target`.
+    ///
+    /// Both images are at most 4 GiB. Runs on the host, with the `alloc` feature.
+    pub fn generate_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let header = PatchHeader {
+            source_len: source.len() as u32,
+            target_len: target.len() as u32,
+            source_hash: Sha512::digest(source).into(),
+            target_hash: Sha512::digest(target).into(),
+        };
+        let mut out = Vec::new();
+        out.extend_from_slice(&header.to_bytes());
+
+        // Positions in the source, sorted by the bytes following them.
+        let mut index: Vec<(u64, u32)> = (0..source.len().saturating_sub(KEY_LEN - 1))
+            .map(|i| (key(&source[i..]), i as u32))
+            .collect();
+        index.sort_unstable();
+
+        let match_len = |s: usize, t: usize| source[s..].iter().zip(&target[t..]).take_while(|(a, b)| a == b).count();
+
+        let mut source_pos = 0usize;
+        let mut pending = 0;
+        let mut t = 0;
+        while t < target.len() {
+            // Prefer continuing at the current source position, which needs no seek.
+            let mut best = (match_len(source_pos.min(source.len()), t), source_pos);
+            if best.0 < MIN_MATCH && t + KEY_LEN <= target.len() {
+                let k = key(&target[t..]);
+                let start = index.partition_point(|&(key, _)| key < k);
+                for &(key, pos) in index[start..].iter().take(MAX_CANDIDATES) {
+                    if key != k {
+                        break;
+                    }
+                    let len = match_len(pos as usize, t);
+                    if len > best.0 {
+                        best = (len, pos as usize);
+                    }
+                }
+            }
+
+            let (len, pos) = best;
+            if len < MIN_MATCH {
+                t += 1;
+                continue;
+            }
+            push_insert(&mut out, &target[pending..t]);
+            let seek = pos as i64 - source_pos as i64;
+            out.push(OP_COPY);
+            push_varint(&mut out, ((seek << 1) ^ (seek >> 63)) as u64);
+            push_varint(&mut out, len as u64);
+            source_pos = pos + len;
+            t += len;
+            pending = t;
+        }
+        push_insert(&mut out, &target[pending..]);
+        out
+    }
+
+    /// Apply a patch to `source`, checking both images against the hashes in the patch header.
+    ///
+    /// Runs on the host, with the `alloc` feature, to check a patch before distributing it.
+    pub fn apply_patch(source: &[u8], mut patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+        let mut decoder = Decoder::new();
+        let Some(header) = decoder.feed_header(&mut patch)? else {
+            return Err(PatchError::Incomplete);
+        };
+        let Some(source) = source.get(..header.source_len as usize) else {
+            return Err(PatchError::SourceMismatch);
+        };
+        if Sha512::digest(source).as_slice() != header.source_hash {
+            return Err(PatchError::SourceMismatch);
+        }
+
+        let mut target = Vec::with_capacity(header.target_len as usize);
+        while let Some(step) = decoder.next(&mut patch, usize::MAX)? {
+            match step {
+                Step::Copy { offset, len } => {
+                    let bytes = source.get(offset as usize..offset as usize + len);
+                    target.extend_from_slice(bytes.ok_or(PatchError::Malformed)?);
+                }
+                Step::Insert(bytes) => target.extend_from_slice(bytes),
+            }
+        }
+        if !decoder.is_done() {
+            return Err(PatchError::Incomplete);
+        }
+        if Sha512::digest(&target).as_slice() != header.target_hash {
+            return Err(PatchError::HashMismatch);
+        }
+        Ok(target)
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+pub use generate::{apply_patch, generate_patch};
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Apply a patch in memory, feeding it in chunks of `chunk` bytes.
+    fn apply(source: &[u8], patch: &[u8], chunk: usize, max: usize) -> Result<Vec<u8>, PatchError> {
+        let mut decoder = Decoder::new();
+        let mut target = Vec::new();
+        for mut input in patch.chunks(chunk) {
+            decoder.feed_header(&mut input)?;
+            while let Some(step) = decoder.next(&mut input, max)? {
+                match step {
+                    Step::Copy { offset, len } => {
+                        target.extend_from_slice(&source[offset as usize..offset as usize + len]);
+                    }
+                    Step::Insert(bytes) => target.extend_from_slice(bytes),
+                }
+            }
+        }
+        assert!(decoder.is_done());
+        Ok(target)
+    }
+
+    pub(crate) fn firmware(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let source = firmware(1, 20000);
+        let mut target = source.clone();
+        // Change some bytes, insert a block and remove another.
+        target[100..110].copy_from_slice(&[0; 10]);
+        target.splice(5000..5000, firmware(2, 300));
+        target.drain(12000..12500);
+        target.extend_from_slice(&source[..1000]);
+
+        let patch = generate_patch(&source, &target);
+        assert!(patch.len() < 1000, "patch is {} bytes", patch.len());
+        for (chunk, max) in [(patch.len(), usize::MAX), (1, 7), (13, 256)] {
+            assert_eq!(apply(&source, &patch, chunk, max).unwrap(), target);
+        }
+
+        assert_eq!(apply(&[], &generate_patch(&[], &target), 100, 64).unwrap(), target);
+        assert_eq!(apply(&source, &generate_patch(&source, &[]), 100, 64).unwrap(), []);
+    }
+
+    #[test]
+    fn apply_checks_hashes() {
+        let source = firmware(1, 5000);
+        let mut target = source.clone();
+        target[1000..1100].fill(0x55);
+        let patch = generate_patch(&source, &target);
+        assert_eq!(apply_patch(&source, &patch), Ok(target));
+
+        let mut other = source.clone();
+        other[10] ^= 1;
+        assert_eq!(apply_patch(&other, &patch), Err(PatchError::SourceMismatch));
+        assert_eq!(apply_patch(&source[..4000], &patch), Err(PatchError::SourceMismatch));
+        assert_eq!(
+            apply_patch(&source, &patch[..patch.len() - 1]),
+            Err(PatchError::Incomplete)
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let source = firmware(1, 1000);
+        let patch = generate_patch(&source, &source[..500]);
+
+        let mut bad = patch.clone();
+        bad[0] ^= 1;
+        assert_eq!(apply(&source, &bad, 64, 64), Err(PatchError::BadMagic));
+
+        // Copy past the end of the source.
+        let mut bad = patch[..PATCH_HEADER_LEN].to_vec();
+        bad.extend_from_slice(&[OP_COPY, 0xd0, 0x0f, 0x10]);
+        assert_eq!(apply(&source, &bad, 64, 64).err(), Some(PatchError::Malformed));
+
+        // Data past the end of the target.
+        let mut bad = patch.clone();
+        bad.extend_from_slice(&[OP_INSERT, 1, 0]);
+        assert_eq!(apply(&source, &bad, 64, 64).err(), Some(PatchError::Malformed));
+    }
+}
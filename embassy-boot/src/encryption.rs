@@ -0,0 +1,209 @@
+//! Encrypted updates, decrypted while swapping them in.
+use embedded_storage::nor_flash::{ErrorType, ReadNorFlash};
+
+/// Length of the initialization vector of an encrypted update.
+pub const IV_LEN: usize = 16;
+
+/// Provides AES-CTR encryption with the device key, for encrypted updates.
+///
+/// Implement this with a hardware crypto peripheral, or use [`SoftwareAes`] with the `aes`
+/// feature.
+pub trait KeyProvider {
+    /// Apply the keystream to `data`, a multiple of 16 bytes long.
+    ///
+    /// Each block of 16 bytes is XORed with the encryption of `counter`, incremented by the index of
+    /// the block as a 128-bit big endian integer.
+    fn apply_keystream(&mut self, counter: &[u8; 16], data: &mut [u8]);
+}
+
+impl<K: KeyProvider> KeyProvider for &mut K {
+    fn apply_keystream(&mut self, counter: &[u8; 16], data: &mut [u8]) {
+        K::apply_keystream(self, counter, data)
+    }
+}
+
+/// Key provider for a bootloader without encryption.
+pub enum NoKey {}
+
+impl KeyProvider for NoKey {
+    fn apply_keystream(&mut self, _counter: &[u8; 16], _data: &mut [u8]) {
+        match *self {}
+    }
+}
+
+/// Software AES-CTR key provider.
+#[cfg(feature = "aes")]
+pub struct SoftwareAes<C> {
+    cipher: C,
+}
+
+#[cfg(feature = "aes")]
+impl SoftwareAes<aes::Aes128> {
+    /// Create a key provider for an AES-128 key.
+    pub fn aes128(key: &[u8; 16]) -> Self {
+        use aes::cipher::KeyInit;
+        Self {
+            cipher: aes::Aes128::new(key.into()),
+        }
+    }
+}
+
+#[cfg(feature = "aes")]
+impl SoftwareAes<aes::Aes256> {
+    /// Create a key provider for an AES-256 key.
+    pub fn aes256(key: &[u8; 32]) -> Self {
+        use aes::cipher::KeyInit;
+        Self {
+            cipher: aes::Aes256::new(key.into()),
+        }
+    }
+}
+
+#[cfg(feature = "aes")]
+impl<C: aes::cipher::BlockEncrypt + aes::cipher::BlockSizeUser<BlockSize = aes::cipher::consts::U16>> KeyProvider
+    for SoftwareAes<C>
+{
+    fn apply_keystream(&mut self, counter: &[u8; 16], data: &mut [u8]) {
+        let mut counter = u128::from_be_bytes(*counter);
+        for chunk in data.chunks_mut(16) {
+            let mut block = counter.to_be_bytes().into();
+            self.cipher.encrypt_block(&mut block);
+            chunk.iter_mut().zip(block.iter()).for_each(|(b, k)| *b ^= k);
+            counter = counter.wrapping_add(1);
+        }
+    }
+}
+
+/// Counter block for block `index` of an image.
+///
+/// The image swapped out of the active partition is encrypted with the top bit of the
+/// initialization vector flipped, so that it never shares a keystream with the update.
+fn counter_block(iv: &[u8; IV_LEN], swapped_out: bool, index: u32) -> [u8; 16] {
+    let mut counter = u128::from_be_bytes(*iv);
+    if swapped_out {
+        counter ^= 1 << 127;
+    }
+    counter.wrapping_add(index as u128).to_be_bytes()
+}
+
+/// Encrypt or decrypt `data`, at `offset` in an image.
+pub(crate) fn crypt<K: KeyProvider>(key: &mut K, iv: &[u8; IV_LEN], swapped_out: bool, offset: u32, data: &mut [u8]) {
+    let mut pos = 0;
+    while pos < data.len() {
+        let at = offset + pos as u32;
+        let skip = at as usize % 16;
+        let counter = counter_block(iv, swapped_out, at / 16);
+        let blocks = (data.len() - pos) / 16 * 16;
+        if skip == 0 && blocks > 0 {
+            key.apply_keystream(&counter, &mut data[pos..pos + blocks]);
+            pos += blocks;
+        } else {
+            let mut block = [0; 16];
+            key.apply_keystream(&counter, &mut block);
+            let len = (16 - skip).min(data.len() - pos);
+            data[pos..pos + len]
+                .iter_mut()
+                .zip(&block[skip..])
+                .for_each(|(b, k)| *b ^= k);
+            pos += len;
+        }
+    }
+}
+
+/// Encrypt or decrypt `data`, at `offset` in an update encrypted with `iv`.
+///
+/// Updates are encrypted with AES-CTR, the counter of the block at `offset` being `iv` plus
+/// `offset / 16` as a 128-bit big endian integer. This can be used on the host to encrypt updates.
+pub fn apply_image_keystream<K: KeyProvider>(key: &mut K, iv: &[u8; IV_LEN], offset: u32, data: &mut [u8]) {
+    crypt(key, iv, false, offset, data)
+}
+
+/// Flash reading an encrypted update as plaintext.
+pub(crate) struct Decrypt<'a, F, K> {
+    pub(crate) flash: &'a mut F,
+    pub(crate) key: &'a mut K,
+    pub(crate) iv: [u8; IV_LEN],
+}
+
+impl<F: ErrorType, K> ErrorType for Decrypt<'_, F, K> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash, K: KeyProvider> ReadNorFlash for Decrypt<'_, F, K> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset, bytes)?;
+        crypt(self.key, &self.iv, false, offset, bytes);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use sha2::{Digest, Sha512};
+
+    use super::*;
+
+    /// Key provider for tests, using a hash of the counter as keystream.
+    pub(crate) struct TestKey;
+
+    impl KeyProvider for TestKey {
+        fn apply_keystream(&mut self, counter: &[u8; 16], data: &mut [u8]) {
+            let mut counter = u128::from_be_bytes(*counter);
+            for chunk in data.chunks_mut(16) {
+                let block = Sha512::digest(counter.to_be_bytes());
+                chunk.iter_mut().zip(block.iter()).for_each(|(b, k)| *b ^= k);
+                counter = counter.wrapping_add(1);
+            }
+        }
+    }
+
+    #[test]
+    fn crypt_unaligned() {
+        let iv = [0xfe; IV_LEN];
+        let mut whole = [0u8; 100];
+        crypt(&mut TestKey, &iv, false, 0, &mut whole);
+
+        // Any split gives the same keystream
+        for split in [1, 7, 16, 33] {
+            let mut data = [0u8; 100];
+            let (a, b) = data.split_at_mut(split);
+            crypt(&mut TestKey, &iv, false, 0, a);
+            crypt(&mut TestKey, &iv, false, split as u32, b);
+            assert_eq!(data, whole);
+        }
+
+        let mut swapped = [0u8; 100];
+        crypt(&mut TestKey, &iv, true, 0, &mut swapped);
+        assert_ne!(swapped, whole);
+    }
+
+    #[cfg(feature = "aes")]
+    #[test]
+    fn software_aes() {
+        // NIST SP 800-38A, F.5.1
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c,
+        ];
+        let iv = [
+            0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
+        ];
+        let mut data = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a, 0xae, 0x2d,
+            0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf, 0x8e, 0x51,
+        ];
+        apply_image_keystream(&mut SoftwareAes::aes128(&key), &iv, 0, &mut data);
+        assert_eq!(
+            data,
+            [
+                0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26, 0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d, 0xb6, 0xce, 0x98,
+                0x06, 0xf6, 0x6b, 0x79, 0x70, 0xfd, 0xff, 0x86, 0x17, 0x18, 0x7b, 0xb9, 0xff, 0xfd, 0xff
+            ]
+        );
+    }
+}
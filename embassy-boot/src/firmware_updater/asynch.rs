@@ -3,10 +3,22 @@ use digest::Digest;
 use embassy_embedded_hal::flash::partition::Partition;
 #[cfg(target_os = "none")]
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embedded_storage_async::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use sha2::Sha512;
 
 use super::FirmwareUpdaterConfig;
-use crate::{FirmwareUpdaterError, State, BOOT_MAGIC, DFU_DETACH_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC};
+#[cfg(any(test, feature = "compression"))]
+use crate::compression::Decoder;
+use crate::delta::{
+    checkpoint_interval, decode_header, decode_record, encode_record, encode_slot, header_slot_len, record_slot_len,
+    record_slots, slot_valid, Step,
+};
+use crate::{
+    attempt_offset, decode_attempt, iv_offset, iv_size, state_end, FirmwareUpdaterError, PatchError, Patcher, State,
+    BOOT_MAGIC, DFU_DETACH_MAGIC, IV_LEN, MAX_TRIAL_BOOTS, STATE_ERASE_VALUE, SWAP_MAGIC,
+};
+#[cfg(any(test, feature = "compression"))]
+use crate::{DecompressError, DECOMPRESS_MAGIC};
 
 /// FirmwareUpdater is an application API for interacting with the BootLoader without the ability to
 /// 'mess up' the internal bootloader state
@@ -61,6 +73,13 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         }
     }
 
+    /// Keep the last erase page of the state partition when changing the state, see
+    /// [`FirmwareState::with_counter_page`].
+    pub fn with_counter_page(mut self) -> Self {
+        self.state = self.state.with_counter_page();
+        self
+    }
+
     /// Obtain the current state.
     ///
     /// This is useful to check if the bootloader has just done a swap, in order
@@ -172,12 +191,38 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         Ok(())
     }
 
+    /// Size of the DFU partition.
+    pub fn dfu_capacity(&self) -> usize {
+        self.dfu.capacity()
+    }
+
+    /// Erase the DFU partition from `from` to `to`, which must be aligned to its erase size.
+    ///
+    /// Sectors erased this way are not erased again by [`write_firmware`](Self::write_firmware)
+    /// when it continues in the last of them.
+    pub async fn erase_dfu(&mut self, from: u32, to: u32) -> Result<(), FirmwareUpdaterError> {
+        self.state.verify_booted().await?;
+        self.dfu.erase(from, to).await?;
+        if to > from {
+            self.last_erased_dfu_sector_index = Some((to as usize - 1) / DFU::ERASE_SIZE);
+        }
+        Ok(())
+    }
+
     /// Mark to trigger firmware swap on next boot.
     #[cfg(not(feature = "_verify"))]
     pub async fn mark_updated(&mut self) -> Result<(), FirmwareUpdaterError> {
         self.state.mark_updated().await
     }
 
+    /// Mark to trigger firmware swap on next boot, of an update encrypted with `iv`.
+    ///
+    /// See [`FirmwareState::mark_updated_encrypted`].
+    #[cfg(not(feature = "_verify"))]
+    pub async fn mark_updated_encrypted(&mut self, iv: &[u8; IV_LEN]) -> Result<(), FirmwareUpdaterError> {
+        self.state.mark_updated_encrypted(iv).await
+    }
+
     /// Mark to trigger USB DFU on next boot.
     pub async fn mark_dfu(&mut self) -> Result<(), FirmwareUpdaterError> {
         self.state.verify_booted().await?;
@@ -189,6 +234,23 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         self.state.mark_booted().await
     }
 
+    /// Mark firmware boot successful if `health_check` completes with `true`, returning whether it did.
+    ///
+    /// See [`FirmwareState::mark_booted_if`](crate::FirmwareState::mark_booted_if).
+    pub async fn mark_booted_if(
+        &mut self,
+        health_check: impl core::future::Future<Output = bool>,
+    ) -> Result<bool, FirmwareUpdaterError> {
+        self.state.mark_booted_if(health_check).await
+    }
+
+    /// Get the number of boots left to mark an update as booted before it is reverted.
+    ///
+    /// See [`FirmwareState::remaining_boot_attempts`](crate::FirmwareState::remaining_boot_attempts).
+    pub async fn remaining_boot_attempts(&mut self) -> Result<Option<u8>, FirmwareUpdaterError> {
+        self.state.remaining_boot_attempts().await
+    }
+
     /// Writes firmware data to the device.
     ///
     /// This function writes the given data to the firmware area starting at the specified offset.
@@ -255,6 +317,218 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         Ok(())
     }
 
+    /// Start applying a patch with [`write_patch`](Self::write_patch), or resume after a reset.
+    ///
+    /// Returns the offset in the patch to continue from. If no patch was in progress, this is 0 and
+    /// the progress of any previous patch is discarded. Use [`Patcher::header`] to check that the
+    /// patch being resumed is the expected one, or [`cancel_patch`](Self::cancel_patch) to start over.
+    pub async fn resume_patch(&mut self, patcher: &mut Patcher<'_>) -> Result<u32, FirmwareUpdaterError> {
+        self.state.verify_booted().await?;
+
+        let header_len = header_slot_len(DFU::WRITE_SIZE);
+        let record_len = record_slot_len(DFU::WRITE_SIZE);
+        assert!(patcher.buf.len() >= header_len);
+        assert_eq!(patcher.buf.len() % DFU::WRITE_SIZE, 0);
+        assert_eq!(DFU::ERASE_SIZE % patcher.buf.len(), 0);
+
+        patcher.reset();
+        let area = self.checkpoint_area();
+        self.dfu.read(area, &mut patcher.buf[..header_len]).await?;
+        let slot = &patcher.buf[..header_len];
+        if !slot_valid(slot, DFU::WRITE_SIZE) || !decode_header(&mut patcher.decoder, slot) {
+            self.cancel_patch(patcher).await?;
+            return Ok(0);
+        }
+
+        let target_len = unwrap!(patcher.decoder.header()).target_len;
+        patcher.interval = checkpoint_interval(target_len, DFU::ERASE_SIZE, DFU::WRITE_SIZE);
+        while patcher.slots < record_slots(DFU::ERASE_SIZE, DFU::WRITE_SIZE) {
+            let offset = area + (header_len + patcher.slots * record_len) as u32;
+            let slot = &mut patcher.buf[..record_len];
+            self.dfu.read(offset, slot).await?;
+            if slot.iter().all(|&b| b == STATE_ERASE_VALUE) {
+                break;
+            }
+            // Records torn by a reset are skipped
+            if slot_valid(slot, DFU::WRITE_SIZE) {
+                decode_record(&mut patcher.decoder, slot);
+            }
+            patcher.slots += 1;
+        }
+        Ok(patcher.decoder.consumed())
+    }
+
+    /// Discard the progress of a patch, to start applying it again from the start.
+    pub async fn cancel_patch(&mut self, patcher: &mut Patcher<'_>) -> Result<(), FirmwareUpdaterError> {
+        let area = self.checkpoint_area();
+        self.dfu.erase(area, area + DFU::ERASE_SIZE as u32).await?;
+        patcher.reset();
+        Ok(())
+    }
+
+    /// Apply the next bytes of a patch, reading the source image from `active` and writing the
+    /// reconstructed image to the dfu partition.
+    ///
+    /// The source image must be readable at any offset of `active`, as with internal flash: a read
+    /// size other than 1 fails to compile.
+    /// [`resume_patch`](Self::resume_patch) must be called before the first bytes of the patch,
+    /// and again after an error.
+    pub async fn write_patch<ACTIVE: ReadNorFlash>(
+        &mut self,
+        active: &mut ACTIVE,
+        patcher: &mut Patcher<'_>,
+        mut data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        assert!(patcher.started);
+        const { core::assert!(ACTIVE::READ_SIZE == 1) };
+        self.state.verify_booted().await?;
+
+        if patcher.decoder.header().is_none() {
+            let Some(header) = patcher.decoder.feed_header(&mut data)? else {
+                return Ok(());
+            };
+            if let Err(e) = self.start_patch(active, patcher, header).await {
+                patcher.reset();
+                return Err(e);
+            }
+        }
+
+        while let Some(step) = patcher.decoder.next(&mut data, patcher.buf.len() - patcher.fill)? {
+            let fill = patcher.fill;
+            patcher.fill += match step {
+                Step::Copy { offset, len } => {
+                    active.read(offset, &mut patcher.buf[fill..fill + len]).await?;
+                    len
+                }
+                Step::Insert(bytes) => {
+                    patcher.buf[fill..fill + bytes.len()].copy_from_slice(bytes);
+                    bytes.len()
+                }
+            };
+            if patcher.fill == patcher.buf.len() {
+                self.flush_patch(patcher).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish applying a patch, checking the reconstructed image against the hash in the patch
+    /// header.
+    ///
+    /// Returns the length of the update, which can then be marked as updated. The progress of the
+    /// patch is discarded, whether the check succeeds or not.
+    pub async fn finish_patch(&mut self, patcher: &mut Patcher<'_>) -> Result<u32, FirmwareUpdaterError> {
+        let header = match patcher.decoder.header() {
+            Some(header) if patcher.is_done() => header,
+            _ => return Err(PatchError::Incomplete.into()),
+        };
+        if patcher.fill > 0 {
+            self.flush_patch(patcher).await?;
+        }
+
+        let mut hash = [0; 64];
+        self.hash::<Sha512>(header.target_len, patcher.buf, &mut hash).await?;
+        self.cancel_patch(patcher).await?;
+        if hash != header.target_hash {
+            return Err(PatchError::HashMismatch.into());
+        }
+        Ok(header.target_len)
+    }
+
+    /// Offset of the last dfu sector, holding the progress of a patch.
+    fn checkpoint_area(&self) -> u32 {
+        (self.dfu.capacity() - DFU::ERASE_SIZE) as u32
+    }
+
+    /// Check the active image is the source of the patch, and save the header.
+    async fn start_patch<ACTIVE: ReadNorFlash>(
+        &mut self,
+        active: &mut ACTIVE,
+        patcher: &mut Patcher<'_>,
+        header: crate::PatchHeader,
+    ) -> Result<(), FirmwareUpdaterError> {
+        let area = self.checkpoint_area();
+        if header.source_len as usize > active.capacity() || header.target_len > area {
+            return Err(PatchError::TooLarge.into());
+        }
+
+        let mut digest = Sha512::new();
+        for offset in (0..header.source_len).step_by(patcher.buf.len()) {
+            let len = core::cmp::min((header.source_len - offset) as usize, patcher.buf.len());
+            active.read(offset, &mut patcher.buf[..len]).await?;
+            digest.update(&patcher.buf[..len]);
+        }
+        if digest.finalize().as_slice() != header.source_hash {
+            return Err(PatchError::SourceMismatch.into());
+        }
+
+        let slot = &mut patcher.buf[..header_slot_len(DFU::WRITE_SIZE)];
+        encode_slot(&header.to_bytes(), slot, DFU::WRITE_SIZE);
+        self.dfu.write(area, slot).await?;
+        patcher.interval = checkpoint_interval(header.target_len, DFU::ERASE_SIZE, DFU::WRITE_SIZE);
+        Ok(())
+    }
+
+    /// Write the assembled part of the target image, and save the progress at sector boundaries.
+    async fn flush_patch(&mut self, patcher: &mut Patcher<'_>) -> Result<(), FirmwareUpdaterError> {
+        let produced = patcher.decoder.produced() as usize;
+        let offset = produced - patcher.fill;
+        let len = patcher.fill.div_ceil(DFU::WRITE_SIZE) * DFU::WRITE_SIZE;
+        patcher.buf[patcher.fill..len].fill(STATE_ERASE_VALUE);
+        if offset % DFU::ERASE_SIZE == 0 {
+            self.dfu.erase(offset as u32, (offset + DFU::ERASE_SIZE) as u32).await?;
+        }
+        self.dfu.write(offset as u32, &patcher.buf[..len]).await?;
+        patcher.fill = 0;
+
+        if produced % DFU::ERASE_SIZE == 0
+            && (produced / DFU::ERASE_SIZE) % patcher.interval == 0
+            && patcher.slots < record_slots(DFU::ERASE_SIZE, DFU::WRITE_SIZE)
+        {
+            let record_len = record_slot_len(DFU::WRITE_SIZE);
+            let offset =
+                self.checkpoint_area() as usize + header_slot_len(DFU::WRITE_SIZE) + patcher.slots * record_len;
+            let slot = &mut patcher.buf[..record_len];
+            encode_slot(&encode_record(&patcher.decoder), slot, DFU::WRITE_SIZE);
+            self.dfu.write(offset as u32, slot).await?;
+            patcher.slots += 1;
+        }
+        Ok(())
+    }
+
+    /// Mark to trigger decompression of a compressed update on next boot.
+    ///
+    /// The update must be an LZ4 frame with block checksums, as created by `lz4 -BX`, written to the
+    /// dfu partition as is, for example with [`write_firmware`](Self::write_firmware). The frame is
+    /// read back with `buf` and checked against its block checksums and content size before being
+    /// marked, as the bootloader overwrites the active image while decompressing it.
+    ///
+    /// Returns the length of the decompressed update. `buf` must be a multiple of the read size of
+    /// the dfu partition, and follow its alignment rules.
+    ///
+    /// Requires the `compression` feature, in the bootloader as well. See
+    /// [`BootLoader::prepare_boot`](crate::BootLoader::prepare_boot).
+    #[cfg(all(any(test, feature = "compression"), not(feature = "_verify")))]
+    pub async fn mark_updated_compressed(&mut self, buf: &mut [u8]) -> Result<u32, FirmwareUpdaterError> {
+        if buf.is_empty() || buf.len() % DFU::READ_SIZE != 0 {
+            return Err(DecompressError::BadBuffer.into());
+        }
+        let mut decoder = Decoder::new();
+        let mut offset = 0;
+        while !decoder.is_done() {
+            let len = buf.len().min(self.dfu.capacity() - offset);
+            if len == 0 {
+                break;
+            }
+            self.dfu.read(offset as u32, &mut buf[..len]).await?;
+            offset += len;
+            decoder.skip(&buf[..len])?;
+        }
+        let len = decoder.finish()?;
+        self.state.set_magic(DECOMPRESS_MAGIC, None).await?;
+        Ok(len)
+    }
+
     /// Prepare for an incoming DFU update by erasing the entire DFU area and
     /// returning its `Partition`.
     ///
@@ -274,6 +548,7 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
 pub struct FirmwareState<'d, STATE> {
     state: STATE,
     aligned: &'d mut [u8],
+    counter_page: bool,
 }
 
 impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
@@ -295,13 +570,26 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
     /// and follow the alignment rules for the flash being read from and written to.
     pub fn new(state: STATE, aligned: &'d mut [u8]) -> Self {
         assert_eq!(aligned.len(), STATE::WRITE_SIZE.max(STATE::READ_SIZE));
-        Self { state, aligned }
+        Self {
+            state,
+            aligned,
+            counter_page: false,
+        }
+    }
+
+    /// Keep the last erase page of the state partition when changing the state.
+    ///
+    /// That page holds the anti-rollback counter of a bootloader [with an image
+    /// policy](crate::BootLoader::with_image_policy), and must be kept to refuse older updates.
+    pub fn with_counter_page(mut self) -> Self {
+        self.counter_page = true;
+        self
     }
 
     // Make sure we are running a booted firmware to avoid reverting to a bad state.
     async fn verify_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
         let state = self.get_state().await?;
-        if state == State::Boot || state == State::DfuDetach || state == State::Revert {
+        if state == State::Boot || state == State::DfuDetach || state == State::Revert || state == State::Rejected {
             Ok(())
         } else {
             Err(FirmwareUpdaterError::BadState)
@@ -320,23 +608,96 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
 
     /// Mark to trigger firmware swap on next boot.
     pub async fn mark_updated(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(SWAP_MAGIC).await
+        self.set_magic(SWAP_MAGIC, None).await
+    }
+
+    /// Mark to trigger firmware swap on next boot, of an update encrypted with `iv`.
+    ///
+    /// The initialization vector is stored in the state partition, for a bootloader decrypting
+    /// updates [while swapping them in](crate::BootLoader::with_encryption).
+    pub async fn mark_updated_encrypted(&mut self, iv: &[u8; IV_LEN]) -> Result<(), FirmwareUpdaterError> {
+        self.set_magic(SWAP_MAGIC, Some(iv)).await
     }
 
     /// Mark to trigger USB DFU on next boot.
     pub async fn mark_dfu(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(DFU_DETACH_MAGIC).await
+        self.set_magic(DFU_DETACH_MAGIC, None).await
     }
 
     /// Mark firmware boot successful and stop rollback on reset.
     pub async fn mark_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(BOOT_MAGIC).await
+        self.set_magic(BOOT_MAGIC, None).await
     }
 
-    async fn set_magic(&mut self, magic: u8) -> Result<(), FirmwareUpdaterError> {
+    /// Mark firmware boot successful if `health_check` completes with `true`, returning whether it did.
+    ///
+    /// If the check fails, the state is left as is: an update being tried gets reverted once it
+    /// runs out of [trial boots](crate::BootLoader::with_trial_boots).
+    pub async fn mark_booted_if(
+        &mut self,
+        health_check: impl core::future::Future<Output = bool>,
+    ) -> Result<bool, FirmwareUpdaterError> {
+        if !health_check.await {
+            return Ok(false);
+        }
+        self.mark_booted().await?;
+        Ok(true)
+    }
+
+    /// Get the number of boots left to mark an update as booted before it is reverted.
+    ///
+    /// Returns `None` if no update is being tried, and `Some(0)` if the update will be reverted on
+    /// the next boot unless it is marked as booted now.
+    pub async fn remaining_boot_attempts(&mut self) -> Result<Option<u8>, FirmwareUpdaterError> {
+        if self.get_state().await? != State::Swap {
+            return Ok(None);
+        }
+
+        let capacity = self.state.capacity();
+        let mut remaining = 0;
+        for index in 0..MAX_TRIAL_BOOTS as usize {
+            let offset = attempt_offset(
+                capacity,
+                STATE::ERASE_SIZE,
+                self.counter_page,
+                STATE::WRITE_SIZE,
+                STATE::READ_SIZE,
+                index,
+            );
+            self.state.read(offset as u32, self.aligned).await?;
+            match decode_attempt(self.aligned) {
+                Some(r) => remaining = r,
+                None => break,
+            }
+        }
+        Ok(Some(remaining))
+    }
+
+    /// Write the initialization vector of an encrypted update, preceding the copy of the anti-rollback
+    /// counter.
+    async fn write_iv(&mut self, iv: &[u8; IV_LEN]) -> Result<(), FirmwareUpdaterError> {
+        let offset = iv_offset(
+            self.state.capacity(),
+            STATE::ERASE_SIZE,
+            self.counter_page,
+            STATE::WRITE_SIZE,
+            STATE::READ_SIZE,
+        );
+        for pos in (0..iv_size(STATE::WRITE_SIZE, STATE::READ_SIZE)).step_by(self.aligned.len()) {
+            self.aligned.fill(0);
+            if pos < IV_LEN {
+                let len = self.aligned.len().min(IV_LEN - pos);
+                self.aligned[..len].copy_from_slice(&iv[pos..pos + len]);
+            }
+            self.state.write((offset + pos) as u32, self.aligned).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_magic(&mut self, magic: u8, iv: Option<&[u8; IV_LEN]>) -> Result<(), FirmwareUpdaterError> {
         self.state.read(0, &mut self.aligned).await?;
 
-        if self.aligned[..STATE::WRITE_SIZE].iter().any(|&b| b != magic) {
+        if iv.is_some() || self.aligned[..STATE::WRITE_SIZE].iter().any(|&b| b != magic) {
             // Read progress validity
             if STATE::READ_SIZE <= 2 * STATE::WRITE_SIZE {
                 self.state.read(STATE::WRITE_SIZE as u32, &mut self.aligned).await?;
@@ -357,8 +718,12 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
                     .await?;
             }
 
-            // Clear magic and progress
-            self.state.erase(0, self.state.capacity() as u32).await?;
+            // Clear magic and progress, keeping the anti-rollback counter page if there is one
+            let end = state_end(self.state.capacity(), STATE::ERASE_SIZE, self.counter_page);
+            self.state.erase(0, end as u32).await?;
+            if let Some(iv) = iv {
+                self.write_iv(iv).await?;
+            }
 
             // Set magic
             self.aligned.fill(magic);
@@ -370,6 +735,9 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
 
 #[cfg(test)]
 mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
     use embassy_embedded_hal::flash::partition::Partition;
     use embassy_sync::blocking_mutex::raw::NoopRawMutex;
     use embassy_sync::mutex::Mutex;
@@ -377,7 +745,10 @@ mod tests {
     use sha1::{Digest, Sha1};
 
     use super::*;
+    use crate::compression::tests::{compress, compressible};
+    use crate::delta::tests::firmware;
     use crate::mem_flash::MemFlash;
+    use crate::{generate_patch, PATCH_HEADER_LEN};
 
     #[test]
     fn can_verify_sha1() {
@@ -470,4 +841,147 @@ mod tests {
 
         assert_eq!(Sha1::digest(update).as_slice(), hash);
     }
+
+    type PatchPartition<'a> = Partition<'a, NoopRawMutex, MemFlash<131072, 4096, 8>>;
+
+    struct PatchTest {
+        flash: Mutex<NoopRawMutex, MemFlash<131072, 4096, 8>>,
+        source: Vec<u8>,
+        target: Vec<u8>,
+    }
+
+    impl PatchTest {
+        fn new() -> Self {
+            let source = firmware(1, 30000);
+            let mut target = source.clone();
+            for i in (0..target.len()).step_by(2000) {
+                target[i] ^= 0xff;
+            }
+            target.splice(9000..9000, firmware(2, 10000));
+            target.truncate(32768);
+
+            let mut flash = MemFlash::<131072, 4096, 8>::default();
+            flash.program(4096, &source).unwrap();
+            Self {
+                flash: Mutex::new(flash),
+                source,
+                target,
+            }
+        }
+
+        fn partitions(
+            &self,
+        ) -> (
+            PatchPartition<'_>,
+            FirmwareUpdaterConfig<PatchPartition<'_>, PatchPartition<'_>>,
+        ) {
+            let active = Partition::new(&self.flash, 4096, 32768);
+            let dfu = Partition::new(&self.flash, 65536, 36864);
+            let state = Partition::new(&self.flash, 0, 4096);
+            (active, FirmwareUpdaterConfig { dfu, state })
+        }
+
+        fn dfu_contents(&self) -> Vec<u8> {
+            let mut dfu = vec![0; self.target.len()];
+            let mut flash = block_on(self.flash.lock());
+            embedded_storage::nor_flash::ReadNorFlash::read(&mut *flash, 65536, &mut dfu).unwrap();
+            dfu
+        }
+    }
+
+    #[test]
+    fn can_apply_patch() {
+        let test = PatchTest::new();
+        let patch = generate_patch(&test.source, &test.target);
+        let (mut active, config) = test.partitions();
+        let mut aligned = [0; 8];
+        let mut updater = FirmwareUpdater::new(config, &mut aligned);
+        let mut buf = [0; 512];
+        let mut patcher = Patcher::new(&mut buf);
+
+        assert_eq!(block_on(updater.resume_patch(&mut patcher)).unwrap(), 0);
+        for chunk in patch.chunks(100) {
+            block_on(updater.write_patch(&mut active, &mut patcher, chunk)).unwrap();
+        }
+        let len = block_on(updater.finish_patch(&mut patcher)).unwrap();
+        assert_eq!(len as usize, test.target.len());
+        assert_eq!(test.dfu_contents(), test.target);
+
+        // A patch for another image is refused
+        let patch = generate_patch(&test.target, &test.source);
+        assert_eq!(block_on(updater.resume_patch(&mut patcher)).unwrap(), 0);
+        assert!(matches!(
+            block_on(updater.write_patch(&mut active, &mut patcher, &patch)),
+            Err(FirmwareUpdaterError::Patch(PatchError::SourceMismatch))
+        ));
+    }
+
+    #[test]
+    fn can_resume_patch() {
+        let test = PatchTest::new();
+        let patch = generate_patch(&test.source, &test.target);
+
+        // Apply part of the patch, then reset
+        {
+            let (mut active, config) = test.partitions();
+            let mut aligned = [0; 8];
+            let mut updater = FirmwareUpdater::new(config, &mut aligned);
+            let mut buf = [0; 512];
+            let mut patcher = Patcher::new(&mut buf);
+            assert_eq!(block_on(updater.resume_patch(&mut patcher)).unwrap(), 0);
+            for chunk in patch[..patch.len() * 2 / 3].chunks(100) {
+                block_on(updater.write_patch(&mut active, &mut patcher, chunk)).unwrap();
+            }
+        }
+
+        let (mut active, config) = test.partitions();
+        let mut aligned = [0; 8];
+        let mut updater = FirmwareUpdater::new(config, &mut aligned);
+        let mut buf = [0; 512];
+        let mut patcher = Patcher::new(&mut buf);
+        let offset = block_on(updater.resume_patch(&mut patcher)).unwrap() as usize;
+        assert!(offset > PATCH_HEADER_LEN && offset < patch.len() * 2 / 3);
+        assert_eq!(patcher.target_offset() % 4096, 0);
+        assert_eq!(patcher.header().unwrap().target_len as usize, test.target.len());
+
+        for chunk in patch[offset..].chunks(100) {
+            block_on(updater.write_patch(&mut active, &mut patcher, chunk)).unwrap();
+        }
+        block_on(updater.finish_patch(&mut patcher)).unwrap();
+        assert_eq!(test.dfu_contents(), test.target);
+    }
+
+    #[test]
+    fn can_mark_updated_compressed() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 4096, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+
+        let update = compressible(50000);
+        let mut frame = compress(&update);
+        frame.resize(frame.len().next_multiple_of(8), 0xff);
+        let mut buf = [0; 100];
+        for (i, chunk) in frame.chunks(1024).enumerate() {
+            block_on(updater.write_firmware(i * 1024, chunk)).unwrap();
+        }
+        assert_eq!(
+            block_on(updater.mark_updated_compressed(&mut buf)).unwrap() as usize,
+            update.len()
+        );
+        assert_eq!(block_on(updater.get_state()).unwrap(), State::Swap);
+
+        // A corrupted update is not marked.
+        block_on(updater.mark_booted()).unwrap();
+        let mid = frame.len() / 2;
+        frame[mid] ^= 1;
+        block_on(updater.erase_dfu(0, 65536)).unwrap();
+        block_on(updater.write_firmware(0, &frame)).unwrap();
+        assert!(matches!(
+            block_on(updater.mark_updated_compressed(&mut buf)),
+            Err(FirmwareUpdaterError::Decompress(_))
+        ));
+        assert_eq!(block_on(updater.get_state()).unwrap(), State::Boot);
+    }
 }
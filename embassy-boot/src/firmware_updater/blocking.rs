@@ -3,10 +3,22 @@ use digest::Digest;
 use embassy_embedded_hal::flash::partition::BlockingPartition;
 #[cfg(target_os = "none")]
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use sha2::Sha512;
 
 use super::FirmwareUpdaterConfig;
-use crate::{FirmwareUpdaterError, State, BOOT_MAGIC, DFU_DETACH_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC};
+#[cfg(any(test, feature = "compression"))]
+use crate::compression::Decoder;
+use crate::delta::{
+    checkpoint_interval, decode_header, decode_record, encode_record, encode_slot, header_slot_len, record_slot_len,
+    record_slots, slot_valid, Step,
+};
+use crate::{
+    attempt_offset, decode_attempt, iv_offset, iv_size, state_end, FirmwareUpdaterError, PatchError, Patcher, State,
+    BOOT_MAGIC, DFU_DETACH_MAGIC, IV_LEN, MAX_TRIAL_BOOTS, STATE_ERASE_VALUE, SWAP_MAGIC,
+};
+#[cfg(any(test, feature = "compression"))]
+use crate::{DecompressError, DECOMPRESS_MAGIC};
 
 /// Blocking FirmwareUpdater is an application API for interacting with the BootLoader without the ability to
 /// 'mess up' the internal bootloader state
@@ -96,6 +108,13 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> BlockingFirmwareUpdater<'d, DFU, STATE>
         }
     }
 
+    /// Keep the last erase page of the state partition when changing the state, see
+    /// [`BlockingFirmwareState::with_counter_page`].
+    pub fn with_counter_page(mut self) -> Self {
+        self.state = self.state.with_counter_page();
+        self
+    }
+
     /// Obtain the current state.
     ///
     /// This is useful to check if the bootloader has just done a swap, in order
@@ -207,12 +226,38 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> BlockingFirmwareUpdater<'d, DFU, STATE>
         Ok(())
     }
 
+    /// Size of the DFU partition.
+    pub fn dfu_capacity(&self) -> usize {
+        self.dfu.capacity()
+    }
+
+    /// Erase the DFU partition from `from` to `to`, which must be aligned to its erase size.
+    ///
+    /// Sectors erased this way are not erased again by [`write_firmware`](Self::write_firmware)
+    /// when it continues in the last of them.
+    pub fn erase_dfu(&mut self, from: u32, to: u32) -> Result<(), FirmwareUpdaterError> {
+        self.state.verify_booted()?;
+        self.dfu.erase(from, to)?;
+        if to > from {
+            self.last_erased_dfu_sector_index = Some((to as usize - 1) / DFU::ERASE_SIZE);
+        }
+        Ok(())
+    }
+
     /// Mark to trigger firmware swap on next boot.
     #[cfg(not(feature = "_verify"))]
     pub fn mark_updated(&mut self) -> Result<(), FirmwareUpdaterError> {
         self.state.mark_updated()
     }
 
+    /// Mark to trigger firmware swap on next boot, of an update encrypted with `iv`.
+    ///
+    /// See [`BlockingFirmwareState::mark_updated_encrypted`].
+    #[cfg(not(feature = "_verify"))]
+    pub fn mark_updated_encrypted(&mut self, iv: &[u8; IV_LEN]) -> Result<(), FirmwareUpdaterError> {
+        self.state.mark_updated_encrypted(iv)
+    }
+
     /// Mark to trigger USB DFU device on next boot.
     pub fn mark_dfu(&mut self) -> Result<(), FirmwareUpdaterError> {
         self.state.verify_booted()?;
@@ -224,6 +269,20 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> BlockingFirmwareUpdater<'d, DFU, STATE>
         self.state.mark_booted()
     }
 
+    /// Mark firmware boot successful if `health_check` returns `true`, returning whether it did.
+    ///
+    /// See [`FirmwareState::mark_booted_if`](crate::FirmwareState::mark_booted_if).
+    pub fn mark_booted_if(&mut self, health_check: impl FnOnce() -> bool) -> Result<bool, FirmwareUpdaterError> {
+        self.state.mark_booted_if(health_check)
+    }
+
+    /// Get the number of boots left to mark an update as booted before it is reverted.
+    ///
+    /// See [`FirmwareState::remaining_boot_attempts`](crate::FirmwareState::remaining_boot_attempts).
+    pub fn remaining_boot_attempts(&mut self) -> Result<Option<u8>, FirmwareUpdaterError> {
+        self.state.remaining_boot_attempts()
+    }
+
     /// Writes firmware data to the device.
     ///
     /// This function writes the given data to the firmware area starting at the specified offset.
@@ -290,6 +349,218 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> BlockingFirmwareUpdater<'d, DFU, STATE>
         Ok(())
     }
 
+    /// Start applying a patch with [`write_patch`](Self::write_patch), or resume after a reset.
+    ///
+    /// Returns the offset in the patch to continue from. If no patch was in progress, this is 0 and
+    /// the progress of any previous patch is discarded. Use [`Patcher::header`] to check that the
+    /// patch being resumed is the expected one, or [`cancel_patch`](Self::cancel_patch) to start over.
+    pub fn resume_patch(&mut self, patcher: &mut Patcher<'_>) -> Result<u32, FirmwareUpdaterError> {
+        self.state.verify_booted()?;
+
+        let header_len = header_slot_len(DFU::WRITE_SIZE);
+        let record_len = record_slot_len(DFU::WRITE_SIZE);
+        assert!(patcher.buf.len() >= header_len);
+        assert_eq!(patcher.buf.len() % DFU::WRITE_SIZE, 0);
+        assert_eq!(DFU::ERASE_SIZE % patcher.buf.len(), 0);
+
+        patcher.reset();
+        let area = self.checkpoint_area();
+        self.dfu.read(area, &mut patcher.buf[..header_len])?;
+        let slot = &patcher.buf[..header_len];
+        if !slot_valid(slot, DFU::WRITE_SIZE) || !decode_header(&mut patcher.decoder, slot) {
+            self.cancel_patch(patcher)?;
+            return Ok(0);
+        }
+
+        let target_len = unwrap!(patcher.decoder.header()).target_len;
+        patcher.interval = checkpoint_interval(target_len, DFU::ERASE_SIZE, DFU::WRITE_SIZE);
+        while patcher.slots < record_slots(DFU::ERASE_SIZE, DFU::WRITE_SIZE) {
+            let offset = area + (header_len + patcher.slots * record_len) as u32;
+            let slot = &mut patcher.buf[..record_len];
+            self.dfu.read(offset, slot)?;
+            if slot.iter().all(|&b| b == STATE_ERASE_VALUE) {
+                break;
+            }
+            // Records torn by a reset are skipped
+            if slot_valid(slot, DFU::WRITE_SIZE) {
+                decode_record(&mut patcher.decoder, slot);
+            }
+            patcher.slots += 1;
+        }
+        Ok(patcher.decoder.consumed())
+    }
+
+    /// Discard the progress of a patch, to start applying it again from the start.
+    pub fn cancel_patch(&mut self, patcher: &mut Patcher<'_>) -> Result<(), FirmwareUpdaterError> {
+        let area = self.checkpoint_area();
+        self.dfu.erase(area, area + DFU::ERASE_SIZE as u32)?;
+        patcher.reset();
+        Ok(())
+    }
+
+    /// Apply the next bytes of a patch, reading the source image from `active` and writing the
+    /// reconstructed image to the dfu partition.
+    ///
+    /// The source image must be readable at any offset of `active`, as with internal flash: a read
+    /// size other than 1 fails to compile.
+    /// [`resume_patch`](Self::resume_patch) must be called before the first bytes of the patch,
+    /// and again after an error.
+    pub fn write_patch<ACTIVE: ReadNorFlash>(
+        &mut self,
+        active: &mut ACTIVE,
+        patcher: &mut Patcher<'_>,
+        mut data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        assert!(patcher.started);
+        const { core::assert!(ACTIVE::READ_SIZE == 1) };
+        self.state.verify_booted()?;
+
+        if patcher.decoder.header().is_none() {
+            let Some(header) = patcher.decoder.feed_header(&mut data)? else {
+                return Ok(());
+            };
+            if let Err(e) = self.start_patch(active, patcher, header) {
+                patcher.reset();
+                return Err(e);
+            }
+        }
+
+        while let Some(step) = patcher.decoder.next(&mut data, patcher.buf.len() - patcher.fill)? {
+            let fill = patcher.fill;
+            patcher.fill += match step {
+                Step::Copy { offset, len } => {
+                    active.read(offset, &mut patcher.buf[fill..fill + len])?;
+                    len
+                }
+                Step::Insert(bytes) => {
+                    patcher.buf[fill..fill + bytes.len()].copy_from_slice(bytes);
+                    bytes.len()
+                }
+            };
+            if patcher.fill == patcher.buf.len() {
+                self.flush_patch(patcher)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish applying a patch, checking the reconstructed image against the hash in the patch
+    /// header.
+    ///
+    /// Returns the length of the update, which can then be marked as updated. The progress of the
+    /// patch is discarded, whether the check succeeds or not.
+    pub fn finish_patch(&mut self, patcher: &mut Patcher<'_>) -> Result<u32, FirmwareUpdaterError> {
+        let header = match patcher.decoder.header() {
+            Some(header) if patcher.is_done() => header,
+            _ => return Err(PatchError::Incomplete.into()),
+        };
+        if patcher.fill > 0 {
+            self.flush_patch(patcher)?;
+        }
+
+        let mut hash = [0; 64];
+        self.hash::<Sha512>(header.target_len, patcher.buf, &mut hash)?;
+        self.cancel_patch(patcher)?;
+        if hash != header.target_hash {
+            return Err(PatchError::HashMismatch.into());
+        }
+        Ok(header.target_len)
+    }
+
+    /// Offset of the last dfu sector, holding the progress of a patch.
+    fn checkpoint_area(&self) -> u32 {
+        (self.dfu.capacity() - DFU::ERASE_SIZE) as u32
+    }
+
+    /// Check the active image is the source of the patch, and save the header.
+    fn start_patch<ACTIVE: ReadNorFlash>(
+        &mut self,
+        active: &mut ACTIVE,
+        patcher: &mut Patcher<'_>,
+        header: crate::PatchHeader,
+    ) -> Result<(), FirmwareUpdaterError> {
+        let area = self.checkpoint_area();
+        if header.source_len as usize > active.capacity() || header.target_len > area {
+            return Err(PatchError::TooLarge.into());
+        }
+
+        let mut digest = Sha512::new();
+        for offset in (0..header.source_len).step_by(patcher.buf.len()) {
+            let len = core::cmp::min((header.source_len - offset) as usize, patcher.buf.len());
+            active.read(offset, &mut patcher.buf[..len])?;
+            digest.update(&patcher.buf[..len]);
+        }
+        if digest.finalize().as_slice() != header.source_hash {
+            return Err(PatchError::SourceMismatch.into());
+        }
+
+        let slot = &mut patcher.buf[..header_slot_len(DFU::WRITE_SIZE)];
+        encode_slot(&header.to_bytes(), slot, DFU::WRITE_SIZE);
+        self.dfu.write(area, slot)?;
+        patcher.interval = checkpoint_interval(header.target_len, DFU::ERASE_SIZE, DFU::WRITE_SIZE);
+        Ok(())
+    }
+
+    /// Write the assembled part of the target image, and save the progress at sector boundaries.
+    fn flush_patch(&mut self, patcher: &mut Patcher<'_>) -> Result<(), FirmwareUpdaterError> {
+        let produced = patcher.decoder.produced() as usize;
+        let offset = produced - patcher.fill;
+        let len = patcher.fill.div_ceil(DFU::WRITE_SIZE) * DFU::WRITE_SIZE;
+        patcher.buf[patcher.fill..len].fill(STATE_ERASE_VALUE);
+        if offset % DFU::ERASE_SIZE == 0 {
+            self.dfu.erase(offset as u32, (offset + DFU::ERASE_SIZE) as u32)?;
+        }
+        self.dfu.write(offset as u32, &patcher.buf[..len])?;
+        patcher.fill = 0;
+
+        if produced % DFU::ERASE_SIZE == 0
+            && (produced / DFU::ERASE_SIZE) % patcher.interval == 0
+            && patcher.slots < record_slots(DFU::ERASE_SIZE, DFU::WRITE_SIZE)
+        {
+            let record_len = record_slot_len(DFU::WRITE_SIZE);
+            let offset =
+                self.checkpoint_area() as usize + header_slot_len(DFU::WRITE_SIZE) + patcher.slots * record_len;
+            let slot = &mut patcher.buf[..record_len];
+            encode_slot(&encode_record(&patcher.decoder), slot, DFU::WRITE_SIZE);
+            self.dfu.write(offset as u32, slot)?;
+            patcher.slots += 1;
+        }
+        Ok(())
+    }
+
+    /// Mark to trigger decompression of a compressed update on next boot.
+    ///
+    /// The update must be an LZ4 frame with block checksums, as created by `lz4 -BX`, written to the
+    /// dfu partition as is, for example with [`write_firmware`](Self::write_firmware). The frame is
+    /// read back with `buf` and checked against its block checksums and content size before being
+    /// marked, as the bootloader overwrites the active image while decompressing it.
+    ///
+    /// Returns the length of the decompressed update. `buf` must be a multiple of the read size of
+    /// the dfu partition, and follow its alignment rules.
+    ///
+    /// Requires the `compression` feature, in the bootloader as well. See
+    /// [`BootLoader::prepare_boot`](crate::BootLoader::prepare_boot).
+    #[cfg(all(any(test, feature = "compression"), not(feature = "_verify")))]
+    pub fn mark_updated_compressed(&mut self, buf: &mut [u8]) -> Result<u32, FirmwareUpdaterError> {
+        if buf.is_empty() || buf.len() % DFU::READ_SIZE != 0 {
+            return Err(DecompressError::BadBuffer.into());
+        }
+        let mut decoder = Decoder::new();
+        let mut offset = 0;
+        while !decoder.is_done() {
+            let len = buf.len().min(self.dfu.capacity() - offset);
+            if len == 0 {
+                break;
+            }
+            self.dfu.read(offset as u32, &mut buf[..len])?;
+            offset += len;
+            decoder.skip(&buf[..len])?;
+        }
+        let len = decoder.finish()?;
+        self.state.set_magic(DECOMPRESS_MAGIC, None)?;
+        Ok(len)
+    }
+
     /// Prepare for an incoming DFU update by erasing the entire DFU area and
     /// returning its `Partition`.
     ///
@@ -309,6 +580,7 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> BlockingFirmwareUpdater<'d, DFU, STATE>
 pub struct BlockingFirmwareState<'d, STATE> {
     state: STATE,
     aligned: &'d mut [u8],
+    counter_page: bool,
 }
 
 impl<'d, STATE: NorFlash> BlockingFirmwareState<'d, STATE> {
@@ -330,13 +602,26 @@ impl<'d, STATE: NorFlash> BlockingFirmwareState<'d, STATE> {
     /// and written to.
     pub fn new(state: STATE, aligned: &'d mut [u8]) -> Self {
         assert_eq!(aligned.len(), STATE::WRITE_SIZE);
-        Self { state, aligned }
+        Self {
+            state,
+            aligned,
+            counter_page: false,
+        }
+    }
+
+    /// Keep the last erase page of the state partition when changing the state.
+    ///
+    /// That page holds the anti-rollback counter of a bootloader [with an image
+    /// policy](crate::BootLoader::with_image_policy), and must be kept to refuse older updates.
+    pub fn with_counter_page(mut self) -> Self {
+        self.counter_page = true;
+        self
     }
 
     // Make sure we are running a booted firmware to avoid reverting to a bad state.
     fn verify_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
         let state = self.get_state()?;
-        if state == State::Boot || state == State::DfuDetach || state == State::Revert {
+        if state == State::Boot || state == State::DfuDetach || state == State::Revert || state == State::Rejected {
             Ok(())
         } else {
             Err(FirmwareUpdaterError::BadState)
@@ -355,23 +640,93 @@ impl<'d, STATE: NorFlash> BlockingFirmwareState<'d, STATE> {
 
     /// Mark to trigger firmware swap on next boot.
     pub fn mark_updated(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(SWAP_MAGIC)
+        self.set_magic(SWAP_MAGIC, None)
+    }
+
+    /// Mark to trigger firmware swap on next boot, of an update encrypted with `iv`.
+    ///
+    /// The initialization vector is stored in the state partition, for a bootloader decrypting
+    /// updates [while swapping them in](crate::BootLoader::with_encryption).
+    pub fn mark_updated_encrypted(&mut self, iv: &[u8; IV_LEN]) -> Result<(), FirmwareUpdaterError> {
+        self.set_magic(SWAP_MAGIC, Some(iv))
     }
 
     /// Mark to trigger USB DFU on next boot.
     pub fn mark_dfu(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(DFU_DETACH_MAGIC)
+        self.set_magic(DFU_DETACH_MAGIC, None)
     }
 
     /// Mark firmware boot successful and stop rollback on reset.
     pub fn mark_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(BOOT_MAGIC)
+        self.set_magic(BOOT_MAGIC, None)
+    }
+
+    /// Mark firmware boot successful if `health_check` returns `true`, returning whether it did.
+    ///
+    /// If the check fails, the state is left as is: an update being tried gets reverted once it
+    /// runs out of [trial boots](crate::BootLoader::with_trial_boots).
+    pub fn mark_booted_if(&mut self, health_check: impl FnOnce() -> bool) -> Result<bool, FirmwareUpdaterError> {
+        if !health_check() {
+            return Ok(false);
+        }
+        self.mark_booted()?;
+        Ok(true)
+    }
+
+    /// Get the number of boots left to mark an update as booted before it is reverted.
+    ///
+    /// Returns `None` if no update is being tried, and `Some(0)` if the update will be reverted on
+    /// the next boot unless it is marked as booted now.
+    pub fn remaining_boot_attempts(&mut self) -> Result<Option<u8>, FirmwareUpdaterError> {
+        if self.get_state()? != State::Swap {
+            return Ok(None);
+        }
+
+        let capacity = self.state.capacity();
+        let mut remaining = 0;
+        for index in 0..MAX_TRIAL_BOOTS as usize {
+            let offset = attempt_offset(
+                capacity,
+                STATE::ERASE_SIZE,
+                self.counter_page,
+                STATE::WRITE_SIZE,
+                STATE::READ_SIZE,
+                index,
+            );
+            self.state.read(offset as u32, self.aligned)?;
+            match decode_attempt(self.aligned) {
+                Some(r) => remaining = r,
+                None => break,
+            }
+        }
+        Ok(Some(remaining))
     }
 
-    fn set_magic(&mut self, magic: u8) -> Result<(), FirmwareUpdaterError> {
+    /// Write the initialization vector of an encrypted update, preceding the copy of the anti-rollback
+    /// counter.
+    fn write_iv(&mut self, iv: &[u8; IV_LEN]) -> Result<(), FirmwareUpdaterError> {
+        let offset = iv_offset(
+            self.state.capacity(),
+            STATE::ERASE_SIZE,
+            self.counter_page,
+            STATE::WRITE_SIZE,
+            STATE::READ_SIZE,
+        );
+        for pos in (0..iv_size(STATE::WRITE_SIZE, STATE::READ_SIZE)).step_by(self.aligned.len()) {
+            self.aligned.fill(0);
+            if pos < IV_LEN {
+                let len = self.aligned.len().min(IV_LEN - pos);
+                self.aligned[..len].copy_from_slice(&iv[pos..pos + len]);
+            }
+            self.state.write((offset + pos) as u32, self.aligned)?;
+        }
+        Ok(())
+    }
+
+    fn set_magic(&mut self, magic: u8, iv: Option<&[u8; IV_LEN]>) -> Result<(), FirmwareUpdaterError> {
         self.state.read(0, &mut self.aligned)?;
 
-        if self.aligned.iter().any(|&b| b != magic) {
+        if iv.is_some() || self.aligned.iter().any(|&b| b != magic) {
             // Read progress validity
             self.state.read(STATE::WRITE_SIZE as u32, &mut self.aligned)?;
 
@@ -383,8 +738,12 @@ impl<'d, STATE: NorFlash> BlockingFirmwareState<'d, STATE> {
                 self.state.write(STATE::WRITE_SIZE as u32, &self.aligned)?;
             }
 
-            // Clear magic and progress
-            self.state.erase(0, self.state.capacity() as u32)?;
+            // Clear magic and progress, keeping the anti-rollback counter page if there is one
+            let end = state_end(self.state.capacity(), STATE::ERASE_SIZE, self.counter_page);
+            self.state.erase(0, end as u32)?;
+            if let Some(iv) = iv {
+                self.write_iv(iv)?;
+            }
 
             // Set magic
             self.aligned.fill(magic);
@@ -404,6 +763,9 @@ mod tests {
     use sha1::{Digest, Sha1};
 
     use super::*;
+    use crate::compression::tests::{compress, compressible};
+    use crate::delta::tests::firmware;
+    use crate::generate_patch;
     use crate::mem_flash::MemFlash;
 
     #[test]
@@ -505,4 +867,80 @@ mod tests {
 
         assert_eq!(Sha1::digest(update).as_slice(), hash);
     }
+
+    #[test]
+    fn can_apply_patch() {
+        let source = firmware(1, 12000);
+        let mut target = source.clone();
+        target.splice(3000..3000, firmware(2, 5000));
+        let patch = generate_patch(&source, &target);
+
+        let mut flash = MemFlash::<131072, 4096, 8>::default();
+        flash.program(4096, &source).unwrap();
+        let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(flash));
+        let state = BlockingPartition::new(&flash, 0, 4096);
+        let mut active = BlockingPartition::new(&flash, 4096, 20480);
+        let dfu = BlockingPartition::new(&flash, 65536, 24576);
+        let mut aligned = [0; 8];
+        let mut updater = BlockingFirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut buf = [0; 1024];
+        let mut patcher = Patcher::new(&mut buf);
+
+        assert_eq!(updater.resume_patch(&mut patcher).unwrap(), 0);
+        assert!(matches!(
+            updater.finish_patch(&mut patcher),
+            Err(FirmwareUpdaterError::Patch(PatchError::Incomplete))
+        ));
+        for chunk in patch.chunks(64) {
+            updater.write_patch(&mut active, &mut patcher, chunk).unwrap();
+        }
+        assert_eq!(updater.finish_patch(&mut patcher).unwrap() as usize, target.len());
+
+        let mut dfu = [0; 17000];
+        updater.read_dfu(0, &mut dfu).unwrap();
+        assert_eq!(&dfu[..], &target[..]);
+    }
+
+    #[test]
+    fn can_erase_dfu() {
+        let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(MemFlash::<131072, 4096, 8>::default()));
+        let state = BlockingPartition::new(&flash, 0, 4096);
+        let dfu = BlockingPartition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+        let mut updater = BlockingFirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        assert_eq!(updater.dfu_capacity(), 65536);
+
+        updater.write_firmware(0, &[0x11; 8192]).unwrap();
+        updater.erase_dfu(4096, 8192).unwrap();
+        // The erased sector is written in two parts without being erased in between.
+        updater.write_firmware(4096, &[0x22; 2048]).unwrap();
+        updater.write_firmware(6144, &[0x33; 2048]).unwrap();
+
+        let mut dfu = [0; 8192];
+        updater.read_dfu(0, &mut dfu).unwrap();
+        assert!(dfu[..4096].iter().all(|&b| b == 0x11));
+        assert!(dfu[4096..6144].iter().all(|&b| b == 0x22));
+        assert!(dfu[6144..].iter().all(|&b| b == 0x33));
+    }
+
+    #[test]
+    fn can_mark_updated_compressed() {
+        let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(MemFlash::<131072, 4096, 8>::default()));
+        let state = BlockingPartition::new(&flash, 0, 4096);
+        let dfu = BlockingPartition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+        let mut updater = BlockingFirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+
+        let update = compressible(30000);
+        let mut frame = compress(&update);
+        frame.resize(frame.len().next_multiple_of(8), 0xff);
+        updater.write_firmware(0, &frame).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(updater.mark_updated_compressed(&mut buf).unwrap() as usize, update.len());
+        assert_eq!(updater.get_state().unwrap(), State::Swap);
+        assert!(matches!(
+            updater.mark_updated_compressed(&mut []),
+            Err(FirmwareUpdaterError::Decompress(DecompressError::BadBuffer))
+        ));
+    }
 }
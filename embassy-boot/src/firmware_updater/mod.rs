@@ -5,6 +5,8 @@ pub use asynch::{FirmwareState, FirmwareUpdater};
 pub use blocking::{BlockingFirmwareState, BlockingFirmwareUpdater};
 use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
 
+use crate::PatchError;
+
 /// Firmware updater flash configuration holding the two flashes used by the updater
 ///
 /// If only a single flash is actually used, then that flash should be partitioned into two partitions before use.
@@ -17,6 +19,24 @@ pub struct FirmwareUpdaterConfig<DFU, STATE> {
     pub state: STATE,
 }
 
+/// Errors while decompressing an update.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecompressError {
+    /// The update is not an LZ4 frame, or uses unsupported options.
+    BadFormat,
+    /// The compressed data is invalid.
+    Malformed,
+    /// The decompressed update does not fit in the active partition.
+    TooLarge,
+    /// The update was not completely received.
+    Incomplete,
+    /// The update does not match its block checksums or the content size of the frame.
+    ChecksumMismatch,
+    /// The buffer used to check the update is not a multiple of the read size of the dfu partition.
+    BadBuffer,
+}
+
 /// Errors returned by FirmwareUpdater
 #[derive(Debug)]
 pub enum FirmwareUpdaterError {
@@ -26,6 +46,10 @@ pub enum FirmwareUpdaterError {
     Signature(signature::Error),
     /// Bad state.
     BadState,
+    /// Patch errors.
+    Patch(PatchError),
+    /// Decompression errors.
+    Decompress(DecompressError),
 }
 
 #[cfg(feature = "defmt")]
@@ -35,6 +59,8 @@ impl defmt::Format for FirmwareUpdaterError {
             FirmwareUpdaterError::Flash(_) => defmt::write!(fmt, "FirmwareUpdaterError::Flash(_)"),
             FirmwareUpdaterError::Signature(_) => defmt::write!(fmt, "FirmwareUpdaterError::Signature(_)"),
             FirmwareUpdaterError::BadState => defmt::write!(fmt, "FirmwareUpdaterError::BadState"),
+            FirmwareUpdaterError::Patch(e) => defmt::write!(fmt, "FirmwareUpdaterError::Patch({})", e),
+            FirmwareUpdaterError::Decompress(e) => defmt::write!(fmt, "FirmwareUpdaterError::Decompress({})", e),
         }
     }
 }
@@ -47,3 +73,15 @@ where
         FirmwareUpdaterError::Flash(error.kind())
     }
 }
+
+impl From<PatchError> for FirmwareUpdaterError {
+    fn from(error: PatchError) -> Self {
+        FirmwareUpdaterError::Patch(error)
+    }
+}
+
+impl From<DecompressError> for FirmwareUpdaterError {
+    fn from(error: DecompressError) -> Self {
+        FirmwareUpdaterError::Decompress(error)
+    }
+}
@@ -0,0 +1,401 @@
+use embedded_storage::nor_flash::ReadNorFlash;
+use sha2::{Digest, Sha512};
+
+use crate::BootError;
+
+/// Magic number at the start of an image header, "EMBI" in little endian.
+pub const IMAGE_MAGIC: u32 = u32::from_le_bytes(*b"EMBI");
+/// Magic number at the start of an image trailer.
+pub const TRAILER_MAGIC: u16 = 0xb007;
+/// Length of the fixed part of the image header.
+pub const HEADER_LEN: usize = 32;
+/// Length of the trailer info preceding the TLV entries.
+pub const TRAILER_INFO_LEN: usize = 4;
+
+/// Trailer TLV: SHA-512 of the header and the payload.
+pub const TLV_SHA512: u16 = 0x0010;
+/// Trailer TLV: ed25519 signature of the SHA-512 hash.
+pub const TLV_ED25519: u16 = 0x0020;
+
+/// Semantic version of an image.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Version {
+    /// Major version.
+    pub major: u8,
+    /// Minor version.
+    pub minor: u8,
+    /// Patch version.
+    pub patch: u16,
+}
+
+impl Version {
+    /// Create a version.
+    pub const fn new(major: u8, minor: u8, patch: u16) -> Self {
+        Self { major, minor, patch }
+    }
+}
+
+/// Errors found while validating an image.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageError {
+    /// The image does not start with [`IMAGE_MAGIC`].
+    BadMagic,
+    /// The header size is too small, or the header uses unknown flags.
+    UnsupportedHeader,
+    /// The image does not fit in the partition.
+    TooLarge,
+    /// The image was built for different hardware.
+    WrongHardware,
+    /// The security counter of the image is lower than the stored anti-rollback counter.
+    Rollback,
+    /// The version of the image is lower than the version of the active image.
+    Downgrade,
+    /// The trailer is missing or malformed.
+    BadTrailer,
+    /// The hash in the trailer does not match the image.
+    HashMismatch,
+    /// The signature is missing or invalid.
+    Signature,
+}
+
+/// Header at the start of a firmware image.
+///
+/// Images consist of the header, the payload and a trailer. All fields are little endian.
+///
+/// | Offset | Size | Description                                                  |
+/// |--------|------|--------------------------------------------------------------|
+/// | 0      | 4    | [`IMAGE_MAGIC`]                                              |
+/// | 4      | 2    | Header size, the offset of the payload, at least [`HEADER_LEN`] |
+/// | 6      | 2    | Flags, must be zero                                          |
+/// | 8      | 1    | Major version                                                |
+/// | 9      | 1    | Minor version                                                |
+/// | 10     | 2    | Patch version                                                |
+/// | 12     | 4    | Security counter                                             |
+/// | 16     | 4    | Hardware ID                                                  |
+/// | 20     | 4    | Payload length                                               |
+/// | 24     | 8    | Reserved, must be zero                                       |
+///
+/// The header is padded up to the header size, which lets the payload start at an address suitable
+/// for the vector table. The application must be linked at the start of the active partition plus
+/// the header size, and the bootloader must jump there.
+///
+/// The trailer directly follows the payload. It starts with [`TRAILER_MAGIC`] and the total length of
+/// the trailer as two `u16`, followed by entries of a `u16` type, a `u16` length and the value:
+///
+/// - [`TLV_SHA512`]: SHA-512 of the header, including padding, and the payload. Required.
+/// - [`TLV_ED25519`]: ed25519 signature of the SHA-512 hash. Required if the [`ImagePolicy`] has a
+///   public key.
+///
+/// Unknown entries are ignored.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct ImageHeader {
+    /// Offset of the payload from the start of the image.
+    pub header_size: u16,
+    /// Image flags.
+    pub flags: u16,
+    /// Version of the image.
+    pub version: Version,
+    /// Anti-rollback counter. Images with a counter lower than the one of a previously confirmed
+    /// image are refused.
+    pub security_counter: u32,
+    /// Identifier of the hardware the image is built for.
+    pub hardware_id: u32,
+    /// Length of the payload in bytes.
+    pub payload_len: u32,
+}
+
+impl ImageHeader {
+    /// Create a header for a payload.
+    pub const fn new(version: Version, security_counter: u32, hardware_id: u32, payload_len: u32) -> Self {
+        Self {
+            header_size: HEADER_LEN as u16,
+            flags: 0,
+            version,
+            security_counter,
+            hardware_id,
+            payload_len,
+        }
+    }
+
+    /// Parse a header from the first [`HEADER_LEN`] bytes of `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<Self, ImageError> {
+        let bytes: &[u8; HEADER_LEN] = bytes
+            .get(..HEADER_LEN)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(ImageError::BadMagic)?;
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        if u32_at(0) != IMAGE_MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let header = Self {
+            header_size: u16_at(4),
+            flags: u16_at(6),
+            version: Version::new(bytes[8], bytes[9], u16_at(10)),
+            security_counter: u32_at(12),
+            hardware_id: u32_at(16),
+            payload_len: u32_at(20),
+        };
+        if (header.header_size as usize) < HEADER_LEN || header.flags != 0 || bytes[24..].iter().any(|&b| b != 0) {
+            return Err(ImageError::UnsupportedHeader);
+        }
+        Ok(header)
+    }
+
+    /// Read the header of an image mapped in memory, such as the running application.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to at least [`HEADER_LEN`] readable bytes.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, ImageError> {
+        Self::parse(core::slice::from_raw_parts(ptr, HEADER_LEN))
+    }
+
+    /// Serialize the fixed part of the header.
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.header_size.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[8] = self.version.major;
+        bytes[9] = self.version.minor;
+        bytes[10..12].copy_from_slice(&self.version.patch.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.security_counter.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.hardware_id.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.payload_len.to_le_bytes());
+        bytes
+    }
+
+    /// Offset of the trailer from the start of the image.
+    pub fn trailer_offset(&self) -> u32 {
+        self.header_size as u32 + self.payload_len
+    }
+}
+
+/// Requirements for images installed by the [`BootLoader`](crate::BootLoader).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ImagePolicy {
+    /// Hardware ID that images must match.
+    pub hardware_id: u32,
+    /// Accept images with a lower version than the active image, as long as their security counter
+    /// is high enough.
+    pub allow_downgrade: bool,
+    /// ed25519 public key to verify image signatures with, or `None` to accept unsigned images.
+    #[cfg(feature = "_verify")]
+    pub public_key: Option<[u8; 32]>,
+}
+
+impl ImagePolicy {
+    /// Create a policy accepting images for `hardware_id`, refusing downgrades.
+    pub const fn new(hardware_id: u32) -> Self {
+        Self {
+            hardware_id,
+            allow_downgrade: false,
+            #[cfg(feature = "_verify")]
+            public_key: None,
+        }
+    }
+}
+
+/// Read `out.len()` bytes at any `offset`, reading through `aligned_buf` to respect the read size.
+pub(crate) fn read_unaligned<F: ReadNorFlash>(
+    flash: &mut F,
+    mut offset: u32,
+    mut out: &mut [u8],
+    aligned_buf: &mut [u8],
+) -> Result<(), F::Error> {
+    while !out.is_empty() {
+        let start = offset - offset % F::READ_SIZE as u32;
+        let len = aligned_buf.len().min(flash.capacity() - start as usize);
+        flash.read(start, &mut aligned_buf[..len])?;
+
+        let skip = (offset - start) as usize;
+        let n = out.len().min(len - skip);
+        out[..n].copy_from_slice(&aligned_buf[skip..skip + n]);
+        out = &mut out[n..];
+        offset += n as u32;
+    }
+    Ok(())
+}
+
+/// Validate the image at the start of `flash` against `policy`.
+///
+/// `min_counter` is the anti-rollback counter the image must reach, and `active` the header of the
+/// active image, if it has one.
+pub(crate) fn validate<F: ReadNorFlash>(
+    flash: &mut F,
+    max_len: usize,
+    policy: &ImagePolicy,
+    min_counter: u32,
+    active: Option<&ImageHeader>,
+    aligned_buf: &mut [u8],
+) -> Result<ImageHeader, BootError> {
+    let mut bytes = [0; HEADER_LEN];
+    read_unaligned(flash, 0, &mut bytes, aligned_buf)?;
+    let header = ImageHeader::parse(&bytes)?;
+
+    if header.hardware_id != policy.hardware_id {
+        return Err(ImageError::WrongHardware.into());
+    }
+    if header.security_counter < min_counter {
+        return Err(ImageError::Rollback.into());
+    }
+    if let Some(active) = active {
+        if header.version < active.version && !policy.allow_downgrade {
+            return Err(ImageError::Downgrade.into());
+        }
+    }
+
+    let trailer_offset = header.trailer_offset();
+    if trailer_offset as usize + TRAILER_INFO_LEN > max_len {
+        return Err(ImageError::TooLarge.into());
+    }
+    let mut info = [0; TRAILER_INFO_LEN];
+    read_unaligned(flash, trailer_offset, &mut info, aligned_buf)?;
+    let trailer_len = u16::from_le_bytes([info[2], info[3]]) as usize;
+    if u16::from_le_bytes([info[0], info[1]]) != TRAILER_MAGIC || trailer_len < TRAILER_INFO_LEN {
+        return Err(ImageError::BadTrailer.into());
+    }
+    if trailer_offset as usize + trailer_len > max_len {
+        return Err(ImageError::TooLarge.into());
+    }
+
+    let mut hash = None;
+    let mut signature = None;
+    let mut offset = TRAILER_INFO_LEN;
+    while offset < trailer_len {
+        let mut tlv = [0; 4];
+        if offset + tlv.len() > trailer_len {
+            return Err(ImageError::BadTrailer.into());
+        }
+        read_unaligned(flash, trailer_offset + offset as u32, &mut tlv, aligned_buf)?;
+        let kind = u16::from_le_bytes([tlv[0], tlv[1]]);
+        let len = u16::from_le_bytes([tlv[2], tlv[3]]) as usize;
+        offset += tlv.len();
+        if offset + len > trailer_len {
+            return Err(ImageError::BadTrailer.into());
+        }
+
+        let slot = match kind {
+            TLV_SHA512 => &mut hash,
+            TLV_ED25519 => &mut signature,
+            _ => {
+                offset += len;
+                continue;
+            }
+        };
+        if len != 64 {
+            return Err(ImageError::BadTrailer.into());
+        }
+        let mut value = [0; 64];
+        read_unaligned(flash, trailer_offset + offset as u32, &mut value, aligned_buf)?;
+        *slot = Some(value);
+        offset += len;
+    }
+
+    let expected = hash.ok_or(ImageError::BadTrailer)?;
+    let mut digest = Sha512::new();
+    let mut offset = 0;
+    while offset < trailer_offset {
+        let len = aligned_buf.len().min(flash.capacity() - offset as usize);
+        flash.read(offset, &mut aligned_buf[..len])?;
+        let n = len.min((trailer_offset - offset) as usize);
+        digest.update(&aligned_buf[..n]);
+        offset += n as u32;
+    }
+    let hash: [u8; 64] = digest.finalize().into();
+    if hash != expected {
+        return Err(ImageError::HashMismatch.into());
+    }
+
+    #[cfg(feature = "_verify")]
+    if let Some(public_key) = &policy.public_key {
+        let signature = signature.ok_or(ImageError::Signature)?;
+        verify_signature(public_key, &signature, &hash)?;
+    }
+    #[cfg(not(feature = "_verify"))]
+    let _ = signature;
+
+    Ok(header)
+}
+
+#[cfg(feature = "_verify")]
+fn verify_signature(public_key: &[u8; 32], signature: &[u8; 64], message: &[u8; 64]) -> Result<(), ImageError> {
+    #[cfg(feature = "ed25519-dalek")]
+    {
+        use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+        let public_key = VerifyingKey::from_bytes(public_key).map_err(|_| ImageError::Signature)?;
+        let signature = Signature::from_bytes(signature);
+        public_key
+            .verify(message, &signature)
+            .map_err(|_| ImageError::Signature)
+    }
+    #[cfg(feature = "ed25519-salty")]
+    {
+        use salty::{PublicKey, Signature};
+
+        let public_key = PublicKey::try_from(public_key).map_err(|_| ImageError::Signature)?;
+        let signature = Signature::try_from(signature).map_err(|_| ImageError::Signature)?;
+        public_key
+            .verify(message, &signature)
+            .map_err(|_| ImageError::Signature)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build an image with the given header and payload into `out`, returning its length.
+    pub(crate) fn build_image(header: &ImageHeader, payload: &[u8], key: Option<&[u8; 32]>, out: &mut [u8]) -> usize {
+        let header_size = header.header_size as usize;
+        out[..header_size].fill(0);
+        out[..HEADER_LEN].copy_from_slice(&header.to_bytes());
+        out[header_size..header_size + payload.len()].copy_from_slice(payload);
+
+        let trailer_offset = header_size + payload.len();
+        let hash: [u8; 64] = Sha512::digest(&out[..trailer_offset]).into();
+        let mut trailer_len = TRAILER_INFO_LEN;
+        let mut push = |out: &mut [u8], kind: u16, value: &[u8]| {
+            let at = trailer_offset + trailer_len;
+            out[at..at + 2].copy_from_slice(&kind.to_le_bytes());
+            out[at + 2..at + 4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+            out[at + 4..at + 4 + value.len()].copy_from_slice(value);
+            trailer_len += 4 + value.len();
+        };
+        push(out, TLV_SHA512, &hash);
+        if let Some(key) = key {
+            use ed25519_dalek::{Signer, SigningKey};
+            let signature = SigningKey::from_bytes(key).sign(&hash);
+            push(out, TLV_ED25519, &signature.to_bytes());
+        }
+        out[trailer_offset..trailer_offset + 2].copy_from_slice(&TRAILER_MAGIC.to_le_bytes());
+        out[trailer_offset + 2..trailer_offset + 4].copy_from_slice(&(trailer_len as u16).to_le_bytes());
+        trailer_offset + trailer_len
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let mut header = ImageHeader::new(Version::new(1, 2, 300), 7, 0xabcd, 1234);
+        header.header_size = 256;
+        assert_eq!(ImageHeader::parse(&header.to_bytes()), Ok(header));
+
+        let mut bytes = header.to_bytes();
+        bytes[0] ^= 1;
+        assert_eq!(ImageHeader::parse(&bytes), Err(ImageError::BadMagic));
+        let mut bytes = header.to_bytes();
+        bytes[6] = 1;
+        assert_eq!(ImageHeader::parse(&bytes), Err(ImageError::UnsupportedHeader));
+        assert_eq!(ImageHeader::parse(&bytes[..8]), Err(ImageError::BadMagic));
+
+        assert!(Version::new(1, 2, 3) < Version::new(1, 3, 0));
+        assert!(Version::new(2, 0, 0) > Version::new(1, 255, 65535));
+    }
+}
@@ -4,11 +4,20 @@
 #![doc = include_str!("../README.md")]
 mod fmt;
 
+#[cfg(any(test, feature = "alloc"))]
+extern crate alloc;
+
 mod boot_loader;
+#[cfg(any(test, feature = "compression"))]
+mod compression;
+mod delta;
 mod digest_adapters;
+mod encryption;
 mod firmware_updater;
+mod image;
 #[cfg(test)]
 mod mem_flash;
+mod multi_boot_loader;
 #[cfg(test)]
 mod test_flash;
 
@@ -20,15 +29,134 @@ pub(crate) const STATE_ERASE_VALUE: u8 = 0xFF;
 pub(crate) const STATE_ERASE_VALUE: u8 = 0x00;
 
 pub use boot_loader::{BootError, BootLoader, BootLoaderConfig};
+#[cfg(any(test, feature = "alloc"))]
+pub use delta::{apply_patch, generate_patch};
+pub use delta::{PatchError, PatchHeader, Patcher, PATCH_HEADER_LEN, PATCH_MAGIC};
+#[cfg(feature = "aes")]
+pub use encryption::SoftwareAes;
+pub use encryption::{apply_image_keystream, KeyProvider, NoKey, IV_LEN};
 pub use firmware_updater::{
-    BlockingFirmwareState, BlockingFirmwareUpdater, FirmwareState, FirmwareUpdater, FirmwareUpdaterConfig,
-    FirmwareUpdaterError,
+    BlockingFirmwareState, BlockingFirmwareUpdater, DecompressError, FirmwareState, FirmwareUpdater,
+    FirmwareUpdaterConfig, FirmwareUpdaterError,
+};
+pub use image::{
+    ImageError, ImageHeader, ImagePolicy, Version, HEADER_LEN, IMAGE_MAGIC, TLV_ED25519, TLV_SHA512, TRAILER_INFO_LEN,
+    TRAILER_MAGIC,
 };
+pub use multi_boot_loader::{ImageSlot, MultiBootLoader, MultiBootLoaderConfig};
 
 pub(crate) const REVERT_MAGIC: u8 = 0xC0;
 pub(crate) const BOOT_MAGIC: u8 = 0xD0;
 pub(crate) const SWAP_MAGIC: u8 = 0xF0;
 pub(crate) const DFU_DETACH_MAGIC: u8 = 0xE0;
+pub(crate) const REJECTED_MAGIC: u8 = 0xB0;
+pub(crate) const DECOMPRESS_MAGIC: u8 = 0xA0;
+
+/// Size of a copy of the anti-rollback counter, for a state partition with the given write and read
+/// sizes.
+pub(crate) const fn counter_size(write_size: usize, read_size: usize) -> usize {
+    let size = if write_size > read_size { write_size } else { read_size };
+    if size > 8 {
+        size
+    } else {
+        8
+    }
+}
+
+/// End of the part of the state partition erased when the state changes.
+///
+/// With `counter_page`, used with an image policy, the last erase page of the state partition holds
+/// the anti-rollback counter and is only erased to raise the counter once the page is full.
+pub(crate) const fn state_end(capacity: usize, erase_size: usize, counter_page: bool) -> usize {
+    if counter_page && capacity > erase_size {
+        capacity - erase_size
+    } else {
+        capacity
+    }
+}
+
+/// Offset of the copy of the anti-rollback counter that is kept at the end of the erased part of
+/// the state partition while the counter page is erased.
+pub(crate) const fn counter_backup_offset(
+    capacity: usize,
+    erase_size: usize,
+    counter_page: bool,
+    write_size: usize,
+    read_size: usize,
+) -> usize {
+    state_end(capacity, erase_size, counter_page) - counter_size(write_size, read_size)
+}
+
+/// Decode a copy of the anti-rollback counter, `None` if it was never written or its write was
+/// interrupted.
+pub(crate) fn decode_counter(bytes: &[u8]) -> Option<u32> {
+    let counter = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let check = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    match counter == !check {
+        true => Some(counter),
+        false => None,
+    }
+}
+
+/// Maximum number of trial boots.
+pub(crate) const MAX_TRIAL_BOOTS: u8 = 127;
+
+/// Size of the initialization vector of an encrypted update stored in the state partition, for a
+/// partition with the given write and read sizes.
+pub(crate) const fn iv_size(write_size: usize, read_size: usize) -> usize {
+    let word_size = if write_size > read_size { write_size } else { read_size };
+    IV_LEN.div_ceil(word_size) * word_size
+}
+
+/// Offset of the initialization vector of an encrypted update, preceding the copy of the
+/// anti-rollback counter.
+pub(crate) const fn iv_offset(
+    capacity: usize,
+    erase_size: usize,
+    counter_page: bool,
+    write_size: usize,
+    read_size: usize,
+) -> usize {
+    counter_backup_offset(capacity, erase_size, counter_page, write_size, read_size) - iv_size(write_size, read_size)
+}
+
+/// Offset of the word recording trial boot `index` in a state partition of `capacity` bytes, with
+/// the given erase, write and read sizes.
+pub(crate) const fn attempt_offset(
+    capacity: usize,
+    erase_size: usize,
+    counter_page: bool,
+    write_size: usize,
+    read_size: usize,
+    index: usize,
+) -> usize {
+    let word_size = if write_size > read_size { write_size } else { read_size };
+    iv_offset(capacity, erase_size, counter_page, write_size, read_size) - (index + 1) * word_size
+}
+
+/// Decode a trial boot word, returning the number of trial boots left, or `None` if it was never
+/// written.
+pub(crate) fn decode_attempt(word: &[u8]) -> Option<u8> {
+    match word.iter().all(|&b| b == STATE_ERASE_VALUE) {
+        true => None,
+        false => Some(word[0] ^ !STATE_ERASE_VALUE),
+    }
+}
+
+/// Encode a trial boot word. As `remaining` is below 128, the word always differs from erased
+/// flash.
+pub(crate) fn encode_attempt(remaining: u8, word: &mut [u8]) {
+    word.fill(!STATE_ERASE_VALUE);
+    word[0] = remaining ^ !STATE_ERASE_VALUE;
+}
+
+/// Encode a copy of the anti-rollback counter for storage, followed by its complement so that
+/// interrupted writes are detected.
+pub(crate) fn encode_counter(counter: u32, bytes: &mut [u8]) {
+    bytes.fill(0);
+    bytes[..4].copy_from_slice(&counter.to_le_bytes());
+    bytes[4..8].copy_from_slice(&(!counter).to_le_bytes());
+}
 
 /// The state of the bootloader after running prepare.
 #[derive(PartialEq, Eq, Debug)]
@@ -42,6 +170,9 @@ pub enum State {
     Revert,
     /// Application has received a request to reboot into DFU mode to apply an update.
     DfuDetach,
+    /// Bootloader has refused the update because the image in the dfu partition did not pass the
+    /// [`ImagePolicy`], and will boot the active partition.
+    Rejected,
 }
 
 impl<T> From<T> for State
@@ -50,12 +181,15 @@ where
 {
     fn from(magic: T) -> State {
         let magic = magic.as_ref();
-        if !magic.iter().any(|&b| b != SWAP_MAGIC) {
+        // A compressed update is swapped in by decompressing it.
+        if !magic.iter().any(|&b| b != SWAP_MAGIC) || !magic.iter().any(|&b| b != DECOMPRESS_MAGIC) {
             State::Swap
         } else if !magic.iter().any(|&b| b != REVERT_MAGIC) {
             State::Revert
         } else if !magic.iter().any(|&b| b != DFU_DETACH_MAGIC) {
             State::DfuDetach
+        } else if !magic.iter().any(|&b| b != REJECTED_MAGIC) {
+            State::Rejected
         } else {
             State::Boot
         }
@@ -88,6 +222,7 @@ mod tests {
 
     use super::*;
     use crate::boot_loader::BootLoaderConfig;
+    use crate::encryption::tests::TestKey;
     use crate::firmware_updater::FirmwareUpdaterConfig;
     use crate::mem_flash::MemFlash;
     use crate::test_flash::{AsyncTestFlash, BlockingTestFlash};
@@ -127,6 +262,43 @@ mod tests {
         assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
     }
 
+    #[test]
+    fn test_baseline_state_size() {
+        // Without an image policy, the whole state partition holds the progress, so a state
+        // partition of several erase pages only needs to fit the progress of the swap.
+        const STATE_SIZE: usize = (2 + 2 * 4) * 4;
+        const FIRMWARE_SIZE: usize = 16384;
+        const ORIGINAL: [u8; FIRMWARE_SIZE] = [0x55; FIRMWARE_SIZE];
+        const UPDATE: [u8; FIRMWARE_SIZE] = [0xAA; FIRMWARE_SIZE];
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<FIRMWARE_SIZE, 4096, 4>::default(),
+            dfu: MemFlash::<20480, 4096, 4>::default(),
+            state: MemFlash::<STATE_SIZE, 8, 4>::default(),
+        });
+        flash.active().write(0, &ORIGINAL).unwrap();
+        flash.dfu().write(0, &UPDATE).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        state.mark_updated().unwrap();
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        let mut page = [0; 4096];
+        let mut read_buf = [0; FIRMWARE_SIZE];
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(UPDATE, read_buf);
+
+        state.mark_booted().unwrap();
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(UPDATE, read_buf);
+    }
+
     #[test]
     #[cfg(not(feature = "_verify"))]
     fn test_swap_state() {
@@ -348,4 +520,312 @@ mod tests {
         ))
         .is_ok());
     }
+
+    type ImageTestFlash =
+        BlockingTestFlash<MemFlash<16384, 4096, 4>, MemFlash<20480, 4096, 4>, MemFlash<8192, 4096, 4>>;
+
+    fn image_test_flash() -> ImageTestFlash {
+        BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::default(),
+            dfu: MemFlash::default(),
+            state: MemFlash::default(),
+        })
+    }
+
+    /// Write an image to `flash`, returning the payload.
+    fn write_image<F: NorFlash>(
+        flash: &mut F,
+        version: Version,
+        security_counter: u32,
+        key: Option<&[u8; 32]>,
+        tamper: bool,
+    ) -> [u8; 8192] {
+        let payload = [version.minor; 8192];
+        let mut image = [0xff; 12288];
+        let header = ImageHeader::new(version, security_counter, 0x1234, payload.len() as u32);
+        image::tests::build_image(&header, &payload, key, &mut image);
+        if tamper {
+            image[HEADER_LEN + 100] ^= 1;
+        }
+        flash.erase(0, image.len() as u32).unwrap();
+        flash.write(0, &image).unwrap();
+        payload
+    }
+
+    fn prepare_image_boot(flash: &ImageTestFlash, policy: &ImagePolicy) -> State {
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        })
+        .with_image_policy(policy.clone());
+        let mut page = [0; 4096];
+        bootloader.prepare_boot(&mut page).unwrap()
+    }
+
+    fn set_image_state(flash: &ImageTestFlash, booted: bool) {
+        let mut aligned = [0; 4];
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned).with_counter_page();
+        match booted {
+            true => state.mark_booted().unwrap(),
+            false => state.mark_updated().unwrap(),
+        }
+    }
+
+    fn active_payload(flash: &ImageTestFlash) -> [u8; 8192] {
+        let mut payload = [0; 8192];
+        flash.active().read(HEADER_LEN as u32, &mut payload).unwrap();
+        payload
+    }
+
+    #[test]
+    fn test_image_anti_rollback() {
+        let flash = image_test_flash();
+        let policy = ImagePolicy::new(0x1234);
+        let mut page = [0; 4096];
+
+        write_image(&mut flash.active(), Version::new(1, 0, 0), 1, None, false);
+        let update = write_image(&mut flash.dfu(), Version::new(1, 1, 0), 2, None, false);
+        set_image_state(&flash, false);
+        assert_eq!(State::Swap, prepare_image_boot(&flash, &policy));
+        assert_eq!(update, active_payload(&flash));
+
+        // The counter is raised once the update is confirmed.
+        set_image_state(&flash, true);
+        assert_eq!(State::Boot, prepare_image_boot(&flash, &policy));
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        })
+        .with_image_policy(policy.clone());
+        assert_eq!(2, bootloader.security_counter(&mut page).unwrap());
+        assert_eq!(
+            Version::new(1, 1, 0),
+            bootloader.active_image(&mut page).unwrap().version
+        );
+
+        // A newer version with an old security counter is refused, even if the active image was
+        // replaced by one with a lower counter.
+        let active = write_image(&mut flash.active(), Version::new(1, 1, 0), 0, None, false);
+        write_image(&mut flash.dfu(), Version::new(1, 2, 0), 1, None, false);
+        set_image_state(&flash, false);
+        assert_eq!(State::Rejected, prepare_image_boot(&flash, &policy));
+        assert_eq!(State::Rejected, prepare_image_boot(&flash, &policy));
+        assert_eq!(active, active_payload(&flash));
+
+        // Updating the state keeps the counter.
+        set_image_state(&flash, true);
+        assert_eq!(2, bootloader.security_counter(&mut page).unwrap());
+    }
+
+    #[test]
+    fn test_image_rejected() {
+        let flash = image_test_flash();
+        let mut policy = ImagePolicy::new(0x1234);
+        write_image(&mut flash.active(), Version::new(1, 1, 0), 0, None, false);
+        let original = active_payload(&flash);
+
+        let check = |policy: &ImagePolicy, version: Version, tamper: bool| {
+            write_image(&mut flash.dfu(), version, 0, None, tamper);
+            set_image_state(&flash, false);
+            prepare_image_boot(&flash, policy)
+        };
+        assert_eq!(State::Rejected, check(&policy, Version::new(1, 2, 0), true));
+        assert_eq!(State::Rejected, check(&policy, Version::new(1, 0, 0), false));
+        assert_eq!(
+            State::Rejected,
+            check(&ImagePolicy::new(0x4321), Version::new(1, 2, 0), false)
+        );
+        assert_eq!(original, active_payload(&flash));
+
+        policy.allow_downgrade = true;
+        assert_eq!(State::Swap, check(&policy, Version::new(1, 0, 0), false));
+        assert_ne!(original, active_payload(&flash));
+    }
+
+    #[test]
+    #[cfg(feature = "_verify")]
+    fn test_image_signature() {
+        use ed25519_dalek::SigningKey;
+        use rand::rngs::OsRng;
+
+        let key = SigningKey::generate(&mut OsRng).to_bytes();
+        let other_key = SigningKey::generate(&mut OsRng).to_bytes();
+        let mut policy = ImagePolicy::new(0x1234);
+        policy.public_key = Some(SigningKey::from_bytes(&key).verifying_key().to_bytes());
+
+        let flash = image_test_flash();
+        for (key, expected) in [
+            (None, State::Rejected),
+            (Some(&other_key), State::Rejected),
+            (Some(&key), State::Swap),
+        ] {
+            write_image(&mut flash.dfu(), Version::new(1, 0, 0), 0, key, false);
+            set_image_state(&flash, false);
+            assert_eq!(expected, prepare_image_boot(&flash, &policy));
+        }
+    }
+
+    #[test]
+    fn test_trial_boots() {
+        const FIRMWARE_SIZE: usize = 16384;
+        const ORIGINAL: [u8; FIRMWARE_SIZE] = [0x55; FIRMWARE_SIZE];
+        const UPDATE: [u8; FIRMWARE_SIZE] = [0xAA; FIRMWARE_SIZE];
+        let flash = image_test_flash();
+        flash.active().write(0, &ORIGINAL).unwrap();
+        flash.dfu().write(0, &UPDATE).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut page = [0; 4096];
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        assert_eq!(None, state.remaining_boot_attempts().unwrap());
+        state.mark_updated().unwrap();
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        })
+        .with_trial_boots(3);
+        for remaining in [2, 1, 0] {
+            assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+            assert_eq!(Some(remaining), state.remaining_boot_attempts().unwrap());
+            assert!(!state.mark_booted_if(|| false).unwrap());
+            let mut read_buf = [0; FIRMWARE_SIZE];
+            flash.active().read(0, &mut read_buf).unwrap();
+            assert_eq!(UPDATE, read_buf);
+        }
+
+        // Out of trial boots.
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(State::Revert, bootloader.prepare_boot(&mut page).unwrap());
+        let mut read_buf = [0; FIRMWARE_SIZE];
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(ORIGINAL, read_buf);
+
+        // Marking the update as booted stops the trial.
+        state.mark_updated().unwrap();
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        assert!(state.mark_booted_if(|| true).unwrap());
+        assert_eq!(None, state.remaining_boot_attempts().unwrap());
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(UPDATE, read_buf);
+    }
+
+    #[test]
+    fn test_encrypted_update() {
+        const FIRMWARE_SIZE: usize = 16384;
+        let original: [u8; FIRMWARE_SIZE] = core::array::from_fn(|i| i as u8);
+        let update: [u8; FIRMWARE_SIZE] = core::array::from_fn(|i| (i / 7) as u8);
+        let iv = [0x42; IV_LEN];
+        let mut encrypted = update;
+        apply_image_keystream(&mut TestKey, &iv, 0, &mut encrypted);
+
+        let flash = image_test_flash();
+        flash.active().write(0, &original).unwrap();
+        flash.dfu().write(0, &encrypted).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut page = [0; 8];
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        state.mark_updated_encrypted(&iv).unwrap();
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        })
+        .with_encryption(TestKey);
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        let mut read_buf = [0; FIRMWARE_SIZE];
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(update, read_buf);
+        // The previous image is not stored in plaintext
+        flash.dfu().read(4096, &mut read_buf).unwrap();
+        assert!(read_buf.chunks(16).zip(original.chunks(16)).all(|(a, b)| a != b));
+
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(State::Revert, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(original, read_buf);
+        flash.dfu().read(0, &mut read_buf).unwrap();
+        assert_eq!(encrypted, read_buf);
+
+        // Updates must be encrypted
+        state.mark_updated().unwrap();
+        assert_eq!(State::Rejected, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(original, read_buf);
+    }
+
+    #[test]
+    #[cfg(not(feature = "_verify"))]
+    fn test_compressed_update() {
+        use crate::compression::tests::{compress, compressible};
+
+        const ACTIVE_SIZE: usize = 16384;
+        const ORIGINAL: [u8; ACTIVE_SIZE] = [0x55; ACTIVE_SIZE];
+        // The dfu partition only holds the compressed update.
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<ACTIVE_SIZE, 4096, 4>::default(),
+            dfu: MemFlash::<8192, 4096, 4>::default(),
+            state: MemFlash::<4096, 1024, 4>::default(),
+        });
+        flash.active().write(0, &ORIGINAL).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut buf = [0; 64];
+        let mut page = [0; 1024];
+        let mut updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        let mut write_update = |update: &[u8]| {
+            let mut frame = compress(update);
+            frame.resize(frame.len().next_multiple_of(4), 0xff);
+            updater.erase_dfu(0, 8192).unwrap();
+            updater.write_firmware(0, &frame).unwrap();
+            updater.mark_updated_compressed(&mut buf).unwrap()
+        };
+        let bootloader = || {
+            BootLoader::new(BootLoaderConfig {
+                active: flash.active(),
+                dfu: flash.dfu(),
+                state: flash.state(),
+            })
+        };
+
+        let update = compressible(ACTIVE_SIZE - 1000);
+        assert_eq!(update.len() as u32, write_update(&update));
+        assert_eq!(State::Boot, bootloader().prepare_boot(&mut page).unwrap());
+        let mut read_buf = [0; ACTIVE_SIZE];
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(update, read_buf[..update.len()]);
+        assert_eq!(State::Boot, bootloader().prepare_boot(&mut page).unwrap());
+
+        // Compressed updates cannot be validated before overwriting the active image.
+        let other = compressible(ACTIVE_SIZE - 2000);
+        write_update(&other);
+        let policy = ImagePolicy::new(0x1234);
+        assert_eq!(
+            State::Rejected,
+            bootloader().with_image_policy(policy).prepare_boot(&mut page).unwrap()
+        );
+
+        // A compressed update larger than the active partition is rejected.
+        write_update(&compressible(ACTIVE_SIZE + 1000));
+        assert_eq!(State::Rejected, bootloader().prepare_boot(&mut page).unwrap());
+
+        // Updates that are not compressed do not fit in the dfu partition.
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        state.mark_updated().unwrap();
+        assert_eq!(State::Rejected, bootloader().prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(update, read_buf[..update.len()]);
+    }
 }
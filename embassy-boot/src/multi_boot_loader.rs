@@ -0,0 +1,472 @@
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+
+use crate::{
+    BootError, BootLoader, BootLoaderConfig, ImagePolicy, State, BOOT_MAGIC, REJECTED_MAGIC, REVERT_MAGIC,
+    STATE_ERASE_VALUE, SWAP_MAGIC,
+};
+
+/// Partitions of one image managed by a [`MultiBootLoader`].
+pub struct ImageSlot<ACTIVE, DFU> {
+    /// Flash type used for the active partition - the partition which will be booted from.
+    pub active: ACTIVE,
+    /// Flash type used for the dfu partition - the partition which will be swapped in when requested.
+    pub dfu: DFU,
+}
+
+/// Multi-image bootloader flash configuration, holding the partitions of each image and the state
+/// partition they share.
+///
+/// All active partitions are of the same flash type, as are all dfu partitions. Images in flashes of
+/// different types can be managed with a flash type dispatching to either.
+pub struct MultiBootLoaderConfig<ACTIVE, DFU, STATE, const N: usize> {
+    /// Partitions of each image.
+    pub images: [ImageSlot<ACTIVE, DFU>; N],
+    /// Flash type used for the state partition.
+    pub state: STATE,
+}
+
+/// BootLoader updating several images together, such as the firmware of each core of a multi-core
+/// chip, or the firmware of a co-processor.
+///
+/// The images of an update set are swapped in together: the update is only committed if every image
+/// is valid and has been swapped in, and if the update is not marked as booted, every image is
+/// reverted.
+///
+/// The state partition is divided into `N + 1` regions of equal size, which must be a multiple of
+/// the erase size. The first region holds the state of the update set, and is used as the state
+/// partition of the application's [`FirmwareUpdater`](crate::FirmwareUpdater)s or
+/// [`FirmwareState`](crate::FirmwareState): the update set is marked as updated once every dfu
+/// partition has been written, and marked as booted once every image runs correctly. Region `i + 1`
+/// holds the progress of swapping image `i` and, with an image policy, its anti-rollback counter.
+///
+/// Trial boots and encrypted updates are not supported with several images.
+pub struct MultiBootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize> {
+    images: [ImageSlot<ACTIVE, DFU>; N],
+    /// The first region of the state partition has the following format:
+    /// All ranges are in multiples of WRITE_SIZE bytes.
+    /// | Range         | Description                                                                  |
+    /// | 0..1          | Magic indicating the state of the update set.                                |
+    /// | 1..2          | Progress validity. !ERASE_VALUE while the application marks the set booted.  |
+    /// | 2..3          | !ERASE_VALUE once all images are validated and their progress reset.         |
+    /// | 3..3 + N      | !ERASE_VALUE at `3 + i` once image `i` has been swapped.                     |
+    /// | 3 + N..3 + 2N | !ERASE_VALUE at `3 + N + i` once image `i` has been reverted.                |
+    state: STATE,
+    policies: [Option<ImagePolicy>; N],
+}
+
+const PROGRESS_VALIDITY: usize = 1;
+const STARTED: usize = 2;
+const SWAPPED: usize = 3;
+
+impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize> MultiBootLoader<ACTIVE, DFU, STATE, N> {
+    /// Create a new instance of a multi-image bootloader with the flash partitions.
+    ///
+    /// The partitions of each image have the same requirements as with a [`BootLoader`].
+    pub fn new(config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>) -> Self {
+        Self {
+            images: config.images,
+            state: config.state,
+            policies: [const { None }; N],
+        }
+    }
+
+    /// Validate updates of image `index` against `policy` before swapping the update set in.
+    ///
+    /// If any image of the update set does not satisfy its policy, no image is swapped in, and
+    /// [`prepare_boot`](Self::prepare_boot) returns [`State::Rejected`].
+    pub fn with_image_policy(mut self, index: usize, policy: ImagePolicy) -> Self {
+        self.policies[index] = Some(policy);
+        self
+    }
+
+    /// Perform necessary boot preparations like swapping the images of an update set.
+    ///
+    /// Each image is swapped and reverted as described in [`BootLoader::prepare_boot`], one after the
+    /// other. The provided aligned_buf argument must satisfy the alignment requirements of every
+    /// partition flash.
+    pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        assert!(N > 0);
+        let region_size = self.region_size();
+        assert!(region_size > 0);
+        assert_eq!(0, region_size % STATE::ERASE_SIZE);
+        assert!((SWAPPED + 2 * N) * STATE::WRITE_SIZE <= region_size);
+        for index in 0..N {
+            self.image(index).assert_config(aligned_buf);
+            // The revert progress must fit as well.
+            let page_count =
+                self.images[index].active.capacity() / BootLoader::<ACTIVE, DFU, STATE>::PAGE_SIZE as usize;
+            assert!((2 + 4 * page_count) * STATE::WRITE_SIZE <= region_size);
+        }
+
+        let state = self.read_state(aligned_buf)?;
+        if state == State::Swap {
+            if self.is_marked(PROGRESS_VALIDITY, aligned_buf)? {
+                // The application was interrupted while marking the update set as booted.
+                self.reset_state(BOOT_MAGIC, aligned_buf)?;
+                return Ok(State::Boot);
+            }
+
+            if !self.is_marked(STARTED, aligned_buf)? {
+                // Only validate before starting, the dfu partitions are modified while swapping.
+                for index in 0..N {
+                    match self.image(index).validate_update(aligned_buf) {
+                        Ok(_) => {}
+                        Err(BootError::Image(e)) => {
+                            warn!("Update of image {} rejected: {:?}", index, e);
+                            self.reset_state(REJECTED_MAGIC, aligned_buf)?;
+                            return Ok(State::Rejected);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                // Clear the progress of the previous update set.
+                for index in 0..N {
+                    self.image(index).reset_state(SWAP_MAGIC, aligned_buf)?;
+                }
+                self.mark(STARTED, aligned_buf)?;
+            }
+
+            // Images are marked swapped in order, so the update set is swapped once the last one is.
+            //
+            // If we're in the swap state once swapped, this means we should revert since the app has
+            // failed to mark boot as successful
+            if !self.is_marked(SWAPPED + N - 1, aligned_buf)? {
+                for index in 0..N {
+                    if !self.is_marked(SWAPPED + index, aligned_buf)? {
+                        trace!("Swapping image {}", index);
+                        self.image(index).swap(aligned_buf)?;
+                        self.mark(SWAPPED + index, aligned_buf)?;
+                    }
+                }
+                trace!("Swapping done");
+            } else {
+                for index in 0..N {
+                    if !self.is_marked(SWAPPED + N + index, aligned_buf)? {
+                        trace!("Reverting image {}", index);
+                        self.image(index).revert(aligned_buf)?;
+                        self.mark(SWAPPED + N + index, aligned_buf)?;
+                    }
+                }
+                self.reset_state(REVERT_MAGIC, aligned_buf)?;
+            }
+        } else if state == State::Boot {
+            for index in 0..N {
+                if self.policies[index].is_some() {
+                    self.image(index).update_counter(aligned_buf)?;
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    fn region_size(&self) -> usize {
+        self.state.capacity() / (N + 1) / STATE::ERASE_SIZE * STATE::ERASE_SIZE
+    }
+
+    fn region(&mut self, index: usize) -> StateRegion<'_, STATE> {
+        let size = self.region_size();
+        StateRegion {
+            flash: &mut self.state,
+            offset: (index * size) as u32,
+            size: size as u32,
+        }
+    }
+
+    /// Bootloader for image `index`, with its region of the state partition.
+    fn image(&mut self, index: usize) -> BootLoader<&mut ACTIVE, &mut DFU, StateRegion<'_, STATE>> {
+        let size = self.region_size();
+        let slot = &mut self.images[index];
+        let bootloader = BootLoader::new(BootLoaderConfig {
+            active: &mut slot.active,
+            dfu: &mut slot.dfu,
+            state: StateRegion {
+                flash: &mut self.state,
+                offset: ((index + 1) * size) as u32,
+                size: size as u32,
+            },
+        });
+        match self.policies[index].clone() {
+            Some(policy) => bootloader.with_image_policy(policy),
+            None => bootloader,
+        }
+    }
+
+    fn read_state(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        self.region(0).read(0, state_word)?;
+        Ok(State::from(state_word))
+    }
+
+    fn is_marked(&mut self, word: usize, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        self.region(0).read((word * STATE::WRITE_SIZE) as u32, state_word)?;
+        Ok(!state_word.contains(&STATE_ERASE_VALUE))
+    }
+
+    fn mark(&mut self, word: usize, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        state_word.fill(!STATE_ERASE_VALUE);
+        self.region(0).write((word * STATE::WRITE_SIZE) as u32, state_word)?;
+        Ok(())
+    }
+
+    /// Erase the state of the update set and set `magic`.
+    fn reset_state(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let mut region = self.region(0);
+        region.erase(0, region.size)?;
+
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        state_word.fill(magic);
+        region.write(0, state_word)?;
+        Ok(())
+    }
+}
+
+/// Region of the state partition.
+pub(crate) struct StateRegion<'a, F> {
+    flash: &'a mut F,
+    offset: u32,
+    size: u32,
+}
+
+impl<F: ErrorType> ErrorType for StateRegion<'_, F> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash> ReadNorFlash for StateRegion<'_, F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        assert!(offset + bytes.len() as u32 <= self.size);
+        self.flash.read(self.offset + offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<F: NorFlash> NorFlash for StateRegion<'_, F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        assert!(from <= to && to <= self.size);
+        self.flash.erase(self.offset + from, self.offset + to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        assert!(offset + bytes.len() as u32 <= self.size);
+        self.flash.write(self.offset + offset, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::image::tests::build_image;
+    use crate::mem_flash::MemFlash;
+    use crate::test_flash::{FaultFlash, MultiTestFlash, Power};
+    use crate::{BlockingFirmwareState, ImageHeader, Version, HEADER_LEN};
+
+    type TestFlash = MultiTestFlash<MemFlash<8192, 4096, 4>, MemFlash<12288, 4096, 4>, MemFlash<24576, 4096, 4>, 2>;
+
+    /// Image whose bytes all depend on their offset, so that misplaced pages are noticed.
+    fn image(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8 ^ (i >> 8) as u8 ^ seed).collect()
+    }
+
+    fn write<F: NorFlash>(flash: &mut F, data: &[u8]) {
+        flash.erase(0, flash.capacity() as u32).unwrap();
+        flash.write(0, data).unwrap();
+    }
+
+    fn test_flash() -> (TestFlash, [Vec<u8>; 2], [Vec<u8>; 2]) {
+        let flash = TestFlash::new(MultiBootLoaderConfig {
+            images: core::array::from_fn(|_| ImageSlot {
+                active: MemFlash::default(),
+                dfu: MemFlash::default(),
+            }),
+            state: MemFlash::default(),
+        });
+        let old = [image(0x10, 8192), image(0x20, 8192)];
+        let new = [image(0x30, 8192), image(0x40, 8192)];
+        for index in 0..2 {
+            write(&mut flash.active(index), &old[index]);
+            write(&mut flash.dfu(index), &new[index]);
+        }
+        (flash, old, new)
+    }
+
+    fn prepare(flash: &TestFlash) -> State {
+        let mut page = [0; 4096];
+        MultiBootLoader::new(flash.config()).prepare_boot(&mut page).unwrap()
+    }
+
+    fn set_state(flash: &TestFlash, booted: bool) {
+        let mut aligned = [0; 4];
+        let mut state = BlockingFirmwareState::new(flash.set_state(), &mut aligned);
+        match booted {
+            true => state.mark_booted().unwrap(),
+            false => state.mark_updated().unwrap(),
+        }
+    }
+
+    fn actives(flash: &TestFlash) -> [Vec<u8>; 2] {
+        core::array::from_fn(|index| {
+            let mut data = alloc::vec![0; 8192];
+            flash.active(index).read(0, &mut data).unwrap();
+            data
+        })
+    }
+
+    #[test]
+    fn test_multi_swap() {
+        let (flash, _, new) = test_flash();
+        assert_eq!(State::Boot, prepare(&flash));
+
+        set_state(&flash, false);
+        assert_eq!(State::Swap, prepare(&flash));
+        assert_eq!(new, actives(&flash));
+
+        set_state(&flash, true);
+        assert_eq!(State::Boot, prepare(&flash));
+        assert_eq!(new, actives(&flash));
+    }
+
+    #[test]
+    fn test_multi_revert() {
+        let (flash, old, new) = test_flash();
+        set_state(&flash, false);
+        assert_eq!(State::Swap, prepare(&flash));
+        assert_eq!(new, actives(&flash));
+
+        // Not marked as booted, so every image is reverted
+        assert_eq!(State::Swap, prepare(&flash));
+        assert_eq!(old, actives(&flash));
+        assert_eq!(State::Revert, prepare(&flash));
+
+        set_state(&flash, true);
+        assert_eq!(State::Boot, prepare(&flash));
+        assert_eq!(old, actives(&flash));
+    }
+
+    #[test]
+    fn test_multi_rejected() {
+        let (flash, old, _) = test_flash();
+        let header = ImageHeader::new(Version::new(1, 0, 0), 1, 0x1234, 4096);
+        for index in 0..2 {
+            let mut update = [0xff; 8192];
+            build_image(&header, &[index as u8; 4096], None, &mut update);
+            if index == 1 {
+                update[HEADER_LEN + 100] ^= 1;
+            }
+            write(&mut flash.dfu(index), &update);
+        }
+        set_state(&flash, false);
+
+        // The first image is valid, but the update set is only swapped if all are.
+        let mut page = [0; 4096];
+        let mut bootloader = MultiBootLoader::new(flash.config())
+            .with_image_policy(0, ImagePolicy::new(0x1234))
+            .with_image_policy(1, ImagePolicy::new(0x1234));
+        assert_eq!(State::Rejected, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(old, actives(&flash));
+        assert_eq!(State::Rejected, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(old, actives(&flash));
+    }
+
+    type FaultTestFlash = MultiTestFlash<FaultFlash<1024, 4>, FaultFlash<1024, 4>, FaultFlash<1024, 4>, 2>;
+
+    enum Step {
+        Boot(State),
+        MarkBooted,
+    }
+
+    fn fault_boot(flash: &FaultTestFlash) -> Option<State> {
+        let mut page = [0; 512];
+        MultiBootLoader::new(flash.config()).prepare_boot(&mut page).ok()
+    }
+
+    fn fault_actives(flash: &FaultTestFlash) -> [Vec<u8>; 2] {
+        core::array::from_fn(|index| {
+            let mut data = alloc::vec![0; 2048];
+            flash.active(index).read(0, &mut data).unwrap();
+            data
+        })
+    }
+
+    /// Run `steps` on two images, cutting power during operation `cut`, and check the images are
+    /// updated or reverted together.
+    ///
+    /// Returns the number of erases and writes done by the steps without power cuts.
+    fn run(steps: &[Step], cut: Option<usize>) -> usize {
+        let power = Power::new(cut.map_or(0, |cut| cut as u64 + 1));
+        let old = [image(0x10, 2048), image(0x20, 2048)];
+        let new = [image(0x30, 2048), image(0x40, 2048)];
+        let flash = FaultTestFlash::new(MultiBootLoaderConfig {
+            images: core::array::from_fn(|index| ImageSlot {
+                active: FaultFlash::new(&old[index], 2048, power.clone()),
+                dfu: FaultFlash::new(&new[index], 3072, power.clone()),
+            }),
+            state: FaultFlash::new(&[], 3072, power.clone()),
+        });
+        let mut aligned = [0; 4];
+        BlockingFirmwareState::new(flash.set_state(), &mut aligned)
+            .mark_updated()
+            .unwrap();
+
+        power.arm(cut);
+        for step in steps {
+            let done = match step {
+                Step::Boot(expected) => fault_boot(&flash)
+                    .map(|state| assert_eq!(*expected, state, "{:?}", cut))
+                    .is_some(),
+                Step::MarkBooted => BlockingFirmwareState::new(flash.set_state(), &mut aligned)
+                    .mark_booted()
+                    .is_ok(),
+            };
+            if done {
+                continue;
+            }
+
+            power.restore(None);
+            let state = fault_boot(&flash).unwrap();
+            let images = fault_actives(&flash);
+            assert!(images == old || images == new, "{:?}: images not updated together", cut);
+            if state == State::Revert {
+                assert!(images == old, "{:?}: images not reverted", cut);
+            }
+
+            // The device keeps working once the images are confirmed.
+            BlockingFirmwareState::new(flash.set_state(), &mut aligned)
+                .mark_booted()
+                .unwrap();
+            assert_eq!(Some(State::Boot), fault_boot(&flash), "{:?}", cut);
+            assert!(fault_actives(&flash) == images, "{:?}: images changed", cut);
+            return power.ops();
+        }
+        power.ops()
+    }
+
+    #[test]
+    fn power_fail_multi() {
+        let revert = [
+            Step::Boot(State::Swap),
+            Step::Boot(State::Swap),
+            Step::Boot(State::Revert),
+            Step::MarkBooted,
+            Step::Boot(State::Boot),
+        ];
+        let confirm = [Step::Boot(State::Swap), Step::MarkBooted, Step::Boot(State::Boot)];
+        for steps in [&revert[..], &confirm[..]] {
+            let ops = run(steps, None);
+            for cut in 0..ops {
+                run(steps, Some(cut));
+            }
+        }
+    }
+}
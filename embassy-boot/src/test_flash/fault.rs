@@ -0,0 +1,547 @@
+//! Flash cutting power during an erase or write, to check the bootloader survives power failures.
+//!
+//! When power is cut during a write, the words before the cut are written, the word being written
+//! is torn, with only some of the bits to clear actually cleared, and the rest is untouched. When
+//! power is cut during an erase, the range is erased up to the cut, the word being erased has only
+//! some of its bits set, and the rest is untouched. Writes only clear bits, as on NOR flash, so a
+//! word can be written again with the same value after a torn write.
+
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+/// Power supply shared by the flashes of a device.
+pub struct Power {
+    /// Operations left before power is cut, if armed.
+    budget: Cell<Option<usize>>,
+    /// Operations completed since armed.
+    ops: Cell<usize>,
+    off: Cell<bool>,
+    rng: Cell<u64>,
+}
+
+impl Power {
+    pub fn new(seed: u64) -> Rc<Self> {
+        Rc::new(Self {
+            budget: Cell::new(None),
+            ops: Cell::new(0),
+            off: Cell::new(false),
+            rng: Cell::new(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1),
+        })
+    }
+
+    /// Cut power during operation `budget`, counting from 0, or never.
+    pub fn arm(&self, budget: Option<usize>) {
+        self.budget.set(budget);
+        self.ops.set(0);
+    }
+
+    /// Restore power after a cut, cutting it again during operation `budget` if any.
+    pub fn restore(&self, budget: Option<usize>) {
+        self.off.set(false);
+        self.arm(budget);
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.off.get()
+    }
+
+    /// Operations completed since armed.
+    pub fn ops(&self) -> usize {
+        self.ops.get()
+    }
+
+    fn random(&self) -> u64 {
+        // xorshift64
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+        x
+    }
+
+    /// Start an erase or write, returning false if power is cut during it.
+    fn operate(&self) -> Result<bool, FaultFlashError> {
+        if self.off.get() {
+            return Err(FaultFlashError);
+        }
+        match self.budget.get() {
+            Some(0) => {
+                self.off.set(true);
+                Ok(false)
+            }
+            budget => {
+                self.budget.set(budget.map(|b| b - 1));
+                self.ops.set(self.ops.get() + 1);
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FaultFlashError;
+
+impl NorFlashError for FaultFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+/// Flash losing power as directed by [`Power`].
+pub struct FaultFlash<const ERASE_SIZE: usize, const WRITE_SIZE: usize> {
+    pub mem: Vec<u8>,
+    power: Rc<Power>,
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> FaultFlash<ERASE_SIZE, WRITE_SIZE> {
+    /// Create a flash of `size` bytes starting with `contents`, erased after.
+    pub fn new(contents: &[u8], size: usize, power: Rc<Power>) -> Self {
+        let mut mem = vec![0xff; size];
+        mem[..contents.len()].copy_from_slice(contents);
+        Self { mem, power }
+    }
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> ErrorType for FaultFlash<ERASE_SIZE, WRITE_SIZE> {
+    type Error = FaultFlashError;
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> ReadNorFlash for FaultFlash<ERASE_SIZE, WRITE_SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        if self.power.is_off() {
+            return Err(FaultFlashError);
+        }
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.mem[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.mem.len()
+    }
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> NorFlash for FaultFlash<ERASE_SIZE, WRITE_SIZE> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = (from as usize, to as usize);
+        assert!(from % ERASE_SIZE == 0 && to % ERASE_SIZE == 0 && to <= self.mem.len());
+        let mut end = to;
+        let powered = self.power.operate()?;
+        if !powered {
+            end = from + (self.power.random() as usize % ((to - from) / WRITE_SIZE)) * WRITE_SIZE;
+            for byte in &mut self.mem[end..end + WRITE_SIZE] {
+                *byte |= self.power.random() as u8;
+            }
+        }
+        self.mem[from..end].fill(0xff);
+        match powered {
+            true => Ok(()),
+            false => Err(FaultFlashError),
+        }
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        assert!(offset % WRITE_SIZE == 0 && bytes.len() % WRITE_SIZE == 0);
+        assert!(offset + bytes.len() <= self.mem.len());
+        let mut len = bytes.len();
+        let powered = self.power.operate()?;
+        if !powered {
+            len = (self.power.random() as usize % (bytes.len() / WRITE_SIZE)) * WRITE_SIZE;
+            for (byte, new) in self.mem[offset + len..offset + len + WRITE_SIZE]
+                .iter_mut()
+                .zip(&bytes[len..])
+            {
+                *byte &= new | self.power.random() as u8;
+            }
+        }
+        for (byte, new) in self.mem[offset..offset + len].iter_mut().zip(bytes) {
+            *byte &= new;
+        }
+        match powered {
+            true => Ok(()),
+            false => Err(FaultFlashError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::ReadNorFlash;
+
+    use super::*;
+    use crate::compression::tests::{compress, compressible};
+    use crate::firmware_updater::FirmwareUpdaterConfig;
+    use crate::image::tests::build_image;
+    use crate::test_flash::BlockingTestFlash;
+    use crate::{
+        BlockingFirmwareState, BlockingFirmwareUpdater, BootLoader, BootLoaderConfig, ImageHeader, ImagePolicy, State,
+        Version, HEADER_LEN,
+    };
+
+    const STATE_SIZE: usize = 4096;
+
+    #[derive(Debug, PartialEq)]
+    enum Image {
+        Old,
+        New,
+    }
+
+    #[derive(Debug)]
+    enum Step {
+        /// Run the bootloader, expecting a state and, if interrupted, one of the images in the
+        /// active partition once it is run again.
+        Boot(State, &'static [Image]),
+        /// Mark the update as booted from the application, running the given image.
+        MarkBooted(Image),
+    }
+
+    // Power lost once a swap is complete but before the application marks it booted is a failed
+    // trial boot, which reverts the update.
+    const SWAP: &[Image] = &[Image::New, Image::Old];
+
+    /// An update that is not confirmed, and reverted.
+    const REVERT: &[Step] = &[
+        Step::Boot(State::Swap, SWAP),
+        Step::Boot(State::Swap, &[Image::Old]),
+        Step::Boot(State::Revert, &[Image::Old]),
+        Step::MarkBooted(Image::Old),
+        Step::Boot(State::Boot, &[Image::Old]),
+    ];
+    /// An update confirmed by the application.
+    const CONFIRM: &[Step] = &[
+        Step::Boot(State::Swap, SWAP),
+        Step::MarkBooted(Image::New),
+        Step::Boot(State::Boot, &[Image::New]),
+    ];
+
+    type Device<const AE: usize, const AW: usize, const DE: usize, const DW: usize> =
+        BlockingTestFlash<FaultFlash<AE, AW>, FaultFlash<DE, DW>, FaultFlash<STATE_SIZE, AW>>;
+
+    fn boot<const AE: usize, const AW: usize, const DE: usize, const DW: usize>(
+        device: &Device<AE, AW, DE, DW>,
+    ) -> Option<State> {
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: device.active(),
+            dfu: device.dfu(),
+            state: device.state(),
+        });
+        let mut page = [0; 512];
+        bootloader.prepare_boot(&mut page).ok()
+    }
+
+    /// Images whose bytes all depend on their offset, so that misplaced pages are noticed.
+    fn images(len: usize) -> (Vec<u8>, Vec<u8>) {
+        let old = (0..len).map(|i| (i ^ (i >> 8)) as u8).collect();
+        let new = (0..len).map(|i| (i ^ (i >> 8) ^ 0xa5) as u8).collect();
+        (old, new)
+    }
+
+    /// Run `steps` on a device with the given number of pages, cutting power during operation
+    /// `cuts[0]`, then `cuts[1]` after rebooting, and so on.
+    ///
+    /// Returns the number of erases and writes done by the steps without power cuts.
+    fn run<const AE: usize, const AW: usize, const DE: usize, const DW: usize>(
+        pages: usize,
+        steps: &[Step],
+        cuts: &[usize],
+    ) -> usize {
+        let page_size = AE.max(DE);
+        let (old, new) = images(pages * page_size);
+        let power = Power::new(cuts.iter().fold(1, |seed, &cut| seed * 1000 + cut as u64));
+        let device: Device<AE, AW, DE, DW> = BlockingTestFlash::new(BootLoaderConfig {
+            active: FaultFlash::new(&old, old.len(), power.clone()),
+            dfu: FaultFlash::new(&new, new.len() + page_size, power.clone()),
+            state: FaultFlash::new(&[], STATE_SIZE, power.clone()),
+        });
+        let mut aligned = [0; AW];
+        BlockingFirmwareState::new(device.state(), &mut aligned)
+            .mark_updated()
+            .unwrap();
+
+        power.arm(cuts.first().copied());
+        for step in steps {
+            let images = match step {
+                Step::Boot(expected, images) => match boot(&device) {
+                    Some(state) => {
+                        assert_eq!(*expected, state, "{:?}", cuts);
+                        continue;
+                    }
+                    None => images,
+                },
+                Step::MarkBooted(image) => match BlockingFirmwareState::new(device.state(), &mut aligned).mark_booted()
+                {
+                    Ok(()) => continue,
+                    Err(_) => core::slice::from_ref(image),
+                },
+            };
+
+            // Reboot until the bootloader completes, cutting power again if requested.
+            assert!(power.is_off());
+            let mut again = cuts[1..].iter().copied();
+            let state = loop {
+                power.restore(again.next());
+                if let Some(state) = boot(&device) {
+                    break state;
+                }
+            };
+
+            // The interrupted step has completed, or was not started for a bootloader step cut
+            // before it modified the active partition.
+            let mut active = vec![0; old.len()];
+            device.active().read(0, &mut active).unwrap();
+            let image = match active {
+                _ if active == old => Image::Old,
+                _ if active == new => Image::New,
+                _ => panic!("{:?}: corrupted image after {:?}", cuts, step),
+            };
+            assert!(
+                images.contains(&image),
+                "{:?}: {:?} image after {:?}",
+                cuts,
+                image,
+                step
+            );
+            if state == State::Revert {
+                assert_eq!(Image::Old, image, "{:?}: update not reverted", cuts);
+            }
+
+            // The device keeps working once the image is confirmed.
+            power.arm(None);
+            BlockingFirmwareState::new(device.state(), &mut aligned)
+                .mark_booted()
+                .unwrap();
+            assert_eq!(Some(State::Boot), boot(&device), "{:?}", cuts);
+            device.active().read(0, &mut active).unwrap();
+            assert!(
+                active == old || active == new,
+                "{:?}: corrupted image after confirming",
+                cuts
+            );
+            return power.ops();
+        }
+        power.ops()
+    }
+
+    /// Cut power once at every erase and write of the steps.
+    fn check<const AE: usize, const AW: usize, const DE: usize, const DW: usize>(pages: usize) {
+        for steps in [REVERT, CONFIRM] {
+            let ops = run::<AE, AW, DE, DW>(pages, steps, &[]);
+            for cut in 0..ops {
+                run::<AE, AW, DE, DW>(pages, steps, &[cut]);
+            }
+        }
+    }
+
+    #[test]
+    fn power_fail_same_page_size() {
+        for pages in 1..=3 {
+            check::<1024, 4, 1024, 4>(pages);
+        }
+        check::<2048, 8, 2048, 8>(2);
+    }
+
+    #[test]
+    fn power_fail_active_page_biggest() {
+        for pages in 1..=3 {
+            check::<2048, 4, 1024, 8>(pages);
+        }
+    }
+
+    #[test]
+    fn power_fail_dfu_page_biggest() {
+        for pages in 1..=3 {
+            check::<1024, 8, 2048, 4>(pages);
+        }
+    }
+
+    #[test]
+    fn power_fail_twice() {
+        // Cut power again at every operation of the recovery.
+        for steps in [REVERT, CONFIRM] {
+            let ops = run::<1024, 4, 1024, 4>(2, steps, &[]);
+            for cut in 0..ops {
+                for again in 0..ops {
+                    run::<1024, 4, 1024, 4>(2, steps, &[cut, again]);
+                }
+            }
+        }
+    }
+
+    /// Device whose state partition has 64 byte pages, the last one holding 8 copies of the
+    /// anti-rollback counter.
+    type CounterDevice = BlockingTestFlash<FaultFlash<1024, 4>, FaultFlash<1024, 4>, FaultFlash<64, 4>>;
+
+    /// Number of updates applied, filling the counter page once.
+    const UPDATES: u32 = 10;
+
+    fn write_image(flash: &mut impl NorFlash, counter: u32) {
+        let header = ImageHeader::new(Version::new(1, counter as u8, 0), counter, 0x1234, 1024);
+        let mut image = [0xff; 2048];
+        build_image(&header, &[counter as u8; 1024], None, &mut image);
+        flash.erase(0, image.len() as u32).unwrap();
+        flash.write(0, &image).unwrap();
+    }
+
+    fn counter_bootloader(
+        device: &CounterDevice,
+    ) -> BootLoader<impl NorFlash + use<'_>, impl NorFlash + use<'_>, impl NorFlash + use<'_>> {
+        BootLoader::new(BootLoaderConfig {
+            active: device.active(),
+            dfu: device.dfu(),
+            state: device.state(),
+        })
+        .with_image_policy(ImagePolicy::new(0x1234))
+    }
+
+    fn counter_boot(device: &CounterDevice) -> Option<State> {
+        let mut page = [0; 512];
+        counter_bootloader(device).prepare_boot(&mut page).ok()
+    }
+
+    fn stored_counter(device: &CounterDevice) -> u32 {
+        let mut page = [0; 512];
+        counter_bootloader(device).security_counter(&mut page).unwrap()
+    }
+
+    fn active_counter(device: &CounterDevice) -> u32 {
+        let mut bytes = [0; HEADER_LEN];
+        device.active().read(0, &mut bytes).unwrap();
+        ImageHeader::parse(&bytes).unwrap().security_counter
+    }
+
+    /// Apply and confirm updates raising the security counter, cutting power during operation `cut`
+    /// of the bootloader and application, and check the anti-rollback counter is never lowered.
+    ///
+    /// Returns the number of erases and writes done without a power cut.
+    fn run_counter(cut: Option<usize>) -> usize {
+        let power = Power::new(cut.map_or(0, |cut| cut as u64 + 1));
+        let device: CounterDevice = BlockingTestFlash::new(BootLoaderConfig {
+            active: FaultFlash::new(&[], 2048, power.clone()),
+            dfu: FaultFlash::new(&[], 3072, power.clone()),
+            state: FaultFlash::new(&[], 192, power.clone()),
+        });
+        write_image(&mut device.active(), 0);
+        let mut aligned = [0; 4];
+
+        let mut ops = 0;
+        for counter in 1..=UPDATES {
+            power.arm(None);
+            write_image(&mut device.dfu(), counter);
+            BlockingFirmwareState::new(device.state(), &mut aligned)
+                .with_counter_page()
+                .mark_updated()
+                .unwrap();
+
+            power.arm(cut.and_then(|cut| cut.checked_sub(ops)));
+            let done = counter_boot(&device).is_some_and(|state| {
+                assert_eq!(State::Swap, state, "{:?}", cut);
+                true
+            }) && BlockingFirmwareState::new(device.state(), &mut aligned)
+                .with_counter_page()
+                .mark_booted()
+                .is_ok()
+                && counter_boot(&device).is_some_and(|state| {
+                    assert_eq!(State::Boot, state, "{:?}", cut);
+                    true
+                });
+            if !done {
+                assert!(power.is_off());
+                power.restore(None);
+                assert!(
+                    stored_counter(&device) >= counter - 1,
+                    "{:?}: counter lowered during update {}",
+                    cut,
+                    counter
+                );
+
+                // The counter is raised once the device is running again.
+                while counter_boot(&device).is_none() {}
+                BlockingFirmwareState::new(device.state(), &mut aligned)
+                    .with_counter_page()
+                    .mark_booted()
+                    .unwrap();
+                assert_eq!(Some(State::Boot), counter_boot(&device), "{:?}", cut);
+                assert_eq!(active_counter(&device), stored_counter(&device), "{:?}", cut);
+                assert!(active_counter(&device) >= counter - 1, "{:?}", cut);
+                return ops + power.ops();
+            }
+            ops += power.ops();
+            assert_eq!(counter, stored_counter(&device));
+        }
+        ops
+    }
+
+    #[test]
+    fn power_fail_counter() {
+        let ops = run_counter(None);
+        for cut in 0..ops {
+            run_counter(Some(cut));
+        }
+    }
+
+    /// Decompress an update smaller than the active partition, cutting power during operation
+    /// `cuts[0]` of the bootloader, then `cuts[1]` after rebooting, and so on.
+    ///
+    /// Returns the number of erases and writes done by the bootloader without power cuts.
+    fn run_compressed(cuts: &[usize]) -> usize {
+        let (old, _) = images(4 * 1024);
+        let new = compressible(old.len() - 100);
+        let mut frame = compress(&new);
+        frame.resize(frame.len().next_multiple_of(4), 0xff);
+        let power = Power::new(cuts.iter().fold(1, |seed, &cut| seed * 1000 + cut as u64));
+        let device: Device<1024, 4, 1024, 4> = BlockingTestFlash::new(BootLoaderConfig {
+            active: FaultFlash::new(&old, old.len(), power.clone()),
+            dfu: FaultFlash::new(&frame, frame.len().next_multiple_of(1024), power.clone()),
+            state: FaultFlash::new(&[], STATE_SIZE, power.clone()),
+        });
+        let mut aligned = [0; 4];
+        let mut buf = [0; 64];
+        BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: device.dfu(),
+                state: device.state(),
+            },
+            &mut aligned,
+        )
+        .mark_updated_compressed(&mut buf)
+        .unwrap();
+
+        power.arm(cuts.first().copied());
+        let mut again = cuts.iter().skip(1).copied();
+        let state = loop {
+            if let Some(state) = boot(&device) {
+                break state;
+            }
+            power.restore(again.next());
+        };
+        let ops = power.ops();
+        assert_eq!(State::Boot, state, "{:?}", cuts);
+        let mut active = vec![0; new.len()];
+        device.active().read(0, &mut active).unwrap();
+        assert!(active == new, "{:?}: corrupted image", cuts);
+        assert_eq!(Some(State::Boot), boot(&device), "{:?}", cuts);
+        ops
+    }
+
+    #[test]
+    fn power_fail_compressed() {
+        let ops = run_compressed(&[]);
+        for cut in 0..ops {
+            run_compressed(&[cut]);
+            for again in 0..ops {
+                run_compressed(&[cut, again]);
+            }
+        }
+    }
+}
@@ -1,5 +1,9 @@
 mod asynch;
 mod blocking;
+mod fault;
+mod multi;
 
 pub(crate) use asynch::AsyncTestFlash;
 pub(crate) use blocking::BlockingTestFlash;
+pub(crate) use fault::{FaultFlash, Power};
+pub(crate) use multi::MultiTestFlash;
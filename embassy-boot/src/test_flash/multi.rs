@@ -0,0 +1,74 @@
+use core::cell::RefCell;
+
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embedded_storage::nor_flash::NorFlash;
+
+use super::BlockingTestFlash;
+use crate::{ImageSlot, MultiBootLoaderConfig};
+
+type Flash<F> = Mutex<NoopRawMutex, RefCell<F>>;
+
+pub struct MultiTestFlash<ACTIVE, DFU, STATE, const N: usize>
+where
+    ACTIVE: NorFlash,
+    DFU: NorFlash,
+    STATE: NorFlash,
+{
+    images: [ImageSlot<Flash<ACTIVE>, Flash<DFU>>; N],
+    state: Flash<STATE>,
+}
+
+impl<ACTIVE, DFU, STATE, const N: usize> MultiTestFlash<ACTIVE, DFU, STATE, N>
+where
+    ACTIVE: NorFlash,
+    DFU: NorFlash,
+    STATE: NorFlash,
+{
+    pub fn new(config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>) -> Self {
+        Self {
+            images: config.images.map(|slot| ImageSlot {
+                active: Mutex::new(RefCell::new(slot.active)),
+                dfu: Mutex::new(RefCell::new(slot.dfu)),
+            }),
+            state: Mutex::new(RefCell::new(config.state)),
+        }
+    }
+
+    pub fn active(&self, index: usize) -> BlockingPartition<NoopRawMutex, ACTIVE> {
+        BlockingTestFlash::<ACTIVE, DFU, STATE>::create_partition(&self.images[index].active)
+    }
+
+    pub fn dfu(&self, index: usize) -> BlockingPartition<NoopRawMutex, DFU> {
+        BlockingTestFlash::<ACTIVE, DFU, STATE>::create_partition(&self.images[index].dfu)
+    }
+
+    /// The whole state partition, used by the bootloader.
+    pub fn state(&self) -> BlockingPartition<NoopRawMutex, STATE> {
+        BlockingTestFlash::<ACTIVE, DFU, STATE>::create_partition(&self.state)
+    }
+
+    /// The region of the state partition holding the state of the update set, used by the application.
+    pub fn set_state(&self) -> BlockingPartition<NoopRawMutex, STATE> {
+        let size = self.state.lock(|f| f.borrow().capacity()) / (N + 1) / STATE::ERASE_SIZE * STATE::ERASE_SIZE;
+        BlockingPartition::new(&self.state, 0, size as u32)
+    }
+
+    pub fn config(
+        &self,
+    ) -> MultiBootLoaderConfig<
+        BlockingPartition<NoopRawMutex, ACTIVE>,
+        BlockingPartition<NoopRawMutex, DFU>,
+        BlockingPartition<NoopRawMutex, STATE>,
+        N,
+    > {
+        MultiBootLoaderConfig {
+            images: core::array::from_fn(|index| ImageSlot {
+                active: self.active(index),
+                dfu: self.dfu(index),
+            }),
+            state: self.state(),
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Bit-banged I2C controller
+//!
+//! Both pins must behave as open-drain outputs that can be read back: setting them high releases
+//! the line, setting them low pulls it down. The controller waits for SCL to actually go high
+//! after releasing it, so targets can stretch the clock, up to [`Config::timeout`].
+//!
+//! # Example (rp2040)
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::bitbang::i2c::{Config, I2c};
+//! use embassy_rp::gpio::{Flex, Pull};
+//!
+//! let mut scl = Flex::new(p.PIN_2);
+//! let mut sda = Flex::new(p.PIN_3);
+//! scl.set_as_output_open_drain();
+//! sda.set_as_output_open_drain();
+//!
+//! let mut i2c = I2c::new(scl, sda, Config::default());
+//! let mut temp = [0; 2];
+//! i2c.write_read(0x48, &[0x00], &mut temp).await?;
+//! ```
+
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_1::digital::{InputPin, OutputPin};
+use embedded_hal_1::i2c::{ErrorKind, NoAcknowledgeSource, Operation};
+use embedded_hal_async::i2c;
+
+use crate::SetConfig;
+
+/// Bit-banged I2C configuration.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// SCL frequency in Hz.
+    pub frequency: u32,
+    /// Maximum time a target may stretch the clock before the transaction fails with
+    /// [`Error::Timeout`].
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            frequency: 100_000,
+            timeout: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Bit-banged I2C error.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// Setting or reading one of the pins failed.
+    Pin,
+    /// The target did not acknowledge the address or a data byte.
+    NoAcknowledge(NoAcknowledgeSource),
+    /// SDA was pulled low by another controller while we released it.
+    ArbitrationLoss,
+    /// SCL was held low for longer than [`Config::timeout`].
+    Timeout,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Error {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self {
+            Self::Pin => defmt::write!(f, "Pin"),
+            Self::NoAcknowledge(source) => defmt::write!(f, "NoAcknowledge({})", defmt::Debug2Format(source)),
+            Self::ArbitrationLoss => defmt::write!(f, "ArbitrationLoss"),
+            Self::Timeout => defmt::write!(f, "Timeout"),
+        }
+    }
+}
+
+impl i2c::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Pin => ErrorKind::Bus,
+            Self::NoAcknowledge(source) => ErrorKind::NoAcknowledge(*source),
+            Self::ArbitrationLoss => ErrorKind::ArbitrationLoss,
+            Self::Timeout => ErrorKind::Other,
+        }
+    }
+}
+
+/// Bit-banged I2C controller.
+pub struct I2c<SCL, SDA> {
+    scl: SCL,
+    sda: SDA,
+    config: Config,
+    half_period: Duration,
+}
+
+impl<SCL, SDA> I2c<SCL, SDA>
+where
+    SCL: OutputPin + InputPin,
+    SDA: OutputPin + InputPin,
+{
+    /// Create a new bit-banged I2C controller.
+    ///
+    /// Both lines are released. Panics if `config.frequency` is zero.
+    pub fn new(mut scl: SCL, mut sda: SDA, config: Config) -> Self {
+        let half_period = super::half_period(config.frequency).expect("frequency must not be zero");
+        let _ = sda.set_high();
+        let _ = scl.set_high();
+        Self {
+            scl,
+            sda,
+            config,
+            half_period,
+        }
+    }
+
+    /// Release the pins.
+    pub fn release(self) -> (SCL, SDA) {
+        (self.scl, self.sda)
+    }
+
+    async fn delay(&self) {
+        Timer::after(self.half_period).await
+    }
+
+    /// Release SCL and wait for it to go high, allowing targets to stretch the clock.
+    async fn release_scl(&mut self) -> Result<(), Error> {
+        self.scl.set_high().map_err(|_| Error::Pin)?;
+        let deadline = Instant::now() + self.config.timeout;
+        while self.scl.is_low().map_err(|_| Error::Pin)? {
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            Timer::after(self.half_period).await;
+        }
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<(), Error> {
+        // For a repeated start SCL is low here, release SDA first so we don't generate a STOP.
+        self.sda.set_high().map_err(|_| Error::Pin)?;
+        self.delay().await;
+        self.release_scl().await?;
+        if self.sda.is_low().map_err(|_| Error::Pin)? {
+            return Err(Error::ArbitrationLoss);
+        }
+        self.delay().await;
+        self.sda.set_low().map_err(|_| Error::Pin)?;
+        self.delay().await;
+        self.scl.set_low().map_err(|_| Error::Pin)?;
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), Error> {
+        self.sda.set_low().map_err(|_| Error::Pin)?;
+        self.delay().await;
+        self.release_scl().await?;
+        self.delay().await;
+        self.sda.set_high().map_err(|_| Error::Pin)?;
+        self.delay().await;
+        Ok(())
+    }
+
+    async fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        if bit {
+            self.sda.set_high().map_err(|_| Error::Pin)?;
+        } else {
+            self.sda.set_low().map_err(|_| Error::Pin)?;
+        }
+        self.delay().await;
+        self.release_scl().await?;
+        if bit && self.sda.is_low().map_err(|_| Error::Pin)? {
+            return Err(Error::ArbitrationLoss);
+        }
+        self.delay().await;
+        self.scl.set_low().map_err(|_| Error::Pin)?;
+        Ok(())
+    }
+
+    async fn read_bit(&mut self) -> Result<bool, Error> {
+        self.sda.set_high().map_err(|_| Error::Pin)?;
+        self.delay().await;
+        self.release_scl().await?;
+        let bit = self.sda.is_high().map_err(|_| Error::Pin)?;
+        self.delay().await;
+        self.scl.set_low().map_err(|_| Error::Pin)?;
+        Ok(bit)
+    }
+
+    /// Write a byte, returning whether the target acknowledged it.
+    async fn write_byte(&mut self, byte: u8) -> Result<bool, Error> {
+        for i in (0..8).rev() {
+            self.write_bit(byte & (1 << i) != 0).await?;
+        }
+        Ok(!self.read_bit().await?)
+    }
+
+    async fn read_byte(&mut self, ack: bool) -> Result<u8, Error> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | self.read_bit().await? as u8;
+        }
+        self.write_bit(!ack).await?;
+        Ok(byte)
+    }
+
+    async fn operations(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Error> {
+        let mut previous_read = None;
+        for i in 0..operations.len() {
+            let read = matches!(operations[i], Operation::Read(_));
+            // Adjacent operations of the same kind are merged, without a repeated start.
+            if previous_read != Some(read) {
+                self.start().await?;
+                if !self.write_byte((address << 1) | read as u8).await? {
+                    return Err(Error::NoAcknowledge(NoAcknowledgeSource::Address));
+                }
+            }
+            previous_read = Some(read);
+
+            let next_read = matches!(operations.get(i + 1), Some(Operation::Read(_)));
+            match &mut operations[i] {
+                Operation::Read(buf) => {
+                    // The last byte before a direction change or the STOP is NACKed.
+                    let len = buf.len();
+                    for (j, byte) in buf.iter_mut().enumerate() {
+                        *byte = self.read_byte(next_read || j + 1 < len).await?;
+                    }
+                }
+                Operation::Write(buf) => {
+                    for byte in buf.iter() {
+                        if !self.write_byte(*byte).await? {
+                            return Err(Error::NoAcknowledge(NoAcknowledgeSource::Data));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<SCL, SDA> i2c::ErrorType for I2c<SCL, SDA> {
+    type Error = Error;
+}
+
+impl<SCL, SDA> i2c::I2c for I2c<SCL, SDA>
+where
+    SCL: OutputPin + InputPin,
+    SDA: OutputPin + InputPin,
+{
+    async fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+
+        let res = self.operations(address, operations).await;
+        match res {
+            // Another controller owns the bus now, don't interfere with it.
+            Err(Error::ArbitrationLoss) => {
+                let _ = self.sda.set_high();
+                let _ = self.scl.set_high();
+                res
+            }
+            // On other failures it's important to still release the bus with a STOP.
+            Err(_) => {
+                let _ = self.stop().await;
+                res
+            }
+            Ok(()) => self.stop().await,
+        }
+    }
+}
+
+impl<SCL, SDA> SetConfig for I2c<SCL, SDA> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.half_period = super::half_period(config.frequency).ok_or(())?;
+        self.config = *config;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_async::i2c::I2c as _;
+
+    use super::*;
+    use crate::bitbang::sim::{run, I2cTarget};
+
+    #[test]
+    fn write_then_read_back() {
+        let (target, scl, sda) = I2cTarget::new(0x50);
+        let mut i2c = I2c::new(scl, sda, Config::default());
+
+        run(async {
+            i2c.write(0x50, &[0x02, 0xaa, 0xbb]).await.unwrap();
+            let mut buf = [0; 3];
+            i2c.write_read(0x50, &[0x01], &mut buf).await.unwrap();
+            assert_eq!(buf, [0x00, 0xaa, 0xbb]);
+        });
+
+        let target = target.borrow();
+        assert_eq!(target.memory[..4], [0x00, 0x00, 0xaa, 0xbb]);
+        assert!(target.stopped);
+    }
+
+    #[test]
+    fn merged_operations_and_clock_stretching() {
+        let (target, scl, sda) = I2cTarget::new(0x50);
+        target.borrow_mut().stretch = Duration::from_micros(50);
+        let mut i2c = I2c::new(scl, sda, Config::default());
+
+        run(async {
+            let mut buf = [0; 2];
+            i2c.transaction(
+                0x50,
+                &mut [
+                    Operation::Write(&[0x04]),
+                    Operation::Write(&[0x11, 0x22]),
+                    Operation::Read(&mut buf[..1]),
+                ],
+            )
+            .await
+            .unwrap();
+            i2c.write_read(0x50, &[0x04], &mut buf).await.unwrap();
+            assert_eq!(buf, [0x11, 0x22]);
+        });
+    }
+
+    #[test]
+    fn address_nack() {
+        let (target, scl, sda) = I2cTarget::new(0x50);
+        let mut i2c = I2c::new(scl, sda, Config::default());
+
+        let res = run(i2c.write(0x51, &[0x00]));
+        assert_eq!(res, Err(Error::NoAcknowledge(NoAcknowledgeSource::Address)));
+        assert!(target.borrow().stopped);
+    }
+
+    #[test]
+    fn stretch_timeout() {
+        let (target, scl, sda) = I2cTarget::new(0x50);
+        target.borrow_mut().stretch = Duration::from_secs(1);
+        let mut i2c = I2c::new(scl, sda, Config::default());
+
+        let res = run(i2c.write(0x50, &[0x00]));
+        assert_eq!(res, Err(Error::Timeout));
+    }
+}
@@ -0,0 +1,28 @@
+//! Software (bit-banged) bus implementations over GPIO pins.
+//!
+//! These drivers implement the async `embedded-hal` and `embedded-io` traits on top of any
+//! [`OutputPin`](embedded_hal_1::digital::OutputPin) / [`InputPin`](embedded_hal_1::digital::InputPin),
+//! using [`embassy_time`] for bit timing. They are useful when a chip runs out of hardware
+//! peripherals, or for protocols on pins that cannot be routed to one.
+//!
+//! Timing is only as accurate as the time driver and executor latency allow, so the
+//! achievable bit rates are much lower than with hardware peripherals. All of them implement
+//! [`SetConfig`](crate::SetConfig), so they can be used with the `*DeviceWithConfig` shared bus
+//! wrappers.
+
+pub mod i2c;
+pub mod spi;
+pub mod uart;
+
+#[cfg(test)]
+pub(crate) mod sim;
+
+use embassy_time::Duration;
+
+/// Duration of half a clock period at `frequency`, or `None` if `frequency` is zero.
+fn half_period(frequency: u32) -> Option<Duration> {
+    match frequency {
+        0 => None,
+        f => Some(Duration::from_hz(2 * f as u64)),
+    }
+}
@@ -0,0 +1,424 @@
+//! Simulated pins and bus targets for testing the bit-banged drivers on the host.
+
+extern crate std;
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use embassy_time::{Duration, Instant, MockDriver};
+use embedded_hal_1::digital::{ErrorType, InputPin, OutputPin};
+use embedded_hal_1::spi::{Mode, Phase, Polarity};
+use embedded_hal_async::digital::Wait;
+
+/// Run `fut` to completion, advancing the mock time driver by one tick whenever it is pending.
+///
+/// Tests share the global mock driver, so they are serialized.
+pub(crate) fn run<F: Future>(fut: F) -> F::Output {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    MockDriver::get().reset();
+
+    let mut fut = pin!(fut);
+    let mut cx = Context::from_waker(Waker::noop());
+    for _ in 0..10_000_000 {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out;
+        }
+        MockDriver::get().advance(Duration::from_ticks(1));
+    }
+    panic!("simulation did not finish");
+}
+
+/// Something driving and observing the simulated pins.
+pub(crate) trait Device {
+    fn set(&mut self, pin: usize, high: bool);
+    fn get(&mut self, pin: usize) -> bool;
+}
+
+/// A pin connected to a simulated [`Device`].
+pub(crate) struct SimPin<D> {
+    device: Rc<RefCell<D>>,
+    pin: usize,
+}
+
+impl<D: Device> SimPin<D> {
+    fn new(device: &Rc<RefCell<D>>, pin: usize) -> Self {
+        Self {
+            device: device.clone(),
+            pin,
+        }
+    }
+
+    fn level(&self) -> bool {
+        self.device.borrow_mut().get(self.pin)
+    }
+
+    async fn wait_until(&mut self, mut cond: impl FnMut(bool, bool) -> bool) -> Result<(), Infallible> {
+        let mut last = self.level();
+        poll_fn(|_| {
+            let now = self.level();
+            let done = cond(last, now);
+            last = now;
+            match done {
+                true => Poll::Ready(Ok(())),
+                false => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl<D> ErrorType for SimPin<D> {
+    type Error = Infallible;
+}
+
+impl<D: Device> OutputPin for SimPin<D> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.device.borrow_mut().set(self.pin, false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.device.borrow_mut().set(self.pin, true);
+        Ok(())
+    }
+}
+
+impl<D: Device> InputPin for SimPin<D> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.level())
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.level())
+    }
+}
+
+impl<D: Device> Wait for SimPin<D> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_until(|_, now| now).await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_until(|_, now| !now).await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_until(|last, now| !last && now).await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_until(|last, now| last && !now).await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_until(|last, now| last != now).await
+    }
+}
+
+/// A single wire, pin 0 drives it and pin 1 reads it.
+pub(crate) struct Loopback {
+    line: bool,
+}
+
+impl Loopback {
+    pub(crate) fn new() -> (SimPin<Self>, SimPin<Self>) {
+        let device = Rc::new(RefCell::new(Self { line: true }));
+        (SimPin::new(&device, 0), SimPin::new(&device, 1))
+    }
+}
+
+impl Device for Loopback {
+    fn set(&mut self, _pin: usize, high: bool) {
+        self.line = high;
+    }
+
+    fn get(&mut self, _pin: usize) -> bool {
+        self.line
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum I2cState {
+    Idle,
+    Receive {
+        bits: u8,
+        byte: u8,
+        address: bool,
+        first: bool,
+    },
+    AckOut {
+        read: bool,
+        address: bool,
+    },
+    Transmit {
+        bits: u8,
+        byte: u8,
+    },
+    AckIn {
+        ack: bool,
+    },
+    Ignore,
+}
+
+/// An I2C target with 16 bytes of memory, addressed by the first byte written.
+///
+/// Pin 0 is SCL and pin 1 is SDA, both open-drain.
+pub(crate) struct I2cTarget {
+    address: u8,
+    pub memory: [u8; 16],
+    /// Whether the last condition seen on the bus was a STOP.
+    pub stopped: bool,
+    /// How long to hold SCL low after each byte.
+    pub stretch: Duration,
+    pointer: usize,
+    controller: [bool; 2],
+    sda_out: bool,
+    stretch_until: Instant,
+    previous: (bool, bool),
+    state: I2cState,
+}
+
+impl I2cTarget {
+    pub(crate) fn new(address: u8) -> (Rc<RefCell<Self>>, SimPin<Self>, SimPin<Self>) {
+        let device = Rc::new(RefCell::new(Self {
+            address,
+            memory: [0; 16],
+            stopped: false,
+            stretch: Duration::from_ticks(0),
+            pointer: 0,
+            controller: [true, true],
+            sda_out: true,
+            stretch_until: Instant::from_ticks(0),
+            previous: (true, true),
+            state: I2cState::Idle,
+        }));
+        let scl = SimPin::new(&device, 0);
+        let sda = SimPin::new(&device, 1);
+        (device, scl, sda)
+    }
+
+    fn lines(&self) -> (bool, bool) {
+        let scl = self.controller[0] && Instant::now() >= self.stretch_until;
+        let sda = self.controller[1] && self.sda_out;
+        (scl, sda)
+    }
+
+    fn update(&mut self) {
+        let (scl, sda) = self.lines();
+        let (prev_scl, prev_sda) = self.previous;
+
+        if scl && prev_scl && sda != prev_sda {
+            if sda {
+                self.state = I2cState::Idle;
+                self.stopped = true;
+            } else {
+                self.state = I2cState::Receive {
+                    bits: 0,
+                    byte: 0,
+                    address: true,
+                    first: false,
+                };
+                self.stopped = false;
+            }
+            self.sda_out = true;
+        } else if scl && !prev_scl {
+            self.rising(sda);
+        } else if !scl && prev_scl {
+            self.falling();
+        }
+        self.previous = self.lines();
+    }
+
+    fn rising(&mut self, sda: bool) {
+        match &mut self.state {
+            I2cState::Receive { bits, byte, .. } => {
+                *byte = (*byte << 1) | sda as u8;
+                *bits += 1;
+            }
+            I2cState::AckIn { ack } => *ack = !sda,
+            _ => {}
+        }
+    }
+
+    fn falling(&mut self) {
+        match self.state {
+            I2cState::Receive {
+                bits: 8,
+                byte,
+                address,
+                first,
+            } => {
+                if address && byte >> 1 != self.address {
+                    self.state = I2cState::Ignore;
+                    return;
+                }
+                if !address {
+                    if first {
+                        self.pointer = byte as usize;
+                    } else {
+                        self.memory[self.pointer % 16] = byte;
+                        self.pointer += 1;
+                    }
+                }
+                self.sda_out = false;
+                self.stretch_until = Instant::now() + self.stretch;
+                self.state = I2cState::AckOut {
+                    read: address && byte & 1 == 1,
+                    address,
+                };
+            }
+            I2cState::AckOut { read: true, .. } | I2cState::AckIn { ack: true } => {
+                let byte = self.memory[self.pointer % 16];
+                self.pointer += 1;
+                self.state = I2cState::Transmit { bits: 0, byte };
+                self.transmit();
+            }
+            I2cState::AckOut { read: false, address } => {
+                self.sda_out = true;
+                self.state = I2cState::Receive {
+                    bits: 0,
+                    byte: 0,
+                    address: false,
+                    first: address,
+                };
+            }
+            I2cState::Transmit { .. } => self.transmit(),
+            I2cState::AckIn { ack: false } => {
+                self.sda_out = true;
+                self.state = I2cState::Ignore;
+            }
+            _ => {}
+        }
+    }
+
+    fn transmit(&mut self) {
+        if let I2cState::Transmit { bits, byte } = &mut self.state {
+            if *bits == 8 {
+                self.sda_out = true;
+                self.state = I2cState::AckIn { ack: false };
+            } else {
+                self.sda_out = *byte & (0x80 >> *bits) != 0;
+                *bits += 1;
+            }
+        }
+    }
+}
+
+impl Device for I2cTarget {
+    fn set(&mut self, pin: usize, high: bool) {
+        self.update();
+        self.controller[pin] = high;
+        self.update();
+    }
+
+    fn get(&mut self, pin: usize) -> bool {
+        self.update();
+        let (scl, sda) = self.lines();
+        [scl, sda][pin]
+    }
+}
+
+/// An SPI target replying with a fixed sequence of bytes.
+///
+/// Pin 0 is SCK, pin 1 is MOSI and pin 2 is MISO.
+pub(crate) struct SpiTarget {
+    mode: Mode,
+    lsb_first: bool,
+    /// Bytes received from the controller.
+    pub received: Vec<u8>,
+    response: VecDeque<u8>,
+    sck: bool,
+    mosi: bool,
+    miso: bool,
+    in_byte: u8,
+    in_bits: u8,
+    out_byte: u8,
+    out_bits: u8,
+}
+
+impl SpiTarget {
+    pub(crate) fn new(
+        mode: Mode,
+        lsb_first: bool,
+        response: &[u8],
+    ) -> (Rc<RefCell<Self>>, SimPin<Self>, SimPin<Self>, SimPin<Self>) {
+        let mut target = Self {
+            mode,
+            lsb_first,
+            received: Vec::new(),
+            response: response.iter().copied().collect(),
+            sck: mode.polarity == Polarity::IdleHigh,
+            mosi: false,
+            miso: false,
+            in_byte: 0,
+            in_bits: 0,
+            out_byte: 0,
+            out_bits: 8,
+        };
+        if mode.phase == Phase::CaptureOnFirstTransition {
+            target.shift_out();
+        }
+        let device = Rc::new(RefCell::new(target));
+        let (sck, mosi, miso) = (
+            SimPin::new(&device, 0),
+            SimPin::new(&device, 1),
+            SimPin::new(&device, 2),
+        );
+        (device, sck, mosi, miso)
+    }
+
+    fn bit(&self, n: u8) -> u8 {
+        match self.lsb_first {
+            true => n,
+            false => 7 - n,
+        }
+    }
+
+    fn shift_out(&mut self) {
+        if self.out_bits == 8 {
+            self.out_byte = self.response.pop_front().unwrap_or(0);
+            self.out_bits = 0;
+        }
+        self.miso = self.out_byte & (1 << self.bit(self.out_bits)) != 0;
+        self.out_bits += 1;
+    }
+
+    fn sample(&mut self) {
+        self.in_byte |= (self.mosi as u8) << self.bit(self.in_bits);
+        self.in_bits += 1;
+        if self.in_bits == 8 {
+            self.received.push(self.in_byte);
+            self.in_byte = 0;
+            self.in_bits = 0;
+        }
+    }
+}
+
+impl Device for SpiTarget {
+    fn set(&mut self, pin: usize, high: bool) {
+        match pin {
+            0 if high != self.sck => {
+                self.sck = high;
+                let leading = high != (self.mode.polarity == Polarity::IdleHigh);
+                match (self.mode.phase == Phase::CaptureOnFirstTransition, leading) {
+                    (true, true) | (false, false) => self.sample(),
+                    (true, false) | (false, true) => self.shift_out(),
+                }
+            }
+            1 => self.mosi = high,
+            _ => {}
+        }
+    }
+
+    fn get(&mut self, pin: usize) -> bool {
+        [self.sck, self.mosi, self.miso][pin]
+    }
+}
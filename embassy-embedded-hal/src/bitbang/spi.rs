@@ -0,0 +1,259 @@
+//! Bit-banged SPI controller
+//!
+//! Supports all four SPI modes and both bit orders. Chip select is not handled here; combine with
+//! [`SpiDevice`](crate::shared_bus::asynch::spi::SpiDevice) or drive it manually.
+//!
+//! # Example (nrf52)
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::bitbang::spi::{Config, Spi};
+//! use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
+//!
+//! let sck = Output::new(p.P0_13, Level::Low, OutputDrive::Standard);
+//! let mosi = Output::new(p.P0_14, Level::Low, OutputDrive::Standard);
+//! let miso = Input::new(p.P0_15, Pull::None);
+//!
+//! let mut spi = Spi::new(sck, mosi, miso, Config::default());
+//! spi.transfer_in_place(&mut buf).await?;
+//! ```
+
+use embassy_time::{Duration, Timer};
+use embedded_hal_1::digital::{InputPin, OutputPin, PinState};
+use embedded_hal_1::spi::{ErrorKind, Mode, Phase, Polarity, MODE_0};
+use embedded_hal_async::spi;
+
+use crate::SetConfig;
+
+/// Order in which the bits of a word are shifted out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BitOrder {
+    /// Most significant bit first.
+    MsbFirst,
+    /// Least significant bit first.
+    LsbFirst,
+}
+
+/// Bit-banged SPI configuration.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// SCK frequency in Hz.
+    pub frequency: u32,
+    /// Clock polarity and phase.
+    pub mode: Mode,
+    /// Bit order.
+    pub bit_order: BitOrder,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            frequency: 100_000,
+            mode: MODE_0,
+            bit_order: BitOrder::MsbFirst,
+        }
+    }
+}
+
+/// Bit-banged SPI error.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum Error {
+    /// Setting or reading one of the pins failed.
+    Pin,
+}
+
+impl spi::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// Bit-banged SPI controller.
+pub struct Spi<SCK, MOSI, MISO> {
+    sck: SCK,
+    mosi: MOSI,
+    miso: MISO,
+    config: Config,
+    half_period: Duration,
+}
+
+impl<SCK, MOSI, MISO> Spi<SCK, MOSI, MISO>
+where
+    SCK: OutputPin,
+    MOSI: OutputPin,
+    MISO: InputPin,
+{
+    /// Create a new bit-banged SPI controller.
+    ///
+    /// SCK is set to its idle level. Panics if `config.frequency` is zero.
+    pub fn new(sck: SCK, mosi: MOSI, miso: MISO, config: Config) -> Self {
+        let half_period = super::half_period(config.frequency).expect("frequency must not be zero");
+        let mut this = Self {
+            sck,
+            mosi,
+            miso,
+            config,
+            half_period,
+        };
+        let _ = this.set_sck(false);
+        this
+    }
+
+    /// Release the pins.
+    pub fn release(self) -> (SCK, MOSI, MISO) {
+        (self.sck, self.mosi, self.miso)
+    }
+
+    /// Drive SCK to its idle (`active == false`) or active level.
+    fn set_sck(&mut self, active: bool) -> Result<(), Error> {
+        let idle_high = self.config.mode.polarity == Polarity::IdleHigh;
+        self.sck
+            .set_state(PinState::from(idle_high != active))
+            .map_err(|_| Error::Pin)
+    }
+
+    async fn transfer_byte(&mut self, out: u8) -> Result<u8, Error> {
+        let mut word = 0;
+        for i in 0..8 {
+            let shift = match self.config.bit_order {
+                BitOrder::MsbFirst => 7 - i,
+                BitOrder::LsbFirst => i,
+            };
+            let bit = PinState::from(out & (1 << shift) != 0);
+
+            let sample = match self.config.mode.phase {
+                Phase::CaptureOnFirstTransition => {
+                    self.mosi.set_state(bit).map_err(|_| Error::Pin)?;
+                    Timer::after(self.half_period).await;
+                    self.set_sck(true)?;
+                    let sample = self.miso.is_high().map_err(|_| Error::Pin)?;
+                    Timer::after(self.half_period).await;
+                    self.set_sck(false)?;
+                    sample
+                }
+                Phase::CaptureOnSecondTransition => {
+                    self.set_sck(true)?;
+                    self.mosi.set_state(bit).map_err(|_| Error::Pin)?;
+                    Timer::after(self.half_period).await;
+                    self.set_sck(false)?;
+                    let sample = self.miso.is_high().map_err(|_| Error::Pin)?;
+                    Timer::after(self.half_period).await;
+                    sample
+                }
+            };
+            word |= (sample as u8) << shift;
+        }
+        Ok(word)
+    }
+}
+
+impl<SCK, MOSI, MISO> spi::ErrorType for Spi<SCK, MOSI, MISO> {
+    type Error = Error;
+}
+
+impl<SCK, MOSI, MISO> spi::SpiBus for Spi<SCK, MOSI, MISO>
+where
+    SCK: OutputPin,
+    MOSI: OutputPin,
+    MISO: InputPin,
+{
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        for word in words {
+            *word = self.transfer_byte(0x00).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        for word in words {
+            self.transfer_byte(*word).await?;
+        }
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        for i in 0..read.len().max(write.len()) {
+            let word = self.transfer_byte(write.get(i).copied().unwrap_or(0x00)).await?;
+            if let Some(r) = read.get_mut(i) {
+                *r = word;
+            }
+        }
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        for word in words {
+            *word = self.transfer_byte(*word).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<SCK, MOSI, MISO> SetConfig for Spi<SCK, MOSI, MISO>
+where
+    SCK: OutputPin,
+    MOSI: OutputPin,
+    MISO: InputPin,
+{
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.half_period = super::half_period(config.frequency).ok_or(())?;
+        self.config = *config;
+        self.set_sck(false).map_err(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_1::spi::{MODE_1, MODE_2, MODE_3};
+    use embedded_hal_async::spi::SpiBus;
+
+    use super::*;
+    use crate::bitbang::sim::{run, SpiTarget};
+
+    fn check_mode(mode: Mode, bit_order: BitOrder) {
+        let config = Config {
+            mode,
+            bit_order,
+            ..Default::default()
+        };
+
+        let (target, sck, mosi, miso) = SpiTarget::new(config.mode, bit_order == BitOrder::LsbFirst, &[0x5a, 0xc3]);
+        let mut spi = Spi::new(sck, mosi, miso, config);
+
+        let mut buf = [0xa5, 0x3c];
+        run(spi.transfer_in_place(&mut buf)).unwrap();
+
+        assert_eq!(buf, [0x5a, 0xc3], "{mode:?} {bit_order:?}");
+        assert_eq!(target.borrow().received, [0xa5, 0x3c], "{mode:?} {bit_order:?}");
+    }
+
+    #[test]
+    fn all_modes() {
+        for mode in [MODE_0, MODE_1, MODE_2, MODE_3] {
+            check_mode(mode, BitOrder::MsbFirst);
+            check_mode(mode, BitOrder::LsbFirst);
+        }
+    }
+
+    #[test]
+    fn uneven_transfer() {
+        let (target, sck, mosi, miso) = SpiTarget::new(MODE_0, false, &[0x01, 0x02, 0x03]);
+        let mut spi = Spi::new(sck, mosi, miso, Config::default());
+
+        let mut read = [0; 3];
+        run(spi.transfer(&mut read, &[0xff])).unwrap();
+
+        assert_eq!(read, [0x01, 0x02, 0x03]);
+        assert_eq!(target.borrow().received, [0xff, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,407 @@
+//! Bit-banged UART
+//!
+//! Frames are 8 data bits, LSB first, with optional parity and one or two stop bits. Bit timing
+//! is scheduled from the start of each frame, so executor latency does not accumulate across
+//! the bits of a frame.
+//!
+//! The receiver only listens while a read is in progress; bytes arriving while no read is
+//! pending are lost, since there is no buffering.
+//!
+//! # Example (stm32)
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::bitbang::uart::{Config, Uart};
+//! use embassy_stm32::exti::ExtiInput;
+//! use embassy_stm32::gpio::{Level, Output, Pull, Speed};
+//!
+//! let tx = Output::new(p.PA2, Level::High, Speed::Low);
+//! let rx = ExtiInput::new(p.PA3, p.EXTI3, Pull::Up);
+//!
+//! let mut config = Config::default();
+//! config.baudrate = 9600;
+//! let mut uart = Uart::new(tx, rx, config);
+//! uart.write_all(b"hello\r\n").await?;
+//! ```
+
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_hal_1::digital::{InputPin, OutputPin, PinState};
+use embedded_hal_async::digital::Wait;
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+
+use crate::SetConfig;
+
+/// Parity bit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Parity {
+    /// No parity.
+    ParityNone,
+    /// Even parity.
+    ParityEven,
+    /// Odd parity.
+    ParityOdd,
+}
+
+/// Stop bits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StopBits {
+    #[doc = "1 stop bit"]
+    STOP1,
+    #[doc = "2 stop bits"]
+    STOP2,
+}
+
+/// Bit-banged UART configuration.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Baud rate.
+    pub baudrate: u32,
+    /// Parity bit.
+    pub parity: Parity,
+    /// Stop bits.
+    pub stop_bits: StopBits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            baudrate: 9600,
+            parity: Parity::ParityNone,
+            stop_bits: StopBits::STOP1,
+        }
+    }
+}
+
+impl Config {
+    fn bit_time(&self) -> Option<Duration> {
+        match self.baudrate {
+            0 => None,
+            b => Some(Duration::from_hz(b as u64)),
+        }
+    }
+
+    /// Number of bits in a frame, including start and stop bits.
+    fn frame_bits(&self) -> u64 {
+        let parity = match self.parity {
+            Parity::ParityNone => 0,
+            _ => 1,
+        };
+        let stop = match self.stop_bits {
+            StopBits::STOP1 => 1,
+            StopBits::STOP2 => 2,
+        };
+        1 + 8 + parity + stop
+    }
+
+    fn parity_bit(&self, byte: u8) -> Option<bool> {
+        let odd_ones = byte.count_ones() % 2 == 1;
+        match self.parity {
+            Parity::ParityNone => None,
+            Parity::ParityEven => Some(odd_ones),
+            Parity::ParityOdd => Some(!odd_ones),
+        }
+    }
+}
+
+/// Bit-banged UART error.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum Error {
+    /// Setting or reading one of the pins failed.
+    Pin,
+    /// The received parity bit didn't match the configured parity.
+    Parity,
+    /// The received character didn't have a valid stop bit.
+    Framing,
+}
+
+impl embedded_io_async::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Pin => ErrorKind::Other,
+            Self::Parity | Self::Framing => ErrorKind::InvalidData,
+        }
+    }
+}
+
+/// Transmit half of a bit-banged UART.
+pub struct UartTx<TX> {
+    tx: TX,
+    config: Config,
+    bit_time: Duration,
+}
+
+impl<TX: OutputPin> UartTx<TX> {
+    /// Create a new transmitter, setting the line to idle (high).
+    ///
+    /// Panics if `config.baudrate` is zero.
+    pub fn new(mut tx: TX, config: Config) -> Self {
+        let bit_time = config.bit_time().expect("baudrate must not be zero");
+        let _ = tx.set_high();
+        Self { tx, config, bit_time }
+    }
+
+    async fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
+        // Start bit (0), data bits, parity bit, stop bits (1), LSB first.
+        let mut frame = (byte as u16) << 1;
+        let mut len = 9;
+        if let Some(parity) = self.config.parity_bit(byte) {
+            frame |= (parity as u16) << len;
+            len += 1;
+        }
+        frame |= !0 << len;
+
+        let start = Instant::now();
+        for n in 0..self.config.frame_bits() as u32 {
+            self.tx
+                .set_state(PinState::from(frame & (1 << n) != 0))
+                .map_err(|_| Error::Pin)?;
+            Timer::at(start + self.bit_time * (n + 1)).await;
+        }
+        Ok(())
+    }
+}
+
+impl<TX> ErrorType for UartTx<TX> {
+    type Error = Error;
+}
+
+impl<TX: OutputPin> Write for UartTx<TX> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        for byte in buf {
+            self.write_byte(*byte).await?;
+        }
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<TX: OutputPin> SetConfig for UartTx<TX> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.bit_time = config.bit_time().ok_or(())?;
+        self.config = *config;
+        Ok(())
+    }
+}
+
+/// Receive half of a bit-banged UART.
+pub struct UartRx<RX> {
+    rx: RX,
+    config: Config,
+    bit_time: Duration,
+}
+
+impl<RX: InputPin + Wait> UartRx<RX> {
+    /// Create a new receiver.
+    ///
+    /// Panics if `config.baudrate` is zero.
+    pub fn new(rx: RX, config: Config) -> Self {
+        let bit_time = config.bit_time().expect("baudrate must not be zero");
+        Self { rx, config, bit_time }
+    }
+
+    /// Wait for the line to become idle (high) for a full frame, to synchronize to frame boundaries.
+    pub async fn wait_idle(&mut self) -> Result<(), Error> {
+        loop {
+            self.rx.wait_for_high().await.map_err(|_| Error::Pin)?;
+            let frame = self.bit_time * self.config.frame_bits() as u32;
+            if with_timeout(frame, self.rx.wait_for_low()).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn sample(&mut self, at: Instant) -> Result<bool, Error> {
+        Timer::at(at).await;
+        self.rx.is_high().map_err(|_| Error::Pin)
+    }
+
+    /// Receive the frame whose start bit has just been detected.
+    async fn read_frame(&mut self) -> Result<Option<u8>, Error> {
+        let start = Instant::now();
+        let bit_time = self.bit_time;
+        let mid = |n: u32| start + bit_time * n + bit_time / 2;
+
+        if self.sample(mid(0)).await? {
+            // Glitch rather than a start bit.
+            return Ok(None);
+        }
+        let mut byte = 0u8;
+        for i in 0..8 {
+            byte |= (self.sample(mid(1 + i)).await? as u8) << i;
+        }
+        let mut n = 9;
+        let parity_ok = match self.config.parity_bit(byte) {
+            Some(expected) => {
+                n += 1;
+                self.sample(mid(n - 1)).await? == expected
+            }
+            None => true,
+        };
+        if !self.sample(mid(n)).await? {
+            return Err(Error::Framing);
+        }
+        if !parity_ok {
+            return Err(Error::Parity);
+        }
+        Ok(Some(byte))
+    }
+}
+
+impl<RX> ErrorType for UartRx<RX> {
+    type Error = Error;
+}
+
+impl<RX: InputPin + Wait> Read for UartRx<RX> {
+    /// Receive at least one byte, then keep receiving until `buf` is full or the line stays idle
+    /// for a whole frame.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let frame = self.bit_time * self.config.frame_bits() as u32;
+        let mut n = 0;
+        while n < buf.len() {
+            if n == 0 {
+                self.rx.wait_for_low().await.map_err(|_| Error::Pin)?;
+            } else if with_timeout(frame, self.rx.wait_for_low()).await.is_err() {
+                break;
+            }
+            if let Some(byte) = self.read_frame().await? {
+                buf[n] = byte;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<RX: InputPin + Wait> SetConfig for UartRx<RX> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.bit_time = config.bit_time().ok_or(())?;
+        self.config = *config;
+        Ok(())
+    }
+}
+
+/// Bit-banged UART.
+pub struct Uart<TX, RX> {
+    tx: UartTx<TX>,
+    rx: UartRx<RX>,
+}
+
+impl<TX: OutputPin, RX: InputPin + Wait> Uart<TX, RX> {
+    /// Create a new UART, setting the TX line to idle (high).
+    ///
+    /// Panics if `config.baudrate` is zero.
+    pub fn new(tx: TX, rx: RX, config: Config) -> Self {
+        Self {
+            tx: UartTx::new(tx, config),
+            rx: UartRx::new(rx, config),
+        }
+    }
+
+    /// Split the UART into transmit and receive halves, which can be used from separate tasks.
+    pub fn split(self) -> (UartTx<TX>, UartRx<RX>) {
+        (self.tx, self.rx)
+    }
+}
+
+impl<TX, RX> ErrorType for Uart<TX, RX> {
+    type Error = Error;
+}
+
+impl<TX: OutputPin, RX> Write for Uart<TX, RX> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.tx.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.tx.flush().await
+    }
+}
+
+impl<TX, RX: InputPin + Wait> Read for Uart<TX, RX> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.rx.read(buf).await
+    }
+}
+
+impl<TX: OutputPin, RX: InputPin + Wait> SetConfig for Uart<TX, RX> {
+    type Config = Config;
+    type ConfigError = ();
+
+    fn set_config(&mut self, config: &Self::Config) -> Result<(), Self::ConfigError> {
+        self.tx.set_config(config)?;
+        self.rx.set_config(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::join::join;
+
+    use super::*;
+    use crate::bitbang::sim::{run, Loopback};
+
+    #[test]
+    fn loopback() {
+        for (parity, stop_bits) in [
+            (Parity::ParityNone, StopBits::STOP1),
+            (Parity::ParityEven, StopBits::STOP1),
+            (Parity::ParityOdd, StopBits::STOP2),
+        ] {
+            let config = Config {
+                parity,
+                stop_bits,
+                ..Default::default()
+            };
+
+            let (tx, rx) = Loopback::new();
+            let (mut tx, mut rx) = Uart::new(tx, rx, config).split();
+
+            let mut buf = [0; 6];
+            let (w, r) = run(join(tx.write_all(b"hello!"), rx.read_exact(&mut buf)));
+            w.unwrap();
+            r.unwrap();
+            assert_eq!(&buf, b"hello!");
+        }
+    }
+
+    #[test]
+    fn read_returns_after_idle_frame() {
+        let (tx, rx) = Loopback::new();
+        let (mut tx, mut rx) = Uart::new(tx, rx, Config::default()).split();
+
+        let mut buf = [0; 8];
+        let (w, r) = run(join(tx.write_all(b"abc"), rx.read(&mut buf)));
+        w.unwrap();
+        assert_eq!(r, Ok(3));
+        assert_eq!(&buf[..3], b"abc");
+    }
+
+    #[test]
+    fn parity_error() {
+        let mut config = Config {
+            parity: Parity::ParityOdd,
+            ..Default::default()
+        };
+        let (tx, rx) = Loopback::new();
+        let mut tx = UartTx::new(tx, config);
+        config.parity = Parity::ParityEven;
+        let mut rx = UartRx::new(rx, config);
+
+        let mut buf = [0; 1];
+        let (_, r) = run(join(tx.write_all(&[0x55]), rx.read(&mut buf)));
+        assert_eq!(r, Err(Error::Parity));
+    }
+}
@@ -0,0 +1,545 @@
+//! Directory entries.
+
+use core::ops::BitOr;
+
+use super::name::{self, AliasBasis, MAX_LONG_NAME};
+use super::{BlockDevice, Error, FatType, FileSystem, BLOCK_SIZE};
+
+/// First cluster value referring to the root directory, as used in `..` entries.
+pub(super) const ROOT: u32 = 0;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_BLOCK: u32 = (super::BLOCK_SIZE / ENTRY_SIZE) as u32;
+
+const DELETED: u8 = 0xe5;
+const LONG_NAME: u8 = 0x0f;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const CHARS_PER_LONG_ENTRY: usize = 13;
+/// Byte offsets of the UTF-16 characters in a long name entry.
+const LONG_NAME_CHARS: [usize; CHARS_PER_LONG_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// 1980-01-01, the earliest FAT date.
+const DEFAULT_DATE: [u8; 2] = [0x21, 0x00];
+
+/// File attributes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Attributes(u8);
+
+impl Attributes {
+    /// The file must not be modified.
+    pub const READ_ONLY: Self = Self(0x01);
+    /// The entry is hidden from normal directory listings.
+    pub const HIDDEN: Self = Self(0x02);
+    /// The entry belongs to the operating system.
+    pub const SYSTEM: Self = Self(0x04);
+    /// The entry holds the volume label.
+    pub const VOLUME_ID: Self = Self(0x08);
+    /// The entry is a directory.
+    pub const DIRECTORY: Self = Self(0x10);
+    /// The file was modified since the last backup.
+    pub const ARCHIVE: Self = Self(0x20);
+
+    /// Create attributes from their raw bits.
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// Get the raw bits.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether all attributes in `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Attributes {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Position of an entry within a directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) struct DirPos {
+    /// Current cluster, or [`ROOT`] for the fixed FAT12/16 root directory.
+    cluster: u32,
+    /// Block within the cluster or root directory.
+    block: u32,
+    /// Entry within the block.
+    index: u32,
+}
+
+impl DirPos {
+    pub(super) const fn start(cluster: u32) -> Self {
+        Self {
+            cluster,
+            block: 0,
+            index: 0,
+        }
+    }
+}
+
+/// An entry of a directory.
+#[derive(Clone)]
+pub struct DirEntry {
+    name: [u8; MAX_LONG_NAME],
+    name_len: u8,
+    short_name: [u8; 12],
+    short_name_len: u8,
+    raw_short_name: [u8; 11],
+    attributes: Attributes,
+    pub(super) cluster: u32,
+    pub(super) size: u32,
+    /// Position of the short name entry.
+    pub(super) pos: DirPos,
+    /// Position of the first long name entry, or of the short name entry if there is none.
+    first: DirPos,
+}
+
+impl DirEntry {
+    fn new(raw: &[u8; ENTRY_SIZE], fat_type: FatType, pos: DirPos, first: DirPos) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]) as u32;
+        let cluster_hi = match fat_type {
+            FatType::Fat32 => u16_at(20),
+            _ => 0,
+        };
+
+        let mut entry = Self {
+            name: [0; MAX_LONG_NAME],
+            name_len: 0,
+            short_name: [0; 12],
+            short_name_len: 0,
+            raw_short_name: raw[..11].try_into().unwrap(),
+            attributes: Attributes(raw[11]),
+            cluster: cluster_hi << 16 | u16_at(26),
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            pos,
+            first,
+        };
+        entry.short_name_len = name::format_short(&entry.raw_short_name, 0, &mut entry.short_name) as u8;
+
+        let mut display = [0; 12];
+        let len = name::format_short(&entry.raw_short_name, raw[12], &mut display);
+        entry.name[..len].copy_from_slice(&display[..len]);
+        entry.name_len = len as u8;
+        entry
+    }
+
+    /// Set the long name from UTF-16, keeping the short name if it does not fit.
+    fn set_long_name(&mut self, units: &[u16]) {
+        let mut name = [0; MAX_LONG_NAME];
+        let mut len = 0;
+        for c in char::decode_utf16(units.iter().copied()) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            if len + c.len_utf8() > name.len() {
+                return;
+            }
+            len += c.encode_utf8(&mut name[len..]).len();
+        }
+        self.name = name;
+        self.name_len = len as u8;
+    }
+
+    /// Get the name of the entry: its long name if it has one, or its short name otherwise.
+    ///
+    /// Long names longer than 255 bytes in UTF-8 are replaced by the short name.
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+
+    /// Get the 8.3 short name of the entry, such as `LONGFI~1.TXT`.
+    pub fn short_name(&self) -> &str {
+        core::str::from_utf8(&self.short_name[..self.short_name_len as usize]).unwrap_or("")
+    }
+
+    /// Get the attributes of the entry.
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    /// Whether the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+
+    /// Whether the entry is a file.
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    /// Get the size of the file in bytes, or zero for directories.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name) || self.short_name().eq_ignore_ascii_case(name)
+    }
+
+    fn is_dot(&self) -> bool {
+        self.raw_short_name == *b".          " || self.raw_short_name == *b"..         "
+    }
+}
+
+impl core::fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DirEntry")
+            .field("name", &self.name())
+            .field("short_name", &self.short_name())
+            .field("attributes", &self.attributes)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DirEntry {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "DirEntry {{ name: {=str}, short_name: {=str}, attributes: {}, size: {} }}",
+            self.name(),
+            self.short_name(),
+            self.attributes,
+            self.size
+        )
+    }
+}
+
+/// An open directory, listing its entries.
+///
+/// The `.` and `..` entries are skipped, as are volume labels.
+pub struct Dir<'a, D: BlockDevice<BLOCK_SIZE>> {
+    fs: &'a mut FileSystem<D>,
+    pos: Option<DirPos>,
+}
+
+impl<'a, D: BlockDevice<BLOCK_SIZE>> Dir<'a, D> {
+    pub(super) fn new(fs: &'a mut FileSystem<D>, cluster: u32) -> Self {
+        let pos = Some(fs.dir_start(cluster));
+        Self { fs, pos }
+    }
+
+    /// Get the next entry, or `None` at the end of the directory.
+    pub async fn next(&mut self) -> Result<Option<DirEntry>, Error<D::Error>> {
+        loop {
+            match self.fs.next_entry(&mut self.pos).await? {
+                Some(entry) if entry.is_dot() => continue,
+                entry => return Ok(entry),
+            }
+        }
+    }
+}
+
+/// Long name entries collected while scanning a directory.
+struct LongName {
+    units: [u16; 20 * CHARS_PER_LONG_ENTRY],
+    /// Sequence number of the last entry collected, or 0 if there is no valid long name.
+    seq: u8,
+    count: u8,
+    checksum: u8,
+    first: DirPos,
+}
+
+impl LongName {
+    fn new() -> Self {
+        Self {
+            units: [0; 20 * CHARS_PER_LONG_ENTRY],
+            seq: 0,
+            count: 0,
+            checksum: 0,
+            first: DirPos::start(ROOT),
+        }
+    }
+
+    fn push(&mut self, raw: &[u8; ENTRY_SIZE], pos: DirPos) {
+        let seq = raw[0] & !LAST_LONG_ENTRY;
+        if raw[0] & LAST_LONG_ENTRY != 0 && (1..=20).contains(&seq) {
+            self.count = seq;
+            self.checksum = raw[13];
+            self.first = pos;
+        } else if self.seq < 2 || seq != self.seq - 1 || raw[13] != self.checksum {
+            self.seq = 0;
+            return;
+        }
+        self.seq = seq;
+
+        let units = &mut self.units[(seq as usize - 1) * CHARS_PER_LONG_ENTRY..][..CHARS_PER_LONG_ENTRY];
+        for (unit, &i) in units.iter_mut().zip(LONG_NAME_CHARS.iter()) {
+            *unit = u16::from_le_bytes([raw[i], raw[i + 1]]);
+        }
+    }
+
+    /// Get the long name belonging to the short name entry `short`, if any.
+    fn get(&self, short: &[u8; 11]) -> Option<&[u16]> {
+        if self.seq != 1 || self.checksum != name::checksum(short) {
+            return None;
+        }
+        let units = &self.units[..self.count as usize * CHARS_PER_LONG_ENTRY];
+        let len = units.iter().position(|&u| u == 0).unwrap_or(units.len());
+        Some(&units[..len])
+    }
+}
+
+/// Build a short name entry.
+pub(super) fn raw_entry(short: &[u8; 11], attributes: Attributes, cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    raw[..11].copy_from_slice(short);
+    raw[11] = attributes.0;
+    raw[16..18].copy_from_slice(&DEFAULT_DATE);
+    raw[18..20].copy_from_slice(&DEFAULT_DATE);
+    raw[24..26].copy_from_slice(&DEFAULT_DATE);
+    set_cluster_and_size(&mut raw, cluster, size);
+    raw
+}
+
+fn set_cluster_and_size(raw: &mut [u8; ENTRY_SIZE], cluster: u32, size: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    raw[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> FileSystem<D> {
+    /// Get the position of the first entry of the directory starting at `cluster`.
+    pub(super) fn dir_start(&self, cluster: u32) -> DirPos {
+        match (cluster, self.fat_type) {
+            (ROOT, FatType::Fat32) => DirPos::start(self.root_cluster),
+            (cluster, _) => DirPos::start(cluster),
+        }
+    }
+
+    /// Move to the next entry, returning `false` at the end of the directory.
+    ///
+    /// If `extend` is set, a cluster is appended to the directory instead of ending it.
+    pub(super) async fn advance(&mut self, pos: &mut DirPos, extend: bool) -> Result<bool, Error<D::Error>> {
+        pos.index += 1;
+        if pos.index == ENTRIES_PER_BLOCK {
+            pos.index = 0;
+            pos.block += 1;
+        }
+        if pos.cluster == ROOT {
+            return Ok(pos.block * ENTRIES_PER_BLOCK + pos.index < self.root_entries);
+        }
+        if pos.block < self.sectors_per_cluster {
+            return Ok(true);
+        }
+
+        pos.block = 0;
+        pos.cluster = match self.next_cluster(pos.cluster).await? {
+            Some(next) => next,
+            None if extend => {
+                let next = self.alloc_cluster(Some(pos.cluster)).await?;
+                self.zero_cluster(next).await?;
+                next
+            }
+            None => return Ok(false),
+        };
+        Ok(true)
+    }
+
+    fn entry_location(&self, pos: DirPos) -> (u32, usize) {
+        let lba = match pos.cluster {
+            ROOT => self.root_start + pos.block,
+            cluster => self.cluster_lba(cluster) + pos.block,
+        };
+        (lba, pos.index as usize * ENTRY_SIZE)
+    }
+
+    async fn read_entry(&mut self, pos: DirPos) -> Result<[u8; ENTRY_SIZE], Error<D::Error>> {
+        let (lba, offset) = self.entry_location(pos);
+        let block = self.data.get(&mut self.device, lba, true).await?;
+        Ok(block[offset..offset + ENTRY_SIZE].try_into().unwrap())
+    }
+
+    pub(super) async fn write_entry(&mut self, pos: DirPos, raw: &[u8; ENTRY_SIZE]) -> Result<(), Error<D::Error>> {
+        let (lba, offset) = self.entry_location(pos);
+        let block = self.data.get(&mut self.device, lba, true).await?;
+        block[offset..offset + ENTRY_SIZE].copy_from_slice(raw);
+        self.data.dirty = true;
+        Ok(())
+    }
+
+    /// Read the entry at `pos` and advance past it, setting `pos` to `None` at the end.
+    pub(super) async fn next_entry(&mut self, pos: &mut Option<DirPos>) -> Result<Option<DirEntry>, Error<D::Error>> {
+        let mut long_name = LongName::new();
+        while let Some(here) = *pos {
+            let raw = self.read_entry(here).await?;
+            if raw[0] == 0 {
+                *pos = None;
+                break;
+            }
+            let mut next = here;
+            *pos = match self.advance(&mut next, false).await? {
+                true => Some(next),
+                false => None,
+            };
+
+            if raw[0] == DELETED {
+                long_name.seq = 0;
+            } else if raw[11] & 0x3f == LONG_NAME {
+                long_name.push(&raw, here);
+            } else if Attributes(raw[11]).contains(Attributes::VOLUME_ID) {
+                long_name.seq = 0;
+            } else {
+                let short = raw[..11].try_into().unwrap();
+                let long = long_name.get(&short);
+                let first = long.map_or(here, |_| long_name.first);
+                let mut entry = DirEntry::new(&raw, self.fat_type, here, first);
+                if let Some(long) = long {
+                    entry.set_long_name(long);
+                }
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the entry `name` in the directory starting at `dir`.
+    pub(super) async fn find(&mut self, dir: u32, name: &str) -> Result<Option<DirEntry>, Error<D::Error>> {
+        let mut pos = Some(self.dir_start(dir));
+        while let Some(entry) = self.next_entry(&mut pos).await? {
+            if entry.matches(name) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn short_name_exists(&mut self, dir: u32, short: &[u8; 11]) -> Result<bool, Error<D::Error>> {
+        let mut pos = Some(self.dir_start(dir));
+        while let Some(entry) = self.next_entry(&mut pos).await? {
+            if entry.raw_short_name == *short {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Create an entry `name` in the directory starting at `dir`.
+    pub(super) async fn create_entry(
+        &mut self,
+        dir: u32,
+        name: &str,
+        attributes: Attributes,
+        cluster: u32,
+    ) -> Result<DirEntry, Error<D::Error>> {
+        if !name::is_valid(name) {
+            return Err(Error::InvalidName);
+        }
+        if self.find(dir, name).await?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+
+        // A long name is needed unless the name fits in 8.3, and the case flags can preserve
+        // its case. If it fits ignoring case, it is its own short name, which can't exist yet.
+        let (short, case) = match name::to_short(name) {
+            Some((short, case)) => (short, case),
+            None => {
+                let basis = AliasBasis::new(name);
+                let mut short = None;
+                for n in 1..1_000_000 {
+                    let alias = basis.alias(n);
+                    if !self.short_name_exists(dir, &alias).await? {
+                        short = Some(alias);
+                        break;
+                    }
+                }
+                (short.ok_or(Error::AlreadyExists)?, None)
+            }
+        };
+        let mut units = [0; MAX_LONG_NAME];
+        let units = match case {
+            Some(_) => &units[..0],
+            None => {
+                let len = units.iter_mut().zip(name.encode_utf16()).map(|(o, u)| *o = u).count();
+                &units[..len]
+            }
+        };
+        let long_entries = units.len().div_ceil(CHARS_PER_LONG_ENTRY);
+
+        // Find enough consecutive free entries, growing the directory if needed.
+        let mut pos = self.dir_start(dir);
+        let extend = pos.cluster != ROOT;
+        let mut first = pos;
+        let mut free = 0;
+        loop {
+            let raw = self.read_entry(pos).await?;
+            if raw[0] == 0 || raw[0] == DELETED {
+                if free == 0 {
+                    first = pos;
+                }
+                free += 1;
+                if free == long_entries + 1 {
+                    break;
+                }
+            } else {
+                free = 0;
+            }
+            if !self.advance(&mut pos, extend).await? {
+                return Err(Error::NoSpace);
+            }
+        }
+
+        let checksum = name::checksum(&short);
+        let mut pos = first;
+        for seq in (1..=long_entries).rev() {
+            let mut raw = [0; ENTRY_SIZE];
+            raw[0] = seq as u8;
+            if seq == long_entries {
+                raw[0] |= LAST_LONG_ENTRY;
+            }
+            raw[11] = LONG_NAME;
+            raw[13] = checksum;
+            let chars = units[(seq - 1) * CHARS_PER_LONG_ENTRY..]
+                .iter()
+                .copied()
+                .chain([0])
+                .chain(core::iter::repeat(0xffff));
+            for (&i, c) in LONG_NAME_CHARS.iter().zip(chars) {
+                raw[i..i + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            self.write_entry(pos, &raw).await?;
+            self.advance(&mut pos, false).await?;
+        }
+
+        let mut raw = raw_entry(&short, attributes, cluster, 0);
+        raw[12] = case.unwrap_or(0);
+        self.write_entry(pos, &raw).await?;
+
+        let mut entry = DirEntry::new(&raw, self.fat_type, pos, first);
+        if !units.is_empty() {
+            entry.set_long_name(units);
+        }
+        Ok(entry)
+    }
+
+    /// Mark an entry and its long name entries as deleted.
+    pub(super) async fn remove_entry(&mut self, entry: &DirEntry) -> Result<(), Error<D::Error>> {
+        let mut pos = entry.first;
+        loop {
+            let mut raw = self.read_entry(pos).await?;
+            raw[0] = DELETED;
+            self.write_entry(pos, &raw).await?;
+            if pos == entry.pos {
+                return Ok(());
+            }
+            if !self.advance(&mut pos, false).await? {
+                return Err(Error::Corrupted);
+            }
+        }
+    }
+
+    /// Update the first cluster and size of a file entry after it was written to.
+    pub(super) async fn update_entry(&mut self, pos: DirPos, cluster: u32, size: u32) -> Result<(), Error<D::Error>> {
+        let mut raw = self.read_entry(pos).await?;
+        set_cluster_and_size(&mut raw, cluster, size);
+        raw[11] |= Attributes::ARCHIVE.0;
+        raw[24..26].copy_from_slice(&DEFAULT_DATE);
+        self.write_entry(pos, &raw).await
+    }
+}
@@ -0,0 +1,175 @@
+use embedded_io_async::{ErrorType, Read, Seek, SeekFrom, Write};
+
+use super::dir::{DirEntry, DirPos};
+use super::{BlockDevice, Error, FileSystem, BLOCK_SIZE};
+
+/// An open file.
+///
+/// Read, write and seek through the [`embedded_io_async`] traits. Changes to the size of the
+/// file are recorded in its directory entry on [`flush`](Write::flush) and [`close`](File::close);
+/// dropping a written file without closing it may lose data.
+pub struct File<'a, D: BlockDevice<BLOCK_SIZE>> {
+    fs: &'a mut FileSystem<D>,
+    entry: DirPos,
+    first_cluster: u32,
+    size: u32,
+    pos: u32,
+    /// Cluster `cluster_index` of the file, to avoid following the chain from the start on
+    /// every access, or 0 if not known.
+    cluster: u32,
+    cluster_index: u32,
+    dirty: bool,
+}
+
+impl<'a, D: BlockDevice<BLOCK_SIZE>> File<'a, D> {
+    pub(super) fn new(fs: &'a mut FileSystem<D>, entry: &DirEntry) -> Self {
+        Self {
+            fs,
+            entry: entry.pos,
+            first_cluster: entry.cluster,
+            size: entry.size,
+            pos: 0,
+            cluster: 0,
+            cluster_index: 0,
+            dirty: false,
+        }
+    }
+
+    /// Get the size of the file in bytes.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Truncate the file at the current position.
+    pub async fn truncate(&mut self) -> Result<(), Error<D::Error>> {
+        if self.pos == self.size {
+            return Ok(());
+        }
+
+        if self.pos == 0 {
+            let first = core::mem::take(&mut self.first_cluster);
+            if first != 0 {
+                self.fs.free_chain(first).await?;
+            }
+            self.cluster = 0;
+        } else {
+            let last = self.cluster_at(self.pos - 1, false).await?.ok_or(Error::Corrupted)?;
+            if let Some(next) = self.fs.next_cluster(last).await? {
+                self.fs.fat_set(last, 0x0fff_ffff).await?;
+                self.fs.free_chain(next).await?;
+            }
+        }
+        self.size = self.pos;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Flush the file and close it.
+    pub async fn close(mut self) -> Result<(), Error<D::Error>> {
+        self.flush().await
+    }
+
+    /// Get the cluster holding byte `pos` of the file, optionally allocating missing clusters.
+    async fn cluster_at(&mut self, pos: u32, allocate: bool) -> Result<Option<u32>, Error<D::Error>> {
+        let index = pos / self.fs.cluster_size();
+        if self.first_cluster == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            self.first_cluster = self.fs.alloc_cluster(None).await?;
+            self.dirty = true;
+        }
+
+        let (mut cluster, mut i) = match self.cluster != 0 && self.cluster_index <= index {
+            true => (self.cluster, self.cluster_index),
+            false => (self.first_cluster, 0),
+        };
+        while i < index {
+            cluster = match self.fs.next_cluster(cluster).await? {
+                Some(next) => next,
+                None if allocate => self.fs.alloc_cluster(Some(cluster)).await?,
+                None => return Ok(None),
+            };
+            i += 1;
+        }
+        self.cluster = cluster;
+        self.cluster_index = index;
+        Ok(Some(cluster))
+    }
+
+    /// Get the block holding byte `pos` of the file, and the offset of `pos` within it.
+    async fn block_at(&mut self, pos: u32, allocate: bool) -> Result<(u32, usize), Error<D::Error>> {
+        let cluster = self.cluster_at(pos, allocate).await?.ok_or(Error::Corrupted)?;
+        let offset = pos % self.fs.cluster_size();
+        let lba = self.fs.cluster_lba(cluster) + offset / BLOCK_SIZE as u32;
+        Ok((lba, offset as usize % BLOCK_SIZE))
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> ErrorType for File<'_, D> {
+    type Error = Error<D::Error>;
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Read for File<'_, D> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let remaining = (self.size - self.pos) as usize;
+        if buf.is_empty() || remaining == 0 {
+            return Ok(0);
+        }
+
+        let (lba, offset) = self.block_at(self.pos, false).await?;
+        let len = buf.len().min(remaining).min(BLOCK_SIZE - offset);
+        let block = self.fs.data.get(&mut self.fs.device, lba, true).await?;
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        self.pos += len as u32;
+        Ok(len)
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Write for File<'_, D> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == u32::MAX {
+            return Err(Error::FileTooLarge);
+        }
+
+        let (lba, offset) = self.block_at(self.pos, true).await?;
+        let len = buf.len().min(BLOCK_SIZE - offset).min((u32::MAX - self.pos) as usize);
+        // The old contents don't matter if the whole block, or everything up to the end of the
+        // file, is overwritten.
+        let read = offset != 0 || (len != BLOCK_SIZE && self.pos + (len as u32) < self.size);
+        let block = self.fs.data.get(&mut self.fs.device, lba, read).await?;
+        block[offset..offset + len].copy_from_slice(&buf[..len]);
+        self.fs.data.dirty = true;
+
+        self.pos += len as u32;
+        self.size = self.size.max(self.pos);
+        self.dirty = true;
+        Ok(len)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if self.dirty {
+            self.fs.update_entry(self.entry, self.first_cluster, self.size).await?;
+            self.dirty = false;
+        }
+        self.fs.flush().await
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Seek for File<'_, D> {
+    async fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => (self.size as i64).saturating_add(offset),
+            SeekFrom::Current(offset) => (self.pos as i64).saturating_add(offset),
+        };
+        if !(0..=self.size as i64).contains(&pos) {
+            return Err(Error::InvalidSeek);
+        }
+        self.pos = pos as u32;
+        Ok(pos as u64)
+    }
+}
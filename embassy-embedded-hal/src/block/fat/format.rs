@@ -0,0 +1,252 @@
+use core::slice;
+
+use super::dir::{raw_entry, Attributes};
+use super::{Block, BlockDevice, Error, FatType, BLOCK_SIZE};
+use crate::block::{block_count, zeroed};
+
+/// Options for [`format`].
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// FAT variant, or `None` to choose one based on the size of the device.
+    pub fat_type: Option<FatType>,
+    /// Blocks per cluster, a power of two up to 128, or `None` to choose based on the size of
+    /// the device.
+    pub blocks_per_cluster: Option<u8>,
+    /// Volume label, padded with spaces.
+    pub label: [u8; 11],
+    /// Volume serial number.
+    pub volume_id: u32,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            fat_type: None,
+            blocks_per_cluster: None,
+            label: *b"NO NAME    ",
+            volume_id: 0,
+        }
+    }
+}
+
+const FAT_COUNT: u32 = 2;
+const MEDIA: u8 = 0xf8;
+
+struct Layout {
+    fat_type: FatType,
+    blocks_per_cluster: u32,
+    reserved: u32,
+    fat_size: u32,
+    root_entries: u32,
+    cluster_count: u32,
+}
+
+impl Layout {
+    fn new(total: u32, options: &FormatOptions) -> Option<Self> {
+        let fat_type = options.fat_type.unwrap_or(match total {
+            0..8400 => FatType::Fat12,
+            8400..1_048_576 => FatType::Fat16,
+            _ => FatType::Fat32,
+        });
+        let blocks_per_cluster = match options.blocks_per_cluster {
+            Some(n) if n.is_power_of_two() && n <= 128 => n as u32,
+            Some(_) => return None,
+            None => default_blocks_per_cluster(total, fat_type),
+        };
+        let (reserved, root_entries) = match fat_type {
+            FatType::Fat32 => (32, 0),
+            _ => (1, 512),
+        };
+        let root_blocks = root_entries * 32 / BLOCK_SIZE as u32;
+
+        // The allocation table size depends on the number of clusters, which depends on the
+        // space left after the allocation tables.
+        let mut fat_size = 1;
+        let cluster_count = loop {
+            let data = total.checked_sub(reserved + FAT_COUNT * fat_size + root_blocks)?;
+            let clusters = data / blocks_per_cluster;
+            let bytes = match fat_type {
+                FatType::Fat12 => ((clusters + 2) * 3).div_ceil(2),
+                FatType::Fat16 => (clusters + 2) * 2,
+                FatType::Fat32 => (clusters + 2) * 4,
+            };
+            let needed = bytes.div_ceil(BLOCK_SIZE as u32);
+            if needed <= fat_size {
+                break clusters;
+            }
+            fat_size = needed;
+        };
+
+        if FatType::from_cluster_count(cluster_count) != fat_type || cluster_count > 0x0fff_fff4 {
+            return None;
+        }
+        Some(Self {
+            fat_type,
+            blocks_per_cluster,
+            reserved,
+            fat_size,
+            root_entries,
+            cluster_count,
+        })
+    }
+}
+
+fn default_blocks_per_cluster(total: u32, fat_type: FatType) -> u32 {
+    let max_clusters = match fat_type {
+        FatType::Fat12 => 4084,
+        FatType::Fat16 => 65524,
+        // Following the Microsoft recommendations, which trade slack space for a smaller FAT.
+        FatType::Fat32 => {
+            return match total {
+                0..=532_480 => 1,
+                532_481..=16_777_216 => 8,
+                16_777_217..=33_554_432 => 16,
+                33_554_433..=67_108_864 => 32,
+                _ => 64,
+            }
+        }
+    };
+    let mut blocks_per_cluster = 1;
+    while total / blocks_per_cluster > max_clusters && blocks_per_cluster < 128 {
+        blocks_per_cluster *= 2;
+    }
+    blocks_per_cluster
+}
+
+/// Format `device` with a FAT filesystem.
+///
+/// All data on the device is lost. The filesystem starts at the first block, without a
+/// partition table; to format a partition, pass a [`Partition`](crate::block::Partition).
+pub async fn format<D: BlockDevice<BLOCK_SIZE>>(device: &mut D, options: &FormatOptions) -> Result<(), Error<D::Error>> {
+    let total = block_count(device).await.map_err(Error::Device)?;
+    let layout = Layout::new(total, options).ok_or(Error::InvalidFileSystem)?;
+    let fat32 = layout.fat_type == FatType::Fat32;
+
+    let mut write =
+        async |lba: u32, block: &Block<D::Align>| device.write(lba, slice::from_ref(block)).await.map_err(Error::Device);
+    let zero = zeroed();
+
+    let mut boot = zeroed();
+    boot[..11].copy_from_slice(&[
+        0xeb,
+        if fat32 { 0x58 } else { 0x3c },
+        0x90,
+        b'E',
+        b'M',
+        b'B',
+        b'A',
+        b'S',
+        b'S',
+        b'Y',
+        b' ',
+    ]);
+    boot[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+    boot[13] = layout.blocks_per_cluster as u8;
+    boot[14..16].copy_from_slice(&(layout.reserved as u16).to_le_bytes());
+    boot[16] = FAT_COUNT as u8;
+    boot[17..19].copy_from_slice(&(layout.root_entries as u16).to_le_bytes());
+    match u16::try_from(total) {
+        Ok(total) if !fat32 => boot[19..21].copy_from_slice(&total.to_le_bytes()),
+        _ => boot[32..36].copy_from_slice(&total.to_le_bytes()),
+    }
+    boot[21] = MEDIA;
+    boot[24..26].copy_from_slice(&63u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&255u16.to_le_bytes());
+    let ext = match fat32 {
+        true => {
+            boot[36..40].copy_from_slice(&layout.fat_size.to_le_bytes());
+            boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+            boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+            boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+            64
+        }
+        false => {
+            boot[22..24].copy_from_slice(&(layout.fat_size as u16).to_le_bytes());
+            36
+        }
+    };
+    boot[ext] = 0x80;
+    boot[ext + 2] = 0x29;
+    boot[ext + 3..ext + 7].copy_from_slice(&options.volume_id.to_le_bytes());
+    boot[ext + 7..ext + 18].copy_from_slice(&options.label);
+    boot[ext + 18..ext + 26].copy_from_slice(match layout.fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    write(0, &boot).await?;
+    for lba in 1..layout.reserved {
+        write(lba, &zero).await?;
+    }
+
+    if fat32 {
+        let mut fs_info = zeroed();
+        fs_info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        // The root directory uses the first cluster.
+        fs_info[488..492].copy_from_slice(&(layout.cluster_count - 1).to_le_bytes());
+        fs_info[492..496].copy_from_slice(&3u32.to_le_bytes());
+        fs_info[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+        write(1, &fs_info).await?;
+        write(6, &boot).await?;
+        write(7, &fs_info).await?;
+    }
+
+    let mut fat = zeroed();
+    let first_entries: &[u8] = match layout.fat_type {
+        FatType::Fat12 => &[MEDIA, 0xff, 0xff],
+        FatType::Fat16 => &[MEDIA, 0xff, 0xff, 0xff],
+        FatType::Fat32 => &[MEDIA, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f],
+    };
+    fat[..first_entries.len()].copy_from_slice(first_entries);
+    for i in 0..FAT_COUNT {
+        let start = layout.reserved + i * layout.fat_size;
+        write(start, &fat).await?;
+        for lba in start + 1..start + layout.fat_size {
+            write(lba, &zero).await?;
+        }
+    }
+
+    let root_start = layout.reserved + FAT_COUNT * layout.fat_size;
+    let root_blocks = match fat32 {
+        true => layout.blocks_per_cluster,
+        false => layout.root_entries * 32 / BLOCK_SIZE as u32,
+    };
+    let mut root = zeroed();
+    if options.label != FormatOptions::default().label {
+        root[..32].copy_from_slice(&raw_entry(&options.label, Attributes::VOLUME_ID, 0, 0));
+    }
+    write(root_start, &root).await?;
+    for lba in root_start + 1..root_start + root_blocks {
+        write(lba, &zero).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(total: u32, fat_type: Option<FatType>) -> Option<(FatType, u32, u32)> {
+        let options = FormatOptions {
+            fat_type,
+            ..Default::default()
+        };
+        Layout::new(total, &options).map(|l| (l.fat_type, l.blocks_per_cluster, l.cluster_count))
+    }
+
+    #[test]
+    fn layouts() {
+        // 1.44 MB floppy.
+        assert_eq!(layout(2880, None), Some((FatType::Fat12, 1, 2829)));
+        assert_eq!(layout(64 * 1024, None), Some((FatType::Fat16, 2, 32623)));
+        assert_eq!(layout(1 << 20, None), Some((FatType::Fat32, 8, 130812)));
+        assert_eq!(layout(2880, Some(FatType::Fat32)), None);
+        assert_eq!(layout(40, None), Some((FatType::Fat12, 1, 5)));
+        assert_eq!(layout(32, None), None);
+    }
+}
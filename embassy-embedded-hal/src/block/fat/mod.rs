@@ -0,0 +1,802 @@
+//! FAT12/16/32 filesystem.
+//!
+//! [`FileSystem`] mounts a FAT volume on a [`BlockDevice`], such as a whole SD card formatted
+//! without partition table, or a [`Partition`](super::Partition) of one. It supports long file
+//! names, nested directories and reading and writing files through the `embedded-io-async`
+//! traits, without requiring `alloc`.
+//!
+//! Paths use `/` as separator and are relative to the root directory. Names are compared
+//! ignoring ASCII case, against both the long name and the 8.3 short name of each entry.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::block::fat::FileSystem;
+//! use embedded_io_async::{Read, Write};
+//!
+//! let mut fs = FileSystem::mount(sd_card).await?;
+//!
+//! fs.create_dir("logs").await?;
+//! let mut file = fs.create("logs/Boot log.txt").await?;
+//! file.write_all(b"hello\n").await?;
+//! file.close().await?;
+//!
+//! let mut dir = fs.read_dir("logs").await?;
+//! while let Some(entry) = dir.next().await? {
+//!     info!("{} ({} bytes)", entry.name(), entry.size());
+//! }
+//! ```
+//!
+//! # Caching
+//!
+//! The filesystem keeps one block of the allocation table and one block of file or directory
+//! data in RAM. Written data only reaches the device when these blocks are evicted, or when
+//! [`File::flush`](embedded_io_async::Write::flush), [`File::close`] or
+//! [`FileSystem::flush`] is called. Only one file or directory can be open at a time, since it
+//! borrows the filesystem mutably.
+//!
+//! Timestamps are not tracked; new and modified entries are dated 1980-01-01.
+
+mod dir;
+mod file;
+mod format;
+mod name;
+
+use core::slice;
+
+pub use dir::{Attributes, Dir, DirEntry};
+use dir::{DirPos, ROOT};
+pub use file::File;
+pub use format::{format, FormatOptions};
+
+use super::{zeroed, Block, BlockDevice, BLOCK_SIZE};
+
+/// FAT variant, determined by the number of clusters of the volume.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FatType {
+    /// FAT12, up to 4084 clusters.
+    Fat12,
+    /// FAT16, up to 65524 clusters.
+    Fat16,
+    /// FAT32.
+    Fat32,
+}
+
+impl FatType {
+    fn from_cluster_count(clusters: u32) -> Self {
+        match clusters {
+            0..4085 => Self::Fat12,
+            4085..65525 => Self::Fat16,
+            _ => Self::Fat32,
+        }
+    }
+
+    /// Smallest allocation table value marking the end of a cluster chain.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            Self::Fat12 => 0xff8,
+            Self::Fat16 => 0xfff8,
+            Self::Fat32 => 0x0fff_fff8,
+        }
+    }
+}
+
+/// Filesystem error.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<T> {
+    /// The volume does not hold a supported FAT filesystem, or can't be formatted with the
+    /// requested parameters.
+    InvalidFileSystem,
+    /// The filesystem structures are inconsistent, such as a cluster chain pointing outside
+    /// the volume.
+    Corrupted,
+    /// No file or directory exists at the path.
+    NotFound,
+    /// A file or directory already exists at the path.
+    AlreadyExists,
+    /// A directory was expected, but the path refers to a file.
+    NotADirectory,
+    /// A file was expected, but the path refers to a directory.
+    IsADirectory,
+    /// The directory to remove is not empty.
+    DirectoryNotEmpty,
+    /// The name is empty, too long or contains characters not allowed in FAT names.
+    InvalidName,
+    /// There are no free clusters, or the FAT12/16 root directory is full.
+    NoSpace,
+    /// Seek to a position beyond the end of the file.
+    InvalidSeek,
+    /// The file would grow beyond 4 GiB.
+    FileTooLarge,
+    /// Underlying device error.
+    Device(T),
+}
+
+impl<T: core::fmt::Debug> embedded_io_async::Error for Error<T> {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        use embedded_io_async::ErrorKind;
+        match self {
+            Self::NotFound => ErrorKind::NotFound,
+            Self::AlreadyExists => ErrorKind::AlreadyExists,
+            Self::InvalidName | Self::InvalidSeek => ErrorKind::InvalidInput,
+            Self::InvalidFileSystem | Self::Corrupted => ErrorKind::InvalidData,
+            Self::NoSpace | Self::FileTooLarge => ErrorKind::OutOfMemory,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// A cached block.
+struct Cache<A> {
+    block: Block<A>,
+    lba: Option<u32>,
+    dirty: bool,
+    /// Number of copies to write on flush, `stride` blocks apart, for mirrored FATs.
+    copies: u32,
+    stride: u32,
+}
+
+impl<A: aligned::Alignment> Cache<A> {
+    const fn new(copies: u32, stride: u32) -> Self {
+        Self {
+            block: zeroed(),
+            lba: None,
+            dirty: false,
+            copies,
+            stride,
+        }
+    }
+
+    async fn flush<D: BlockDevice<BLOCK_SIZE, Align = A>>(&mut self, device: &mut D) -> Result<(), Error<D::Error>> {
+        if let (Some(lba), true) = (self.lba, self.dirty) {
+            for i in 0..self.copies {
+                device
+                    .write(lba + i * self.stride, slice::from_ref(&self.block))
+                    .await
+                    .map_err(Error::Device)?;
+            }
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Get block `lba`, reading it from the device unless `read` is false because the caller
+    /// overwrites all of it.
+    async fn get<D: BlockDevice<BLOCK_SIZE, Align = A>>(
+        &mut self,
+        device: &mut D,
+        lba: u32,
+        read: bool,
+    ) -> Result<&mut Block<A>, Error<D::Error>> {
+        if self.lba != Some(lba) {
+            self.flush(device).await?;
+            self.lba = None;
+            if read {
+                device
+                    .read(lba, slice::from_mut(&mut self.block))
+                    .await
+                    .map_err(Error::Device)?;
+            }
+            self.lba = Some(lba);
+        }
+        Ok(&mut self.block)
+    }
+}
+
+/// A mounted FAT filesystem.
+pub struct FileSystem<D: BlockDevice<BLOCK_SIZE>> {
+    device: D,
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    root_start: u32,
+    root_entries: u32,
+    root_cluster: u32,
+    data_start: u32,
+    cluster_count: u32,
+    fs_info: Option<u32>,
+    fs_info_dirty: bool,
+    free_count: Option<u32>,
+    next_free: u32,
+    fat: Cache<D::Align>,
+    data: Cache<D::Align>,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> FileSystem<D> {
+    /// Mount the FAT filesystem on `device`.
+    pub async fn mount(mut device: D) -> Result<Self, Error<D::Error>> {
+        let mut boot = zeroed();
+        device
+            .read(0, slice::from_mut(&mut boot))
+            .await
+            .map_err(Error::Device)?;
+
+        let u16_at = |i: usize| u16::from_le_bytes([boot[i], boot[i + 1]]) as u32;
+        let u32_at = |i: usize| u32::from_le_bytes(boot[i..i + 4].try_into().unwrap());
+
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved = u16_at(14);
+        let fat_count = boot[16] as u32;
+        let root_entries = u16_at(17);
+        let total = match u16_at(19) {
+            0 => u32_at(32),
+            n => n,
+        };
+        let fat_size = match u16_at(22) {
+            0 => u32_at(36),
+            n => n,
+        };
+        if boot[510..512] != [0x55, 0xaa]
+            || u16_at(11) != BLOCK_SIZE as u32
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_size == 0
+        {
+            return Err(Error::InvalidFileSystem);
+        }
+
+        let root_sectors = (root_entries * 32).div_ceil(BLOCK_SIZE as u32);
+        let fat_start = reserved;
+        let root_start = fat_start + fat_count * fat_size;
+        let data_start = root_start + root_sectors;
+        let cluster_count = total.checked_sub(data_start).ok_or(Error::InvalidFileSystem)? / sectors_per_cluster;
+        let fat_type = FatType::from_cluster_count(cluster_count);
+
+        let fat_entries = match fat_type {
+            FatType::Fat12 => fat_size * BLOCK_SIZE as u32 * 2 / 3,
+            FatType::Fat16 => fat_size * BLOCK_SIZE as u32 / 2,
+            FatType::Fat32 => fat_size * BLOCK_SIZE as u32 / 4,
+        };
+        let (root_cluster, fs_info) = match fat_type {
+            FatType::Fat32 => (u32_at(44), Some(u16_at(48)).filter(|&s| s != 0 && s < reserved)),
+            _ => (0, None),
+        };
+        if fat_entries < cluster_count + 2
+            || (fat_type == FatType::Fat32 && (root_entries != 0 || !(2..cluster_count + 2).contains(&root_cluster)))
+            || (fat_type != FatType::Fat32 && root_entries == 0)
+        {
+            return Err(Error::InvalidFileSystem);
+        }
+
+        let mut fs = Self {
+            device,
+            fat_type,
+            sectors_per_cluster,
+            fat_start,
+            root_start,
+            root_entries,
+            root_cluster,
+            data_start,
+            cluster_count,
+            fs_info,
+            fs_info_dirty: false,
+            free_count: None,
+            next_free: 2,
+            fat: Cache::new(fat_count, fat_size),
+            data: Cache::new(1, 0),
+        };
+        fs.read_fs_info().await?;
+        Ok(fs)
+    }
+
+    /// Read the free cluster count and allocation hint from the FAT32 FSInfo sector.
+    async fn read_fs_info(&mut self) -> Result<(), Error<D::Error>> {
+        let Some(lba) = self.fs_info else {
+            return Ok(());
+        };
+        let block = self.data.get(&mut self.device, lba, true).await?;
+        let u32_at = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+        if u32_at(0) != 0x4161_5252 || u32_at(484) != 0x6141_7272 {
+            self.fs_info = None;
+            return Ok(());
+        }
+
+        let max = self.cluster_count + 2;
+        self.free_count = Some(u32_at(488)).filter(|&n| n <= self.cluster_count);
+        self.next_free = Some(u32_at(492)).filter(|&n| (2..max).contains(&n)).unwrap_or(2);
+        Ok(())
+    }
+
+    /// Get the FAT variant of the volume.
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Get the size of a cluster in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * BLOCK_SIZE as u32
+    }
+
+    /// Get the total number of data clusters of the volume.
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// Count the free clusters.
+    ///
+    /// On FAT12/16 volumes, and on FAT32 volumes without a valid free count in the FSInfo
+    /// sector, this scans the whole allocation table the first time.
+    pub async fn free_clusters(&mut self) -> Result<u32, Error<D::Error>> {
+        if let Some(n) = self.free_count {
+            return Ok(n);
+        }
+        let mut free = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_get(cluster).await? == 0 {
+                free += 1;
+            }
+        }
+        self.free_count = Some(free);
+        Ok(free)
+    }
+
+    /// Write all cached data to the device.
+    pub async fn flush(&mut self) -> Result<(), Error<D::Error>> {
+        if let (Some(lba), true) = (self.fs_info, self.fs_info_dirty) {
+            let free = self.free_count.unwrap_or(u32::MAX);
+            let next = self.next_free;
+            let block = self.data.get(&mut self.device, lba, true).await?;
+            block[488..492].copy_from_slice(&free.to_le_bytes());
+            block[492..496].copy_from_slice(&next.to_le_bytes());
+            self.data.dirty = true;
+            self.fs_info_dirty = false;
+        }
+        self.fat.flush(&mut self.device).await?;
+        self.data.flush(&mut self.device).await
+    }
+
+    /// Flush all cached data and return the device.
+    pub async fn unmount(mut self) -> Result<D, Error<D::Error>> {
+        self.flush().await?;
+        Ok(self.device)
+    }
+
+    /// Get information about the file or directory at `path`.
+    pub async fn metadata(&mut self, path: &str) -> Result<DirEntry, Error<D::Error>> {
+        let (dir, name) = self.resolve_parent(path).await?;
+        if name.is_empty() {
+            return Err(Error::InvalidName);
+        }
+        self.find(dir, name).await?.ok_or(Error::NotFound)
+    }
+
+    /// Open the directory at `path` to list its entries.
+    pub async fn read_dir(&mut self, path: &str) -> Result<Dir<'_, D>, Error<D::Error>> {
+        let (dir, name) = self.resolve_parent(path).await?;
+        let cluster = match name {
+            "" => ROOT,
+            name => self.find_dir(dir, name).await?,
+        };
+        Ok(Dir::new(self, cluster))
+    }
+
+    /// Create a directory at `path`. Its parent directory must exist.
+    pub async fn create_dir(&mut self, path: &str) -> Result<(), Error<D::Error>> {
+        let (parent, name) = self.resolve_parent(path).await?;
+        if self.find(parent, name).await?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+
+        let cluster = self.alloc_cluster(None).await?;
+        self.zero_cluster(cluster).await?;
+        let mut pos = DirPos::start(cluster);
+        self.write_entry(pos, &dir::raw_entry(b".          ", Attributes::DIRECTORY, cluster, 0))
+            .await?;
+        self.advance(&mut pos, false).await?;
+        self.write_entry(pos, &dir::raw_entry(b"..         ", Attributes::DIRECTORY, parent, 0))
+            .await?;
+
+        if let Err(e) = self.create_entry(parent, name, Attributes::DIRECTORY, cluster).await {
+            self.free_chain(cluster).await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Remove the file or empty directory at `path`.
+    pub async fn remove(&mut self, path: &str) -> Result<(), Error<D::Error>> {
+        let entry = self.metadata(path).await?;
+        if entry.is_dir() {
+            let mut dir = Dir::new(self, entry.cluster);
+            if dir.next().await?.is_some() {
+                return Err(Error::DirectoryNotEmpty);
+            }
+        }
+        self.remove_entry(&entry).await?;
+        if entry.cluster != 0 {
+            self.free_chain(entry.cluster).await?;
+        }
+        Ok(())
+    }
+
+    /// Open the existing file at `path` for reading and writing.
+    pub async fn open(&mut self, path: &str) -> Result<File<'_, D>, Error<D::Error>> {
+        let entry = self.metadata(path).await?;
+        if entry.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        Ok(File::new(self, &entry))
+    }
+
+    /// Create a file at `path`, or truncate it if it exists, and open it for reading and writing.
+    pub async fn create(&mut self, path: &str) -> Result<File<'_, D>, Error<D::Error>> {
+        let (dir, name) = self.resolve_parent(path).await?;
+        let entry = match self.find(dir, name).await? {
+            Some(entry) if entry.is_dir() => return Err(Error::IsADirectory),
+            Some(entry) => entry,
+            None => self.create_entry(dir, name, Attributes::ARCHIVE, 0).await?,
+        };
+        let mut file = File::new(self, &entry);
+        file.truncate().await?;
+        Ok(file)
+    }
+
+    /// Split `path` into the first cluster of its parent directory and its last component.
+    ///
+    /// The last component is empty if `path` refers to the root directory.
+    async fn resolve_parent<'p>(&mut self, path: &'p str) -> Result<(u32, &'p str), Error<D::Error>> {
+        let mut components = path.split('/').filter(|c| !c.is_empty() && *c != ".");
+        let mut dir = ROOT;
+        let Some(mut name) = components.next() else {
+            return Ok((ROOT, ""));
+        };
+        for next in components {
+            dir = self.find_dir(dir, name).await?;
+            name = next;
+        }
+        Ok((dir, name))
+    }
+
+    /// Find the subdirectory `name` of `dir`, returning its first cluster.
+    async fn find_dir(&mut self, dir: u32, name: &str) -> Result<u32, Error<D::Error>> {
+        match self.find(dir, name).await? {
+            Some(entry) if entry.is_dir() => Ok(entry.cluster),
+            Some(_) => Err(Error::NotADirectory),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    async fn fat_byte(&mut self, offset: u32) -> Result<&mut u8, Error<D::Error>> {
+        let lba = self.fat_start + offset / BLOCK_SIZE as u32;
+        let block = self.fat.get(&mut self.device, lba, true).await?;
+        Ok(&mut block[offset as usize % BLOCK_SIZE])
+    }
+
+    /// Read the allocation table entry of `cluster`.
+    async fn fat_get(&mut self, cluster: u32) -> Result<u32, Error<D::Error>> {
+        match self.fat_type {
+            FatType::Fat12 => {
+                // Entries are 12 bits, and may straddle a block boundary.
+                let offset = cluster + cluster / 2;
+                let lo = *self.fat_byte(offset).await? as u32;
+                let hi = *self.fat_byte(offset + 1).await? as u32;
+                let value = lo | hi << 8;
+                Ok(match cluster & 1 {
+                    0 => value & 0xfff,
+                    _ => value >> 4,
+                })
+            }
+            FatType::Fat16 => {
+                let lo = *self.fat_byte(cluster * 2).await? as u32;
+                let hi = *self.fat_byte(cluster * 2 + 1).await? as u32;
+                Ok(lo | hi << 8)
+            }
+            FatType::Fat32 => {
+                let lba = self.fat_start + cluster * 4 / BLOCK_SIZE as u32;
+                let i = (cluster * 4) as usize % BLOCK_SIZE;
+                let block = self.fat.get(&mut self.device, lba, true).await?;
+                Ok(u32::from_le_bytes(block[i..i + 4].try_into().unwrap()) & 0x0fff_ffff)
+            }
+        }
+    }
+
+    /// Write the allocation table entry of `cluster`.
+    async fn fat_set(&mut self, cluster: u32, value: u32) -> Result<(), Error<D::Error>> {
+        match self.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                let (lo, hi) = match cluster & 1 {
+                    0 => (0xff, 0x0f),
+                    _ => (0xf0, 0xff),
+                };
+                let value = match cluster & 1 {
+                    0 => value & 0xfff,
+                    _ => (value & 0xfff) << 4,
+                };
+                let byte = self.fat_byte(offset).await?;
+                *byte = (*byte & !lo) | (value as u8 & lo);
+                self.fat.dirty = true;
+                let byte = self.fat_byte(offset + 1).await?;
+                *byte = (*byte & !hi) | ((value >> 8) as u8 & hi);
+                self.fat.dirty = true;
+            }
+            FatType::Fat16 => {
+                *self.fat_byte(cluster * 2).await? = value as u8;
+                *self.fat_byte(cluster * 2 + 1).await? = (value >> 8) as u8;
+                self.fat.dirty = true;
+            }
+            FatType::Fat32 => {
+                let lba = self.fat_start + cluster * 4 / BLOCK_SIZE as u32;
+                let i = (cluster * 4) as usize % BLOCK_SIZE;
+                let block = self.fat.get(&mut self.device, lba, true).await?;
+                // The top 4 bits are reserved and must be preserved.
+                let old = u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+                let value = (old & 0xf000_0000) | (value & 0x0fff_ffff);
+                block[i..i + 4].copy_from_slice(&value.to_le_bytes());
+                self.fat.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the cluster following `cluster` in its chain, or `None` at the end of the chain.
+    async fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, Error<D::Error>> {
+        let next = self.fat_get(cluster).await?;
+        if next >= self.fat_type.end_of_chain() {
+            Ok(None)
+        } else if (2..self.cluster_count + 2).contains(&next) {
+            Ok(Some(next))
+        } else {
+            Err(Error::Corrupted)
+        }
+    }
+
+    /// Allocate a free cluster, appending it to the chain ending in `previous` if given.
+    async fn alloc_cluster(&mut self, previous: Option<u32>) -> Result<u32, Error<D::Error>> {
+        let end = self.cluster_count + 2;
+        let start = self.next_free;
+        let mut cluster = start;
+        while self.fat_get(cluster).await? != 0 {
+            cluster = match cluster + 1 {
+                c if c == end => 2,
+                c => c,
+            };
+            if cluster == start {
+                return Err(Error::NoSpace);
+            }
+        }
+
+        self.fat_set(cluster, 0x0fff_ffff).await?;
+        if let Some(previous) = previous {
+            self.fat_set(previous, cluster).await?;
+        }
+        self.next_free = match cluster + 1 {
+            c if c == end => 2,
+            c => c,
+        };
+        self.free_count = self.free_count.map(|n| n.saturating_sub(1));
+        self.fs_info_dirty = true;
+        Ok(cluster)
+    }
+
+    /// Free the cluster chain starting at `cluster`.
+    async fn free_chain(&mut self, mut cluster: u32) -> Result<(), Error<D::Error>> {
+        loop {
+            let next = self.next_cluster(cluster).await?;
+            self.fat_set(cluster, 0).await?;
+            self.free_count = self.free_count.map(|n| n + 1);
+            self.fs_info_dirty = true;
+            match next {
+                Some(next) => cluster = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    async fn zero_cluster(&mut self, cluster: u32) -> Result<(), Error<D::Error>> {
+        let lba = self.cluster_lba(cluster);
+        for i in 0..self.sectors_per_cluster {
+            self.data.get(&mut self.device, lba + i, false).await?.fill(0);
+            self.data.dirty = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use embedded_io_async::{Read, Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::block::MemBlockDevice;
+
+    type Disk = MemBlockDevice<Vec<u8>>;
+
+    async fn formatted(blocks: usize, fat_type: FatType) -> FileSystem<Disk> {
+        let mut disk = MemBlockDevice::new(vec![0xa5u8; blocks * BLOCK_SIZE]);
+        let options = FormatOptions {
+            fat_type: Some(fat_type),
+            ..Default::default()
+        };
+        format(&mut disk, &options).await.unwrap();
+        let fs = FileSystem::mount(disk).await.unwrap();
+        assert_eq!(fs.fat_type(), fat_type);
+        fs
+    }
+
+    async fn list(fs: &mut FileSystem<Disk>, path: &str) -> Vec<(std::string::String, bool, u32)> {
+        let mut dir = fs.read_dir(path).await.unwrap();
+        let mut entries = Vec::new();
+        while let Some(entry) = dir.next().await.unwrap() {
+            entries.push((entry.name().into(), entry.is_dir(), entry.size()));
+        }
+        entries
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    /// Exercise files, directories and long names, then remount and check everything survived.
+    async fn scenario(mut fs: FileSystem<Disk>) {
+        let free = fs.free_clusters().await.unwrap();
+        let cluster_size = fs.cluster_size() as usize;
+        let data = pattern(3 * cluster_size + 100);
+
+        fs.create_dir("docs").await.unwrap();
+        fs.create_dir("docs/A rather long directory name").await.unwrap();
+        assert_eq!(fs.create_dir("DOCS").await, Err(Error::AlreadyExists));
+
+        let mut file = fs.create("docs/A rather long directory name/data.bin").await.unwrap();
+        file.write_all(&data).await.unwrap();
+        file.close().await.unwrap();
+
+        let mut file = fs.create("readme.txt").await.unwrap();
+        file.write_all(b"hello").await.unwrap();
+        file.close().await.unwrap();
+
+        for i in 0..20 {
+            let name = std::format!("docs/log file number {i}.txt");
+            let mut file = fs.create(&name).await.unwrap();
+            file.write_all(name.as_bytes()).await.unwrap();
+            file.close().await.unwrap();
+        }
+
+        let mut fs = FileSystem::mount(fs.unmount().await.unwrap()).await.unwrap();
+
+        assert_eq!(
+            list(&mut fs, "/").await,
+            [("docs".into(), true, 0), ("readme.txt".into(), false, 5)]
+        );
+        let docs = list(&mut fs, "docs").await;
+        assert_eq!(docs.len(), 21);
+        assert_eq!(docs[0], ("A rather long directory name".into(), true, 0));
+        assert_eq!(docs[20], ("log file number 19.txt".into(), false, 27));
+
+        let mut file = fs.open("/Docs/a rather long DIRECTORY name/DATA.BIN").await.unwrap();
+        let mut read = vec![0; data.len()];
+        file.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, data);
+        assert_eq!(file.read(&mut read).await.unwrap(), 0);
+
+        // Overwrite in the middle of the second cluster, and append.
+        let middle = cluster_size as u64 + 10;
+        file.seek(SeekFrom::Start(middle)).await.unwrap();
+        file.write_all(b"patch").await.unwrap();
+        file.seek(SeekFrom::End(0)).await.unwrap();
+        file.write_all(b"tail").await.unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(1)).await, Err(Error::InvalidSeek));
+        file.close().await.unwrap();
+
+        let mut file = fs.open("docs/A rather long directory name/data.bin").await.unwrap();
+        assert_eq!(file.size(), data.len() as u32 + 4);
+        let mut read = vec![0; data.len() + 4];
+        file.read_exact(&mut read).await.unwrap();
+        let mut expected = data.clone();
+        expected[middle as usize..][..5].copy_from_slice(b"patch");
+        expected.extend_from_slice(b"tail");
+        assert_eq!(read, expected);
+
+        // Clean up everything and check no clusters leaked.
+        assert_eq!(fs.remove("docs").await, Err(Error::DirectoryNotEmpty));
+        for (name, _, _) in list(&mut fs, "docs/A rather long directory name").await {
+            fs.remove(&std::format!("docs/A rather long directory name/{name}"))
+                .await
+                .unwrap();
+        }
+        for (name, _, _) in list(&mut fs, "docs").await {
+            fs.remove(&std::format!("docs/{name}")).await.unwrap();
+        }
+        fs.remove("docs").await.unwrap();
+        fs.remove("readme.txt").await.unwrap();
+        assert_eq!(fs.open("readme.txt").await.err(), Some(Error::NotFound));
+        assert_eq!(list(&mut fs, "").await, []);
+        assert_eq!(fs.free_clusters().await.unwrap(), free);
+
+        let mut fs = FileSystem::mount(fs.unmount().await.unwrap()).await.unwrap();
+        fs.free_count = None;
+        assert_eq!(fs.free_clusters().await.unwrap(), free);
+    }
+
+    #[futures_test::test]
+    async fn fat12() {
+        scenario(formatted(2048, FatType::Fat12).await).await;
+    }
+
+    #[futures_test::test]
+    async fn fat16() {
+        scenario(formatted(32 * 1024, FatType::Fat16).await).await;
+    }
+
+    #[futures_test::test]
+    async fn fat32() {
+        scenario(formatted(80 * 1024, FatType::Fat32).await).await;
+    }
+
+    #[futures_test::test]
+    async fn short_names() {
+        let mut fs = formatted(2048, FatType::Fat12).await;
+        for name in [
+            "README.TXT",
+            "readme.txt",
+            "notes.md",
+            "Makefile",
+            "long name.txt",
+            "long_name.txt",
+            "LONG NAME 2.TXT",
+        ] {
+            fs.create(name).await.unwrap().close().await.unwrap();
+        }
+
+        let mut dir = fs.read_dir("").await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = dir.next().await.unwrap() {
+            names.push((
+                std::string::String::from(entry.name()),
+                std::string::String::from(entry.short_name()),
+            ));
+        }
+        assert_eq!(
+            names,
+            [
+                ("README.TXT".into(), "README.TXT".into()),
+                ("notes.md".into(), "NOTES.MD".into()),
+                ("Makefile".into(), "MAKEFILE".into()),
+                ("long name.txt".into(), "LONGNA~1.TXT".into()),
+                ("long_name.txt".into(), "LONG_N~1.TXT".into()),
+                ("LONG NAME 2.TXT".into(), "LONGNA~2.TXT".into()),
+            ]
+        );
+    }
+
+    #[futures_test::test]
+    async fn errors() {
+        let mut fs = formatted(2048, FatType::Fat12).await;
+        fs.create_dir("dir").await.unwrap();
+        fs.create("file").await.unwrap().close().await.unwrap();
+
+        assert_eq!(fs.open("dir").await.err(), Some(Error::IsADirectory));
+        assert_eq!(fs.create("file/x").await.err(), Some(Error::NotADirectory));
+        assert_eq!(fs.create("missing/x").await.err(), Some(Error::NotFound));
+        assert_eq!(fs.create("a:b").await.err(), Some(Error::InvalidName));
+        assert_eq!(fs.create("dir/..").await.err(), Some(Error::IsADirectory));
+        assert_eq!(fs.metadata("").await.err(), Some(Error::InvalidName));
+
+        // The FAT12 root directory has a fixed size.
+        let mut i = 0;
+        let res = loop {
+            if let Err(e) = fs.create(&std::format!("f{i}")).await {
+                break e;
+            }
+            i += 1;
+        };
+        assert_eq!(res, Error::NoSpace);
+        assert_eq!(i, 510);
+
+        let mut disk = fs.unmount().await.unwrap();
+        disk.storage_mut()[510] = 0;
+        assert_eq!(FileSystem::mount(disk).await.err(), Some(Error::InvalidFileSystem));
+    }
+}
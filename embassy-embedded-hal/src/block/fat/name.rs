@@ -0,0 +1,222 @@
+//! Long and short (8.3) file names.
+
+/// Maximum length of a long name in UTF-16 code units.
+pub(super) const MAX_LONG_NAME: usize = 255;
+
+/// Short name flag: the base name is displayed in lowercase.
+pub(super) const LOWERCASE_BASE: u8 = 0x08;
+/// Short name flag: the extension is displayed in lowercase.
+pub(super) const LOWERCASE_EXT: u8 = 0x10;
+
+/// Whether `name` can be used as a long name.
+pub(super) fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && name.encode_utf16().count() <= MAX_LONG_NAME
+        && name.chars().all(|c| c >= ' ' && !"\"*/:<>?\\|".contains(c))
+}
+
+/// Whether `b` is allowed in short names, besides upper case letters and digits.
+fn is_short_special(b: u8) -> bool {
+    b"!#$%&'()-@^_`{}~".contains(&b)
+}
+
+/// Convert one part of a name that fits in 8.3, returning whether it has lower and upper case
+/// letters.
+fn short_part(part: &str, out: &mut [u8]) -> Option<(bool, bool)> {
+    if part.len() > out.len() {
+        return None;
+    }
+    let (mut lower, mut upper) = (false, false);
+    for (o, b) in out.iter_mut().zip(part.bytes()) {
+        lower |= b.is_ascii_lowercase();
+        upper |= b.is_ascii_uppercase();
+        if !(b.is_ascii_alphanumeric() || is_short_special(b)) {
+            return None;
+        }
+        *o = b.to_ascii_uppercase();
+    }
+    Some((lower, upper))
+}
+
+/// Convert `name` to a short name if it fits in 8.3 ignoring case.
+///
+/// Also returns the case flags preserving the case of `name`, or `None` if that takes a long
+/// name because base or extension have mixed case.
+pub(super) fn to_short(name: &str) -> Option<([u8; 11], Option<u8>)> {
+    let (base, ext) = match name.split_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    let mut short = [b' '; 11];
+    if base.is_empty() {
+        return None;
+    }
+    let base = short_part(base, &mut short[..8])?;
+    let ext = short_part(ext, &mut short[8..])?;
+
+    let case = match (base, ext) {
+        ((true, true), _) | (_, (true, true)) => None,
+        ((lower_base, _), (lower_ext, _)) => {
+            Some(if lower_base { LOWERCASE_BASE } else { 0 } | if lower_ext { LOWERCASE_EXT } else { 0 })
+        }
+    };
+    Some((short, case))
+}
+
+/// Basis of the generated short names (`BASIS~N.EXT`) for a long name.
+pub(super) struct AliasBasis {
+    base: [u8; 8],
+    base_len: usize,
+    ext: [u8; 3],
+}
+
+impl AliasBasis {
+    pub(super) fn new(name: &str) -> Self {
+        let (base, ext) = match name.rfind('.') {
+            Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+            _ => (name, ""),
+        };
+
+        let mut basis = Self {
+            base: [b'_'; 8],
+            base_len: 0,
+            ext: [b' '; 3],
+        };
+        for (o, c) in basis.base.iter_mut().zip(Self::convert(base)) {
+            *o = c;
+            basis.base_len += 1;
+        }
+        basis.base_len = basis.base_len.max(1);
+        for (o, c) in basis.ext.iter_mut().zip(Self::convert(ext)) {
+            *o = c;
+        }
+        basis
+    }
+
+    /// Convert characters to their short name equivalent, dropping spaces and dots.
+    fn convert(part: &str) -> impl Iterator<Item = u8> + '_ {
+        part.chars().filter(|&c| c != ' ' && c != '.').map(|c| match c {
+            c if c.is_ascii_alphanumeric() || (c.is_ascii() && is_short_special(c as u8)) => {
+                c.to_ascii_uppercase() as u8
+            }
+            _ => b'_',
+        })
+    }
+
+    /// Short name with numeric tail `n`, such as `LONGNA~1.TXT`.
+    pub(super) fn alias(&self, n: u32) -> [u8; 11] {
+        let mut tail = [0; 7];
+        let mut i = tail.len();
+        let mut n = n;
+        while n > 0 || i == tail.len() {
+            i -= 1;
+            tail[i] = b'0' + (n % 10) as u8;
+            n /= 10;
+        }
+        i -= 1;
+        tail[i] = b'~';
+        let tail = &tail[i..];
+
+        let mut short = [b' '; 11];
+        let base_len = self.base_len.min(8 - tail.len());
+        short[..base_len].copy_from_slice(&self.base[..base_len]);
+        short[base_len..base_len + tail.len()].copy_from_slice(tail);
+        short[8..].copy_from_slice(&self.ext);
+        short
+    }
+}
+
+/// Checksum of a short name, stored in its long name entries.
+pub(super) fn checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+/// Format a short name as `NAME.EXT`, applying the lowercase flags in `case`.
+///
+/// Bytes outside ASCII, from the OEM code page, are replaced by `_`.
+pub(super) fn format_short(short: &[u8; 11], case: u8, out: &mut [u8; 12]) -> usize {
+    let mut len = 0;
+    let mut push = |part: &[u8], lower: bool| {
+        for &b in part {
+            let b = match b {
+                b if !b.is_ascii() => b'_',
+                b if lower => b.to_ascii_lowercase(),
+                b => b,
+            };
+            out[len] = b;
+            len += 1;
+        }
+    };
+
+    let mut base = &short[..8];
+    while let [rest @ .., b' '] = base {
+        base = rest;
+    }
+    let mut ext = &short[8..];
+    while let [rest @ .., b' '] = ext {
+        ext = rest;
+    }
+
+    // 0x05 stands for a leading 0xe5, which marks deleted entries.
+    let first = match base.first() {
+        Some(0x05) => Some(0xe5),
+        b => b.copied(),
+    };
+    if let Some(first) = first {
+        push(&[first], case & LOWERCASE_BASE != 0);
+        push(&base[1..], case & LOWERCASE_BASE != 0);
+    }
+    if !ext.is_empty() {
+        push(b".", false);
+        push(ext, case & LOWERCASE_EXT != 0);
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_names() {
+        assert_eq!(to_short("README.TXT"), Some((*b"README  TXT", Some(0))));
+        assert_eq!(
+            to_short("readme.txt"),
+            Some((*b"README  TXT", Some(LOWERCASE_BASE | LOWERCASE_EXT)))
+        );
+        assert_eq!(to_short("README.txt"), Some((*b"README  TXT", Some(LOWERCASE_EXT))));
+        assert_eq!(to_short("Readme.txt"), Some((*b"README  TXT", None)));
+        assert_eq!(to_short("LONGNAME1.TXT"), None);
+        assert_eq!(to_short("A.B.C"), None);
+        assert_eq!(to_short(".hidden"), None);
+        assert_eq!(to_short("a b"), None);
+
+        let mut out = [0; 12];
+        let len = format_short(b"README  TXT", LOWERCASE_EXT, &mut out);
+        assert_eq!(&out[..len], b"README.txt");
+        let len = format_short(b"..         ", 0, &mut out);
+        assert_eq!(&out[..len], b"..");
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!(AliasBasis::new("long file name.txt").alias(1), *b"LONGFI~1TXT");
+        assert_eq!(AliasBasis::new("a+b.tar.gz").alias(12), *b"A_BTA~12GZ ");
+        assert_eq!(AliasBasis::new(".profile").alias(1), *b"PROFIL~1   ");
+        assert_eq!(AliasBasis::new("ünï").alias(123456), *b"_~123456   ");
+    }
+
+    #[test]
+    fn validity() {
+        assert!(is_valid("a long name, with [brackets].txt"));
+        assert!(!is_valid(""));
+        assert!(!is_valid(".."));
+        assert!(!is_valid("trailing."));
+        assert!(!is_valid("what?"));
+    }
+}
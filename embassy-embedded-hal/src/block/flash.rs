@@ -0,0 +1,135 @@
+use embedded_storage_async::nor_flash::NorFlash;
+
+use super::{check_bounds, Block, BlockDevice, Error, BLOCK_SIZE};
+
+/// A NOR flash exposed as a block device.
+///
+/// Writing a block reads the erase sectors it touches into a scratch buffer, then erases and
+/// reprograms them. Sectors whose contents don't change are left alone, and sectors that are
+/// still erased are programmed without erasing first.
+///
+/// There is no wear leveling, so this is best suited for data that is rarely written, such as
+/// a small FAT volume holding configuration files.
+pub struct NorFlashBlockDevice<'a, F> {
+    flash: F,
+    buffer: &'a mut [u8],
+}
+
+impl<'a, F: NorFlash> NorFlashBlockDevice<'a, F> {
+    /// Create a new block device on `flash`, using `buffer` to hold one erase sector.
+    ///
+    /// Panics if `buffer` is smaller than the erase size, or if the read or write size of the
+    /// flash does not divide the block size.
+    pub fn new(flash: F, buffer: &'a mut [u8]) -> Self {
+        assert!(buffer.len() >= F::ERASE_SIZE, "buffer must hold one erase sector");
+        assert!(
+            BLOCK_SIZE % F::READ_SIZE == 0 && BLOCK_SIZE % F::WRITE_SIZE == 0,
+            "read and write size must divide the block size"
+        );
+        Self { flash, buffer }
+    }
+
+    /// Release the flash.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    fn len(&self) -> u32 {
+        (self.flash.capacity() / BLOCK_SIZE) as u32
+    }
+}
+
+impl<F: NorFlash> BlockDevice<BLOCK_SIZE> for NorFlashBlockDevice<'_, F> {
+    type Error = Error<F::Error>;
+    type Align = aligned::A4;
+
+    async fn read(&mut self, block_address: u32, blocks: &mut [Block]) -> Result<(), Self::Error> {
+        check_bounds(block_address, blocks.len(), self.len())?;
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let offset = (block_address as usize + i) * BLOCK_SIZE;
+            self.flash
+                .read(offset as u32, &mut block[..])
+                .await
+                .map_err(Error::Device)?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, block_address: u32, blocks: &[Block]) -> Result<(), Self::Error> {
+        check_bounds(block_address, blocks.len(), self.len())?;
+
+        let start = block_address as usize * BLOCK_SIZE;
+        let end = start + blocks.len() * BLOCK_SIZE;
+        let mut sector = start - start % F::ERASE_SIZE;
+        while sector < end {
+            let buf = &mut self.buffer[..F::ERASE_SIZE];
+            self.flash.read(sector as u32, buf).await.map_err(Error::Device)?;
+
+            // The part of the sector covered by this write.
+            let from = start.max(sector);
+            let to = end.min(sector + F::ERASE_SIZE);
+            let old = &mut buf[from - sector..to - sector];
+            let new = blocks.iter().flat_map(|b| b.iter()).skip(from - start);
+
+            let erased = old.iter().all(|&b| b == 0xff);
+            let mut changed = false;
+            for (old, &new) in old.iter_mut().zip(new) {
+                changed |= *old != new;
+                *old = new;
+            }
+
+            if changed && erased {
+                let data = &buf[from - sector..to - sector];
+                self.flash.write(from as u32, data).await.map_err(Error::Device)?;
+            } else if changed {
+                let to = (sector + F::ERASE_SIZE) as u32;
+                self.flash.erase(sector as u32, to).await.map_err(Error::Device)?;
+                self.flash.write(sector as u32, buf).await.map_err(Error::Device)?;
+            }
+            sector += F::ERASE_SIZE;
+        }
+        Ok(())
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        Ok(self.len() as u64 * BLOCK_SIZE as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{block_count, zeroed, Aligned};
+    use crate::flash::mem_flash::MemFlash;
+
+    #[futures_test::test]
+    async fn read_modify_write() {
+        let mut buffer = [0; 2048];
+        let mut device = NorFlashBlockDevice::new(MemFlash::<8192, 2048, 4>::default(), &mut buffer);
+        assert_eq!(block_count(&mut device).await, Ok(16));
+
+        // Erased flash is programmed without erasing.
+        device.write(1, &[Aligned([0x11; BLOCK_SIZE])]).await.unwrap();
+        assert!(device.flash.erases.is_empty());
+
+        // Rewriting an unchanged block does nothing, changing one erases its sector only.
+        device.write(1, &[Aligned([0x11; BLOCK_SIZE])]).await.unwrap();
+        assert_eq!(device.flash.writes.len(), 1);
+        device.write(2, &[Aligned([0x22; BLOCK_SIZE])]).await.unwrap();
+        device.write(2, &[Aligned([0x33; BLOCK_SIZE])]).await.unwrap();
+        assert_eq!(device.flash.erases, [(0, 2048)]);
+
+        let mut read = [zeroed(), zeroed(), zeroed()];
+        device.read(0, &mut read).await.unwrap();
+        assert_eq!(read[0], Aligned([0xff; BLOCK_SIZE]));
+        assert_eq!(read[1], Aligned([0x11; BLOCK_SIZE]));
+        assert_eq!(read[2], Aligned([0x33; BLOCK_SIZE]));
+
+        // Writes spanning erase sectors.
+        let blocks = [Aligned([0x44; BLOCK_SIZE]), Aligned([0x55; BLOCK_SIZE])];
+        device.write(3, &blocks).await.unwrap();
+        assert_eq!(device.flash.mem[3 * BLOCK_SIZE], 0x44);
+        assert_eq!(device.flash.mem[4 * BLOCK_SIZE], 0x55);
+        assert_eq!(device.write(15, &blocks).await, Err(Error::OutOfBounds));
+    }
+}
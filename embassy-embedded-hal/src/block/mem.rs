@@ -0,0 +1,88 @@
+use core::convert::Infallible;
+
+use super::{check_bounds, Block, BlockDevice, Error, BLOCK_SIZE};
+
+/// A block device backed by memory.
+///
+/// The storage can be anything that derefs to a byte slice, such as an array, a `&mut [u8]` or a
+/// `Vec<u8>`. Trailing bytes that don't fill a whole block are not used.
+pub struct MemBlockDevice<T> {
+    storage: T,
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> MemBlockDevice<T> {
+    /// Create a new block device backed by `storage`.
+    pub const fn new(storage: T) -> Self {
+        Self { storage }
+    }
+
+    /// Get the underlying storage.
+    pub fn storage(&self) -> &T {
+        &self.storage
+    }
+
+    /// Get the underlying storage mutably.
+    pub fn storage_mut(&mut self) -> &mut T {
+        &mut self.storage
+    }
+
+    /// Consume the device, returning the underlying storage.
+    pub fn into_inner(self) -> T {
+        self.storage
+    }
+
+    fn len(&self) -> u32 {
+        (self.storage.as_ref().len() / BLOCK_SIZE) as u32
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> BlockDevice<BLOCK_SIZE> for MemBlockDevice<T> {
+    type Error = Error<Infallible>;
+    type Align = aligned::A4;
+
+    async fn read(&mut self, block_address: u32, blocks: &mut [Block]) -> Result<(), Self::Error> {
+        check_bounds(block_address, blocks.len(), self.len())?;
+        let start = block_address as usize * BLOCK_SIZE;
+        let data = self.storage.as_ref()[start..].chunks_exact(BLOCK_SIZE);
+        for (block, data) in blocks.iter_mut().zip(data) {
+            block.copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, block_address: u32, blocks: &[Block]) -> Result<(), Self::Error> {
+        check_bounds(block_address, blocks.len(), self.len())?;
+        let start = block_address as usize * BLOCK_SIZE;
+        let data = self.storage.as_mut()[start..].chunks_exact_mut(BLOCK_SIZE);
+        for (block, data) in blocks.iter().zip(data) {
+            data.copy_from_slice(&block[..]);
+        }
+        Ok(())
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        Ok(self.len() as u64 * BLOCK_SIZE as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{block_count, zeroed, Aligned};
+
+    #[futures_test::test]
+    async fn read_write() {
+        let mut device = MemBlockDevice::new([0u8; 4 * BLOCK_SIZE + 100]);
+        assert_eq!(block_count(&mut device).await, Ok(4));
+
+        let blocks = [Aligned([0xaa; BLOCK_SIZE]), Aligned([0xbb; BLOCK_SIZE])];
+        device.write(2, &blocks).await.unwrap();
+        assert_eq!(device.write(3, &blocks).await, Err(Error::OutOfBounds));
+
+        let mut read = [zeroed(), zeroed()];
+        device.read(1, &mut read).await.unwrap();
+        assert_eq!(read[0], zeroed());
+        assert_eq!(read[1], blocks[0]);
+        assert_eq!(device.storage()[3 * BLOCK_SIZE], 0xbb);
+    }
+}
@@ -0,0 +1,61 @@
+//! Block device abstraction and utilities.
+//!
+//! [`BlockDevice`] is the async interface of [`block_device_driver`] to storage addressed in fixed
+//! blocks, such as SD cards, eMMC or USB mass storage. This module works with 512-byte blocks,
+//! and provides:
+//!
+//! - [`MemBlockDevice`], a block device in RAM, for tests and RAM disks.
+//! - [`NorFlashBlockDevice`], exposing a NOR flash as blocks.
+//! - [`Partition`], restricting a shared block device to a range of blocks, and
+//!   [`partition_table`] to find partitions in an MBR or GPT partition table.
+//! - [`fat`], a FAT12/16/32 filesystem.
+
+pub mod fat;
+mod flash;
+mod mem;
+mod partition;
+pub mod partition_table;
+
+pub use aligned::Aligned;
+pub use block_device_driver::BlockDevice;
+pub use flash::NorFlashBlockDevice;
+pub use mem::MemBlockDevice;
+pub use partition::Partition;
+
+/// Size of a block in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+/// A block of data, with the alignment `A` required by a block device.
+///
+/// Blocks of the devices in this module are 4-byte aligned, so drivers can use them as DMA
+/// buffers directly.
+pub type Block<A = aligned::A4> = Aligned<A, [u8; BLOCK_SIZE]>;
+
+/// Create a block filled with zeros.
+pub(crate) const fn zeroed<A: aligned::Alignment>() -> Block<A> {
+    Aligned([0; BLOCK_SIZE])
+}
+
+/// Number of blocks of `device`, saturated to the 32-bit block addresses of [`BlockDevice`].
+pub(crate) async fn block_count<D: BlockDevice<BLOCK_SIZE>>(device: &mut D) -> Result<u32, D::Error> {
+    let blocks = device.size().await? / BLOCK_SIZE as u64;
+    Ok(blocks.min(u32::MAX as u64) as u32)
+}
+
+/// Block device adapter error.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<T> {
+    /// The requested blocks are outside the device.
+    OutOfBounds,
+    /// Underlying device error.
+    Device(T),
+}
+
+/// Check that `count` blocks starting at `block_address` fit in a device of `block_count` blocks.
+fn check_bounds<T>(block_address: u32, count: usize, block_count: u32) -> Result<(), Error<T>> {
+    match (block_address as u64 + count as u64) <= block_count as u64 {
+        true => Ok(()),
+        false => Err(Error::OutOfBounds),
+    }
+}
@@ -0,0 +1,89 @@
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+
+use super::{check_bounds, Block, BlockDevice, Error, BLOCK_SIZE};
+
+/// A range of blocks of an underlying shared block device.
+///
+/// Like [`flash::partition::Partition`](crate::flash::partition::Partition), there is no
+/// guarantee that multiple partitions on the same device don't overlap; use
+/// [`partition_table`](super::partition_table) to find the partitions of a disk.
+pub struct Partition<'a, M: RawMutex, T: BlockDevice<BLOCK_SIZE>> {
+    device: &'a Mutex<M, T>,
+    offset: u32,
+    count: u32,
+}
+
+impl<'a, M: RawMutex, T: BlockDevice<BLOCK_SIZE>> Clone for Partition<'a, M, T> {
+    fn clone(&self) -> Self {
+        Self {
+            device: self.device,
+            offset: self.offset,
+            count: self.count,
+        }
+    }
+}
+
+impl<'a, M: RawMutex, T: BlockDevice<BLOCK_SIZE>> Partition<'a, M, T> {
+    /// Create a new partition of `count` blocks starting at block `offset`.
+    pub const fn new(device: &'a Mutex<M, T>, offset: u32, count: u32) -> Self {
+        Self { device, offset, count }
+    }
+
+    /// Get the first block of the partition on the device.
+    pub const fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Get the number of blocks in the partition.
+    pub const fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+impl<M: RawMutex, T: BlockDevice<BLOCK_SIZE>> BlockDevice<BLOCK_SIZE> for Partition<'_, M, T> {
+    type Error = Error<T::Error>;
+    type Align = T::Align;
+
+    async fn read(&mut self, block_address: u32, blocks: &mut [Block<Self::Align>]) -> Result<(), Self::Error> {
+        check_bounds(block_address, blocks.len(), self.count)?;
+        let mut device = self.device.lock().await;
+        device
+            .read(self.offset + block_address, blocks)
+            .await
+            .map_err(Error::Device)
+    }
+
+    async fn write(&mut self, block_address: u32, blocks: &[Block<Self::Align>]) -> Result<(), Self::Error> {
+        check_bounds(block_address, blocks.len(), self.count)?;
+        let mut device = self.device.lock().await;
+        device
+            .write(self.offset + block_address, blocks)
+            .await
+            .map_err(Error::Device)
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        Ok(self.count as u64 * BLOCK_SIZE as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::block::{zeroed, Aligned, MemBlockDevice};
+
+    #[futures_test::test]
+    async fn offset_and_bounds() {
+        let device = Mutex::<NoopRawMutex, _>::new(MemBlockDevice::new([0u8; 8 * BLOCK_SIZE]));
+        let mut partition = Partition::new(&device, 2, 4);
+
+        partition.write(1, &[Aligned([0xaa; BLOCK_SIZE])]).await.unwrap();
+        assert_eq!(partition.write(4, &[zeroed()]).await, Err(Error::OutOfBounds));
+
+        let device = device.try_lock().unwrap();
+        assert_eq!(device.storage()[3 * BLOCK_SIZE], 0xaa);
+    }
+}
@@ -0,0 +1,402 @@
+//! MBR and GPT partition tables.
+//!
+//! [`read`] finds the partitions on a disk, which can then be accessed with
+//! [`Partition`](super::Partition):
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::block::{partition_table, Partition};
+//!
+//! let mut partitions = [partition_table::PartitionInfo::default(); 4];
+//! let n = partition_table::read(&mut sd_card, &mut partitions).await?;
+//! let info = partitions[..n].iter().find(|p| p.partition_type.is_fat()).unwrap();
+//!
+//! let disk = Mutex::<NoopRawMutex, _>::new(sd_card);
+//! let volume = Partition::new(&disk, info.first_block, info.block_count);
+//! ```
+//!
+//! Only the four primary partitions of an MBR are reported; extended partitions are returned
+//! as is, without following the logical partitions inside them.
+
+use super::{block_count, zeroed, Block, BlockDevice, BLOCK_SIZE};
+
+/// Partition table error.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<T> {
+    /// The first block of the disk holds no valid MBR.
+    NoPartitionTable,
+    /// The protective MBR points to a GPT, but neither the primary nor the backup GPT is valid.
+    InvalidGpt,
+    /// A GPT partition lies beyond the 32-bit block addresses supported by [`BlockDevice`].
+    OutOfRange,
+    /// Underlying device error.
+    Device(T),
+}
+
+/// A GUID, as stored on disk.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// EFI system partition.
+    pub const EFI_SYSTEM: Self = Self::from_fields(
+        0xC12A7328,
+        0xF81F,
+        0x11D2,
+        [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+    );
+    /// Microsoft basic data partition, used for FAT and exFAT volumes.
+    pub const BASIC_DATA: Self = Self::from_fields(
+        0xEBD0A0A2,
+        0xB9E5,
+        0x4433,
+        [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+    );
+    /// Linux filesystem data.
+    pub const LINUX_FILESYSTEM: Self = Self::from_fields(
+        0x0FC63DAF,
+        0x8483,
+        0x4772,
+        [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+    );
+
+    /// Create a GUID from its fields, as written in the usual
+    /// `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` notation.
+    pub const fn from_fields(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7],
+        ])
+    }
+}
+
+/// Type of a partition.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PartitionType {
+    /// MBR partition type byte.
+    Mbr(u8),
+    /// GPT partition type GUID.
+    Gpt(Guid),
+}
+
+impl PartitionType {
+    /// Whether the partition type is used for FAT volumes.
+    pub fn is_fat(&self) -> bool {
+        match self {
+            Self::Mbr(t) => matches!(t, 0x01 | 0x04 | 0x06 | 0x0b | 0x0c | 0x0e),
+            Self::Gpt(guid) => *guid == Guid::BASIC_DATA || *guid == Guid::EFI_SYSTEM,
+        }
+    }
+}
+
+/// A partition found in a partition table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PartitionInfo {
+    /// Partition type.
+    pub partition_type: PartitionType,
+    /// First block of the partition.
+    pub first_block: u32,
+    /// Number of blocks in the partition.
+    pub block_count: u32,
+}
+
+impl Default for PartitionInfo {
+    fn default() -> Self {
+        Self {
+            partition_type: PartitionType::Mbr(0),
+            first_block: 0,
+            block_count: 0,
+        }
+    }
+}
+
+const MBR_GPT_PROTECTIVE: u8 = 0xee;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Read the partition table of `device` into `partitions`, returning the number of partitions
+/// found.
+///
+/// Partitions that don't fit in `partitions` are ignored. A GPT is used if the MBR is a
+/// protective MBR; if the primary GPT is damaged, the backup GPT at the end of the disk is
+/// used instead.
+pub async fn read<D: BlockDevice<BLOCK_SIZE>>(device: &mut D, partitions: &mut [PartitionInfo]) -> Result<usize, Error<D::Error>> {
+    let mut block = zeroed();
+    device
+        .read(0, core::slice::from_mut(&mut block))
+        .await
+        .map_err(Error::Device)?;
+
+    let entries = parse_mbr(&block).ok_or(Error::NoPartitionTable)?;
+    if entries
+        .iter()
+        .any(|e| e.partition_type == PartitionType::Mbr(MBR_GPT_PROTECTIVE))
+    {
+        return read_gpt(device, &mut block, partitions).await;
+    }
+
+    let mut n = 0;
+    for (entry, out) in entries.iter().filter(|e| e.block_count != 0).zip(partitions) {
+        *out = *entry;
+        n += 1;
+    }
+    Ok(n)
+}
+
+fn parse_mbr(block: &[u8; BLOCK_SIZE]) -> Option<[PartitionInfo; 4]> {
+    if block[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+
+    let mut entries = [PartitionInfo::default(); 4];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let raw = &block[446 + i * 16..][..16];
+        // Boot code of a volume boot record in place of an MBR won't have valid status bytes.
+        if raw[0] != 0x00 && raw[0] != 0x80 {
+            return None;
+        }
+        if raw[4] == 0 {
+            continue;
+        }
+        *entry = PartitionInfo {
+            partition_type: PartitionType::Mbr(raw[4]),
+            first_block: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            block_count: u32::from_le_bytes(raw[12..16].try_into().unwrap()),
+        };
+    }
+
+    match entries.iter().any(|e| e.block_count != 0 && e.first_block != 0) {
+        true => Some(entries),
+        false => None,
+    }
+}
+
+struct GptHeader {
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+async fn read_gpt<D: BlockDevice<BLOCK_SIZE>>(
+    device: &mut D,
+    block: &mut Block<D::Align>,
+    partitions: &mut [PartitionInfo],
+) -> Result<usize, Error<D::Error>> {
+    let last = block_count(device).await.map_err(Error::Device)?.saturating_sub(1);
+    for header_lba in [1, last] {
+        device
+            .read(header_lba, core::slice::from_mut(block))
+            .await
+            .map_err(Error::Device)?;
+        let Some(header) = parse_gpt_header(block, header_lba) else {
+            continue;
+        };
+        if let Some(n) = read_gpt_entries(device, block, &header, partitions).await? {
+            return Ok(n);
+        }
+    }
+    Err(Error::InvalidGpt)
+}
+
+fn parse_gpt_header(block: &[u8; BLOCK_SIZE], lba: u32) -> Option<GptHeader> {
+    let u32_at = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_le_bytes(block[i..i + 8].try_into().unwrap());
+
+    let header_size = u32_at(12) as usize;
+    if &block[..8] != GPT_SIGNATURE || !(92..=512).contains(&header_size) || u64_at(24) != lba as u64 {
+        return None;
+    }
+
+    let mut header = [0; 512];
+    header[..header_size].copy_from_slice(&block[..header_size]);
+    header[16..20].fill(0);
+    if crc32(!0, &header[..header_size]) != !u32_at(16) {
+        return None;
+    }
+
+    let entry_size = u32_at(84);
+    if entry_size < 128 || 512 % entry_size != 0 {
+        return None;
+    }
+    Some(GptHeader {
+        entries_lba: u64_at(72),
+        entry_count: u32_at(80),
+        entry_size,
+        entries_crc: u32_at(88),
+    })
+}
+
+/// Read the partition entries, or `None` if their checksum doesn't match.
+async fn read_gpt_entries<D: BlockDevice<BLOCK_SIZE>>(
+    device: &mut D,
+    block: &mut Block<D::Align>,
+    header: &GptHeader,
+    partitions: &mut [PartitionInfo],
+) -> Result<Option<usize>, Error<D::Error>> {
+    let per_block = 512 / header.entry_size;
+    let blocks = header.entry_count.div_ceil(per_block);
+    let first: u32 = header.entries_lba.try_into().map_err(|_| Error::OutOfRange)?;
+
+    let mut crc = !0;
+    let mut n = 0;
+    for i in 0..blocks {
+        device
+            .read(first + i, core::slice::from_mut(block))
+            .await
+            .map_err(Error::Device)?;
+
+        let in_block = per_block.min(header.entry_count - i * per_block) as usize;
+        let entries = block[..in_block * header.entry_size as usize].chunks_exact(header.entry_size as usize);
+        for entry in entries {
+            crc = crc32(crc, entry);
+
+            let type_guid = Guid(entry[..16].try_into().unwrap());
+            if type_guid == Guid::default() || n == partitions.len() {
+                continue;
+            }
+            let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+            let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+            let (Ok(first_block), Ok(block_count)) = (
+                first_lba.try_into(),
+                (last_lba + 1).saturating_sub(first_lba).try_into(),
+            ) else {
+                return Err(Error::OutOfRange);
+            };
+            partitions[n] = PartitionInfo {
+                partition_type: PartitionType::Gpt(type_guid),
+                first_block,
+                block_count,
+            };
+            n += 1;
+        }
+    }
+
+    match !crc == header.entries_crc {
+        true => Ok(Some(n)),
+        false => Ok(None),
+    }
+}
+
+/// Update a CRC-32 (IEEE 802.3) with `data`, without the initial and final inversion.
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::MemBlockDevice;
+
+    fn mbr_entry(disk: &mut [u8], i: usize, partition_type: u8, first: u32, count: u32) {
+        let entry = &mut disk[446 + i * 16..][..16];
+        entry[4] = partition_type;
+        entry[8..12].copy_from_slice(&first.to_le_bytes());
+        entry[12..16].copy_from_slice(&count.to_le_bytes());
+        disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+    }
+
+    /// Write a GPT header at `lba` with two partitions, and its entries at `entries_lba`.
+    fn gpt(disk: &mut [u8], lba: usize, entries_lba: usize) {
+        let entries = &mut disk[entries_lba * BLOCK_SIZE..][..32 * BLOCK_SIZE];
+        entries.fill(0);
+        for (i, (guid, first, last)) in [(Guid::EFI_SYSTEM, 34u64, 40u64), (Guid::BASIC_DATA, 41, 60)]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = &mut entries[i * 128..][..128];
+            entry[..16].copy_from_slice(&guid.0);
+            entry[16] = i as u8 + 1;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        let entries_crc = !crc32(!0, &entries[..128 * 128]);
+
+        let header = &mut disk[lba * BLOCK_SIZE..][..BLOCK_SIZE];
+        header.fill(0);
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&(lba as u64).to_le_bytes());
+        header[72..80].copy_from_slice(&(entries_lba as u64).to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = !crc32(!0, &header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(!crc32(!0, b"123456789"), 0xcbf4_3926);
+    }
+
+    #[futures_test::test]
+    async fn mbr() {
+        let mut disk = [0u8; 4 * BLOCK_SIZE];
+        mbr_entry(&mut disk, 0, 0x0c, 2048, 1000);
+        mbr_entry(&mut disk, 2, 0x83, 4096, 2000);
+        let mut device = MemBlockDevice::new(disk);
+
+        let mut partitions = [PartitionInfo::default(); 4];
+        let n = read(&mut device, &mut partitions).await.unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(
+            partitions[0],
+            PartitionInfo {
+                partition_type: PartitionType::Mbr(0x0c),
+                first_block: 2048,
+                block_count: 1000,
+            }
+        );
+        assert!(partitions[0].partition_type.is_fat());
+        assert_eq!(partitions[1].partition_type, PartitionType::Mbr(0x83));
+
+        // A volume boot record is not a partition table.
+        device.storage_mut()[446] = 0x3c;
+        assert_eq!(read(&mut device, &mut partitions).await, Err(Error::NoPartitionTable));
+    }
+
+    #[futures_test::test]
+    async fn gpt_with_backup() {
+        let mut disk = [0u8; 128 * BLOCK_SIZE];
+        mbr_entry(&mut disk, 0, MBR_GPT_PROTECTIVE, 1, 127);
+        gpt(&mut disk, 1, 2);
+        let mut device = MemBlockDevice::new(disk);
+
+        let mut partitions = [PartitionInfo::default(); 1];
+        assert_eq!(read(&mut device, &mut partitions).await, Ok(1));
+        assert_eq!(partitions[0].partition_type, PartitionType::Gpt(Guid::EFI_SYSTEM));
+
+        let mut partitions = [PartitionInfo::default(); 4];
+        assert_eq!(read(&mut device, &mut partitions).await, Ok(2));
+        assert_eq!(
+            partitions[1],
+            PartitionInfo {
+                partition_type: PartitionType::Gpt(Guid::BASIC_DATA),
+                first_block: 41,
+                block_count: 20,
+            }
+        );
+
+        // Corrupt the primary entries, fall back to the backup.
+        let disk = device.storage_mut();
+        gpt(disk, 127, 95);
+        disk[2 * BLOCK_SIZE + 32] ^= 1;
+        assert_eq!(read(&mut device, &mut partitions).await, Ok(2));
+        assert_eq!(partitions[1].first_block, 41);
+
+        device.storage_mut()[127 * BLOCK_SIZE] = 0;
+        assert_eq!(read(&mut device, &mut partitions).await, Err(Error::InvalidGpt));
+    }
+}
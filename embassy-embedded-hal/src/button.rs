@@ -0,0 +1,292 @@
+//! Debounced button input with long-press, repeat and multi-click detection.
+//!
+//! [`Button`] turns any pin implementing [`InputPin`] and async [`Wait`] (for example an
+//! `ExtiInput` on stm32 or an `Input` on nrf and rp) into a sequence of [`Event`]s:
+//!
+//! - [`Event::Press`] and [`Event::Release`] as soon as the pin has been stable for
+//!   [`Config::debounce`].
+//! - [`Event::LongPress`] once the button has been held for [`Config::long_press`], followed by
+//!   [`Event::Repeat`] every [`Config::repeat`] while it stays held.
+//! - [`Event::Click`] with the number of short presses, once no further press followed within
+//!   [`Config::click_gap`] of the last release.
+//!
+//! Since a click is only reported after the click gap has passed, use [`Event::Press`] or
+//! [`Event::Release`] directly when latency matters more than telling single and double clicks
+//! apart.
+//!
+//! # Example (stm32)
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::button::{Button, Config, Event};
+//! use embassy_stm32::exti::ExtiInput;
+//! use embassy_stm32::gpio::Pull;
+//!
+//! let pin = ExtiInput::new(p.PC13, p.EXTI13, Pull::Up);
+//! let mut button = Button::new(pin, Config::default());
+//!
+//! loop {
+//!     match button.next().await? {
+//!         Event::Click(1) => info!("single click"),
+//!         Event::Click(2) => info!("double click"),
+//!         Event::LongPress => info!("long press"),
+//!         _ => {}
+//!     }
+//! }
+//! ```
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_1::digital::{InputPin, PinState};
+use embedded_hal_async::digital::Wait;
+
+/// Button configuration.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Pin level while the button is pressed. Defaults to [`PinState::Low`], for a button to
+    /// ground with a pull-up.
+    pub pressed_level: PinState,
+    /// How long the pin must be stable before a change is accepted.
+    pub debounce: Duration,
+    /// How long the button must be held to report [`Event::LongPress`].
+    pub long_press: Duration,
+    /// Interval of [`Event::Repeat`] after a long press, or `None` to disable repeating.
+    pub repeat: Option<Duration>,
+    /// Maximum time between releasing the button and pressing it again for the presses to be
+    /// counted as one multi-click.
+    pub click_gap: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pressed_level: PinState::Low,
+            debounce: Duration::from_millis(10),
+            long_press: Duration::from_millis(500),
+            repeat: None,
+            click_gap: Duration::from_millis(300),
+        }
+    }
+}
+
+/// Button event.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// The button was pressed.
+    Press,
+    /// The button was released.
+    Release,
+    /// The button was clicked this many times in a row.
+    ///
+    /// A press that turned into a long press is not a click, and discards the clicks before it.
+    Click(u8),
+    /// The button has been held for [`Config::long_press`].
+    LongPress,
+    /// The button is still held after a long press, see [`Config::repeat`].
+    Repeat,
+}
+
+/// Debounced button.
+pub struct Button<P> {
+    pin: P,
+    config: Config,
+    pressed: bool,
+    long: bool,
+    clicks: u8,
+    deadline: Option<Instant>,
+}
+
+impl<P> Button<P>
+where
+    P: InputPin + Wait,
+{
+    /// Create a new button.
+    ///
+    /// The button starts out released; if the pin is already at the pressed level, the first
+    /// event is [`Event::Press`].
+    pub fn new(pin: P, config: Config) -> Self {
+        Self {
+            pin,
+            config,
+            pressed: false,
+            long: false,
+            clicks: 0,
+            deadline: None,
+        }
+    }
+
+    /// Release the pin.
+    pub fn release(self) -> P {
+        self.pin
+    }
+
+    /// Whether the button is currently pressed, after debouncing.
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Change the configuration. Takes effect from the next state change.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Wait for the next event.
+    ///
+    /// This is cancel safe, except that a debounce in progress is restarted on the next call.
+    pub async fn next(&mut self) -> Result<Event, P::Error> {
+        loop {
+            let changed = match self.deadline {
+                Some(deadline) => match select(self.wait_change(), Timer::at(deadline)).await {
+                    Either::First(res) => res?,
+                    Either::Second(()) => return Ok(self.timeout(deadline)),
+                },
+                None => self.wait_change().await?,
+            };
+            if !changed {
+                continue;
+            }
+
+            self.pressed = !self.pressed;
+            let now = Instant::now();
+            if self.pressed {
+                self.long = false;
+                self.deadline = Some(now + self.config.long_press);
+                return Ok(Event::Press);
+            }
+
+            self.deadline = match self.long {
+                true => None,
+                false => {
+                    self.clicks = self.clicks.saturating_add(1);
+                    Some(now + self.config.click_gap)
+                }
+            };
+            return Ok(Event::Release);
+        }
+    }
+
+    /// Handle the long-press, repeat or click gap deadline passing.
+    fn timeout(&mut self, deadline: Instant) -> Event {
+        if !self.pressed {
+            self.deadline = None;
+            return Event::Click(core::mem::take(&mut self.clicks));
+        }
+
+        self.deadline = self.config.repeat.map(|repeat| deadline + repeat);
+        match self.long {
+            true => Event::Repeat,
+            false => {
+                self.long = true;
+                self.clicks = 0;
+                Event::LongPress
+            }
+        }
+    }
+
+    /// Wait for the pin to leave the current state and settle, returning whether it settled in
+    /// the other state.
+    async fn wait_change(&mut self) -> Result<bool, P::Error> {
+        let target = match self.pressed {
+            true => !self.config.pressed_level,
+            false => self.config.pressed_level,
+        };
+        match target {
+            PinState::High => self.pin.wait_for_high().await?,
+            PinState::Low => self.pin.wait_for_low().await?,
+        }
+
+        // Restart the debounce period on every edge.
+        while let Either::First(res) = select(self.pin.wait_for_any_edge(), Timer::after(self.config.debounce)).await {
+            res?;
+        }
+
+        let high = self.pin.is_high()?;
+        Ok(PinState::from(high) == target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embedded_hal_1::digital::OutputPin;
+
+    use super::*;
+    use crate::bitbang::sim::{run, Loopback};
+
+    /// Drive the button through `script` of `(level, duration)` steps and collect its events.
+    fn events(config: Config, script: &[(bool, u64)]) -> Vec<Event> {
+        let (mut out, pin) = Loopback::new();
+        let mut button = Button::new(pin, config);
+        let mut events = Vec::new();
+
+        run(select(
+            async {
+                for &(high, ms) in script {
+                    out.set_state(PinState::from(high)).unwrap();
+                    Timer::after_millis(ms).await;
+                }
+            },
+            async {
+                loop {
+                    events.push(button.next().await.unwrap());
+                }
+            },
+        ));
+        events
+    }
+
+    #[test]
+    fn bouncy_click() {
+        let events = events(
+            Config::default(),
+            &[(false, 1), (true, 2), (false, 100), (true, 1), (false, 1), (true, 500)],
+        );
+        assert_eq!(events, [Event::Press, Event::Release, Event::Click(1)]);
+    }
+
+    #[test]
+    fn double_click() {
+        let events = events(Config::default(), &[(false, 50), (true, 100), (false, 50), (true, 500)]);
+        assert_eq!(
+            events,
+            [
+                Event::Press,
+                Event::Release,
+                Event::Press,
+                Event::Release,
+                Event::Click(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn long_press_with_repeat() {
+        let config = Config {
+            repeat: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let events = events(config, &[(false, 50), (true, 100), (false, 750), (true, 500)]);
+        assert_eq!(
+            events,
+            [
+                Event::Press,
+                Event::Release,
+                Event::Press,
+                Event::LongPress,
+                Event::Repeat,
+                Event::Repeat,
+                Event::Release,
+            ]
+        );
+    }
+
+    #[test]
+    fn glitch_is_ignored() {
+        let events = events(Config::default(), &[(false, 2), (true, 500)]);
+        assert_eq!(events, []);
+    }
+}
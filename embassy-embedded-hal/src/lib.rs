@@ -3,8 +3,20 @@
 #![warn(missing_docs)]
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "mock")]
+extern crate alloc;
+
 pub mod adapter;
+#[cfg(feature = "time")]
+pub mod bitbang;
+pub mod block;
+#[cfg(feature = "time")]
+pub mod button;
 pub mod flash;
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "time")]
+pub mod rotary;
 pub mod shared_bus;
 
 /// Set the configuration of a peripheral driver.
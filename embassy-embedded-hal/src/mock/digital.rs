@@ -0,0 +1,211 @@
+//! Mock GPIO pin
+//!
+//! [`MockPin`] implements `OutputPin`, `InputPin` and the async `Wait` trait. Setting the output,
+//! reading the input and waiting each consume one [`Transaction`].
+
+use embedded_hal_1::digital::{ErrorKind, ErrorType, InputPin, OutputPin, PinState};
+
+use super::{Expectations, Injected};
+
+/// Condition a [`Wait`](embedded_hal_async::digital::Wait) call waits for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaitFor {
+    /// `wait_for_high`
+    High,
+    /// `wait_for_low`
+    Low,
+    /// `wait_for_rising_edge`
+    RisingEdge,
+    /// `wait_for_falling_edge`
+    FallingEdge,
+    /// `wait_for_any_edge`
+    AnyEdge,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Set(PinState),
+    Get(PinState),
+    Wait(WaitFor),
+}
+
+/// An expected pin operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    kind: Kind,
+    injected: Injected<ErrorKind>,
+}
+
+impl Transaction {
+    fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            injected: Injected::none(),
+        }
+    }
+
+    /// Expect the output to be set to `state`.
+    pub fn set(state: PinState) -> Self {
+        Self::new(Kind::Set(state))
+    }
+
+    /// Expect the input to be read, returning `state`.
+    pub fn get(state: PinState) -> Self {
+        Self::new(Kind::Get(state))
+    }
+
+    /// Expect a wait for `condition`.
+    ///
+    /// Combine with `with_delay` to simulate the event happening some time later.
+    pub fn wait(condition: WaitFor) -> Self {
+        Self::new(Kind::Wait(condition))
+    }
+
+    /// Make the operation fail with `error` once it has been checked.
+    pub fn with_error(mut self, error: ErrorKind) -> Self {
+        self.injected.error = Some(error);
+        self
+    }
+
+    /// Delay completion of the operation by `delay`.
+    ///
+    /// Async operations wait on a [`Timer`](embassy_time::Timer), blocking operations busy-wait.
+    #[cfg(feature = "time")]
+    pub fn with_delay(mut self, delay: embassy_time::Duration) -> Self {
+        self.injected.delay = Some(delay);
+        self
+    }
+}
+
+/// Mock GPIO pin checking operations against a list of expected [`Transaction`]s.
+///
+/// Clones share the same expectations, so a clone can be given to a driver (e.g. as the CS pin of
+/// a shared bus [`SpiDevice`](crate::shared_bus::asynch::spi::SpiDevice)) while the test keeps
+/// another to call [`done`](MockPin::done).
+#[derive(Clone)]
+pub struct MockPin {
+    expectations: Expectations<Transaction>,
+}
+
+impl MockPin {
+    /// Create a new mock expecting `expectations`, in order.
+    pub fn new(expectations: &[Transaction]) -> Self {
+        Self {
+            expectations: Expectations::new(expectations),
+        }
+    }
+
+    /// Append more expectations to the script.
+    pub fn update_expectations(&mut self, expectations: &[Transaction]) {
+        self.expectations.extend(expectations);
+    }
+
+    /// Assert that all expectations have been consumed.
+    pub fn done(&mut self) {
+        self.expectations.done();
+    }
+
+    fn set(&mut self, state: PinState) -> Result<(), ErrorKind> {
+        let t = self.expectations.next("set");
+        t.injected.block();
+        match t.kind {
+            Kind::Set(s) => assert_eq!(state, s, "pin state mismatch"),
+            other => panic!("expected {:?}, got set to {:?}", other, state),
+        }
+        t.injected.result()
+    }
+
+    fn get(&mut self) -> Result<PinState, ErrorKind> {
+        let t = self.expectations.next("get");
+        t.injected.block();
+        let state = match t.kind {
+            Kind::Get(s) => s,
+            other => panic!("expected {:?}, got get", other),
+        };
+        t.injected.result().map(|_| state)
+    }
+
+    async fn wait(&mut self, condition: WaitFor) -> Result<(), ErrorKind> {
+        let t = self.expectations.next("wait");
+        t.injected.delay().await;
+        match t.kind {
+            Kind::Wait(c) => assert_eq!(condition, c, "wait condition mismatch"),
+            other => panic!("expected {:?}, got wait for {:?}", other, condition),
+        }
+        t.injected.result()
+    }
+}
+
+impl ErrorType for MockPin {
+    type Error = ErrorKind;
+}
+
+impl OutputPin for MockPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.set(PinState::Low)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.set(PinState::High)
+    }
+}
+
+impl InputPin for MockPin {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.get()? == PinState::High)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.get()? == PinState::Low)
+    }
+}
+
+impl embedded_hal_async::digital::Wait for MockPin {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait(WaitFor::High).await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait(WaitFor::Low).await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait(WaitFor::RisingEdge).await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait(WaitFor::FallingEdge).await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait(WaitFor::AnyEdge).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_async::digital::Wait;
+
+    use super::*;
+
+    #[futures_test::test]
+    async fn scripted_input() {
+        let mut pin = MockPin::new(&[
+            Transaction::get(PinState::High),
+            Transaction::wait(WaitFor::FallingEdge),
+            Transaction::get(PinState::Low).with_error(ErrorKind::Other),
+        ]);
+
+        assert!(pin.is_high().unwrap());
+        pin.wait_for_falling_edge().await.unwrap();
+        assert_eq!(pin.is_low(), Err(ErrorKind::Other));
+        pin.done();
+    }
+
+    #[test]
+    #[should_panic(expected = "pin state mismatch")]
+    fn wrong_state_panics() {
+        let mut pin = MockPin::new(&[Transaction::set(PinState::Low)]);
+        let _ = pin.set_high();
+    }
+}
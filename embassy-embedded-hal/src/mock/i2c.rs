@@ -0,0 +1,276 @@
+//! Mock I2C bus
+//!
+//! [`MockI2c`] implements both the blocking and async `I2c` traits. Each call to
+//! `transaction` (and therefore each `read`, `write` and `write_read`) consumes one
+//! [`Transaction`], which lists the operations expected between START and STOP.
+
+use alloc::vec::Vec;
+
+use embedded_hal_1::i2c::{self, Error, ErrorKind, ErrorType};
+
+use super::{Expectations, Injected};
+
+/// An expected operation within an I2C transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Expect a read, returning the given bytes to the caller.
+    Read(Vec<u8>),
+    /// Expect a write of exactly the given bytes.
+    Write(Vec<u8>),
+}
+
+/// An expected I2C transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    address: u8,
+    operations: Vec<Operation>,
+    injected: Injected<ErrorKind>,
+}
+
+impl Transaction {
+    /// Expect a transaction with `address` consisting of `operations`.
+    pub fn new(address: u8, operations: &[Operation]) -> Self {
+        Self {
+            address,
+            operations: operations.into(),
+            injected: Injected::none(),
+        }
+    }
+
+    /// Expect a read from `address`, returning `data` to the caller.
+    pub fn read(address: u8, data: &[u8]) -> Self {
+        Self::new(address, &[Operation::Read(data.into())])
+    }
+
+    /// Expect a write of exactly `data` to `address`.
+    pub fn write(address: u8, data: &[u8]) -> Self {
+        Self::new(address, &[Operation::Write(data.into())])
+    }
+
+    /// Expect a write of exactly `write` followed by a read returning `read`, with a repeated start.
+    pub fn write_read(address: u8, write: &[u8], read: &[u8]) -> Self {
+        Self::new(address, &[Operation::Write(write.into()), Operation::Read(read.into())])
+    }
+
+    /// Make the transaction fail with `error` once it has been checked.
+    pub fn with_error(mut self, error: ErrorKind) -> Self {
+        self.injected.error = Some(error);
+        self
+    }
+
+    /// Delay completion of the transaction by `delay`.
+    ///
+    /// Async operations wait on a [`Timer`](embassy_time::Timer), blocking operations busy-wait.
+    #[cfg(feature = "time")]
+    pub fn with_delay(mut self, delay: embassy_time::Duration) -> Self {
+        self.injected.delay = Some(delay);
+        self
+    }
+
+    fn complete(self, address: u8, operations: &mut [i2c::Operation<'_>]) -> Result<(), ErrorKind> {
+        assert_eq!(address, self.address, "address mismatch");
+        assert_eq!(
+            operations.len(),
+            self.operations.len(),
+            "operation count mismatch, expected {:?}",
+            self.operations
+        );
+        for (op, expected) in operations.iter_mut().zip(self.operations) {
+            match (op, expected) {
+                (i2c::Operation::Read(buf), Operation::Read(data)) => {
+                    assert_eq!(buf.len(), data.len(), "read length mismatch");
+                    buf.copy_from_slice(&data);
+                }
+                (i2c::Operation::Write(buf), Operation::Write(data)) => {
+                    assert_eq!(*buf, &data[..], "write data mismatch");
+                }
+                (op, expected) => panic!("expected {:?}, got {:?}", expected, op),
+            }
+        }
+        self.injected.result()
+    }
+}
+
+/// Mock I2C bus checking transactions against a list of expected [`Transaction`]s.
+///
+/// Clones share the same expectations.
+#[derive(Clone)]
+pub struct MockI2c {
+    expectations: Expectations<Transaction>,
+}
+
+impl MockI2c {
+    /// Create a new mock expecting `expectations`, in order.
+    pub fn new(expectations: &[Transaction]) -> Self {
+        Self {
+            expectations: Expectations::new(expectations),
+        }
+    }
+
+    /// Append more expectations to the script.
+    pub fn update_expectations(&mut self, expectations: &[Transaction]) {
+        self.expectations.extend(expectations);
+    }
+
+    /// Assert that all expectations have been consumed.
+    pub fn done(&mut self) {
+        self.expectations.done();
+    }
+}
+
+impl ErrorType for MockI2c {
+    type Error = ErrorKind;
+}
+
+impl i2c::I2c for MockI2c {
+    fn transaction(&mut self, address: u8, operations: &mut [i2c::Operation<'_>]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("transaction");
+        t.injected.block();
+        t.complete(address, operations)
+    }
+}
+
+impl embedded_hal_async::i2c::I2c for MockI2c {
+    async fn transaction(&mut self, address: u8, operations: &mut [i2c::Operation<'_>]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("transaction");
+        t.injected.delay().await;
+        t.complete(address, operations)
+    }
+}
+
+/// I2C bus wrapper recording every transaction performed on the inner bus.
+///
+/// The recorded trace can be fed to [`MockI2c::new`] to replay it in a test.
+pub struct Recorder<T> {
+    inner: T,
+    transactions: Vec<Transaction>,
+}
+
+impl<T> Recorder<T> {
+    /// Create a new recorder wrapping `inner`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            transactions: Vec::new(),
+        }
+    }
+
+    /// Transactions recorded so far.
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// Clear the recorded transactions.
+    pub fn clear(&mut self) {
+        self.transactions.clear();
+    }
+
+    /// Release the inner bus.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record<E: Error>(
+        &mut self,
+        address: u8,
+        operations: &[i2c::Operation<'_>],
+        res: Result<(), E>,
+    ) -> Result<(), E> {
+        let operations: Vec<_> = operations
+            .iter()
+            .map(|op| match op {
+                i2c::Operation::Read(buf) => Operation::Read(buf.to_vec()),
+                i2c::Operation::Write(buf) => Operation::Write(buf.to_vec()),
+            })
+            .collect();
+        let mut t = Transaction::new(address, &operations);
+        if let Err(e) = &res {
+            t = t.with_error(e.kind());
+        }
+        self.transactions.push(t);
+        res
+    }
+}
+
+impl<T: ErrorType> ErrorType for Recorder<T> {
+    type Error = T::Error;
+}
+
+impl<T: i2c::I2c> i2c::I2c for Recorder<T> {
+    fn transaction(&mut self, address: u8, operations: &mut [i2c::Operation<'_>]) -> Result<(), Self::Error> {
+        let res = self.inner.transaction(address, operations);
+        self.record(address, operations, res)
+    }
+}
+
+impl<T: embedded_hal_async::i2c::I2c> embedded_hal_async::i2c::I2c for Recorder<T> {
+    async fn transaction(&mut self, address: u8, operations: &mut [i2c::Operation<'_>]) -> Result<(), Self::Error> {
+        let res = self.inner.transaction(address, operations).await;
+        self.record(address, operations, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::mutex::Mutex;
+    use embedded_hal_async::i2c::I2c as _;
+
+    use super::*;
+    use crate::shared_bus::asynch::i2c::I2cDevice;
+    use crate::shared_bus::blocking::i2c::I2cDevice as BlockingI2cDevice;
+    use crate::shared_bus::I2cDeviceError;
+
+    #[futures_test::test]
+    async fn devices_share_bus() {
+        let bus = Mutex::<NoopRawMutex, _>::new(MockI2c::new(&[
+            Transaction::write_read(0x48, &[0x00], &[0x12, 0x34]),
+            Transaction::write(0x76, &[0xf4, 0x27]),
+            Transaction::read(0x48, &[0x56]).with_error(ErrorKind::ArbitrationLoss),
+        ]));
+
+        let mut dev1 = I2cDevice::new(&bus);
+        let mut dev2 = I2cDevice::new(&bus);
+
+        let mut temp = [0; 2];
+        dev1.write_read(0x48, &[0x00], &mut temp).await.unwrap();
+        assert_eq!(temp, [0x12, 0x34]);
+        dev2.write(0x76, &[0xf4, 0x27]).await.unwrap();
+        let res = dev1.read(0x48, &mut temp[..1]).await;
+        assert_eq!(res, Err(I2cDeviceError::I2c(ErrorKind::ArbitrationLoss)));
+
+        bus.lock().await.done();
+    }
+
+    #[test]
+    fn blocking_device_with_recorder() {
+        use embedded_hal_1::i2c::I2c;
+
+        let mock = MockI2c::new(&[Transaction::new(
+            0x50,
+            &[Operation::Write(vec![0x00, 0x10]), Operation::Read(vec![0xaa, 0xbb])],
+        )]);
+        let bus = embassy_sync::blocking_mutex::NoopMutex::new(RefCell::new(Recorder::new(mock)));
+
+        let mut dev = BlockingI2cDevice::new(&bus);
+        let mut buf = [0; 2];
+        dev.write_read(0x50, &[0x00, 0x10], &mut buf).unwrap();
+        assert_eq!(buf, [0xaa, 0xbb]);
+
+        let recorder = bus.into_inner().into_inner();
+        let mut replay = MockI2c::new(recorder.transactions());
+        I2c::write_read(&mut replay, 0x50, &[0x00, 0x10], &mut buf).unwrap();
+        replay.done();
+        recorder.into_inner().done();
+    }
+
+    #[futures_test::test]
+    #[should_panic(expected = "address mismatch")]
+    async fn wrong_address_panics() {
+        let mut i2c = MockI2c::new(&[Transaction::write(0x10, &[0x01])]);
+        let _ = i2c.write(0x11, &[0x01]).await;
+    }
+}
@@ -0,0 +1,116 @@
+//! Mock peripherals for testing drivers on the host.
+//!
+//! The mocks in this module implement the blocking `embedded-hal` and the `embedded-hal-async`
+//! traits, and check every operation against a script of expected transactions. Each expected
+//! transaction can additionally inject an error, or (with the `time` feature) a delay before the
+//! operation completes.
+//!
+//! Mocks are cheap handles to shared state: cloning one yields another handle to the same script.
+//! This allows a test to hand a mock to the code under test, including the shared bus wrappers in
+//! [`shared_bus`](crate::shared_bus), and still call `done()` afterwards to assert that every
+//! expectation was met.
+//!
+//! [`spi::Recorder`] and [`i2c::Recorder`] wrap a real bus and capture the transactions it
+//! performs, so that traces taken on hardware can be replayed in tests.
+//!
+//! # Example
+//!
+//! ```rust
+//! use embassy_embedded_hal::mock::spi::{MockSpi, Transaction};
+//! use embedded_hal_1::spi::SpiBus;
+//!
+//! let mut spi = MockSpi::new(&[Transaction::write(&[0x9f]), Transaction::read(&[0xef, 0x40])]);
+//!
+//! let mut id = [0; 2];
+//! spi.write(&[0x9f]).unwrap();
+//! spi.read(&mut id).unwrap();
+//! assert_eq!(id, [0xef, 0x40]);
+//!
+//! spi.done();
+//! ```
+
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt::Debug;
+
+pub mod digital;
+pub mod i2c;
+pub mod spi;
+
+/// Queue of expected transactions, shared between clones of a mock.
+pub(crate) struct Expectations<T> {
+    queue: Rc<RefCell<VecDeque<T>>>,
+}
+
+impl<T: Clone + Debug> Expectations<T> {
+    pub(crate) fn new(expectations: &[T]) -> Self {
+        Self {
+            queue: Rc::new(RefCell::new(expectations.iter().cloned().collect())),
+        }
+    }
+
+    pub(crate) fn extend(&self, expectations: &[T]) {
+        self.queue.borrow_mut().extend(expectations.iter().cloned());
+    }
+
+    /// Pop the next expectation, panicking if the script is exhausted.
+    pub(crate) fn next(&self, operation: &str) -> T {
+        match self.queue.borrow_mut().pop_front() {
+            Some(t) => t,
+            None => panic!("unexpected {operation}: no more expectations"),
+        }
+    }
+
+    pub(crate) fn done(&self) {
+        let queue = self.queue.borrow();
+        assert!(queue.is_empty(), "expectations not met: {queue:?}");
+    }
+}
+
+impl<T> Clone for Expectations<T> {
+    fn clone(&self) -> Self {
+        Self {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+/// Error and delay injected into an expected transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Injected<E> {
+    pub(crate) error: Option<E>,
+    #[cfg(feature = "time")]
+    pub(crate) delay: Option<embassy_time::Duration>,
+}
+
+impl<E: Copy> Injected<E> {
+    pub(crate) const fn none() -> Self {
+        Self {
+            error: None,
+            #[cfg(feature = "time")]
+            delay: None,
+        }
+    }
+
+    pub(crate) fn result(&self) -> Result<(), E> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) async fn delay(&self) {
+        #[cfg(feature = "time")]
+        if let Some(delay) = self.delay {
+            embassy_time::Timer::after(delay).await;
+        }
+    }
+
+    pub(crate) fn block(&self) {
+        #[cfg(feature = "time")]
+        if let Some(delay) = self.delay {
+            embassy_time::block_for(delay);
+        }
+    }
+}
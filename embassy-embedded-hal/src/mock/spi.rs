@@ -0,0 +1,442 @@
+//! Mock SPI bus
+//!
+//! [`MockSpi`] implements both the blocking and async `SpiBus` traits for 8-bit words. Every call
+//! consumes one [`Transaction`] and panics if it does not match what the driver did.
+//!
+//! # Example
+//!
+//! Testing a driver through the shared bus wrappers:
+//!
+//! ```rust
+//! # embassy_futures::block_on(async {
+//! use embassy_embedded_hal::mock::digital::{MockPin, Transaction as PinTransaction};
+//! use embassy_embedded_hal::mock::spi::{MockSpi, Transaction};
+//! use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+//! use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+//! use embassy_sync::mutex::Mutex;
+//! use embedded_hal_1::digital::PinState;
+//! use embedded_hal_async::spi::SpiDevice as _;
+//!
+//! let bus = Mutex::<NoopRawMutex, _>::new(MockSpi::new(&[Transaction::write(&[0x06]), Transaction::flush()]));
+//! let mut cs = MockPin::new(&[PinTransaction::set(PinState::Low), PinTransaction::set(PinState::High)]);
+//!
+//! let mut device = SpiDevice::new(&bus, cs.clone());
+//! device.write(&[0x06]).await.unwrap();
+//!
+//! bus.lock().await.done();
+//! cs.done();
+//! # });
+//! ```
+
+use alloc::vec::Vec;
+
+use embedded_hal_1::spi::{Error, ErrorKind, ErrorType};
+
+use super::{Expectations, Injected};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Read(Vec<u8>),
+    Write(Vec<u8>),
+    Transfer(Vec<u8>, Vec<u8>),
+    TransferInPlace(Vec<u8>, Vec<u8>),
+    Flush,
+}
+
+/// An expected SPI bus operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    kind: Kind,
+    injected: Injected<ErrorKind>,
+}
+
+impl Transaction {
+    fn new(kind: Kind) -> Self {
+        Self {
+            kind,
+            injected: Injected::none(),
+        }
+    }
+
+    /// Expect a read, returning `data` to the caller.
+    pub fn read(data: &[u8]) -> Self {
+        Self::new(Kind::Read(data.into()))
+    }
+
+    /// Expect a write of exactly `data`.
+    pub fn write(data: &[u8]) -> Self {
+        Self::new(Kind::Write(data.into()))
+    }
+
+    /// Expect a transfer writing exactly `write`, returning `read` to the caller.
+    pub fn transfer(write: &[u8], read: &[u8]) -> Self {
+        Self::new(Kind::Transfer(write.into(), read.into()))
+    }
+
+    /// Expect an in-place transfer of exactly `write`, returning `read` in the same buffer.
+    pub fn transfer_in_place(write: &[u8], read: &[u8]) -> Self {
+        assert_eq!(write.len(), read.len(), "in-place transfers must have equal lengths");
+        Self::new(Kind::TransferInPlace(write.into(), read.into()))
+    }
+
+    /// Expect a flush.
+    pub fn flush() -> Self {
+        Self::new(Kind::Flush)
+    }
+
+    /// Make the operation fail with `error` once it has been checked.
+    pub fn with_error(mut self, error: ErrorKind) -> Self {
+        self.injected.error = Some(error);
+        self
+    }
+
+    /// Delay completion of the operation by `delay`.
+    ///
+    /// Async operations wait on a [`Timer`](embassy_time::Timer), blocking operations busy-wait.
+    #[cfg(feature = "time")]
+    pub fn with_delay(mut self, delay: embassy_time::Duration) -> Self {
+        self.injected.delay = Some(delay);
+        self
+    }
+
+    fn complete_read(self, buf: &mut [u8]) -> Result<(), ErrorKind> {
+        match self.kind {
+            Kind::Read(data) => {
+                assert_eq!(buf.len(), data.len(), "read length mismatch");
+                buf.copy_from_slice(&data);
+            }
+            other => panic!("expected {:?}, got read of {} bytes", other, buf.len()),
+        }
+        self.injected.result()
+    }
+
+    fn complete_write(self, buf: &[u8]) -> Result<(), ErrorKind> {
+        match self.kind {
+            Kind::Write(data) => assert_eq!(buf, &data[..], "write data mismatch"),
+            other => panic!("expected {:?}, got write of {:02x?}", other, buf),
+        }
+        self.injected.result()
+    }
+
+    fn complete_transfer(self, read: &mut [u8], write: &[u8]) -> Result<(), ErrorKind> {
+        match self.kind {
+            Kind::Transfer(w, r) => {
+                assert_eq!(write, &w[..], "transfer write data mismatch");
+                assert_eq!(read.len(), r.len(), "transfer read length mismatch");
+                read.copy_from_slice(&r);
+            }
+            other => panic!("expected {:?}, got transfer of {:02x?}", other, write),
+        }
+        self.injected.result()
+    }
+
+    fn complete_transfer_in_place(self, buf: &mut [u8]) -> Result<(), ErrorKind> {
+        match self.kind {
+            Kind::TransferInPlace(w, r) => {
+                assert_eq!(buf, &w[..], "transfer write data mismatch");
+                buf.copy_from_slice(&r);
+            }
+            other => panic!("expected {:?}, got in-place transfer of {:02x?}", other, buf),
+        }
+        self.injected.result()
+    }
+
+    fn complete_flush(self) -> Result<(), ErrorKind> {
+        match self.kind {
+            Kind::Flush => {}
+            other => panic!("expected {:?}, got flush", other),
+        }
+        self.injected.result()
+    }
+}
+
+/// Mock SPI bus checking operations against a list of expected [`Transaction`]s.
+///
+/// Clones share the same expectations.
+#[derive(Clone)]
+pub struct MockSpi {
+    expectations: Expectations<Transaction>,
+}
+
+impl MockSpi {
+    /// Create a new mock expecting `expectations`, in order.
+    pub fn new(expectations: &[Transaction]) -> Self {
+        Self {
+            expectations: Expectations::new(expectations),
+        }
+    }
+
+    /// Append more expectations to the script.
+    pub fn update_expectations(&mut self, expectations: &[Transaction]) {
+        self.expectations.extend(expectations);
+    }
+
+    /// Assert that all expectations have been consumed.
+    pub fn done(&mut self) {
+        self.expectations.done();
+    }
+}
+
+impl ErrorType for MockSpi {
+    type Error = ErrorKind;
+}
+
+impl embedded_hal_1::spi::SpiBus for MockSpi {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("read");
+        t.injected.block();
+        t.complete_read(words)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("write");
+        t.injected.block();
+        t.complete_write(words)
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("transfer");
+        t.injected.block();
+        t.complete_transfer(read, write)
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("in-place transfer");
+        t.injected.block();
+        t.complete_transfer_in_place(words)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        let t = self.expectations.next("flush");
+        t.injected.block();
+        t.complete_flush()
+    }
+}
+
+impl embedded_hal_async::spi::SpiBus for MockSpi {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("read");
+        t.injected.delay().await;
+        t.complete_read(words)
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("write");
+        t.injected.delay().await;
+        t.complete_write(words)
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("transfer");
+        t.injected.delay().await;
+        t.complete_transfer(read, write)
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let t = self.expectations.next("in-place transfer");
+        t.injected.delay().await;
+        t.complete_transfer_in_place(words)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        let t = self.expectations.next("flush");
+        t.injected.delay().await;
+        t.complete_flush()
+    }
+}
+
+/// SPI bus wrapper recording every operation performed on the inner bus.
+///
+/// The recorded trace can be fed to [`MockSpi::new`] to replay it in a test.
+pub struct Recorder<T> {
+    inner: T,
+    transactions: Vec<Transaction>,
+}
+
+impl<T> Recorder<T> {
+    /// Create a new recorder wrapping `inner`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            transactions: Vec::new(),
+        }
+    }
+
+    /// Transactions recorded so far.
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// Clear the recorded transactions.
+    pub fn clear(&mut self) {
+        self.transactions.clear();
+    }
+
+    /// Release the inner bus.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record<E: Error>(&mut self, kind: Kind, res: Result<(), E>) -> Result<(), E> {
+        let mut t = Transaction::new(kind);
+        if let Err(e) = &res {
+            t = t.with_error(e.kind());
+        }
+        self.transactions.push(t);
+        res
+    }
+}
+
+impl<T: ErrorType> ErrorType for Recorder<T> {
+    type Error = T::Error;
+}
+
+impl<T: embedded_hal_1::spi::SpiBus> embedded_hal_1::spi::SpiBus for Recorder<T> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let res = self.inner.read(words);
+        self.record(Kind::Read(words.into()), res)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let res = self.inner.write(words);
+        self.record(Kind::Write(words.into()), res)
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let res = self.inner.transfer(read, write);
+        self.record(Kind::Transfer(write.into(), read.into()), res)
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let write = words.into();
+        let res = self.inner.transfer_in_place(words);
+        self.record(Kind::TransferInPlace(write, words.into()), res)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        let res = self.inner.flush();
+        self.record(Kind::Flush, res)
+    }
+}
+
+impl<T: embedded_hal_async::spi::SpiBus> embedded_hal_async::spi::SpiBus for Recorder<T> {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let res = self.inner.read(words).await;
+        self.record(Kind::Read(words.into()), res)
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let res = self.inner.write(words).await;
+        self.record(Kind::Write(words.into()), res)
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let res = self.inner.transfer(read, write).await;
+        self.record(Kind::Transfer(write.into(), read.into()), res)
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let write = words.into();
+        let res = self.inner.transfer_in_place(words).await;
+        self.record(Kind::TransferInPlace(write, words.into()), res)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        let res = self.inner.flush().await;
+        self.record(Kind::Flush, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::mutex::Mutex;
+    use embedded_hal_1::digital::PinState;
+    use embedded_hal_async::spi::{SpiBus, SpiDevice as _};
+
+    use super::*;
+    use crate::mock::digital::{self, MockPin};
+    use crate::shared_bus::asynch::spi::SpiDevice;
+    use crate::shared_bus::SpiDeviceError;
+
+    fn cs_cycle() -> [digital::Transaction; 2] {
+        [
+            digital::Transaction::set(PinState::Low),
+            digital::Transaction::set(PinState::High),
+        ]
+    }
+
+    #[futures_test::test]
+    async fn devices_share_bus() {
+        let bus = Mutex::<NoopRawMutex, _>::new(MockSpi::new(&[
+            Transaction::transfer(&[0x9f, 0x00], &[0x00, 0xef]),
+            Transaction::flush(),
+            Transaction::write(&[0x06]),
+            Transaction::flush(),
+        ]));
+        let mut cs1 = MockPin::new(&cs_cycle());
+        let mut cs2 = MockPin::new(&cs_cycle());
+
+        let mut dev1 = SpiDevice::new(&bus, cs1.clone());
+        let mut dev2 = SpiDevice::new(&bus, cs2.clone());
+
+        let mut id = [0; 2];
+        dev1.transfer(&mut id, &[0x9f, 0x00]).await.unwrap();
+        assert_eq!(id, [0x00, 0xef]);
+        dev2.write(&[0x06]).await.unwrap();
+
+        bus.lock().await.done();
+        cs1.done();
+        cs2.done();
+    }
+
+    #[futures_test::test]
+    async fn device_releases_cs_on_error() {
+        let bus = Mutex::<NoopRawMutex, _>::new(MockSpi::new(&[
+            Transaction::write(&[0x01]).with_error(ErrorKind::Overrun),
+            Transaction::flush(),
+        ]));
+        let mut cs = MockPin::new(&cs_cycle());
+
+        let mut dev = SpiDevice::new(&bus, cs.clone());
+        let res = dev.write(&[0x01]).await;
+        assert_eq!(res, Err(SpiDeviceError::Spi(ErrorKind::Overrun)));
+
+        bus.lock().await.done();
+        cs.done();
+    }
+
+    #[futures_test::test]
+    async fn recorded_trace_replays() {
+        let mut recorder = Recorder::new(MockSpi::new(&[
+            Transaction::transfer_in_place(&[0x05, 0x00], &[0xff, 0x02]),
+            Transaction::read(&[0x12]).with_error(ErrorKind::ModeFault),
+        ]));
+
+        let mut buf = [0x05, 0x00];
+        recorder.transfer_in_place(&mut buf).await.unwrap();
+        let mut byte = [0x12];
+        recorder.read(&mut byte).await.unwrap_err();
+
+        let mut replay = MockSpi::new(recorder.transactions());
+        let mut buf = [0x05, 0x00];
+        replay.transfer_in_place(&mut buf).await.unwrap();
+        assert_eq!(buf, [0xff, 0x02]);
+        assert_eq!(replay.read(&mut byte).await, Err(ErrorKind::ModeFault));
+        replay.done();
+    }
+
+    #[futures_test::test]
+    #[should_panic(expected = "write data mismatch")]
+    async fn wrong_write_panics() {
+        let mut spi = MockSpi::new(&[Transaction::write(&[0x01])]);
+        let _ = spi.write(&[0x02]).await;
+    }
+
+    #[test]
+    #[should_panic(expected = "expectations not met")]
+    fn unmet_expectations_panic() {
+        let mut spi = MockSpi::new(&[Transaction::flush()]);
+        spi.done();
+    }
+}
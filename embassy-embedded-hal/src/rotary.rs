@@ -0,0 +1,263 @@
+//! Debounced quadrature rotary encoder input.
+//!
+//! [`Rotary`] decodes the two pins of an incremental rotary encoder, each implementing
+//! [`InputPin`] and async [`Wait`], into [`Direction`] steps. Both pins are debounced with
+//! [`Config::debounce`], and the Gray code sequence of the encoder makes any remaining bounce on
+//! one pin cancel out.
+//!
+//! # Example (stm32)
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::rotary::{Config, Direction, Rotary};
+//! use embassy_stm32::exti::ExtiInput;
+//! use embassy_stm32::gpio::Pull;
+//!
+//! let a = ExtiInput::new(p.PA0, p.EXTI0, Pull::Up);
+//! let b = ExtiInput::new(p.PA1, p.EXTI1, Pull::Up);
+//! let mut rotary = Rotary::new(a, b, Config::default());
+//!
+//! let mut volume = 0i32;
+//! loop {
+//!     match rotary.next().await? {
+//!         Direction::Clockwise => volume += 1,
+//!         Direction::CounterClockwise => volume -= 1,
+//!     }
+//! }
+//! ```
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+use embedded_hal_1::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+
+/// Rotary encoder configuration.
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// How long both pins must be stable before their levels are decoded.
+    ///
+    /// Must be shorter than the time between two transitions at the fastest rotation, or steps
+    /// are lost.
+    pub debounce: Duration,
+    /// Number of transitions of the quadrature signals per reported step: 4 for encoders with
+    /// one detent per full cycle, 2 for one detent per half cycle, 1 for every transition.
+    pub transitions_per_step: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(1),
+            transitions_per_step: 4,
+        }
+    }
+}
+
+/// Rotation direction of a step.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// Pin A leads pin B.
+    Clockwise,
+    /// Pin B leads pin A.
+    CounterClockwise,
+}
+
+/// Next state of the `(A, B)` levels, as bits 1 and 0, when turning clockwise.
+const fn clockwise(state: u8) -> u8 {
+    match state {
+        0b00 => 0b10,
+        0b10 => 0b11,
+        0b11 => 0b01,
+        _ => 0b00,
+    }
+}
+
+/// Debounced rotary encoder.
+pub struct Rotary<A, B> {
+    a: A,
+    b: B,
+    config: Config,
+    state: Option<u8>,
+    count: i8,
+}
+
+impl<A, B> Rotary<A, B>
+where
+    A: InputPin + Wait,
+    B: InputPin<Error = A::Error> + Wait<Error = A::Error>,
+{
+    /// Create a new rotary encoder from its A and B pins.
+    ///
+    /// The position when [`next`](Self::next) is first called is the starting position.
+    pub fn new(a: A, b: B, config: Config) -> Self {
+        Self {
+            a,
+            b,
+            config,
+            state: None,
+            count: 0,
+        }
+    }
+
+    /// Release the pins.
+    pub fn release(self) -> (A, B) {
+        (self.a, self.b)
+    }
+
+    /// Change the configuration. Discards a partial step.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+        self.count = 0;
+    }
+
+    /// Wait for the next step.
+    ///
+    /// This is cancel safe, except that a debounce in progress is restarted on the next call.
+    pub async fn next(&mut self) -> Result<Direction, A::Error> {
+        let mut state = match self.state {
+            Some(state) => state,
+            None => self.read()?,
+        };
+        self.state = Some(state);
+        let steps = self.config.transitions_per_step.clamp(1, 4) as i8;
+
+        loop {
+            let a_high = state & 0b10 != 0;
+            let b_high = state & 0b01 != 0;
+            let a = async {
+                match a_high {
+                    true => self.a.wait_for_low().await,
+                    false => self.a.wait_for_high().await,
+                }
+            };
+            let b = async {
+                match b_high {
+                    true => self.b.wait_for_low().await,
+                    false => self.b.wait_for_high().await,
+                }
+            };
+            match select(a, b).await {
+                Either::First(res) | Either::Second(res) => res?,
+            }
+
+            // Restart the debounce period on every edge.
+            while let Either::First(res) = select(
+                select(self.a.wait_for_any_edge(), self.b.wait_for_any_edge()),
+                Timer::after(self.config.debounce),
+            )
+            .await
+            {
+                match res {
+                    Either::First(res) | Either::Second(res) => res?,
+                }
+            }
+
+            let next = self.read()?;
+            if next == clockwise(state) {
+                self.count += 1;
+            } else if state == clockwise(next) {
+                self.count -= 1;
+            }
+            // Both pins changing at once skips a state, its direction is unknown.
+            state = next;
+            self.state = Some(state);
+
+            if self.count >= steps {
+                self.count = 0;
+                return Ok(Direction::Clockwise);
+            }
+            if self.count <= -steps {
+                self.count = 0;
+                return Ok(Direction::CounterClockwise);
+            }
+        }
+    }
+
+    fn read(&mut self) -> Result<u8, A::Error> {
+        Ok((self.a.is_high()? as u8) << 1 | self.b.is_high()? as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embedded_hal_1::digital::{OutputPin, PinState};
+
+    use super::*;
+    use crate::bitbang::sim::{run, Loopback};
+
+    /// Drive the encoder through `script` of `(a, b, microseconds)` steps and collect its steps.
+    fn steps(config: Config, script: &[(bool, bool, u64)]) -> Vec<Direction> {
+        let (mut out_a, a) = Loopback::new();
+        let (mut out_b, b) = Loopback::new();
+        let mut rotary = Rotary::new(a, b, config);
+        let mut steps = Vec::new();
+
+        run(select(
+            async {
+                // Let the encoder read its starting position.
+                Timer::after_millis(1).await;
+                for &(a, b, us) in script {
+                    out_a.set_state(PinState::from(a)).unwrap();
+                    out_b.set_state(PinState::from(b)).unwrap();
+                    Timer::after_micros(us).await;
+                }
+            },
+            async {
+                loop {
+                    steps.push(rotary.next().await.unwrap());
+                }
+            },
+        ));
+        steps
+    }
+
+    const CLOCKWISE: [(bool, bool, u64); 4] = [
+        (false, true, 5000),
+        (false, false, 5000),
+        (true, false, 5000),
+        (true, true, 5000),
+    ];
+
+    #[test]
+    fn full_cycle_steps() {
+        let mut script = Vec::new();
+        script.extend_from_slice(&CLOCKWISE);
+        script.extend_from_slice(&CLOCKWISE);
+        script.extend(CLOCKWISE.iter().rev().skip(1).copied());
+        script.push((true, true, 5000));
+        assert_eq!(
+            steps(Config::default(), &script),
+            [Direction::Clockwise, Direction::Clockwise, Direction::CounterClockwise]
+        );
+    }
+
+    #[test]
+    fn bounce_is_ignored() {
+        // Pin A bounces while leaving the detent, then the rotation goes back.
+        let script = [
+            (false, true, 100),
+            (true, true, 100),
+            (false, true, 5000),
+            (true, true, 5000),
+            (false, true, 5000),
+            (false, false, 5000),
+            (false, true, 5000),
+            (true, true, 5000),
+        ];
+        assert_eq!(steps(Config::default(), &script), []);
+    }
+
+    #[test]
+    fn half_cycle_steps() {
+        let config = Config {
+            transitions_per_step: 2,
+            ..Default::default()
+        };
+        assert_eq!(steps(config, &CLOCKWISE), [Direction::Clockwise, Direction::Clockwise]);
+    }
+}
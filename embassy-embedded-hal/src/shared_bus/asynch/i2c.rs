@@ -21,48 +21,171 @@
 //! let i2c_dev2 = I2cDevice::new(i2c_bus);
 //! let mpu = Mpu6050::new(i2c_dev2);
 //! ```
+//!
+//! # Timeouts and bus recovery
+//!
+//! With the `time` feature, each device can be given a transaction timeout with
+//! [`I2cDevice::set_timeout`]. A device can also be given an [`I2cBusRecovery`] hook with
+//! [`I2cDevice::with_recovery`], which is run with the bus locked whenever a transaction fails
+//! in a way that suggests the bus is stuck.
+//!
+//! ```rust,ignore
+//! use embassy_embedded_hal::shared_bus::recover_i2c_bus;
+//!
+//! let mut i2c_dev1 = I2cDevice::new(i2c_bus).with_recovery(|bus: &mut Twim<TWISPI0>| {
+//!     // Reclaim the pins as GPIOs while the bus is locked, clock out the stuck target,
+//!     // then reinitialize the peripheral.
+//!     let mut scl = Flex::new(unsafe { P0_03::steal() });
+//!     let mut sda = Flex::new(unsafe { P0_04::steal() });
+//!     scl.set_as_input_output(Pull::Up, OutputDrive::Standard0Disconnect1);
+//!     sda.set_as_input_output(Pull::Up, OutputDrive::Standard0Disconnect1);
+//!     let recovered = recover_i2c_bus(&mut scl, &mut sda, &mut Delay);
+//!     drop((scl, sda));
+//!     *bus = Twim::new(unsafe { TWISPI0::steal() }, Irqs, unsafe { P0_03::steal() }, unsafe { P0_04::steal() }, config);
+//!     recovered
+//! });
+//! i2c_dev1.set_timeout(Some(Duration::from_millis(10)));
+//!
+//! let found = i2c_dev1.scan().await?;
+//! ```
 
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::mutex::Mutex;
 use embedded_hal_async::i2c;
 
-use crate::shared_bus::I2cDeviceError;
+use crate::shared_bus::{I2cAddressSet, I2cBusRecovery, I2cDeviceError, I2cErrorCounters, I2cSupervisor, NoRecovery};
 use crate::SetConfig;
 
+/// Run `op` on the bus, applying the device's timeout if any.
+async fn run<BUS: i2c::ErrorType>(
+    bus: &mut BUS,
+    #[cfg(feature = "time")] timeout: Option<embassy_time::Duration>,
+    op: impl AsyncFnOnce(&mut BUS) -> Result<(), BUS::Error>,
+) -> Result<(), I2cDeviceError<BUS::Error>> {
+    #[cfg(feature = "time")]
+    if let Some(timeout) = timeout {
+        return match embassy_time::with_timeout(timeout, op(bus)).await {
+            Ok(res) => res.map_err(I2cDeviceError::I2c),
+            Err(_) => Err(I2cDeviceError::Timeout),
+        };
+    }
+    op(bus).await.map_err(I2cDeviceError::I2c)
+}
+
 /// I2C device on a shared bus.
-pub struct I2cDevice<'a, M: RawMutex, BUS> {
+pub struct I2cDevice<'a, M: RawMutex, BUS, R = NoRecovery> {
     bus: &'a Mutex<M, BUS>,
+    supervisor: I2cSupervisor<R>,
+    #[cfg(feature = "time")]
+    timeout: Option<embassy_time::Duration>,
 }
 
 impl<'a, M: RawMutex, BUS> I2cDevice<'a, M, BUS> {
     /// Create a new `I2cDevice`.
     pub fn new(bus: &'a Mutex<M, BUS>) -> Self {
-        Self { bus }
+        Self {
+            bus,
+            supervisor: I2cSupervisor::new(NoRecovery),
+            #[cfg(feature = "time")]
+            timeout: None,
+        }
     }
 }
 
-impl<'a, M: RawMutex, BUS> i2c::ErrorType for I2cDevice<'a, M, BUS>
+impl<'a, M: RawMutex, BUS, R> I2cDevice<'a, M, BUS, R> {
+    /// Use `recovery` to recover the bus when a transaction of this device leaves it stuck.
+    pub fn with_recovery<R2: I2cBusRecovery<BUS>>(self, recovery: R2) -> I2cDevice<'a, M, BUS, R2> {
+        I2cDevice {
+            bus: self.bus,
+            supervisor: I2cSupervisor {
+                recovery,
+                counters: self.supervisor.counters,
+            },
+            #[cfg(feature = "time")]
+            timeout: self.timeout,
+        }
+    }
+
+    /// Set the maximum duration of a transaction, or `None` to wait forever.
+    ///
+    /// The timeout does not include waiting for other devices to release the bus. When it
+    /// expires, the transaction is cancelled and fails with [`I2cDeviceError::Timeout`].
+    #[cfg(feature = "time")]
+    pub fn set_timeout(&mut self, timeout: Option<embassy_time::Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Errors encountered by this device so far.
+    pub fn error_counters(&self) -> &I2cErrorCounters {
+        &self.supervisor.counters
+    }
+
+    /// Reset the error counters of this device.
+    pub fn reset_error_counters(&mut self) {
+        self.supervisor.counters = I2cErrorCounters::default();
+    }
+}
+
+impl<M, BUS, R> I2cDevice<'_, M, BUS, R>
+where
+    M: RawMutex,
+    BUS: i2c::I2c,
+    R: I2cBusRecovery<BUS>,
+{
+    async fn execute(
+        &mut self,
+        op: impl AsyncFnOnce(&mut BUS) -> Result<(), BUS::Error>,
+    ) -> Result<(), I2cDeviceError<BUS::Error>> {
+        let mut bus = self.bus.lock().await;
+        let res = run(
+            &mut *bus,
+            #[cfg(feature = "time")]
+            self.timeout,
+            op,
+        )
+        .await;
+        self.supervisor.finish(&mut *bus, res)
+    }
+
+    /// Probe all non-reserved 7-bit addresses with an empty write, returning those that acknowledged.
+    ///
+    /// The bus is released between probes. NACKs are not counted in the error counters.
+    pub async fn scan(&mut self) -> Result<I2cAddressSet, I2cDeviceError<BUS::Error>> {
+        let mut found = I2cAddressSet::default();
+        let no_acknowledge = self.supervisor.counters.no_acknowledge;
+        for address in I2cAddressSet::SCAN_RANGE {
+            let res = self.execute(async |bus| bus.write(address, &[]).await).await;
+            if res.is_ok() {
+                found.insert(address);
+            } else if !I2cSupervisor::<R>::is_absent(&res) {
+                self.supervisor.counters.no_acknowledge = no_acknowledge;
+                res?;
+            }
+        }
+        self.supervisor.counters.no_acknowledge = no_acknowledge;
+        Ok(found)
+    }
+}
+
+impl<'a, M: RawMutex, BUS, R> i2c::ErrorType for I2cDevice<'a, M, BUS, R>
 where
     BUS: i2c::ErrorType,
 {
     type Error = I2cDeviceError<BUS::Error>;
 }
 
-impl<M, BUS> i2c::I2c for I2cDevice<'_, M, BUS>
+impl<M, BUS, R> i2c::I2c for I2cDevice<'_, M, BUS, R>
 where
     M: RawMutex,
     BUS: i2c::I2c,
+    R: I2cBusRecovery<BUS>,
 {
     async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), I2cDeviceError<BUS::Error>> {
-        let mut bus = self.bus.lock().await;
-        bus.read(address, read).await.map_err(I2cDeviceError::I2c)?;
-        Ok(())
+        self.execute(async |bus| bus.read(address, read).await).await
     }
 
     async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), I2cDeviceError<BUS::Error>> {
-        let mut bus = self.bus.lock().await;
-        bus.write(address, write).await.map_err(I2cDeviceError::I2c)?;
-        Ok(())
+        self.execute(async |bus| bus.write(address, write).await).await
     }
 
     async fn write_read(
@@ -71,11 +194,8 @@ where
         write: &[u8],
         read: &mut [u8],
     ) -> Result<(), I2cDeviceError<BUS::Error>> {
-        let mut bus = self.bus.lock().await;
-        bus.write_read(address, write, read)
+        self.execute(async |bus| bus.write_read(address, write, read).await)
             .await
-            .map_err(I2cDeviceError::I2c)?;
-        Ok(())
     }
 
     async fn transaction(
@@ -83,11 +203,8 @@ where
         address: u8,
         operations: &mut [embedded_hal_async::i2c::Operation<'_>],
     ) -> Result<(), I2cDeviceError<BUS::Error>> {
-        let mut bus = self.bus.lock().await;
-        bus.transaction(address, operations)
+        self.execute(async |bus| bus.transaction(address, operations).await)
             .await
-            .map_err(I2cDeviceError::I2c)?;
-        Ok(())
     }
 }
 
@@ -96,24 +213,113 @@ where
 /// This is like [`I2cDevice`], with an additional bus configuration that's applied
 /// to the bus before each use using [`SetConfig`]. This allows different
 /// devices on the same bus to use different communication settings.
-pub struct I2cDeviceWithConfig<'a, M: RawMutex, BUS: SetConfig> {
+pub struct I2cDeviceWithConfig<'a, M: RawMutex, BUS: SetConfig, R = NoRecovery> {
     bus: &'a Mutex<M, BUS>,
     config: BUS::Config,
+    supervisor: I2cSupervisor<R>,
+    #[cfg(feature = "time")]
+    timeout: Option<embassy_time::Duration>,
 }
 
 impl<'a, M: RawMutex, BUS: SetConfig> I2cDeviceWithConfig<'a, M, BUS> {
     /// Create a new `I2cDeviceWithConfig`.
     pub fn new(bus: &'a Mutex<M, BUS>, config: BUS::Config) -> Self {
-        Self { bus, config }
+        Self {
+            bus,
+            config,
+            supervisor: I2cSupervisor::new(NoRecovery),
+            #[cfg(feature = "time")]
+            timeout: None,
+        }
     }
+}
 
+impl<'a, M: RawMutex, BUS: SetConfig, R> I2cDeviceWithConfig<'a, M, BUS, R> {
     /// Change the device's config at runtime
     pub fn set_config(&mut self, config: BUS::Config) {
         self.config = config;
     }
+
+    /// Use `recovery` to recover the bus when a transaction of this device leaves it stuck.
+    pub fn with_recovery<R2: I2cBusRecovery<BUS>>(self, recovery: R2) -> I2cDeviceWithConfig<'a, M, BUS, R2> {
+        I2cDeviceWithConfig {
+            bus: self.bus,
+            config: self.config,
+            supervisor: I2cSupervisor {
+                recovery,
+                counters: self.supervisor.counters,
+            },
+            #[cfg(feature = "time")]
+            timeout: self.timeout,
+        }
+    }
+
+    /// Set the maximum duration of a transaction, or `None` to wait forever.
+    ///
+    /// See [`I2cDevice::set_timeout`].
+    #[cfg(feature = "time")]
+    pub fn set_timeout(&mut self, timeout: Option<embassy_time::Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Errors encountered by this device so far.
+    pub fn error_counters(&self) -> &I2cErrorCounters {
+        &self.supervisor.counters
+    }
+
+    /// Reset the error counters of this device.
+    pub fn reset_error_counters(&mut self) {
+        self.supervisor.counters = I2cErrorCounters::default();
+    }
+}
+
+impl<M, BUS, R> I2cDeviceWithConfig<'_, M, BUS, R>
+where
+    M: RawMutex,
+    BUS: i2c::I2c + SetConfig,
+    R: I2cBusRecovery<BUS>,
+{
+    async fn execute(
+        &mut self,
+        op: impl AsyncFnOnce(&mut BUS) -> Result<(), BUS::Error>,
+    ) -> Result<(), I2cDeviceError<BUS::Error>> {
+        let mut bus = self.bus.lock().await;
+        let res = match bus.set_config(&self.config) {
+            Ok(()) => {
+                run(
+                    &mut *bus,
+                    #[cfg(feature = "time")]
+                    self.timeout,
+                    op,
+                )
+                .await
+            }
+            Err(_) => Err(I2cDeviceError::Config),
+        };
+        self.supervisor.finish(&mut *bus, res)
+    }
+
+    /// Probe all non-reserved 7-bit addresses with an empty write, returning those that acknowledged.
+    ///
+    /// See [`I2cDevice::scan`].
+    pub async fn scan(&mut self) -> Result<I2cAddressSet, I2cDeviceError<BUS::Error>> {
+        let mut found = I2cAddressSet::default();
+        let no_acknowledge = self.supervisor.counters.no_acknowledge;
+        for address in I2cAddressSet::SCAN_RANGE {
+            let res = self.execute(async |bus| bus.write(address, &[]).await).await;
+            if res.is_ok() {
+                found.insert(address);
+            } else if !I2cSupervisor::<R>::is_absent(&res) {
+                self.supervisor.counters.no_acknowledge = no_acknowledge;
+                res?;
+            }
+        }
+        self.supervisor.counters.no_acknowledge = no_acknowledge;
+        Ok(found)
+    }
 }
 
-impl<'a, M, BUS> i2c::ErrorType for I2cDeviceWithConfig<'a, M, BUS>
+impl<'a, M, BUS, R> i2c::ErrorType for I2cDeviceWithConfig<'a, M, BUS, R>
 where
     BUS: i2c::ErrorType,
     M: RawMutex,
@@ -122,23 +328,18 @@ where
     type Error = I2cDeviceError<BUS::Error>;
 }
 
-impl<M, BUS> i2c::I2c for I2cDeviceWithConfig<'_, M, BUS>
+impl<M, BUS, R> i2c::I2c for I2cDeviceWithConfig<'_, M, BUS, R>
 where
     M: RawMutex,
     BUS: i2c::I2c + SetConfig,
+    R: I2cBusRecovery<BUS>,
 {
     async fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cDeviceError<BUS::Error>> {
-        let mut bus = self.bus.lock().await;
-        bus.set_config(&self.config).map_err(|_| I2cDeviceError::Config)?;
-        bus.read(address, buffer).await.map_err(I2cDeviceError::I2c)?;
-        Ok(())
+        self.execute(async |bus| bus.read(address, buffer).await).await
     }
 
     async fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cDeviceError<BUS::Error>> {
-        let mut bus = self.bus.lock().await;
-        bus.set_config(&self.config).map_err(|_| I2cDeviceError::Config)?;
-        bus.write(address, bytes).await.map_err(I2cDeviceError::I2c)?;
-        Ok(())
+        self.execute(async |bus| bus.write(address, bytes).await).await
     }
 
     async fn write_read(
@@ -147,20 +348,88 @@ where
         wr_buffer: &[u8],
         rd_buffer: &mut [u8],
     ) -> Result<(), I2cDeviceError<BUS::Error>> {
-        let mut bus = self.bus.lock().await;
-        bus.set_config(&self.config).map_err(|_| I2cDeviceError::Config)?;
-        bus.write_read(address, wr_buffer, rd_buffer)
+        self.execute(async |bus| bus.write_read(address, wr_buffer, rd_buffer).await)
             .await
-            .map_err(I2cDeviceError::I2c)?;
-        Ok(())
     }
 
     async fn transaction(&mut self, address: u8, operations: &mut [i2c::Operation<'_>]) -> Result<(), Self::Error> {
-        let mut bus = self.bus.lock().await;
-        bus.set_config(&self.config).map_err(|_| I2cDeviceError::Config)?;
-        bus.transaction(address, operations)
+        self.execute(async |bus| bus.transaction(address, operations).await)
             .await
-            .map_err(I2cDeviceError::I2c)?;
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embedded_hal_async::i2c::{ErrorKind, I2c, Operation};
+
+    use super::*;
+
+    struct FakeBus {
+        stuck: bool,
+        hang: bool,
+    }
+
+    impl i2c::ErrorType for FakeBus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for FakeBus {
+        async fn transaction(&mut self, _address: u8, _operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+            if self.hang {
+                core::future::pending().await
+            } else if self.stuck {
+                Err(ErrorKind::ArbitrationLoss)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[futures_test::test]
+    async fn stuck_bus_is_recovered() {
+        let bus = Mutex::<NoopRawMutex, _>::new(FakeBus {
+            stuck: true,
+            hang: false,
+        });
+        let mut dev = I2cDevice::new(&bus).with_recovery(|bus: &mut FakeBus| {
+            bus.stuck = false;
+            true
+        });
+        let mut other = I2cDevice::new(&bus);
+
+        assert_eq!(
+            dev.write(0x48, &[0x00]).await,
+            Err(I2cDeviceError::I2c(ErrorKind::ArbitrationLoss))
+        );
+        other.write(0x20, &[0x00]).await.unwrap();
+
+        assert_eq!(dev.error_counters().arbitration_loss, 1);
+        assert_eq!(dev.error_counters().recoveries, 1);
+        assert_eq!(other.error_counters(), &I2cErrorCounters::default());
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn hung_transaction_times_out() {
+        use embassy_time::Duration;
+
+        let bus = Mutex::<NoopRawMutex, _>::new(FakeBus {
+            stuck: false,
+            hang: true,
+        });
+        let mut dev = I2cDevice::new(&bus).with_recovery(|bus: &mut FakeBus| {
+            bus.hang = false;
+            true
+        });
+        dev.set_timeout(Some(Duration::from_millis(1)));
+
+        let res = crate::bitbang::sim::run(dev.write(0x48, &[0x00]));
+        assert_eq!(res, Err(I2cDeviceError::Timeout));
+        assert_eq!(dev.error_counters().timeout, 1);
+        assert_eq!(dev.error_counters().recoveries, 1);
+
+        let found = crate::bitbang::sim::run(dev.scan()).unwrap();
+        assert_eq!(found.len(), 0x78 - 0x08);
     }
 }
@@ -14,6 +14,10 @@
 //! let i2c_dev1 = I2cDevice::new(i2c_bus);
 //! let mpu = Mpu6050::new(i2c_dev1);
 //! ```
+//!
+//! Devices can be given an [`I2cBusRecovery`] hook with [`I2cDevice::with_recovery`], see the
+//! [async shared bus](crate::shared_bus::asynch::i2c) for details. Transaction timeouts are only
+//! available on the async shared bus, since a blocking transaction cannot be cancelled.
 
 use core::cell::RefCell;
 
@@ -21,61 +25,116 @@ use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::blocking_mutex::Mutex;
 use embedded_hal_1::i2c::{ErrorType, I2c, Operation};
 
-use crate::shared_bus::I2cDeviceError;
+use crate::shared_bus::{I2cAddressSet, I2cBusRecovery, I2cDeviceError, I2cErrorCounters, I2cSupervisor, NoRecovery};
 use crate::SetConfig;
 
 /// I2C device on a shared bus.
-pub struct I2cDevice<'a, M: RawMutex, BUS> {
+pub struct I2cDevice<'a, M: RawMutex, BUS, R = NoRecovery> {
     bus: &'a Mutex<M, RefCell<BUS>>,
+    supervisor: I2cSupervisor<R>,
 }
 
 impl<'a, M: RawMutex, BUS> I2cDevice<'a, M, BUS> {
     /// Create a new `I2cDevice`.
     pub fn new(bus: &'a Mutex<M, RefCell<BUS>>) -> Self {
-        Self { bus }
+        Self {
+            bus,
+            supervisor: I2cSupervisor::new(NoRecovery),
+        }
+    }
+}
+
+impl<'a, M: RawMutex, BUS, R> I2cDevice<'a, M, BUS, R> {
+    /// Use `recovery` to recover the bus when a transaction of this device leaves it stuck.
+    pub fn with_recovery<R2: I2cBusRecovery<BUS>>(self, recovery: R2) -> I2cDevice<'a, M, BUS, R2> {
+        I2cDevice {
+            bus: self.bus,
+            supervisor: I2cSupervisor {
+                recovery,
+                counters: self.supervisor.counters,
+            },
+        }
+    }
+
+    /// Errors encountered by this device so far.
+    pub fn error_counters(&self) -> &I2cErrorCounters {
+        &self.supervisor.counters
+    }
+
+    /// Reset the error counters of this device.
+    pub fn reset_error_counters(&mut self) {
+        self.supervisor.counters = I2cErrorCounters::default();
+    }
+}
+
+impl<M, BUS, R> I2cDevice<'_, M, BUS, R>
+where
+    M: RawMutex,
+    BUS: I2c,
+    R: I2cBusRecovery<BUS>,
+{
+    fn execute(
+        &mut self,
+        op: impl FnOnce(&mut BUS) -> Result<(), BUS::Error>,
+    ) -> Result<(), I2cDeviceError<BUS::Error>> {
+        self.bus.lock(|bus| {
+            let mut bus = bus.borrow_mut();
+            let res = op(&mut bus).map_err(I2cDeviceError::I2c);
+            self.supervisor.finish(&mut *bus, res)
+        })
+    }
+
+    /// Probe all non-reserved 7-bit addresses with an empty write, returning those that acknowledged.
+    ///
+    /// The bus is released between probes. NACKs are not counted in the error counters.
+    pub fn scan(&mut self) -> Result<I2cAddressSet, I2cDeviceError<BUS::Error>> {
+        let mut found = I2cAddressSet::default();
+        let no_acknowledge = self.supervisor.counters.no_acknowledge;
+        for address in I2cAddressSet::SCAN_RANGE {
+            let res = self.execute(|bus| bus.write(address, &[]));
+            if res.is_ok() {
+                found.insert(address);
+            } else if !I2cSupervisor::<R>::is_absent(&res) {
+                self.supervisor.counters.no_acknowledge = no_acknowledge;
+                res?;
+            }
+        }
+        self.supervisor.counters.no_acknowledge = no_acknowledge;
+        Ok(found)
     }
 }
 
-impl<'a, M: RawMutex, BUS> ErrorType for I2cDevice<'a, M, BUS>
+impl<'a, M: RawMutex, BUS, R> ErrorType for I2cDevice<'a, M, BUS, R>
 where
     BUS: ErrorType,
 {
     type Error = I2cDeviceError<BUS::Error>;
 }
 
-impl<M, BUS> I2c for I2cDevice<'_, M, BUS>
+impl<M, BUS, R> I2c for I2cDevice<'_, M, BUS, R>
 where
     M: RawMutex,
     BUS: I2c,
+    R: I2cBusRecovery<BUS>,
 {
     fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
-        self.bus
-            .lock(|bus| bus.borrow_mut().read(address, buffer).map_err(I2cDeviceError::I2c))
+        self.execute(|bus| bus.read(address, buffer))
     }
 
     fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
-        self.bus
-            .lock(|bus| bus.borrow_mut().write(address, bytes).map_err(I2cDeviceError::I2c))
+        self.execute(|bus| bus.write(address, bytes))
     }
 
     fn write_read(&mut self, address: u8, wr_buffer: &[u8], rd_buffer: &mut [u8]) -> Result<(), Self::Error> {
-        self.bus.lock(|bus| {
-            bus.borrow_mut()
-                .write_read(address, wr_buffer, rd_buffer)
-                .map_err(I2cDeviceError::I2c)
-        })
+        self.execute(|bus| bus.write_read(address, wr_buffer, rd_buffer))
     }
 
     fn transaction<'a>(&mut self, address: u8, operations: &mut [Operation<'a>]) -> Result<(), Self::Error> {
-        self.bus.lock(|bus| {
-            bus.borrow_mut()
-                .transaction(address, operations)
-                .map_err(I2cDeviceError::I2c)
-        })
+        self.execute(|bus| bus.transaction(address, operations))
     }
 }
 
-impl<'a, M, BUS, E> embedded_hal_02::blocking::i2c::Write for I2cDevice<'_, M, BUS>
+impl<'a, M, BUS, E, R> embedded_hal_02::blocking::i2c::Write for I2cDevice<'_, M, BUS, R>
 where
     M: RawMutex,
     BUS: embedded_hal_02::blocking::i2c::Write<Error = E>,
@@ -88,7 +147,7 @@ where
     }
 }
 
-impl<'a, M, BUS, E> embedded_hal_02::blocking::i2c::Read for I2cDevice<'_, M, BUS>
+impl<'a, M, BUS, E, R> embedded_hal_02::blocking::i2c::Read for I2cDevice<'_, M, BUS, R>
 where
     M: RawMutex,
     BUS: embedded_hal_02::blocking::i2c::Read<Error = E>,
@@ -101,7 +160,7 @@ where
     }
 }
 
-impl<'a, M, BUS, E> embedded_hal_02::blocking::i2c::WriteRead for I2cDevice<'_, M, BUS>
+impl<'a, M, BUS, E, R> embedded_hal_02::blocking::i2c::WriteRead for I2cDevice<'_, M, BUS, R>
 where
     M: RawMutex,
     BUS: embedded_hal_02::blocking::i2c::WriteRead<Error = E>,
@@ -122,24 +181,93 @@ where
 /// This is like [`I2cDevice`], with an additional bus configuration that's applied
 /// to the bus before each use using [`SetConfig`]. This allows different
 /// devices on the same bus to use different communication settings.
-pub struct I2cDeviceWithConfig<'a, M: RawMutex, BUS: SetConfig> {
+pub struct I2cDeviceWithConfig<'a, M: RawMutex, BUS: SetConfig, R = NoRecovery> {
     bus: &'a Mutex<M, RefCell<BUS>>,
     config: BUS::Config,
+    supervisor: I2cSupervisor<R>,
 }
 
 impl<'a, M: RawMutex, BUS: SetConfig> I2cDeviceWithConfig<'a, M, BUS> {
     /// Create a new `I2cDeviceWithConfig`.
     pub fn new(bus: &'a Mutex<M, RefCell<BUS>>, config: BUS::Config) -> Self {
-        Self { bus, config }
+        Self {
+            bus,
+            config,
+            supervisor: I2cSupervisor::new(NoRecovery),
+        }
     }
+}
 
+impl<'a, M: RawMutex, BUS: SetConfig, R> I2cDeviceWithConfig<'a, M, BUS, R> {
     /// Change the device's config at runtime
     pub fn set_config(&mut self, config: BUS::Config) {
         self.config = config;
     }
+
+    /// Use `recovery` to recover the bus when a transaction of this device leaves it stuck.
+    pub fn with_recovery<R2: I2cBusRecovery<BUS>>(self, recovery: R2) -> I2cDeviceWithConfig<'a, M, BUS, R2> {
+        I2cDeviceWithConfig {
+            bus: self.bus,
+            config: self.config,
+            supervisor: I2cSupervisor {
+                recovery,
+                counters: self.supervisor.counters,
+            },
+        }
+    }
+
+    /// Errors encountered by this device so far.
+    pub fn error_counters(&self) -> &I2cErrorCounters {
+        &self.supervisor.counters
+    }
+
+    /// Reset the error counters of this device.
+    pub fn reset_error_counters(&mut self) {
+        self.supervisor.counters = I2cErrorCounters::default();
+    }
+}
+
+impl<M, BUS, R> I2cDeviceWithConfig<'_, M, BUS, R>
+where
+    M: RawMutex,
+    BUS: I2c + SetConfig,
+    R: I2cBusRecovery<BUS>,
+{
+    fn execute(
+        &mut self,
+        op: impl FnOnce(&mut BUS) -> Result<(), BUS::Error>,
+    ) -> Result<(), I2cDeviceError<BUS::Error>> {
+        self.bus.lock(|bus| {
+            let mut bus = bus.borrow_mut();
+            let res = match bus.set_config(&self.config) {
+                Ok(()) => op(&mut bus).map_err(I2cDeviceError::I2c),
+                Err(_) => Err(I2cDeviceError::Config),
+            };
+            self.supervisor.finish(&mut *bus, res)
+        })
+    }
+
+    /// Probe all non-reserved 7-bit addresses with an empty write, returning those that acknowledged.
+    ///
+    /// See [`I2cDevice::scan`].
+    pub fn scan(&mut self) -> Result<I2cAddressSet, I2cDeviceError<BUS::Error>> {
+        let mut found = I2cAddressSet::default();
+        let no_acknowledge = self.supervisor.counters.no_acknowledge;
+        for address in I2cAddressSet::SCAN_RANGE {
+            let res = self.execute(|bus| bus.write(address, &[]));
+            if res.is_ok() {
+                found.insert(address);
+            } else if !I2cSupervisor::<R>::is_absent(&res) {
+                self.supervisor.counters.no_acknowledge = no_acknowledge;
+                res?;
+            }
+        }
+        self.supervisor.counters.no_acknowledge = no_acknowledge;
+        Ok(found)
+    }
 }
 
-impl<'a, M, BUS> ErrorType for I2cDeviceWithConfig<'a, M, BUS>
+impl<'a, M, BUS, R> ErrorType for I2cDeviceWithConfig<'a, M, BUS, R>
 where
     M: RawMutex,
     BUS: ErrorType + SetConfig,
@@ -147,41 +275,115 @@ where
     type Error = I2cDeviceError<BUS::Error>;
 }
 
-impl<M, BUS> I2c for I2cDeviceWithConfig<'_, M, BUS>
+impl<M, BUS, R> I2c for I2cDeviceWithConfig<'_, M, BUS, R>
 where
     M: RawMutex,
     BUS: I2c + SetConfig,
+    R: I2cBusRecovery<BUS>,
 {
     fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
-        self.bus.lock(|bus| {
-            let mut bus = bus.borrow_mut();
-            bus.set_config(&self.config).map_err(|_| I2cDeviceError::Config)?;
-            bus.read(address, buffer).map_err(I2cDeviceError::I2c)
-        })
+        self.execute(|bus| bus.read(address, buffer))
     }
 
     fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
-        self.bus.lock(|bus| {
-            let mut bus = bus.borrow_mut();
-            bus.set_config(&self.config).map_err(|_| I2cDeviceError::Config)?;
-            bus.write(address, bytes).map_err(I2cDeviceError::I2c)
-        })
+        self.execute(|bus| bus.write(address, bytes))
     }
 
     fn write_read(&mut self, address: u8, wr_buffer: &[u8], rd_buffer: &mut [u8]) -> Result<(), Self::Error> {
-        self.bus.lock(|bus| {
-            let mut bus = bus.borrow_mut();
-            bus.set_config(&self.config).map_err(|_| I2cDeviceError::Config)?;
-            bus.write_read(address, wr_buffer, rd_buffer)
-                .map_err(I2cDeviceError::I2c)
-        })
+        self.execute(|bus| bus.write_read(address, wr_buffer, rd_buffer))
     }
 
     fn transaction<'a>(&mut self, address: u8, operations: &mut [Operation<'a>]) -> Result<(), Self::Error> {
-        self.bus.lock(|bus| {
-            let mut bus = bus.borrow_mut();
-            bus.set_config(&self.config).map_err(|_| I2cDeviceError::Config)?;
-            bus.transaction(address, operations).map_err(I2cDeviceError::I2c)
-        })
+        self.execute(|bus| bus.transaction(address, operations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::NoopMutex;
+    use embedded_hal_1::i2c::{ErrorKind, NoAcknowledgeSource};
+
+    use super::*;
+
+    struct FakeBus {
+        present: &'static [u8],
+        stuck: bool,
+    }
+
+    impl ErrorType for FakeBus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for FakeBus {
+        fn transaction(&mut self, address: u8, _operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+            if self.stuck {
+                Err(ErrorKind::Bus)
+            } else if self.present.contains(&address) {
+                Ok(())
+            } else {
+                Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address))
+            }
+        }
+    }
+
+    #[test]
+    fn scan_finds_devices() {
+        let bus = NoopMutex::new(RefCell::new(FakeBus {
+            present: &[0x20, 0x48, 0x76],
+            stuck: false,
+        }));
+        let mut dev = I2cDevice::new(&bus);
+
+        let found = dev.scan().unwrap();
+        assert_eq!(found.len(), 3);
+        assert!(found.iter().eq([0x20, 0x48, 0x76]));
+        assert_eq!(dev.error_counters(), &I2cErrorCounters::default());
+
+        assert!(dev.write(0x21, &[0x00]).is_err());
+        assert_eq!(dev.error_counters().no_acknowledge, 1);
+    }
+
+    #[test]
+    fn stuck_bus_is_recovered() {
+        let bus = NoopMutex::new(RefCell::new(FakeBus {
+            present: &[0x48],
+            stuck: true,
+        }));
+        let mut dev = I2cDevice::new(&bus).with_recovery(|bus: &mut FakeBus| {
+            bus.stuck = false;
+            true
+        });
+
+        assert_eq!(dev.write(0x48, &[0x00]), Err(I2cDeviceError::I2c(ErrorKind::Bus)));
+        dev.write(0x48, &[0x00]).unwrap();
+
+        let counters = dev.error_counters();
+        assert_eq!(counters.bus, 1);
+        assert_eq!(counters.recoveries, 1);
+
+        dev.reset_error_counters();
+        assert_eq!(dev.error_counters(), &I2cErrorCounters::default());
+    }
+
+    #[test]
+    fn stuck_bus_fails_scan() {
+        let bus = NoopMutex::new(RefCell::new(FakeBus {
+            present: &[0x48],
+            stuck: true,
+        }));
+        let mut dev = I2cDeviceWithConfig::new(&bus, ());
+
+        assert_eq!(dev.scan(), Err(I2cDeviceError::I2c(ErrorKind::Bus)));
+        assert_eq!(dev.error_counters().bus, 1);
+        assert_eq!(dev.error_counters().recoveries, 0);
+    }
+
+    impl SetConfig for FakeBus {
+        type Config = ();
+        type ConfigError = ();
+
+        fn set_config(&mut self, _config: &Self::Config) -> Result<(), Self::ConfigError> {
+            Ok(())
+        }
     }
 }
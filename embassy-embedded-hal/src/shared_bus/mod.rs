@@ -1,6 +1,9 @@
 //! Shared bus implementations
 use core::fmt::Debug;
 
+use embedded_hal_1::delay::DelayNs;
+use embedded_hal_1::digital::{InputPin, OutputPin};
+use embedded_hal_1::i2c::ErrorKind;
 use embedded_hal_1::{i2c, spi};
 
 pub mod asynch;
@@ -14,6 +17,8 @@ pub enum I2cDeviceError<BUS> {
     I2c(BUS),
     /// Configuration of the inner I2C bus failed.
     Config,
+    /// The transaction did not complete within the device's timeout.
+    Timeout,
 }
 
 impl<BUS> i2c::Error for I2cDeviceError<BUS>
@@ -24,10 +29,211 @@ where
         match self {
             Self::I2c(e) => e.kind(),
             Self::Config => i2c::ErrorKind::Other,
+            Self::Timeout => i2c::ErrorKind::Other,
         }
     }
 }
 
+/// Per-device error counters of a shared I2C bus device.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct I2cErrorCounters {
+    /// Transactions not acknowledged by the target.
+    pub no_acknowledge: u32,
+    /// Transactions that lost arbitration.
+    pub arbitration_loss: u32,
+    /// Transactions that failed with a bus error.
+    pub bus: u32,
+    /// Transactions that timed out.
+    pub timeout: u32,
+    /// Transactions that failed for any other reason.
+    pub other: u32,
+    /// Bus recoveries performed after a failed transaction.
+    pub recoveries: u32,
+}
+
+impl I2cErrorCounters {
+    fn record<E: i2c::Error>(&mut self, error: &I2cDeviceError<E>) {
+        let counter = match error {
+            I2cDeviceError::I2c(e) => match e.kind() {
+                ErrorKind::NoAcknowledge(_) => &mut self.no_acknowledge,
+                ErrorKind::ArbitrationLoss => &mut self.arbitration_loss,
+                ErrorKind::Bus => &mut self.bus,
+                _ => &mut self.other,
+            },
+            I2cDeviceError::Timeout => &mut self.timeout,
+            I2cDeviceError::Config => &mut self.other,
+        };
+        *counter = counter.saturating_add(1);
+    }
+}
+
+/// Hook used by the shared I2C bus devices to recover a stuck bus.
+///
+/// It is called with the bus locked after a transaction failed with a bus error, lost
+/// arbitration or timed out, which usually means a target is holding SDA low. Implementations
+/// typically reclaim SCL and SDA from the peripheral as GPIOs, call [`recover_i2c_bus`], and
+/// reinitialize the peripheral.
+///
+/// It is implemented for closures taking `&mut BUS` and returning whether the bus was recovered.
+pub trait I2cBusRecovery<BUS> {
+    /// Attempt to recover the bus, returning `true` if it was released.
+    fn recover(&mut self, bus: &mut BUS) -> bool;
+}
+
+impl<BUS, F: FnMut(&mut BUS) -> bool> I2cBusRecovery<BUS> for F {
+    fn recover(&mut self, bus: &mut BUS) -> bool {
+        self(bus)
+    }
+}
+
+/// Bus recovery hook that does nothing.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct NoRecovery;
+
+impl<BUS> I2cBusRecovery<BUS> for NoRecovery {
+    fn recover(&mut self, _bus: &mut BUS) -> bool {
+        false
+    }
+}
+
+/// Release a stuck I2C bus by clocking out the target holding SDA low.
+///
+/// Following the I2C specification, this pulses SCL up to 9 times until SDA is released, then
+/// generates a STOP condition. SCL and SDA must be configured as open-drain outputs, with SDA
+/// readable. The clock runs at about 100 kHz.
+///
+/// Returns `true` if SDA was released.
+pub fn recover_i2c_bus<SCL, SDA, D>(scl: &mut SCL, sda: &mut SDA, delay: &mut D) -> bool
+where
+    SCL: OutputPin,
+    SDA: OutputPin + InputPin,
+    D: DelayNs,
+{
+    clock_out_stuck_target(scl, sda, delay).is_some()
+}
+
+fn clock_out_stuck_target<SCL, SDA, D>(scl: &mut SCL, sda: &mut SDA, delay: &mut D) -> Option<()>
+where
+    SCL: OutputPin,
+    SDA: OutputPin + InputPin,
+    D: DelayNs,
+{
+    const HALF_PERIOD_US: u32 = 5;
+
+    sda.set_high().ok()?;
+    scl.set_high().ok()?;
+    delay.delay_us(HALF_PERIOD_US);
+    for _ in 0..9 {
+        if sda.is_high().ok()? {
+            break;
+        }
+        scl.set_low().ok()?;
+        delay.delay_us(HALF_PERIOD_US);
+        scl.set_high().ok()?;
+        delay.delay_us(HALF_PERIOD_US);
+    }
+    if !sda.is_high().ok()? {
+        return None;
+    }
+
+    // STOP: SDA rising while SCL is high.
+    scl.set_low().ok()?;
+    delay.delay_us(HALF_PERIOD_US);
+    sda.set_low().ok()?;
+    delay.delay_us(HALF_PERIOD_US);
+    scl.set_high().ok()?;
+    delay.delay_us(HALF_PERIOD_US);
+    sda.set_high().ok()?;
+    delay.delay_us(HALF_PERIOD_US);
+    Some(())
+}
+
+/// Set of 7-bit addresses found by a bus scan.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct I2cAddressSet(u128);
+
+impl I2cAddressSet {
+    /// Addresses probed by a scan, excluding the reserved ones.
+    pub(crate) const SCAN_RANGE: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+    pub(crate) fn insert(&mut self, address: u8) {
+        self.0 |= 1 << address;
+    }
+
+    /// Whether a device responded at `address`.
+    pub fn contains(&self, address: u8) -> bool {
+        address < 128 && self.0 & (1 << address) != 0
+    }
+
+    /// Number of devices found.
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Whether no device was found.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Iterate over the addresses found, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..128).filter(|a| self.contains(*a))
+    }
+}
+
+/// Recovery hook and error counters of a shared I2C bus device.
+pub(crate) struct I2cSupervisor<R> {
+    pub(crate) recovery: R,
+    pub(crate) counters: I2cErrorCounters,
+}
+
+impl<R> I2cSupervisor<R> {
+    pub(crate) const fn new(recovery: R) -> Self {
+        Self {
+            recovery,
+            counters: I2cErrorCounters {
+                no_acknowledge: 0,
+                arbitration_loss: 0,
+                bus: 0,
+                timeout: 0,
+                other: 0,
+                recoveries: 0,
+            },
+        }
+    }
+
+    /// Count a failed transaction, and recover the bus if needed.
+    pub(crate) fn finish<BUS, E>(
+        &mut self,
+        bus: &mut BUS,
+        res: Result<(), I2cDeviceError<E>>,
+    ) -> Result<(), I2cDeviceError<E>>
+    where
+        R: I2cBusRecovery<BUS>,
+        E: i2c::Error,
+    {
+        if let Err(e) = &res {
+            self.counters.record(e);
+            let stuck = match e {
+                I2cDeviceError::I2c(e) => matches!(e.kind(), ErrorKind::Bus | ErrorKind::ArbitrationLoss),
+                I2cDeviceError::Timeout => true,
+                I2cDeviceError::Config => false,
+            };
+            if stuck && self.recovery.recover(bus) {
+                self.counters.recoveries = self.counters.recoveries.saturating_add(1);
+            }
+        }
+        res
+    }
+
+    /// Whether a probe result means no device answered at the address.
+    pub(crate) fn is_absent<E: i2c::Error>(res: &Result<(), I2cDeviceError<E>>) -> bool {
+        matches!(res, Err(I2cDeviceError::I2c(e)) if matches!(e.kind(), ErrorKind::NoAcknowledge(_)))
+    }
+}
+
 /// Error returned by SPI device implementations in this crate.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -57,3 +263,98 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    use embedded_hal_1::digital::ErrorType;
+
+    use super::*;
+
+    /// A target holding SDA low until it has seen `stuck_for` SCL pulses.
+    struct Target {
+        stuck_for: u32,
+        pulses: Cell<u32>,
+        sda: Cell<bool>,
+        stopped: Cell<bool>,
+    }
+
+    struct Scl<'a>(&'a Target);
+    struct Sda<'a>(&'a Target);
+    struct NoDelay;
+
+    impl ErrorType for Scl<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Scl<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0.pulses.set(self.0.pulses.get() + 1);
+            Ok(())
+        }
+    }
+
+    impl ErrorType for Sda<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Sda<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.sda.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            if !self.0.sda.get() && self.is_high()? {
+                self.0.stopped.set(true);
+            }
+            self.0.sda.set(true);
+            Ok(())
+        }
+    }
+
+    impl InputPin for Sda<'_> {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            // The first `set_high` on SCL is the release, not a pulse.
+            Ok(self.0.pulses.get() > self.0.stuck_for)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.is_high()?)
+        }
+    }
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    fn recover(stuck_for: u32) -> (bool, u32, bool) {
+        let target = Target {
+            stuck_for,
+            pulses: Cell::new(0),
+            sda: Cell::new(true),
+            stopped: Cell::new(false),
+        };
+        let recovered = recover_i2c_bus(&mut Scl(&target), &mut Sda(&target), &mut NoDelay);
+        (recovered, target.pulses.get(), target.stopped.get())
+    }
+
+    #[test]
+    fn recovery_clocks_until_sda_released() {
+        // Release, 3 pulses, then the STOP.
+        assert_eq!(recover(3), (true, 5, true));
+        assert_eq!(recover(9), (true, 11, true));
+        assert_eq!(recover(0), (true, 2, true));
+    }
+
+    #[test]
+    fn recovery_gives_up_after_9_pulses() {
+        assert_eq!(recover(10), (false, 10, false));
+    }
+}
@@ -652,6 +652,39 @@ impl<'d, T: Instance> PdPhy<'d, T> {
     }
 }
 
+impl<'d, T: Instance> embassy_usb_driver::pd::PdPhy for PdPhy<'d, T> {
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, embassy_usb_driver::pd::RxError> {
+        use embassy_usb_driver::pd::RxError as E;
+        loop {
+            match self.receive_with_sop(buf).await {
+                Ok((Sop::Sop, size)) => return Ok(size),
+                // Messages for cable plugs are not for the port partner.
+                Ok(_) => continue,
+                Err(RxError::Crc) => return Err(E::Crc),
+                Err(RxError::Overrun) => return Err(E::Overrun),
+                Err(RxError::HardReset) => return Err(E::HardReset),
+            }
+        }
+    }
+
+    async fn transmit(&mut self, msg: &[u8]) -> Result<(), embassy_usb_driver::pd::TxError> {
+        PdPhy::transmit(self, msg).await.map_err(TxError::into_driver)
+    }
+
+    async fn transmit_hard_reset(&mut self) -> Result<(), embassy_usb_driver::pd::TxError> {
+        self.transmit_hardreset().await.map_err(TxError::into_driver)
+    }
+}
+
+impl TxError {
+    fn into_driver(self) -> embassy_usb_driver::pd::TxError {
+        match self {
+            TxError::Discarded => embassy_usb_driver::pd::TxError::Discarded,
+            TxError::HardReset => embassy_usb_driver::pd::TxError::HardReset,
+        }
+    }
+}
+
 /// Interrupt handler.
 pub struct InterruptHandler<T: Instance> {
     _phantom: PhantomData<T>,
@@ -838,6 +838,10 @@ impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, In> {
         &self.info
     }
 
+    fn frame_number(&self) -> Result<u16, Unsupported> {
+        Ok(T::regs().fnr().read().fn_())
+    }
+
     async fn wait_enabled(&mut self) {
         trace!("wait_enabled IN WAITING");
         let index = self.info.addr.index();
@@ -860,6 +864,10 @@ impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, Out> {
         &self.info
     }
 
+    fn frame_number(&self) -> Result<u16, Unsupported> {
+        Ok(T::regs().fnr().read().fn_())
+    }
+
     async fn wait_enabled(&mut self) {
         trace!("wait_enabled OUT WAITING");
         let index = self.info.addr.index();
@@ -12,6 +12,22 @@ pub(crate) const DFU_PROTOCOL_DFU: u8 = 0x02;
 pub(crate) const DFU_PROTOCOL_RT: u8 = 0x01;
 /// DFU functional descriptor
 pub(crate) const DESC_DFU_FUNCTIONAL: u8 = 0x21;
+#[cfg(feature = "dfu")]
+/// DFU version 1.1
+pub(crate) const DFU_VERSION: u16 = 0x0110;
+#[cfg(feature = "dfu")]
+/// DFU version of the ST DfuSe extensions
+pub(crate) const DFUSE_VERSION: u16 = 0x011a;
+
+#[cfg(feature = "dfu")]
+/// DfuSe command, in block 0 of a DFU_UPLOAD: list the supported commands
+pub(crate) const DFUSE_GET_COMMANDS: u8 = 0x00;
+#[cfg(feature = "dfu")]
+/// DfuSe command, in block 0 of a DFU_DNLOAD: set the address of the next blocks
+pub(crate) const DFUSE_SET_ADDRESS: u8 = 0x21;
+#[cfg(feature = "dfu")]
+/// DfuSe command, in block 0 of a DFU_DNLOAD: erase the page at an address, or all pages
+pub(crate) const DFUSE_ERASE: u8 = 0x41;
 
 macro_rules! define_dfu_attributes {
     ($macro:path) => {
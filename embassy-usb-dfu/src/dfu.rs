@@ -1,15 +1,58 @@
+use core::fmt::Write as _;
+
 use embassy_boot::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterError};
 use embassy_usb::control::{InResponse, OutResponse, Recipient, RequestType};
 use embassy_usb::driver::Driver;
+use embassy_usb::types::{InterfaceNumber, StringIndex};
 use embassy_usb::{Builder, FunctionBuilder, Handler};
-use embedded_storage::nor_flash::{NorFlash, NorFlashErrorKind};
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
 
 use crate::consts::{
-    DfuAttributes, Request, State, Status, APPN_SPEC_SUBCLASS_DFU, DESC_DFU_FUNCTIONAL, DFU_PROTOCOL_DFU,
-    USB_CLASS_APPN_SPEC,
+    DfuAttributes, Request, State, Status, APPN_SPEC_SUBCLASS_DFU, DESC_DFU_FUNCTIONAL, DFUSE_ERASE,
+    DFUSE_GET_COMMANDS, DFUSE_SET_ADDRESS, DFUSE_VERSION, DFU_PROTOCOL_DFU, DFU_VERSION, USB_CLASS_APPN_SPEC,
 };
 use crate::Reset;
 
+/// Memory that can be read back with DFU_UPLOAD, such as the ACTIVE partition.
+///
+/// Implemented for any [`ReadNorFlash`].
+pub trait UploadSource {
+    /// Read `buf.len()` bytes at `offset`, aligned to the read size of the memory.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), NorFlashErrorKind>;
+
+    /// Read size of the memory.
+    fn read_size(&self) -> usize;
+
+    /// Size of the memory.
+    fn capacity(&self) -> usize;
+}
+
+impl<F: ReadNorFlash> UploadSource for F {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), NorFlashErrorKind> {
+        ReadNorFlash::read(self, offset, buf).map_err(|e| e.kind())
+    }
+
+    fn read_size(&self) -> usize {
+        F::READ_SIZE
+    }
+
+    fn capacity(&self) -> usize {
+        ReadNorFlash::capacity(self)
+    }
+}
+
+/// Addresses of the partitions for the ST DfuSe extensions.
+#[derive(Copy, Clone)]
+struct DfuSe {
+    dfu_address: u32,
+    active_address: u32,
+}
+
+/// Alternate setting of the DFU partition.
+const ALT_DFU: u8 = 0;
+/// Alternate setting of the ACTIVE partition, if it can be uploaded.
+const ALT_ACTIVE: u8 = 1;
+
 /// Internal state for USB DFU
 pub struct Control<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> {
     updater: BlockingFirmwareUpdater<'d, DFU, STATE>,
@@ -19,6 +62,14 @@ pub struct Control<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_S
     offset: usize,
     buf: AlignedBuffer<BLOCK_SIZE>,
     reset: RST,
+    iface: InterfaceNumber,
+    alt: u8,
+    active: Option<&'d mut dyn UploadSource>,
+    dfuse: Option<DfuSe>,
+    address: u32,
+    manifested: bool,
+    strings: [Option<StringIndex>; 2],
+    string_buf: [u8; 40],
 
     #[cfg(feature = "_verify")]
     public_key: &'static [u8; 32],
@@ -40,29 +91,360 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Co
             offset: 0,
             buf: AlignedBuffer([0; BLOCK_SIZE]),
             reset,
+            iface: InterfaceNumber(0),
+            alt: ALT_DFU,
+            active: None,
+            dfuse: None,
+            address: 0,
+            manifested: false,
+            strings: [None; 2],
+            string_buf: [0; 40],
 
             #[cfg(feature = "_verify")]
             public_key,
         }
     }
 
+    /// Expose the ACTIVE partition as a second alternate setting of the interface, so that the
+    /// running firmware can be read back with DFU_UPLOAD if [`DfuAttributes::CAN_UPLOAD`] is set.
+    pub fn with_active(mut self, active: &'d mut dyn UploadSource) -> Self {
+        self.active = Some(active);
+        self
+    }
+
+    /// Use the ST DfuSe extensions, as used by `dfu-util -s address`, with the DFU and ACTIVE
+    /// partitions at the given addresses.
+    ///
+    /// Blocks are written to and read from the address set by the host, which must be in the
+    /// partition of the selected alternate setting, and pages can be erased by the host. The update
+    /// is marked once the host leaves DfuSe mode, with `dfu-util -s address:leave`. The transfer size
+    /// used by the host must be `BLOCK_SIZE`.
+    pub fn with_dfuse(mut self, dfu_address: u32, active_address: u32) -> Self {
+        self.dfuse = Some(DfuSe {
+            dfu_address,
+            active_address,
+        });
+        self
+    }
+
     fn reset_state(&mut self) {
         self.offset = 0;
         self.state = State::DfuIdle;
         self.status = Status::Ok;
     }
+
+    fn fail(&mut self, status: Status) {
+        self.state = State::Error;
+        self.status = status;
+    }
+
+    /// Size and DfuSe address of the partition of alternate setting `alt`.
+    fn region(&self, alt: u8) -> Option<(usize, u32)> {
+        let dfuse = self.dfuse.map(|d| (d.dfu_address, d.active_address));
+        match alt {
+            ALT_DFU => Some((self.updater.dfu_capacity(), dfuse.map_or(0, |d| d.0))),
+            ALT_ACTIVE => Some((self.active.as_ref()?.capacity(), dfuse.map_or(0, |d| d.1))),
+            _ => None,
+        }
+    }
+
+    /// Offset in the partition of the selected alternate setting of DfuSe block `block`.
+    fn dfuse_offset(&self, block: u16) -> Option<usize> {
+        let (capacity, base) = self.region(self.alt)?;
+        let address = self
+            .address
+            .checked_add((block as u32 - 2).checked_mul(BLOCK_SIZE as u32)?)?;
+        let offset = address.checked_sub(base)? as usize;
+        (offset < capacity).then_some(offset)
+    }
+
+    /// Read at most `len` bytes at `offset` in the partition of the selected alternate setting into
+    /// the buffer, returning the number of bytes read.
+    fn read(&mut self, offset: usize, len: usize) -> Result<usize, Status> {
+        let (capacity, _) = self.region(self.alt).ok_or(Status::ErrTarget)?;
+        let len = len.min(BLOCK_SIZE).min(capacity.saturating_sub(offset));
+        if len == 0 {
+            return Ok(0);
+        }
+        let buf = self.buf.as_mut();
+        match self.alt {
+            ALT_DFU => {
+                let aligned = len.next_multiple_of(DFU::READ_SIZE).min(capacity - offset);
+                self.updater.read_dfu(offset as u32, &mut buf[..aligned])?;
+            }
+            _ => {
+                let active = self.active.as_mut().ok_or(Status::ErrTarget)?;
+                let aligned = len.next_multiple_of(active.read_size()).min(capacity - offset);
+                active.read(offset as u32, &mut buf[..aligned])?;
+            }
+        }
+        Ok(len)
+    }
+
+    /// Final DFU_DNLOAD, mark the update and start manifestation.
+    fn manifest(&mut self) {
+        debug!("Receiving final transfer");
+
+        #[cfg(feature = "_verify")]
+        let update_res: Result<(), FirmwareUpdaterError> = {
+            const SIGNATURE_LEN: usize = 64;
+
+            let mut signature = [0; SIGNATURE_LEN];
+            match self.offset.checked_sub(SIGNATURE_LEN) {
+                Some(update_len) => {
+                    let update_len = update_len as u32;
+                    self.updater.read_dfu(update_len, &mut signature).and_then(|_| {
+                        self.updater
+                            .verify_and_mark_updated(self.public_key, &signature, update_len)
+                    })
+                }
+                None => Err(FirmwareUpdaterError::Signature(Default::default())),
+            }
+        };
+
+        #[cfg(not(feature = "_verify"))]
+        let update_res = self.updater.mark_updated();
+
+        match update_res {
+            Ok(_) => {
+                self.status = Status::Ok;
+                self.state = State::ManifestSync;
+                info!("Update complete");
+            }
+            Err(e) => {
+                error!("Error completing update: {}", e);
+                self.fail(e.into());
+            }
+        }
+    }
+
+    fn dnload(&mut self, req: embassy_usb::control::Request, data: &[u8]) -> OutResponse {
+        if req.value == 0 {
+            info!("Download starting");
+            self.state = State::Download;
+            self.offset = 0;
+        }
+
+        if self.state != State::Download {
+            error!("Unexpected DNLOAD while chip is waiting for a GETSTATUS");
+            self.fail(Status::ErrUnknown);
+            return OutResponse::Rejected;
+        }
+
+        if req.length == 0 {
+            self.manifest();
+            return OutResponse::Accepted;
+        }
+
+        debug!("Writing {} bytes at {}", data.len(), self.offset);
+        match self.updater.write_firmware(self.offset, self.buf.as_ref()) {
+            Ok(_) => {
+                self.status = Status::Ok;
+                self.state = State::DlSync;
+                self.offset += data.len();
+            }
+            Err(e) => {
+                error!("Error writing firmware: {:?}", e);
+                self.fail(e.into());
+            }
+        }
+        OutResponse::Accepted
+    }
+
+    fn dfuse_dnload(&mut self, req: embassy_usb::control::Request, data: &[u8]) -> OutResponse {
+        match self.state {
+            State::DfuIdle => self.offset = 0,
+            State::Download => {}
+            _ => {
+                error!("Unexpected DNLOAD while chip is waiting for a GETSTATUS");
+                self.fail(Status::ErrUnknown);
+                return OutResponse::Rejected;
+            }
+        }
+
+        if req.length == 0 {
+            info!("Leaving DfuSe mode");
+            self.manifest();
+            return OutResponse::Accepted;
+        }
+
+        let res = match req.value {
+            0 => self.dfuse_command(data),
+            1 => Err(Status::ErrStalledPkt),
+            block => match self.dfuse_offset(block) {
+                Some(offset) if offset + data.len() <= self.updater.dfu_capacity() && self.alt == ALT_DFU => {
+                    debug!("Writing {} bytes at {}", data.len(), offset);
+                    let len = data.len().next_multiple_of(DFU::WRITE_SIZE);
+                    self.buf.as_mut()[data.len()..len].fill(0xff);
+                    self.updater
+                        .write_firmware(offset, &self.buf.as_ref()[..len])
+                        .map(|_| self.offset = self.offset.max(offset + data.len()))
+                        .map_err(Status::from)
+                }
+                Some(_) if self.alt != ALT_DFU => Err(Status::ErrWrite),
+                _ => Err(Status::ErrAddress),
+            },
+        };
+        match res {
+            Ok(()) => {
+                self.status = Status::Ok;
+                self.state = State::DlSync;
+                OutResponse::Accepted
+            }
+            Err(Status::ErrStalledPkt) => {
+                self.fail(Status::ErrStalledPkt);
+                OutResponse::Rejected
+            }
+            Err(status) => {
+                error!("DfuSe download failed: {}", status as u8);
+                self.fail(status);
+                OutResponse::Accepted
+            }
+        }
+    }
+
+    fn dfuse_command(&mut self, data: &[u8]) -> Result<(), Status> {
+        let address = data.get(1..5).map(|a| u32::from_le_bytes([a[0], a[1], a[2], a[3]]));
+        match (data[0], address, data.len()) {
+            (DFUSE_SET_ADDRESS, Some(address), 5) => {
+                debug!("DfuSe set address {:#x}", address);
+                self.address = address;
+                Ok(())
+            }
+            (DFUSE_ERASE, Some(address), 5) => {
+                let dfu_address = self.dfuse.map_or(0, |d| d.dfu_address);
+                let capacity = self.updater.dfu_capacity();
+                match address.checked_sub(dfu_address).map(|o| o as usize) {
+                    Some(offset) if self.alt == ALT_DFU && offset < capacity => {
+                        let page = offset / DFU::ERASE_SIZE * DFU::ERASE_SIZE;
+                        debug!("DfuSe erase page {:#x}", address);
+                        self.updater
+                            .erase_dfu(page as u32, (page + DFU::ERASE_SIZE) as u32)
+                            .map_err(Status::from)
+                    }
+                    _ => Err(Status::ErrAddress),
+                }
+            }
+            (DFUSE_ERASE, None, 1) if self.alt == ALT_DFU => {
+                debug!("DfuSe mass erase");
+                let capacity = self.updater.dfu_capacity();
+                self.updater.erase_dfu(0, capacity as u32).map_err(Status::from)
+            }
+            _ => Err(Status::ErrStalledPkt),
+        }
+    }
+
+    fn upload<'a>(&'a mut self, req: embassy_usb::control::Request, buf: &'a mut [u8]) -> InResponse<'a> {
+        match self.state {
+            State::DfuIdle => self.offset = 0,
+            State::UploadIdle => {}
+            _ => {
+                error!("Unexpected UPLOAD");
+                self.fail(Status::ErrUnknown);
+                return InResponse::Rejected;
+            }
+        }
+
+        let offset = match (self.dfuse.is_some(), req.value) {
+            (true, 0) => {
+                buf[..3].copy_from_slice(&[DFUSE_GET_COMMANDS, DFUSE_SET_ADDRESS, DFUSE_ERASE]);
+                self.state = State::DfuIdle;
+                return InResponse::Accepted(&buf[..3]);
+            }
+            (true, 1) => None,
+            (true, block) => self.dfuse_offset(block),
+            (false, _) => Some(self.offset),
+        };
+        let Some(offset) = offset else {
+            self.fail(Status::ErrAddress);
+            return InResponse::Rejected;
+        };
+
+        match self.read(offset, req.length as usize) {
+            Ok(len) => {
+                self.offset = offset + len;
+                // A short frame ends the upload.
+                self.state = match len < req.length as usize {
+                    true => State::DfuIdle,
+                    false => State::UploadIdle,
+                };
+                InResponse::Accepted(&self.buf.as_ref()[..len])
+            }
+            Err(status) => {
+                error!("Error reading firmware: {}", status as u8);
+                self.fail(status);
+                InResponse::Rejected
+            }
+        }
+    }
+
+    /// Format the string describing the partition of alternate setting `alt`.
+    fn format_string(&mut self, alt: u8) -> Option<&str> {
+        struct Writer<'a>(&'a mut [u8], usize);
+        impl core::fmt::Write for Writer<'_> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                let end = self.1 + s.len();
+                self.0
+                    .get_mut(self.1..end)
+                    .ok_or(core::fmt::Error)?
+                    .copy_from_slice(s.as_bytes());
+                self.1 = end;
+                Ok(())
+            }
+        }
+
+        let name = match alt {
+            ALT_DFU => "DFU",
+            _ => "ACTIVE",
+        };
+        let (capacity, address) = self.region(alt)?;
+        let mut w = Writer(&mut self.string_buf, 0);
+        match self.dfuse {
+            None => w.write_str(name).ok()?,
+            Some(_) => {
+                // Memory layout, as `@name/address/count*size<unit><type>`, with the type giving
+                // whether the memory is readable (1), erasable (2) and writable (4).
+                let (count, size, mut kind) = match alt {
+                    ALT_DFU => (capacity / DFU::ERASE_SIZE, DFU::ERASE_SIZE, 0),
+                    _ => (1, capacity, 0),
+                };
+                if self.attrs.contains(DfuAttributes::CAN_UPLOAD) {
+                    kind |= 1;
+                }
+                if alt == ALT_DFU && self.attrs.contains(DfuAttributes::CAN_DOWNLOAD) {
+                    kind |= 2 | 4;
+                }
+                let (size, unit) = match size % 1024 {
+                    0 => (size / 1024, 'K'),
+                    _ => (size, ' '),
+                };
+                let kind = (b'`' + kind) as char;
+                write!(w, "@{name} /{address:#010x}/{count:02}*{size:03}{unit}{kind}").ok()?;
+            }
+        }
+        let len = w.1;
+        core::str::from_utf8(&self.string_buf[..len]).ok()
+    }
+}
+
+impl From<NorFlashErrorKind> for Status {
+    fn from(e: NorFlashErrorKind) -> Self {
+        match e {
+            NorFlashErrorKind::NotAligned => Status::ErrWrite,
+            NorFlashErrorKind::OutOfBounds => Status::ErrAddress,
+            _ => Status::ErrUnknown,
+        }
+    }
 }
 
 impl From<FirmwareUpdaterError> for Status {
     fn from(e: FirmwareUpdaterError) -> Self {
         match e {
-            FirmwareUpdaterError::Flash(e) => match e {
-                NorFlashErrorKind::NotAligned => Status::ErrWrite,
-                NorFlashErrorKind::OutOfBounds => Status::ErrAddress,
-                _ => Status::ErrUnknown,
-            },
+            FirmwareUpdaterError::Flash(e) => e.into(),
             FirmwareUpdaterError::Signature(_) => Status::ErrVerify,
             FirmwareUpdaterError::BadState => Status::ErrUnknown,
+            FirmwareUpdaterError::Patch(_) => Status::ErrVerify,
+            FirmwareUpdaterError::Decompress(_) => Status::ErrVerify,
         }
     }
 }
@@ -70,12 +452,29 @@ impl From<FirmwareUpdaterError> for Status {
 impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Handler
     for Control<'d, DFU, STATE, RST, BLOCK_SIZE>
 {
+    fn reset(&mut self) {
+        if self.manifested {
+            info!("USB reset after manifestation, resetting");
+            self.reset.sys_reset();
+        }
+        self.alt = ALT_DFU;
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface == self.iface {
+            self.alt = alternate_setting;
+            self.reset_state();
+        }
+    }
+
     fn control_out(
         &mut self,
         req: embassy_usb::control::Request,
         data: &[u8],
     ) -> Option<embassy_usb::control::OutResponse> {
-        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
             debug!("Unknown out request: {:?}", req);
             return None;
         }
@@ -86,78 +485,24 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Ha
                 Some(OutResponse::Accepted)
             }
             Ok(Request::Dnload) if self.attrs.contains(DfuAttributes::CAN_DOWNLOAD) => {
-                if req.value == 0 {
-                    info!("Download starting");
-                    self.state = State::Download;
-                    self.offset = 0;
-                }
-
-                if self.state != State::Download {
-                    error!("Unexpected DNLOAD while chip is waiting for a GETSTATUS");
-                    self.status = Status::ErrUnknown;
-                    self.state = State::Error;
-                    return Some(OutResponse::Rejected);
-                }
-
                 if data.len() > BLOCK_SIZE {
                     error!("USB data len exceeded block size");
-                    self.status = Status::ErrUnknown;
-                    self.state = State::Error;
+                    self.fail(Status::ErrUnknown);
                     return Some(OutResponse::Rejected);
                 }
 
                 debug!("Copying {} bytes to buffer", data.len());
                 self.buf.as_mut()[..data.len()].copy_from_slice(data);
 
-                let final_transfer = req.length == 0;
-                if final_transfer {
-                    debug!("Receiving final transfer");
-
-                    #[cfg(feature = "_verify")]
-                    let update_res: Result<(), FirmwareUpdaterError> = {
-                        const SIGNATURE_LEN: usize = 64;
-
-                        let mut signature = [0; SIGNATURE_LEN];
-                        let update_len = (self.offset - SIGNATURE_LEN) as u32;
-
-                        self.updater.read_dfu(update_len, &mut signature).and_then(|_| {
-                            self.updater
-                                .verify_and_mark_updated(self.public_key, &signature, update_len)
-                        })
-                    };
-
-                    #[cfg(not(feature = "_verify"))]
-                    let update_res = self.updater.mark_updated();
-
-                    match update_res {
-                        Ok(_) => {
-                            self.status = Status::Ok;
-                            self.state = State::ManifestSync;
-                            info!("Update complete");
-                        }
-                        Err(e) => {
-                            error!("Error completing update: {}", e);
-                            self.state = State::Error;
-                            self.status = e.into();
-                        }
-                    }
-                } else {
-                    debug!("Writing {} bytes at {}", data.len(), self.offset);
-                    match self.updater.write_firmware(self.offset, self.buf.as_ref()) {
-                        Ok(_) => {
-                            self.status = Status::Ok;
-                            self.state = State::DlSync;
-                            self.offset += data.len();
-                        }
-                        Err(e) => {
-                            error!("Error writing firmware: {:?}", e);
-                            self.state = State::Error;
-                            self.status = e.into();
-                        }
+                match self.dfuse {
+                    Some(_) => Some(self.dfuse_dnload(req, data)),
+                    None if self.alt != ALT_DFU => {
+                        error!("DNLOAD to a read-only alternate setting");
+                        self.fail(Status::ErrWrite);
+                        Some(OutResponse::Rejected)
                     }
+                    None => Some(self.dnload(req, data)),
                 }
-
-                Some(OutResponse::Accepted)
             }
             Ok(Request::Detach) => Some(OutResponse::Accepted), // Device is already in DFU mode
             Ok(Request::ClrStatus) => {
@@ -174,15 +519,27 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Ha
         req: embassy_usb::control::Request,
         buf: &'a mut [u8],
     ) -> Option<embassy_usb::control::InResponse<'a>> {
-        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
             debug!("Unknown in request: {:?}", req);
             return None;
         }
         match Request::try_from(req.request) {
             Ok(Request::GetStatus) => {
                 match self.state {
-                    State::DlSync => self.state = State::Download,
+                    // DfuSe hosts expect the device to be busy while it executes a command.
+                    State::DlSync if self.dfuse.is_some() => self.state = State::DlBusy,
+                    State::DlSync | State::DlBusy => self.state = State::Download,
+                    State::ManifestSync if self.attrs.contains(DfuAttributes::MANIFESTATION_TOLERANT) => {
+                        self.state = State::Manifest
+                    }
                     State::ManifestSync => self.reset.sys_reset(),
+                    State::Manifest => {
+                        info!("Manifestation complete, waiting for a USB reset");
+                        self.manifested = true;
+                        self.state = State::DfuIdle;
+                    }
                     _ => {}
                 }
 
@@ -194,22 +551,29 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> Ha
                 buf[0] = self.state as u8;
                 Some(InResponse::Accepted(&buf[0..1]))
             }
-            Ok(Request::Upload) if self.attrs.contains(DfuAttributes::CAN_UPLOAD) => {
-                //TODO: FirmwareUpdater provides a way of reading the active partition so we could in theory add functionality to upload firmware.
-                Some(InResponse::Rejected)
-            }
+            Ok(Request::Upload) if self.attrs.contains(DfuAttributes::CAN_UPLOAD) => Some(self.upload(req, buf)),
             _ => None,
         }
     }
+
+    fn get_string(&mut self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        let alt = self.strings.iter().position(|&s| s == Some(index))?;
+        self.format_string(alt as u8)
+    }
 }
 
 /// An implementation of the USB DFU 1.1 protocol
 ///
 /// This function will add a DFU interface descriptor to the provided Builder, and register the provided Control as a handler for the USB device
-/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download if configured by the user.
+/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download and Upload if configured by the user.
 ///
 /// Once the host has initiated a DFU download operation, the chunks sent by the host will be written to the DFU partition.
 /// Once the final sync in the manifestation phase has been received, the handler will trigger a system reset to swap the new firmware.
+/// If [`DfuAttributes::MANIFESTATION_TOLERANT`] is set, the handler instead returns to the idle state, so that the host can
+/// read the update back, and triggers a system reset on the next USB reset.
+///
+/// The DFU partition is exposed as alternate setting 0 of the interface, and the ACTIVE partition as alternate setting 1 if
+/// it was given with [`Control::with_active`].
 pub fn usb_dfu<'d, D: Driver<'d>, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize>(
     builder: &mut Builder<'d, D>,
     handler: &'d mut Control<'d, DFU, STATE, RST, BLOCK_SIZE>,
@@ -222,20 +586,348 @@ pub fn usb_dfu<'d, D: Driver<'d>, DFU: NorFlash, STATE: NorFlash, RST: Reset, co
     func_modifier(&mut func);
 
     let mut iface = func.interface();
-    let mut alt = iface.alt_setting(USB_CLASS_APPN_SPEC, APPN_SPEC_SUBCLASS_DFU, DFU_PROTOCOL_DFU, None);
-    alt.descriptor(
-        DESC_DFU_FUNCTIONAL,
-        &[
-            handler.attrs.bits(),
-            0xc4,
-            0x09, // 2500ms timeout, doesn't affect operation as DETACH not necessary in bootloader code
-            (BLOCK_SIZE & 0xff) as u8,
-            ((BLOCK_SIZE & 0xff00) >> 8) as u8,
-            0x10,
-            0x01, // DFU 1.1
-        ],
-    );
+    handler.iface = iface.interface_number();
+    let alt_settings = match handler.active {
+        Some(_) => 2,
+        None => 1,
+    };
+    let version = match handler.dfuse {
+        Some(_) => DFUSE_VERSION,
+        None => DFU_VERSION,
+    };
+    // Alternate settings are only named when there are several, or as required by DfuSe.
+    let named = alt_settings > 1 || handler.dfuse.is_some();
+    for index in 0..alt_settings {
+        let string = named.then(|| iface.string());
+        handler.strings[index] = string;
+        let mut alt = iface.alt_setting(USB_CLASS_APPN_SPEC, APPN_SPEC_SUBCLASS_DFU, DFU_PROTOCOL_DFU, string);
+        alt.descriptor(
+            DESC_DFU_FUNCTIONAL,
+            &[
+                handler.attrs.bits(),
+                0xc4,
+                0x09, // 2500ms timeout, doesn't affect operation as DETACH not necessary in bootloader code
+                (BLOCK_SIZE & 0xff) as u8,
+                ((BLOCK_SIZE & 0xff00) >> 8) as u8,
+                (version & 0xff) as u8,
+                (version >> 8) as u8,
+            ],
+        );
+    }
 
     drop(func);
     builder.handler(handler);
 }
+
+// The tests drive the unsigned flow, signed updates are covered by embassy-boot.
+#[cfg(all(test, not(feature = "_verify")))]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::vec::Vec;
+
+    use embassy_boot::FirmwareUpdaterConfig;
+    use embassy_usb::control::Request as UsbRequest;
+    use embassy_usb::driver::Direction;
+    use embedded_storage::nor_flash::ErrorType;
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 256;
+    const DFU_ADDRESS: u32 = 0x0800_8000;
+    const ACTIVE_ADDRESS: u32 = 0x0800_2000;
+
+    struct MemFlash<const SIZE: usize>([u8; SIZE]);
+
+    impl<const SIZE: usize> ErrorType for MemFlash<SIZE> {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl<const SIZE: usize> ReadNorFlash for MemFlash<SIZE> {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            let data = self
+                .0
+                .get(offset..offset + bytes.len())
+                .ok_or(NorFlashErrorKind::OutOfBounds)?;
+            bytes.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            SIZE
+        }
+    }
+
+    impl<const SIZE: usize> NorFlash for MemFlash<SIZE> {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 1024;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.0[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            let mem = self
+                .0
+                .get_mut(offset..offset + bytes.len())
+                .ok_or(NorFlashErrorKind::OutOfBounds)?;
+            for (mem, byte) in mem.iter_mut().zip(bytes) {
+                *mem &= *byte;
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct TestReset(Cell<usize>);
+
+    impl Reset for TestReset {
+        fn sys_reset(&self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    type TestControl<'d> = Control<'d, &'d mut MemFlash<8192>, &'d mut MemFlash<4096>, TestReset, BLOCK_SIZE>;
+
+    fn request(direction: Direction, request: Request, value: u16, length: usize) -> UsbRequest {
+        UsbRequest {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request: request as u8,
+            value,
+            index: 0,
+            length: length as u16,
+        }
+    }
+
+    fn out(control: &mut TestControl<'_>, req: Request, value: u16, data: &[u8]) -> Option<OutResponse> {
+        control.control_out(request(Direction::Out, req, value, data.len()), data)
+    }
+
+    fn upload(control: &mut TestControl<'_>, value: u16, length: usize) -> Option<Vec<u8>> {
+        let mut buf = [0; 64];
+        match control.control_in(request(Direction::In, Request::Upload, value, length), &mut buf) {
+            Some(InResponse::Accepted(data)) => Some(data.to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Returns the status and state reported by DFU_GETSTATUS.
+    fn status(control: &mut TestControl<'_>) -> (u8, u8) {
+        let mut buf = [0; 64];
+        match control.control_in(request(Direction::In, Request::GetStatus, 0, 6), &mut buf) {
+            Some(InResponse::Accepted(data)) => (data[0], data[4]),
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+
+    fn dfuse_command(control: &mut TestControl<'_>, command: u8, address: u32) {
+        let mut data = [command, 0, 0, 0, 0];
+        data[1..].copy_from_slice(&address.to_le_bytes());
+        assert_eq!(out(control, Request::Dnload, 0, &data), Some(OutResponse::Accepted));
+        assert_eq!(status(control), (Status::Ok as u8, State::DlBusy as u8));
+        assert_eq!(status(control), (Status::Ok as u8, State::Download as u8));
+    }
+
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    #[test]
+    fn download() {
+        let mut dfu = MemFlash([0; 8192]);
+        let mut state = MemFlash([0xff; 4096]);
+        let mut aligned = [0; 4];
+        let updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: &mut dfu,
+                state: &mut state,
+            },
+            &mut aligned,
+        );
+        let mut control: TestControl<'_> = Control::new(updater, DfuAttributes::CAN_DOWNLOAD, TestReset::default());
+
+        let firmware = pattern(1, 300);
+        for (block, chunk) in firmware.chunks(BLOCK_SIZE).enumerate() {
+            assert_eq!(
+                out(&mut control, Request::Dnload, block as u16, chunk),
+                Some(OutResponse::Accepted)
+            );
+            assert_eq!(status(&mut control), (Status::Ok as u8, State::Download as u8));
+        }
+        // Requests for other interfaces are ignored.
+        let mut req = request(Direction::Out, Request::Dnload, 2, 0);
+        req.index = 1;
+        assert_eq!(control.control_out(req, &[]), None);
+        // Upload is not enabled.
+        assert_eq!(upload(&mut control, 0, 64), None);
+
+        assert_eq!(out(&mut control, Request::Dnload, 2, &[]), Some(OutResponse::Accepted));
+        assert_eq!(control.reset.0.get(), 0);
+        status(&mut control);
+        assert_eq!(control.reset.0.get(), 1);
+
+        assert_eq!(&dfu.0[..300], &firmware[..]);
+        assert_eq!(state.0[..4], [0xf0; 4]);
+    }
+
+    #[test]
+    fn upload_partitions() {
+        let mut dfu = MemFlash([0; 8192]);
+        dfu.0.copy_from_slice(&pattern(2, 8192));
+        let mut state = MemFlash([0xff; 4096]);
+        let mut active = MemFlash([0; 4096]);
+        active.0.copy_from_slice(&pattern(3, 4096));
+        let mut aligned = [0; 4];
+        let updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: &mut dfu,
+                state: &mut state,
+            },
+            &mut aligned,
+        );
+        let attrs = DfuAttributes::CAN_DOWNLOAD | DfuAttributes::CAN_UPLOAD;
+        let mut control: TestControl<'_> = Control::new(updater, attrs, TestReset::default()).with_active(&mut active);
+
+        let mut data = Vec::new();
+        for block in 0.. {
+            let chunk = upload(&mut control, block, BLOCK_SIZE).unwrap();
+            data.extend_from_slice(&chunk);
+            if chunk.len() < BLOCK_SIZE {
+                break;
+            }
+            assert_eq!(status(&mut control), (Status::Ok as u8, State::UploadIdle as u8));
+        }
+        assert_eq!(data, pattern(2, 8192));
+        assert_eq!(status(&mut control), (Status::Ok as u8, State::DfuIdle as u8));
+
+        control.set_alternate_setting(InterfaceNumber(0), ALT_ACTIVE);
+        let mut data = Vec::new();
+        for block in 0.. {
+            let chunk = upload(&mut control, block, 200).unwrap();
+            data.extend_from_slice(&chunk);
+            if chunk.len() < 200 {
+                break;
+            }
+        }
+        assert_eq!(data, pattern(3, 4096));
+
+        // The ACTIVE partition is read-only.
+        assert_eq!(
+            out(&mut control, Request::Dnload, 0, &[0; 16]),
+            Some(OutResponse::Rejected)
+        );
+        assert_eq!(status(&mut control), (Status::ErrWrite as u8, State::Error as u8));
+    }
+
+    #[test]
+    fn manifestation_tolerant() {
+        let mut dfu = MemFlash([0; 8192]);
+        let mut state = MemFlash([0xff; 4096]);
+        let mut aligned = [0; 4];
+        let updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: &mut dfu,
+                state: &mut state,
+            },
+            &mut aligned,
+        );
+        let attrs = DfuAttributes::CAN_DOWNLOAD | DfuAttributes::CAN_UPLOAD | DfuAttributes::MANIFESTATION_TOLERANT;
+        let mut control: TestControl<'_> = Control::new(updater, attrs, TestReset::default());
+
+        let firmware = pattern(4, BLOCK_SIZE);
+        assert_eq!(
+            out(&mut control, Request::Dnload, 0, &firmware),
+            Some(OutResponse::Accepted)
+        );
+        status(&mut control);
+        assert_eq!(out(&mut control, Request::Dnload, 1, &[]), Some(OutResponse::Accepted));
+        assert_eq!(status(&mut control), (Status::Ok as u8, State::Manifest as u8));
+        assert_eq!(status(&mut control), (Status::Ok as u8, State::DfuIdle as u8));
+        assert_eq!(control.reset.0.get(), 0);
+
+        // The update can be read back before the device is reset.
+        assert_eq!(upload(&mut control, 0, BLOCK_SIZE).unwrap(), firmware);
+        control.reset();
+        assert_eq!(control.reset.0.get(), 1);
+    }
+
+    #[test]
+    fn dfuse() {
+        let mut dfu = MemFlash([0; 8192]);
+        let mut state = MemFlash([0xff; 4096]);
+        let mut active = MemFlash([0; 4096]);
+        active.0.copy_from_slice(&pattern(5, 4096));
+        let mut aligned = [0; 4];
+        let updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: &mut dfu,
+                state: &mut state,
+            },
+            &mut aligned,
+        );
+        let attrs = DfuAttributes::CAN_DOWNLOAD | DfuAttributes::CAN_UPLOAD;
+        let mut control: TestControl<'_> = Control::new(updater, attrs, TestReset::default())
+            .with_active(&mut active)
+            .with_dfuse(DFU_ADDRESS, ACTIVE_ADDRESS);
+
+        control.strings = [Some(StringIndex(4)), Some(StringIndex(5))];
+        assert_eq!(control.get_string(StringIndex(4), 0), Some("@DFU /0x08008000/08*001Kg"));
+        assert_eq!(
+            control.get_string(StringIndex(5), 0),
+            Some("@ACTIVE /0x08002000/01*004Ka")
+        );
+        assert_eq!(upload(&mut control, 0, 16).unwrap(), [0x00, 0x21, 0x41]);
+
+        let firmware = pattern(6, 300);
+        dfuse_command(&mut control, DFUSE_ERASE, DFU_ADDRESS + 0x400);
+        dfuse_command(&mut control, DFUSE_SET_ADDRESS, DFU_ADDRESS + 0x400);
+        for (block, chunk) in firmware.chunks(BLOCK_SIZE).enumerate() {
+            assert_eq!(
+                out(&mut control, Request::Dnload, block as u16 + 2, chunk),
+                Some(OutResponse::Accepted)
+            );
+            assert_eq!(status(&mut control), (Status::Ok as u8, State::DlBusy as u8));
+            assert_eq!(status(&mut control), (Status::Ok as u8, State::Download as u8));
+        }
+
+        // Out of the DFU partition.
+        dfuse_command(&mut control, DFUSE_SET_ADDRESS, ACTIVE_ADDRESS);
+        assert_eq!(
+            out(&mut control, Request::Dnload, 2, &[0; 16]),
+            Some(OutResponse::Accepted)
+        );
+        assert_eq!(status(&mut control), (Status::ErrAddress as u8, State::Error as u8));
+        assert_eq!(
+            out(&mut control, Request::ClrStatus, 0, &[]),
+            Some(OutResponse::Accepted)
+        );
+
+        // Read back the ACTIVE partition.
+        control.set_alternate_setting(InterfaceNumber(0), ALT_ACTIVE);
+        dfuse_command(&mut control, DFUSE_SET_ADDRESS, ACTIVE_ADDRESS + 0x100);
+        assert_eq!(out(&mut control, Request::Abort, 0, &[]), Some(OutResponse::Accepted));
+        assert_eq!(upload(&mut control, 2, 64).unwrap(), &pattern(5, 4096)[0x100..0x140]);
+        assert_eq!(upload(&mut control, 3, 64).unwrap(), &pattern(5, 4096)[0x200..0x240]);
+        assert_eq!(out(&mut control, Request::Abort, 0, &[]), Some(OutResponse::Accepted));
+
+        // Leave DfuSe mode to mark the update.
+        control.set_alternate_setting(InterfaceNumber(0), ALT_DFU);
+        dfuse_command(&mut control, DFUSE_SET_ADDRESS, DFU_ADDRESS);
+        assert_eq!(out(&mut control, Request::Dnload, 2, &[]), Some(OutResponse::Accepted));
+        status(&mut control);
+        assert_eq!(control.reset.0.get(), 1);
+
+        assert_eq!(dfu.0[..0x400], [0; 0x400]);
+        assert_eq!(&dfu.0[0x400..0x400 + 300], &firmware[..]);
+        assert_eq!(dfu.0[0x400 + 300..0x800], [0xff; 0x400 - 300]);
+        assert_eq!(state.0[..4], [0xf0; 4]);
+    }
+}
@@ -0,0 +1,162 @@
+//! Host-side driver traits.
+//!
+//! A [`UsbHostDriver`] controls the root port of a USB host controller: it reports device
+//! connections, resets the port and allocates [`UsbPipe`]s, which perform the transfers with an
+//! endpoint of the connected device.
+
+use crate::{Direction, EndpointType};
+
+/// Speed of a device connected to the host.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Speed {
+    /// Low speed, 1.5 Mbit/s.
+    Low,
+    /// Full speed, 12 Mbit/s.
+    Full,
+    /// High speed, 480 Mbit/s.
+    High,
+}
+
+/// Event returned by [`UsbHostDriver::wait_for_device_event`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceEvent {
+    /// A device has been connected to the port, at the given speed.
+    Connected(Speed),
+    /// The device has been disconnected.
+    Disconnected,
+}
+
+/// Errors returned by host transfers.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HostError {
+    /// The endpoint responded with a STALL handshake.
+    Stall,
+    /// The device did not respond.
+    Timeout,
+    /// The transfer failed after retries, because of CRC, bit stuffing or toggle errors.
+    TransactionError,
+    /// The device sent more data than fits in the buffer.
+    BufferOverflow,
+    /// The device has been disconnected.
+    Disconnected,
+    /// No pipe or channel is available.
+    NoPipe,
+}
+
+/// Setup packet of a control transfer.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SetupPacket {
+    /// Request type bitmap, including the direction of the data stage.
+    pub request_type: u8,
+    /// Request code.
+    pub request: u8,
+    /// Request value.
+    pub value: u16,
+    /// Request index.
+    pub index: u16,
+    /// Length of the data stage.
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Direction of the data stage.
+    pub fn direction(&self) -> Direction {
+        match self.request_type & 0x80 {
+            0 => Direction::Out,
+            _ => Direction::In,
+        }
+    }
+
+    /// Serialize the packet, as sent on the bus.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let value = self.value.to_le_bytes();
+        let index = self.index.to_le_bytes();
+        let length = self.length.to_le_bytes();
+        [
+            self.request_type,
+            self.request,
+            value[0],
+            value[1],
+            index[0],
+            index[1],
+            length[0],
+            length[1],
+        ]
+    }
+
+    /// Parse a packet as sent on the bus.
+    pub fn from_bytes(buf: &[u8; 8]) -> Self {
+        Self {
+            request_type: buf[0],
+            request: buf[1],
+            value: u16::from_le_bytes([buf[2], buf[3]]),
+            index: u16::from_le_bytes([buf[4], buf[5]]),
+            length: u16::from_le_bytes([buf[6], buf[7]]),
+        }
+    }
+}
+
+/// Endpoint of a device a pipe is allocated for.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PipeInfo {
+    /// Address of the device, 0 before it has been addressed.
+    pub device_address: u8,
+    /// Speed of the device.
+    pub speed: Speed,
+    /// Endpoint number, without the direction bit.
+    pub endpoint: u8,
+    /// Direction of the endpoint. Control pipes transfer in both directions.
+    pub direction: Direction,
+    /// Transfer type of the endpoint.
+    pub ep_type: EndpointType,
+    /// Max packet size of the endpoint, in bytes.
+    pub max_packet_size: u16,
+    /// Polling interval of interrupt endpoints, in milliseconds.
+    pub interval_ms: u8,
+}
+
+/// Host controller driver, owning the root port.
+pub trait UsbHostDriver {
+    /// Type of the pipes of this driver.
+    type Pipe: UsbPipe;
+
+    /// Wait for a device to be connected to or disconnected from the port.
+    async fn wait_for_device_event(&self) -> DeviceEvent;
+
+    /// Reset the port, after which the connected device answers on address 0.
+    async fn bus_reset(&self);
+
+    /// Allocate a pipe to an endpoint of the connected device.
+    ///
+    /// The pipe is released when it is dropped.
+    fn alloc_pipe(&self, info: PipeInfo) -> Result<Self::Pipe, HostError>;
+}
+
+/// Pipe to an endpoint of a device.
+///
+/// Data toggles are handled by the pipe, and NAKs are retried until the transfer completes, which
+/// makes reads of interrupt pipes wait for the device to have data.
+pub trait UsbPipe {
+    /// Get the endpoint the pipe is allocated for.
+    fn info(&self) -> &PipeInfo;
+
+    /// Perform a control transfer with an IN or no data stage, returning the length of the data
+    /// received into `buf`.
+    async fn control_in(&mut self, setup: &SetupPacket, buf: &mut [u8]) -> Result<usize, HostError>;
+
+    /// Perform a control transfer with an OUT or no data stage.
+    async fn control_out(&mut self, setup: &SetupPacket, data: &[u8]) -> Result<(), HostError>;
+
+    /// Read data from an IN pipe until `buf` is full or a short packet is received, returning the
+    /// length of the data.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, HostError>;
+
+    /// Write `data` to an OUT pipe, split into packets. A zero-length packet is sent if the data is
+    /// empty, but not after a full last packet.
+    async fn write(&mut self, data: &[u8]) -> Result<(), HostError>;
+}
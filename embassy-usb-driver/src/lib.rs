@@ -3,6 +3,9 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
+pub mod host;
+pub mod pd;
+
 /// Direction of USB traffic. Note that in the USB standard the direction is always indicated from
 /// the perspective of the host, which is backward for devices, but the standard directions are used
 /// for consistency.
@@ -27,7 +30,14 @@ pub enum EndpointType {
     /// Control endpoint. Used for device management. Only the host can initiate requests. Usually
     /// used only endpoint 0.
     Control = 0b00,
-    /// Isochronous endpoint. Used for time-critical unreliable data. Not implemented yet.
+    /// Isochronous endpoint. Used for time-critical unreliable data.
+    ///
+    /// Isochronous endpoints transfer at most one packet per service interval, which is a
+    /// number of frames (full-speed) or microframes (high-speed) given by the endpoint's
+    /// polling interval. There are no handshakes and no retries: a packet that is corrupted
+    /// or that is not ready when the host polls the endpoint is lost. Isochronous endpoints
+    /// never stall. See [`EndpointIn::write`] and [`EndpointOut::read`] for the scheduling of
+    /// packets, and [`Endpoint::frame_number`] for synchronizing with the bus.
     Isochronous = 0b01,
     /// Bulk endpoint. Used for large amounts of best-effort reliable data.
     Bulk = 0b10,
@@ -132,7 +142,7 @@ pub trait Driver<'a> {
     ///
     /// * `ep_type` - the endpoint's type.
     /// * `max_packet_size` - Maximum packet size in bytes.
-    /// * `interval_ms` - Polling interval parameter for interrupt endpoints.
+    /// * `interval_ms` - Polling interval parameter for interrupt and isochronous endpoints.
     fn alloc_endpoint_out(
         &mut self,
         ep_type: EndpointType,
@@ -150,7 +160,7 @@ pub trait Driver<'a> {
     ///
     /// * `ep_type` - the endpoint's type.
     /// * `max_packet_size` - Maximum packet size in bytes.
-    /// * `interval_ms` - Polling interval parameter for interrupt endpoints.
+    /// * `interval_ms` - Polling interval parameter for interrupt and isochronous endpoints.
     fn alloc_endpoint_in(
         &mut self,
         ep_type: EndpointType,
@@ -227,6 +237,25 @@ pub trait Endpoint {
 
     /// Wait for the endpoint to be enabled.
     async fn wait_enabled(&mut self);
+
+    /// Get the number of the current (micro)frame, from the last start-of-frame packet.
+    ///
+    /// For full-speed devices, this is the 11-bit frame number. For high-speed devices, the
+    /// frame number is shifted left by 3 bits and the lower 3 bits hold the microframe number.
+    ///
+    /// This is mostly useful for isochronous endpoints, for example to measure the rate of an
+    /// audio clock against the USB start-of-frame clock, and compute explicit feedback values.
+    ///
+    /// The default implementation just returns `Unsupported`. It is implemented by the `usb` driver
+    /// of `embassy-stm32` and by `embassy-usb-synopsys-otg`, and so by the drivers built on it such
+    /// as the `otg` driver of `embassy-stm32`. The nRF and RP drivers do not implement it yet.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - This driver doesn't provide access to the frame number.
+    fn frame_number(&self) -> Result<u16, Unsupported> {
+        Err(Unsupported)
+    }
 }
 
 /// OUT Endpoint trait.
@@ -235,6 +264,10 @@ pub trait EndpointOut: Endpoint {
     /// the packet.
     ///
     /// This should also clear any NAK flags and prepare the endpoint to receive the next packet.
+    ///
+    /// For isochronous endpoints, this returns the packet received in one service interval.
+    /// Packets received with errors are dropped by the driver, and packets received while the
+    /// previous one was not read yet may be dropped too. The host does not retry them.
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError>;
 }
 
@@ -348,6 +381,12 @@ pub trait ControlPipe {
 /// IN Endpoint trait.
 pub trait EndpointIn: Endpoint {
     /// Write a single packet of data to the endpoint.
+    ///
+    /// For isochronous endpoints, the packet is scheduled for the next service interval in
+    /// which the host polls the endpoint, and this returns once it has been transmitted. If the
+    /// host doesn't poll the endpoint in that (micro)frame, the packet may be discarded without
+    /// an error. A zero-length `buf` sends an empty packet, which tells the host there is no
+    /// data for this interval.
     async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError>;
 }
 
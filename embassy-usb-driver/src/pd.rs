@@ -0,0 +1,42 @@
+//! USB Power Delivery PHY trait.
+//!
+//! A [`PdPhy`] sends and receives the Power Delivery messages of a port on its CC line, such as
+//! the UCPD peripheral of STM32 chips. The protocol layer, which handles `GoodCRC` messages,
+//! retries and message IDs, is left to the PD stack.
+
+/// Error returned by [`PdPhy::receive`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RxError {
+    /// Incorrect CRC or truncated message.
+    Crc,
+    /// The buffer was too small for the received message.
+    Overrun,
+    /// A hard reset was received before or during reception.
+    HardReset,
+}
+
+/// Error returned by [`PdPhy::transmit`] and [`PdPhy::transmit_hard_reset`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TxError {
+    /// The transmission was discarded, because of a concurrent reception or noise on the line.
+    Discarded,
+    /// A hard reset was received before or during transmission.
+    HardReset,
+}
+
+/// Power Delivery PHY, transmitting and receiving messages with the port partner.
+pub trait PdPhy {
+    /// Receive a message sent with the SOP ordered set, that is for the port partner, into `buf`.
+    ///
+    /// Messages for cable plugs are dropped. Returns the length of the message, header included
+    /// and CRC excluded.
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, RxError>;
+
+    /// Transmit a message with the SOP ordered set. `msg` includes the header, and not the CRC.
+    async fn transmit(&mut self, msg: &[u8]) -> Result<(), TxError>;
+
+    /// Transmit a hard reset signal.
+    async fn transmit_hard_reset(&mut self) -> Result<(), TxError>;
+}
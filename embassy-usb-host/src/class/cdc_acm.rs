@@ -0,0 +1,269 @@
+//! CDC-ACM class driver, for USB serial ports.
+
+use crate::descriptor::ConfigurationDescriptor;
+use crate::driver::host::{SetupPacket, UsbHostDriver, UsbPipe};
+use crate::driver::{Direction, EndpointType};
+use crate::{request_type, Device, Error};
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// Number of stop bits for LineCoding
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StopBits {
+    /// 1 stop bit
+    One = 0,
+    /// 1.5 stop bits
+    OnePointFive = 1,
+    /// 2 stop bits
+    Two = 2,
+}
+
+/// Parity for LineCoding
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParityType {
+    /// No parity bit.
+    None = 0,
+    /// Parity bit is 1 if the amount of `1` bits in the data byte is odd.
+    Odd = 1,
+    /// Parity bit is 1 if the amount of `1` bits in the data byte is even.
+    Even = 2,
+    /// Parity bit is always 1
+    Mark = 3,
+    /// Parity bit is always 0
+    Space = 4,
+}
+
+/// Line coding parameters of the serial port.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LineCoding {
+    /// Data rate in bits per second.
+    pub data_rate: u32,
+    /// Number of stop bits.
+    pub stop_bits: StopBits,
+    /// Parity type.
+    pub parity_type: ParityType,
+    /// Number of data bits: 5, 6, 7, 8 or 16.
+    pub data_bits: u8,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self {
+            data_rate: 115_200,
+            stop_bits: StopBits::One,
+            parity_type: ParityType::None,
+            data_bits: 8,
+        }
+    }
+}
+
+/// Driver for a CDC-ACM serial port.
+pub struct CdcAcmHost<D: UsbHostDriver> {
+    read_pipe: D::Pipe,
+    write_pipe: D::Pipe,
+    comm_interface: u8,
+}
+
+impl<D: UsbHostDriver> CdcAcmHost<D> {
+    /// Set up the first serial port of the configuration.
+    ///
+    /// The configuration must be selected with [`Device::set_configuration`].
+    pub async fn new(device: &mut Device<'_, D>, config: &ConfigurationDescriptor<'_>) -> Result<Self, Error> {
+        let comm = config
+            .find_interface(USB_CLASS_CDC, Some(CDC_SUBCLASS_ACM), None)
+            .ok_or(Error::InterfaceNotFound)?;
+        // The union functional descriptor names the data interface, which usually follows.
+        let data_number = comm
+            .descriptors()
+            .find(|d| d.len() >= 5 && d[1] == CS_INTERFACE && d[2] == CDC_TYPE_UNION)
+            .map_or(comm.interface_number + 1, |d| d[4]);
+        let data = config
+            .interfaces()
+            .find(|i| i.interface_number == data_number && i.interface_class == USB_CLASS_CDC_DATA)
+            .ok_or(Error::InterfaceNotFound)?;
+        let read_ep = data
+            .find_endpoint(EndpointType::Bulk, Direction::In)
+            .ok_or(Error::InvalidDescriptor)?;
+        let write_ep = data
+            .find_endpoint(EndpointType::Bulk, Direction::Out)
+            .ok_or(Error::InvalidDescriptor)?;
+
+        Ok(Self {
+            read_pipe: device.alloc_pipe(&read_ep)?,
+            write_pipe: device.alloc_pipe(&write_ep)?,
+            comm_interface: comm.interface_number,
+        })
+    }
+
+    /// Gets the maximum packet size of the data endpoints.
+    pub fn max_packet_size(&self) -> u16 {
+        self.read_pipe.info().max_packet_size
+    }
+
+    /// Set the line coding of the serial port.
+    pub async fn set_line_coding(&mut self, device: &mut Device<'_, D>, coding: &LineCoding) -> Result<(), Error> {
+        let mut data = [0; 7];
+        data[..4].copy_from_slice(&coding.data_rate.to_le_bytes());
+        data[4] = coding.stop_bits as u8;
+        data[5] = coding.parity_type as u8;
+        data[6] = coding.data_bits;
+        let setup = self.class_request(REQ_SET_LINE_CODING, 0, data.len() as u16);
+        device.control_out(&setup, &data).await?;
+        Ok(())
+    }
+
+    /// Set the DTR (data terminal ready) and RTS (request to send) signals.
+    pub async fn set_control_line_state(
+        &mut self,
+        device: &mut Device<'_, D>,
+        dtr: bool,
+        rts: bool,
+    ) -> Result<(), Error> {
+        let value = dtr as u16 | (rts as u16) << 1;
+        let setup = self.class_request(REQ_SET_CONTROL_LINE_STATE, value, 0);
+        device.control_out(&setup, &[]).await?;
+        Ok(())
+    }
+
+    /// Read data from the device, until `buf` is full or the device ends the transfer.
+    ///
+    /// `buf` should be a multiple of the max packet size.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.read_pipe.read(buf).await?)
+    }
+
+    /// Write data to the device.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.write_pipe.write(data).await?;
+        if !data.is_empty() && data.len() % self.write_pipe.info().max_packet_size as usize == 0 {
+            // End the transfer with a zero-length packet.
+            self.write_pipe.write(&[]).await?;
+        }
+        Ok(())
+    }
+
+    fn class_request(&self, request: u8, value: u16, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: request_type::CLASS | request_type::INTERFACE,
+            request,
+            value,
+            index: self.comm_interface as u16,
+            length,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::driver::host::HostError;
+    use crate::mock::{self, Function, MockHost};
+    use crate::UsbHost;
+
+    #[rustfmt::skip]
+    const CONFIG: [u8; 62] = [
+        9, 2, 62, 0, 3, 1, 0, 0x80, 50,
+        // Communication interface, with the union descriptor naming interface 2
+        9, 4, 0, 0, 1, 2, 2, 1, 0,
+        5, 0x24, 0x06, 0, 2,
+        7, 5, 0x83, 3, 8, 0, 255,
+        // A decoy data interface, then the real one
+        9, 4, 1, 0, 0, 0x0a, 0, 0, 0,
+        9, 4, 2, 0, 2, 0x0a, 0, 0, 0,
+        7, 5, 0x81, 2, 64, 0, 0,
+        7, 5, 0x02, 2, 64, 0, 0,
+    ];
+
+    #[derive(Default)]
+    struct Loopback {
+        line_coding: Vec<u8>,
+        control_line_state: u16,
+        data: VecDeque<u8>,
+        zero_length_packets: usize,
+    }
+
+    impl Function for Loopback {
+        fn control_out(&mut self, setup: &SetupPacket, data: &[u8]) -> Result<(), HostError> {
+            match (setup.request_type, setup.request, setup.index) {
+                (0x21, REQ_SET_LINE_CODING, 0) => self.line_coding = data.into(),
+                (0x21, REQ_SET_CONTROL_LINE_STATE, 0) => self.control_line_state = setup.value,
+                _ => return Err(HostError::Stall),
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, ep: u8, buf: &mut [u8]) -> Result<usize, HostError> {
+            assert_eq!(ep, 0x81);
+            if self.data.is_empty() {
+                return Err(HostError::Timeout);
+            }
+            let n = buf.len().min(self.data.len());
+            for (b, d) in buf.iter_mut().zip(self.data.drain(..n)) {
+                *b = d;
+            }
+            Ok(n)
+        }
+
+        fn write(&mut self, ep: u8, data: &[u8]) -> Result<(), HostError> {
+            assert_eq!(ep, 0x02);
+            if data.is_empty() {
+                self.zero_length_packets += 1;
+            }
+            self.data.extend(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        block_on(async {
+            let host = UsbHost::new(MockHost::new(mock::device_descriptor(64), &CONFIG, Loopback::default()));
+            let mut device = mock::enumerate(&host).await;
+            let mut buf = [0; 64];
+            let config = device.configuration_descriptor(0, &mut buf).await.unwrap();
+            let mut serial = CdcAcmHost::new(&mut device, &config).await.unwrap();
+            assert_eq!(serial.max_packet_size(), 64);
+
+            let coding = LineCoding {
+                data_rate: 9600,
+                parity_type: ParityType::Even,
+                ..Default::default()
+            };
+            serial.set_line_coding(&mut device, &coding).await.unwrap();
+            serial.set_control_line_state(&mut device, true, false).await.unwrap();
+            {
+                let device = host.driver().device.borrow();
+                assert_eq!(device.function.line_coding, [0x80, 0x25, 0, 0, 0, 2, 8]);
+                assert_eq!(device.function.control_line_state, 1);
+            }
+
+            serial.write(b"hello").await.unwrap();
+            let data: Vec<u8> = (0..64).collect();
+            serial.write(&data).await.unwrap();
+            assert_eq!(host.driver().device.borrow().function.zero_length_packets, 1);
+
+            let mut buf = [0; 128];
+            assert_eq!(serial.read(&mut buf).await, Ok(69));
+            assert_eq!(&buf[..5], b"hello");
+            assert_eq!(&buf[5..69], &data[..]);
+        })
+    }
+}
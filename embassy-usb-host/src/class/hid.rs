@@ -0,0 +1,257 @@
+//! HID class driver for keyboards and mice, using the boot protocol.
+//!
+//! The boot protocol has fixed report formats, so reports can be used without parsing the report
+//! descriptor of the device.
+
+use crate::descriptor::ConfigurationDescriptor;
+use crate::driver::host::{HostError, SetupPacket, UsbHostDriver, UsbPipe};
+use crate::driver::{Direction, EndpointType};
+use crate::{request_type, Device, Error};
+
+const USB_CLASS_HID: u8 = 0x03;
+const HID_SUBCLASS_BOOT: u8 = 0x01;
+
+const REQ_SET_REPORT: u8 = 0x09;
+const REQ_SET_IDLE: u8 = 0x0a;
+const REQ_SET_PROTOCOL: u8 = 0x0b;
+
+const REPORT_TYPE_OUTPUT: u16 = 0x02;
+const PROTOCOL_BOOT: u16 = 0;
+
+/// Boot protocol of an interface.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BootProtocol {
+    /// Keyboard.
+    Keyboard = 1,
+    /// Mouse.
+    Mouse = 2,
+}
+
+/// Keyboard boot report.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardReport {
+    /// Modifier keys: left control, shift, alt, GUI, then the right ones.
+    pub modifiers: u8,
+    /// Usage IDs of the pressed keys, 0 for none.
+    pub keys: [u8; 6],
+}
+
+impl KeyboardReport {
+    /// Whether the key with the usage ID `key` is pressed.
+    pub fn is_pressed(&self, key: u8) -> bool {
+        key != 0 && self.keys.contains(&key)
+    }
+}
+
+/// Mouse boot report.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseReport {
+    /// Buttons, the first one in bit 0.
+    pub buttons: u8,
+    /// Relative horizontal movement.
+    pub x: i8,
+    /// Relative vertical movement.
+    pub y: i8,
+    /// Relative wheel movement, 0 if the mouse does not report it.
+    pub wheel: i8,
+}
+
+/// Keyboard LEDs set by [`HidBoot::set_leds`].
+pub mod leds {
+    /// Num lock.
+    pub const NUM_LOCK: u8 = 0x01;
+    /// Caps lock.
+    pub const CAPS_LOCK: u8 = 0x02;
+    /// Scroll lock.
+    pub const SCROLL_LOCK: u8 = 0x04;
+}
+
+/// Driver for a keyboard or mouse interface, in boot protocol.
+pub struct HidBoot<D: UsbHostDriver> {
+    pipe: D::Pipe,
+    interface: u8,
+    protocol: BootProtocol,
+}
+
+impl<D: UsbHostDriver> HidBoot<D> {
+    /// Set up the first keyboard or mouse interface of the configuration, `protocol` choosing which.
+    ///
+    /// The configuration must be selected with [`Device::set_configuration`].
+    pub async fn new(
+        device: &mut Device<'_, D>,
+        config: &ConfigurationDescriptor<'_>,
+        protocol: BootProtocol,
+    ) -> Result<Self, Error> {
+        let iface = config
+            .find_interface(USB_CLASS_HID, Some(HID_SUBCLASS_BOOT), Some(protocol as u8))
+            .ok_or(Error::InterfaceNotFound)?;
+        let ep = iface
+            .find_endpoint(EndpointType::Interrupt, Direction::In)
+            .ok_or(Error::InvalidDescriptor)?;
+        let interface = iface.interface_number;
+
+        let setup = class_request(REQ_SET_PROTOCOL, PROTOCOL_BOOT, interface, 0);
+        device.control_out(&setup, &[]).await?;
+        // Only report changes. SET_IDLE is optional for mice, which may stall it.
+        let setup = class_request(REQ_SET_IDLE, 0, interface, 0);
+        match device.control_out(&setup, &[]).await {
+            Ok(()) | Err(HostError::Stall) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self {
+            pipe: device.alloc_pipe(&ep)?,
+            interface,
+            protocol,
+        })
+    }
+
+    /// Number of the interface.
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+
+    /// Boot protocol of the interface.
+    pub fn protocol(&self) -> BootProtocol {
+        self.protocol
+    }
+
+    /// Wait for a keyboard report.
+    pub async fn read_keyboard(&mut self) -> Result<KeyboardReport, Error> {
+        assert_eq!(self.protocol, BootProtocol::Keyboard);
+        let mut buf = [0; 64];
+        let n = self.read(&mut buf).await?;
+        if n < 8 {
+            return Err(Error::InvalidResponse);
+        }
+        let mut keys = [0; 6];
+        keys.copy_from_slice(&buf[2..8]);
+        Ok(KeyboardReport {
+            modifiers: buf[0],
+            keys,
+        })
+    }
+
+    /// Wait for a mouse report.
+    pub async fn read_mouse(&mut self) -> Result<MouseReport, Error> {
+        assert_eq!(self.protocol, BootProtocol::Mouse);
+        let mut buf = [0; 64];
+        let n = self.read(&mut buf).await?;
+        if n < 3 {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(MouseReport {
+            buttons: buf[0],
+            x: buf[1] as i8,
+            y: buf[2] as i8,
+            wheel: if n > 3 { buf[3] as i8 } else { 0 },
+        })
+    }
+
+    /// Set the keyboard LEDs, a combination of the [`leds`] bits.
+    pub async fn set_leds(&mut self, device: &mut Device<'_, D>, leds: u8) -> Result<(), Error> {
+        let setup = class_request(REQ_SET_REPORT, REPORT_TYPE_OUTPUT << 8, self.interface, 1);
+        device.control_out(&setup, &[leds]).await?;
+        Ok(())
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        // Devices may send reports longer than the boot report, with their max packet size.
+        let mps = (self.pipe.info().max_packet_size as usize).clamp(1, buf.len());
+        Ok(self.pipe.read(&mut buf[..mps]).await?)
+    }
+}
+
+fn class_request(request: u8, value: u16, interface: u8, length: u16) -> SetupPacket {
+    SetupPacket {
+        request_type: request_type::CLASS | request_type::INTERFACE,
+        request,
+        value,
+        index: interface as u16,
+        length,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::collections::VecDeque;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::mock::{self, Function, MockHost};
+    use crate::UsbHost;
+
+    #[rustfmt::skip]
+    const CONFIG: [u8; 34] = [
+        9, 2, 34, 0, 1, 1, 0, 0x80, 50,
+        9, 4, 0, 0, 1, 3, 1, 1, 0,
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+        7, 5, 0x81, 3, 8, 0, 10,
+    ];
+
+    #[derive(Default)]
+    struct Keyboard {
+        protocol: Option<u16>,
+        idle: Option<u16>,
+        leds: u8,
+        reports: VecDeque<[u8; 8]>,
+    }
+
+    impl Function for Keyboard {
+        fn control_out(&mut self, setup: &SetupPacket, data: &[u8]) -> Result<(), HostError> {
+            match (setup.request_type, setup.request, setup.value) {
+                (0x21, REQ_SET_PROTOCOL, value) => self.protocol = Some(value),
+                (0x21, REQ_SET_IDLE, value) => self.idle = Some(value),
+                (0x21, REQ_SET_REPORT, 0x0200) => self.leds = data[0],
+                _ => return Err(HostError::Stall),
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, ep: u8, buf: &mut [u8]) -> Result<usize, HostError> {
+            assert_eq!((ep, buf.len()), (0x81, 8));
+            let report = self.reports.pop_front().ok_or(HostError::Timeout)?;
+            buf.copy_from_slice(&report);
+            Ok(report.len())
+        }
+    }
+
+    #[test]
+    fn keyboard() {
+        block_on(async {
+            let mut keyboard = Keyboard::default();
+            keyboard.reports.push_back([0x02, 0, 0x04, 0x05, 0, 0, 0, 0]);
+            let host = UsbHost::new(MockHost::new(mock::device_descriptor(8), &CONFIG, keyboard));
+            let mut device = mock::enumerate(&host).await;
+            let mut buf = [0; 64];
+            let config = device.configuration_descriptor(0, &mut buf).await.unwrap();
+
+            let result = HidBoot::new(&mut device, &config, BootProtocol::Mouse).await;
+            assert_eq!(result.err(), Some(Error::InterfaceNotFound));
+            let mut hid = HidBoot::new(&mut device, &config, BootProtocol::Keyboard)
+                .await
+                .unwrap();
+            assert_eq!(hid.interface(), 0);
+            {
+                let device = host.driver().device.borrow();
+                assert_eq!(device.function.protocol, Some(PROTOCOL_BOOT));
+                assert_eq!(device.function.idle, Some(0));
+            }
+
+            let report = hid.read_keyboard().await.unwrap();
+            assert_eq!(report.modifiers, 0x02);
+            assert!(report.is_pressed(0x04) && report.is_pressed(0x05));
+            assert!(!report.is_pressed(0x06) && !report.is_pressed(0));
+            assert_eq!(hid.read_keyboard().await, Err(Error::Transfer(HostError::Timeout)));
+
+            hid.set_leds(&mut device, leds::CAPS_LOCK).await.unwrap();
+            assert_eq!(host.driver().device.borrow().function.leds, leds::CAPS_LOCK);
+        })
+    }
+}
@@ -0,0 +1,4 @@
+//! Drivers for well-known USB device classes.
+pub mod cdc_acm;
+pub mod hid;
+pub mod msc;
@@ -0,0 +1,556 @@
+//! Mass Storage Class driver, with the Bulk-Only Transport and the SCSI transparent command set.
+
+use crate::descriptor::{ConfigurationDescriptor, EndpointDescriptor};
+use crate::driver::host::{HostError, SetupPacket, UsbHostDriver, UsbPipe};
+use crate::driver::{Direction, EndpointType};
+use crate::{request_type, Device, Error};
+
+const USB_CLASS_MSC: u8 = 0x08;
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+const REQ_GET_MAX_LUN: u8 = 0xfe;
+const REQ_BULK_ONLY_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+
+/// Standard INQUIRY data of a logical unit.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Inquiry {
+    /// Peripheral device type, 0 for block devices.
+    pub device_type: u8,
+    /// Whether the medium is removable.
+    pub removable: bool,
+    /// Vendor identification, padded with spaces.
+    pub vendor: [u8; 8],
+    /// Product identification, padded with spaces.
+    pub product: [u8; 16],
+    /// Product revision, padded with spaces.
+    pub revision: [u8; 4],
+}
+
+/// Capacity of a logical unit.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capacity {
+    /// Number of blocks.
+    pub block_count: u32,
+    /// Size of a block in bytes.
+    pub block_size: u32,
+}
+
+/// Sense data, describing why the last command failed.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sense {
+    /// Sense key.
+    pub key: u8,
+    /// Additional sense code.
+    pub asc: u8,
+    /// Additional sense code qualifier.
+    pub ascq: u8,
+}
+
+/// Data stage of a command.
+enum Data<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+/// Driver for a mass storage device.
+pub struct MscHost<D: UsbHostDriver> {
+    read_pipe: D::Pipe,
+    write_pipe: D::Pipe,
+    read_ep: EndpointDescriptor,
+    write_ep: EndpointDescriptor,
+    interface: u8,
+    max_lun: u8,
+    tag: u32,
+}
+
+impl<D: UsbHostDriver> MscHost<D> {
+    /// Set up the first mass storage interface of the configuration.
+    ///
+    /// The configuration must be selected with [`Device::set_configuration`].
+    pub async fn new(device: &mut Device<'_, D>, config: &ConfigurationDescriptor<'_>) -> Result<Self, Error> {
+        let iface = config
+            .find_interface(USB_CLASS_MSC, Some(MSC_SUBCLASS_SCSI), Some(MSC_PROTOCOL_BOT))
+            .ok_or(Error::InterfaceNotFound)?;
+        let read_ep = iface
+            .find_endpoint(EndpointType::Bulk, Direction::In)
+            .ok_or(Error::InvalidDescriptor)?;
+        let write_ep = iface
+            .find_endpoint(EndpointType::Bulk, Direction::Out)
+            .ok_or(Error::InvalidDescriptor)?;
+        let interface = iface.interface_number;
+
+        // Devices with a single logical unit may stall GET MAX LUN.
+        let setup = SetupPacket {
+            request_type: request_type::DIR_IN | request_type::CLASS | request_type::INTERFACE,
+            request: REQ_GET_MAX_LUN,
+            value: 0,
+            index: interface as u16,
+            length: 1,
+        };
+        let mut buf = [0; 1];
+        let max_lun = match device.control_in(&setup, &mut buf).await {
+            Ok(1) => buf[0].min(15),
+            Ok(_) | Err(HostError::Stall) => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            read_pipe: device.alloc_pipe(&read_ep)?,
+            write_pipe: device.alloc_pipe(&write_ep)?,
+            read_ep,
+            write_ep,
+            interface,
+            max_lun,
+            tag: 0,
+        })
+    }
+
+    /// Highest logical unit number of the device.
+    pub fn max_lun(&self) -> u8 {
+        self.max_lun
+    }
+
+    /// Read the INQUIRY data of a logical unit.
+    pub async fn inquiry(&mut self, device: &mut Device<'_, D>, lun: u8) -> Result<Inquiry, Error> {
+        let mut buf = [0; 36];
+        let n = self
+            .command(device, lun, &[INQUIRY, 0, 0, 0, 36, 0], Data::In(&mut buf))
+            .await?;
+        if n < buf.len() {
+            return Err(Error::InvalidResponse);
+        }
+        let mut inquiry = Inquiry {
+            device_type: buf[0] & 0x1f,
+            removable: buf[1] & 0x80 != 0,
+            vendor: [0; 8],
+            product: [0; 16],
+            revision: [0; 4],
+        };
+        inquiry.vendor.copy_from_slice(&buf[8..16]);
+        inquiry.product.copy_from_slice(&buf[16..32]);
+        inquiry.revision.copy_from_slice(&buf[32..36]);
+        Ok(inquiry)
+    }
+
+    /// Check whether a logical unit is ready, failing with [`Error::CommandFailed`] if it is not,
+    /// for example because its medium is not present.
+    pub async fn test_unit_ready(&mut self, device: &mut Device<'_, D>, lun: u8) -> Result<(), Error> {
+        self.command(device, lun, &[TEST_UNIT_READY, 0, 0, 0, 0, 0], Data::None)
+            .await?;
+        Ok(())
+    }
+
+    /// Read the sense data of a logical unit, describing why its last command failed.
+    pub async fn request_sense(&mut self, device: &mut Device<'_, D>, lun: u8) -> Result<Sense, Error> {
+        let mut buf = [0; 18];
+        let n = self
+            .command(device, lun, &[REQUEST_SENSE, 0, 0, 0, 18, 0], Data::In(&mut buf))
+            .await?;
+        if n < 14 {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(Sense {
+            key: buf[2] & 0x0f,
+            asc: buf[12],
+            ascq: buf[13],
+        })
+    }
+
+    /// Read the capacity of a logical unit.
+    pub async fn read_capacity(&mut self, device: &mut Device<'_, D>, lun: u8) -> Result<Capacity, Error> {
+        let mut buf = [0; 8];
+        let cb = [READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let n = self.command(device, lun, &cb, Data::In(&mut buf)).await?;
+        if n < buf.len() {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(Capacity {
+            block_count: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]).wrapping_add(1),
+            block_size: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        })
+    }
+
+    /// Read `blocks` blocks starting at `lba` into `buf`, whose length is their total size.
+    pub async fn read_blocks(
+        &mut self,
+        device: &mut Device<'_, D>,
+        lun: u8,
+        lba: u32,
+        blocks: u16,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let cb = rw_command(READ_10, lba, blocks);
+        let n = self.command(device, lun, &cb, Data::In(buf)).await?;
+        match n == buf.len() {
+            true => Ok(()),
+            false => Err(Error::InvalidResponse),
+        }
+    }
+
+    /// Write `data` to `blocks` blocks starting at `lba`, its length being their total size.
+    pub async fn write_blocks(
+        &mut self,
+        device: &mut Device<'_, D>,
+        lun: u8,
+        lba: u32,
+        blocks: u16,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let cb = rw_command(WRITE_10, lba, blocks);
+        let n = self.command(device, lun, &cb, Data::Out(data)).await?;
+        match n == data.len() {
+            true => Ok(()),
+            false => Err(Error::InvalidResponse),
+        }
+    }
+
+    /// Run a SCSI command, returning the length of the data transferred.
+    async fn command(
+        &mut self,
+        device: &mut Device<'_, D>,
+        lun: u8,
+        cb: &[u8],
+        data: Data<'_>,
+    ) -> Result<usize, Error> {
+        assert!(lun <= self.max_lun, "invalid logical unit");
+        self.tag = self.tag.wrapping_add(1);
+        let (len, dir_in) = match &data {
+            Data::None => (0, false),
+            Data::In(buf) => (buf.len(), true),
+            Data::Out(data) => (data.len(), false),
+        };
+
+        let mut cbw = [0; CBW_LEN];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        cbw[12] = if dir_in { 0x80 } else { 0x00 };
+        cbw[13] = lun;
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        if let Err(e) = self.write_pipe.write(&cbw).await {
+            // The device did not accept the command: recover it.
+            warn!("Mass storage command block wrapper failed: {:?}", e);
+            self.reset_recovery(device).await?;
+            return Err(e.into());
+        }
+
+        // A stalled data stage is followed by the status.
+        let result = match data {
+            Data::None => Ok(()),
+            Data::In(buf) => self.read_pipe.read(buf).await.map(drop),
+            Data::Out(data) => self.write_pipe.write(data).await,
+        };
+        match result {
+            Ok(()) => {}
+            Err(HostError::Stall) => {
+                let ep = if dir_in { self.read_ep } else { self.write_ep };
+                device.clear_halt(ep.address.into()).await?;
+                self.realloc_pipe(device, dir_in)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut csw = [0; CSW_LEN];
+        let n = match self.read_pipe.read(&mut csw).await {
+            // Retry once after the endpoint stalled.
+            Err(HostError::Stall) => {
+                device.clear_halt(self.read_ep.address.into()).await?;
+                self.realloc_pipe(device, true)?;
+                self.read_pipe.read(&mut csw).await?
+            }
+            n => n?,
+        };
+
+        let signature = u32::from_le_bytes([csw[0], csw[1], csw[2], csw[3]]);
+        let tag = u32::from_le_bytes([csw[4], csw[5], csw[6], csw[7]]);
+        if n != CSW_LEN || signature != CSW_SIGNATURE || tag != self.tag || csw[12] == 2 {
+            warn!("Invalid mass storage command status, resetting");
+            self.reset_recovery(device).await?;
+            return Err(Error::InvalidResponse);
+        }
+        let residue = u32::from_le_bytes([csw[8], csw[9], csw[10], csw[11]]) as usize;
+        match csw[12] {
+            0 => Ok(len.saturating_sub(residue)),
+            _ => Err(Error::CommandFailed),
+        }
+    }
+
+    /// Reset the device after a protocol error.
+    async fn reset_recovery(&mut self, device: &mut Device<'_, D>) -> Result<(), Error> {
+        let setup = SetupPacket {
+            request_type: request_type::CLASS | request_type::INTERFACE,
+            request: REQ_BULK_ONLY_RESET,
+            value: 0,
+            index: self.interface as u16,
+            length: 0,
+        };
+        device.control_out(&setup, &[]).await?;
+        device.clear_halt(self.read_ep.address.into()).await?;
+        device.clear_halt(self.write_ep.address.into()).await?;
+        self.realloc_pipe(device, true)?;
+        self.realloc_pipe(device, false)?;
+        Ok(())
+    }
+
+    /// Allocate a pipe again, to reset its data toggle after the endpoint was halted.
+    fn realloc_pipe(&mut self, device: &Device<'_, D>, dir_in: bool) -> Result<(), HostError> {
+        match dir_in {
+            true => self.read_pipe = device.alloc_pipe(&self.read_ep)?,
+            false => self.write_pipe = device.alloc_pipe(&self.write_ep)?,
+        }
+        Ok(())
+    }
+}
+
+fn rw_command(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let lba = lba.to_be_bytes();
+    let blocks = blocks.to_be_bytes();
+    [opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, blocks[0], blocks[1], 0]
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+    use std::{mem, vec};
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::mock::{self, Function, MockHost};
+    use crate::UsbHost;
+
+    #[rustfmt::skip]
+    const CONFIG: [u8; 32] = [
+        9, 2, 32, 0, 1, 1, 0, 0x80, 50,
+        9, 4, 0, 0, 2, 8, 6, 0x50, 0,
+        7, 5, 0x81, 2, 64, 0, 0,
+        7, 5, 0x02, 2, 64, 0, 0,
+    ];
+
+    const BLOCK_SIZE: usize = 512;
+    const BLOCK_COUNT: usize = 16;
+
+    enum Stage {
+        Command,
+        DataIn(Vec<u8>, [u8; CSW_LEN]),
+        DataOut { offset: usize, len: usize, tag: u32 },
+        Status([u8; CSW_LEN]),
+    }
+
+    /// Bulk-only RAM disk with a single logical unit, stalling GET MAX LUN.
+    struct RamDisk {
+        data: Vec<u8>,
+        stage: Stage,
+        halted_in: bool,
+        halted_out: bool,
+        sense: (u8, u8),
+    }
+
+    impl RamDisk {
+        fn new() -> Self {
+            Self {
+                data: vec![0; BLOCK_SIZE * BLOCK_COUNT],
+                stage: Stage::Command,
+                halted_in: false,
+                halted_out: false,
+                sense: (0, 0),
+            }
+        }
+
+        fn command(&mut self, cbw: &[u8]) -> Stage {
+            assert_eq!(cbw.len(), CBW_LEN);
+            assert_eq!(cbw[..4], CBW_SIGNATURE.to_le_bytes());
+            let tag = u32::from_le_bytes([cbw[4], cbw[5], cbw[6], cbw[7]]);
+            let len = u32::from_le_bytes([cbw[8], cbw[9], cbw[10], cbw[11]]) as usize;
+            let dir_in = cbw[12] & 0x80 != 0;
+            assert_eq!(cbw[13], 0);
+            let cb = &cbw[15..15 + cbw[14] as usize];
+
+            let data_in = |mut data: Vec<u8>| {
+                data.truncate(len);
+                let csw = csw(tag, len - data.len(), 0);
+                Stage::DataIn(data, csw)
+            };
+            match cb[0] {
+                TEST_UNIT_READY => return Stage::Status(csw(tag, 0, 0)),
+                REQUEST_SENSE => {
+                    let mut sense = vec![0; 18];
+                    sense[0] = 0x70;
+                    sense[2] = self.sense.0;
+                    sense[7] = 10;
+                    sense[12] = self.sense.1;
+                    self.sense = (0, 0);
+                    return data_in(sense);
+                }
+                INQUIRY => {
+                    let mut inquiry = vec![0, 0x80, 4, 2, 31, 0, 0, 0];
+                    inquiry.extend(b"Embassy RAM disk        0.1 ");
+                    return data_in(inquiry);
+                }
+                READ_CAPACITY_10 => {
+                    let mut capacity = (BLOCK_COUNT as u32 - 1).to_be_bytes().to_vec();
+                    capacity.extend((BLOCK_SIZE as u32).to_be_bytes());
+                    return data_in(capacity);
+                }
+                READ_10 | WRITE_10 => {
+                    let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as usize;
+                    let blocks = u16::from_be_bytes([cb[7], cb[8]]) as usize;
+                    assert_eq!(len, blocks * BLOCK_SIZE);
+                    if lba + blocks <= BLOCK_COUNT {
+                        let offset = lba * BLOCK_SIZE;
+                        return match cb[0] {
+                            READ_10 => data_in(self.data[offset..offset + len].to_vec()),
+                            _ => Stage::DataOut { offset, len, tag },
+                        };
+                    }
+                    // Logical block address out of range.
+                    self.sense = (5, 0x21);
+                }
+                // Invalid command operation code.
+                _ => self.sense = (5, 0x20),
+            }
+            if len > 0 {
+                self.halted_in |= dir_in;
+                self.halted_out |= !dir_in;
+            }
+            Stage::Status(csw(tag, len, 1))
+        }
+    }
+
+    fn csw(tag: u32, residue: usize, status: u8) -> [u8; CSW_LEN] {
+        let mut csw = [0; CSW_LEN];
+        csw[..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&(residue as u32).to_le_bytes());
+        csw[12] = status;
+        csw
+    }
+
+    impl Function for RamDisk {
+        fn control_out(&mut self, setup: &SetupPacket, _data: &[u8]) -> Result<(), HostError> {
+            match (setup.request_type, setup.request, setup.index) {
+                (0x21, REQ_BULK_ONLY_RESET, 0) => self.stage = Stage::Command,
+                _ => return Err(HostError::Stall),
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, ep: u8, buf: &mut [u8]) -> Result<usize, HostError> {
+            assert_eq!(ep, 0x81);
+            if self.halted_in {
+                return Err(HostError::Stall);
+            }
+            let (data, next) = match mem::replace(&mut self.stage, Stage::Command) {
+                Stage::DataIn(data, csw) => (data, Stage::Status(csw)),
+                Stage::Status(csw) => (csw.to_vec(), Stage::Command),
+                stage => {
+                    self.stage = stage;
+                    return Err(HostError::Timeout);
+                }
+            };
+            assert!(buf.len() >= data.len());
+            buf[..data.len()].copy_from_slice(&data);
+            self.stage = next;
+            Ok(data.len())
+        }
+
+        fn write(&mut self, ep: u8, data: &[u8]) -> Result<(), HostError> {
+            assert_eq!(ep, 0x02);
+            if self.halted_out {
+                return Err(HostError::Stall);
+            }
+            self.stage = match mem::replace(&mut self.stage, Stage::Command) {
+                Stage::Command => self.command(data),
+                Stage::DataOut { offset, len, tag } => {
+                    assert_eq!(data.len(), len);
+                    self.data[offset..offset + len].copy_from_slice(data);
+                    Stage::Status(csw(tag, 0, 0))
+                }
+                _ => panic!("unexpected data"),
+            };
+            Ok(())
+        }
+
+        fn clear_halt(&mut self, ep: u8) {
+            match ep {
+                0x81 => self.halted_in = false,
+                0x02 => self.halted_out = false,
+                _ => panic!("invalid endpoint"),
+            }
+        }
+    }
+
+    #[test]
+    fn ram_disk() {
+        block_on(async {
+            let host = UsbHost::new(MockHost::new(mock::device_descriptor(64), &CONFIG, RamDisk::new()));
+            let mut device = mock::enumerate(&host).await;
+            let mut buf = [0; 64];
+            let config = device.configuration_descriptor(0, &mut buf).await.unwrap();
+            let mut msc = MscHost::new(&mut device, &config).await.unwrap();
+            assert_eq!(msc.max_lun(), 0);
+
+            let inquiry = msc.inquiry(&mut device, 0).await.unwrap();
+            assert_eq!((inquiry.device_type, inquiry.removable), (0, true));
+            assert_eq!(&inquiry.vendor, b"Embassy ");
+            assert_eq!(&inquiry.product, b"RAM disk        ");
+            msc.test_unit_ready(&mut device, 0).await.unwrap();
+            let capacity = msc.read_capacity(&mut device, 0).await.unwrap();
+            assert_eq!(capacity.block_count as usize, BLOCK_COUNT);
+            assert_eq!(capacity.block_size as usize, BLOCK_SIZE);
+
+            let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+            msc.write_blocks(&mut device, 0, 3, 2, &data).await.unwrap();
+            let mut buf = [0; 2 * BLOCK_SIZE];
+            msc.read_blocks(&mut device, 0, 3, 2, &mut buf).await.unwrap();
+            assert_eq!(&buf[..], &data[..]);
+            msc.read_blocks(&mut device, 0, 4, 1, &mut buf[..BLOCK_SIZE])
+                .await
+                .unwrap();
+            assert_eq!(&buf[..BLOCK_SIZE], &data[BLOCK_SIZE..]);
+        })
+    }
+
+    #[test]
+    fn failed_commands() {
+        block_on(async {
+            let host = UsbHost::new(MockHost::new(mock::device_descriptor(64), &CONFIG, RamDisk::new()));
+            let mut device = mock::enumerate(&host).await;
+            let mut buf = [0; 64];
+            let config = device.configuration_descriptor(0, &mut buf).await.unwrap();
+            let mut msc = MscHost::new(&mut device, &config).await.unwrap();
+
+            // The device stalls the data stage, then reports the failure.
+            let mut buf = [0; BLOCK_SIZE];
+            let result = msc.read_blocks(&mut device, 0, BLOCK_COUNT as u32, 1, &mut buf).await;
+            assert_eq!(result, Err(Error::CommandFailed));
+            let sense = msc.request_sense(&mut device, 0).await.unwrap();
+            assert_eq!((sense.key, sense.asc, sense.ascq), (5, 0x21, 0));
+
+            let result = msc.write_blocks(&mut device, 0, BLOCK_COUNT as u32, 1, &buf).await;
+            assert_eq!(result, Err(Error::CommandFailed));
+            assert!(!host.driver().device.borrow().function.halted_out);
+            msc.test_unit_ready(&mut device, 0).await.unwrap();
+        })
+    }
+}
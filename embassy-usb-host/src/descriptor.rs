@@ -0,0 +1,334 @@
+//! Parsing of the descriptors read from devices.
+
+use crate::driver::{Direction, EndpointAddress, EndpointType};
+use crate::Error;
+
+/// Standard descriptor types.
+pub mod descriptor_type {
+    /// Device descriptor.
+    pub const DEVICE: u8 = 1;
+    /// Configuration descriptor.
+    pub const CONFIGURATION: u8 = 2;
+    /// String descriptor.
+    pub const STRING: u8 = 3;
+    /// Interface descriptor.
+    pub const INTERFACE: u8 = 4;
+    /// Endpoint descriptor.
+    pub const ENDPOINT: u8 = 5;
+    /// Interface association descriptor.
+    pub const INTERFACE_ASSOCIATION: u8 = 11;
+}
+
+/// Device descriptor.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceDescriptor {
+    /// USB specification release number, in BCD.
+    pub usb_version: u16,
+    /// Device class code.
+    pub device_class: u8,
+    /// Device sub-class code.
+    pub device_sub_class: u8,
+    /// Device protocol code.
+    pub device_protocol: u8,
+    /// Max packet size of endpoint 0.
+    pub max_packet_size0: u8,
+    /// Vendor ID.
+    pub vendor_id: u16,
+    /// Product ID.
+    pub product_id: u16,
+    /// Device release number, in BCD.
+    pub device_release: u16,
+    /// Index of the manufacturer string.
+    pub manufacturer: u8,
+    /// Index of the product string.
+    pub product: u8,
+    /// Index of the serial number string.
+    pub serial_number: u8,
+    /// Number of configurations.
+    pub num_configurations: u8,
+}
+
+/// Length of a device descriptor.
+pub const DEVICE_DESCRIPTOR_LEN: usize = 18;
+
+impl DeviceDescriptor {
+    /// Parse a device descriptor.
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < DEVICE_DESCRIPTOR_LEN || buf[1] != descriptor_type::DEVICE {
+            return Err(Error::InvalidDescriptor);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        Ok(Self {
+            usb_version: u16_at(2),
+            device_class: buf[4],
+            device_sub_class: buf[5],
+            device_protocol: buf[6],
+            max_packet_size0: buf[7],
+            vendor_id: u16_at(8),
+            product_id: u16_at(10),
+            device_release: u16_at(12),
+            manufacturer: buf[14],
+            product: buf[15],
+            serial_number: buf[16],
+            num_configurations: buf[17],
+        })
+    }
+}
+
+/// Iterator over the descriptors of a buffer, yielding each one as a slice starting with its length and type.
+#[derive(Clone)]
+pub struct Descriptors<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Descriptors<'a> {
+    /// Iterate over the descriptors of `buf`.
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let len = *self.buf.first()? as usize;
+        if len < 2 || len > self.buf.len() {
+            // Malformed, stop there.
+            self.buf = &[];
+            return None;
+        }
+        let (descriptor, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(descriptor)
+    }
+}
+
+/// Configuration descriptor, with the descriptors of its interfaces.
+#[derive(Copy, Clone, Debug)]
+pub struct ConfigurationDescriptor<'a> {
+    buf: &'a [u8],
+    /// Number of interfaces.
+    pub num_interfaces: u8,
+    /// Value to select this configuration with SET_CONFIGURATION.
+    pub configuration_value: u8,
+    /// Index of the configuration string.
+    pub configuration: u8,
+    /// Attributes: self-powered, remote wakeup.
+    pub attributes: u8,
+    /// Maximum power consumption, in 2 mA units.
+    pub max_power: u8,
+}
+
+/// Length of a configuration descriptor, without the descriptors of its interfaces.
+pub const CONFIGURATION_DESCRIPTOR_LEN: usize = 9;
+
+impl<'a> ConfigurationDescriptor<'a> {
+    /// Parse a configuration descriptor, with its `wTotalLength` bytes of interface descriptors.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, Error> {
+        if buf.len() < CONFIGURATION_DESCRIPTOR_LEN || buf[1] != descriptor_type::CONFIGURATION {
+            return Err(Error::InvalidDescriptor);
+        }
+        let total_length = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if total_length < CONFIGURATION_DESCRIPTOR_LEN || total_length > buf.len() {
+            return Err(Error::InvalidDescriptor);
+        }
+        Ok(Self {
+            buf: &buf[..total_length],
+            num_interfaces: buf[4],
+            configuration_value: buf[5],
+            configuration: buf[6],
+            attributes: buf[7],
+            max_power: buf[8],
+        })
+    }
+
+    /// Total length of the descriptors of the configuration.
+    pub fn total_length(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// All descriptors of the configuration, including the configuration descriptor itself.
+    pub fn descriptors(&self) -> Descriptors<'a> {
+        Descriptors::new(self.buf)
+    }
+
+    /// The interfaces and alternate settings of the configuration.
+    pub fn interfaces(&self) -> Interfaces<'a> {
+        Interfaces {
+            descriptors: self.descriptors(),
+        }
+    }
+
+    /// Find the interface matching the class, subclass and protocol codes, `None` matching any.
+    pub fn find_interface(
+        &self,
+        class: u8,
+        sub_class: Option<u8>,
+        protocol: Option<u8>,
+    ) -> Option<InterfaceDescriptor<'a>> {
+        self.interfaces().find(|i| {
+            i.interface_class == class
+                && sub_class.is_none_or(|s| s == i.interface_sub_class)
+                && protocol.is_none_or(|p| p == i.interface_protocol)
+        })
+    }
+}
+
+/// Iterator over the interfaces of a configuration.
+#[derive(Clone)]
+pub struct Interfaces<'a> {
+    descriptors: Descriptors<'a>,
+}
+
+impl<'a> Iterator for Interfaces<'a> {
+    type Item = InterfaceDescriptor<'a>;
+
+    fn next(&mut self) -> Option<InterfaceDescriptor<'a>> {
+        loop {
+            let descriptor = self.descriptors.next()?;
+            if descriptor[1] != descriptor_type::INTERFACE || descriptor.len() < 9 {
+                continue;
+            }
+            // The interface extends to the next interface descriptor.
+            let rest = self.descriptors.buf;
+            let len = Descriptors::new(rest)
+                .take_while(|d| d[1] != descriptor_type::INTERFACE && d[1] != descriptor_type::INTERFACE_ASSOCIATION)
+                .map(|d| d.len())
+                .sum();
+            return Some(InterfaceDescriptor {
+                extra: &rest[..len],
+                interface_number: descriptor[2],
+                alternate_setting: descriptor[3],
+                num_endpoints: descriptor[4],
+                interface_class: descriptor[5],
+                interface_sub_class: descriptor[6],
+                interface_protocol: descriptor[7],
+                interface: descriptor[8],
+            });
+        }
+    }
+}
+
+/// Interface descriptor, with the class-specific and endpoint descriptors that follow it.
+#[derive(Copy, Clone, Debug)]
+pub struct InterfaceDescriptor<'a> {
+    extra: &'a [u8],
+    /// Interface number.
+    pub interface_number: u8,
+    /// Alternate setting.
+    pub alternate_setting: u8,
+    /// Number of endpoints, excluding endpoint 0.
+    pub num_endpoints: u8,
+    /// Interface class code.
+    pub interface_class: u8,
+    /// Interface sub-class code.
+    pub interface_sub_class: u8,
+    /// Interface protocol code.
+    pub interface_protocol: u8,
+    /// Index of the interface string.
+    pub interface: u8,
+}
+
+impl<'a> InterfaceDescriptor<'a> {
+    /// The class-specific and endpoint descriptors following the interface descriptor.
+    pub fn descriptors(&self) -> Descriptors<'a> {
+        Descriptors::new(self.extra)
+    }
+
+    /// The endpoints of the interface.
+    pub fn endpoints(&self) -> impl Iterator<Item = EndpointDescriptor> + 'a {
+        self.descriptors()
+            .filter(|d| d[1] == descriptor_type::ENDPOINT)
+            .filter_map(|d| EndpointDescriptor::parse(d).ok())
+    }
+
+    /// Find the endpoint with the given transfer type and direction.
+    pub fn find_endpoint(&self, ep_type: EndpointType, direction: Direction) -> Option<EndpointDescriptor> {
+        self.endpoints()
+            .find(|e| e.ep_type() == ep_type && e.address.direction() == direction)
+    }
+}
+
+/// Endpoint descriptor.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EndpointDescriptor {
+    /// Endpoint address.
+    pub address: EndpointAddress,
+    /// Attributes, including the transfer type.
+    pub attributes: u8,
+    /// Max packet size, with the number of additional transactions per microframe in bits 11-12.
+    pub max_packet_size: u16,
+    /// Polling interval, in frames or as an exponent depending on the speed and transfer type.
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    /// Parse an endpoint descriptor.
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < 7 || buf[1] != descriptor_type::ENDPOINT {
+            return Err(Error::InvalidDescriptor);
+        }
+        Ok(Self {
+            address: buf[2].into(),
+            attributes: buf[3],
+            max_packet_size: u16::from_le_bytes([buf[4], buf[5]]),
+            interval: buf[6],
+        })
+    }
+
+    /// Transfer type of the endpoint.
+    pub fn ep_type(&self) -> EndpointType {
+        match self.attributes & 0x03 {
+            0 => EndpointType::Control,
+            1 => EndpointType::Isochronous,
+            2 => EndpointType::Bulk,
+            _ => EndpointType::Interrupt,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configuration() {
+        #[rustfmt::skip]
+        let buf = [
+            9, 2, 43, 0, 2, 1, 0, 0x80, 50,
+            // Interface 0 with a class descriptor and an endpoint
+            9, 4, 0, 0, 1, 3, 1, 1, 0,
+            9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+            7, 5, 0x81, 3, 8, 0, 10,
+            // Interface 1 with no endpoint
+            9, 4, 1, 0, 0, 0xff, 0, 0, 4,
+            // Trailing garbage, not part of the configuration
+            1, 2, 3,
+        ];
+        let config = ConfigurationDescriptor::parse(&buf).unwrap();
+        assert_eq!(config.total_length(), 43);
+        assert_eq!(config.configuration_value, 1);
+        assert_eq!(config.descriptors().count(), 5);
+
+        let mut interfaces = config.interfaces();
+        let hid = interfaces.next().unwrap();
+        assert_eq!((hid.interface_number, hid.interface_class), (0, 3));
+        assert_eq!(hid.descriptors().count(), 2);
+        let ep = hid.find_endpoint(EndpointType::Interrupt, Direction::In).unwrap();
+        assert_eq!((u8::from(ep.address), ep.max_packet_size, ep.interval), (0x81, 8, 10));
+        assert!(hid.find_endpoint(EndpointType::Bulk, Direction::In).is_none());
+        let vendor = interfaces.next().unwrap();
+        assert_eq!((vendor.interface_number, vendor.interface), (1, 4));
+        assert_eq!(vendor.endpoints().count(), 0);
+        assert!(interfaces.next().is_none());
+
+        assert_eq!(config.find_interface(0xff, None, None).unwrap().interface_number, 1);
+        assert!(config.find_interface(3, Some(1), Some(2)).is_none());
+
+        assert!(ConfigurationDescriptor::parse(&buf[..20]).is_err());
+        assert!(DeviceDescriptor::parse(&buf).is_err());
+    }
+}
@@ -0,0 +1,379 @@
+#![no_std]
+#![allow(async_fn_in_trait)]
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+
+// This mod MUST go first, so that the others see its macros.
+pub(crate) mod fmt;
+
+pub use embassy_usb_driver as driver;
+
+pub mod class;
+pub mod descriptor;
+#[cfg(test)]
+mod mock;
+
+use embassy_time::Timer;
+
+use crate::descriptor::{
+    descriptor_type, ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, CONFIGURATION_DESCRIPTOR_LEN,
+    DEVICE_DESCRIPTOR_LEN,
+};
+use crate::driver::host::{DeviceEvent, HostError, PipeInfo, SetupPacket, Speed, UsbHostDriver, UsbPipe};
+use crate::driver::{Direction, EndpointType};
+
+/// Bits of the request type of a setup packet.
+pub mod request_type {
+    /// Data stage from the device to the host.
+    pub const DIR_IN: u8 = 0x80;
+    /// Standard request.
+    pub const STANDARD: u8 = 0x00;
+    /// Class request.
+    pub const CLASS: u8 = 0x20;
+    /// Vendor request.
+    pub const VENDOR: u8 = 0x40;
+    /// Request to the device.
+    pub const DEVICE: u8 = 0x00;
+    /// Request to an interface.
+    pub const INTERFACE: u8 = 0x01;
+    /// Request to an endpoint.
+    pub const ENDPOINT: u8 = 0x02;
+}
+
+const REQ_CLEAR_FEATURE: u8 = 0x01;
+const REQ_SET_ADDRESS: u8 = 0x05;
+const REQ_GET_DESCRIPTOR: u8 = 0x06;
+const REQ_SET_CONFIGURATION: u8 = 0x09;
+const REQ_SET_INTERFACE: u8 = 0x0b;
+
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// Errors of the host stack and class drivers.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A transfer failed.
+    Transfer(HostError),
+    /// The device returned a malformed descriptor.
+    InvalidDescriptor,
+    /// The buffer is too small for the descriptors of the device.
+    BufferTooSmall,
+    /// The configuration has no interface supported by the class driver.
+    InterfaceNotFound,
+    /// The device reported that a command failed.
+    CommandFailed,
+    /// The device returned an unexpected response, and may need to be reset.
+    InvalidResponse,
+}
+
+impl From<HostError> for Error {
+    fn from(e: HostError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+/// USB host, owning the host controller driver.
+pub struct UsbHost<D: UsbHostDriver> {
+    driver: D,
+}
+
+impl<D: UsbHostDriver> UsbHost<D> {
+    /// Create a new host with the given driver.
+    pub fn new(driver: D) -> Self {
+        Self { driver }
+    }
+
+    /// Get the host controller driver.
+    pub fn driver(&self) -> &D {
+        &self.driver
+    }
+
+    /// Wait for a device to be connected, returning its speed.
+    pub async fn wait_for_connection(&self) -> Speed {
+        loop {
+            if let DeviceEvent::Connected(speed) = self.driver.wait_for_device_event().await {
+                return speed;
+            }
+        }
+    }
+
+    /// Wait for the device to be disconnected.
+    pub async fn wait_for_disconnection(&self) {
+        while self.driver.wait_for_device_event().await != DeviceEvent::Disconnected {}
+    }
+
+    /// Reset the connected device and give it the address `address`, which must not be 0.
+    ///
+    /// The device is left unconfigured: read its configuration descriptor with
+    /// [`Device::configuration_descriptor`] and select it with [`Device::set_configuration`].
+    pub async fn enumerate(&self, speed: Speed, address: u8) -> Result<Device<'_, D>, Error> {
+        assert!(address != 0 && address < 128, "invalid device address");
+
+        self.driver.bus_reset().await;
+
+        // The max packet size of endpoint 0 is unknown, but 8 bytes are always accepted.
+        let mut ep0 = self.driver.alloc_pipe(control_pipe(0, speed, 8))?;
+        let mut buf = [0; DEVICE_DESCRIPTOR_LEN];
+        let n = ep0
+            .control_in(&get_descriptor(descriptor_type::DEVICE, 0, 0, 8), &mut buf[..8])
+            .await?;
+        if n < 8 || buf[1] != descriptor_type::DEVICE {
+            return Err(Error::InvalidDescriptor);
+        }
+        let max_packet_size0 = buf[7];
+        if !matches!(max_packet_size0, 8 | 16 | 32 | 64) {
+            return Err(Error::InvalidDescriptor);
+        }
+        debug!("Device on endpoint 0 with max packet size {}", max_packet_size0);
+
+        let setup = SetupPacket {
+            request_type: request_type::STANDARD | request_type::DEVICE,
+            request: REQ_SET_ADDRESS,
+            value: address as u16,
+            index: 0,
+            length: 0,
+        };
+        ep0.control_out(&setup, &[]).await?;
+        drop(ep0);
+        // Recovery interval of SET_ADDRESS.
+        Timer::after_millis(2).await;
+
+        let mut ep0 = self
+            .driver
+            .alloc_pipe(control_pipe(address, speed, max_packet_size0 as u16))?;
+        let len = DEVICE_DESCRIPTOR_LEN as u16;
+        let n = ep0
+            .control_in(&get_descriptor(descriptor_type::DEVICE, 0, 0, len), &mut buf)
+            .await?;
+        let descriptor = DeviceDescriptor::parse(&buf[..n])?;
+        info!(
+            "Device {:04x}:{:04x} at address {}",
+            descriptor.vendor_id, descriptor.product_id, address
+        );
+
+        Ok(Device {
+            driver: &self.driver,
+            ep0,
+            address,
+            speed,
+            descriptor,
+        })
+    }
+}
+
+/// An enumerated device.
+pub struct Device<'d, D: UsbHostDriver> {
+    driver: &'d D,
+    ep0: D::Pipe,
+    address: u8,
+    speed: Speed,
+    descriptor: DeviceDescriptor,
+}
+
+impl<'d, D: UsbHostDriver> Device<'d, D> {
+    /// Address of the device.
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Speed of the device.
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Device descriptor of the device.
+    pub fn descriptor(&self) -> &DeviceDescriptor {
+        &self.descriptor
+    }
+
+    /// Perform a control transfer with an IN or no data stage, returning the length of the data.
+    pub async fn control_in(&mut self, setup: &SetupPacket, buf: &mut [u8]) -> Result<usize, HostError> {
+        self.ep0.control_in(setup, buf).await
+    }
+
+    /// Perform a control transfer with an OUT or no data stage.
+    pub async fn control_out(&mut self, setup: &SetupPacket, data: &[u8]) -> Result<(), HostError> {
+        self.ep0.control_out(setup, data).await
+    }
+
+    /// Read a descriptor, returning its length.
+    pub async fn get_descriptor(
+        &mut self,
+        descriptor_type: u8,
+        index: u8,
+        language_id: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, HostError> {
+        let len = buf.len().min(u16::MAX as usize) as u16;
+        let setup = get_descriptor(descriptor_type, index, language_id, len);
+        self.ep0.control_in(&setup, buf).await
+    }
+
+    /// Read the configuration descriptor `index` with the descriptors of its interfaces into `buf`.
+    pub async fn configuration_descriptor<'b>(
+        &mut self,
+        index: u8,
+        buf: &'b mut [u8],
+    ) -> Result<ConfigurationDescriptor<'b>, Error> {
+        if buf.len() < CONFIGURATION_DESCRIPTOR_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        let n = self
+            .get_descriptor(
+                descriptor_type::CONFIGURATION,
+                index,
+                0,
+                &mut buf[..CONFIGURATION_DESCRIPTOR_LEN],
+            )
+            .await?;
+        if n < CONFIGURATION_DESCRIPTOR_LEN || buf[1] != descriptor_type::CONFIGURATION {
+            return Err(Error::InvalidDescriptor);
+        }
+        let total_length = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if total_length > buf.len() {
+            warn!("Configuration descriptor of {} bytes is too large", total_length);
+            return Err(Error::BufferTooSmall);
+        }
+        let n = self
+            .get_descriptor(descriptor_type::CONFIGURATION, index, 0, &mut buf[..total_length])
+            .await?;
+        ConfigurationDescriptor::parse(&buf[..n])
+    }
+
+    /// Select the configuration with the given `bConfigurationValue`, 0 to deconfigure the device.
+    pub async fn set_configuration(&mut self, value: u8) -> Result<(), HostError> {
+        let setup = SetupPacket {
+            request_type: request_type::STANDARD | request_type::DEVICE,
+            request: REQ_SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        };
+        self.ep0.control_out(&setup, &[]).await
+    }
+
+    /// Select an alternate setting of an interface.
+    pub async fn set_interface(&mut self, interface: u8, alternate_setting: u8) -> Result<(), HostError> {
+        let setup = SetupPacket {
+            request_type: request_type::STANDARD | request_type::INTERFACE,
+            request: REQ_SET_INTERFACE,
+            value: alternate_setting as u16,
+            index: interface as u16,
+            length: 0,
+        };
+        self.ep0.control_out(&setup, &[]).await
+    }
+
+    /// Clear the halt condition of an endpoint after it stalled.
+    ///
+    /// The data toggle of the pipe to the endpoint must be reset too, which is done by allocating
+    /// it again.
+    pub async fn clear_halt(&mut self, endpoint: u8) -> Result<(), HostError> {
+        let setup = SetupPacket {
+            request_type: request_type::STANDARD | request_type::ENDPOINT,
+            request: REQ_CLEAR_FEATURE,
+            value: FEATURE_ENDPOINT_HALT,
+            index: endpoint as u16,
+            length: 0,
+        };
+        self.ep0.control_out(&setup, &[]).await
+    }
+
+    /// Allocate a pipe to an endpoint of the device.
+    pub fn alloc_pipe(&self, endpoint: &EndpointDescriptor) -> Result<D::Pipe, HostError> {
+        let interval_ms = match (self.speed, endpoint.ep_type()) {
+            // High speed intervals are 2^(bInterval-1) microframes.
+            (Speed::High, EndpointType::Interrupt | EndpointType::Isochronous) => {
+                let microframes = 1u32 << endpoint.interval.clamp(1, 16).saturating_sub(1);
+                (microframes / 8).clamp(1, 255) as u8
+            }
+            _ => endpoint.interval.max(1),
+        };
+        self.driver.alloc_pipe(PipeInfo {
+            device_address: self.address,
+            speed: self.speed,
+            endpoint: endpoint.address.index() as u8,
+            direction: endpoint.address.direction(),
+            ep_type: endpoint.ep_type(),
+            max_packet_size: endpoint.max_packet_size & 0x7ff,
+            interval_ms,
+        })
+    }
+}
+
+fn control_pipe(device_address: u8, speed: Speed, max_packet_size: u16) -> PipeInfo {
+    PipeInfo {
+        device_address,
+        speed,
+        endpoint: 0,
+        direction: Direction::Out,
+        ep_type: EndpointType::Control,
+        max_packet_size,
+        interval_ms: 0,
+    }
+}
+
+fn get_descriptor(descriptor_type: u8, index: u8, language_id: u16, length: u16) -> SetupPacket {
+    SetupPacket {
+        request_type: request_type::DIR_IN | request_type::STANDARD | request_type::DEVICE,
+        request: REQ_GET_DESCRIPTOR,
+        value: (descriptor_type as u16) << 8 | index as u16,
+        index: language_id,
+        length,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::mock::{device_descriptor, MockHost};
+
+    #[rustfmt::skip]
+    const CONFIG: [u8; 18] = [
+        9, 2, 18, 0, 1, 1, 0, 0x80, 50,
+        9, 4, 0, 0, 0, 0xff, 0, 0, 0,
+    ];
+
+    #[test]
+    fn enumerate() {
+        block_on(async {
+            let host = UsbHost::new(MockHost::new(device_descriptor(16), &CONFIG, ()));
+            let speed = host.wait_for_connection().await;
+            assert_eq!(speed, Speed::Full);
+            let mut device = host.enumerate(speed, 5).await.unwrap();
+            assert_eq!(device.address(), 5);
+            assert_eq!(host.driver().device.borrow().address, 5);
+            let descriptor = device.descriptor();
+            assert_eq!(descriptor.max_packet_size0, 16);
+            assert_eq!((descriptor.vendor_id, descriptor.product_id), (0x1234, 0x5678));
+
+            let mut buf = [0; 16];
+            let result = device.configuration_descriptor(0, &mut buf).await;
+            assert_eq!(result.unwrap_err(), Error::BufferTooSmall);
+            let mut buf = [0; 64];
+            let config = device.configuration_descriptor(0, &mut buf).await.unwrap();
+            assert_eq!(config.total_length(), CONFIG.len());
+            assert_eq!(config.interfaces().count(), 1);
+            device.set_configuration(config.configuration_value).await.unwrap();
+            assert_eq!(host.driver().device.borrow().configuration, 1);
+
+            let result = device.get_descriptor(descriptor_type::STRING, 1, 0x409, &mut buf).await;
+            assert_eq!(result, Err(HostError::Stall));
+
+            host.wait_for_disconnection().await;
+        })
+    }
+
+    #[test]
+    fn invalid_max_packet_size() {
+        block_on(async {
+            let host = UsbHost::new(MockHost::new(device_descriptor(12), &CONFIG, ()));
+            let speed = host.wait_for_connection().await;
+            let result = host.enumerate(speed, 1).await;
+            assert_eq!(result.err(), Some(Error::InvalidDescriptor));
+            assert_eq!(host.driver().device.borrow().address, 0);
+        })
+    }
+}
@@ -0,0 +1,186 @@
+//! Simulated host controller with a single device, for the tests.
+
+extern crate std;
+
+use core::future::pending;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use crate::driver::host::{DeviceEvent, HostError, PipeInfo, SetupPacket, Speed, UsbHostDriver, UsbPipe};
+use crate::driver::{Direction, EndpointType};
+use crate::{Device, UsbHost};
+
+/// Behaviour of the functions of a simulated device, on top of the standard requests.
+pub trait Function {
+    fn control_in(&mut self, _setup: &SetupPacket, _buf: &mut [u8]) -> Result<usize, HostError> {
+        Err(HostError::Stall)
+    }
+    fn control_out(&mut self, _setup: &SetupPacket, _data: &[u8]) -> Result<(), HostError> {
+        Err(HostError::Stall)
+    }
+    /// Read from endpoint `ep`, `Timeout` meaning that no data is available.
+    fn read(&mut self, _ep: u8, _buf: &mut [u8]) -> Result<usize, HostError> {
+        Err(HostError::Stall)
+    }
+    fn write(&mut self, _ep: u8, _data: &[u8]) -> Result<(), HostError> {
+        Err(HostError::Stall)
+    }
+    fn clear_halt(&mut self, _ep: u8) {}
+}
+
+pub struct MockDevice<F> {
+    pub address: u8,
+    pub configuration: u8,
+    pub device_descriptor: [u8; 18],
+    pub config_descriptor: Vec<u8>,
+    pub function: F,
+}
+
+impl<F: Function> MockDevice<F> {
+    fn control_in(&mut self, setup: &SetupPacket, buf: &mut [u8]) -> Result<usize, HostError> {
+        assert_eq!(setup.length as usize, buf.len());
+        match (setup.request_type, setup.request) {
+            (0x80, 0x06) => {
+                let descriptor: &[u8] = match setup.value >> 8 {
+                    1 => &self.device_descriptor,
+                    2 => &self.config_descriptor,
+                    _ => return Err(HostError::Stall),
+                };
+                let n = descriptor.len().min(buf.len());
+                buf[..n].copy_from_slice(&descriptor[..n]);
+                Ok(n)
+            }
+            _ => self.function.control_in(setup, buf),
+        }
+    }
+
+    fn control_out(&mut self, setup: &SetupPacket, data: &[u8]) -> Result<(), HostError> {
+        assert_eq!(setup.length as usize, data.len());
+        match (setup.request_type, setup.request) {
+            (0x00, 0x05) => self.address = setup.value as u8,
+            (0x00, 0x09) => self.configuration = setup.value as u8,
+            (0x01, 0x0b) => {}
+            (0x02, 0x01) => self.function.clear_halt(setup.index as u8),
+            _ => return self.function.control_out(setup, data),
+        }
+        Ok(())
+    }
+}
+
+pub struct MockHost<F> {
+    pub device: Rc<RefCell<MockDevice<F>>>,
+    events: RefCell<VecDeque<DeviceEvent>>,
+}
+
+impl<F: Function> MockHost<F> {
+    pub fn new(device_descriptor: [u8; 18], config_descriptor: &[u8], function: F) -> Self {
+        Self {
+            device: Rc::new(RefCell::new(MockDevice {
+                address: 0x55,
+                configuration: 0,
+                device_descriptor,
+                config_descriptor: config_descriptor.into(),
+                function,
+            })),
+            events: RefCell::new([DeviceEvent::Connected(Speed::Full), DeviceEvent::Disconnected].into()),
+        }
+    }
+}
+
+impl<F: Function> UsbHostDriver for MockHost<F> {
+    type Pipe = MockPipe<F>;
+
+    async fn wait_for_device_event(&self) -> DeviceEvent {
+        let event = self.events.borrow_mut().pop_front();
+        match event {
+            Some(event) => event,
+            None => pending().await,
+        }
+    }
+
+    async fn bus_reset(&self) {
+        let mut device = self.device.borrow_mut();
+        device.address = 0;
+        device.configuration = 0;
+    }
+
+    fn alloc_pipe(&self, info: PipeInfo) -> Result<Self::Pipe, HostError> {
+        Ok(MockPipe {
+            info,
+            device: self.device.clone(),
+        })
+    }
+}
+
+pub struct MockPipe<F> {
+    info: PipeInfo,
+    device: Rc<RefCell<MockDevice<F>>>,
+}
+
+impl<F: Function> MockPipe<F> {
+    /// The device, if it is at the address of the pipe.
+    fn device(&self) -> Result<std::cell::RefMut<'_, MockDevice<F>>, HostError> {
+        let device = self.device.borrow_mut();
+        match device.address == self.info.device_address {
+            true => Ok(device),
+            false => Err(HostError::Timeout),
+        }
+    }
+
+    fn endpoint(&self) -> u8 {
+        self.info.endpoint | if self.info.direction == Direction::In { 0x80 } else { 0 }
+    }
+}
+
+impl<F: Function> UsbPipe for MockPipe<F> {
+    fn info(&self) -> &PipeInfo {
+        &self.info
+    }
+
+    async fn control_in(&mut self, setup: &SetupPacket, buf: &mut [u8]) -> Result<usize, HostError> {
+        assert_eq!(self.info.ep_type, EndpointType::Control);
+        if self.info.device_address == 0 {
+            // Only the first packet can be read before the max packet size is known.
+            assert!(buf.len() <= 8);
+        }
+        self.device()?.control_in(setup, buf)
+    }
+
+    async fn control_out(&mut self, setup: &SetupPacket, data: &[u8]) -> Result<(), HostError> {
+        assert_eq!(self.info.ep_type, EndpointType::Control);
+        self.device()?.control_out(setup, data)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, HostError> {
+        let ep = self.endpoint();
+        self.device()?.function.read(ep, buf)
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), HostError> {
+        let ep = self.endpoint();
+        self.device()?.function.write(ep, data)
+    }
+}
+
+/// Device descriptor with the given max packet size of endpoint 0.
+pub fn device_descriptor(max_packet_size0: u8) -> [u8; 18] {
+    #[rustfmt::skip]
+    let descriptor = [
+        18, 1, 0x00, 0x02, 0, 0, 0, max_packet_size0,
+        0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 3, 1,
+    ];
+    descriptor
+}
+
+/// Enumerate the device of `host` and select its configuration.
+pub async fn enumerate<F: Function>(host: &UsbHost<MockHost<F>>) -> Device<'_, MockHost<F>> {
+    let speed = host.wait_for_connection().await;
+    let mut device = host.enumerate(speed, 1).await.unwrap();
+    device.set_configuration(1).await.unwrap();
+    device
+}
+
+/// Device without any function, answering the standard requests only.
+impl Function for () {}
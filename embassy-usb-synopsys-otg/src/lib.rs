@@ -1031,6 +1031,10 @@ impl<'d> embassy_usb_driver::Endpoint for Endpoint<'d, In> {
         &self.info
     }
 
+    fn frame_number(&self) -> Result<u16, Unsupported> {
+        Ok(self.regs.dsts().read().fnsof())
+    }
+
     async fn wait_enabled(&mut self) {
         poll_fn(|cx| {
             let ep_index = self.info.addr.index();
@@ -1052,6 +1056,10 @@ impl<'d> embassy_usb_driver::Endpoint for Endpoint<'d, Out> {
         &self.info
     }
 
+    fn frame_number(&self) -> Result<u16, Unsupported> {
+        Ok(self.regs.dsts().read().fnsof())
+    }
+
     async fn wait_enabled(&mut self) {
         poll_fn(|cx| {
             let ep_index = self.info.addr.index();
@@ -0,0 +1,688 @@
+#![warn(missing_docs)]
+#![doc = include_str!("../README.md")]
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Poll, Waker};
+
+use embassy_usb_driver::{
+    Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo, EndpointType, Event, Unsupported,
+};
+use log::*;
+
+mod server;
+
+pub use embassy_usb_driver::host::Speed;
+pub use server::{Config, Server, USBIP_PORT};
+
+const NUM_ENDPOINTS: usize = 16;
+
+const EPIPE: i32 = 32;
+const EOVERFLOW: i32 = 75;
+
+/// A USB request block submitted by the host, or by the server itself.
+struct Urb {
+    seqnum: u32,
+    /// Submitted by the server, which waits for its completion.
+    local: bool,
+    setup: [u8; 8],
+    dir_in: bool,
+    /// Length of the buffer of IN transfers.
+    length: usize,
+    /// Data received from the device for IN transfers, or to send to it for OUT transfers.
+    data: Vec<u8>,
+    /// Length of the OUT data already read by the device.
+    offset: usize,
+    /// End OUT transfers of a multiple of the max packet size with a zero-length packet.
+    zero_packet: bool,
+}
+
+impl Urb {
+    fn actual_length(&self) -> usize {
+        match self.dir_in {
+            true => self.data.len(),
+            false => self.offset,
+        }
+    }
+}
+
+enum Reply {
+    Submit(Urb, i32),
+    Unlink { seqnum: u32, status: i32 },
+}
+
+#[derive(Default)]
+struct EndpointState {
+    allocated: bool,
+    enabled: bool,
+    stalled: bool,
+    urbs: VecDeque<Urb>,
+    waker: Option<Waker>,
+}
+
+impl EndpointState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// State shared by the driver and the server.
+#[derive(Default)]
+struct Inner {
+    events: VecDeque<Event>,
+    bus_waker: Option<Waker>,
+    /// Endpoints indexed by direction, then number. The control URBs are queued on OUT endpoint 0.
+    endpoints: [[EndpointState; NUM_ENDPOINTS]; 2],
+    /// The device is processing the first control URB.
+    control_started: bool,
+    replies: VecDeque<Reply>,
+    local_reply: Option<(Urb, i32)>,
+    server_waker: Option<Waker>,
+}
+
+impl Inner {
+    fn endpoint(&mut self, addr: EndpointAddress) -> &mut EndpointState {
+        &mut self.endpoints[addr.is_in() as usize][addr.index()]
+    }
+
+    fn push_event(&mut self, event: Event) {
+        self.events.push_back(event);
+        if let Some(waker) = self.bus_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn push_reply(&mut self, reply: Reply) {
+        match reply {
+            Reply::Submit(urb, status) if urb.local => self.local_reply = Some((urb, status)),
+            reply => self.replies.push_back(reply),
+        }
+        if let Some(waker) = self.server_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Queue a URB on endpoint `ep`.
+    fn submit(&mut self, ep: usize, urb: Urb) {
+        let endpoint = match ep {
+            0 => &mut self.endpoints[0][0],
+            _ => &mut self.endpoints[urb.dir_in as usize][ep],
+        };
+        if endpoint.allocated && !endpoint.stalled {
+            endpoint.urbs.push_back(urb);
+            endpoint.wake();
+        } else {
+            self.push_reply(Reply::Submit(urb, -EPIPE));
+        }
+    }
+
+    /// Remove a URB the host gave up on, returning whether it was still pending.
+    fn unlink(&mut self, seqnum: u32) -> bool {
+        for (dir, endpoints) in self.endpoints.iter_mut().enumerate() {
+            for (ep, endpoint) in endpoints.iter_mut().enumerate() {
+                if let Some(i) = endpoint.urbs.iter().position(|u| u.seqnum == seqnum && !u.local) {
+                    endpoint.urbs.remove(i);
+                    if (dir, ep, i) == (0, 0, 0) {
+                        // The control pipe returns `Disabled` for the rest of the request.
+                        self.control_started = false;
+                    }
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Complete the control URB being processed by the device.
+    fn finish_control(&mut self, status: i32) {
+        if self.control_started {
+            self.control_started = false;
+            let urb = self.endpoints[0][0].urbs.pop_front().unwrap();
+            self.push_reply(Reply::Submit(urb, status));
+        }
+    }
+
+    /// Reset the bus, dropping all pending URBs and disabling the endpoints.
+    fn reset(&mut self) {
+        for endpoint in self.endpoints.iter_mut().flatten() {
+            endpoint.urbs.clear();
+            endpoint.enabled = false;
+            endpoint.stalled = false;
+            endpoint.wake();
+        }
+        self.control_started = false;
+        self.replies.clear();
+        self.local_reply = None;
+        self.push_event(Event::Reset);
+    }
+}
+
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Inner>>);
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().unwrap()
+    }
+}
+
+/// Create a virtual USB device, returning the driver to build it with `embassy-usb` and the server
+/// exporting it.
+pub fn new(config: Config) -> (Driver, Server) {
+    let shared = Shared::default();
+    // The device is plugged in from the start, to read its descriptors.
+    shared.lock().push_event(Event::PowerDetected);
+    (Driver { shared: shared.clone() }, Server::new(shared, config))
+}
+
+/// USB driver of the virtual device.
+pub struct Driver {
+    shared: Shared,
+}
+
+impl Driver {
+    fn alloc_endpoint<D: Dir>(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Endpoint<D>, EndpointAllocError> {
+        if ep_type == EndpointType::Isochronous {
+            warn!("Isochronous endpoints are not supported");
+            return Err(EndpointAllocError);
+        }
+        let mut inner = self.shared.lock();
+        let endpoints = &mut inner.endpoints[(D::dir() == Direction::In) as usize];
+        let index = match ep_addr {
+            Some(addr) if (1..NUM_ENDPOINTS).contains(&addr.index()) => addr.index(),
+            Some(_) => return Err(EndpointAllocError),
+            None => (1..NUM_ENDPOINTS)
+                .find(|&i| !endpoints[i].allocated)
+                .ok_or(EndpointAllocError)?,
+        };
+        let endpoint = &mut endpoints[index];
+        if endpoint.allocated {
+            return Err(EndpointAllocError);
+        }
+        endpoint.allocated = true;
+        Ok(Endpoint {
+            shared: self.shared.clone(),
+            info: EndpointInfo {
+                addr: EndpointAddress::from_parts(index, D::dir()),
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+            _phantom: PhantomData,
+        })
+    }
+}
+
+impl<'a> embassy_usb_driver::Driver<'a> for Driver {
+    type EndpointOut = Endpoint<Out>;
+    type EndpointIn = Endpoint<In>;
+    type ControlPipe = ControlPipe;
+    type Bus = Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.alloc_endpoint(ep_type, ep_addr, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.alloc_endpoint(ep_type, ep_addr, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let mut inner = self.shared.lock();
+        for endpoint in &mut inner.endpoints {
+            endpoint[0].allocated = true;
+        }
+        drop(inner);
+        (
+            Bus {
+                shared: self.shared.clone(),
+            },
+            ControlPipe {
+                shared: self.shared,
+                max_packet_size: control_max_packet_size as usize,
+            },
+        )
+    }
+}
+
+/// USB bus of the virtual device.
+pub struct Bus {
+    shared: Shared,
+}
+
+impl embassy_usb_driver::Bus for Bus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        poll_fn(|cx| {
+            let mut inner = self.shared.lock();
+            match inner.events.pop_front() {
+                Some(event) => Poll::Ready(event),
+                None => {
+                    inner.bus_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        trace!("endpoint {ep_addr:?} enabled: {enabled}");
+        if ep_addr.index() != 0 {
+            let mut inner = self.shared.lock();
+            let endpoint = inner.endpoint(ep_addr);
+            endpoint.enabled = enabled;
+            endpoint.wake();
+        }
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        trace!("endpoint {ep_addr:?} stalled: {stalled}");
+        if ep_addr.index() == 0 {
+            // Control requests are rejected through the control pipe.
+            return;
+        }
+        let mut inner = self.shared.lock();
+        let endpoint = inner.endpoint(ep_addr);
+        endpoint.stalled = stalled;
+        if stalled {
+            let urbs = core::mem::take(&mut endpoint.urbs);
+            for urb in urbs {
+                inner.push_reply(Reply::Submit(urb, -EPIPE));
+            }
+        }
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        self.shared.lock().endpoint(ep_addr).stalled
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+trait SealedDir {
+    fn dir() -> Direction;
+}
+
+/// Marker trait for the endpoint direction.
+#[allow(private_bounds)]
+pub trait Dir: SealedDir {}
+
+/// Marker type for the IN direction.
+pub enum In {}
+impl SealedDir for In {
+    fn dir() -> Direction {
+        Direction::In
+    }
+}
+impl Dir for In {}
+
+/// Marker type for the OUT direction.
+pub enum Out {}
+impl SealedDir for Out {
+    fn dir() -> Direction {
+        Direction::Out
+    }
+}
+impl Dir for Out {}
+
+/// Endpoint of the virtual device.
+pub struct Endpoint<D: Dir> {
+    shared: Shared,
+    info: EndpointInfo,
+    _phantom: PhantomData<D>,
+}
+
+impl<D: Dir> embassy_usb_driver::Endpoint for Endpoint<D> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        poll_fn(|cx| {
+            let mut inner = self.shared.lock();
+            let endpoint = inner.endpoint(self.info.addr);
+            match endpoint.enabled {
+                true => Poll::Ready(()),
+                false => {
+                    endpoint.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl embassy_usb_driver::EndpointOut for Endpoint<Out> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let max_packet_size = self.info.max_packet_size as usize;
+        poll_fn(|cx| {
+            let mut inner = self.shared.lock();
+            let endpoint = inner.endpoint(self.info.addr);
+            if !endpoint.enabled {
+                return Poll::Ready(Err(EndpointError::Disabled));
+            }
+            let Some(urb) = endpoint.urbs.front_mut() else {
+                endpoint.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            };
+
+            let n = (urb.data.len() - urb.offset).min(max_packet_size);
+            if n > buf.len() {
+                return Poll::Ready(Err(EndpointError::BufferOverflow));
+            }
+            buf[..n].copy_from_slice(&urb.data[urb.offset..][..n]);
+            urb.offset += n;
+            // A short packet ends the transfer, a full one only if no zero-length packet follows.
+            if n < max_packet_size || (urb.offset == urb.data.len() && !urb.zero_packet) {
+                let urb = endpoint.urbs.pop_front().unwrap();
+                inner.push_reply(Reply::Submit(urb, 0));
+            }
+            Poll::Ready(Ok(n))
+        })
+        .await
+    }
+}
+
+impl embassy_usb_driver::EndpointIn for Endpoint<In> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        let max_packet_size = self.info.max_packet_size as usize;
+        if buf.len() > max_packet_size {
+            return Err(EndpointError::BufferOverflow);
+        }
+        poll_fn(|cx| {
+            let mut inner = self.shared.lock();
+            let endpoint = inner.endpoint(self.info.addr);
+            if !endpoint.enabled {
+                return Poll::Ready(Err(EndpointError::Disabled));
+            }
+            // Without a URB from the host, the packet is NAKed.
+            let Some(urb) = endpoint.urbs.front_mut() else {
+                endpoint.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            };
+
+            let n = buf.len().min(urb.length - urb.data.len());
+            urb.data.extend_from_slice(&buf[..n]);
+            let status = if n < buf.len() { -EOVERFLOW } else { 0 };
+            if status != 0 || buf.len() < max_packet_size || urb.data.len() == urb.length {
+                let urb = endpoint.urbs.pop_front().unwrap();
+                inner.push_reply(Reply::Submit(urb, status));
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+}
+
+/// Control pipe of the virtual device.
+pub struct ControlPipe {
+    shared: Shared,
+    max_packet_size: usize,
+}
+
+impl embassy_usb_driver::ControlPipe for ControlPipe {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        poll_fn(|cx| {
+            let mut inner = self.shared.lock();
+            if !inner.control_started {
+                if let Some(urb) = inner.endpoints[0][0].urbs.front() {
+                    let setup = urb.setup;
+                    inner.control_started = true;
+                    return Poll::Ready(setup);
+                }
+            }
+            inner.endpoints[0][0].waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        let mut inner = self.shared.lock();
+        if !inner.control_started {
+            return Err(EndpointError::Disabled);
+        }
+        let urb = inner.endpoints[0][0].urbs.front_mut().unwrap();
+        let n = (urb.data.len() - urb.offset).min(self.max_packet_size);
+        if n > buf.len() {
+            return Err(EndpointError::BufferOverflow);
+        }
+        buf[..n].copy_from_slice(&urb.data[urb.offset..][..n]);
+        urb.offset += n;
+        Ok(n)
+    }
+
+    async fn data_in(&mut self, data: &[u8], _first: bool, last: bool) -> Result<(), EndpointError> {
+        let mut inner = self.shared.lock();
+        if !inner.control_started {
+            return Err(EndpointError::Disabled);
+        }
+        let urb = inner.endpoints[0][0].urbs.front_mut().unwrap();
+        let n = data.len().min(urb.length - urb.data.len());
+        urb.data.extend_from_slice(&data[..n]);
+        if last {
+            inner.finish_control(0);
+        }
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.shared.lock().finish_control(0);
+    }
+
+    async fn reject(&mut self) {
+        self.shared.lock().finish_control(-EPIPE);
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        trace!("address set to {addr}");
+        self.shared.lock().finish_control(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::net::{TcpListener, TcpStream};
+
+    use async_io::Async;
+    use embassy_futures::join::join;
+    use embassy_futures::select::select;
+    use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+    use embassy_usb::Builder;
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// USB/IP client, playing the role of the `vhci-hcd` driver.
+    struct Client {
+        stream: Async<TcpStream>,
+        seqnum: u32,
+        /// Pending IN transfers, whose replies carry data.
+        pending_in: HashSet<u32>,
+        /// Replies received while waiting for another one.
+        replies: HashMap<u32, (i32, Vec<u8>)>,
+    }
+
+    impl Client {
+        async fn connect(port: u16, code: u16) -> Async<TcpStream> {
+            let mut stream = Async::<TcpStream>::connect(([127, 0, 0, 1], port)).await.unwrap();
+            let mut request = [0x01, 0x11, (code >> 8) as u8, code as u8, 0, 0, 0, 0].to_vec();
+            if code == 0x8003 {
+                request.extend_from_slice(b"1-1");
+                request.resize(8 + 32, 0);
+            }
+            stream.write_all(&request).await.unwrap();
+            stream
+        }
+
+        async fn submit(&mut self, ep: u8, dir_in: bool, setup: [u8; 8], data: &[u8], length: usize) -> u32 {
+            self.seqnum += 1;
+            let mut buf = [0; 48].to_vec();
+            buf[0..4].copy_from_slice(&1u32.to_be_bytes());
+            buf[4..8].copy_from_slice(&self.seqnum.to_be_bytes());
+            buf[8..12].copy_from_slice(&0x1_0001u32.to_be_bytes());
+            buf[12..16].copy_from_slice(&(dir_in as u32).to_be_bytes());
+            buf[16..20].copy_from_slice(&(ep as u32).to_be_bytes());
+            buf[24..28].copy_from_slice(&(length as u32).to_be_bytes());
+            buf[40..48].copy_from_slice(&setup);
+            buf.extend_from_slice(data);
+            self.stream.write_all(&buf).await.unwrap();
+            if dir_in {
+                self.pending_in.insert(self.seqnum);
+            }
+            self.seqnum
+        }
+
+        async fn unlink(&mut self, seqnum: u32) -> u32 {
+            self.seqnum += 1;
+            let mut buf = [0; 48];
+            buf[0..4].copy_from_slice(&2u32.to_be_bytes());
+            buf[4..8].copy_from_slice(&self.seqnum.to_be_bytes());
+            buf[20..24].copy_from_slice(&seqnum.to_be_bytes());
+            self.stream.write_all(&buf).await.unwrap();
+            self.seqnum
+        }
+
+        /// Wait for the reply to `seqnum`, returning its status and data.
+        async fn reply(&mut self, seqnum: u32) -> (i32, Vec<u8>) {
+            while !self.replies.contains_key(&seqnum) {
+                let mut header = [0; 48];
+                self.stream.read_exact(&mut header).await.unwrap();
+                let u32_at = |i: usize| u32::from_be_bytes(header[i..i + 4].try_into().unwrap());
+                let mut data = Vec::new();
+                // OUT transfers have no data in their reply, only their length.
+                if u32_at(0) == 3 && self.pending_in.remove(&u32_at(4)) {
+                    data.resize(u32_at(24) as usize, 0);
+                    self.stream.read_exact(&mut data).await.unwrap();
+                }
+                self.replies.insert(u32_at(4), (u32_at(20) as i32, data));
+            }
+            self.replies.remove(&seqnum).unwrap()
+        }
+
+        async fn control_in(&mut self, setup: [u8; 8]) -> (i32, Vec<u8>) {
+            let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+            let seqnum = self.submit(0, true, setup, &[], length).await;
+            self.reply(seqnum).await
+        }
+    }
+
+    #[test]
+    fn serial_loopback() {
+        async_io::block_on(async {
+            let (driver, mut server) = new(Config::default());
+            let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+            config.max_packet_size_0 = 64;
+            let mut config_descriptor = [0; 256];
+            let mut bos_descriptor = [0; 256];
+            let mut control_buf = [0; 64];
+            let mut state = State::new();
+            let mut builder = Builder::new(
+                driver,
+                config,
+                &mut config_descriptor,
+                &mut bos_descriptor,
+                &mut [],
+                &mut control_buf,
+            );
+            let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+            let mut usb = builder.build();
+
+            let echo = async {
+                loop {
+                    class.wait_connection().await;
+                    let mut buf = [0; 64];
+                    while let Ok(n) = class.read_packet(&mut buf).await {
+                        if class.write_packet(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            };
+
+            let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let client = async {
+                // List the device.
+                let mut stream = Client::connect(port, 0x8005).await;
+                let mut reply = Vec::new();
+                stream.read_to_end(&mut reply).await.unwrap();
+                assert_eq!(reply[..12], [0x01, 0x11, 0, 5, 0, 0, 0, 0, 0, 0, 0, 1]);
+                let device = &reply[12..];
+                assert_eq!(&device[256..260], b"1-1\0");
+                // Full speed, VID:PID, then composite device class and two interfaces.
+                assert_eq!(device[296..304], [0, 0, 0, 2, 0xc0, 0xde, 0xca, 0xfe]);
+                assert_eq!(device[306..312], [0xef, 2, 1, 1, 1, 2]);
+                assert_eq!(device[312..], [2, 2, 0, 0, 0x0a, 0, 0, 0]);
+
+                let mut stream = Client::connect(port, 0x8003).await;
+                let mut reply = [0; 8 + 312];
+                stream.read_exact(&mut reply).await.unwrap();
+                assert_eq!(reply[..8], [0x01, 0x11, 0, 3, 0, 0, 0, 0]);
+                let mut client = Client {
+                    stream,
+                    seqnum: 0,
+                    pending_in: HashSet::new(),
+                    replies: HashMap::new(),
+                };
+
+                let (status, data) = client.control_in([0x80, 0x06, 0, 1, 0, 0, 64, 0]).await;
+                assert_eq!((status, data.len()), (0, 18));
+                assert_eq!(data[8..12], [0xde, 0xc0, 0xfe, 0xca]);
+                let (status, _) = client.control_in([0x80, 0x06, 0, 0x42, 0, 0, 64, 0]).await;
+                assert_eq!(status, -EPIPE);
+                let seqnum = client.submit(0, false, [0, 0x09, 1, 0, 0, 0, 0, 0], &[], 0).await;
+                assert_eq!(client.reply(seqnum).await.0, 0);
+
+                // The bulk OUT endpoint is 1 and the bulk IN one 2, after the interrupt endpoint.
+                let read = client.submit(2, true, [0; 8], &[], 512).await;
+                let write = client.submit(1, false, [0; 8], b"hello", 5).await;
+                assert_eq!(client.reply(write).await.0, 0);
+                assert_eq!(client.reply(read).await, (0, b"hello".to_vec()));
+
+                // A pending read can be cancelled.
+                let read = client.submit(2, true, [0; 8], &[], 512).await;
+                let unlink = client.unlink(read).await;
+                assert_eq!(client.reply(unlink).await.0, -104);
+                // Endpoints which do not exist stall.
+                let seqnum = client.submit(5, true, [0; 8], &[], 64).await;
+                assert_eq!(client.reply(seqnum).await.0, -EPIPE);
+            };
+
+            select(join(usb.run(), echo), select(server.run(listener), client)).await;
+        })
+    }
+}
@@ -0,0 +1,340 @@
+use std::future::poll_fn;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::task::Poll;
+
+use async_io::Async;
+use embassy_futures::select::{select, Either};
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use log::*;
+
+use crate::{Reply, Shared, Speed, Urb, EPIPE};
+
+/// Default TCP port of USB/IP servers.
+pub const USBIP_PORT: u16 = 3240;
+
+const USBIP_VERSION: u16 = 0x0111;
+
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+
+const USBIP_CMD_SUBMIT: u32 = 1;
+const USBIP_CMD_UNLINK: u32 = 2;
+const USBIP_RET_SUBMIT: u32 = 3;
+const USBIP_RET_UNLINK: u32 = 4;
+
+const URB_ZERO_PACKET: u32 = 0x0040;
+const ECONNRESET: i32 = 104;
+
+const BUSNUM: u32 = 1;
+const DEVNUM: u32 = 1;
+
+/// Configuration of the USB/IP server.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// Bus ID of the device, passed to `usbip attach -b`.
+    pub bus_id: String,
+    /// Speed reported to the host.
+    pub speed: Speed,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bus_id: "1-1".into(),
+            speed: Speed::Full,
+        }
+    }
+}
+
+/// Device and configuration descriptors, read from the device to list it.
+#[derive(Clone)]
+struct Descriptors {
+    device: Vec<u8>,
+    configuration: Vec<u8>,
+}
+
+/// USB/IP server exporting the virtual device.
+pub struct Server {
+    shared: Shared,
+    config: Config,
+    descriptors: Option<Descriptors>,
+}
+
+impl Server {
+    pub(crate) fn new(shared: Shared, config: Config) -> Self {
+        Self {
+            shared,
+            config,
+            descriptors: None,
+        }
+    }
+
+    /// Serve the device to the USB/IP clients connecting to `listener`, one at a time.
+    ///
+    /// The `UsbDevice` built with the driver must be running. This only returns if accepting a
+    /// connection fails.
+    pub async fn run(&mut self, listener: TcpListener) -> io::Error {
+        let listener = match Async::new(listener) {
+            Ok(listener) => listener,
+            Err(e) => return e,
+        };
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => return e,
+            };
+            debug!("USB/IP connection from {peer}");
+            if let Err(e) = self.handle_connection(&stream).await {
+                warn!("USB/IP connection from {peer} failed: {e}");
+            }
+        }
+    }
+
+    async fn handle_connection(&mut self, mut stream: &Async<TcpStream>) -> io::Result<()> {
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).await?;
+        let version = u16::from_be_bytes([header[0], header[1]]);
+        let code = u16::from_be_bytes([header[2], header[3]]);
+        if version != USBIP_VERSION {
+            warn!("Unsupported USB/IP version {version:04x}");
+        }
+
+        match code {
+            OP_REQ_DEVLIST => {
+                let descriptors = self.descriptors().await?;
+                let mut reply = op_header(OP_REP_DEVLIST, 0);
+                reply.extend_from_slice(&1u32.to_be_bytes());
+                reply.extend(device_info(&self.config, &descriptors));
+                // Interfaces of the first alternate settings.
+                for d in iter_descriptors(&descriptors.configuration) {
+                    if d[1] == 4 && d.len() >= 9 && d[3] == 0 {
+                        reply.extend_from_slice(&[d[5], d[6], d[7], 0]);
+                    }
+                }
+                stream.write_all(&reply).await
+            }
+            OP_REQ_IMPORT => {
+                let mut bus_id = [0; 32];
+                stream.read_exact(&mut bus_id).await?;
+                let len = bus_id.iter().position(|&b| b == 0).unwrap_or(bus_id.len());
+                if bus_id[..len] != *self.config.bus_id.as_bytes() {
+                    warn!("Import of unknown bus ID {}", String::from_utf8_lossy(&bus_id[..len]));
+                    return stream.write_all(&op_header(OP_REP_IMPORT, 1)).await;
+                }
+                let descriptors = self.descriptors().await?;
+                let mut reply = op_header(OP_REP_IMPORT, 0);
+                reply.extend(device_info(&self.config, &descriptors));
+                stream.write_all(&reply).await?;
+                self.attach(stream).await
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown USB/IP operation {code:04x}"),
+            )),
+        }
+    }
+
+    /// Exchange URBs with the host which imported the device, until it disconnects.
+    async fn attach(&mut self, stream: &Async<TcpStream>) -> io::Result<()> {
+        // The host does not forward SET_ADDRESS: reset the device and address it.
+        self.shared.lock().reset();
+        let setup = [0x00, 0x05, DEVNUM as u8, 0, 0, 0, 0, 0];
+        self.local_control(setup).await?;
+        info!("USB/IP device attached");
+
+        let result = match select(self.read_commands(stream), self.write_replies(stream)).await {
+            Either::First(r) | Either::Second(r) => r,
+        };
+        self.shared.lock().reset();
+        info!("USB/IP device detached");
+        result
+    }
+
+    async fn read_commands(&self, mut stream: &Async<TcpStream>) -> io::Result<()> {
+        loop {
+            let mut header = [0; 48];
+            match stream.read_exact(&mut header).await {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                r => r?,
+            }
+            let u32_at = |i: usize| u32::from_be_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+            let (command, seqnum, dir_in, ep) = (u32_at(0), u32_at(4), u32_at(12) == 1, u32_at(16) as usize);
+            match command {
+                USBIP_CMD_SUBMIT => {
+                    let length = u32_at(24) as usize;
+                    let mut data = Vec::new();
+                    if !dir_in {
+                        data.resize(length, 0);
+                        stream.read_exact(&mut data).await?;
+                    }
+                    let packets = u32_at(32);
+                    if packets != 0 && packets != u32::MAX {
+                        // Isochronous packet descriptors, the endpoint cannot exist.
+                        let mut descriptors = vec![0; 16 * packets as usize];
+                        stream.read_exact(&mut descriptors).await?;
+                    }
+                    if ep >= crate::NUM_ENDPOINTS {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid endpoint"));
+                    }
+                    trace!("submit {seqnum}: ep {ep} in {dir_in} length {length}");
+                    let urb = Urb {
+                        seqnum,
+                        local: false,
+                        setup: header[40..48].try_into().unwrap(),
+                        dir_in,
+                        length: if dir_in { length } else { 0 },
+                        data,
+                        offset: 0,
+                        zero_packet: u32_at(20) & URB_ZERO_PACKET != 0,
+                    };
+                    self.shared.lock().submit(ep, urb);
+                }
+                USBIP_CMD_UNLINK => {
+                    let mut inner = self.shared.lock();
+                    let status = match inner.unlink(u32_at(20)) {
+                        true => -ECONNRESET,
+                        false => 0,
+                    };
+                    inner.push_reply(Reply::Unlink { seqnum, status });
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown USB/IP command {command}"),
+                    ))
+                }
+            }
+        }
+    }
+
+    async fn write_replies(&self, mut stream: &Async<TcpStream>) -> io::Result<()> {
+        loop {
+            let reply = poll_fn(|cx| {
+                let mut inner = self.shared.lock();
+                match inner.replies.pop_front() {
+                    Some(reply) => Poll::Ready(reply),
+                    None => {
+                        inner.server_waker = Some(cx.waker().clone());
+                        Poll::Pending
+                    }
+                }
+            })
+            .await;
+
+            let (command, seqnum, status) = match &reply {
+                Reply::Submit(urb, status) => (USBIP_RET_SUBMIT, urb.seqnum, *status),
+                Reply::Unlink { seqnum, status } => (USBIP_RET_UNLINK, *seqnum, *status),
+            };
+            trace!("reply {seqnum}: status {status}");
+            // The device ID, direction and endpoint are left to 0.
+            let mut buf = vec![0; 48];
+            buf[0..4].copy_from_slice(&command.to_be_bytes());
+            buf[4..8].copy_from_slice(&seqnum.to_be_bytes());
+            buf[20..24].copy_from_slice(&status.to_be_bytes());
+            if let Reply::Submit(urb, _) = &reply {
+                buf[24..28].copy_from_slice(&(urb.actual_length() as u32).to_be_bytes());
+                if urb.dir_in {
+                    buf.extend_from_slice(&urb.data);
+                }
+            }
+            stream.write_all(&buf).await?;
+        }
+    }
+
+    /// Perform a control request on the device, returning the data it sent.
+    async fn local_control(&self, setup: [u8; 8]) -> io::Result<Vec<u8>> {
+        let urb = Urb {
+            seqnum: 0,
+            local: true,
+            setup,
+            dir_in: setup[0] & 0x80 != 0,
+            length: u16::from_le_bytes([setup[6], setup[7]]) as usize,
+            data: Vec::new(),
+            offset: 0,
+            zero_packet: false,
+        };
+        self.shared.lock().submit(0, urb);
+        let (urb, status) = poll_fn(|cx| {
+            let mut inner = self.shared.lock();
+            match inner.local_reply.take() {
+                Some(reply) => Poll::Ready(reply),
+                None => {
+                    inner.server_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+        match status {
+            0 => Ok(urb.data),
+            s if s == -EPIPE => Err(io::Error::other("control request stalled by the device")),
+            _ => Err(io::Error::other(format!("control request failed with {status}"))),
+        }
+    }
+
+    async fn descriptors(&mut self) -> io::Result<Descriptors> {
+        if self.descriptors.is_none() {
+            let device = self.local_control([0x80, 0x06, 0, 1, 0, 0, 18, 0]).await?;
+            let header = self.local_control([0x80, 0x06, 0, 2, 0, 0, 9, 0]).await?;
+            if device.len() < 18 || header.len() < 9 {
+                return Err(io::Error::other("invalid descriptors"));
+            }
+            let total_length = [header[2], header[3]];
+            let configuration = self
+                .local_control([0x80, 0x06, 0, 2, 0, 0, total_length[0], total_length[1]])
+                .await?;
+            self.descriptors = Some(Descriptors { device, configuration });
+        }
+        Ok(self.descriptors.clone().unwrap())
+    }
+}
+
+/// The `usbip_usb_device` structure of the device.
+fn device_info(config: &Config, descriptors: &Descriptors) -> Vec<u8> {
+    let (device, configuration) = (&descriptors.device, &descriptors.configuration);
+    let mut info = vec![0; 256 + 32];
+    let path = format!("/sys/devices/embassy/usb{BUSNUM}/{}", config.bus_id);
+    info[..path.len().min(255)].copy_from_slice(&path.as_bytes()[..path.len().min(255)]);
+    let bus_id = config.bus_id.as_bytes();
+    info[256..][..bus_id.len().min(31)].copy_from_slice(&bus_id[..bus_id.len().min(31)]);
+    info.extend_from_slice(&BUSNUM.to_be_bytes());
+    info.extend_from_slice(&DEVNUM.to_be_bytes());
+    let speed: u32 = match config.speed {
+        Speed::Low => 1,
+        Speed::Full => 2,
+        Speed::High => 3,
+    };
+    info.extend_from_slice(&speed.to_be_bytes());
+    // Vendor ID, product ID and release number.
+    for i in [8, 10, 12] {
+        info.extend_from_slice(&[device[i + 1], device[i]]);
+    }
+    info.extend_from_slice(&[device[4], device[5], device[6]]);
+    // Configuration value, number of configurations and interfaces.
+    info.extend_from_slice(&[configuration[5], device[17], configuration[4]]);
+    info
+}
+
+fn op_header(code: u16, status: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(8);
+    header.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+    header.extend_from_slice(&code.to_be_bytes());
+    header.extend_from_slice(&status.to_be_bytes());
+    header
+}
+
+fn iter_descriptors(mut buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+    core::iter::from_fn(move || {
+        let len = *buf.first()? as usize;
+        if len < 2 || len > buf.len() {
+            return None;
+        }
+        let (descriptor, rest) = buf.split_at(len);
+        buf = rest;
+        Some(descriptor)
+    })
+}
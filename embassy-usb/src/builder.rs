@@ -5,7 +5,7 @@ use crate::descriptor::{BosWriter, DescriptorWriter, SynchronizationType, UsageT
 use crate::driver::{Driver, Endpoint, EndpointAddress, EndpointInfo, EndpointType};
 use crate::msos::{DeviceLevelDescriptor, FunctionLevelDescriptor, MsOsDescriptorWriter};
 use crate::types::{InterfaceNumber, StringIndex};
-use crate::{Handler, Interface, UsbDevice, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START};
+use crate::{Handler, Interface, UsbDevice, CONFIGURATION_VALUE, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START};
 
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -146,9 +146,13 @@ impl<'a> Config<'a> {
 pub struct Builder<'d, D: Driver<'d>> {
     config: Config<'d>,
     handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+    handler_configurations: Vec<u8, MAX_HANDLER_COUNT>,
     interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
     control_buf: &'d mut [u8],
 
+    configuration_value: u8,
+    first_interface_index: usize,
+
     driver: D,
     next_string_index: u8,
 
@@ -192,7 +196,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         let mut config_descriptor = DescriptorWriter::new(config_descriptor_buf);
         let mut bos_descriptor = BosWriter::new(DescriptorWriter::new(bos_descriptor_buf));
 
-        config_descriptor.configuration(&config);
+        config_descriptor.configuration(&config, CONFIGURATION_VALUE);
         bos_descriptor.bos();
 
         Builder {
@@ -200,7 +204,10 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             config,
             interfaces: Vec::new(),
             handlers: Vec::new(),
+            handler_configurations: Vec::new(),
             control_buf,
+            configuration_value: CONFIGURATION_VALUE,
+            first_interface_index: 0,
             next_string_index: STRING_INDEX_CUSTOM_START,
 
             config_descriptor,
@@ -227,6 +234,8 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             self.driver,
             self.config,
             self.handlers,
+            self.handler_configurations,
+            self.configuration_value,
             self.config_descriptor.into_buf(),
             self.bos_descriptor.writer.into_buf(),
             msos_descriptor,
@@ -241,6 +250,30 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         self.control_buf.len()
     }
 
+    /// Start a new configuration.
+    ///
+    /// Functions and handlers added after this call belong to the new configuration, which gets the
+    /// next `bConfigurationValue` (the configuration started by [`Builder::new`] is
+    /// [`CONFIGURATION_VALUE`]). Interface numbers start again from 0 in each configuration.
+    /// Endpoints are never shared between configurations, so each configuration allocates its own.
+    ///
+    /// Only the handlers of the configuration selected by the host receive interface and endpoint
+    /// control requests, alternate setting changes and `configured` notifications. All other events
+    /// are delivered to every handler.
+    ///
+    /// Note that most hosts only ever select the first configuration: Windows always does, Linux
+    /// picks the first one it has a driver for, preferring non-vendor-specific classes.
+    pub fn next_configuration(&mut self) {
+        self.config_descriptor.end_configuration();
+        self.configuration_value = self
+            .configuration_value
+            .checked_add(1)
+            .expect("embassy-usb: too many configurations");
+        self.first_interface_index = self.interfaces.len();
+        self.config_descriptor
+            .configuration(&self.config, self.configuration_value);
+    }
+
     /// Add an USB function.
     ///
     /// If [`Config::composite_with_iads`] is set, this will add an IAD descriptor
@@ -248,7 +281,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     ///
     /// If it's not set, no IAD descriptor is added.
     pub fn function(&mut self, class: u8, subclass: u8, protocol: u8) -> FunctionBuilder<'_, 'd, D> {
-        let first_interface = InterfaceNumber::new(self.interfaces.len() as u8);
+        let first_interface = InterfaceNumber::new((self.interfaces.len() - self.first_interface_index) as u8);
         let iface_count_index = if self.config.composite_with_iads {
             self.config_descriptor
                 .iad(first_interface, 0, class, subclass, protocol);
@@ -272,7 +305,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     /// handled by the USB stack.
     pub fn handler(&mut self, handler: &'d mut dyn Handler) {
         assert!(
-            self.handlers.push(handler).is_ok(),
+            self.handlers.push(handler).is_ok() && self.handler_configurations.push(self.configuration_value).is_ok(),
             "embassy-usb: handler list full. Increase the `max_handler_count` compile-time setting. Current value: {}",
             MAX_HANDLER_COUNT
         );
@@ -331,8 +364,9 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
             self.builder.config_descriptor.buf[i] += 1;
         }
 
-        let number = self.builder.interfaces.len() as _;
+        let number = (self.builder.interfaces.len() - self.builder.first_interface_index) as _;
         let iface = Interface {
+            configuration: self.builder.configuration_value,
             current_alt_setting: 0,
             num_alt_settings: 0,
         };
@@ -351,8 +385,9 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
 
     /// Add an MS OS 2.0 Function Level Feature Descriptor.
     pub fn msos_feature<T: FunctionLevelDescriptor>(&mut self, desc: T) {
-        if !self.builder.msos_descriptor.is_in_config_subset() {
-            self.builder.msos_descriptor.configuration(0);
+        let config_index = self.builder.configuration_value - CONFIGURATION_VALUE;
+        if self.builder.msos_descriptor.config_subset_index() != Some(config_index) {
+            self.builder.msos_descriptor.configuration(config_index);
         }
 
         if !self.builder.msos_descriptor.is_in_function_subset() {
@@ -395,7 +430,8 @@ impl<'a, 'd, D: Driver<'d>> InterfaceBuilder<'a, 'd, D> {
     ) -> InterfaceAltBuilder<'_, 'd, D> {
         let number = self.next_alt_setting_number;
         self.next_alt_setting_number += 1;
-        self.builder.interfaces[self.interface_number.0 as usize].num_alt_settings += 1;
+        self.builder.interfaces[self.builder.first_interface_index + self.interface_number.0 as usize]
+            .num_alt_settings += 1;
 
         self.builder.config_descriptor.interface_alt(
             self.interface_number,
@@ -0,0 +1,1011 @@
+//! Chip/Smart Card Interface Devices (CCID) class, presenting a smart card reader with one slot.
+//!
+//! The reader exchanges APDUs with the card (APDU level exchange), so the host never sees TPDUs
+//! and the card can be a secure element or a software implementation, such as an OpenPGP card.
+//! The class handles the CCID protocol itself:
+//!
+//! - the CCID class descriptor, and the `ABORT` class request,
+//! - the bulk message framing, with `PC_to_RDR_IccPowerOn`, `PC_to_RDR_IccPowerOff`,
+//!   `PC_to_RDR_GetSlotStatus`, `PC_to_RDR_XfrBlock`, `PC_to_RDR_Escape`, `PC_to_RDR_Abort` and the
+//!   protocol parameter commands,
+//! - time extension requests while the card processes a command,
+//! - `RDR_to_PC_NotifySlotChange` notifications when a card is inserted or removed with [`Slot`].
+//!
+//! Requires the `time` feature.
+//!
+//! Cards are [`SmartCard`]s. APDUs must fit in one message, chained `PC_to_RDR_XfrBlock`
+//! messages are rejected.
+
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Ticker};
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`, or 0 to use the interface class.
+pub const USB_CLASS_CCID: u8 = 0x0B;
+
+const CCID_SUBCLASS: u8 = 0x00;
+const CCID_PROTOCOL: u8 = 0x00;
+
+const DESC_CCID: u8 = 0x21;
+
+const REQ_ABORT: u8 = 0x01;
+
+const PC_TO_RDR_SET_PARAMETERS: u8 = 0x61;
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+const PC_TO_RDR_GET_SLOT_STATUS: u8 = 0x65;
+const PC_TO_RDR_ESCAPE: u8 = 0x6B;
+const PC_TO_RDR_GET_PARAMETERS: u8 = 0x6C;
+const PC_TO_RDR_RESET_PARAMETERS: u8 = 0x6D;
+const PC_TO_RDR_ICC_CLOCK: u8 = 0x6E;
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6F;
+const PC_TO_RDR_ABORT: u8 = 0x72;
+
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+const RDR_TO_PC_PARAMETERS: u8 = 0x82;
+const RDR_TO_PC_ESCAPE: u8 = 0x83;
+const RDR_TO_PC_NOTIFY_SLOT_CHANGE: u8 = 0x50;
+
+/// Slot error codes, in the `bError` field of responses.
+const ERR_CMD_ABORTED: u8 = 0xFF;
+const ERR_ICC_MUTE: u8 = 0xFE;
+const ERR_HW_ERROR: u8 = 0xFB;
+const ERR_CMD_NOT_SUPPORTED: u8 = 0x00;
+/// Offsets of invalid header fields, also reported in `bError`.
+const ERR_BAD_LENGTH: u8 = 1;
+const ERR_BAD_SLOT: u8 = 5;
+const ERR_BAD_LEVEL_PARAMETER: u8 = 8;
+
+/// `bmCommandStatus`, in the two highest bits of `bStatus`.
+const COMMAND_FAILED: u8 = 0x40;
+const COMMAND_TIME_EXTENSION: u8 = 0x80;
+
+/// Size of the header of all bulk messages.
+const HEADER_LEN: usize = 10;
+/// Maximum length of an answer-to-reset.
+const MAX_ATR_LEN: usize = 33;
+/// Length of command APDUs with 255 bytes of data and a maximum response length of 256 bytes.
+const SHORT_APDU_LEN: usize = 261;
+
+/// T=1 protocol data of `RDR_to_PC_Parameters`: Fi/Di, checksum and convention, guard time,
+/// BWI/CWI, clock stop, IFSC and NAD.
+const PROTOCOL_T1: u8 = 1;
+const T1_PARAMETERS: [u8; 7] = [0x11, 0x10, 0x00, 0x4D, 0x00, 0xFE, 0x00];
+
+/// Interval between time extension requests while the card processes a command.
+const TIME_EXTENSION_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Error of a [`SmartCard`] operation, reported to the host in the `bError` field of the response.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The card doesn't answer.
+    Mute,
+    /// The card or the reader failed.
+    HardwareError,
+    /// The command isn't supported.
+    NotSupported,
+}
+
+impl Error {
+    fn code(self) -> u8 {
+        match self {
+            Error::Mute => ERR_ICC_MUTE,
+            Error::HardwareError => ERR_HW_ERROR,
+            Error::NotSupported => ERR_CMD_NOT_SUPPORTED,
+        }
+    }
+}
+
+/// A smart card, to which the reader passes APDUs.
+pub trait SmartCard {
+    /// Activate (or reset) the card, and write its answer-to-reset to `atr`.
+    ///
+    /// Returns the length of the answer-to-reset, which is at most 33 bytes.
+    async fn power_on(&mut self, atr: &mut [u8]) -> Result<usize, Error>;
+
+    /// Deactivate the card.
+    async fn power_off(&mut self) {}
+
+    /// Process the command APDU `command`, and write the response APDU, including the status
+    /// word, to `response`.
+    ///
+    /// Returns the length of the response APDU. The host is sent time extension requests while
+    /// the command is processed, and it can abort it, in which case the future is dropped.
+    async fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, Error>;
+
+    /// Process a vendor-specific `PC_to_RDR_Escape` command, and write its response to `response`.
+    ///
+    /// Returns the length of the response. Escape commands are not supported by default.
+    async fn escape(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        let _ = (command, response);
+        Err(Error::NotSupported)
+    }
+}
+
+/// Configuration of the CCID class.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Config {
+    /// Maximum packet size of the bulk endpoints. For full-speed devices, this has to be one of 8,
+    /// 16, 32 or 64.
+    pub max_packet_size: u16,
+    /// Maximum length of bulk messages, a 10-byte header followed by an APDU, reported in the
+    /// class descriptor.
+    ///
+    /// It must be at least 10 bytes longer than the largest APDUs, and than `max_packet_size`.
+    /// The default of 271 bytes fits short APDUs, extended APDUs need up to 65546 bytes.
+    pub max_message_length: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_packet_size: 64,
+            max_message_length: HEADER_LEN + SHORT_APDU_LEN,
+        }
+    }
+}
+
+/// Presence of a card, and whether it changed since the last notification.
+#[derive(Copy, Clone)]
+struct Presence {
+    present: bool,
+    changed: bool,
+}
+
+/// Internal state for the CCID class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`, with a card in the slot.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared {
+                presence: CriticalSectionMutex::new(Cell::new(Presence {
+                    present: true,
+                    changed: false,
+                })),
+                presence_changed: Signal::new(),
+                abort: Signal::new(),
+            },
+        }
+    }
+}
+
+/// Shared data between Control, Slot and CcidClass
+struct ControlShared {
+    presence: CriticalSectionMutex<Cell<Presence>>,
+    presence_changed: Signal<CriticalSectionRawMutex, ()>,
+    /// Sequence number of the command aborted with the `ABORT` request.
+    abort: Signal<CriticalSectionRawMutex, u8>,
+}
+
+impl ControlShared {
+    fn is_card_present(&self) -> bool {
+        self.presence.lock(|p| p.get().present)
+    }
+
+    /// Returns the presence of the card, and whether it changed, clearing the change.
+    fn take_presence(&self) -> Presence {
+        self.presence.lock(|p| {
+            let presence = p.get();
+            p.set(Presence {
+                changed: false,
+                ..presence
+            });
+            presence
+        })
+    }
+
+    /// Wait for the `ABORT` request of command `seq`.
+    async fn wait_abort(&self, seq: u8) {
+        while self.abort.wait().await != seq {}
+    }
+}
+
+struct Control<'a> {
+    iface: InterfaceNumber,
+    shared: &'a ControlShared,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        // The host assumes the slot is empty until notified otherwise.
+        self.shared.presence.lock(|p| {
+            let presence = p.get();
+            p.set(Presence {
+                changed: presence.present,
+                ..presence
+            })
+        });
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_ABORT => {
+                let [slot, seq] = req.value.to_le_bytes();
+                debug!("CCID abort of command {} on slot {}", seq, slot);
+                if slot == 0 {
+                    self.shared.abort.signal(seq);
+                }
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        // GET_CLOCK_FREQUENCIES and GET_DATA_RATES are not supported, the reader has a single
+        // clock frequency and data rate.
+        Some(InResponse::Rejected)
+    }
+}
+
+/// Handle to insert and remove the card of a [`CcidClass`], from any task.
+#[derive(Copy, Clone)]
+pub struct Slot<'d> {
+    shared: &'d ControlShared,
+}
+
+impl<'d> Slot<'d> {
+    /// Whether a card is in the slot.
+    pub fn is_card_present(&self) -> bool {
+        self.shared.is_card_present()
+    }
+
+    /// Insert or remove the card, notifying the host.
+    pub fn set_card_present(&self, present: bool) {
+        let changed = self.shared.presence.lock(|p| {
+            let presence = p.get();
+            if presence.present == present {
+                return false;
+            }
+            p.set(Presence { present, changed: true });
+            true
+        });
+        if changed {
+            self.shared.presence_changed.signal(());
+        }
+    }
+}
+
+/// Status of the card, in the two lowest bits of `bStatus`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum IccStatus {
+    Active = 0,
+    Inactive = 1,
+    NoIcc = 2,
+}
+
+/// Header of a bulk message.
+struct Header {
+    message_type: u8,
+    len: usize,
+    slot: u8,
+    seq: u8,
+    params: [u8; 3],
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Self {
+        Self {
+            message_type: data[0],
+            len: u32::from_le_bytes([data[1], data[2], data[3], data[4]]) as usize,
+            slot: data[5],
+            seq: data[6],
+            params: [data[7], data[8], data[9]],
+        }
+    }
+}
+
+/// USB CCID class, with a single slot.
+pub struct CcidClass<'d, D: Driver<'d>> {
+    bulk: Bulk<'d, D>,
+    notify_ep: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> CcidClass<'d, D> {
+    /// Creates a new CcidClass.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config) -> Self {
+        assert!(config.max_message_length >= HEADER_LEN + config.max_packet_size as usize);
+        let max_message_length = u32::try_from(config.max_message_length).unwrap_or(u32::MAX);
+
+        let mut func = builder.function(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL);
+        let mut iface = func.interface();
+        let iface_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL, None);
+
+        let mut desc = [0; 52];
+        // bcdCCID
+        desc[0..2].copy_from_slice(&0x0110u16.to_le_bytes());
+        // bMaxSlotIndex
+        desc[2] = 0;
+        // bVoltageSupport: 5V, 3V and 1.8V
+        desc[3] = 0x07;
+        // dwProtocols: T=1
+        desc[4..8].copy_from_slice(&0x02u32.to_le_bytes());
+        // dwDefaultClock, in kHz
+        desc[8..12].copy_from_slice(&4000u32.to_le_bytes());
+        // dwMaximumClock
+        desc[12..16].copy_from_slice(&4000u32.to_le_bytes());
+        // bNumClockSupported
+        desc[16] = 0;
+        // dwDataRate, in bps
+        desc[17..21].copy_from_slice(&10752u32.to_le_bytes());
+        // dwMaxDataRate
+        desc[21..25].copy_from_slice(&10752u32.to_le_bytes());
+        // bNumDataRatesSupported
+        desc[25] = 0;
+        // dwMaxIFSD
+        desc[26..30].copy_from_slice(&254u32.to_le_bytes());
+        // dwSynchProtocols
+        desc[30..34].copy_from_slice(&0u32.to_le_bytes());
+        // dwMechanical
+        desc[34..38].copy_from_slice(&0u32.to_le_bytes());
+        // dwFeatures: automatic parameter configuration, activation, voltage, clock, baud rate and
+        // PPS, with short and extended APDU level exchange.
+        desc[38..42].copy_from_slice(&0x0004_00FEu32.to_le_bytes());
+        // dwMaxCCIDMessageLength
+        desc[42..46].copy_from_slice(&max_message_length.to_le_bytes());
+        // bClassGetResponse: echo the class of the APDU
+        desc[46] = 0xFF;
+        // bClassEnvelope
+        desc[47] = 0xFF;
+        // wLcdLayout: no LCD
+        desc[48..50].copy_from_slice(&0u16.to_le_bytes());
+        // bPINSupport: no PIN pad
+        desc[50] = 0;
+        // bMaxCCIDBusySlots
+        desc[51] = 1;
+        alt.descriptor(DESC_CCID, &desc);
+
+        let read_ep = alt.endpoint_bulk_out(None, config.max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, config.max_packet_size);
+        let notify_ep = alt.endpoint_interrupt_in(None, 8, 32);
+        drop(func);
+
+        let control = state.control.write(Control {
+            iface: iface_num,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        CcidClass {
+            bulk: Bulk {
+                read_ep,
+                write_ep,
+                shared: &state.shared,
+                max_message_length: config.max_message_length,
+                powered: false,
+            },
+            notify_ep,
+        }
+    }
+
+    /// Gets the maximum packet size of the bulk endpoints in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        self.bulk.max_packet_size()
+    }
+
+    /// Returns a handle to insert and remove the card.
+    pub fn slot(&self) -> Slot<'d> {
+        Slot {
+            shared: self.bulk.shared,
+        }
+    }
+
+    /// Serve the host, passing APDUs to `card`.
+    ///
+    /// Messages from the host are received into `request`, and messages to the host are written
+    /// to `response`. Both must be at least [`Config::max_message_length`] bytes long.
+    pub async fn run<C: SmartCard>(&mut self, card: &mut C, request: &mut [u8], response: &mut [u8]) -> ! {
+        let len = self.bulk.max_message_length;
+        assert!(request.len() >= len && response.len() >= len);
+        let request = &mut request[..len];
+        let response = &mut response[..len];
+
+        let shared = self.bulk.shared;
+        loop {
+            self.bulk.read_ep.wait_enabled().await;
+            let e = match select(
+                self.bulk.serve(card, request, response),
+                notify::<D>(&mut self.notify_ep, shared),
+            )
+            .await
+            {
+                Either::First(e) | Either::Second(e) => e,
+            };
+            debug!("CCID transfer ended: {:?}", e);
+            if self.bulk.powered {
+                self.bulk.powered = false;
+                card.power_off().await;
+            }
+        }
+    }
+}
+
+/// Send slot change notifications.
+async fn notify<'d, D: Driver<'d>>(ep: &mut D::EndpointIn, shared: &ControlShared) -> EndpointError {
+    loop {
+        let presence = shared.take_presence();
+        if presence.changed {
+            trace!("CCID slot change, card present: {}", presence.present);
+            let state = presence.present as u8 | 0x02;
+            if let Err(e) = ep.write(&[RDR_TO_PC_NOTIFY_SLOT_CHANGE, state]).await {
+                return e;
+            }
+        }
+        shared.presence_changed.wait().await;
+    }
+}
+
+/// The bulk endpoints, and the slot they serve.
+struct Bulk<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    shared: &'d ControlShared,
+    max_message_length: usize,
+    powered: bool,
+}
+
+impl<'d, D: Driver<'d>> Bulk<'d, D> {
+    fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    fn icc_status(&mut self) -> IccStatus {
+        if !self.shared.is_card_present() {
+            self.powered = false;
+            IccStatus::NoIcc
+        } else if self.powered {
+            IccStatus::Active
+        } else {
+            IccStatus::Inactive
+        }
+    }
+
+    async fn serve<C: SmartCard>(&mut self, card: &mut C, request: &mut [u8], response: &mut [u8]) -> EndpointError {
+        loop {
+            let result = match self.read_message(request).await {
+                Ok(Some(len)) => self.command(card, &request[..len], response).await,
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                return e;
+            }
+        }
+    }
+
+    /// Process the message `message`, header included.
+    async fn command<C: SmartCard>(
+        &mut self,
+        card: &mut C,
+        message: &[u8],
+        response: &mut [u8],
+    ) -> Result<(), EndpointError> {
+        let header = Header::parse(message);
+        let data = &message[HEADER_LEN..];
+        trace!(
+            "CCID command {:02x}, sequence {}, {} bytes",
+            header.message_type,
+            header.seq,
+            data.len()
+        );
+
+        if header.slot != 0 {
+            let status = COMMAND_FAILED | IccStatus::NoIcc as u8;
+            return self.reply_status(&header, status, ERR_BAD_SLOT).await;
+        }
+        if data.len() != header.len {
+            let status = COMMAND_FAILED | self.icc_status() as u8;
+            return self.reply_status(&header, status, ERR_BAD_LENGTH).await;
+        }
+
+        let icc = self.icc_status();
+        match header.message_type {
+            PC_TO_RDR_ICC_POWER_ON => {
+                if icc == IccStatus::NoIcc {
+                    return self.reply_error(&header, icc, ERR_ICC_MUTE).await;
+                }
+                // The voltage in bPowerSelect is ignored, as automatic voltage selection is supported.
+                self.powered = false;
+                let atr = &mut response[HEADER_LEN..HEADER_LEN + MAX_ATR_LEN];
+                match card.power_on(atr).await {
+                    Ok(len) => {
+                        self.powered = true;
+                        self.reply(response, RDR_TO_PC_DATA_BLOCK, &header, len.min(MAX_ATR_LEN), 0, 0, 0)
+                            .await
+                    }
+                    Err(e) => self.reply_error(&header, IccStatus::Inactive, e.code()).await,
+                }
+            }
+            PC_TO_RDR_ICC_POWER_OFF => {
+                if self.powered {
+                    self.powered = false;
+                    card.power_off().await;
+                }
+                let icc = self.icc_status();
+                self.reply_status(&header, icc as u8, 0).await
+            }
+            PC_TO_RDR_GET_SLOT_STATUS | PC_TO_RDR_ICC_CLOCK => self.reply_status(&header, icc as u8, 0).await,
+            PC_TO_RDR_ABORT => {
+                self.shared.abort.reset();
+                self.reply_status(&header, icc as u8, 0).await
+            }
+            PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => {
+                if icc == IccStatus::NoIcc {
+                    return self.reply_error(&header, icc, ERR_ICC_MUTE).await;
+                }
+                // The parameters are negotiated automatically, and can't be changed.
+                response[HEADER_LEN..HEADER_LEN + T1_PARAMETERS.len()].copy_from_slice(&T1_PARAMETERS);
+                let len = T1_PARAMETERS.len();
+                self.reply(response, RDR_TO_PC_PARAMETERS, &header, len, icc as u8, 0, PROTOCOL_T1)
+                    .await
+            }
+            PC_TO_RDR_XFR_BLOCK => {
+                if icc != IccStatus::Active {
+                    return self.reply_error(&header, icc, ERR_ICC_MUTE).await;
+                }
+                if header.params[1..] != [0, 0] {
+                    // Chained APDUs are not supported.
+                    return self.reply_error(&header, icc, ERR_BAD_LEVEL_PARAMETER).await;
+                }
+                let (_, out) = response.split_at_mut(HEADER_LEN);
+                let result = select3(
+                    card.transmit(data, out),
+                    self.shared.wait_abort(header.seq),
+                    Self::time_extensions(&mut self.write_ep, &header),
+                )
+                .await;
+                match result {
+                    Either3::First(Ok(len)) => self.reply(response, RDR_TO_PC_DATA_BLOCK, &header, len, 0, 0, 0).await,
+                    Either3::First(Err(e)) => self.reply_error(&header, icc, e.code()).await,
+                    Either3::Second(()) => {
+                        debug!("CCID command {} aborted", header.seq);
+                        self.reply_error(&header, icc, ERR_CMD_ABORTED).await
+                    }
+                    Either3::Third(e) => Err(e),
+                }
+            }
+            PC_TO_RDR_ESCAPE => {
+                let (_, out) = response.split_at_mut(HEADER_LEN);
+                match card.escape(data, out).await {
+                    Ok(len) => {
+                        self.reply(response, RDR_TO_PC_ESCAPE, &header, len, icc as u8, 0, 0)
+                            .await
+                    }
+                    Err(e) => {
+                        let status = COMMAND_FAILED | icc as u8;
+                        self.reply(response, RDR_TO_PC_ESCAPE, &header, 0, status, e.code(), 0)
+                            .await
+                    }
+                }
+            }
+            _ => {
+                debug!("Unsupported CCID command {:02x}", header.message_type);
+                let status = COMMAND_FAILED | icc as u8;
+                self.reply_status(&header, status, ERR_CMD_NOT_SUPPORTED).await
+            }
+        }
+    }
+
+    /// Request time extensions until the command `header` completes.
+    async fn time_extensions(write_ep: &mut D::EndpointIn, header: &Header) -> EndpointError {
+        let mut ticker = Ticker::every(TIME_EXTENSION_INTERVAL);
+        loop {
+            ticker.next().await;
+            let mut message = [0; HEADER_LEN];
+            // bError is the multiplier of the block waiting time.
+            write_header(
+                &mut message,
+                RDR_TO_PC_DATA_BLOCK,
+                header,
+                0,
+                COMMAND_TIME_EXTENSION,
+                1,
+                0,
+            );
+            if let Err(e) = write_ep.write(&message).await {
+                return e;
+            }
+        }
+    }
+
+    /// Reply with an `RDR_to_PC_DataBlock` for commands returning data, or an
+    /// `RDR_to_PC_SlotStatus` otherwise, reporting the failure `error`.
+    async fn reply_error(&mut self, header: &Header, icc: IccStatus, error: u8) -> Result<(), EndpointError> {
+        let status = COMMAND_FAILED | icc as u8;
+        match header.message_type {
+            PC_TO_RDR_ICC_POWER_ON | PC_TO_RDR_XFR_BLOCK => {
+                let mut message = [0; HEADER_LEN];
+                write_header(&mut message, RDR_TO_PC_DATA_BLOCK, header, 0, status, error, 0);
+                self.write(&message).await
+            }
+            PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => {
+                let mut message = [0; HEADER_LEN];
+                write_header(
+                    &mut message,
+                    RDR_TO_PC_PARAMETERS,
+                    header,
+                    0,
+                    status,
+                    error,
+                    PROTOCOL_T1,
+                );
+                self.write(&message).await
+            }
+            _ => self.reply_status(header, status, error).await,
+        }
+    }
+
+    /// Reply with an `RDR_to_PC_SlotStatus`, with the clock running.
+    async fn reply_status(&mut self, header: &Header, status: u8, error: u8) -> Result<(), EndpointError> {
+        let mut message = [0; HEADER_LEN];
+        write_header(&mut message, RDR_TO_PC_SLOT_STATUS, header, 0, status, error, 0);
+        self.write(&message).await
+    }
+
+    /// Reply with the message in `buf`, whose `len` bytes of data follow the header.
+    #[allow(clippy::too_many_arguments)]
+    async fn reply(
+        &mut self,
+        buf: &mut [u8],
+        message_type: u8,
+        header: &Header,
+        len: usize,
+        status: u8,
+        error: u8,
+        last: u8,
+    ) -> Result<(), EndpointError> {
+        write_header(buf, message_type, header, len, status, error, last);
+        self.write(&buf[..HEADER_LEN + len]).await
+    }
+
+    /// Write a message, terminated by a short packet.
+    async fn write(&mut self, message: &[u8]) -> Result<(), EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        for packet in message.chunks(mps) {
+            self.write_ep.write(packet).await?;
+        }
+        if message.len() % mps == 0 {
+            self.write_ep.write(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive a message into `buf`, returning its length, header included.
+    ///
+    /// Messages longer than `buf` are answered with an error and dropped.
+    async fn read_message(&mut self, buf: &mut [u8]) -> Result<Option<usize>, EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        let mut received = 0;
+        let mut expected = None;
+        loop {
+            // Data beyond the end of the buffer is dropped, overwriting the end of the buffer,
+            // which is longer than a header and a packet.
+            let start = received.min(buf.len() - mps);
+            let n = self.read_ep.read(&mut buf[start..start + mps]).await?;
+            received += n;
+
+            if expected.is_none() && received >= HEADER_LEN {
+                expected = Some(HEADER_LEN + Header::parse(buf).len);
+            }
+            match expected {
+                Some(expected) if received >= expected => break,
+                _ if n < mps => break,
+                _ => {}
+            }
+        }
+
+        if received < HEADER_LEN {
+            warn!("CCID message too short");
+            return Ok(None);
+        }
+        if received > buf.len() {
+            warn!("CCID message too long");
+            let header = Header::parse(buf);
+            let icc = self.icc_status();
+            self.reply_error(&header, icc, ERR_BAD_LENGTH).await?;
+            return Ok(None);
+        }
+        Ok(Some(received))
+    }
+}
+
+/// Write the header of a response to the command `header`.
+fn write_header(buf: &mut [u8], message_type: u8, header: &Header, len: usize, status: u8, error: u8, last: u8) {
+    buf[0] = message_type;
+    buf[1..5].copy_from_slice(&(len as u32).to_le_bytes());
+    buf[5] = header.slot;
+    buf[6] = header.seq;
+    buf[7] = status;
+    buf[8] = error;
+    buf[9] = last;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_time::Timer;
+
+    use super::*;
+    use crate::driver::{Direction, EndpointAddress, EndpointType};
+    use crate::test_driver::{TestDriver, TestEndpoint};
+
+    const MPS: u16 = 64;
+    const ATR: [u8; 4] = [0x3B, 0x02, 0x14, 0x50];
+
+    /// Answers every command APDU with its data and `90 00`, after a delay in milliseconds
+    /// given by its first byte.
+    struct EchoCard;
+
+    impl SmartCard for EchoCard {
+        async fn power_on(&mut self, atr: &mut [u8]) -> Result<usize, Error> {
+            atr[..ATR.len()].copy_from_slice(&ATR);
+            Ok(ATR.len())
+        }
+
+        async fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+            Timer::after_millis(command[0] as u64 * 10).await;
+            response[..command.len()].copy_from_slice(command);
+            response[command.len()..command.len() + 2].copy_from_slice(&[0x90, 0x00]);
+            Ok(command.len() + 2)
+        }
+    }
+
+    fn message(message_type: u8, slot: u8, seq: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = Vec::from([message_type]);
+        message.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        message.extend_from_slice(&[slot, seq, 0, 0, 0]);
+        message.extend_from_slice(data);
+        message
+    }
+
+    /// Splits messages into packets, ending each one with a short packet.
+    fn packets(messages: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        for message in messages {
+            packets.extend(message.chunks(MPS as usize).map(<[u8]>::to_vec));
+            if message.len() % MPS as usize == 0 {
+                packets.push(Vec::new());
+            }
+        }
+        packets
+    }
+
+    /// Serves `messages` from the host, and returns the messages sent back.
+    fn transfer(state: &State<'_>, messages: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut read_ep = TestEndpoint::new(
+            EndpointAddress::from_parts(1, Direction::Out),
+            EndpointType::Bulk,
+            MPS,
+            0,
+        );
+        read_ep.packets = packets(messages).into();
+        let mut bulk: Bulk<'_, TestDriver> = Bulk {
+            read_ep,
+            write_ep: TestEndpoint::new(
+                EndpointAddress::from_parts(1, Direction::In),
+                EndpointType::Bulk,
+                MPS,
+                0,
+            ),
+            shared: &state.shared,
+            max_message_length: Config::default().max_message_length,
+            powered: false,
+        };
+        let mut request = [0; HEADER_LEN + SHORT_APDU_LEN];
+        let mut response = [0; HEADER_LEN + SHORT_APDU_LEN];
+        assert_eq!(
+            block_on(bulk.serve(&mut EchoCard, &mut request, &mut response)),
+            EndpointError::Disabled
+        );
+
+        // Every message ends with a short packet.
+        let mut replies = Vec::new();
+        let mut reply = Vec::new();
+        for packet in bulk.write_ep.written {
+            reply.extend_from_slice(&packet);
+            if packet.len() < MPS as usize {
+                replies.push(core::mem::take(&mut reply));
+            }
+        }
+        assert!(reply.is_empty());
+        replies
+    }
+
+    /// The message type, slot, sequence number, status and error of a reply.
+    fn header(reply: &[u8]) -> (u8, u8, u8, u8, u8) {
+        let len = u32::from_le_bytes(reply[1..5].try_into().unwrap()) as usize;
+        assert_eq!(reply.len(), HEADER_LEN + len);
+        (reply[0], reply[5], reply[6], reply[7], reply[8])
+    }
+
+    #[test]
+    fn slot_and_sequence() {
+        let state = State::new();
+        let replies = transfer(
+            &state,
+            &[
+                message(PC_TO_RDR_GET_SLOT_STATUS, 0, 7, &[]),
+                message(PC_TO_RDR_ICC_POWER_ON, 0, 8, &[]),
+                message(PC_TO_RDR_XFR_BLOCK, 0, 9, &[0, 0xA4, 0x04, 0x00]),
+                message(PC_TO_RDR_GET_PARAMETERS, 0, 10, &[]),
+                message(PC_TO_RDR_ICC_POWER_OFF, 0, 11, &[]),
+                message(PC_TO_RDR_GET_SLOT_STATUS, 1, 12, &[]),
+                message(0x6A, 0, 13, &[]),
+            ],
+        );
+        let headers: Vec<_> = replies.iter().map(|reply| header(reply)).collect();
+        assert_eq!(
+            headers,
+            [
+                (RDR_TO_PC_SLOT_STATUS, 0, 7, IccStatus::Inactive as u8, 0),
+                (RDR_TO_PC_DATA_BLOCK, 0, 8, IccStatus::Active as u8, 0),
+                (RDR_TO_PC_DATA_BLOCK, 0, 9, IccStatus::Active as u8, 0),
+                (RDR_TO_PC_PARAMETERS, 0, 10, IccStatus::Active as u8, 0),
+                (RDR_TO_PC_SLOT_STATUS, 0, 11, IccStatus::Inactive as u8, 0),
+                // Only slot 0 exists, and unknown commands are rejected.
+                (
+                    RDR_TO_PC_SLOT_STATUS,
+                    1,
+                    12,
+                    COMMAND_FAILED | IccStatus::NoIcc as u8,
+                    ERR_BAD_SLOT
+                ),
+                (
+                    RDR_TO_PC_SLOT_STATUS,
+                    0,
+                    13,
+                    COMMAND_FAILED | IccStatus::Inactive as u8,
+                    ERR_CMD_NOT_SUPPORTED
+                ),
+            ]
+        );
+        assert_eq!(replies[1][HEADER_LEN..], ATR);
+        assert_eq!(replies[2][HEADER_LEN..], [0, 0xA4, 0x04, 0x00, 0x90, 0x00]);
+        assert_eq!(replies[3][9], PROTOCOL_T1);
+        assert_eq!(replies[3][HEADER_LEN..], T1_PARAMETERS);
+    }
+
+    #[test]
+    fn length_limits() {
+        let state = State::new();
+
+        // A 200-byte APDU spans several packets, and its 202-byte response too.
+        let apdu: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut wrong_length = message(PC_TO_RDR_XFR_BLOCK, 0, 4, &[0; 4]);
+        wrong_length[1] = 5;
+
+        let replies = transfer(
+            &state,
+            &[
+                message(PC_TO_RDR_ICC_POWER_ON, 0, 1, &[]),
+                message(PC_TO_RDR_XFR_BLOCK, 0, 2, &apdu),
+                // The response to a 52-byte APDU fills a packet, and is ended by a zero-length packet.
+                message(PC_TO_RDR_XFR_BLOCK, 0, 3, &[0; 52]),
+                // The length in the header must match the message.
+                wrong_length,
+                // Messages longer than the buffer are answered with an error, and dropped.
+                message(PC_TO_RDR_XFR_BLOCK, 0, 5, &[0; SHORT_APDU_LEN + 1]),
+                // Messages shorter than a header are dropped without an answer.
+                Vec::from([PC_TO_RDR_GET_SLOT_STATUS, 0, 0, 0]),
+                message(PC_TO_RDR_GET_SLOT_STATUS, 0, 6, &[]),
+            ],
+        );
+        let headers: Vec<_> = replies.iter().map(|reply| header(reply)).collect();
+        let failed = COMMAND_FAILED | IccStatus::Active as u8;
+        assert_eq!(
+            headers,
+            [
+                (RDR_TO_PC_DATA_BLOCK, 0, 1, 0, 0),
+                (RDR_TO_PC_DATA_BLOCK, 0, 2, 0, 0),
+                (RDR_TO_PC_DATA_BLOCK, 0, 3, 0, 0),
+                (RDR_TO_PC_SLOT_STATUS, 0, 4, failed, ERR_BAD_LENGTH),
+                (RDR_TO_PC_DATA_BLOCK, 0, 5, failed, ERR_BAD_LENGTH),
+                (RDR_TO_PC_SLOT_STATUS, 0, 6, IccStatus::Active as u8, 0),
+            ]
+        );
+        assert_eq!(replies[1][HEADER_LEN..HEADER_LEN + 200], apdu);
+        assert_eq!(replies[2].len(), MPS as usize);
+    }
+
+    #[test]
+    fn time_extensions() {
+        let state = State::new();
+
+        // The card takes 1.5 s to answer: the host is sent a time extension request after 1 s.
+        let replies = transfer(
+            &state,
+            &[
+                message(PC_TO_RDR_ICC_POWER_ON, 0, 1, &[]),
+                message(PC_TO_RDR_XFR_BLOCK, 0, 2, &[150, 0xB0]),
+                message(PC_TO_RDR_XFR_BLOCK, 0, 3, &[0, 0xB0]),
+            ],
+        );
+        let headers: Vec<_> = replies.iter().map(|reply| header(reply)).collect();
+        assert_eq!(
+            headers,
+            [
+                (RDR_TO_PC_DATA_BLOCK, 0, 1, 0, 0),
+                (RDR_TO_PC_DATA_BLOCK, 0, 2, COMMAND_TIME_EXTENSION, 1),
+                (RDR_TO_PC_DATA_BLOCK, 0, 2, 0, 0),
+                (RDR_TO_PC_DATA_BLOCK, 0, 3, 0, 0),
+            ]
+        );
+        assert_eq!(replies[2][HEADER_LEN..], [150, 0xB0, 0x90, 0x00]);
+    }
+
+    #[test]
+    fn abort() {
+        let state = State::new();
+
+        // The host aborted command 2 with the `ABORT` request.
+        state.shared.abort.signal(2);
+        let replies = transfer(
+            &state,
+            &[
+                message(PC_TO_RDR_ICC_POWER_ON, 0, 1, &[]),
+                message(PC_TO_RDR_XFR_BLOCK, 0, 2, &[200, 0xB0]),
+                message(PC_TO_RDR_ABORT, 0, 2, &[]),
+            ],
+        );
+        let headers: Vec<_> = replies.iter().map(|reply| header(reply)).collect();
+        assert_eq!(
+            headers,
+            [
+                (RDR_TO_PC_DATA_BLOCK, 0, 1, 0, 0),
+                (
+                    RDR_TO_PC_DATA_BLOCK,
+                    0,
+                    2,
+                    COMMAND_FAILED | IccStatus::Active as u8,
+                    ERR_CMD_ABORTED
+                ),
+                (RDR_TO_PC_SLOT_STATUS, 0, 2, IccStatus::Active as u8, 0),
+            ]
+        );
+    }
+}
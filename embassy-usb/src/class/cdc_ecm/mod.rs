@@ -0,0 +1,414 @@
+//! CDC-ECM class implementation, aka Ethernet over USB.
+//!
+//! Each Ethernet frame is sent as a single USB transfer, terminated by a short packet.
+//!
+//! # Compatibility
+//!
+//! Windows: NOT supported, use [`rndis`](crate::class::rndis) or the [`rndis_ecm`](crate::class::rndis_ecm)
+//! composite instead.
+//!
+//! Linux: Well-supported since forever.
+//!
+//! macOS: Supported out of the box, including older versions that lack CDC-NCM support.
+//!
+//! Android: Supported by most manufacturers, often more reliably than CDC-NCM.
+
+use core::mem::MaybeUninit;
+
+use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::{InterfaceNumber, StringIndex};
+use crate::{Builder, Handler};
+
+pub mod embassy_net;
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_CDC: u8 = 0x02;
+
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ECM: u8 = 0x06;
+
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_UNION: u8 = 0x06;
+const CDC_TYPE_ETHERNET: u8 = 0x0F;
+
+const REQ_SET_ETHERNET_MULTICAST_FILTERS: u8 = 0x40;
+//const REQ_SET_ETHERNET_POWER_MANAGEMENT_PATTERN_FILTER: u8 = 0x41;
+//const REQ_GET_ETHERNET_POWER_MANAGEMENT_PATTERN_FILTER: u8 = 0x42;
+const REQ_SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+//const REQ_GET_ETHERNET_STATISTIC: u8 = 0x44;
+
+const NOTIF_NETWORK_CONNECTION: u8 = 0x00;
+const NOTIF_CONNECTION_SPEED_CHANGE: u8 = 0x2A;
+
+const NOTIF_MAX_PACKET_SIZE: u16 = 16;
+const NOTIF_POLL_INTERVAL: u8 = 32;
+
+/// Receive buffer size: a maximum-size Ethernet frame (1514 bytes without FCS), rounded up to a
+/// multiple of every possible bulk max packet size.
+const FRAME_BUF_SIZE: usize = 1536;
+
+const ALTERNATE_SETTING_DISABLED: u8 = 0x00;
+const ALTERNATE_SETTING_ENABLED: u8 = 0x01;
+
+/// Internal state for the CDC-ECM class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::default(),
+        }
+    }
+}
+
+/// Shared data between Control and `CdcEcmClass`
+#[derive(Default)]
+struct ControlShared {
+    mac_addr: [u8; 6],
+}
+
+struct Control<'a> {
+    mac_addr_string: StringIndex,
+    shared: &'a ControlShared,
+    mac_addr_str: [u8; 12],
+    comm_if: InterfaceNumber,
+    data_if: InterfaceNumber,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface != self.data_if {
+            return;
+        }
+
+        match alternate_setting {
+            ALTERNATE_SETTING_ENABLED => info!("ecm: interface enabled"),
+            ALTERNATE_SETTING_DISABLED => info!("ecm: interface disabled"),
+            _ => unreachable!(),
+        }
+    }
+
+    fn control_out(&mut self, req: control::Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_SET_ETHERNET_PACKET_FILTER => {
+                // All frames are passed to the device anyway, there is no hardware to filter them.
+                debug!("ecm: packet filter {:04x}", req.value);
+                Some(OutResponse::Accepted)
+            }
+            REQ_SET_ETHERNET_MULTICAST_FILTERS => {
+                // wNumberMCFilters is 0, but some hosts send this anyway.
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
+        {
+            return None;
+        }
+
+        Some(InResponse::Rejected)
+    }
+
+    fn get_string(&mut self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if index == self.mac_addr_string {
+            let mac_addr = self.shared.mac_addr;
+            let s = &mut self.mac_addr_str;
+            for i in 0..12 {
+                let n = (mac_addr[i / 2] >> ((1 - i % 2) * 4)) & 0xF;
+                s[i] = match n {
+                    0x0..=0x9 => b'0' + n,
+                    0xA..=0xF => b'A' + n - 0xA,
+                    _ => unreachable!(),
+                }
+            }
+
+            Some(unsafe { core::str::from_utf8_unchecked(s) })
+        } else {
+            None
+        }
+    }
+}
+
+/// CDC-ECM class
+pub struct CdcEcmClass<'d, D: Driver<'d>> {
+    _comm_if: InterfaceNumber,
+    comm_ep: D::EndpointIn,
+
+    data_if: InterfaceNumber,
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+
+    _control: &'d ControlShared,
+
+    max_packet_size: usize,
+}
+
+impl<'d, D: Driver<'d>> CdcEcmClass<'d, D> {
+    /// Create a new CDC ECM class.
+    ///
+    /// `mac_address` is the MAC address the host will use for its side of the link.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        mac_address: [u8; 6],
+        max_packet_size: u16,
+    ) -> Self {
+        state.shared.mac_addr = mac_address;
+
+        let mut func = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ECM, CDC_PROTOCOL_NONE);
+
+        // Control interface
+        let mut iface = func.interface();
+        let mac_addr_string = iface.string();
+        let comm_if = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_CDC, CDC_SUBCLASS_ECM, CDC_PROTOCOL_NONE, None);
+
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_HEADER, // bDescriptorSubtype
+                0x10,
+                0x01, // bcdCDC (1.10)
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_UNION,        // bDescriptorSubtype
+                comm_if.into(),        // bControlInterface
+                u8::from(comm_if) + 1, // bSubordinateInterface
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_ETHERNET,      // bDescriptorSubtype
+                mac_addr_string.into(), // iMACAddress
+                0,                      // bmEthernetStatistics
+                0,                      // |
+                0,                      // |
+                0,                      // |
+                0xea,                   // wMaxSegmentSize = 1514
+                0x05,                   // |
+                0,                      // wNumberMCFilters
+                0,                      // |
+                0,                      // bNumberPowerFilters
+            ],
+        );
+
+        let comm_ep = alt.endpoint_interrupt_in(None, NOTIF_MAX_PACKET_SIZE, NOTIF_POLL_INTERVAL);
+
+        // Data interface. The default alternate setting has no endpoints, the host selects the
+        // second one to start the data transfer.
+        let mut iface = func.interface();
+        let data_if = iface.interface_number();
+        iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, CDC_PROTOCOL_NONE, None);
+        let mut alt = iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, CDC_PROTOCOL_NONE, None);
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+
+        drop(func);
+
+        let control = state.control.write(Control {
+            mac_addr_string,
+            shared: &state.shared,
+            mac_addr_str: [0; 12],
+            comm_if,
+            data_if,
+        });
+        builder.handler(control);
+
+        CdcEcmClass {
+            _comm_if: comm_if,
+            comm_ep,
+            data_if,
+            read_ep,
+            write_ep,
+            _control: &state.shared,
+            max_packet_size: max_packet_size as usize,
+        }
+    }
+
+    /// Split the class into a sender and receiver.
+    ///
+    /// This allows concurrently sending and receiving packets from separate tasks.
+    pub fn split(self) -> (Sender<'d, D>, Receiver<'d, D>) {
+        (
+            Sender {
+                write_ep: self.write_ep,
+                max_packet_size: self.max_packet_size,
+            },
+            Receiver {
+                data_if: self.data_if,
+                comm_ep: self.comm_ep,
+                read_ep: self.read_ep,
+            },
+        )
+    }
+}
+
+/// CDC ECM class packet sender.
+///
+/// You can obtain a `Sender` with [`CdcEcmClass::split`]
+pub struct Sender<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+    max_packet_size: usize,
+}
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    /// Write a packet.
+    ///
+    /// This waits until the packet is successfully stored in the CDC-ECM endpoint buffers.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        for chunk in data.chunks(self.max_packet_size) {
+            self.write_ep.write(chunk).await?;
+        }
+
+        // Send ZLP if needed, the host relies on a short packet to find the end of the frame.
+        if data.len() % self.max_packet_size == 0 {
+            self.write_ep.write(&[]).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// CDC ECM class packet receiver.
+///
+/// You can obtain a `Receiver` with [`CdcEcmClass::split`]
+pub struct Receiver<'d, D: Driver<'d>> {
+    data_if: InterfaceNumber,
+    comm_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    /// Read a network packet.
+    ///
+    /// This waits until a packet is successfully received from the endpoint buffers.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        // Retry loop
+        loop {
+            // read frame
+            let mut frame = [0u8; FRAME_BUF_SIZE];
+            let mut pos = 0;
+            let mut truncated = false;
+            loop {
+                let n = if pos == FRAME_BUF_SIZE {
+                    // Drain the rest of an oversized frame.
+                    truncated = true;
+                    self.read_ep.read(&mut frame[..]).await?
+                } else {
+                    let n = self.read_ep.read(&mut frame[pos..]).await?;
+                    pos += n;
+                    n
+                };
+                if n < self.read_ep.info().max_packet_size as usize {
+                    break;
+                }
+            }
+
+            if truncated {
+                warn!("Received frame larger than {} bytes.", FRAME_BUF_SIZE);
+                continue;
+            }
+            if pos == 0 {
+                // empty, ignore.
+                continue;
+            }
+
+            let Some(dst) = buf.get_mut(..pos) else {
+                warn!("Received frame larger than the buffer.");
+                continue;
+            };
+            dst.copy_from_slice(&frame[..pos]);
+
+            return Ok(pos);
+        }
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) -> Result<(), EndpointError> {
+        loop {
+            self.read_ep.wait_enabled().await;
+            self.comm_ep.wait_enabled().await;
+
+            match self.notify_connection().await {
+                Ok(()) => break,                   // Done!
+                Err(EndpointError::Disabled) => {} // Got disabled again, wait again.
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn notify_connection(&mut self) -> Result<(), EndpointError> {
+        let data_if: u8 = self.data_if.into();
+
+        // Report the bus speed as the link speed, as there's no other link.
+        let bit_rate: u32 = if self.read_ep.info().max_packet_size >= 512 {
+            480_000_000
+        } else {
+            12_000_000
+        };
+        let mut buf = [
+            0xA1,                          // bmRequestType
+            NOTIF_CONNECTION_SPEED_CHANGE, // bNotificationType
+            0x00,                          // wValue
+            0x00,
+            data_if, // wIndex = interface
+            0x00,
+            0x08, // wLength
+            0x00,
+            0, // DLBitRate
+            0,
+            0,
+            0,
+            0, // ULBitRate
+            0,
+            0,
+            0,
+        ];
+        buf[8..12].copy_from_slice(&bit_rate.to_le_bytes());
+        buf[12..16].copy_from_slice(&bit_rate.to_le_bytes());
+        self.comm_ep.write(&buf).await?;
+
+        let buf = [
+            0xA1,                     // bmRequestType
+            NOTIF_NETWORK_CONNECTION, // bNotificationType
+            0x01,                     // wValue = connected
+            0x00,
+            data_if, // wIndex = interface
+            0x00,
+            0x00, // wLength
+            0x00,
+        ];
+        self.comm_ep.write(&buf).await
+    }
+}
@@ -0,0 +1,489 @@
+//! CTAPHID packet framing: message reassembly, fragmentation and channel allocation.
+//!
+//! This doesn't depend on USB, the class feeds it the 64-byte HID reports.
+
+use super::{Error, CMD_INIT};
+
+/// Size of a CTAPHID packet, which is a HID report.
+pub(crate) const PACKET_SIZE: usize = 64;
+
+const INIT_HEADER_LEN: usize = 7;
+const CONT_HEADER_LEN: usize = 5;
+const INIT_DATA_LEN: usize = PACKET_SIZE - INIT_HEADER_LEN;
+const CONT_DATA_LEN: usize = PACKET_SIZE - CONT_HEADER_LEN;
+const MAX_SEQ: usize = 0x80;
+
+/// Init packets have the highest bit of the command byte set.
+const INIT_FLAG: u8 = 0x80;
+
+/// Maximum size of a CTAPHID message, an init packet followed by 128 continuation packets.
+pub const MAX_MESSAGE_SIZE: usize = INIT_DATA_LEN + MAX_SEQ * CONT_DATA_LEN;
+
+/// Channel ID used by hosts to allocate a channel.
+pub const BROADCAST_CID: u32 = 0xFFFF_FFFF;
+
+/// Header of an init packet.
+pub(crate) struct InitHeader {
+    pub cid: u32,
+    pub cmd: u8,
+    pub len: usize,
+}
+
+impl InitHeader {
+    /// Parse the header of `packet`, or `None` for continuation packets.
+    pub fn parse(packet: &[u8; PACKET_SIZE]) -> Option<Self> {
+        (packet[4] & INIT_FLAG != 0).then(|| Self {
+            cid: u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]),
+            cmd: packet[4] & !INIT_FLAG,
+            len: u16::from_be_bytes([packet[5], packet[6]]) as usize,
+        })
+    }
+
+    /// Returns the data of the init packet.
+    pub fn data<'a>(&self, packet: &'a [u8; PACKET_SIZE]) -> &'a [u8] {
+        &packet[INIT_HEADER_LEN..INIT_HEADER_LEN + self.len.min(INIT_DATA_LEN)]
+    }
+}
+
+/// A complete message, whose data is at the start of the reassembly buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Message {
+    pub cid: u32,
+    pub cmd: u8,
+    pub len: usize,
+}
+
+/// Result of feeding a packet to the [`Assembler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// The message needs more continuation packets.
+    Pending,
+    /// The message is complete.
+    Complete(Message),
+    /// The packet is invalid, and `error` must be sent on channel `cid`.
+    Error { cid: u32, error: Error },
+    /// The packet must be silently dropped.
+    Ignored,
+}
+
+struct Transaction {
+    cid: u32,
+    cmd: u8,
+    len: usize,
+    received: usize,
+    seq: u8,
+}
+
+/// Reassembles messages from packets, one at a time, and allocates channels.
+pub(crate) struct Assembler {
+    transaction: Option<Transaction>,
+    next_cid: u32,
+}
+
+impl Assembler {
+    pub const fn new() -> Self {
+        Self {
+            transaction: None,
+            next_cid: 1,
+        }
+    }
+
+    /// Forget all channels and any message being reassembled.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Returns the channel of the message being reassembled.
+    pub fn busy_cid(&self) -> Option<u32> {
+        self.transaction.as_ref().map(|t| t.cid)
+    }
+
+    /// Drop the message being reassembled.
+    pub fn abort(&mut self) {
+        self.transaction = None;
+    }
+
+    /// Allocate a new channel.
+    pub fn allocate(&mut self) -> u32 {
+        let cid = self.next_cid;
+        self.next_cid = match cid.wrapping_add(1) {
+            0 | BROADCAST_CID => 1,
+            next => next,
+        };
+        cid
+    }
+
+    fn is_allocated(&self, cid: u32) -> bool {
+        cid != 0 && cid != BROADCAST_CID && cid < self.next_cid
+    }
+
+    /// Feed a packet, reassembling its data into `buf`.
+    ///
+    /// Messages longer than `buf` are rejected.
+    pub fn push(&mut self, packet: &[u8; PACKET_SIZE], buf: &mut [u8]) -> Outcome {
+        let Some(header) = InitHeader::parse(packet) else {
+            return self.push_cont(packet, buf);
+        };
+        let cid = header.cid;
+
+        if let Some(t) = &self.transaction {
+            if t.cid != cid {
+                return Outcome::Error {
+                    cid,
+                    error: Error::ChannelBusy,
+                };
+            }
+            // An INIT command resynchronizes the channel, anything else is out of sequence.
+            self.transaction = None;
+            if header.cmd != CMD_INIT {
+                return Outcome::Error {
+                    cid,
+                    error: Error::InvalidSequence,
+                };
+            }
+        }
+
+        let valid_channel = if cid == BROADCAST_CID {
+            header.cmd == CMD_INIT
+        } else {
+            self.is_allocated(cid)
+        };
+        if !valid_channel {
+            return Outcome::Error {
+                cid,
+                error: Error::InvalidChannel,
+            };
+        }
+        if header.len > MAX_MESSAGE_SIZE || header.len > buf.len() {
+            return Outcome::Error {
+                cid,
+                error: Error::InvalidLength,
+            };
+        }
+
+        let data = header.data(packet);
+        buf[..data.len()].copy_from_slice(data);
+        if data.len() == header.len {
+            return Outcome::Complete(Message {
+                cid,
+                cmd: header.cmd,
+                len: header.len,
+            });
+        }
+
+        self.transaction = Some(Transaction {
+            cid,
+            cmd: header.cmd,
+            len: header.len,
+            received: data.len(),
+            seq: 0,
+        });
+        Outcome::Pending
+    }
+
+    fn push_cont(&mut self, packet: &[u8; PACKET_SIZE], buf: &mut [u8]) -> Outcome {
+        let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        let Some(t) = self.transaction.as_mut().filter(|t| t.cid == cid) else {
+            // Spurious continuation packet, from an aborted message or another channel.
+            return Outcome::Ignored;
+        };
+
+        if packet[4] != t.seq {
+            self.transaction = None;
+            return Outcome::Error {
+                cid,
+                error: Error::InvalidSequence,
+            };
+        }
+
+        let n = (t.len - t.received).min(CONT_DATA_LEN);
+        buf[t.received..t.received + n].copy_from_slice(&packet[CONT_HEADER_LEN..CONT_HEADER_LEN + n]);
+        t.received += n;
+        t.seq += 1;
+
+        if t.received < t.len {
+            return Outcome::Pending;
+        }
+        let message = Message {
+            cid,
+            cmd: t.cmd,
+            len: t.len,
+        };
+        self.transaction = None;
+        Outcome::Complete(message)
+    }
+}
+
+/// Splits a message into packets.
+pub(crate) struct Fragments<'a> {
+    cid: u32,
+    cmd: u8,
+    data: &'a [u8],
+    offset: usize,
+    seq: Option<u8>,
+}
+
+impl<'a> Fragments<'a> {
+    /// `data` must not be longer than [`MAX_MESSAGE_SIZE`].
+    pub fn new(cid: u32, cmd: u8, data: &'a [u8]) -> Self {
+        assert!(data.len() <= MAX_MESSAGE_SIZE);
+        Self {
+            cid,
+            cmd,
+            data,
+            offset: 0,
+            seq: None,
+        }
+    }
+}
+
+impl<'a> Iterator for Fragments<'a> {
+    type Item = [u8; PACKET_SIZE];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..4].copy_from_slice(&self.cid.to_be_bytes());
+
+        let header_len = match self.seq {
+            None => {
+                packet[4] = self.cmd | INIT_FLAG;
+                packet[5..7].copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+                self.seq = Some(0);
+                INIT_HEADER_LEN
+            }
+            Some(_) if self.offset >= self.data.len() => return None,
+            Some(seq) => {
+                packet[4] = seq;
+                self.seq = Some(seq + 1);
+                CONT_HEADER_LEN
+            }
+        };
+
+        let n = (self.data.len() - self.offset).min(PACKET_SIZE - header_len);
+        packet[header_len..header_len + n].copy_from_slice(&self.data[self.offset..self.offset + n]);
+        self.offset += n;
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::class::ctaphid::CMD_PING;
+
+    fn init(cid: u32, cmd: u8, data: &[u8]) -> [u8; PACKET_SIZE] {
+        Fragments::new(cid, cmd, data).next().unwrap()
+    }
+
+    fn cont(cid: u32, seq: u8) -> [u8; PACKET_SIZE] {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..4].copy_from_slice(&cid.to_be_bytes());
+        packet[4] = seq;
+        packet
+    }
+
+    /// An assembler with channels 1 and 2 allocated.
+    fn assembler() -> Assembler {
+        let mut assembler = Assembler::new();
+        assert_eq!(assembler.allocate(), 1);
+        assert_eq!(assembler.allocate(), 2);
+        assembler
+    }
+
+    fn error(cid: u32, error: Error) -> Outcome {
+        Outcome::Error { cid, error }
+    }
+
+    #[test]
+    fn init_allocates_channels() {
+        let mut assembler = Assembler::new();
+        let mut buf = [0; 64];
+
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(
+            assembler.push(&init(BROADCAST_CID, CMD_INIT, &nonce), &mut buf),
+            Outcome::Complete(Message {
+                cid: BROADCAST_CID,
+                cmd: CMD_INIT,
+                len: 8
+            })
+        );
+        assert_eq!(buf[..8], nonce);
+
+        // Only INIT is allowed on the broadcast channel, and other channels must be allocated.
+        assert_eq!(
+            assembler.push(&init(BROADCAST_CID, CMD_PING, &[]), &mut buf),
+            error(BROADCAST_CID, Error::InvalidChannel)
+        );
+        assert_eq!(
+            assembler.push(&init(1, CMD_PING, &[]), &mut buf),
+            error(1, Error::InvalidChannel)
+        );
+        assert_eq!(assembler.allocate(), 1);
+        assert!(matches!(
+            assembler.push(&init(1, CMD_PING, &[]), &mut buf),
+            Outcome::Complete(_)
+        ));
+        assert_eq!(
+            assembler.push(&init(0, CMD_PING, &[]), &mut buf),
+            error(0, Error::InvalidChannel)
+        );
+
+        // Channel IDs wrap around, skipping 0 and the broadcast channel.
+        assembler.next_cid = BROADCAST_CID - 1;
+        assert_eq!(assembler.allocate(), BROADCAST_CID - 1);
+        assert_eq!(assembler.allocate(), 1);
+
+        assembler.reset();
+        assert_eq!(
+            assembler.push(&init(1, CMD_PING, &[]), &mut buf),
+            error(1, Error::InvalidChannel)
+        );
+    }
+
+    #[test]
+    fn reassembly() {
+        let mut assembler = assembler();
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut buf = [0; 256];
+
+        let mut packets = Fragments::new(1, CMD_PING, &data).peekable();
+        let mut count = 0;
+        while let Some(packet) = packets.next() {
+            let outcome = assembler.push(&packet, &mut buf);
+            count += 1;
+            match packets.peek() {
+                Some(_) => {
+                    assert_eq!(outcome, Outcome::Pending);
+                    assert_eq!(assembler.busy_cid(), Some(1));
+                }
+                None => assert_eq!(
+                    outcome,
+                    Outcome::Complete(Message {
+                        cid: 1,
+                        cmd: CMD_PING,
+                        len: 200
+                    })
+                ),
+            }
+        }
+        // 57 bytes in the init packet, then 59 bytes per continuation packet.
+        assert_eq!(count, 4);
+        assert_eq!(buf[..200], data[..]);
+        assert_eq!(assembler.busy_cid(), None);
+    }
+
+    #[test]
+    fn message_size() {
+        let mut assembler = assembler();
+        let mut buf = vec![0; MAX_MESSAGE_SIZE + 1];
+
+        let data: Vec<u8> = (0..MAX_MESSAGE_SIZE).map(|i| (i * 7) as u8).collect();
+        let packets: Vec<_> = Fragments::new(1, CMD_PING, &data).collect();
+        assert_eq!(packets.len(), 1 + MAX_SEQ);
+        let (last, packets) = packets.split_last().unwrap();
+        for packet in packets {
+            assert_eq!(assembler.push(packet, &mut buf), Outcome::Pending);
+        }
+        assert!(matches!(assembler.push(last, &mut buf), Outcome::Complete(m) if m.len == MAX_MESSAGE_SIZE));
+        assert_eq!(buf[..MAX_MESSAGE_SIZE], data[..]);
+
+        let mut packet = init(1, CMD_PING, &[]);
+        packet[5..7].copy_from_slice(&(MAX_MESSAGE_SIZE as u16 + 1).to_be_bytes());
+        assert_eq!(assembler.push(&packet, &mut buf), error(1, Error::InvalidLength));
+
+        // Messages must also fit in the buffer.
+        packet[5..7].copy_from_slice(&100u16.to_be_bytes());
+        assert_eq!(assembler.push(&packet, &mut buf[..99]), error(1, Error::InvalidLength));
+        assert_eq!(assembler.busy_cid(), None);
+    }
+
+    #[test]
+    fn wrong_sequence() {
+        let mut assembler = assembler();
+        let mut buf = [0; 256];
+        let packets: Vec<_> = Fragments::new(1, CMD_PING, &[0xaa; 200]).collect();
+
+        assert_eq!(assembler.push(&packets[0], &mut buf), Outcome::Pending);
+        assert_eq!(assembler.push(&packets[1], &mut buf), Outcome::Pending);
+        // Sequence number 2 is skipped.
+        assert_eq!(assembler.push(&packets[3], &mut buf), error(1, Error::InvalidSequence));
+        assert_eq!(assembler.busy_cid(), None);
+        assert_eq!(assembler.push(&packets[2], &mut buf), Outcome::Ignored);
+    }
+
+    #[test]
+    fn channel_busy() {
+        let mut assembler = assembler();
+        let mut buf = [0; 256];
+        let packets: Vec<_> = Fragments::new(1, CMD_PING, &[0xaa; 100]).collect();
+
+        assert_eq!(assembler.push(&packets[0], &mut buf), Outcome::Pending);
+        assert_eq!(
+            assembler.push(&init(2, CMD_PING, &[0x55; 8]), &mut buf),
+            error(2, Error::ChannelBusy)
+        );
+        assert_eq!(
+            assembler.push(&init(BROADCAST_CID, CMD_INIT, &[0x55; 8]), &mut buf),
+            error(BROADCAST_CID, Error::ChannelBusy)
+        );
+
+        // The message of the busy channel is not disturbed.
+        assert!(matches!(
+            assembler.push(&packets[1], &mut buf),
+            Outcome::Complete(m) if m.cid == 1 && m.len == 100
+        ));
+        assert_eq!(buf[..100], [0xaa; 100]);
+    }
+
+    #[test]
+    fn init_resyncs_channel() {
+        let mut assembler = assembler();
+        let mut buf = [0; 256];
+        let packets: Vec<_> = Fragments::new(1, CMD_PING, &[0xaa; 100]).collect();
+
+        assert_eq!(assembler.push(&packets[0], &mut buf), Outcome::Pending);
+        let nonce = [8, 7, 6, 5, 4, 3, 2, 1];
+        assert_eq!(
+            assembler.push(&init(1, CMD_INIT, &nonce), &mut buf),
+            Outcome::Complete(Message {
+                cid: 1,
+                cmd: CMD_INIT,
+                len: 8
+            })
+        );
+        assert_eq!(buf[..8], nonce);
+        assert_eq!(assembler.busy_cid(), None);
+
+        // Any other init packet on the channel aborts the message.
+        assert_eq!(assembler.push(&packets[0], &mut buf), Outcome::Pending);
+        assert_eq!(
+            assembler.push(&init(1, CMD_PING, &[]), &mut buf),
+            error(1, Error::InvalidSequence)
+        );
+        assert_eq!(assembler.busy_cid(), None);
+    }
+
+    #[test]
+    fn spurious_continuation() {
+        let mut assembler = assembler();
+        let mut buf = [0; 256];
+
+        assert_eq!(assembler.push(&cont(1, 0), &mut buf), Outcome::Ignored);
+
+        let packets: Vec<_> = Fragments::new(1, CMD_PING, &[0xaa; 100]).collect();
+        assert_eq!(assembler.push(&packets[0], &mut buf), Outcome::Pending);
+        assert_eq!(assembler.push(&cont(2, 0), &mut buf), Outcome::Ignored);
+        assert_eq!(assembler.busy_cid(), Some(1));
+        assert!(matches!(assembler.push(&packets[1], &mut buf), Outcome::Complete(_)));
+
+        assembler.push(&packets[0], &mut buf);
+        assembler.abort();
+        assert_eq!(assembler.push(&packets[1], &mut buf), Outcome::Ignored);
+    }
+}
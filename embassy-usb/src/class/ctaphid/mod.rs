@@ -0,0 +1,460 @@
+//! FIDO CTAPHID transport, for security keys speaking CTAP2 and U2F (CTAP1).
+//!
+//! The class is a HID interface exchanging 64-byte reports, which carry CTAPHID packets.
+//! It handles the transport itself:
+//!
+//! - channel allocation with `CTAPHID_INIT`, and channel resynchronization,
+//! - reassembly of requests from init and continuation packets, and fragmentation of responses,
+//! - the `CTAPHID_PING`, `CTAPHID_WINK`, `CTAPHID_LOCK` and `CTAPHID_CANCEL` commands,
+//! - keepalive messages while a CTAP2 request is processed,
+//! - transaction timeouts, and busy channel errors while a request is processed.
+//!
+//! Complete `CTAPHID_MSG` (U2F), `CTAPHID_CBOR` (CTAP2) and vendor messages are passed to an
+//! [`Authenticator`].
+//!
+//! Requires the `time` feature.
+
+use core::cell::Cell;
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{with_timeout, Duration, Instant, Ticker};
+
+use super::hid::descriptor::usage::fido;
+use super::hid::descriptor::{item_flags, usage_page, Collection, ReportDescriptor};
+use super::hid::{self, HidReader, HidReaderWriter, HidWriter, ReadError};
+use crate::driver::{Driver, EndpointError};
+use crate::Builder;
+
+mod framing;
+
+use framing::{Assembler, Fragments, InitHeader, Message, Outcome, PACKET_SIZE};
+pub use framing::{BROADCAST_CID, MAX_MESSAGE_SIZE};
+
+const CMD_PING: u8 = 0x01;
+const CMD_MSG: u8 = 0x03;
+const CMD_LOCK: u8 = 0x04;
+const CMD_INIT: u8 = 0x06;
+const CMD_WINK: u8 = 0x08;
+const CMD_CBOR: u8 = 0x10;
+const CMD_CANCEL: u8 = 0x11;
+const CMD_KEEPALIVE: u8 = 0x3B;
+const CMD_ERROR: u8 = 0x3F;
+const CMD_VENDOR_FIRST: u8 = 0x40;
+const CMD_VENDOR_LAST: u8 = 0x7F;
+
+const CTAPHID_PROTOCOL_VERSION: u8 = 2;
+const INIT_NONCE_LEN: usize = 8;
+const LOCK_MAX_SECONDS: u8 = 10;
+
+/// CTAP2 status returned for cancelled requests.
+const CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2D;
+
+/// Maximum time between two packets of a message.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_millis(500);
+/// Interval between keepalive messages.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+
+static REPORT_DESCRIPTOR: ReportDescriptor<27> = ReportDescriptor::new()
+    .usage_page(usage_page::FIDO)
+    .usage(fido::U2F_AUTHENTICATOR_DEVICE)
+    .collection(Collection::Application)
+    .usage(fido::INPUT_REPORT_DATA)
+    .logical_minimum(0)
+    .logical_maximum(255)
+    .report_size(8)
+    .report_count(PACKET_SIZE as u32)
+    .input(item_flags::DATA | item_flags::VARIABLE | item_flags::ABSOLUTE)
+    .usage(fido::OUTPUT_REPORT_DATA)
+    .report_count(PACKET_SIZE as u32)
+    .output(item_flags::DATA | item_flags::VARIABLE | item_flags::ABSOLUTE)
+    .end_collection();
+
+/// Capability flags reported in the `CTAPHID_INIT` response.
+pub mod capabilities {
+    /// The authenticator implements `CTAPHID_WINK`.
+    pub const WINK: u8 = 0x01;
+    /// The authenticator implements `CTAPHID_CBOR`.
+    pub const CBOR: u8 = 0x04;
+    /// The authenticator doesn't implement `CTAPHID_MSG`.
+    pub const NMSG: u8 = 0x08;
+}
+
+/// CTAPHID error codes, sent to the host with `CTAPHID_ERROR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The command is unknown.
+    InvalidCommand = 0x01,
+    /// A parameter of the command is invalid.
+    InvalidParameter = 0x02,
+    /// The length of the message is invalid.
+    InvalidLength = 0x03,
+    /// A continuation packet is out of sequence.
+    InvalidSequence = 0x04,
+    /// The message wasn't completed in time.
+    MessageTimeout = 0x05,
+    /// Another channel is being served.
+    ChannelBusy = 0x06,
+    /// The command requires a channel lock.
+    LockRequired = 0x0A,
+    /// The channel ID is invalid.
+    InvalidChannel = 0x0B,
+    /// Unspecified error.
+    Other = 0x7F,
+}
+
+/// Configuration for the CTAPHID class.
+pub struct Config {
+    /// Capabilities of the authenticator, a combination of [`capabilities`] flags.
+    pub capabilities: u8,
+
+    /// Major, minor and build version numbers of the device, reported in the `CTAPHID_INIT` response.
+    pub device_version: [u8; 3],
+
+    /// Configures how frequently the host should poll for reading/writing reports, in milliseconds.
+    pub poll_ms: u8,
+}
+
+/// Message command passed to the [`Authenticator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// `CTAPHID_MSG`: a U2F (CTAP1) APDU.
+    Msg,
+    /// `CTAPHID_CBOR`: a CTAP2 command byte followed by its CBOR parameters.
+    Cbor,
+    /// Vendor specific command, in the range `0x40..=0x7F`.
+    Vendor(u8),
+}
+
+impl Command {
+    fn code(self) -> u8 {
+        match self {
+            Command::Msg => CMD_MSG,
+            Command::Cbor => CMD_CBOR,
+            Command::Vendor(code) => code,
+        }
+    }
+}
+
+/// Status reported to the host in keepalive messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeepaliveStatus {
+    /// The authenticator is processing the request.
+    Processing = 1,
+    /// The authenticator is waiting for user presence.
+    UpNeeded = 2,
+}
+
+/// Keepalive status of the request being processed.
+pub struct Keepalive {
+    status: Cell<KeepaliveStatus>,
+}
+
+impl Keepalive {
+    fn new() -> Self {
+        Self {
+            status: Cell::new(KeepaliveStatus::Processing),
+        }
+    }
+
+    /// Set the status sent in the following keepalive messages.
+    pub fn set(&self, status: KeepaliveStatus) {
+        self.status.set(status);
+    }
+}
+
+/// Authenticator serving the messages received by the [`CtapHidClass`].
+pub trait Authenticator {
+    /// Process a request and write the response to `response`, returning its length.
+    ///
+    /// For [`Command::Cbor`], keepalive messages are sent to the host every 100 ms while the
+    /// request is processed, with the status set in `keepalive`. If the host cancels the
+    /// request, this future is dropped and the class responds with `CTAP2_ERR_KEEPALIVE_CANCEL`.
+    ///
+    /// Returning an error sends it to the host instead of a response.
+    async fn call(
+        &mut self,
+        command: Command,
+        request: &[u8],
+        response: &mut [u8],
+        keepalive: &Keepalive,
+    ) -> Result<usize, Error>;
+
+    /// Identify the authenticator to the user, for example by blinking a LED.
+    ///
+    /// Only called if [`Config::capabilities`] includes [`capabilities::WINK`].
+    fn wink(&mut self) {}
+}
+
+/// Internal state for the CTAPHID class.
+pub struct State<'d> {
+    hid: hid::State<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self { hid: hid::State::new() }
+    }
+}
+
+/// Why the processing of a request was interrupted.
+enum Interrupt {
+    /// The host cancelled the request.
+    Cancel,
+    /// The host resynchronized the channel, with this `CTAPHID_INIT` nonce.
+    Init([u8; INIT_NONCE_LEN]),
+}
+
+/// FIDO CTAPHID class.
+pub struct CtapHidClass<'d, D: Driver<'d>> {
+    reader: HidReader<'d, D, PACKET_SIZE>,
+    writer: HidWriter<'d, D, PACKET_SIZE>,
+    capabilities: u8,
+    device_version: [u8; 3],
+    assembler: Assembler,
+    lock: Option<(u32, Instant)>,
+}
+
+impl<'d, D: Driver<'d>> CtapHidClass<'d, D> {
+    /// Creates a new CTAPHID class.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config) -> Self {
+        let hid_config = hid::Config {
+            report_descriptor: REPORT_DESCRIPTOR.as_bytes(),
+            request_handler: None,
+            poll_ms: config.poll_ms,
+            max_packet_size: PACKET_SIZE as u16,
+        };
+        let (reader, writer) =
+            HidReaderWriter::<_, PACKET_SIZE, PACKET_SIZE>::new(builder, &mut state.hid, hid_config).split();
+
+        Self {
+            reader,
+            writer,
+            capabilities: config.capabilities,
+            device_version: config.device_version,
+            assembler: Assembler::new(),
+            lock: None,
+        }
+    }
+
+    /// Serve the host, passing messages to `authenticator`.
+    ///
+    /// Requests are reassembled into `request`, and responses are written to `response`.
+    /// Messages are up to [`MAX_MESSAGE_SIZE`] bytes long, and longer requests are rejected.
+    pub async fn run<A: Authenticator>(&mut self, authenticator: &mut A, request: &mut [u8], response: &mut [u8]) -> ! {
+        let request_len = request.len().min(MAX_MESSAGE_SIZE);
+        let request = &mut request[..request_len];
+        let response_len = response.len().min(MAX_MESSAGE_SIZE);
+        let response = &mut response[..response_len];
+        loop {
+            self.reader.ready().await;
+            self.writer.ready().await;
+            let e = self.serve(authenticator, request, response).await;
+            debug!("CTAPHID transfer ended: {:?}", e);
+            self.assembler.reset();
+            self.lock = None;
+        }
+    }
+
+    async fn serve<A: Authenticator>(
+        &mut self,
+        authenticator: &mut A,
+        request: &mut [u8],
+        response: &mut [u8],
+    ) -> EndpointError {
+        loop {
+            match self.serve_message(authenticator, request, response).await {
+                Ok(()) => {}
+                Err(e) => return e,
+            }
+        }
+    }
+
+    async fn serve_message<A: Authenticator>(
+        &mut self,
+        authenticator: &mut A,
+        request: &mut [u8],
+        response: &mut [u8],
+    ) -> Result<(), EndpointError> {
+        let mut packet = [0; PACKET_SIZE];
+        if let Some(cid) = self.assembler.busy_cid() {
+            if with_timeout(TRANSACTION_TIMEOUT, self.read_packet(&mut packet))
+                .await
+                .is_err()
+            {
+                self.assembler.abort();
+                return self.send_error(cid, Error::MessageTimeout).await;
+            }
+        } else {
+            self.read_packet(&mut packet).await?;
+        }
+
+        let message = match self.assembler.push(&packet, request) {
+            Outcome::Complete(message) => message,
+            Outcome::Error { cid, error } => return self.send_error(cid, error).await,
+            Outcome::Pending | Outcome::Ignored => return Ok(()),
+        };
+
+        if let Some((cid, until)) = self.lock {
+            if Instant::now() >= until {
+                self.lock = None;
+            } else if cid != message.cid {
+                return self.send_error(message.cid, Error::ChannelBusy).await;
+            }
+        }
+
+        let Message { cid, cmd, len } = message;
+        let data = &request[..len];
+        trace!("CTAPHID command {:02x} on channel {:08x}, {} bytes", cmd, cid, len);
+        match cmd {
+            CMD_INIT => {
+                let Ok(nonce) = data.try_into() else {
+                    return self.send_error(cid, Error::InvalidLength).await;
+                };
+                let new_cid = if cid == BROADCAST_CID {
+                    self.assembler.allocate()
+                } else {
+                    cid
+                };
+                self.send_init(cid, nonce, new_cid).await
+            }
+            CMD_PING => self.send(cid, CMD_PING, data).await,
+            CMD_WINK if self.capabilities & capabilities::WINK != 0 => {
+                authenticator.wink();
+                self.send(cid, CMD_WINK, &[]).await
+            }
+            CMD_LOCK => match data {
+                &[seconds] if seconds <= LOCK_MAX_SECONDS => {
+                    self.lock = (seconds > 0).then(|| (cid, Instant::now() + Duration::from_secs(seconds as u64)));
+                    self.send(cid, CMD_LOCK, &[]).await
+                }
+                _ => self.send_error(cid, Error::InvalidParameter).await,
+            },
+            // Nothing is being processed.
+            CMD_CANCEL => Ok(()),
+            CMD_MSG if self.capabilities & capabilities::NMSG == 0 => {
+                self.call(authenticator, cid, Command::Msg, data, response).await
+            }
+            CMD_CBOR if self.capabilities & capabilities::CBOR != 0 => {
+                self.call(authenticator, cid, Command::Cbor, data, response).await
+            }
+            CMD_VENDOR_FIRST..=CMD_VENDOR_LAST => {
+                self.call(authenticator, cid, Command::Vendor(cmd), data, response)
+                    .await
+            }
+            _ => self.send_error(cid, Error::InvalidCommand).await,
+        }
+    }
+
+    /// Pass a message to the authenticator, while serving keepalives, cancellation and
+    /// other channels.
+    async fn call<A: Authenticator>(
+        &mut self,
+        authenticator: &mut A,
+        cid: u32,
+        command: Command,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<(), EndpointError> {
+        let keepalive = Keepalive::new();
+        let result = select(
+            authenticator.call(command, request, response, &keepalive),
+            self.service(cid, command == Command::Cbor, &keepalive),
+        )
+        .await;
+
+        match result {
+            Either::First(Ok(len)) => self.send(cid, command.code(), &response[..len]).await,
+            Either::First(Err(error)) => self.send_error(cid, error).await,
+            Either::Second(Ok(Interrupt::Cancel)) => {
+                debug!("CTAPHID request cancelled");
+                self.send(cid, CMD_CBOR, &[CTAP2_ERR_KEEPALIVE_CANCEL]).await
+            }
+            Either::Second(Ok(Interrupt::Init(nonce))) => {
+                debug!("CTAPHID request aborted by channel resynchronization");
+                self.send_init(cid, nonce, cid).await
+            }
+            Either::Second(Err(e)) => Err(e),
+        }
+    }
+
+    /// Serve the host while a request of channel `cid` is being processed.
+    async fn service(&mut self, cid: u32, cbor: bool, keepalive: &Keepalive) -> Result<Interrupt, EndpointError> {
+        let mut ticker = Ticker::every(KEEPALIVE_INTERVAL);
+        let mut packet = [0; PACKET_SIZE];
+        loop {
+            match select(ticker.next(), self.read_packet(&mut packet)).await {
+                Either::First(()) => {
+                    if cbor {
+                        self.send(cid, CMD_KEEPALIVE, &[keepalive.status.get() as u8]).await?;
+                    }
+                }
+                Either::Second(result) => {
+                    result?;
+                    // Continuation packets can only belong to another, spurious, message.
+                    let Some(header) = InitHeader::parse(&packet) else {
+                        continue;
+                    };
+                    if header.cid != cid {
+                        self.send_error(header.cid, Error::ChannelBusy).await?;
+                        continue;
+                    }
+                    match header.cmd {
+                        CMD_CANCEL if cbor => return Ok(Interrupt::Cancel),
+                        CMD_CANCEL => {}
+                        CMD_INIT => match header.data(&packet).try_into() {
+                            Ok(nonce) if header.len == INIT_NONCE_LEN => return Ok(Interrupt::Init(nonce)),
+                            _ => self.send_error(cid, Error::InvalidLength).await?,
+                        },
+                        _ => self.send_error(cid, Error::ChannelBusy).await?,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn read_packet(&mut self, packet: &mut [u8; PACKET_SIZE]) -> Result<(), EndpointError> {
+        loop {
+            match self.reader.read(packet).await {
+                Ok(n) => {
+                    // Some hosts don't pad reports.
+                    packet[n..].fill(0);
+                    return Ok(());
+                }
+                Err(ReadError::Disabled) => return Err(EndpointError::Disabled),
+                Err(e) => warn!("CTAPHID packet dropped: {:?}", e),
+            }
+        }
+    }
+
+    async fn send_init(&mut self, cid: u32, nonce: [u8; INIT_NONCE_LEN], new_cid: u32) -> Result<(), EndpointError> {
+        let mut data = [0; 17];
+        data[..8].copy_from_slice(&nonce);
+        data[8..12].copy_from_slice(&new_cid.to_be_bytes());
+        data[12] = CTAPHID_PROTOCOL_VERSION;
+        data[13..16].copy_from_slice(&self.device_version);
+        data[16] = self.capabilities;
+        self.send(cid, CMD_INIT, &data).await
+    }
+
+    async fn send_error(&mut self, cid: u32, error: Error) -> Result<(), EndpointError> {
+        debug!("CTAPHID error on channel {:08x}: {:?}", cid, error);
+        self.send(cid, CMD_ERROR, &[error as u8]).await
+    }
+
+    async fn send(&mut self, cid: u32, cmd: u8, data: &[u8]) -> Result<(), EndpointError> {
+        for packet in Fragments::new(cid, cmd, data) {
+            self.writer.write(&packet).await?;
+        }
+        Ok(())
+    }
+}
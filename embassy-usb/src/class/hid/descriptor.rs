@@ -0,0 +1,504 @@
+//! HID report descriptor builder.
+//!
+//! [`ReportDescriptor`] encodes report descriptor items into a fixed size buffer. All its
+//! methods are `const fn`, so descriptors can be built at compile time:
+//!
+//! ```
+//! use embassy_usb::class::hid::descriptor::{item_flags, usage_page, Collection, ReportDescriptor};
+//!
+//! static DESCRIPTOR: ReportDescriptor<64> = ReportDescriptor::new()
+//!     .usage_page(usage_page::VENDOR_DEFINED_START)
+//!     .usage(0x01)
+//!     .collection(Collection::Application)
+//!     // Input report 1: 4 bytes
+//!     .report_id(1)
+//!     .usage(0x02)
+//!     .logical_minimum(0)
+//!     .logical_maximum(255)
+//!     .report_size(8)
+//!     .report_count(4)
+//!     .input(item_flags::DATA | item_flags::VARIABLE | item_flags::ABSOLUTE)
+//!     // Feature report 2: a signed 16-bit value
+//!     .report_id(2)
+//!     .usage(0x03)
+//!     .logical_minimum(-32768)
+//!     .logical_maximum(32767)
+//!     .report_size(16)
+//!     .report_count(1)
+//!     .feature(item_flags::DATA | item_flags::VARIABLE | item_flags::ABSOLUTE)
+//!     .end_collection();
+//!
+//! let report_descriptor: &[u8] = DESCRIPTOR.as_bytes();
+//! # let _ = report_descriptor;
+//! ```
+//!
+//! The ready-made descriptors in [`devices`](super::devices) are built the same way.
+
+/// Report descriptor item types.
+const TYPE_MAIN: u8 = 0;
+const TYPE_GLOBAL: u8 = 1;
+const TYPE_LOCAL: u8 = 2;
+
+// Main item tags
+pub(crate) const MAIN_INPUT: u8 = 0x8;
+pub(crate) const MAIN_OUTPUT: u8 = 0x9;
+pub(crate) const MAIN_FEATURE: u8 = 0xB;
+pub(crate) const MAIN_COLLECTION: u8 = 0xA;
+pub(crate) const MAIN_END_COLLECTION: u8 = 0xC;
+
+// Global item tags
+pub(crate) const GLOBAL_USAGE_PAGE: u8 = 0x0;
+pub(crate) const GLOBAL_LOGICAL_MINIMUM: u8 = 0x1;
+pub(crate) const GLOBAL_LOGICAL_MAXIMUM: u8 = 0x2;
+pub(crate) const GLOBAL_PHYSICAL_MINIMUM: u8 = 0x3;
+pub(crate) const GLOBAL_PHYSICAL_MAXIMUM: u8 = 0x4;
+pub(crate) const GLOBAL_UNIT_EXPONENT: u8 = 0x5;
+pub(crate) const GLOBAL_UNIT: u8 = 0x6;
+pub(crate) const GLOBAL_REPORT_SIZE: u8 = 0x7;
+pub(crate) const GLOBAL_REPORT_ID: u8 = 0x8;
+pub(crate) const GLOBAL_REPORT_COUNT: u8 = 0x9;
+pub(crate) const GLOBAL_PUSH: u8 = 0xA;
+pub(crate) const GLOBAL_POP: u8 = 0xB;
+
+// Local item tags
+pub(crate) const LOCAL_USAGE: u8 = 0x0;
+pub(crate) const LOCAL_USAGE_MINIMUM: u8 = 0x1;
+pub(crate) const LOCAL_USAGE_MAXIMUM: u8 = 0x2;
+
+/// Flags of the Input, Output and Feature main items.
+///
+/// Each pair of flags is mutually exclusive, and the first one of a pair is the default
+/// (zero) value, so flags can be combined with `|`.
+pub mod item_flags {
+    /// The item is data that can change.
+    pub const DATA: u16 = 0x000;
+    /// The item is a constant, typically used for padding.
+    pub const CONSTANT: u16 = 0x001;
+    /// Each field is an index into the usages of the item.
+    pub const ARRAY: u16 = 0x000;
+    /// Each field holds the value of one usage of the item.
+    pub const VARIABLE: u16 = 0x002;
+    /// Values are absolute.
+    pub const ABSOLUTE: u16 = 0x000;
+    /// Values are relative to the previous report.
+    pub const RELATIVE: u16 = 0x004;
+    /// Values don't wrap around.
+    pub const NO_WRAP: u16 = 0x000;
+    /// Values wrap around when going past the logical range.
+    pub const WRAP: u16 = 0x008;
+    /// Values are linear.
+    pub const LINEAR: u16 = 0x000;
+    /// Values aren't linear.
+    pub const NON_LINEAR: u16 = 0x010;
+    /// The control returns to a preferred state when not interacted with.
+    pub const PREFERRED_STATE: u16 = 0x000;
+    /// The control has no preferred state.
+    pub const NO_PREFERRED: u16 = 0x020;
+    /// Values outside the logical range are invalid.
+    pub const NO_NULL_POSITION: u16 = 0x000;
+    /// Values outside the logical range mean the control isn't sending meaningful data.
+    pub const NULL_STATE: u16 = 0x040;
+    /// Output and Feature items only: the value is only changed by the host.
+    pub const NON_VOLATILE: u16 = 0x000;
+    /// Output and Feature items only: the value may be changed by the device.
+    pub const VOLATILE: u16 = 0x080;
+    /// The item is a bit field.
+    pub const BIT_FIELD: u16 = 0x000;
+    /// The item is a fixed size stream of bytes.
+    pub const BUFFERED_BYTES: u16 = 0x100;
+}
+
+/// Common usage pages.
+pub mod usage_page {
+    /// Generic Desktop Page
+    pub const GENERIC_DESKTOP: u16 = 0x01;
+    /// Simulation Controls Page
+    pub const SIMULATION: u16 = 0x02;
+    /// Generic Device Controls Page
+    pub const GENERIC_DEVICE: u16 = 0x06;
+    /// Keyboard/Keypad Page
+    pub const KEYBOARD: u16 = 0x07;
+    /// LED Page
+    pub const LED: u16 = 0x08;
+    /// Button Page
+    pub const BUTTON: u16 = 0x09;
+    /// Ordinal Page
+    pub const ORDINAL: u16 = 0x0A;
+    /// Consumer Page
+    pub const CONSUMER: u16 = 0x0C;
+    /// Digitizers Page
+    pub const DIGITIZER: u16 = 0x0D;
+    /// FIDO Alliance Page
+    pub const FIDO: u16 = 0xF1D0;
+    /// First vendor defined page. Pages `0xFF00..=0xFFFF` are vendor defined.
+    pub const VENDOR_DEFINED_START: u16 = 0xFF00;
+}
+
+/// Common usages, grouped by usage page.
+pub mod usage {
+    /// Generic Desktop Page usages
+    pub mod generic_desktop {
+        /// Pointer
+        pub const POINTER: u16 = 0x01;
+        /// Mouse
+        pub const MOUSE: u16 = 0x02;
+        /// Joystick
+        pub const JOYSTICK: u16 = 0x04;
+        /// Gamepad
+        pub const GAMEPAD: u16 = 0x05;
+        /// Keyboard
+        pub const KEYBOARD: u16 = 0x06;
+        /// Keypad
+        pub const KEYPAD: u16 = 0x07;
+        /// X
+        pub const X: u16 = 0x30;
+        /// Y
+        pub const Y: u16 = 0x31;
+        /// Z
+        pub const Z: u16 = 0x32;
+        /// Rx
+        pub const RX: u16 = 0x33;
+        /// Ry
+        pub const RY: u16 = 0x34;
+        /// Rz
+        pub const RZ: u16 = 0x35;
+        /// Slider
+        pub const SLIDER: u16 = 0x36;
+        /// Dial
+        pub const DIAL: u16 = 0x37;
+        /// Wheel
+        pub const WHEEL: u16 = 0x38;
+        /// Hat Switch
+        pub const HAT_SWITCH: u16 = 0x39;
+    }
+
+    /// Consumer Page usages
+    pub mod consumer {
+        /// Consumer Control
+        pub const CONSUMER_CONTROL: u16 = 0x01;
+        /// Highest usage ID of the Consumer Page
+        pub const MAXIMUM: u16 = 0x03FF;
+        /// AC Pan
+        pub const AC_PAN: u16 = 0x0238;
+    }
+
+    /// Digitizers Page usages
+    pub mod digitizer {
+        /// Digitizer
+        pub const DIGITIZER: u16 = 0x01;
+        /// Pen
+        pub const PEN: u16 = 0x02;
+        /// Touch Screen
+        pub const TOUCH_SCREEN: u16 = 0x04;
+        /// Stylus
+        pub const STYLUS: u16 = 0x20;
+        /// Finger
+        pub const FINGER: u16 = 0x22;
+        /// Tip Pressure
+        pub const TIP_PRESSURE: u16 = 0x30;
+        /// In Range
+        pub const IN_RANGE: u16 = 0x32;
+        /// Tip Switch
+        pub const TIP_SWITCH: u16 = 0x42;
+        /// Barrel Switch
+        pub const BARREL_SWITCH: u16 = 0x44;
+        /// Invert
+        pub const INVERT: u16 = 0x3C;
+        /// Eraser
+        pub const ERASER: u16 = 0x45;
+    }
+
+    /// LED Page usages
+    pub mod led {
+        /// Num Lock
+        pub const NUM_LOCK: u16 = 0x01;
+        /// Caps Lock
+        pub const CAPS_LOCK: u16 = 0x02;
+        /// Scroll Lock
+        pub const SCROLL_LOCK: u16 = 0x03;
+        /// Compose
+        pub const COMPOSE: u16 = 0x04;
+        /// Kana
+        pub const KANA: u16 = 0x05;
+    }
+
+    /// FIDO Alliance Page usages
+    pub mod fido {
+        /// U2F Authenticator Device, the application collection of CTAPHID devices
+        pub const U2F_AUTHENTICATOR_DEVICE: u16 = 0x01;
+        /// Input Report Data
+        pub const INPUT_REPORT_DATA: u16 = 0x20;
+        /// Output Report Data
+        pub const OUTPUT_REPORT_DATA: u16 = 0x21;
+    }
+
+    /// Keyboard/Keypad Page usages
+    pub mod keyboard {
+        /// Left Control, the first modifier key
+        pub const LEFT_CONTROL: u16 = 0xE0;
+        /// Right GUI, the last modifier key
+        pub const RIGHT_GUI: u16 = 0xE7;
+    }
+}
+
+/// Collection types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Collection {
+    /// A group of axes, such as a sensor.
+    Physical = 0x00,
+    /// A top-level collection, grouping items that applications see as a single device.
+    Application = 0x01,
+    /// Interrelated data items.
+    Logical = 0x02,
+    /// A group of items forming a report.
+    Report = 0x03,
+    /// An array of selectors.
+    NamedArray = 0x04,
+    /// A usage that modifies the meaning of the usages it contains.
+    UsageSwitch = 0x05,
+    /// A usage that modifies the meaning of the usages attached to it.
+    UsageModifier = 0x06,
+}
+
+/// HID report descriptor, built item by item.
+///
+/// `N` is the capacity of the descriptor, in bytes. Adding an item past the capacity panics,
+/// which fails the build when the descriptor is built in a `const` context.
+//
+// Methods use `core::assert!`, as the `defmt` one can't be evaluated in const fns.
+#[derive(Clone)]
+pub struct ReportDescriptor<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for ReportDescriptor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReportDescriptor<N> {
+    /// Create an empty report descriptor.
+    pub const fn new() -> Self {
+        Self { buf: [0; N], len: 0 }
+    }
+
+    /// Returns the encoded descriptor, to be used as [`Config::report_descriptor`](super::Config::report_descriptor).
+    pub const fn as_bytes(&self) -> &[u8] {
+        // `split_at` is the only const way to get a subslice.
+        self.buf.split_at(self.len).0
+    }
+
+    /// Returns the length of the encoded descriptor.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if no item has been added yet.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append raw, already encoded, items.
+    pub const fn raw(mut self, data: &[u8]) -> Self {
+        core::assert!(self.len + data.len() <= N, "HID report descriptor buffer too small");
+        let mut i = 0;
+        while i < data.len() {
+            self.buf[self.len] = data[i];
+            self.len += 1;
+            i += 1;
+        }
+        self
+    }
+
+    /// Append a short item with `size` bytes of data (0, 1, 2 or 4).
+    const fn item(mut self, item_type: u8, tag: u8, data: u32, size: usize) -> Self {
+        let size_code = match size {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+        let size = if size > 2 { 4 } else { size };
+        core::assert!(self.len + 1 + size <= N, "HID report descriptor buffer too small");
+
+        self.buf[self.len] = tag << 4 | item_type << 2 | size_code;
+        self.len += 1;
+        let mut i = 0;
+        while i < size {
+            self.buf[self.len] = (data >> (8 * i)) as u8;
+            self.len += 1;
+            i += 1;
+        }
+        self
+    }
+
+    /// Append an item with unsigned data, using the shortest encoding.
+    const fn item_unsigned(self, item_type: u8, tag: u8, data: u32) -> Self {
+        let size = if data <= 0xFF {
+            1
+        } else if data <= 0xFFFF {
+            2
+        } else {
+            4
+        };
+        self.item(item_type, tag, data, size)
+    }
+
+    /// Append an item with signed data, using the shortest encoding.
+    const fn item_signed(self, item_type: u8, tag: u8, data: i32) -> Self {
+        let size = if data >= i8::MIN as i32 && data <= i8::MAX as i32 {
+            1
+        } else if data >= i16::MIN as i32 && data <= i16::MAX as i32 {
+            2
+        } else {
+            4
+        };
+        self.item(item_type, tag, data as u32, size)
+    }
+
+    /// Input main item, describing fields of input reports (device to host).
+    ///
+    /// `flags` is a combination of [`item_flags`].
+    pub const fn input(self, flags: u16) -> Self {
+        self.item_unsigned(TYPE_MAIN, MAIN_INPUT, flags as u32)
+    }
+
+    /// Output main item, describing fields of output reports (host to device).
+    ///
+    /// `flags` is a combination of [`item_flags`].
+    pub const fn output(self, flags: u16) -> Self {
+        self.item_unsigned(TYPE_MAIN, MAIN_OUTPUT, flags as u32)
+    }
+
+    /// Feature main item, describing fields of feature reports (both directions, over the control pipe).
+    ///
+    /// `flags` is a combination of [`item_flags`].
+    pub const fn feature(self, flags: u16) -> Self {
+        self.item_unsigned(TYPE_MAIN, MAIN_FEATURE, flags as u32)
+    }
+
+    /// Open a collection. Must be matched by a call to [`end_collection`](Self::end_collection).
+    pub const fn collection(self, collection: Collection) -> Self {
+        self.item(TYPE_MAIN, MAIN_COLLECTION, collection as u32, 1)
+    }
+
+    /// Close the innermost open collection.
+    pub const fn end_collection(self) -> Self {
+        self.item(TYPE_MAIN, MAIN_END_COLLECTION, 0, 0)
+    }
+
+    /// Set the usage page of the following usages.
+    pub const fn usage_page(self, page: u16) -> Self {
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_USAGE_PAGE, page as u32)
+    }
+
+    /// Set the smallest value the following fields can report.
+    pub const fn logical_minimum(self, value: i32) -> Self {
+        self.item_signed(TYPE_GLOBAL, GLOBAL_LOGICAL_MINIMUM, value)
+    }
+
+    /// Set the largest value the following fields can report.
+    pub const fn logical_maximum(self, value: i32) -> Self {
+        self.item_signed(TYPE_GLOBAL, GLOBAL_LOGICAL_MAXIMUM, value)
+    }
+
+    /// Set the physical value corresponding to the logical minimum.
+    pub const fn physical_minimum(self, value: i32) -> Self {
+        self.item_signed(TYPE_GLOBAL, GLOBAL_PHYSICAL_MINIMUM, value)
+    }
+
+    /// Set the physical value corresponding to the logical maximum.
+    pub const fn physical_maximum(self, value: i32) -> Self {
+        self.item_signed(TYPE_GLOBAL, GLOBAL_PHYSICAL_MAXIMUM, value)
+    }
+
+    /// Set the base 10 exponent of the unit of the following fields, in the range `-8..=7`.
+    pub const fn unit_exponent(self, exponent: i8) -> Self {
+        core::assert!(exponent >= -8 && exponent <= 7, "unit exponent out of range");
+        self.item(TYPE_GLOBAL, GLOBAL_UNIT_EXPONENT, (exponent as u32) & 0x0F, 1)
+    }
+
+    /// Set the unit of the following fields, encoded as described in the HID specification.
+    pub const fn unit(self, unit: u32) -> Self {
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_UNIT, unit)
+    }
+
+    /// Set the size, in bits, of each of the following fields.
+    pub const fn report_size(self, bits: u32) -> Self {
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_REPORT_SIZE, bits)
+    }
+
+    /// Set the ID of the report the following fields belong to.
+    ///
+    /// Once a report ID is used, all reports must have one, and the ID is sent as the first
+    /// byte of each report. `0` is reserved.
+    pub const fn report_id(self, id: u8) -> Self {
+        core::assert!(id != 0, "report ID 0 is reserved");
+        self.item(TYPE_GLOBAL, GLOBAL_REPORT_ID, id as u32, 1)
+    }
+
+    /// Set the number of fields of the following main items.
+    pub const fn report_count(self, count: u32) -> Self {
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_REPORT_COUNT, count)
+    }
+
+    /// Save the global item state.
+    pub const fn push(self) -> Self {
+        self.item(TYPE_GLOBAL, GLOBAL_PUSH, 0, 0)
+    }
+
+    /// Restore the global item state saved by the last [`push`](Self::push).
+    pub const fn pop(self) -> Self {
+        self.item(TYPE_GLOBAL, GLOBAL_POP, 0, 0)
+    }
+
+    /// Add a usage, on the current usage page, to the next main item.
+    pub const fn usage(self, usage: u16) -> Self {
+        self.item_unsigned(TYPE_LOCAL, LOCAL_USAGE, usage as u32)
+    }
+
+    /// Add a usage on an explicit usage page to the next main item.
+    pub const fn extended_usage(self, page: u16, usage: u16) -> Self {
+        self.item(TYPE_LOCAL, LOCAL_USAGE, (page as u32) << 16 | usage as u32, 4)
+    }
+
+    /// Set the first usage of a range of usages of the next main item.
+    pub const fn usage_minimum(self, usage: u16) -> Self {
+        self.item_unsigned(TYPE_LOCAL, LOCAL_USAGE_MINIMUM, usage as u32)
+    }
+
+    /// Set the last usage of a range of usages of the next main item.
+    pub const fn usage_maximum(self, usage: u16) -> Self {
+        self.item_unsigned(TYPE_LOCAL, LOCAL_USAGE_MAXIMUM, usage as u32)
+    }
+
+    /// Set the range of usages of the next main item.
+    pub const fn usage_range(self, minimum: u16, maximum: u16) -> Self {
+        self.usage_minimum(minimum).usage_maximum(maximum)
+    }
+
+    /// Add `bits` of constant padding to the current report.
+    ///
+    /// This changes the report size and count, which must be set again for the next items.
+    pub const fn padding_input(self, bits: u32) -> Self {
+        self.report_size(bits).report_count(1).input(item_flags::CONSTANT)
+    }
+
+    /// Add `bits` of constant padding to the current output report.
+    ///
+    /// This changes the report size and count, which must be set again for the next items.
+    pub const fn padding_output(self, bits: u32) -> Self {
+        self.report_size(bits).report_count(1).output(item_flags::CONSTANT)
+    }
+
+    /// Add `bits` of constant padding to the current feature report.
+    ///
+    /// This changes the report size and count, which must be set again for the next items.
+    pub const fn padding_feature(self, bits: u32) -> Self {
+        self.report_size(bits).report_count(1).feature(item_flags::CONSTANT)
+    }
+}
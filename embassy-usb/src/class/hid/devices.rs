@@ -0,0 +1,438 @@
+//! Ready-made report descriptors and reports for common devices.
+//!
+//! Each device comes as:
+//!
+//! - a `static` descriptor without report ID, to be used as [`Config::report_descriptor`](super::Config::report_descriptor),
+//! - a `const fn` appending the same application collection to a [`ReportDescriptor`], with
+//!   an optional report ID, to build composite descriptors,
+//! - a report type, serialized with `to_bytes`.
+//!
+//! When a report ID is used, it must be sent before the report bytes.
+//!
+//! ```
+//! use embassy_usb::class::hid::descriptor::ReportDescriptor;
+//! use embassy_usb::class::hid::devices::{consumer_control, keyboard_nkro, mouse};
+//!
+//! // A keyboard with media keys and a mouse on the same interface.
+//! static DESCRIPTOR: ReportDescriptor<192> =
+//!     mouse(consumer_control(keyboard_nkro(ReportDescriptor::new(), 1), 2), 3);
+//! # let _ = DESCRIPTOR.as_bytes();
+//! ```
+
+use super::descriptor::usage::{consumer, digitizer, generic_desktop, keyboard};
+use super::descriptor::{item_flags, usage_page, Collection, ReportDescriptor};
+
+const VARIABLE: u16 = item_flags::DATA | item_flags::VARIABLE | item_flags::ABSOLUTE;
+const RELATIVE: u16 = item_flags::DATA | item_flags::VARIABLE | item_flags::RELATIVE;
+const ARRAY: u16 = item_flags::DATA | item_flags::ARRAY | item_flags::ABSOLUTE;
+
+const fn with_report_id<const N: usize>(desc: ReportDescriptor<N>, report_id: u8) -> ReportDescriptor<N> {
+    if report_id == 0 {
+        desc
+    } else {
+        desc.report_id(report_id)
+    }
+}
+
+/// Highest key usage of [`KeyboardReport::keys`].
+pub const KEYBOARD_MAX_KEY: u8 = 0xA7;
+
+/// Append an N-key rollover keyboard, with `report_id` if it isn't 0.
+///
+/// The input report is [`KeyboardReport`]. The output report is one byte of LED states, with
+/// Num Lock in bit 0, followed by Caps Lock, Scroll Lock, Compose and Kana.
+pub const fn keyboard_nkro<const N: usize>(desc: ReportDescriptor<N>, report_id: u8) -> ReportDescriptor<N> {
+    with_report_id(
+        desc.usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(generic_desktop::KEYBOARD)
+            .collection(Collection::Application),
+        report_id,
+    )
+    // Modifiers
+    .usage_page(usage_page::KEYBOARD)
+    .usage_range(keyboard::LEFT_CONTROL, keyboard::RIGHT_GUI)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(8)
+    .input(VARIABLE)
+    // One bit per key
+    .usage_range(0, KEYBOARD_MAX_KEY as u16)
+    .report_count(KEYBOARD_MAX_KEY as u32 + 1)
+    .input(VARIABLE)
+    // LEDs
+    .usage_page(usage_page::LED)
+    .usage_range(1, 5)
+    .report_count(5)
+    .output(VARIABLE)
+    .padding_output(3)
+    .end_collection()
+}
+
+/// N-key rollover keyboard descriptor, see [`keyboard_nkro`].
+pub static KEYBOARD_NKRO: ReportDescriptor<47> = keyboard_nkro(ReportDescriptor::new(), 0);
+
+/// Input report of [`keyboard_nkro`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardReport {
+    /// Modifier keys, with Left Control in bit 0 up to Right GUI in bit 7.
+    pub modifiers: u8,
+    /// Key states, one bit per key usage up to [`KEYBOARD_MAX_KEY`].
+    pub keys: [u8; KEYBOARD_MAX_KEY as usize / 8 + 1],
+}
+
+impl KeyboardReport {
+    /// Length of the report in bytes.
+    pub const LEN: usize = 1 + KEYBOARD_MAX_KEY as usize / 8 + 1;
+
+    /// Sets the state of the key with usage `key`, from the Keyboard/Keypad page.
+    ///
+    /// Modifier keys (`0xE0..=0xE7`) are reflected in [`modifiers`](Self::modifiers). Other
+    /// keys above [`KEYBOARD_MAX_KEY`] are ignored.
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        let (byte, bit) = match key {
+            0xE0..=0xE7 => (&mut self.modifiers, key - 0xE0),
+            0..=KEYBOARD_MAX_KEY => (&mut self.keys[key as usize / 8], key % 8),
+            _ => return,
+        };
+        if pressed {
+            *byte |= 1 << bit;
+        } else {
+            *byte &= !(1 << bit);
+        }
+    }
+
+    /// Serializes the report.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut buf = [0; Self::LEN];
+        buf[0] = self.modifiers;
+        buf[1..].copy_from_slice(&self.keys);
+        buf
+    }
+}
+
+/// Append a 5-button mouse with a wheel and horizontal scrolling, with `report_id` if it isn't 0.
+///
+/// The input report is [`MouseReport`].
+pub const fn mouse<const N: usize>(desc: ReportDescriptor<N>, report_id: u8) -> ReportDescriptor<N> {
+    with_report_id(
+        desc.usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(generic_desktop::MOUSE)
+            .collection(Collection::Application),
+        report_id,
+    )
+    .usage(generic_desktop::POINTER)
+    .collection(Collection::Physical)
+    // Buttons
+    .usage_page(usage_page::BUTTON)
+    .usage_range(1, 5)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(5)
+    .input(VARIABLE)
+    .padding_input(3)
+    // Movement
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(generic_desktop::X)
+    .usage(generic_desktop::Y)
+    .usage(generic_desktop::WHEEL)
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(3)
+    .input(RELATIVE)
+    .usage_page(usage_page::CONSUMER)
+    .usage(consumer::AC_PAN)
+    .report_count(1)
+    .input(RELATIVE)
+    .end_collection()
+    .end_collection()
+}
+
+/// Mouse descriptor, see [`mouse`].
+pub static MOUSE: ReportDescriptor<61> = mouse(ReportDescriptor::new(), 0);
+
+/// Input report of [`mouse`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseReport {
+    /// Button states, with the primary button in bit 0.
+    pub buttons: u8,
+    /// Horizontal movement.
+    pub x: i8,
+    /// Vertical movement.
+    pub y: i8,
+    /// Vertical scrolling.
+    pub wheel: i8,
+    /// Horizontal scrolling.
+    pub pan: i8,
+}
+
+impl MouseReport {
+    /// Length of the report in bytes.
+    pub const LEN: usize = 5;
+
+    /// Serializes the report.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        [
+            self.buttons,
+            self.x as u8,
+            self.y as u8,
+            self.wheel as u8,
+            self.pan as u8,
+        ]
+    }
+}
+
+/// Hat switch value when no direction is pressed.
+pub const HAT_CENTERED: u8 = 8;
+
+/// Append a gamepad with 16 buttons, a hat switch, two sticks and two triggers, with
+/// `report_id` if it isn't 0.
+///
+/// The input report is [`GamepadReport`].
+pub const fn gamepad<const N: usize>(desc: ReportDescriptor<N>, report_id: u8) -> ReportDescriptor<N> {
+    with_report_id(
+        desc.usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(generic_desktop::GAMEPAD)
+            .collection(Collection::Application),
+        report_id,
+    )
+    // Buttons
+    .usage_page(usage_page::BUTTON)
+    .usage_range(1, 16)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(16)
+    .input(VARIABLE)
+    // Hat switch, 8 directions clockwise from up, in degrees
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(generic_desktop::HAT_SWITCH)
+    .logical_maximum(7)
+    .physical_minimum(0)
+    .physical_maximum(315)
+    .unit(0x14)
+    .report_size(4)
+    .report_count(1)
+    .input(VARIABLE | item_flags::NULL_STATE)
+    .unit(0)
+    .physical_maximum(0)
+    .padding_input(4)
+    // Sticks
+    .usage(generic_desktop::X)
+    .usage(generic_desktop::Y)
+    .usage(generic_desktop::Z)
+    .usage(generic_desktop::RZ)
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(4)
+    .input(VARIABLE)
+    // Triggers
+    .usage(generic_desktop::RX)
+    .usage(generic_desktop::RY)
+    .logical_minimum(0)
+    .logical_maximum(255)
+    .report_count(2)
+    .input(VARIABLE)
+    .end_collection()
+}
+
+/// Gamepad descriptor, see [`gamepad`].
+pub static GAMEPAD: ReportDescriptor<83> = gamepad(ReportDescriptor::new(), 0);
+
+/// Input report of [`gamepad`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GamepadReport {
+    /// Button states, with button 1 in bit 0.
+    pub buttons: u16,
+    /// Hat switch direction, from 0 (up) to 7 (up left) clockwise, or [`HAT_CENTERED`].
+    pub hat: u8,
+    /// Left stick horizontal position.
+    pub x: i8,
+    /// Left stick vertical position.
+    pub y: i8,
+    /// Right stick horizontal position.
+    pub z: i8,
+    /// Right stick vertical position.
+    pub rz: i8,
+    /// Left trigger position.
+    pub rx: u8,
+    /// Right trigger position.
+    pub ry: u8,
+}
+
+impl Default for GamepadReport {
+    fn default() -> Self {
+        Self {
+            buttons: 0,
+            hat: HAT_CENTERED,
+            x: 0,
+            y: 0,
+            z: 0,
+            rz: 0,
+            rx: 0,
+            ry: 0,
+        }
+    }
+}
+
+impl GamepadReport {
+    /// Length of the report in bytes.
+    pub const LEN: usize = 9;
+
+    /// Serializes the report.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let buttons = self.buttons.to_le_bytes();
+        [
+            buttons[0],
+            buttons[1],
+            self.hat & 0x0F,
+            self.x as u8,
+            self.y as u8,
+            self.z as u8,
+            self.rz as u8,
+            self.rx,
+            self.ry,
+        ]
+    }
+}
+
+/// Number of simultaneous usages of [`ConsumerControlReport`].
+pub const CONSUMER_CONTROL_USAGES: usize = 4;
+
+/// Append a consumer control (media keys), with `report_id` if it isn't 0.
+///
+/// The input report is [`ConsumerControlReport`].
+pub const fn consumer_control<const N: usize>(desc: ReportDescriptor<N>, report_id: u8) -> ReportDescriptor<N> {
+    with_report_id(
+        desc.usage_page(usage_page::CONSUMER)
+            .usage(consumer::CONSUMER_CONTROL)
+            .collection(Collection::Application),
+        report_id,
+    )
+    .usage_range(0, consumer::MAXIMUM)
+    .logical_minimum(0)
+    .logical_maximum(consumer::MAXIMUM as i32)
+    .report_size(16)
+    .report_count(CONSUMER_CONTROL_USAGES as u32)
+    .input(ARRAY)
+    .end_collection()
+}
+
+/// Consumer control descriptor, see [`consumer_control`].
+pub static CONSUMER_CONTROL: ReportDescriptor<23> = consumer_control(ReportDescriptor::new(), 0);
+
+/// Input report of [`consumer_control`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConsumerControlReport {
+    /// Usages of the pressed controls from the Consumer page, such as `0xCD` for Play/Pause,
+    /// or 0 for unused entries.
+    pub usages: [u16; CONSUMER_CONTROL_USAGES],
+}
+
+impl ConsumerControlReport {
+    /// Length of the report in bytes.
+    pub const LEN: usize = 2 * CONSUMER_CONTROL_USAGES;
+
+    /// Serializes the report.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut buf = [0; Self::LEN];
+        for (chunk, usage) in buf.chunks_exact_mut(2).zip(self.usages) {
+            chunk.copy_from_slice(&usage.to_le_bytes());
+        }
+        buf
+    }
+}
+
+/// Highest value of [`DigitizerReport::x`] and [`DigitizerReport::y`].
+pub const DIGITIZER_MAX_POSITION: u16 = 0x7FFF;
+
+/// Highest value of [`DigitizerReport::pressure`].
+pub const DIGITIZER_MAX_PRESSURE: u16 = 1023;
+
+/// Append a pen digitizer with absolute positioning, with `report_id` if it isn't 0.
+///
+/// The input report is [`DigitizerReport`].
+pub const fn digitizer<const N: usize>(desc: ReportDescriptor<N>, report_id: u8) -> ReportDescriptor<N> {
+    with_report_id(
+        desc.usage_page(usage_page::DIGITIZER)
+            .usage(digitizer::PEN)
+            .collection(Collection::Application),
+        report_id,
+    )
+    .usage(digitizer::STYLUS)
+    .collection(Collection::Physical)
+    // Switches
+    .usage(digitizer::TIP_SWITCH)
+    .usage(digitizer::BARREL_SWITCH)
+    .usage(digitizer::ERASER)
+    .usage(digitizer::IN_RANGE)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(4)
+    .input(VARIABLE)
+    .padding_input(4)
+    // Position
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(generic_desktop::X)
+    .usage(generic_desktop::Y)
+    .logical_maximum(DIGITIZER_MAX_POSITION as i32)
+    .report_size(16)
+    .report_count(2)
+    .input(VARIABLE)
+    // Pressure
+    .usage_page(usage_page::DIGITIZER)
+    .usage(digitizer::TIP_PRESSURE)
+    .logical_maximum(DIGITIZER_MAX_PRESSURE as i32)
+    .report_count(1)
+    .input(VARIABLE)
+    .end_collection()
+    .end_collection()
+}
+
+/// Pen digitizer descriptor, see [`digitizer()`].
+pub static DIGITIZER: ReportDescriptor<62> = digitizer(ReportDescriptor::new(), 0);
+
+/// Input report of [`digitizer()`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DigitizerReport {
+    /// The pen tip touches the surface.
+    pub tip: bool,
+    /// The barrel button is pressed.
+    pub barrel: bool,
+    /// The eraser end touches the surface.
+    pub eraser: bool,
+    /// The pen is in range of the digitizer.
+    pub in_range: bool,
+    /// Horizontal position, up to [`DIGITIZER_MAX_POSITION`].
+    pub x: u16,
+    /// Vertical position, up to [`DIGITIZER_MAX_POSITION`].
+    pub y: u16,
+    /// Tip pressure, up to [`DIGITIZER_MAX_PRESSURE`].
+    pub pressure: u16,
+}
+
+impl DigitizerReport {
+    /// Length of the report in bytes.
+    pub const LEN: usize = 7;
+
+    /// Serializes the report.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let switches =
+            self.tip as u8 | (self.barrel as u8) << 1 | (self.eraser as u8) << 2 | (self.in_range as u8) << 3;
+        let mut buf = [0; Self::LEN];
+        buf[0] = switches;
+        buf[1..3].copy_from_slice(&self.x.to_le_bytes());
+        buf[3..5].copy_from_slice(&self.y.to_le_bytes());
+        buf[5..7].copy_from_slice(&self.pressure.to_le_bytes());
+        buf
+    }
+}
@@ -0,0 +1,820 @@
+//! HID report descriptor parser.
+//!
+//! [`ReportLayout::parse`] walks a report descriptor and derives the position, size, logical
+//! range and usages of every field of every report. The layout then decodes raw reports into
+//! [`Report`]s, whose fields can be looked up by usage:
+//!
+//! - output reports received on the interrupt OUT endpoint, with [`HidReader::read_report`](super::HidReader::read_report),
+//! - output and feature reports received over the control pipe, by calling [`ReportLayout::decode`]
+//!   from [`RequestHandler::set_report`](super::RequestHandler::set_report).
+//!
+//! It can also be used to fill input reports, with [`Field::set`].
+//!
+//! Usages are handled as 32-bit extended usages, with the usage page in the upper 16 bits,
+//! see [`extended_usage`].
+
+use heapless::Vec;
+
+use super::descriptor::{
+    item_flags, GLOBAL_LOGICAL_MAXIMUM, GLOBAL_LOGICAL_MINIMUM, GLOBAL_POP, GLOBAL_PUSH, GLOBAL_REPORT_COUNT,
+    GLOBAL_REPORT_ID, GLOBAL_REPORT_SIZE, GLOBAL_USAGE_PAGE, LOCAL_USAGE, LOCAL_USAGE_MAXIMUM, LOCAL_USAGE_MINIMUM,
+    MAIN_COLLECTION, MAIN_END_COLLECTION, MAIN_FEATURE, MAIN_INPUT, MAIN_OUTPUT,
+};
+use super::ReportId;
+
+/// Maximum number of explicit usages of a single main item.
+pub const MAX_USAGES: usize = 16;
+
+/// Maximum nesting of `Push` items.
+const MAX_GLOBAL_STACK: usize = 4;
+
+/// Combine a usage page and a usage ID into a 32-bit extended usage.
+pub const fn extended_usage(page: u16, id: u16) -> u32 {
+    (page as u32) << 16 | id as u32
+}
+
+/// Kind of a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReportKind {
+    /// Input report, sent by the device.
+    Input,
+    /// Output report, sent by the host.
+    Output,
+    /// Feature report, sent by either side over the control pipe.
+    Feature,
+}
+
+impl ReportId {
+    /// Returns the kind of the report.
+    pub const fn kind(&self) -> ReportKind {
+        match self {
+            ReportId::In(_) => ReportKind::Input,
+            ReportId::Out(_) => ReportKind::Output,
+            ReportId::Feature(_) => ReportKind::Feature,
+        }
+    }
+}
+
+/// Error when parsing a report descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    /// The descriptor ends in the middle of an item.
+    Truncated,
+    /// The descriptor has more fields than the layout capacity, or a main item has more than
+    /// [`MAX_USAGES`] usages.
+    TooManyFields,
+    /// Collections or `Push`/`Pop` items aren't balanced, or `Push` items are nested too deep.
+    Unbalanced,
+    /// An item has an invalid value, such as a zero report ID.
+    InvalidItem,
+}
+
+/// Error when decoding a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// The report ID isn't in the descriptor.
+    UnknownReport,
+    /// The report is shorter than described in the descriptor.
+    TooShort,
+}
+
+/// Usages of a field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Usages {
+    /// The field has no usage, as for padding.
+    None,
+    /// A list of usages.
+    List(Vec<u32, MAX_USAGES>),
+    /// A range of usages, inclusive.
+    Range {
+        /// First usage of the range.
+        minimum: u32,
+        /// Last usage of the range.
+        maximum: u32,
+    },
+}
+
+/// A field of a report, as described by one Input, Output or Feature main item.
+///
+/// The field is `report_count` values of `report_size` bits each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    /// Kind of the report the field belongs to.
+    pub kind: ReportKind,
+    /// ID of the report the field belongs to, or 0 if the descriptor doesn't use report IDs.
+    pub report_id: u8,
+    /// Main item flags, see [`item_flags`].
+    pub flags: u16,
+    /// Offset of the first value, in bits, from the start of the report (excluding the report ID).
+    pub bit_offset: u32,
+    /// Size of each value, in bits.
+    pub report_size: u32,
+    /// Number of values.
+    pub report_count: u32,
+    /// Smallest valid value.
+    pub logical_minimum: i32,
+    /// Largest valid value.
+    pub logical_maximum: i32,
+    /// Usages of the values.
+    pub usages: Usages,
+}
+
+impl Field {
+    /// Returns `true` for constant fields, typically padding.
+    pub const fn is_constant(&self) -> bool {
+        self.flags & item_flags::CONSTANT != 0
+    }
+
+    /// Returns `true` if each value has its own usage, `false` if the values are array
+    /// indices into the usages.
+    pub const fn is_variable(&self) -> bool {
+        self.flags & item_flags::VARIABLE != 0
+    }
+
+    /// Returns `true` for relative values.
+    pub const fn is_relative(&self) -> bool {
+        self.flags & item_flags::RELATIVE != 0
+    }
+
+    /// Returns the size of the whole field, in bits.
+    pub const fn bit_len(&self) -> u32 {
+        self.report_size.saturating_mul(self.report_count)
+    }
+
+    /// Returns the usage at `index`.
+    ///
+    /// For variable fields, `index` is the index of the value. For array fields, it is the
+    /// value minus the logical minimum. The last usage of a list applies to all the following
+    /// values, as in the HID specification.
+    pub fn usage(&self, index: u32) -> Option<u32> {
+        match &self.usages {
+            Usages::None => None,
+            Usages::List(list) => list.get(index as usize).or(list.last()).copied(),
+            Usages::Range { minimum, maximum } => {
+                let usage = minimum.checked_add(index)?;
+                (usage <= *maximum).then_some(usage)
+            }
+        }
+    }
+
+    /// Returns the index of `usage`, the inverse of [`usage`](Self::usage).
+    pub fn usage_index(&self, usage: u32) -> Option<u32> {
+        match &self.usages {
+            Usages::None => None,
+            Usages::List(list) => list.iter().position(|&u| u == usage).map(|i| i as u32),
+            Usages::Range { minimum, maximum } => (*minimum..=*maximum).contains(&usage).then(|| usage - minimum),
+        }
+    }
+
+    /// Reads the value at `index` from `report`, which doesn't include the report ID.
+    ///
+    /// The value is sign-extended if the logical minimum is negative. Returns `None` if
+    /// `index` is out of range, if values are wider than 32 bits, or if `report` is too short.
+    pub fn get(&self, report: &[u8], index: u32) -> Option<i32> {
+        if index >= self.report_count || self.report_size == 0 || self.report_size > 32 {
+            return None;
+        }
+        let start = self.bit_offset.saturating_add(index.saturating_mul(self.report_size));
+        if start.saturating_add(self.report_size).div_ceil(8) as usize > report.len() {
+            return None;
+        }
+
+        let mut value: u32 = 0;
+        for bit in 0..self.report_size {
+            let pos = start + bit;
+            if report[(pos / 8) as usize] & (1 << (pos % 8)) != 0 {
+                value |= 1 << bit;
+            }
+        }
+
+        if self.logical_minimum < 0 && self.report_size < 32 {
+            let shift = 32 - self.report_size;
+            Some(((value << shift) as i32) >> shift)
+        } else {
+            Some(value as i32)
+        }
+    }
+
+    /// Writes `value` at `index` into `report`, which doesn't include the report ID.
+    ///
+    /// Bits of `value` that don't fit in the field are dropped. Returns `None` if `index` is
+    /// out of range, if values are wider than 32 bits, or if `report` is too short.
+    pub fn set(&self, report: &mut [u8], index: u32, value: i32) -> Option<()> {
+        if index >= self.report_count || self.report_size == 0 || self.report_size > 32 {
+            return None;
+        }
+        let start = self.bit_offset.saturating_add(index.saturating_mul(self.report_size));
+        if start.saturating_add(self.report_size).div_ceil(8) as usize > report.len() {
+            return None;
+        }
+
+        for bit in 0..self.report_size {
+            let pos = start + bit;
+            let mask = 1 << (pos % 8);
+            if (value as u32) & (1 << bit) != 0 {
+                report[(pos / 8) as usize] |= mask;
+            } else {
+                report[(pos / 8) as usize] &= !mask;
+            }
+        }
+        Some(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Globals {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    // Logical maximum read as unsigned, for descriptors that rely on it.
+    logical_maximum_unsigned: u32,
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+/// Layout of all the reports of a report descriptor.
+///
+/// `N` is the maximum number of fields, including padding.
+#[derive(Debug, Clone)]
+pub struct ReportLayout<const N: usize> {
+    fields: Vec<Field, N>,
+    uses_report_ids: bool,
+}
+
+impl<const N: usize> ReportLayout<N> {
+    /// Parse a report descriptor.
+    pub fn parse(descriptor: &[u8]) -> Result<Self, ParseError> {
+        let mut layout = Self {
+            fields: Vec::new(),
+            uses_report_ids: false,
+        };
+
+        let mut globals = Globals {
+            usage_page: 0,
+            logical_minimum: 0,
+            logical_maximum: 0,
+            logical_maximum_unsigned: 0,
+            report_size: 0,
+            report_count: 0,
+            report_id: 0,
+        };
+        let mut stack: Vec<Globals, MAX_GLOBAL_STACK> = Vec::new();
+        let mut usages: Vec<u32, MAX_USAGES> = Vec::new();
+        let mut usage_minimum = None;
+        let mut usage_maximum = None;
+        let mut depth = 0usize;
+
+        let mut pos = 0;
+        while pos < descriptor.len() {
+            let prefix = descriptor[pos];
+
+            // Long items are reserved, skip them.
+            if prefix == 0xFE {
+                let size = *descriptor.get(pos + 1).ok_or(ParseError::Truncated)? as usize;
+                pos += 3 + size;
+                if pos > descriptor.len() {
+                    return Err(ParseError::Truncated);
+                }
+                continue;
+            }
+
+            let size = match prefix & 0x03 {
+                3 => 4,
+                n => n as usize,
+            };
+            let data = descriptor.get(pos + 1..pos + 1 + size).ok_or(ParseError::Truncated)?;
+            pos += 1 + size;
+
+            let mut unsigned = 0u32;
+            for (i, b) in data.iter().enumerate() {
+                unsigned |= (*b as u32) << (8 * i);
+            }
+            let signed = match size {
+                1 => unsigned as u8 as i8 as i32,
+                2 => unsigned as u16 as i16 as i32,
+                _ => unsigned as i32,
+            };
+            // Usages without an explicit page use the current usage page.
+            let usage = if size == 4 {
+                unsigned
+            } else {
+                extended_usage(globals.usage_page, unsigned as u16)
+            };
+
+            let item_type = (prefix >> 2) & 0x03;
+            let tag = prefix >> 4;
+            match item_type {
+                // Main
+                0 => {
+                    let kind = match tag {
+                        MAIN_INPUT => Some(ReportKind::Input),
+                        MAIN_OUTPUT => Some(ReportKind::Output),
+                        MAIN_FEATURE => Some(ReportKind::Feature),
+                        MAIN_COLLECTION => {
+                            depth += 1;
+                            None
+                        }
+                        MAIN_END_COLLECTION => {
+                            depth = depth.checked_sub(1).ok_or(ParseError::Unbalanced)?;
+                            None
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(kind) = kind {
+                        let usages = match (usage_minimum, usage_maximum) {
+                            (Some(minimum), Some(maximum)) => Usages::Range { minimum, maximum },
+                            _ if !usages.is_empty() => Usages::List(usages.clone()),
+                            _ => Usages::None,
+                        };
+                        layout.add_field(kind, unsigned as u16, &globals, usages)?;
+                    }
+
+                    usages.clear();
+                    usage_minimum = None;
+                    usage_maximum = None;
+                }
+                // Global
+                1 => match tag {
+                    GLOBAL_USAGE_PAGE => globals.usage_page = unsigned as u16,
+                    GLOBAL_LOGICAL_MINIMUM => globals.logical_minimum = signed,
+                    GLOBAL_LOGICAL_MAXIMUM => {
+                        globals.logical_maximum = signed;
+                        globals.logical_maximum_unsigned = unsigned;
+                    }
+                    GLOBAL_REPORT_SIZE => globals.report_size = unsigned,
+                    GLOBAL_REPORT_COUNT => globals.report_count = unsigned,
+                    GLOBAL_REPORT_ID => {
+                        if unsigned == 0 || unsigned > 0xFF {
+                            return Err(ParseError::InvalidItem);
+                        }
+                        globals.report_id = unsigned as u8;
+                        layout.uses_report_ids = true;
+                    }
+                    GLOBAL_PUSH => stack.push(globals).map_err(|_| ParseError::Unbalanced)?,
+                    GLOBAL_POP => globals = stack.pop().ok_or(ParseError::Unbalanced)?,
+                    _ => {}
+                },
+                // Local
+                2 => match tag {
+                    LOCAL_USAGE => usages.push(usage).map_err(|_| ParseError::TooManyFields)?,
+                    LOCAL_USAGE_MINIMUM => usage_minimum = Some(usage),
+                    LOCAL_USAGE_MAXIMUM => usage_maximum = Some(usage),
+                    _ => {}
+                },
+                _ => return Err(ParseError::InvalidItem),
+            }
+        }
+
+        if depth != 0 {
+            return Err(ParseError::Unbalanced);
+        }
+
+        Ok(layout)
+    }
+
+    fn add_field(&mut self, kind: ReportKind, flags: u16, globals: &Globals, usages: Usages) -> Result<(), ParseError> {
+        let report_id = globals.report_id;
+        let bit_offset = self.report_bits(kind, report_id);
+
+        // Many descriptors use a logical maximum that only fits when read as unsigned.
+        let logical_maximum = if globals.logical_minimum >= 0 && globals.logical_maximum < 0 {
+            i32::try_from(globals.logical_maximum_unsigned).unwrap_or(i32::MAX)
+        } else {
+            globals.logical_maximum
+        };
+
+        self.fields
+            .push(Field {
+                kind,
+                report_id,
+                flags,
+                bit_offset,
+                report_size: globals.report_size,
+                report_count: globals.report_count,
+                logical_minimum: globals.logical_minimum,
+                logical_maximum,
+                usages,
+            })
+            .map_err(|_| ParseError::TooManyFields)
+    }
+
+    fn report_bits(&self, kind: ReportKind, report_id: u8) -> u32 {
+        self.fields
+            .iter()
+            .filter(|f| f.kind == kind && f.report_id == report_id)
+            .fold(0, |bits, f| bits.saturating_add(f.bit_len()))
+    }
+
+    /// Returns all the fields of all the reports, in descriptor order.
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Returns `true` if reports are prefixed with a report ID.
+    pub fn uses_report_ids(&self) -> bool {
+        self.uses_report_ids
+    }
+
+    /// Returns the length of a report in bytes, excluding the report ID, or `None` if the
+    /// descriptor doesn't describe it.
+    ///
+    /// `report_id` must be 0 if the descriptor doesn't use report IDs.
+    pub fn report_len(&self, kind: ReportKind, report_id: u8) -> Option<usize> {
+        let exists = self.fields.iter().any(|f| f.kind == kind && f.report_id == report_id);
+        exists.then(|| self.report_bits(kind, report_id).div_ceil(8) as usize)
+    }
+
+    /// Returns the field of a report holding `usage`.
+    pub fn field(&self, kind: ReportKind, report_id: u8, usage: u32) -> Option<&Field> {
+        self.fields
+            .iter()
+            .filter(|f| f.kind == kind && f.report_id == report_id && !f.is_constant())
+            .find(|f| f.usage_index(usage).is_some())
+    }
+
+    /// Decode a report.
+    ///
+    /// If the descriptor uses report IDs, `data` starts with the report ID, as received from
+    /// the host. Extra bytes at the end of `data` are ignored.
+    pub fn decode<'a>(&'a self, kind: ReportKind, data: &'a [u8]) -> Result<Report<'a, N>, DecodeError> {
+        let (report_id, data) = if self.uses_report_ids {
+            match data.split_first() {
+                Some((&id, data)) => (id, data),
+                None => return Err(DecodeError::TooShort),
+            }
+        } else {
+            (0, data)
+        };
+
+        let len = self.report_len(kind, report_id).ok_or(DecodeError::UnknownReport)?;
+        if data.len() < len {
+            return Err(DecodeError::TooShort);
+        }
+
+        Ok(Report {
+            layout: self,
+            kind,
+            report_id,
+            data: &data[..len],
+        })
+    }
+}
+
+/// A report decoded by [`ReportLayout::decode`].
+pub struct Report<'a, const N: usize> {
+    layout: &'a ReportLayout<N>,
+    kind: ReportKind,
+    report_id: u8,
+    data: &'a [u8],
+}
+
+impl<'a, const N: usize> Report<'a, N> {
+    /// Returns the kind of the report.
+    pub fn kind(&self) -> ReportKind {
+        self.kind
+    }
+
+    /// Returns the report ID, or 0 if the descriptor doesn't use report IDs.
+    pub fn id(&self) -> u8 {
+        self.report_id
+    }
+
+    /// Returns the report data, without the report ID.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns the data fields of the report, skipping padding.
+    pub fn fields(&self) -> impl Iterator<Item = &'a Field> + '_ {
+        self.layout
+            .fields
+            .iter()
+            .filter(|f| f.kind == self.kind && f.report_id == self.report_id && !f.is_constant())
+    }
+
+    /// Returns the value of `usage`.
+    ///
+    /// For array fields, such as the keys of a boot keyboard, the value is 1 if `usage` is in
+    /// the array and 0 otherwise.
+    pub fn value(&self, usage: u32) -> Option<i32> {
+        let field = self.layout.field(self.kind, self.report_id, usage)?;
+        let index = field.usage_index(usage)?;
+        if field.is_variable() {
+            field.get(self.data, index)
+        } else {
+            let value = field.logical_minimum.checked_add(index as i32)?;
+            let present = (0..field.report_count).any(|i| field.get(self.data, i) == Some(value));
+            Some(present as i32)
+        }
+    }
+
+    /// Returns the usages and values of the report.
+    ///
+    /// Variable fields yield all their values. Array fields only yield the usages in the
+    /// array, with a value of 1.
+    pub fn values(&self) -> impl Iterator<Item = (u32, i32)> + '_ {
+        self.fields().flat_map(move |field| {
+            (0..field.report_count).filter_map(move |i| {
+                let value = field.get(self.data, i)?;
+                if field.is_variable() {
+                    Some((field.usage(i)?, value))
+                } else if (field.logical_minimum..=field.logical_maximum).contains(&value) {
+                    // Usage ID 0 means no control is selected.
+                    let usage = field.usage(value.wrapping_sub(field.logical_minimum) as u32)?;
+                    (usage & 0xFFFF != 0).then_some((usage, 1))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::class::hid::descriptor::usage::{consumer, digitizer, generic_desktop, keyboard, led};
+    use crate::class::hid::descriptor::{usage_page, Collection, ReportDescriptor};
+    use crate::class::hid::devices::*;
+
+    type Layout = ReportLayout<16>;
+
+    /// Kind, report ID, bit offset, report size and report count of the fields of `layout`.
+    fn fields(layout: &Layout) -> Vec<(ReportKind, u8, u32, u32, u32)> {
+        layout
+            .fields()
+            .iter()
+            .map(|f| (f.kind, f.report_id, f.bit_offset, f.report_size, f.report_count))
+            .collect()
+    }
+
+    use ReportKind::{Feature, Input, Output};
+
+    #[test]
+    fn keyboard_nkro_layout() {
+        let layout = Layout::parse(KEYBOARD_NKRO.as_bytes()).unwrap();
+        assert!(!layout.uses_report_ids());
+        assert_eq!(
+            fields(&layout),
+            [
+                (Input, 0, 0, 1, 8),
+                (Input, 0, 8, 1, KEYBOARD_MAX_KEY as u32 + 1),
+                (Output, 0, 0, 1, 5),
+                (Output, 0, 5, 3, 1),
+            ]
+        );
+        assert_eq!(layout.report_len(Input, 0), Some(KeyboardReport::LEN));
+        assert_eq!(layout.report_len(Output, 0), Some(1));
+        assert_eq!(layout.report_len(Feature, 0), None);
+
+        let mut report = KeyboardReport::default();
+        report.set_key(keyboard::LEFT_CONTROL as u8 + 1, true); // Left Shift
+        report.set_key(0x04, true); // A
+        let bytes = report.to_bytes();
+        let report = layout.decode(Input, &bytes).unwrap();
+        let key = |id| report.value(extended_usage(usage_page::KEYBOARD, id));
+        assert_eq!(
+            (key(0xE0), key(0xE1), key(0x04), key(0x05)),
+            (Some(0), Some(1), Some(1), Some(0))
+        );
+
+        // LED output report: Caps Lock and Kana.
+        let report = layout.decode(Output, &[0b1_0010]).unwrap();
+        let led = |id| report.value(extended_usage(usage_page::LED, id));
+        assert_eq!(
+            (led(led::NUM_LOCK), led(led::CAPS_LOCK), led(led::KANA)),
+            (Some(0), Some(1), Some(1))
+        );
+    }
+
+    #[test]
+    fn mouse_layout() {
+        let layout = Layout::parse(MOUSE.as_bytes()).unwrap();
+        assert_eq!(
+            fields(&layout),
+            [
+                (Input, 0, 0, 1, 5),
+                (Input, 0, 5, 3, 1),
+                (Input, 0, 8, 8, 3),
+                (Input, 0, 32, 8, 1),
+            ]
+        );
+        assert_eq!(layout.report_len(Input, 0), Some(MouseReport::LEN));
+
+        let bytes = MouseReport {
+            buttons: 0b10001,
+            x: -5,
+            y: 100,
+            wheel: -1,
+            pan: 2,
+        }
+        .to_bytes();
+        let report = layout.decode(Input, &bytes).unwrap();
+        let desktop = |id| report.value(extended_usage(usage_page::GENERIC_DESKTOP, id));
+        assert_eq!(
+            (
+                desktop(generic_desktop::X),
+                desktop(generic_desktop::Y),
+                desktop(generic_desktop::WHEEL)
+            ),
+            (Some(-5), Some(100), Some(-1))
+        );
+        assert_eq!(
+            report.value(extended_usage(usage_page::CONSUMER, consumer::AC_PAN)),
+            Some(2)
+        );
+        assert_eq!(report.value(extended_usage(usage_page::BUTTON, 5)), Some(1));
+        assert_eq!(report.value(extended_usage(usage_page::BUTTON, 2)), Some(0));
+        assert!(report.fields().all(|f| f.is_variable()));
+        assert!(layout.fields()[2].is_relative());
+    }
+
+    #[test]
+    fn gamepad_layout() {
+        let layout = Layout::parse(GAMEPAD.as_bytes()).unwrap();
+        assert_eq!(
+            fields(&layout),
+            [
+                (Input, 0, 0, 1, 16),
+                (Input, 0, 16, 4, 1),
+                (Input, 0, 20, 4, 1),
+                (Input, 0, 24, 8, 4),
+                (Input, 0, 56, 8, 2),
+            ]
+        );
+        assert_eq!(layout.report_len(Input, 0), Some(GamepadReport::LEN));
+        let triggers = &layout.fields()[4];
+        assert_eq!((triggers.logical_minimum, triggers.logical_maximum), (0, 255));
+
+        let bytes = GamepadReport {
+            buttons: 0x8001,
+            hat: 2,
+            z: -127,
+            ry: 200,
+            ..Default::default()
+        }
+        .to_bytes();
+        let report = layout.decode(Input, &bytes).unwrap();
+        let desktop = |id| report.value(extended_usage(usage_page::GENERIC_DESKTOP, id));
+        assert_eq!(
+            (
+                desktop(generic_desktop::HAT_SWITCH),
+                desktop(generic_desktop::Z),
+                desktop(generic_desktop::RY)
+            ),
+            (Some(2), Some(-127), Some(200))
+        );
+        assert_eq!(report.value(extended_usage(usage_page::BUTTON, 16)), Some(1));
+    }
+
+    #[test]
+    fn consumer_control_layout() {
+        let layout = Layout::parse(CONSUMER_CONTROL.as_bytes()).unwrap();
+        assert_eq!(fields(&layout), [(Input, 0, 0, 16, CONSUMER_CONTROL_USAGES as u32)]);
+        assert_eq!(layout.report_len(Input, 0), Some(ConsumerControlReport::LEN));
+        assert!(!layout.fields()[0].is_variable());
+
+        let bytes = ConsumerControlReport {
+            usages: [0xCD, 0xE9, 0, 0],
+        }
+        .to_bytes();
+        let report = layout.decode(Input, &bytes).unwrap();
+        let values: Vec<_> = report.values().collect();
+        assert_eq!(
+            values,
+            [
+                (extended_usage(usage_page::CONSUMER, 0xCD), 1),
+                (extended_usage(usage_page::CONSUMER, 0xE9), 1)
+            ]
+        );
+        assert_eq!(report.value(extended_usage(usage_page::CONSUMER, 0xE2)), Some(0));
+    }
+
+    #[test]
+    fn digitizer_layout() {
+        let layout = Layout::parse(DIGITIZER.as_bytes()).unwrap();
+        assert_eq!(
+            fields(&layout),
+            [
+                (Input, 0, 0, 1, 4),
+                (Input, 0, 4, 4, 1),
+                (Input, 0, 8, 16, 2),
+                (Input, 0, 40, 16, 1),
+            ]
+        );
+        assert_eq!(layout.report_len(Input, 0), Some(DigitizerReport::LEN));
+
+        let bytes = DigitizerReport {
+            tip: true,
+            in_range: true,
+            x: DIGITIZER_MAX_POSITION,
+            y: 1234,
+            pressure: DIGITIZER_MAX_PRESSURE,
+            ..Default::default()
+        }
+        .to_bytes();
+        let report = layout.decode(Input, &bytes).unwrap();
+        let pen = |id| report.value(extended_usage(usage_page::DIGITIZER, id));
+        assert_eq!(
+            (
+                pen(digitizer::TIP_SWITCH),
+                pen(digitizer::ERASER),
+                pen(digitizer::IN_RANGE)
+            ),
+            (Some(1), Some(0), Some(1))
+        );
+        assert_eq!(pen(digitizer::TIP_PRESSURE), Some(DIGITIZER_MAX_PRESSURE as i32));
+        let x = report.value(extended_usage(usage_page::GENERIC_DESKTOP, generic_desktop::X));
+        assert_eq!(x, Some(DIGITIZER_MAX_POSITION as i32));
+    }
+
+    #[test]
+    fn report_ids() {
+        static COMPOSITE: ReportDescriptor<192> =
+            mouse(consumer_control(keyboard_nkro(ReportDescriptor::new(), 1), 2), 3);
+        let layout = Layout::parse(COMPOSITE.as_bytes()).unwrap();
+        assert!(layout.uses_report_ids());
+
+        // Each report starts at bit 0.
+        let offsets: Vec<_> = layout.fields().iter().map(|f| (f.report_id, f.bit_offset)).collect();
+        assert_eq!(
+            offsets,
+            [(1, 0), (1, 8), (1, 0), (1, 5), (2, 0), (3, 0), (3, 5), (3, 8), (3, 32)]
+        );
+        assert_eq!(layout.report_len(Input, 1), Some(KeyboardReport::LEN));
+        assert_eq!(layout.report_len(Input, 2), Some(ConsumerControlReport::LEN));
+        assert_eq!(layout.report_len(Input, 3), Some(MouseReport::LEN));
+        assert_eq!(layout.report_len(Output, 1), Some(1));
+        assert_eq!(layout.report_len(Output, 3), None);
+
+        // Output reports start with the report ID.
+        let report = layout.decode(Output, &[1, 0b100]).unwrap();
+        assert_eq!(report.id(), 1);
+        assert_eq!(report.data(), [0b100]);
+        assert_eq!(report.value(extended_usage(usage_page::LED, led::SCROLL_LOCK)), Some(1));
+        assert_eq!(layout.decode(Output, &[2, 0]).err(), Some(DecodeError::UnknownReport));
+        assert_eq!(layout.decode(Output, &[1]).err(), Some(DecodeError::TooShort));
+    }
+
+    #[test]
+    fn feature_report() {
+        // A vendor device with an 8-bit input and a feature report of a 12-bit value and a flag.
+        static DESCRIPTOR: ReportDescriptor<64> = ReportDescriptor::new()
+            .usage_page(usage_page::VENDOR_DEFINED_START)
+            .usage(0x01)
+            .collection(Collection::Application)
+            .report_id(1)
+            .usage(0x10)
+            .logical_minimum(0)
+            .logical_maximum(255)
+            .report_size(8)
+            .report_count(1)
+            .input(item_flags::DATA | item_flags::VARIABLE)
+            .report_id(2)
+            .usage(0x20)
+            .logical_minimum(-2048)
+            .logical_maximum(2047)
+            .report_size(12)
+            .feature(item_flags::DATA | item_flags::VARIABLE)
+            .usage(0x21)
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .feature(item_flags::DATA | item_flags::VARIABLE)
+            .padding_feature(3)
+            .end_collection();
+        let layout = Layout::parse(DESCRIPTOR.as_bytes()).unwrap();
+        assert_eq!(
+            fields(&layout),
+            [
+                (Input, 1, 0, 8, 1),
+                (Feature, 2, 0, 12, 1),
+                (Feature, 2, 12, 1, 1),
+                (Feature, 2, 13, 3, 1),
+            ]
+        );
+        assert_eq!(layout.report_len(Feature, 2), Some(2));
+
+        // -1000 in 12 bits is 0xC18, followed by the flag in bit 12.
+        let report = layout.decode(Feature, &[2, 0x18, 0x1C, 0xFF]).unwrap();
+        assert_eq!(report.data(), [0x18, 0x1C]);
+        let vendor = |id| report.value(extended_usage(usage_page::VENDOR_DEFINED_START, id));
+        assert_eq!((vendor(0x20), vendor(0x21), vendor(0x10)), (Some(-1000), Some(1), None));
+
+        // Filling the same report gives back the same bytes.
+        let mut data = [0; 2];
+        let field = layout.field(Feature, 2, extended_usage(usage_page::VENDOR_DEFINED_START, 0x20));
+        field.unwrap().set(&mut data, 0, -1000).unwrap();
+        layout.fields()[2].set(&mut data, 0, 1).unwrap();
+        assert_eq!(data, [0x18, 0x1C]);
+    }
+}
@@ -14,6 +14,12 @@ use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
 use crate::types::InterfaceNumber;
 use crate::{Builder, Handler};
 
+pub mod descriptor;
+pub mod devices;
+pub mod layout;
+
+use layout::{DecodeError, Report, ReportKind, ReportLayout};
+
 const USB_CLASS_HID: u8 = 0x03;
 const USB_SUBCLASS_NONE: u8 = 0x00;
 const USB_PROTOCOL_NONE: u8 = 0x00;
@@ -199,6 +205,17 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
     pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
         self.reader.read(buf).await
     }
+
+    /// Reads an output report from the Interrupt Out pipe and decodes it with `layout`.
+    ///
+    /// See [`HidReader::read_report`].
+    pub async fn read_report<'a, const F: usize>(
+        &mut self,
+        layout: &'a ReportLayout<F>,
+        buf: &'a mut [u8],
+    ) -> Result<Report<'a, F>, ReadError> {
+        self.reader.read_report(layout, buf).await
+    }
 }
 
 /// USB HID writer.
@@ -226,6 +243,8 @@ pub enum ReadError {
     Disabled,
     /// The report was only partially read. See [`HidReader::read`] for details.
     Sync(Range<usize>),
+    /// The report doesn't match the report layout. See [`HidReader::read_report`] for details.
+    Decode(DecodeError),
 }
 
 impl From<EndpointError> for ReadError {
@@ -314,7 +333,7 @@ impl<'d, D: Driver<'d>, const N: usize> HidReader<'d, D, N> {
                     N
                 ),
                 Err(ReadError::Disabled) => self.ep_out.wait_enabled().await,
-                Err(ReadError::Sync(_)) => unreachable!(),
+                Err(ReadError::Sync(_)) | Err(ReadError::Decode(_)) => unreachable!(),
             }
         }
     }
@@ -371,6 +390,24 @@ impl<'d, D: Driver<'d>, const N: usize> HidReader<'d, D, N> {
             Ok(total)
         }
     }
+
+    /// Reads an output report from the Interrupt Out pipe and decodes it with `layout`.
+    ///
+    /// `layout` should be parsed from the report descriptor of this interface. The returned
+    /// [`Report`] borrows `buf`, which must be at least `N` bytes long, as for [`read()`](Self::read).
+    ///
+    /// Returns [`ReadError::Decode`] if the report ID is unknown or the report is shorter than
+    /// described by `layout`.
+    pub async fn read_report<'a, const F: usize>(
+        &mut self,
+        layout: &'a ReportLayout<F>,
+        buf: &'a mut [u8],
+    ) -> Result<Report<'a, F>, ReadError> {
+        let len = self.read(buf).await?;
+        layout
+            .decode(ReportKind::Output, &buf[..len])
+            .map_err(ReadError::Decode)
+    }
 }
 
 /// Handler for HID-related control requests.
@@ -384,6 +421,8 @@ pub trait RequestHandler {
     }
 
     /// Sets the value of report `id` to `data`.
+    ///
+    /// `data` can be decoded with [`ReportLayout::decode`], using [`ReportId::kind`].
     fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
         let _ = (id, data);
         OutResponse::Rejected
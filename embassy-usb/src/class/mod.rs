@@ -1,8 +1,19 @@
 //! Implementations of well-known USB classes.
+#[cfg(feature = "time")]
+pub mod ccid;
 pub mod cdc_acm;
+pub mod cdc_ecm;
 pub mod cdc_ncm;
 pub mod cmsis_dap_v2;
+#[cfg(feature = "time")]
+pub mod ctaphid;
 pub mod hid;
 pub mod midi;
+pub mod msc;
+pub mod rndis;
+pub mod rndis_ecm;
 pub mod uac1;
+pub mod uac2;
+pub mod usbtmc;
+pub mod uvc;
 pub mod web_usb;
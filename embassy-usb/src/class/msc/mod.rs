@@ -0,0 +1,978 @@
+//! Mass Storage Class implementation, with the Bulk-Only Transport and the SCSI transparent command set.
+//!
+//! The class exposes one or more logical units (LUNs), each backed by a [`BlockDevice`] of
+//! [`block_device_driver`] with 512-byte blocks, as USB drives. It implements the SCSI commands used
+//! by common hosts: INQUIRY, REQUEST SENSE, TEST UNIT READY, READ CAPACITY(10), READ FORMAT
+//! CAPACITIES, MODE SENSE(6/10), READ(10), WRITE(10), VERIFY(10), SYNCHRONIZE CACHE(10), START STOP
+//! UNIT and PREVENT/ALLOW MEDIUM REMOVAL.
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub use aligned::Aligned;
+use block_device_driver::blocks_to_slice_mut;
+pub use block_device_driver::BlockDevice;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::{MappedMutexGuard, Mutex, MutexGuard};
+use embassy_sync::signal::Signal;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+mod scsi;
+
+use scsi::{opcode, Sense};
+
+/// This should be used as `device_class` when building the `UsbDevice`, or 0 to use the interface class.
+pub const USB_CLASS_MSC: u8 = 0x08;
+
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+const REQ_GET_MAX_LUN: u8 = 0xfe;
+const REQ_BULK_ONLY_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+
+/// Maximum number of logical units of a mass storage device.
+pub const MAX_LUNS: usize = 16;
+
+/// Size of a block in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+/// A block of data, with the alignment `A` required by a block device.
+pub type Block<A> = Aligned<A, [u8; BLOCK_SIZE]>;
+
+/// Configuration of a logical unit.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct LunConfig {
+    /// Vendor identification reported by INQUIRY, up to 8 ASCII characters.
+    pub vendor: &'static str,
+    /// Product identification reported by INQUIRY, up to 16 ASCII characters.
+    pub product: &'static str,
+    /// Product revision reported by INQUIRY, up to 4 ASCII characters.
+    pub revision: &'static str,
+    /// Whether the medium is removable. Hosts usually show removable units as USB sticks.
+    pub removable: bool,
+    /// Whether the unit is read-only, regardless of [`Lun::set_write_protected`].
+    pub write_protected: bool,
+}
+
+impl Default for LunConfig {
+    fn default() -> Self {
+        Self {
+            vendor: "Embassy",
+            product: "Mass Storage",
+            revision: "1.0",
+            removable: true,
+            write_protected: false,
+        }
+    }
+}
+
+/// A logical unit of a mass storage device.
+///
+/// Logical units of a class all have the same block device type. Devices of different types can be
+/// combined with an enum implementing [`BlockDevice`].
+///
+/// The unit is shared with [`MscClass::run`]: the application can report the insertion or removal
+/// of the medium and its write protection while the class serves the host.
+pub struct Lun<B> {
+    unit: Mutex<CriticalSectionRawMutex, Unit<B>>,
+    config: LunConfig,
+    present: AtomicBool,
+    write_protected: AtomicBool,
+    attention: AtomicBool,
+    removal_prevented: AtomicBool,
+}
+
+/// State of a logical unit used by the commands.
+struct Unit<B> {
+    device: B,
+    sense: Sense,
+}
+
+impl<B: BlockDevice<BLOCK_SIZE>> Lun<B> {
+    /// Create a new logical unit backed by `device`, with its medium present.
+    pub fn new(device: B, config: LunConfig) -> Self {
+        Self {
+            unit: Mutex::new(Unit {
+                device,
+                sense: Sense::NO_SENSE,
+            }),
+            config,
+            present: AtomicBool::new(true),
+            write_protected: AtomicBool::new(false),
+            attention: AtomicBool::new(false),
+            removal_prevented: AtomicBool::new(false),
+        }
+    }
+
+    /// Lock the block device of the unit, waiting for the current command to complete.
+    pub async fn device(&self) -> MappedMutexGuard<'_, CriticalSectionRawMutex, B> {
+        MutexGuard::map(self.unit.lock().await, |unit| &mut unit.device)
+    }
+
+    /// Release the block device of the unit.
+    pub fn into_device(self) -> B {
+        self.unit.into_inner().device
+    }
+
+    /// Whether the medium is present.
+    pub fn is_present(&self) -> bool {
+        self.present.load(Ordering::Relaxed)
+    }
+
+    /// Set whether the medium is present, such as an SD card in its slot. Inserting the medium is
+    /// reported to the host as a media change.
+    pub fn set_present(&self, present: bool) {
+        if present && !self.is_present() {
+            self.attention.store(true, Ordering::Relaxed);
+        }
+        self.present.store(present, Ordering::Relaxed);
+    }
+
+    /// Whether the unit is write protected, by its configuration or [`Lun::set_write_protected`].
+    pub fn is_write_protected(&self) -> bool {
+        self.config.write_protected || self.write_protected.load(Ordering::Relaxed)
+    }
+
+    /// Set whether the medium is write protected, for example by the lock switch of an SD card.
+    pub fn set_write_protected(&self, write_protected: bool) {
+        self.write_protected.store(write_protected, Ordering::Relaxed);
+    }
+
+    /// Whether the host prevented the removal of the medium with PREVENT ALLOW MEDIUM REMOVAL, for
+    /// example while it is mounted.
+    pub fn removal_prevented(&self) -> bool {
+        self.removal_prevented.load(Ordering::Relaxed)
+    }
+
+    /// Number of blocks of the medium.
+    async fn block_count(&self, device: &mut B) -> Result<u32, Sense> {
+        if !self.is_present() {
+            return Err(Sense::MEDIUM_NOT_PRESENT);
+        }
+        match device.size().await {
+            // Saturated to the 32-bit block addresses of READ(10) and WRITE(10).
+            Ok(size) => Ok((size / BLOCK_SIZE as u64).min(u32::MAX as u64) as u32),
+            Err(_) => {
+                warn!("Block device size error");
+                Err(Sense::MEDIUM_NOT_PRESENT)
+            }
+        }
+    }
+
+    /// Check that `blocks` blocks at `lba` can be accessed.
+    async fn check_range(&self, device: &mut B, lba: u32, blocks: u32) -> Result<(), Sense> {
+        let block_count = self.block_count(device).await?;
+        match lba.checked_add(blocks) {
+            Some(end) if end <= block_count => Ok(()),
+            _ => Err(Sense::LBA_OUT_OF_RANGE),
+        }
+    }
+}
+
+/// Internal state for the mass storage class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared { reset: Signal::new() },
+        }
+    }
+}
+
+/// Shared data between Control and MscClass
+struct ControlShared {
+    reset: Signal<CriticalSectionRawMutex, ()>,
+}
+
+struct Control<'a> {
+    iface: InterfaceNumber,
+    max_lun: u8,
+    shared: &'a ControlShared,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_BULK_ONLY_RESET => {
+                debug!("Bulk-only mass storage reset");
+                self.shared.reset.signal(());
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.iface.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_MAX_LUN if req.length >= 1 => {
+                buf[0] = self.max_lun;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// Status of a command, reported in the command status wrapper.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum CommandStatus {
+    Passed = 0,
+    Failed = 1,
+    PhaseError = 2,
+}
+
+/// Command block wrapper.
+struct Cbw {
+    tag: u32,
+    data_len: u32,
+    dir_in: bool,
+    lun: u8,
+    cb: [u8; 16],
+}
+
+impl Cbw {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() != CBW_LEN || u32::from_le_bytes(data[0..4].try_into().unwrap()) != CBW_SIGNATURE {
+            return None;
+        }
+        let cb_len = (data[14] & 0x1f) as usize;
+        if !(1..=16).contains(&cb_len) {
+            return None;
+        }
+        let mut cb = [0; 16];
+        cb[..cb_len].copy_from_slice(&data[15..15 + cb_len]);
+        Some(Self {
+            tag: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            data_len: u32::from_le_bytes(data[8..12].try_into().unwrap()),
+            dir_in: data[12] & 0x80 != 0,
+            lun: data[13] & 0x0f,
+            cb,
+        })
+    }
+}
+
+/// Command status wrapper of the command with `tag`.
+fn csw(tag: u32, residue: u32, status: CommandStatus) -> [u8; CSW_LEN] {
+    let mut csw = [0; CSW_LEN];
+    csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+    csw[4..8].copy_from_slice(&tag.to_le_bytes());
+    csw[8..12].copy_from_slice(&residue.to_le_bytes());
+    csw[12] = status as u8;
+    csw
+}
+
+/// Outcome of a command: data residue and status.
+type Outcome = Result<(u32, CommandStatus), EndpointError>;
+
+/// USB Mass Storage Class with the Bulk-Only Transport.
+///
+/// The endpoints are never stalled: when the host expects more data than a command returns, the
+/// data is padded with zeros, and when it sends more data than expected, the data is discarded, as
+/// allowed by the Bulk-Only Transport specification. Invalid command block wrappers are ignored.
+pub struct MscClass<'d, D: Driver<'d>> {
+    bot: BulkOnly<D::EndpointOut, D::EndpointIn>,
+    shared: &'d ControlShared,
+    luns: u8,
+}
+
+impl<'d, D: Driver<'d>> MscClass<'d, D> {
+    /// Creates a new MscClass with `luns` logical units and the provided `max_packet_size` in
+    /// bytes. For full-speed devices, `max_packet_size` has to be one of 8, 16, 32 or 64.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, max_packet_size: u16, luns: u8) -> Self {
+        assert!((1..=MAX_LUNS).contains(&(luns as usize)), "1 to 16 logical units");
+
+        let mut func = builder.function(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT);
+        let mut iface = func.interface();
+        let iface_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT, None);
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        drop(func);
+
+        let control = state.control.write(Control {
+            iface: iface_num,
+            max_lun: luns - 1,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        MscClass {
+            bot: BulkOnly { read_ep, write_ep },
+            shared: &state.shared,
+            luns,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        self.bot.max_packet_size() as u16
+    }
+
+    /// Serve the host with the logical units `luns`, as many as given to [`MscClass::new`].
+    ///
+    /// `buf` holds the blocks being transferred: the more blocks it has, the more blocks are read or
+    /// written to the devices at once.
+    pub async fn run<B: BlockDevice<BLOCK_SIZE>>(&mut self, luns: &[Lun<B>], buf: &mut [Block<B::Align>]) -> ! {
+        assert_eq!(luns.len(), self.luns as usize);
+        assert!(BLOCK_SIZE % self.bot.max_packet_size() == 0 && !buf.is_empty());
+
+        let shared = self.shared;
+        loop {
+            self.bot.read_ep.wait_enabled().await;
+            shared.reset.reset();
+            match select(self.bot.serve(luns, buf), shared.reset.wait()).await {
+                Either::First(e) => debug!("Mass storage transfer ended: {:?}", e),
+                Either::Second(()) => info!("Mass storage reset"),
+            }
+        }
+    }
+}
+
+/// Bulk-Only Transport over a pair of bulk endpoints.
+struct BulkOnly<O, I> {
+    read_ep: O,
+    write_ep: I,
+}
+
+impl<O: EndpointOut, I: EndpointIn> BulkOnly<O, I> {
+    fn max_packet_size(&self) -> usize {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size as usize
+    }
+
+    async fn serve<B: BlockDevice<BLOCK_SIZE>>(
+        &mut self,
+        luns: &[Lun<B>],
+        buf: &mut [Block<B::Align>],
+    ) -> EndpointError {
+        let mps = self.max_packet_size();
+        loop {
+            let bytes = blocks_to_slice_mut(buf);
+            let n = match self.read_ep.read(&mut bytes[..mps]).await {
+                Ok(n) => n,
+                Err(e) => return e,
+            };
+            let Some(cbw) = Cbw::parse(&bytes[..n]) else {
+                warn!("Invalid command block wrapper");
+                continue;
+            };
+            let Some(lun) = luns.get(cbw.lun as usize) else {
+                warn!("Command for unknown LUN {}", cbw.lun);
+                continue;
+            };
+
+            let (residue, status) = match self.command(lun, &cbw, buf).await {
+                Ok(outcome) => outcome,
+                Err(e) => return e,
+            };
+            if status != CommandStatus::Passed {
+                debug!("SCSI command {:02x}: {:?}", cbw.cb[0], status);
+            }
+
+            if let Err(e) = self.write_ep.write(&csw(cbw.tag, residue, status)).await {
+                return e;
+            }
+        }
+    }
+
+    async fn command<B: BlockDevice<BLOCK_SIZE>>(
+        &mut self,
+        lun: &Lun<B>,
+        cbw: &Cbw,
+        buf: &mut [Block<B::Align>],
+    ) -> Outcome {
+        let cb = &cbw.cb;
+        trace!("SCSI command {:02x} for LUN {}", cb[0], cbw.lun);
+
+        let mut unit = lun.unit.lock().await;
+        let unit = &mut *unit;
+        if lun.attention.load(Ordering::Relaxed) && !matches!(cb[0], opcode::INQUIRY | opcode::REQUEST_SENSE) {
+            lun.attention.store(false, Ordering::Relaxed);
+            let bytes = blocks_to_slice_mut(buf);
+            return self
+                .no_data(&mut unit.sense, cbw, Err(Sense::MEDIUM_CHANGED), bytes)
+                .await;
+        }
+
+        match cb[0] {
+            opcode::READ_10 => return self.read(lun, unit, cbw, buf).await,
+            opcode::WRITE_10 => return self.write(lun, unit, cbw, buf).await,
+            _ => {}
+        }
+
+        let bytes = blocks_to_slice_mut(buf);
+        let sense = &mut unit.sense;
+        match cb[0] {
+            opcode::TEST_UNIT_READY => {
+                let result = match lun.is_present() {
+                    true => Ok(()),
+                    false => Err(Sense::MEDIUM_NOT_PRESENT),
+                };
+                self.no_data(sense, cbw, result, bytes).await
+            }
+            opcode::REQUEST_SENSE => {
+                let data = sense.to_bytes();
+                *sense = Sense::NO_SENSE;
+                self.data_in(cbw, &data, cb[4] as usize, bytes).await
+            }
+            opcode::INQUIRY if cb[1] & 0x01 != 0 => {
+                // Vital product data pages are not supported.
+                self.no_data(sense, cbw, Err(Sense::INVALID_FIELD_IN_CDB), bytes).await
+            }
+            opcode::INQUIRY => {
+                let config = &lun.config;
+                let data = scsi::inquiry(config.vendor, config.product, config.revision, config.removable);
+                let alloc = u16::from_be_bytes([cb[3], cb[4]]);
+                self.data_in(cbw, &data, alloc as usize, bytes).await
+            }
+            opcode::MODE_SENSE_6 | opcode::MODE_SENSE_10 => {
+                let mut data = [0; 8];
+                let len = scsi::mode_sense(cb[0], lun.is_write_protected(), &mut data);
+                let alloc = match cb[0] {
+                    opcode::MODE_SENSE_6 => cb[4] as usize,
+                    _ => u16::from_be_bytes([cb[7], cb[8]]) as usize,
+                };
+                self.data_in(cbw, &data[..len], alloc, bytes).await
+            }
+            opcode::START_STOP_UNIT => self.no_data(sense, cbw, Ok(()), bytes).await,
+            opcode::PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                lun.removal_prevented.store(cb[4] & 0x01 != 0, Ordering::Relaxed);
+                self.no_data(sense, cbw, Ok(()), bytes).await
+            }
+            opcode::READ_FORMAT_CAPACITIES => {
+                let block_count = lun.block_count(&mut unit.device).await;
+                let data =
+                    scsi::read_format_capacities(block_count.unwrap_or(0), BLOCK_SIZE as u32, block_count.is_ok());
+                let alloc = u16::from_be_bytes([cb[7], cb[8]]);
+                self.data_in(cbw, &data, alloc as usize, bytes).await
+            }
+            opcode::READ_CAPACITY_10 => match lun.block_count(&mut unit.device).await {
+                Ok(block_count) => {
+                    let data = scsi::read_capacity(block_count, BLOCK_SIZE as u32);
+                    self.data_in(cbw, &data, data.len(), bytes).await
+                }
+                Err(e) => self.no_data(sense, cbw, Err(e), bytes).await,
+            },
+            opcode::VERIFY_10 => {
+                let (lba, blocks) = scsi::lba_and_length(cb);
+                let result = lun.check_range(&mut unit.device, lba, blocks).await;
+                self.no_data(sense, cbw, result, bytes).await
+            }
+            // Blocks are written to the device before WRITE(10) completes.
+            opcode::SYNCHRONIZE_CACHE_10 => self.no_data(sense, cbw, Ok(()), bytes).await,
+            _ => {
+                debug!("Unsupported SCSI command {:02x}", cb[0]);
+                self.no_data(sense, cbw, Err(Sense::INVALID_COMMAND), bytes).await
+            }
+        }
+    }
+
+    /// READ(10): send blocks to the host.
+    async fn read<B: BlockDevice<BLOCK_SIZE>>(
+        &mut self,
+        lun: &Lun<B>,
+        unit: &mut Unit<B>,
+        cbw: &Cbw,
+        buf: &mut [Block<B::Align>],
+    ) -> Outcome {
+        let (lba, blocks) = scsi::lba_and_length(&cbw.cb);
+        let len = blocks as usize * BLOCK_SIZE;
+        let expected = cbw.data_len as usize;
+        if expected < len || (expected > 0 && !cbw.dir_in) {
+            return self.phase_error(cbw, blocks_to_slice_mut(buf)).await;
+        }
+        if let Err(e) = lun.check_range(&mut unit.device, lba, blocks).await {
+            return self
+                .no_data(&mut unit.sense, cbw, Err(e), blocks_to_slice_mut(buf))
+                .await;
+        }
+
+        let mut sent = 0;
+        while sent < blocks {
+            let n = buf.len().min((blocks - sent) as usize);
+            if unit.device.read(lba + sent, &mut buf[..n]).await.is_err() {
+                warn!("Block device read error");
+                unit.sense = Sense::UNRECOVERED_READ_ERROR;
+                let residue = expected - sent as usize * BLOCK_SIZE;
+                self.send(blocks_to_slice_mut(buf), 0, residue).await?;
+                return Ok((residue as u32, CommandStatus::Failed));
+            }
+            let bytes = blocks_to_slice_mut(&mut buf[..n]);
+            self.send(bytes, bytes.len(), bytes.len()).await?;
+            sent += n as u32;
+        }
+        self.send(blocks_to_slice_mut(buf), 0, expected - len).await?;
+        Ok(((expected - len) as u32, CommandStatus::Passed))
+    }
+
+    /// WRITE(10): receive blocks from the host.
+    async fn write<B: BlockDevice<BLOCK_SIZE>>(
+        &mut self,
+        lun: &Lun<B>,
+        unit: &mut Unit<B>,
+        cbw: &Cbw,
+        buf: &mut [Block<B::Align>],
+    ) -> Outcome {
+        let (lba, blocks) = scsi::lba_and_length(&cbw.cb);
+        let len = blocks as usize * BLOCK_SIZE;
+        let expected = cbw.data_len as usize;
+        if expected < len || (expected > 0 && cbw.dir_in) {
+            return self.phase_error(cbw, blocks_to_slice_mut(buf)).await;
+        }
+        let result = match lun.check_range(&mut unit.device, lba, blocks).await {
+            Ok(()) if lun.is_write_protected() => Err(Sense::WRITE_PROTECTED),
+            result => result,
+        };
+        if let Err(e) = result {
+            return self
+                .no_data(&mut unit.sense, cbw, Err(e), blocks_to_slice_mut(buf))
+                .await;
+        }
+
+        let mut received = 0;
+        while received < blocks {
+            let n = buf.len().min((blocks - received) as usize);
+            let bytes = blocks_to_slice_mut(&mut buf[..n]);
+            let got = self.receive(bytes).await?;
+            let done = received as usize * BLOCK_SIZE;
+            if got < bytes.len() {
+                // The host ended the transfer early.
+                return Ok(((expected - done - got) as u32, CommandStatus::PhaseError));
+            }
+            if unit.device.write(lba + received, &buf[..n]).await.is_err() {
+                warn!("Block device write error");
+                unit.sense = Sense::WRITE_ERROR;
+                let residue = expected - done;
+                self.discard(blocks_to_slice_mut(buf), residue - got).await?;
+                return Ok((residue as u32, CommandStatus::Failed));
+            }
+            received += n as u32;
+        }
+        self.discard(blocks_to_slice_mut(buf), expected - len).await?;
+        Ok(((expected - len) as u32, CommandStatus::Passed))
+    }
+
+    /// Complete a command without data with `result`, padding or discarding any data expected by the host.
+    async fn no_data(&mut self, sense: &mut Sense, cbw: &Cbw, result: Result<(), Sense>, buf: &mut [u8]) -> Outcome {
+        let status = match result {
+            Ok(()) => CommandStatus::Passed,
+            Err(e) => {
+                *sense = e;
+                CommandStatus::Failed
+            }
+        };
+        let expected = cbw.data_len as usize;
+        match cbw.dir_in {
+            true => self.send(buf, 0, expected).await?,
+            false => self.discard(buf, expected).await?,
+        }
+        Ok((cbw.data_len, status))
+    }
+
+    /// Send the response `data`, truncated to the allocation length `alloc` of the command.
+    async fn data_in(&mut self, cbw: &Cbw, data: &[u8], alloc: usize, buf: &mut [u8]) -> Outcome {
+        let expected = cbw.data_len as usize;
+        if expected == 0 || !cbw.dir_in {
+            return self.phase_error(cbw, buf).await;
+        }
+        let len = data.len().min(alloc).min(expected);
+        buf[..len].copy_from_slice(&data[..len]);
+        self.send(buf, len, expected).await?;
+        Ok(((expected - len) as u32, CommandStatus::Passed))
+    }
+
+    /// The host and the device disagree on the data transfer: skip it and report a phase error.
+    async fn phase_error(&mut self, cbw: &Cbw, buf: &mut [u8]) -> Outcome {
+        let expected = cbw.data_len as usize;
+        match cbw.dir_in {
+            true => self.send(buf, 0, expected).await?,
+            false => self.discard(buf, expected).await?,
+        }
+        Ok((cbw.data_len, CommandStatus::PhaseError))
+    }
+
+    /// Send the first `len` bytes of `buf`, padded with zeros to `total` bytes.
+    async fn send(&mut self, buf: &mut [u8], mut len: usize, total: usize) -> Result<(), EndpointError> {
+        let mps = self.max_packet_size();
+        let mut sent = 0;
+        while sent < total {
+            let n = buf.len().min(total - sent);
+            buf[len..n].fill(0);
+            for packet in buf[..n].chunks(mps) {
+                self.write_ep.write(packet).await?;
+            }
+            sent += n;
+            len = 0;
+        }
+        Ok(())
+    }
+
+    /// Receive data into `buf` until it is full or the host sends a short packet.
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let mps = self.max_packet_size();
+        let mut received = 0;
+        while received < buf.len() {
+            let end = buf.len().min(received + mps);
+            let n = self.read_ep.read(&mut buf[received..end]).await?;
+            received += n;
+            if n < mps {
+                break;
+            }
+        }
+        Ok(received)
+    }
+
+    /// Receive and drop `total` bytes of data.
+    async fn discard(&mut self, buf: &mut [u8], total: usize) -> Result<(), EndpointError> {
+        let mut received = 0;
+        while received < total {
+            let n = buf.len().min(total - received);
+            let got = self.receive(&mut buf[..n]).await?;
+            received += got;
+            if got < n {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::collections::VecDeque;
+    use std::vec;
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::driver::{Direction, EndpointAddress, EndpointInfo, EndpointType};
+
+    const MPS: u16 = 64;
+
+    fn info(direction: Direction) -> EndpointInfo {
+        EndpointInfo {
+            addr: EndpointAddress::from_parts(1, direction),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: MPS,
+            interval_ms: 0,
+        }
+    }
+
+    /// Bulk OUT endpoint, reading the packets of the host until there are none left.
+    struct Out {
+        info: EndpointInfo,
+        packets: VecDeque<Vec<u8>>,
+    }
+
+    impl Endpoint for Out {
+        fn info(&self) -> &EndpointInfo {
+            &self.info
+        }
+
+        async fn wait_enabled(&mut self) {}
+    }
+
+    impl EndpointOut for Out {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+            let packet = self.packets.pop_front().ok_or(EndpointError::Disabled)?;
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+    }
+
+    /// Bulk IN endpoint, collecting the data sent to the host.
+    struct In {
+        info: EndpointInfo,
+        data: Vec<u8>,
+    }
+
+    impl Endpoint for In {
+        fn info(&self) -> &EndpointInfo {
+            &self.info
+        }
+
+        async fn wait_enabled(&mut self) {}
+    }
+
+    impl EndpointIn for In {
+        async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+            assert!(buf.len() <= MPS as usize);
+            self.data.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    struct RamDisk {
+        blocks: Vec<[u8; BLOCK_SIZE]>,
+    }
+
+    impl BlockDevice<BLOCK_SIZE> for RamDisk {
+        type Error = ();
+        type Align = aligned::A4;
+
+        async fn read(&mut self, block_address: u32, data: &mut [Block<aligned::A4>]) -> Result<(), ()> {
+            for (i, block) in data.iter_mut().enumerate() {
+                **block = self.blocks[block_address as usize + i];
+            }
+            Ok(())
+        }
+
+        async fn write(&mut self, block_address: u32, data: &[Block<aligned::A4>]) -> Result<(), ()> {
+            for (i, block) in data.iter().enumerate() {
+                self.blocks[block_address as usize + i] = **block;
+            }
+            Ok(())
+        }
+
+        async fn size(&mut self) -> Result<u64, ()> {
+            Ok((self.blocks.len() * BLOCK_SIZE) as u64)
+        }
+    }
+
+    fn lun(blocks: usize) -> Lun<RamDisk> {
+        let disk = RamDisk {
+            blocks: vec![[0; BLOCK_SIZE]; blocks],
+        };
+        Lun::new(disk, LunConfig::default())
+    }
+
+    fn cbw(tag: u32, data_len: u32, dir_in: bool, cb: &[u8]) -> Vec<u8> {
+        let mut cbw = CBW_SIGNATURE.to_le_bytes().to_vec();
+        cbw.extend_from_slice(&tag.to_le_bytes());
+        cbw.extend_from_slice(&data_len.to_le_bytes());
+        cbw.push(if dir_in { 0x80 } else { 0x00 });
+        cbw.push(0);
+        cbw.push(cb.len() as u8);
+        cbw.extend_from_slice(cb);
+        cbw.resize(CBW_LEN, 0);
+        cbw
+    }
+
+    /// Send the host `packets` to `lun`, and return the data it sent back.
+    fn transfer(lun: &Lun<RamDisk>, packets: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut bot = BulkOnly {
+            read_ep: Out {
+                info: info(Direction::Out),
+                packets: packets.into(),
+            },
+            write_ep: In {
+                info: info(Direction::In),
+                data: Vec::new(),
+            },
+        };
+        let mut buf = [Aligned([0; BLOCK_SIZE]); 2];
+        assert_eq!(
+            block_on(bot.serve(core::slice::from_ref(lun), &mut buf)),
+            EndpointError::Disabled
+        );
+        bot.write_ep.data
+    }
+
+    /// Split the data sent back for one command into its data and command status wrapper.
+    fn response(data: &[u8], tag: u32) -> (&[u8], u32, CommandStatus) {
+        let (data, csw) = data.split_at(data.len() - CSW_LEN);
+        assert_eq!(csw[0..4], CSW_SIGNATURE.to_le_bytes());
+        assert_eq!(csw[4..8], tag.to_le_bytes());
+        let status = match csw[12] {
+            0 => CommandStatus::Passed,
+            1 => CommandStatus::Failed,
+            _ => CommandStatus::PhaseError,
+        };
+        (data, u32::from_le_bytes(csw[8..12].try_into().unwrap()), status)
+    }
+
+    fn request_sense(lun: &Lun<RamDisk>) -> Sense {
+        let data = transfer(lun, vec![cbw(9, 18, true, &[opcode::REQUEST_SENSE, 0, 0, 0, 18, 0])]);
+        let (data, residue, status) = response(&data, 9);
+        assert_eq!((data.len(), residue, status), (18, 0, CommandStatus::Passed));
+        Sense {
+            key: data[2],
+            asc: data[12],
+            ascq: data[13],
+        }
+    }
+
+    #[test]
+    fn cbw_and_csw() {
+        let data = cbw(0x1234_5678, 512, true, &[opcode::READ_10, 0, 0, 0, 0, 1, 0, 0, 1, 0]);
+        let cbw = Cbw::parse(&data).unwrap();
+        assert_eq!(
+            (cbw.tag, cbw.data_len, cbw.dir_in, cbw.lun),
+            (0x1234_5678, 512, true, 0)
+        );
+        assert_eq!(scsi::lba_and_length(&cbw.cb), (1, 1));
+
+        assert!(Cbw::parse(&data[..CBW_LEN - 1]).is_none());
+        let mut bad = data.clone();
+        bad[0] ^= 1;
+        assert!(Cbw::parse(&bad).is_none());
+        let mut bad = data.clone();
+        bad[14] = 0;
+        assert!(Cbw::parse(&bad).is_none());
+
+        assert_eq!(
+            csw(0x1234_5678, 3, CommandStatus::Failed),
+            [0x55, 0x53, 0x42, 0x53, 0x78, 0x56, 0x34, 0x12, 3, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn invalid_cbw_is_ignored() {
+        let lun = lun(8);
+        let mut bad = cbw(1, 0, false, &[opcode::TEST_UNIT_READY]);
+        bad[0] = 0;
+        let data = transfer(&lun, vec![bad, cbw(2, 0, false, &[opcode::TEST_UNIT_READY])]);
+        assert_eq!(response(&data, 2), (&[][..], 0, CommandStatus::Passed));
+    }
+
+    #[test]
+    fn inquiry() {
+        let lun = lun(8);
+        let data = transfer(&lun, vec![cbw(1, 36, true, &[opcode::INQUIRY, 0, 0, 0, 36, 0])]);
+        let (data, residue, status) = response(&data, 1);
+        assert_eq!((residue, status), (0, CommandStatus::Passed));
+        assert_eq!(data[..5], [0x00, 0x80, 0x04, 0x02, 31]);
+        assert_eq!(&data[8..16], b"Embassy ");
+        assert_eq!(&data[16..32], b"Mass Storage    ");
+        assert_eq!(&data[32..36], b"1.0 ");
+
+        // Truncated to the allocation length, padded to the transfer length of the host.
+        let data = transfer(&lun, vec![cbw(2, 64, true, &[opcode::INQUIRY, 0, 0, 0, 8, 0])]);
+        let (data, residue, status) = response(&data, 2);
+        assert_eq!((data.len(), residue, status), (64, 56, CommandStatus::Passed));
+        assert!(data[8..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn read_capacity() {
+        let lun = lun(8);
+        let data = transfer(
+            &lun,
+            vec![cbw(1, 8, true, &[opcode::READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0])],
+        );
+        let (data, residue, status) = response(&data, 1);
+        assert_eq!((residue, status), (0, CommandStatus::Passed));
+        assert_eq!(data, [0, 0, 0, 7, 0, 0, 2, 0]);
+
+        lun.set_present(false);
+        let data = transfer(
+            &lun,
+            vec![cbw(2, 8, true, &[opcode::READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0])],
+        );
+        assert_eq!(response(&data, 2), (&[0; 8][..], 8, CommandStatus::Failed));
+        assert_eq!(request_sense(&lun), Sense::MEDIUM_NOT_PRESENT);
+    }
+
+    #[test]
+    fn request_sense_after_error() {
+        let lun = lun(8);
+        assert_eq!(request_sense(&lun), Sense::NO_SENSE);
+
+        let read = [opcode::READ_10, 0, 0, 0, 0, 7, 0, 0, 2, 0];
+        let data = transfer(&lun, vec![cbw(1, 1024, true, &read)]);
+        let (data, residue, status) = response(&data, 1);
+        assert_eq!((data.len(), residue, status), (1024, 1024, CommandStatus::Failed));
+
+        assert_eq!(request_sense(&lun), Sense::LBA_OUT_OF_RANGE);
+        // The sense data is cleared once reported.
+        assert_eq!(request_sense(&lun), Sense::NO_SENSE);
+    }
+
+    #[test]
+    fn medium_change() {
+        let lun = lun(8);
+        lun.set_present(false);
+        let data = transfer(&lun, vec![cbw(1, 0, false, &[opcode::TEST_UNIT_READY])]);
+        assert_eq!(response(&data, 1), (&[][..], 0, CommandStatus::Failed));
+        assert_eq!(request_sense(&lun), Sense::MEDIUM_NOT_PRESENT);
+
+        lun.set_present(true);
+        let data = transfer(&lun, vec![cbw(2, 0, false, &[opcode::TEST_UNIT_READY])]);
+        assert_eq!(response(&data, 2), (&[][..], 0, CommandStatus::Failed));
+        assert_eq!(request_sense(&lun), Sense::MEDIUM_CHANGED);
+        let data = transfer(&lun, vec![cbw(3, 0, false, &[opcode::TEST_UNIT_READY])]);
+        assert_eq!(response(&data, 3), (&[][..], 0, CommandStatus::Passed));
+    }
+
+    #[test]
+    fn write_and_read() {
+        let lun = lun(8);
+        let mut packets = vec![cbw(1, 3 * 512, false, &[opcode::WRITE_10, 0, 0, 0, 0, 2, 0, 0, 3, 0])];
+        let blocks: Vec<u8> = (0..3 * 512).map(|i| (i / 7) as u8).collect();
+        packets.extend(blocks.chunks(MPS as usize).map(|p| p.to_vec()));
+        let data = transfer(&lun, packets);
+        assert_eq!(response(&data, 1), (&[][..], 0, CommandStatus::Passed));
+
+        let data = transfer(
+            &lun,
+            vec![cbw(2, 3 * 512, true, &[opcode::READ_10, 0, 0, 0, 0, 2, 0, 0, 3, 0])],
+        );
+        let (data, residue, status) = response(&data, 2);
+        assert_eq!((residue, status), (0, CommandStatus::Passed));
+        assert_eq!(data, blocks);
+    }
+
+    #[test]
+    fn write_protected() {
+        let lun = lun(8);
+        lun.set_write_protected(true);
+
+        let data = transfer(&lun, vec![cbw(1, 4, true, &[opcode::MODE_SENSE_6, 0, 0x3f, 0, 4, 0])]);
+        assert_eq!(response(&data, 1), (&[3, 0, 0x80, 0][..], 0, CommandStatus::Passed));
+
+        // The data of the rejected command is discarded, and the next command is served.
+        let mut packets = vec![cbw(2, 512, false, &[opcode::WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0])];
+        packets.extend((0..512 / MPS).map(|_| vec![0xaa; MPS as usize]));
+        packets.push(cbw(3, 18, true, &[opcode::REQUEST_SENSE, 0, 0, 0, 18, 0]));
+        let data = transfer(&lun, packets);
+        let (csw, sense) = data.split_at(CSW_LEN);
+        assert_eq!(response(csw, 2), (&[][..], 512, CommandStatus::Failed));
+        let (sense, _, _) = response(sense, 3);
+        assert_eq!(
+            (sense[2], sense[12]),
+            (Sense::WRITE_PROTECTED.key, Sense::WRITE_PROTECTED.asc)
+        );
+
+        assert!(block_on(lun.device()).blocks[0].iter().all(|&b| b == 0));
+    }
+}
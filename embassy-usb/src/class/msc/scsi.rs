@@ -0,0 +1,118 @@
+//! SCSI transparent command set, as used by USB mass storage devices.
+
+/// SCSI operation codes.
+pub(crate) mod opcode {
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const INQUIRY: u8 = 0x12;
+    pub const MODE_SENSE_6: u8 = 0x1a;
+    pub const START_STOP_UNIT: u8 = 0x1b;
+    pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+    pub const READ_FORMAT_CAPACITIES: u8 = 0x23;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2a;
+    pub const VERIFY_10: u8 = 0x2f;
+    pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+    pub const MODE_SENSE_10: u8 = 0x5a;
+}
+
+/// Sense data reported by REQUEST SENSE after a command failed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sense {
+    /// Sense key.
+    pub key: u8,
+    /// Additional sense code.
+    pub asc: u8,
+    /// Additional sense code qualifier.
+    pub ascq: u8,
+}
+
+impl Sense {
+    pub(crate) const NO_SENSE: Sense = Sense::new(0x00, 0x00, 0x00);
+    pub(crate) const MEDIUM_NOT_PRESENT: Sense = Sense::new(0x02, 0x3a, 0x00);
+    pub(crate) const UNRECOVERED_READ_ERROR: Sense = Sense::new(0x03, 0x11, 0x00);
+    pub(crate) const WRITE_ERROR: Sense = Sense::new(0x03, 0x0c, 0x00);
+    pub(crate) const INVALID_COMMAND: Sense = Sense::new(0x05, 0x20, 0x00);
+    pub(crate) const LBA_OUT_OF_RANGE: Sense = Sense::new(0x05, 0x21, 0x00);
+    pub(crate) const INVALID_FIELD_IN_CDB: Sense = Sense::new(0x05, 0x24, 0x00);
+    pub(crate) const MEDIUM_CHANGED: Sense = Sense::new(0x06, 0x28, 0x00);
+    pub(crate) const WRITE_PROTECTED: Sense = Sense::new(0x07, 0x27, 0x00);
+
+    const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        Self { key, asc, ascq }
+    }
+
+    /// Fixed format sense data.
+    pub(crate) fn to_bytes(self) -> [u8; 18] {
+        let mut data = [0; 18];
+        data[0] = 0x70; // Current errors, fixed format
+        data[2] = self.key;
+        data[7] = 10; // Additional sense length
+        data[12] = self.asc;
+        data[13] = self.ascq;
+        data
+    }
+}
+
+/// Standard INQUIRY data.
+pub(crate) fn inquiry(vendor: &str, product: &str, revision: &str, removable: bool) -> [u8; 36] {
+    let mut data = [b' '; 36];
+    data[0] = 0x00; // Direct access block device
+    data[1] = if removable { 0x80 } else { 0x00 };
+    data[2] = 0x04; // SPC-2
+    data[3] = 0x02; // Response data format
+    data[4] = 31; // Additional length
+    data[5..8].fill(0);
+    pad(&mut data[8..16], vendor);
+    pad(&mut data[16..32], product);
+    pad(&mut data[32..36], revision);
+    data
+}
+
+fn pad(field: &mut [u8], s: &str) {
+    let len = s.len().min(field.len());
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Mode parameter header of MODE SENSE(6) or MODE SENSE(10), without any mode page.
+pub(crate) fn mode_sense(opcode: u8, write_protected: bool, buf: &mut [u8]) -> usize {
+    let wp = if write_protected { 0x80 } else { 0x00 };
+    match opcode {
+        opcode::MODE_SENSE_6 => {
+            buf[..4].copy_from_slice(&[3, 0, wp, 0]);
+            4
+        }
+        _ => {
+            buf[..8].copy_from_slice(&[0, 6, 0, wp, 0, 0, 0, 0]);
+            8
+        }
+    }
+}
+
+/// READ CAPACITY(10) data: address of the last block and block size.
+pub(crate) fn read_capacity(block_count: u32, block_size: u32) -> [u8; 8] {
+    let mut data = [0; 8];
+    data[..4].copy_from_slice(&block_count.saturating_sub(1).to_be_bytes());
+    data[4..].copy_from_slice(&block_size.to_be_bytes());
+    data
+}
+
+/// READ FORMAT CAPACITIES data with the current capacity only.
+pub(crate) fn read_format_capacities(block_count: u32, block_size: u32, present: bool) -> [u8; 12] {
+    let mut data = [0; 12];
+    data[3] = 8; // Capacity list length
+    data[4..8].copy_from_slice(&block_count.to_be_bytes());
+    data[8..].copy_from_slice(&block_size.to_be_bytes());
+    // Descriptor code: formatted media, or no media present
+    data[8] = if present { 0x02 } else { 0x03 };
+    data
+}
+
+/// Logical block address and transfer length of a READ(10), WRITE(10) or VERIFY(10) command.
+pub(crate) fn lba_and_length(cb: &[u8]) -> (u32, u32) {
+    let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]);
+    let len = u16::from_be_bytes([cb[7], cb[8]]) as u32;
+    (lba, len)
+}
@@ -0,0 +1,918 @@
+//! RNDIS class implementation, aka Ethernet over USB for Windows.
+//!
+//! Remote NDIS is a Microsoft protocol that carries NDIS control messages over CDC encapsulated
+//! commands, and wraps every Ethernet frame in a small header on the data interface.
+//!
+//! # Compatibility
+//!
+//! Windows: Supported out of the box. Windows 10 and later bind the in-box driver to the
+//! class codes used here. If an MS OS 2.0 descriptor set header is added to the builder before
+//! creating the class, an `RNDIS` compatible ID is added too, for older versions.
+//!
+//! Linux: Supported by the `rndis_host` driver, though some distributions disable it.
+//! Prefer CDC-ECM or CDC-NCM when possible, see the [`rndis_ecm`](crate::class::rndis_ecm) composite.
+//!
+//! macOS: NOT supported.
+
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::signal::Signal;
+
+use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::msos::CompatibleIdFeatureDescriptor;
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+pub mod embassy_net;
+
+/// Interface class code used by RNDIS: Wireless Controller.
+pub const USB_CLASS_WIRELESS_CONTROLLER: u8 = 0xE0;
+
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const RNDIS_SUBCLASS: u8 = 0x01;
+const RNDIS_PROTOCOL: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+
+const NOTIF_MAX_PACKET_SIZE: u16 = 8;
+const NOTIF_POLL_INTERVAL: u8 = 32;
+const NOTIF_RESPONSE_AVAILABLE: [u8; 8] = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+// Message types
+const MSG_PACKET: u32 = 0x0000_0001;
+const MSG_INITIALIZE: u32 = 0x0000_0002;
+const MSG_HALT: u32 = 0x0000_0003;
+const MSG_QUERY: u32 = 0x0000_0004;
+const MSG_SET: u32 = 0x0000_0005;
+const MSG_RESET: u32 = 0x0000_0006;
+//const MSG_INDICATE_STATUS: u32 = 0x0000_0007;
+const MSG_KEEPALIVE: u32 = 0x0000_0008;
+const MSG_COMPLETION: u32 = 0x8000_0000;
+
+// Status values
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+const STATUS_INVALID_DATA: u32 = 0xC001_0015;
+const STATUS_NOT_SUPPORTED: u32 = 0xC000_00BB;
+
+const RNDIS_MAJOR_VERSION: u32 = 1;
+const RNDIS_MINOR_VERSION: u32 = 0;
+const RNDIS_DF_CONNECTIONLESS: u32 = 0x0000_0001;
+const NDIS_MEDIUM_802_3: u32 = 0x0000_0000;
+const NDIS_MEDIA_STATE_CONNECTED: u32 = 0x0000_0000;
+const NDIS_HARDWARE_STATUS_READY: u32 = 0x0000_0000;
+const NDIS_PHYSICAL_MEDIUM_UNSPECIFIED: u32 = 0x0000_0000;
+
+// General OIDs
+const OID_GEN_SUPPORTED_LIST: u32 = 0x0001_0101;
+const OID_GEN_HARDWARE_STATUS: u32 = 0x0001_0102;
+const OID_GEN_MEDIA_SUPPORTED: u32 = 0x0001_0103;
+const OID_GEN_MEDIA_IN_USE: u32 = 0x0001_0104;
+const OID_GEN_MAXIMUM_FRAME_SIZE: u32 = 0x0001_0106;
+const OID_GEN_LINK_SPEED: u32 = 0x0001_0107;
+const OID_GEN_TRANSMIT_BLOCK_SIZE: u32 = 0x0001_010A;
+const OID_GEN_RECEIVE_BLOCK_SIZE: u32 = 0x0001_010B;
+const OID_GEN_VENDOR_ID: u32 = 0x0001_010C;
+const OID_GEN_VENDOR_DESCRIPTION: u32 = 0x0001_010D;
+const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010E;
+const OID_GEN_MAXIMUM_TOTAL_SIZE: u32 = 0x0001_0111;
+const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+const OID_GEN_PHYSICAL_MEDIUM: u32 = 0x0001_0202;
+const OID_GEN_XMIT_OK: u32 = 0x0002_0101;
+const OID_GEN_RCV_OK: u32 = 0x0002_0102;
+const OID_GEN_XMIT_ERROR: u32 = 0x0002_0103;
+const OID_GEN_RCV_ERROR: u32 = 0x0002_0104;
+const OID_GEN_RCV_NO_BUFFER: u32 = 0x0002_0105;
+
+// 802.3 OIDs
+const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+const OID_802_3_CURRENT_ADDRESS: u32 = 0x0101_0102;
+const OID_802_3_MULTICAST_LIST: u32 = 0x0101_0103;
+const OID_802_3_MAXIMUM_LIST_SIZE: u32 = 0x0101_0104;
+
+const SUPPORTED_OIDS: [u32; 23] = [
+    OID_GEN_SUPPORTED_LIST,
+    OID_GEN_HARDWARE_STATUS,
+    OID_GEN_MEDIA_SUPPORTED,
+    OID_GEN_MEDIA_IN_USE,
+    OID_GEN_MAXIMUM_FRAME_SIZE,
+    OID_GEN_LINK_SPEED,
+    OID_GEN_TRANSMIT_BLOCK_SIZE,
+    OID_GEN_RECEIVE_BLOCK_SIZE,
+    OID_GEN_VENDOR_ID,
+    OID_GEN_VENDOR_DESCRIPTION,
+    OID_GEN_CURRENT_PACKET_FILTER,
+    OID_GEN_MAXIMUM_TOTAL_SIZE,
+    OID_GEN_MEDIA_CONNECT_STATUS,
+    OID_GEN_PHYSICAL_MEDIUM,
+    OID_GEN_XMIT_OK,
+    OID_GEN_RCV_OK,
+    OID_GEN_XMIT_ERROR,
+    OID_GEN_RCV_ERROR,
+    OID_GEN_RCV_NO_BUFFER,
+    OID_802_3_PERMANENT_ADDRESS,
+    OID_802_3_CURRENT_ADDRESS,
+    OID_802_3_MULTICAST_LIST,
+    OID_802_3_MAXIMUM_LIST_SIZE,
+];
+
+const VENDOR_DESCRIPTION: &[u8] = b"embassy-usb RNDIS\0";
+
+/// Maximum size of an Ethernet frame, without FCS.
+const MAX_FRAME_SIZE: usize = 1514;
+/// Size of the header of a `REMOTE_NDIS_PACKET_MSG`.
+const PACKET_HEADER_LEN: usize = 44;
+/// Size of the largest data transfer: one frame with its header.
+const MAX_TRANSFER_SIZE: usize = PACKET_HEADER_LEN + MAX_FRAME_SIZE;
+/// Receive buffer size, rounded up to a multiple of every possible bulk max packet size.
+const TRANSFER_BUF_SIZE: usize = 2048;
+
+/// Size of the largest response to a control message, a `QUERY_CMPLT` with the list of supported OIDs.
+const MAX_RESPONSE_LEN: usize = 24 + 4 * SUPPORTED_OIDS.len();
+
+/// Internal state for the RNDIS class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Shared data between Control and `RndisClass`
+struct ControlShared {
+    mac_addr: [u8; 6],
+    /// Link speed, in units of 100 bit/s.
+    link_speed: u32,
+    /// The host has set a non-zero packet filter, so it wants to exchange data.
+    connected: CriticalSectionMutex<Cell<bool>>,
+    /// A response must be announced with a `RESPONSE_AVAILABLE` notification.
+    response_available: CriticalSectionMutex<Cell<bool>>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl ControlShared {
+    fn new() -> Self {
+        Self {
+            mac_addr: [0; 6],
+            link_speed: 0,
+            connected: CriticalSectionMutex::new(Cell::new(false)),
+            response_available: CriticalSectionMutex::new(Cell::new(false)),
+            changed: Signal::new(),
+        }
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.connected.lock(|c| c.set(connected));
+        self.changed.signal(());
+    }
+}
+
+struct Control<'a> {
+    shared: &'a ControlShared,
+    comm_if: InterfaceNumber,
+    packet_filter: u32,
+    response: [u8; MAX_RESPONSE_LEN],
+    response_len: usize,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+impl<'a> Control<'a> {
+    fn disconnect(&mut self) {
+        self.packet_filter = 0;
+        self.response_len = 0;
+        self.shared.response_available.lock(|r| r.set(false));
+        self.shared.set_connected(false);
+    }
+
+    /// Stores a completion message, to be read with `GET_ENCAPSULATED_RESPONSE`.
+    fn respond(&mut self, msg_type: u32, fields: &[u32], info: &[u8]) {
+        let len = 8 + 4 * fields.len() + info.len();
+        let msg = &mut self.response[..len];
+        msg[0..4].copy_from_slice(&(msg_type | MSG_COMPLETION).to_le_bytes());
+        msg[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        for (dst, field) in msg[8..].chunks_exact_mut(4).zip(fields) {
+            dst.copy_from_slice(&field.to_le_bytes());
+        }
+        msg[8 + 4 * fields.len()..].copy_from_slice(info);
+        self.response_len = len;
+
+        self.shared.response_available.lock(|r| r.set(true));
+        self.shared.changed.signal(());
+    }
+
+    fn handle_message(&mut self, msg: &[u8]) {
+        let (Some(msg_type), Some(request_id)) = (u32_at(msg, 0), u32_at(msg, 8)) else {
+            warn!("rndis: message too short");
+            return;
+        };
+
+        match msg_type {
+            MSG_INITIALIZE => {
+                debug!("rndis: initialize");
+                self.respond(
+                    MSG_INITIALIZE,
+                    &[
+                        request_id,
+                        STATUS_SUCCESS,
+                        RNDIS_MAJOR_VERSION,
+                        RNDIS_MINOR_VERSION,
+                        RNDIS_DF_CONNECTIONLESS,
+                        NDIS_MEDIUM_802_3,
+                        1, // MaxPacketsPerTransfer
+                        MAX_TRANSFER_SIZE as u32,
+                        0, // PacketAlignmentFactor
+                        0, // AFListOffset
+                        0, // AFListSize
+                    ],
+                    &[],
+                );
+            }
+            MSG_HALT => {
+                debug!("rndis: halt");
+                self.disconnect();
+            }
+            MSG_QUERY => {
+                let oid = u32_at(msg, 12).unwrap_or(0);
+                let mut info = [0u8; 4 * SUPPORTED_OIDS.len()];
+                match self.query(oid, &mut info) {
+                    Some(n) => self.respond(MSG_QUERY, &[request_id, STATUS_SUCCESS, n as u32, 16], &info[..n]),
+                    None => {
+                        debug!("rndis: query of unsupported OID {:08x}", oid);
+                        self.respond(MSG_QUERY, &[request_id, STATUS_NOT_SUPPORTED, 0, 0], &[]);
+                    }
+                }
+            }
+            MSG_SET => {
+                let oid = u32_at(msg, 12).unwrap_or(0);
+                let info_len = u32_at(msg, 16).unwrap_or(0) as usize;
+                let info_offset = 8 + u32_at(msg, 20).unwrap_or(0) as usize;
+                let status = match msg.get(info_offset..info_offset + info_len) {
+                    Some(info) => self.set(oid, info),
+                    None => STATUS_INVALID_DATA,
+                };
+                self.respond(MSG_SET, &[request_id, status], &[]);
+            }
+            MSG_RESET => {
+                debug!("rndis: reset");
+                // RESET_CMPLT has no RequestId: Status, AddressingReset.
+                self.respond(MSG_RESET, &[STATUS_SUCCESS, 1], &[]);
+            }
+            MSG_KEEPALIVE => self.respond(MSG_KEEPALIVE, &[request_id, STATUS_SUCCESS], &[]),
+            _ => warn!("rndis: unknown message type {:08x}", msg_type),
+        }
+    }
+
+    /// Writes the value of `oid` into `info`, returns its length or `None` if it is not supported.
+    fn query(&self, oid: u32, info: &mut [u8]) -> Option<usize> {
+        let value = match oid {
+            OID_GEN_SUPPORTED_LIST => {
+                for (dst, oid) in info.chunks_exact_mut(4).zip(SUPPORTED_OIDS) {
+                    dst.copy_from_slice(&oid.to_le_bytes());
+                }
+                return Some(4 * SUPPORTED_OIDS.len());
+            }
+            OID_GEN_VENDOR_DESCRIPTION => {
+                info[..VENDOR_DESCRIPTION.len()].copy_from_slice(VENDOR_DESCRIPTION);
+                return Some(VENDOR_DESCRIPTION.len());
+            }
+            OID_802_3_PERMANENT_ADDRESS | OID_802_3_CURRENT_ADDRESS => {
+                info[..6].copy_from_slice(&self.shared.mac_addr);
+                return Some(6);
+            }
+            // No multicast filtering, all frames are passed to the device.
+            OID_802_3_MULTICAST_LIST => return Some(0),
+            OID_GEN_HARDWARE_STATUS => NDIS_HARDWARE_STATUS_READY,
+            OID_GEN_MEDIA_SUPPORTED | OID_GEN_MEDIA_IN_USE => NDIS_MEDIUM_802_3,
+            OID_GEN_PHYSICAL_MEDIUM => NDIS_PHYSICAL_MEDIUM_UNSPECIFIED,
+            OID_GEN_MAXIMUM_FRAME_SIZE => (MAX_FRAME_SIZE - 14) as u32,
+            OID_GEN_TRANSMIT_BLOCK_SIZE | OID_GEN_RECEIVE_BLOCK_SIZE => MAX_FRAME_SIZE as u32,
+            OID_GEN_MAXIMUM_TOTAL_SIZE => MAX_TRANSFER_SIZE as u32,
+            OID_GEN_LINK_SPEED => self.shared.link_speed,
+            // No IEEE OUI.
+            OID_GEN_VENDOR_ID => 0x00FF_FFFF,
+            OID_GEN_CURRENT_PACKET_FILTER => self.packet_filter,
+            // There's no cable to unplug.
+            OID_GEN_MEDIA_CONNECT_STATUS => NDIS_MEDIA_STATE_CONNECTED,
+            OID_802_3_MAXIMUM_LIST_SIZE => 1,
+            // Statistics are not tracked.
+            OID_GEN_XMIT_OK | OID_GEN_RCV_OK | OID_GEN_XMIT_ERROR | OID_GEN_RCV_ERROR | OID_GEN_RCV_NO_BUFFER => 0,
+            _ => return None,
+        };
+        info[..4].copy_from_slice(&value.to_le_bytes());
+        Some(4)
+    }
+
+    /// Applies a SET request, returns the status.
+    fn set(&mut self, oid: u32, info: &[u8]) -> u32 {
+        match oid {
+            OID_GEN_CURRENT_PACKET_FILTER => {
+                let Some(filter) = u32_at(info, 0) else {
+                    return STATUS_INVALID_DATA;
+                };
+                debug!("rndis: packet filter {:08x}", filter);
+                self.packet_filter = filter;
+                self.shared.set_connected(filter != 0);
+                STATUS_SUCCESS
+            }
+            // No multicast filtering, all frames are passed to the device.
+            OID_802_3_MULTICAST_LIST => STATUS_SUCCESS,
+            _ => {
+                debug!("rndis: set of unsupported OID {:08x}", oid);
+                STATUS_NOT_SUPPORTED
+            }
+        }
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.disconnect();
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if !configured {
+            self.disconnect();
+        }
+    }
+
+    fn control_out(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_SEND_ENCAPSULATED_COMMAND => {
+                self.handle_message(data);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_ENCAPSULATED_RESPONSE => {
+                let len = self.response_len;
+                if len == 0 {
+                    // No response available, reply with a single zero byte.
+                    buf[0] = 0;
+                    return Some(InResponse::Accepted(&buf[..1]));
+                }
+                self.response_len = 0;
+                buf[..len].copy_from_slice(&self.response[..len]);
+                Some(InResponse::Accepted(&buf[..len]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// RNDIS class
+pub struct RndisClass<'d, D: Driver<'d>> {
+    _comm_if: InterfaceNumber,
+    comm_ep: D::EndpointIn,
+
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+
+    control: &'d ControlShared,
+
+    max_packet_size: usize,
+}
+
+impl<'d, D: Driver<'d>> RndisClass<'d, D> {
+    /// Create a new RNDIS class.
+    ///
+    /// `mac_address` is the MAC address the host will use for its side of the link.
+    ///
+    /// The control buffer of the builder must be at least 116 bytes long, to fit the largest response.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        mac_address: [u8; 6],
+        max_packet_size: u16,
+    ) -> Self {
+        assert!(
+            builder.control_buf_len() >= MAX_RESPONSE_LEN,
+            "control buffer must be at least {} bytes for RNDIS",
+            MAX_RESPONSE_LEN
+        );
+
+        state.shared.mac_addr = mac_address;
+        state.shared.link_speed = if max_packet_size >= 512 {
+            480_000_000 / 100
+        } else {
+            12_000_000 / 100
+        };
+
+        let with_msos = !builder.msos_writer().is_empty();
+        let mut func = builder.function(USB_CLASS_WIRELESS_CONTROLLER, RNDIS_SUBCLASS, RNDIS_PROTOCOL);
+        if with_msos {
+            func.msos_feature(CompatibleIdFeatureDescriptor::new("RNDIS", "5162001"));
+        }
+
+        // Control interface
+        let mut iface = func.interface();
+        let comm_if = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_WIRELESS_CONTROLLER, RNDIS_SUBCLASS, RNDIS_PROTOCOL, None);
+
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_HEADER, // bDescriptorSubtype
+                0x10,
+                0x01, // bcdCDC (1.10)
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_CALL_MANAGEMENT, // bDescriptorSubtype
+                0x00,                     // bmCapabilities
+                u8::from(comm_if) + 1,    // bDataInterface
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_ACM, // bDescriptorSubtype
+                0x00,         // bmCapabilities
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_UNION,        // bDescriptorSubtype
+                comm_if.into(),        // bControlInterface
+                u8::from(comm_if) + 1, // bSubordinateInterface
+            ],
+        );
+
+        let comm_ep = alt.endpoint_interrupt_in(None, NOTIF_MAX_PACKET_SIZE, NOTIF_POLL_INTERVAL);
+
+        // Data interface
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, 0x00, None);
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+
+        drop(func);
+
+        let control = state.control.write(Control {
+            shared: &state.shared,
+            comm_if,
+            packet_filter: 0,
+            response: [0; MAX_RESPONSE_LEN],
+            response_len: 0,
+        });
+        builder.handler(control);
+
+        RndisClass {
+            _comm_if: comm_if,
+            comm_ep,
+            read_ep,
+            write_ep,
+            control: &state.shared,
+            max_packet_size: max_packet_size as usize,
+        }
+    }
+
+    /// Split the class into a sender and receiver.
+    ///
+    /// This allows concurrently sending and receiving packets from separate tasks.
+    pub fn split(self) -> (Sender<'d, D>, Receiver<'d, D>) {
+        (
+            Sender {
+                write_ep: self.write_ep,
+                max_packet_size: self.max_packet_size,
+            },
+            Receiver {
+                comm_ep: self.comm_ep,
+                read_ep: self.read_ep,
+                control: self.control,
+            },
+        )
+    }
+}
+
+/// RNDIS class packet sender.
+///
+/// You can obtain a `Sender` with [`RndisClass::split`]
+pub struct Sender<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+    max_packet_size: usize,
+}
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    /// Write a packet.
+    ///
+    /// This waits until the packet is successfully stored in the RNDIS endpoint buffers.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        const ABS_MAX_PACKET_SIZE: usize = 512;
+
+        let mut header = [0u8; PACKET_HEADER_LEN];
+        header[0..4].copy_from_slice(&MSG_PACKET.to_le_bytes());
+        header[4..8].copy_from_slice(&((PACKET_HEADER_LEN + data.len()) as u32).to_le_bytes());
+        // DataOffset, counted from the DataOffset field itself.
+        header[8..12].copy_from_slice(&((PACKET_HEADER_LEN - 8) as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(data.len() as u32).to_le_bytes());
+
+        // Build first packet on a buffer, send next packets straight from `data`.
+        let mut buf = [0; ABS_MAX_PACKET_SIZE];
+        buf[..PACKET_HEADER_LEN].copy_from_slice(&header);
+
+        if PACKET_HEADER_LEN + data.len() < self.max_packet_size {
+            // First packet is not full, just send it.
+            // No need to send ZLP because it's short for sure.
+            buf[PACKET_HEADER_LEN..][..data.len()].copy_from_slice(data);
+            self.write_ep.write(&buf[..PACKET_HEADER_LEN + data.len()]).await?;
+        } else {
+            let (d1, d2) = data.split_at(self.max_packet_size - PACKET_HEADER_LEN);
+
+            buf[PACKET_HEADER_LEN..self.max_packet_size].copy_from_slice(d1);
+            self.write_ep.write(&buf[..self.max_packet_size]).await?;
+
+            for chunk in d2.chunks(self.max_packet_size) {
+                self.write_ep.write(chunk).await?;
+            }
+
+            // Send ZLP if needed.
+            if d2.len() % self.max_packet_size == 0 {
+                self.write_ep.write(&[]).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// RNDIS class packet receiver.
+///
+/// You can obtain a `Receiver` with [`RndisClass::split`]
+///
+/// The receiver also sends the notifications that tell the host a control response is available,
+/// so [`Receiver::wait_connection`] or [`Receiver::read_packet`] must be polled for the class to
+/// answer control messages.
+pub struct Receiver<'d, D: Driver<'d>> {
+    comm_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    /// Read a network packet.
+    ///
+    /// This waits until a packet is successfully received from the endpoint buffers.
+    ///
+    /// Returns [`EndpointError::Disabled`] if the host stops the data transfer, by clearing the
+    /// packet filter or halting the device.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        // Retry loop
+        loop {
+            // read message, serving control notifications until it starts.
+            let mut msg = [0u8; TRANSFER_BUF_SIZE];
+            let mut pos = loop {
+                match select(self.read_ep.read(&mut msg), self.control.changed.wait()).await {
+                    Either::First(n) => break n?,
+                    Either::Second(()) => {
+                        self.notify_response().await?;
+                        if !self.control.connected.lock(Cell::get) {
+                            return Err(EndpointError::Disabled);
+                        }
+                    }
+                }
+            };
+            let mut n = pos;
+            while n == self.read_ep.info().max_packet_size as usize && pos < TRANSFER_BUF_SIZE {
+                n = self.read_ep.read(&mut msg[pos..]).await?;
+                pos += n;
+            }
+
+            let msg = &msg[..pos];
+
+            // Process message header
+            let (Some(msg_type), Some(msg_len)) = (u32_at(msg, 0), u32_at(msg, 4)) else {
+                warn!("Received too short message");
+                continue;
+            };
+            if msg_type != MSG_PACKET {
+                warn!("Received bad message type.");
+                continue;
+            }
+            let (Some(data_offset), Some(data_len)) = (u32_at(msg, 8), u32_at(msg, 12)) else {
+                warn!("Received too short message");
+                continue;
+            };
+            let data_index = 8 + data_offset as usize;
+            let data_len = data_len as usize;
+
+            // Process actual frame, finally. Hosts may pad the transfer, so trust the header.
+            let Some(data) = msg
+                .get(..msg_len as usize)
+                .and_then(|msg| msg.get(data_index..data_index + data_len))
+            else {
+                warn!("Message has a data pointer out of range.");
+                continue;
+            };
+            let Some(dst) = buf.get_mut(..data_len) else {
+                warn!("Received frame larger than the buffer.");
+                continue;
+            };
+            dst.copy_from_slice(data);
+
+            return Ok(data_len);
+        }
+    }
+
+    /// Waits for the USB host to enable this interface
+    ///
+    /// This completes once the host has initialized the device and set a packet filter.
+    pub async fn wait_connection(&mut self) -> Result<(), EndpointError> {
+        loop {
+            self.read_ep.wait_enabled().await;
+            self.comm_ep.wait_enabled().await;
+
+            loop {
+                match self.notify_response().await {
+                    Ok(()) => {}
+                    Err(EndpointError::Disabled) => break, // Got disabled again, wait again.
+                    Err(e) => return Err(e),
+                }
+                if self.control.connected.lock(Cell::get) {
+                    return Ok(());
+                }
+                self.control.changed.wait().await;
+            }
+        }
+    }
+
+    /// Tells the host a control response is available, if there's one.
+    async fn notify_response(&mut self) -> Result<(), EndpointError> {
+        if self.control.response_available.lock(|r| r.replace(false)) {
+            self.comm_ep.write(&NOTIF_RESPONSE_AVAILABLE).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::driver::Direction;
+
+    const MAC_ADDR: [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x56];
+
+    fn shared() -> ControlShared {
+        ControlShared {
+            mac_addr: MAC_ADDR,
+            link_speed: 12_000_000 / 100,
+            ..ControlShared::new()
+        }
+    }
+
+    fn control(shared: &ControlShared) -> Control<'_> {
+        Control {
+            shared,
+            comm_if: InterfaceNumber(0),
+            packet_filter: 0,
+            response: [0; MAX_RESPONSE_LEN],
+            response_len: 0,
+        }
+    }
+
+    fn interface_request(direction: Direction, request: u8) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value: 0,
+            index: 0,
+            length: 256,
+        }
+    }
+
+    /// Builds a message with the given fields after its `RequestId`, followed by `info`.
+    fn message(msg_type: u32, request_id: u32, fields: &[u32], info: &[u8]) -> Vec<u8> {
+        let len = 12 + 4 * fields.len() + info.len();
+        let mut msg = Vec::new();
+        for field in [msg_type, len as u32, request_id].iter().chain(fields) {
+            msg.extend_from_slice(&field.to_le_bytes());
+        }
+        msg.extend_from_slice(info);
+        msg
+    }
+
+    fn query(oid: u32) -> Vec<u8> {
+        // Oid, InformationBufferLength, InformationBufferOffset, DeviceVcHandle
+        message(MSG_QUERY, 7, &[oid, 0, 0, 0], &[])
+    }
+
+    fn set(oid: u32, info: &[u8]) -> Vec<u8> {
+        // The information buffer follows the 28 byte header, its offset starts at the `RequestId`.
+        message(MSG_SET, 9, &[oid, info.len() as u32, 20, 0], info)
+    }
+
+    /// Sends a message, and reads back the response.
+    fn exchange(control: &mut Control<'_>, msg: &[u8]) -> Vec<u8> {
+        let send = interface_request(Direction::Out, REQ_SEND_ENCAPSULATED_COMMAND);
+        assert_eq!(control.control_out(send, msg), Some(OutResponse::Accepted));
+
+        let mut buf = [0; 256];
+        let get = interface_request(Direction::In, REQ_GET_ENCAPSULATED_RESPONSE);
+        match control.control_in(get, &mut buf) {
+            Some(InResponse::Accepted(response)) => response.to_vec(),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    fn words(response: &[u8]) -> Vec<u32> {
+        response
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn initialize() {
+        let shared = shared();
+        let mut control = control(&shared);
+
+        let response = exchange(&mut control, &message(MSG_INITIALIZE, 1, &[1, 0, 0x4000], &[]));
+        assert_eq!(
+            words(&response),
+            [
+                MSG_INITIALIZE | MSG_COMPLETION,
+                52,
+                1,
+                STATUS_SUCCESS,
+                RNDIS_MAJOR_VERSION,
+                RNDIS_MINOR_VERSION,
+                RNDIS_DF_CONNECTIONLESS,
+                NDIS_MEDIUM_802_3,
+                1,
+                MAX_TRANSFER_SIZE as u32,
+                0,
+                0,
+                0,
+            ]
+        );
+        assert!(shared.response_available.lock(Cell::get));
+
+        // A response is read only once, then a single zero byte signals that none is available.
+        let mut buf = [0; 256];
+        let get = interface_request(Direction::In, REQ_GET_ENCAPSULATED_RESPONSE);
+        assert_eq!(control.control_in(get, &mut buf), Some(InResponse::Accepted(&[0])));
+    }
+
+    #[test]
+    fn query_oids() {
+        let shared = shared();
+        let mut control = control(&shared);
+
+        // QUERY_CMPLT: RequestId, Status, InformationBufferLength, InformationBufferOffset.
+        let response = exchange(&mut control, &query(OID_GEN_SUPPORTED_LIST));
+        let response = words(&response);
+        assert_eq!(
+            response[..6],
+            [
+                MSG_QUERY | MSG_COMPLETION,
+                (24 + 4 * SUPPORTED_OIDS.len()) as u32,
+                7,
+                STATUS_SUCCESS,
+                (4 * SUPPORTED_OIDS.len()) as u32,
+                16,
+            ]
+        );
+        assert_eq!(response[6..], SUPPORTED_OIDS);
+
+        let response = exchange(&mut control, &query(OID_802_3_PERMANENT_ADDRESS));
+        assert_eq!(words(&response[8..24]), [7, STATUS_SUCCESS, 6, 16]);
+        assert_eq!(response[24..], MAC_ADDR);
+
+        let response = exchange(&mut control, &query(OID_GEN_LINK_SPEED));
+        assert_eq!(words(&response[24..]), [120_000]);
+
+        let response = exchange(&mut control, &query(OID_GEN_VENDOR_DESCRIPTION));
+        assert_eq!(response[24..], *VENDOR_DESCRIPTION);
+
+        // Every supported OID can be queried.
+        for oid in SUPPORTED_OIDS {
+            let response = exchange(&mut control, &query(oid));
+            assert_eq!(words(&response[12..16]), [STATUS_SUCCESS], "OID {:08x}", oid);
+        }
+
+        let response = exchange(&mut control, &query(0x0001_0203));
+        assert_eq!(
+            words(&response),
+            [MSG_QUERY | MSG_COMPLETION, 24, 7, STATUS_NOT_SUPPORTED, 0, 0]
+        );
+    }
+
+    #[test]
+    fn set_oids() {
+        let shared = shared();
+        let mut control = control(&shared);
+
+        // A non-zero packet filter connects the data path, a halt disconnects it.
+        let response = exchange(
+            &mut control,
+            &set(OID_GEN_CURRENT_PACKET_FILTER, &0x0fu32.to_le_bytes()),
+        );
+        assert_eq!(words(&response), [MSG_SET | MSG_COMPLETION, 16, 9, STATUS_SUCCESS]);
+        assert!(shared.connected.lock(Cell::get));
+        let response = exchange(&mut control, &query(OID_GEN_CURRENT_PACKET_FILTER));
+        assert_eq!(words(&response[24..]), [0x0f]);
+
+        let response = exchange(&mut control, &set(OID_802_3_MULTICAST_LIST, &[]));
+        assert_eq!(words(&response[8..]), [9, STATUS_SUCCESS]);
+
+        let response = exchange(&mut control, &set(OID_GEN_LINK_SPEED, &1u32.to_le_bytes()));
+        assert_eq!(words(&response[8..]), [9, STATUS_NOT_SUPPORTED]);
+
+        // The information buffer must lie within the message, and hold the whole filter.
+        let mut msg = set(OID_GEN_CURRENT_PACKET_FILTER, &0u32.to_le_bytes());
+        msg[16..20].copy_from_slice(&8u32.to_le_bytes());
+        let response = exchange(&mut control, &msg);
+        assert_eq!(words(&response[8..]), [9, STATUS_INVALID_DATA]);
+        let response = exchange(&mut control, &set(OID_GEN_CURRENT_PACKET_FILTER, &[0, 0]));
+        assert_eq!(words(&response[8..]), [9, STATUS_INVALID_DATA]);
+        assert!(shared.connected.lock(Cell::get));
+
+        let send = interface_request(Direction::Out, REQ_SEND_ENCAPSULATED_COMMAND);
+        assert_eq!(
+            control.control_out(send, &message(MSG_HALT, 10, &[], &[])),
+            Some(OutResponse::Accepted)
+        );
+        assert!(!shared.connected.lock(Cell::get));
+        assert_eq!(control.packet_filter, 0);
+    }
+
+    #[test]
+    fn other_messages() {
+        let shared = shared();
+        let mut control = control(&shared);
+
+        // RESET_CMPLT has no RequestId.
+        let response = exchange(&mut control, &message(MSG_RESET, 0, &[], &[]));
+        assert_eq!(words(&response), [MSG_RESET | MSG_COMPLETION, 16, STATUS_SUCCESS, 1]);
+
+        let response = exchange(&mut control, &message(MSG_KEEPALIVE, 3, &[], &[]));
+        assert_eq!(
+            words(&response),
+            [MSG_KEEPALIVE | MSG_COMPLETION, 16, 3, STATUS_SUCCESS]
+        );
+
+        // Short and unknown messages are dropped without a response.
+        assert_eq!(exchange(&mut control, &[0x02, 0, 0, 0, 12, 0, 0, 0]), [0]);
+        assert_eq!(exchange(&mut control, &message(0x0000_0007, 4, &[], &[])), [0]);
+
+        // Requests for other interfaces are left to other handlers.
+        let mut buf = [0; 256];
+        let get = Request {
+            index: 1,
+            ..interface_request(Direction::In, REQ_GET_ENCAPSULATED_RESPONSE)
+        };
+        assert_eq!(control.control_in(get, &mut buf), None);
+    }
+}
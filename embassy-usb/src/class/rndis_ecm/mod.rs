@@ -0,0 +1,166 @@
+//! Composite Ethernet over USB device, with an RNDIS and a CDC-ECM configuration.
+//!
+//! The device presents two configurations, and the host picks the one it has a driver for:
+//!
+//! 1. RNDIS, for Windows, which always selects the first configuration.
+//! 2. CDC-ECM, for Linux, macOS and Android. Linux skips RNDIS configurations when a
+//!    CDC one is available.
+//!
+//! Only one of them is active at a time, so [`Sender`] and [`Receiver`] transparently use
+//! the function of the configuration selected by the host.
+
+use core::cell::Cell;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+
+use super::{cdc_ecm, rndis};
+use crate::driver::{Driver, EndpointError};
+use crate::Builder;
+
+pub mod embassy_net;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Function {
+    None,
+    Rndis,
+    Ecm,
+}
+
+/// Internal state for the RNDIS + CDC-ECM composite class.
+pub struct State<'a> {
+    rndis: rndis::State<'a>,
+    ecm: cdc_ecm::State<'a>,
+    active: CriticalSectionMutex<Cell<Function>>,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            rndis: rndis::State::new(),
+            ecm: cdc_ecm::State::new(),
+            active: CriticalSectionMutex::new(Cell::new(Function::None)),
+        }
+    }
+}
+
+/// RNDIS + CDC-ECM composite class
+pub struct RndisEcmClass<'d, D: Driver<'d>> {
+    rndis: rndis::RndisClass<'d, D>,
+    ecm: cdc_ecm::CdcEcmClass<'d, D>,
+    active: &'d CriticalSectionMutex<Cell<Function>>,
+}
+
+impl<'d, D: Driver<'d>> RndisEcmClass<'d, D> {
+    /// Create a new RNDIS + CDC-ECM composite class.
+    ///
+    /// The RNDIS function is added to the configuration currently being built, which should
+    /// be the first one, then a new configuration is started for the CDC-ECM function.
+    /// The driver must have enough endpoints for both functions, as they don't share them.
+    ///
+    /// `mac_address` is the MAC address the host will use for its side of the link.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        mac_address: [u8; 6],
+        max_packet_size: u16,
+    ) -> Self {
+        let rndis = rndis::RndisClass::new(builder, &mut state.rndis, mac_address, max_packet_size);
+        builder.next_configuration();
+        let ecm = cdc_ecm::CdcEcmClass::new(builder, &mut state.ecm, mac_address, max_packet_size);
+
+        RndisEcmClass {
+            rndis,
+            ecm,
+            active: &state.active,
+        }
+    }
+
+    /// Split the class into a sender and receiver.
+    ///
+    /// This allows concurrently sending and receiving packets from separate tasks.
+    pub fn split(self) -> (Sender<'d, D>, Receiver<'d, D>) {
+        let (rndis_tx, rndis_rx) = self.rndis.split();
+        let (ecm_tx, ecm_rx) = self.ecm.split();
+        (
+            Sender {
+                rndis: rndis_tx,
+                ecm: ecm_tx,
+                active: self.active,
+            },
+            Receiver {
+                rndis: rndis_rx,
+                ecm: ecm_rx,
+                active: self.active,
+            },
+        )
+    }
+}
+
+/// RNDIS + CDC-ECM composite class packet sender.
+///
+/// You can obtain a `Sender` with [`RndisEcmClass::split`]
+pub struct Sender<'d, D: Driver<'d>> {
+    rndis: rndis::Sender<'d, D>,
+    ecm: cdc_ecm::Sender<'d, D>,
+    active: &'d CriticalSectionMutex<Cell<Function>>,
+}
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    /// Write a packet.
+    ///
+    /// This waits until the packet is successfully stored in the endpoint buffers of the
+    /// active function. Returns [`EndpointError::Disabled`] if there's no connection yet.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        match self.active.lock(Cell::get) {
+            Function::Rndis => self.rndis.write_packet(data).await,
+            Function::Ecm => self.ecm.write_packet(data).await,
+            Function::None => Err(EndpointError::Disabled),
+        }
+    }
+}
+
+/// RNDIS + CDC-ECM composite class packet receiver.
+///
+/// You can obtain a `Receiver` with [`RndisEcmClass::split`]
+pub struct Receiver<'d, D: Driver<'d>> {
+    rndis: rndis::Receiver<'d, D>,
+    ecm: cdc_ecm::Receiver<'d, D>,
+    active: &'d CriticalSectionMutex<Cell<Function>>,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    /// Read a network packet.
+    ///
+    /// This waits until a packet is successfully received from the endpoint buffers of the
+    /// active function. Returns [`EndpointError::Disabled`] if there's no connection yet.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        match self.active.lock(Cell::get) {
+            Function::Rndis => self.rndis.read_packet(buf).await,
+            Function::Ecm => self.ecm.read_packet(buf).await,
+            Function::None => Err(EndpointError::Disabled),
+        }
+    }
+
+    /// Waits for the USB host to enable either function
+    pub async fn wait_connection(&mut self) -> Result<(), EndpointError> {
+        self.active.lock(|a| a.set(Function::None));
+
+        let function = match select(self.rndis.wait_connection(), self.ecm.wait_connection()).await {
+            Either::First(res) => res.map(|()| Function::Rndis)?,
+            Either::Second(res) => res.map(|()| Function::Ecm)?,
+        };
+        debug!("rndis_ecm: connected with {:?}", function);
+
+        self.active.lock(|a| a.set(function));
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+//! USB Audio Class 2.0 - Headset device
+//!
+//! Provides a class with two audio streaming interfaces, one for the headphones (host to device)
+//! with explicit sample rate feedback, and one for the microphone (device to host).
+//!
+//! Both streams run at the sample rate of a single clock source. Their sample resolution and
+//! audio channels are configured separately.
+//!
+//! The class provides volume and mute controls for each channel of both streams.
+
+use core::marker::PhantomData;
+
+use super::class_codes::HEADSET;
+use super::terminal_type::TerminalType;
+use super::{AudioPath, CaptureStream, ControlMonitor, Feedback, PlaybackStream, State, StreamConfig};
+use crate::driver::Driver;
+use crate::Builder;
+
+/// Implementation of a USB audio class 2.0 headset.
+pub struct Headset<'d, D: Driver<'d>> {
+    phantom: PhantomData<&'d D>,
+}
+
+impl<'d, D: Driver<'d>> Headset<'d, D> {
+    /// Creates a new [`Headset`] device, split into a playback stream, feedback, a capture stream,
+    /// and a control change notifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `builder` - The builder for the class.
+    /// * `state` - The internal state of the class.
+    /// * `sample_rates_hz` - The supported sample rates in Hz (up to 10). The first one is the default.
+    /// * `playback` - The configuration of the headphones stream.
+    /// * `capture` - The configuration of the microphone stream.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        sample_rates_hz: &'d [u32],
+        playback: StreamConfig<'d>,
+        capture: StreamConfig<'d>,
+    ) -> (
+        PlaybackStream<'d, D>,
+        Feedback<'d, D>,
+        CaptureStream<'d, D>,
+        ControlMonitor<'d>,
+    ) {
+        let playback = AudioPath {
+            stream: playback,
+            terminal_type: TerminalType::OutHeadphones,
+        };
+        let capture = AudioPath {
+            stream: capture,
+            terminal_type: TerminalType::InPersonalMicrophone,
+        };
+
+        let (endpoints, control_monitor) =
+            super::build(builder, state, HEADSET, sample_rates_hz, Some(playback), Some(capture));
+        let (streaming_endpoint, feedback_endpoint) = endpoints.playback.unwrap();
+        let capture_endpoint = endpoints.capture.unwrap();
+
+        (
+            PlaybackStream { streaming_endpoint },
+            Feedback { feedback_endpoint },
+            CaptureStream {
+                streaming_endpoint: capture_endpoint,
+            },
+            control_monitor,
+        )
+    }
+}
@@ -0,0 +1,54 @@
+//! USB Audio Class 2.0 - Microphone device
+//!
+//! Provides a class with a single audio streaming interface (device to host),
+//! that advertises itself as a microphone. The stream is asynchronous: the host
+//! follows the clock of the device from the number of samples in each packet.
+//!
+//! Various aspects of the audio stream can be configured, for example:
+//! - sample rate
+//! - sample resolution
+//! - audio channel count and assignment
+//!
+//! The class provides volume and mute controls for each channel.
+
+use core::marker::PhantomData;
+
+use super::class_codes::MICROPHONE;
+use super::terminal_type::TerminalType;
+use super::{AudioPath, CaptureStream, ControlMonitor, State, StreamConfig};
+use crate::driver::Driver;
+use crate::Builder;
+
+/// Implementation of a USB audio class 2.0 microphone.
+pub struct Microphone<'d, D: Driver<'d>> {
+    phantom: PhantomData<&'d D>,
+}
+
+impl<'d, D: Driver<'d>> Microphone<'d, D> {
+    /// Creates a new [`Microphone`] device, split into a stream and a control change notifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `builder` - The builder for the class.
+    /// * `state` - The internal state of the class.
+    /// * `sample_rates_hz` - The supported sample rates in Hz (up to 10). The first one is the default.
+    /// * `stream` - The configuration of the audio stream.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        sample_rates_hz: &'d [u32],
+        stream: StreamConfig<'d>,
+    ) -> (CaptureStream<'d, D>, ControlMonitor<'d>) {
+        let capture = AudioPath {
+            stream,
+            terminal_type: TerminalType::InMicrophone,
+        };
+
+        let (endpoints, control_monitor) =
+            super::build(builder, state, MICROPHONE, sample_rates_hz, None, Some(capture));
+        let streaming_endpoint = endpoints.capture.unwrap();
+
+        (CaptureStream { streaming_endpoint }, control_monitor)
+    }
+}
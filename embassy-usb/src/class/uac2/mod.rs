@@ -0,0 +1,1178 @@
+//! USB Audio Class 2.0 implementations for different applications.
+//!
+//! Contains:
+//! - The `speaker` class with a single audio streaming interface (host to device), with explicit feedback
+//! - The `microphone` class with a single audio streaming interface (device to host)
+//! - The `headset` class, which combines both
+//!
+//! All classes are clocked by a single internal clock source entity, whose sample rate is selected by the
+//! host from a list of discrete rates. Every audio path has a feature unit with per-channel volume and
+//! mute controls.
+//!
+//! USB Audio Class 2.0 is supported natively by Linux, macOS and Windows 10 (version 1703) or later.
+
+use core::cell::{Cell, RefCell};
+use core::future::{poll_fn, Future};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use heapless::Vec;
+
+use self::class_codes::*;
+use self::terminal_type::TerminalType;
+pub use super::uac1::SampleWidth;
+use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut, Unsupported};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler, InterfaceAltBuilder, InterfaceBuilder};
+
+pub mod headset;
+pub mod microphone;
+pub mod speaker;
+
+mod class_codes;
+mod terminal_type;
+
+/// The maximum number of supported audio channels per stream.
+pub const MAX_AUDIO_CHANNEL_COUNT: usize = 12;
+
+/// The maximum number of supported discrete sample rates.
+pub const MAX_SAMPLE_RATE_COUNT: usize = 10;
+
+/// Arbitrary unique identifier for the clock source.
+const CLOCK_SOURCE_ID: u8 = 0x01;
+
+// Arbitrary unique identifiers for the entities of the playback path (host to device).
+const PLAYBACK_INPUT_TERMINAL_ID: u8 = 0x02;
+const PLAYBACK_FEATURE_UNIT_ID: u8 = 0x03;
+const PLAYBACK_OUTPUT_TERMINAL_ID: u8 = 0x04;
+
+// Arbitrary unique identifiers for the entities of the capture path (device to host).
+const CAPTURE_INPUT_TERMINAL_ID: u8 = 0x05;
+const CAPTURE_FEATURE_UNIT_ID: u8 = 0x06;
+const CAPTURE_OUTPUT_TERMINAL_ID: u8 = 0x07;
+
+// Volume settings go from -25600 to 0, in steps of 256.
+// Therefore, the volume settings are 8q8 values in units of dB.
+const VOLUME_STEPS_PER_DB: i16 = 256;
+const MIN_VOLUME_DB: i16 = -100;
+const MAX_VOLUME_DB: i16 = 0;
+
+/// Size of an explicit feedback packet, which holds up to 4 bytes (16.16 format on high-speed USB).
+const FEEDBACK_PACKET_SIZE: u16 = 4;
+
+/// USB Audio Channel, with the spatial locations of USB Audio Class 2.0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(missing_docs)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Channel {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequencyEffects,
+    BackLeft,
+    BackRight,
+    FrontLeftOfCenter,
+    FrontRightOfCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+    TopCenter,
+}
+
+impl Channel {
+    /// The bit of this channel in the `bmChannelConfig` field [UAC2 4.1].
+    const fn channel_config(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// The volume of an audio channel.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Volume {
+    /// The channel is muted.
+    Muted,
+    /// The channel volume in dB. Ranges from `MIN_VOLUME_DB` (quietest) to `MAX_VOLUME_DB` (loudest).
+    DeciBel(f32),
+}
+
+/// Configuration of an audio stream.
+#[derive(Clone, Copy)]
+pub struct StreamConfig<'d> {
+    /// The advertised audio channels (up to 12). Entries must be unique.
+    pub channels: &'d [Channel],
+    /// The audio sample resolution.
+    pub resolution: SampleWidth,
+    /// The maximum packet size per (micro)frame.
+    ///
+    /// For example, a stereo stream at 32 bit resolution and 48 kHz sample rate yields packets of 384 byte for
+    /// full-speed USB (1 ms frame interval) or 48 byte for high-speed USB (125 us microframe interval).
+    /// Asynchronous streams vary the number of samples per packet, so the packet size should have room for
+    /// at least one extra sample per channel.
+    pub max_packet_size: u16,
+}
+
+/// Internal state for the USB Audio Class.
+pub struct State<'d> {
+    control: Option<Control<'d>>,
+    shared: SharedControl<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: None,
+            shared: SharedControl::default(),
+        }
+    }
+}
+
+/// An audio path through the function, between a USB streaming terminal and a physical terminal.
+struct AudioPath<'d> {
+    stream: StreamConfig<'d>,
+    terminal_type: TerminalType,
+}
+
+/// The endpoints of an audio function.
+struct Endpoints<'d, D: Driver<'d>> {
+    playback: Option<(D::EndpointOut, D::EndpointIn)>,
+    capture: Option<D::EndpointIn>,
+}
+
+/// Get the `bmChannelConfig` field of a set of channels.
+fn channel_config(channels: &[Channel]) -> u32 {
+    assert!(
+        !channels.is_empty() && channels.len() <= MAX_AUDIO_CHANNEL_COUNT,
+        "Invalid channel count {}.",
+        channels.len()
+    );
+
+    let mut channel_config = 0;
+    for channel in channels {
+        let channel = channel.channel_config();
+
+        if channel_config & channel != 0 {
+            panic!("Invalid channel config, duplicate channel {}.", channel);
+        }
+        channel_config |= channel;
+    }
+
+    channel_config
+}
+
+/// Get an input terminal descriptor [UAC2 4.7.2.4].
+fn input_terminal_descriptor(id: u8, terminal_type: TerminalType, channels: &[Channel]) -> [u8; 15] {
+    let terminal_type = u16::from(terminal_type).to_le_bytes();
+    let channel_config = channel_config(channels).to_le_bytes();
+
+    [
+        INPUT_TERMINAL,   // bDescriptorSubtype
+        id,               // bTerminalID
+        terminal_type[0], // wTerminalType
+        terminal_type[1],
+        0x00,                 // bAssocTerminal (none)
+        CLOCK_SOURCE_ID,      // bCSourceID
+        channels.len() as u8, // bNrChannels
+        channel_config[0],    // bmChannelConfig
+        channel_config[1],
+        channel_config[2],
+        channel_config[3],
+        0x00, // iChannelNames (none)
+        0x00, // bmControls (none)
+        0x00,
+        0x00, // iTerminal (none)
+    ]
+}
+
+/// Get an output terminal descriptor [UAC2 4.7.2.5].
+fn output_terminal_descriptor(id: u8, terminal_type: TerminalType, source_id: u8) -> [u8; 10] {
+    let terminal_type = u16::from(terminal_type).to_le_bytes();
+
+    [
+        OUTPUT_TERMINAL,  // bDescriptorSubtype
+        id,               // bTerminalID
+        terminal_type[0], // wTerminalType
+        terminal_type[1],
+        0x00,            // bAssocTerminal (none)
+        source_id,       // bSourceID
+        CLOCK_SOURCE_ID, // bCSourceID
+        0x00,            // bmControls (none)
+        0x00,
+        0x00, // iTerminal (none)
+    ]
+}
+
+/// Get a feature unit descriptor with per-channel mute and volume controls [UAC2 4.7.2.8].
+fn feature_unit_descriptor(
+    id: u8,
+    source_id: u8,
+    channels: &[Channel],
+) -> Vec<u8, { 4 + 4 * (MAX_AUDIO_CHANNEL_COUNT + 1) }> {
+    let mut descriptor = Vec::new();
+    descriptor
+        .extend_from_slice(&[
+            FEATURE_UNIT, // bDescriptorSubtype
+            id,           // bUnitID
+            source_id,    // bSourceID
+        ])
+        .unwrap();
+
+    // Master controls (disabled, use only per-channel control)
+    descriptor.extend_from_slice(&0u32.to_le_bytes()).unwrap();
+
+    // Add per-channel controls
+    let controls = (CONTROL_HOST_PROGRAMMABLE as u32) | (CONTROL_HOST_PROGRAMMABLE as u32) << 2;
+    for _channel in channels {
+        descriptor.extend_from_slice(&controls.to_le_bytes()).unwrap();
+    }
+    descriptor.push(0x00).unwrap(); // iFeature (none)
+
+    descriptor
+}
+
+/// Write the zero-bandwidth and operational alternate settings of an audio streaming interface, up to the
+/// descriptors of its endpoints.
+fn streaming_alt_setting<'a, 'd, D: Driver<'d>>(
+    interface: &'a mut InterfaceBuilder<'_, 'd, D>,
+    terminal_link: u8,
+    stream: &StreamConfig<'d>,
+) -> InterfaceAltBuilder<'a, 'd, D> {
+    // Audio streaming interface, zero-bandwidth [UAC2 4.9.1]
+    interface.alt_setting(AUDIO, AUDIOSTREAMING, IP_VERSION_02_00, None);
+
+    // Audio streaming interface, operational [UAC2 4.9.1]
+    let mut alt = interface.alt_setting(AUDIO, AUDIOSTREAMING, IP_VERSION_02_00, None);
+
+    // Class-specific AS interface descriptor [UAC2 4.9.2]
+    let formats = PCM.to_le_bytes();
+    let channel_config = channel_config(stream.channels).to_le_bytes();
+    alt.descriptor(
+        CS_INTERFACE,
+        &[
+            AS_GENERAL,    // bDescriptorSubtype
+            terminal_link, // bTerminalLink
+            0x00,          // bmControls (none)
+            FORMAT_TYPE_I, // bFormatType
+            formats[0],    // bmFormats (PCM)
+            formats[1],
+            formats[2],
+            formats[3],
+            stream.channels.len() as u8, // bNrChannels
+            channel_config[0],           // bmChannelConfig
+            channel_config[1],
+            channel_config[2],
+            channel_config[3],
+            0x00, // iChannelNames (none)
+        ],
+    );
+
+    // Type I format type descriptor [FMT2 2.3.1.6]
+    alt.descriptor(
+        CS_INTERFACE,
+        &[
+            FORMAT_TYPE,                      // bDescriptorSubtype
+            FORMAT_TYPE_I,                    // bFormatType
+            stream.resolution as u8,          // bSubslotSize
+            stream.resolution.in_bit() as u8, // bBitResolution
+        ],
+    );
+
+    alt
+}
+
+/// Write the class-specific descriptor of an isochronous audio data endpoint [UAC2 4.10.1.2].
+fn iso_endpoint_descriptor<'d, D: Driver<'d>>(alt: &mut InterfaceAltBuilder<'_, 'd, D>) {
+    alt.descriptor(
+        CS_ENDPOINT,
+        &[
+            EP_GENERAL, // bDescriptorSubtype
+            0x00,       // bmAttributes (no max packets only restriction)
+            0x00,       // bmControls (none)
+            0x00,       // bLockDelayUnits (undefined)
+            0x00,       // wLockDelay (0)
+            0x00,
+        ],
+    );
+}
+
+/// Build an audio function with an optional playback and an optional capture path, sharing a clock source.
+fn build<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    state: &'d mut State<'d>,
+    category: u8,
+    sample_rates_hz: &'d [u32],
+    playback: Option<AudioPath<'d>>,
+    capture: Option<AudioPath<'d>>,
+) -> (Endpoints<'d, D>, ControlMonitor<'d>) {
+    assert!(
+        !sample_rates_hz.is_empty() && sample_rates_hz.len() <= MAX_SAMPLE_RATE_COUNT,
+        "Invalid sample rate count {}.",
+        sample_rates_hz.len()
+    );
+
+    // The sample rate range response holds a `MIN`, `MAX` and `RES` value for every discrete rate.
+    assert!(
+        builder.control_buf_len() >= 2 + 12 * sample_rates_hz.len(),
+        "Control buffer too small for the sample rate range."
+    );
+
+    // The class and subclass fields of the IAD aren't required to match the class and subclass fields of
+    // the interfaces in the interface collection that the IAD describes. Microsoft recommends that
+    // the first interface of the collection has class and subclass fields that match the class and
+    // subclass fields of the IAD.
+    let mut func = builder.function(AUDIO_FUNCTION, FUNCTION_SUBCLASS_UNDEFINED, AF_VERSION_02_00);
+
+    // Audio control interface (mandatory) [UAC2 4.7]
+    let mut interface = func.interface();
+    let control_interface = interface.interface_number();
+    let mut alt = interface.alt_setting(AUDIO, AUDIOCONTROL, IP_VERSION_02_00, None);
+
+    // Terminal topology, all clocked by the clock source:
+    // Playback: Input terminal (receives audio stream) -> Feature Unit (mute and volume) -> Output terminal
+    // Capture: Input terminal (e.g. microphone) -> Feature Unit (mute and volume) -> Output terminal (sends audio stream)
+
+    // =======================================
+    // Clock Source Descriptor [UAC2 4.7.2.1]
+    // Internal clock, whose frequency is programmable by the host
+    let clock_source_descriptor = [
+        CLOCK_SOURCE,                                       // bDescriptorSubtype
+        CLOCK_SOURCE_ID,                                    // bClockID
+        CLOCK_TYPE_INTERNAL_PROGRAMMABLE,                   // bmAttributes
+        CONTROL_HOST_PROGRAMMABLE | CONTROL_READ_ONLY << 2, // bmControls (frequency and validity)
+        0x00,                                               // bAssocTerminal (none)
+        0x00,                                               // iClockSource (none)
+    ];
+
+    let playback_descriptors = playback.as_ref().map(|path| {
+        (
+            input_terminal_descriptor(
+                PLAYBACK_INPUT_TERMINAL_ID,
+                TerminalType::UsbStreaming,
+                path.stream.channels,
+            ),
+            feature_unit_descriptor(
+                PLAYBACK_FEATURE_UNIT_ID,
+                PLAYBACK_INPUT_TERMINAL_ID,
+                path.stream.channels,
+            ),
+            output_terminal_descriptor(
+                PLAYBACK_OUTPUT_TERMINAL_ID,
+                path.terminal_type,
+                PLAYBACK_FEATURE_UNIT_ID,
+            ),
+        )
+    });
+
+    let capture_descriptors = capture.as_ref().map(|path| {
+        (
+            input_terminal_descriptor(CAPTURE_INPUT_TERMINAL_ID, path.terminal_type, path.stream.channels),
+            feature_unit_descriptor(CAPTURE_FEATURE_UNIT_ID, CAPTURE_INPUT_TERMINAL_ID, path.stream.channels),
+            output_terminal_descriptor(
+                CAPTURE_OUTPUT_TERMINAL_ID,
+                TerminalType::UsbStreaming,
+                CAPTURE_FEATURE_UNIT_ID,
+            ),
+        )
+    });
+
+    // ===================================================
+    // Class-specific AC Interface Descriptor [UAC2 4.7.2]
+    const DESCRIPTOR_HEADER_SIZE: usize = 2;
+    const INTERFACE_DESCRIPTOR_SIZE: usize = 7;
+
+    let mut total_descriptor_length =
+        INTERFACE_DESCRIPTOR_SIZE + clock_source_descriptor.len() + 2 * DESCRIPTOR_HEADER_SIZE;
+    for (input_terminal, feature_unit, output_terminal) in playback_descriptors.iter().chain(capture_descriptors.iter())
+    {
+        total_descriptor_length +=
+            input_terminal.len() + feature_unit.len() + output_terminal.len() + 3 * DESCRIPTOR_HEADER_SIZE;
+    }
+
+    let total_descriptor_length = (total_descriptor_length as u16).to_le_bytes();
+    let interface_descriptor: [u8; INTERFACE_DESCRIPTOR_SIZE] = [
+        HEADER, // bDescriptorSubtype (Header)
+        ADC_VERSION as u8,
+        (ADC_VERSION >> 8) as u8, // bcdADC
+        category,                 // bCategory
+        total_descriptor_length[0],
+        total_descriptor_length[1], // wTotalLength
+        0x00,                       // bmControls (no latency control)
+    ];
+
+    alt.descriptor(CS_INTERFACE, &interface_descriptor);
+    alt.descriptor(CS_INTERFACE, &clock_source_descriptor);
+    for (input_terminal, feature_unit, output_terminal) in playback_descriptors.iter().chain(capture_descriptors.iter())
+    {
+        alt.descriptor(CS_INTERFACE, input_terminal);
+        alt.descriptor(CS_INTERFACE, feature_unit);
+        alt.descriptor(CS_INTERFACE, output_terminal);
+    }
+
+    // =======================================================
+    // Playback streaming interface, with an asynchronous data
+    // endpoint and its explicit feedback endpoint
+    let playback_endpoints = playback.as_ref().map(|path| {
+        let mut interface = func.interface();
+        let mut alt = streaming_alt_setting(&mut interface, PLAYBACK_INPUT_TERMINAL_ID, &path.stream);
+
+        let streaming_endpoint = alt.endpoint_isochronous_out(
+            None,
+            path.stream.max_packet_size,
+            1,
+            SynchronizationType::Asynchronous,
+            UsageType::DataEndpoint,
+            &[],
+        );
+        iso_endpoint_descriptor(&mut alt);
+
+        // The feedback endpoint descriptor follows the descriptors of the data endpoint.
+        let feedback_endpoint = alt.endpoint_isochronous_in(
+            None,
+            FEEDBACK_PACKET_SIZE,
+            1,
+            SynchronizationType::NoSynchronization,
+            UsageType::FeedbackEndpoint,
+            &[],
+        );
+
+        (streaming_endpoint, feedback_endpoint)
+    });
+
+    // ========================================================
+    // Capture streaming interface, with an asynchronous data endpoint
+    // The host adapts to the rate of the device from the size of the packets
+    let capture_endpoint = capture.as_ref().map(|path| {
+        let mut interface = func.interface();
+        let mut alt = streaming_alt_setting(&mut interface, CAPTURE_OUTPUT_TERMINAL_ID, &path.stream);
+
+        let streaming_endpoint = alt.endpoint_isochronous_in(
+            None,
+            path.stream.max_packet_size,
+            1,
+            SynchronizationType::Asynchronous,
+            UsageType::DataEndpoint,
+            &[],
+        );
+        iso_endpoint_descriptor(&mut alt);
+
+        streaming_endpoint
+    });
+
+    // Free up the builder.
+    drop(func);
+
+    // Store stream information
+    state.shared.sample_rates_hz = sample_rates_hz;
+    state.shared.sample_rate_hz = AtomicU32::new(sample_rates_hz[0]);
+    if let Some(path) = &playback {
+        state.shared.playback.channels = path.stream.channels;
+    }
+    if let Some(path) = &capture {
+        state.shared.capture.channels = path.stream.channels;
+    }
+
+    state.control = Some(Control {
+        shared: &state.shared,
+        control_interface_number: control_interface,
+    });
+
+    builder.handler(state.control.as_mut().unwrap());
+
+    (
+        Endpoints {
+            playback: playback_endpoints,
+            capture: capture_endpoint,
+        },
+        ControlMonitor { shared: &state.shared },
+    )
+}
+
+/// Audio settings for a feature unit.
+///
+/// Contains volume and mute control.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct AudioSettings {
+    /// Channel mute states. Index zero is the (unused) master channel.
+    muted: [bool; MAX_AUDIO_CHANNEL_COUNT + 1],
+    /// Channel volume levels in 8.8 format (in dB). Index zero is the (unused) master channel.
+    volume_8q8_db: [i16; MAX_AUDIO_CHANNEL_COUNT + 1],
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            muted: [false; MAX_AUDIO_CHANNEL_COUNT + 1],
+            volume_8q8_db: [MAX_VOLUME_DB * VOLUME_STEPS_PER_DB; MAX_AUDIO_CHANNEL_COUNT + 1],
+        }
+    }
+}
+
+/// A feature unit, and the channels of its audio path.
+struct FeatureUnit<'d> {
+    /// Channel assignments, empty if the function doesn't have this audio path.
+    channels: &'d [Channel],
+
+    /// The collection of audio settings (volumes, mute states).
+    audio_settings: CriticalSectionMutex<Cell<AudioSettings>>,
+}
+
+impl<'d> Default for FeatureUnit<'d> {
+    fn default() -> Self {
+        FeatureUnit {
+            channels: &[],
+            audio_settings: CriticalSectionMutex::new(Cell::new(AudioSettings::default())),
+        }
+    }
+}
+
+impl<'d> FeatureUnit<'d> {
+    fn volume(&self, channel: Channel) -> Option<Volume> {
+        // The logical channels start at one (zero is the master channel).
+        let channel_index = self.channels.iter().position(|&c| c == channel)? + 1;
+        let audio_settings = self.audio_settings.lock(|x| x.get());
+
+        if audio_settings.muted[channel_index] {
+            return Some(Volume::Muted);
+        }
+
+        Some(Volume::DeciBel(
+            (audio_settings.volume_8q8_db[channel_index] as f32) / (VOLUME_STEPS_PER_DB as f32),
+        ))
+    }
+
+    fn reset(&self) {
+        self.audio_settings.lock(|x| x.set(AudioSettings::default()));
+    }
+}
+
+struct Control<'d> {
+    control_interface_number: InterfaceNumber,
+    shared: &'d SharedControl<'d>,
+}
+
+/// Shared data between [`Control`] and the audio classes.
+struct SharedControl<'d> {
+    /// The sample rates in Hz that the clock source supports.
+    sample_rates_hz: &'d [u32],
+
+    /// The current sample rate of the clock source in Hz.
+    sample_rate_hz: AtomicU32,
+
+    /// The feature unit of the playback path.
+    playback: FeatureUnit<'d>,
+
+    /// The feature unit of the capture path.
+    capture: FeatureUnit<'d>,
+
+    // Notification mechanism.
+    waker: RefCell<WakerRegistration>,
+    changed: AtomicBool,
+}
+
+impl<'d> Default for SharedControl<'d> {
+    fn default() -> Self {
+        SharedControl {
+            sample_rates_hz: &[],
+            sample_rate_hz: AtomicU32::new(0),
+            playback: FeatureUnit::default(),
+            capture: FeatureUnit::default(),
+            waker: RefCell::new(WakerRegistration::new()),
+            changed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<'d> SharedControl<'d> {
+    fn changed(&self) -> impl Future<Output = ()> + '_ {
+        poll_fn(|context| {
+            if self.changed.load(Ordering::Relaxed) {
+                self.changed.store(false, Ordering::Relaxed);
+                Poll::Ready(())
+            } else {
+                self.waker.borrow_mut().register(context.waker());
+                Poll::Pending
+            }
+        })
+    }
+
+    fn feature_unit(&self, entity_id: u8) -> Option<&FeatureUnit<'d>> {
+        let feature_unit = match entity_id {
+            PLAYBACK_FEATURE_UNIT_ID => &self.playback,
+            CAPTURE_FEATURE_UNIT_ID => &self.capture,
+            _ => return None,
+        };
+
+        // The function may not have this audio path.
+        (!feature_unit.channels.is_empty()).then_some(feature_unit)
+    }
+}
+
+/// Used for reading audio frames from the host.
+pub struct PlaybackStream<'d, D: Driver<'d>> {
+    streaming_endpoint: D::EndpointOut,
+}
+
+impl<'d, D: Driver<'d>> PlaybackStream<'d, D> {
+    /// Reads a single packet from the OUT endpoint.
+    pub async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.streaming_endpoint.read(data).await
+    }
+
+    /// Waits for the USB host to enable this interface.
+    pub async fn wait_connection(&mut self) {
+        self.streaming_endpoint.wait_enabled().await;
+    }
+
+    /// Gets the current (micro)frame number, if supported by the driver.
+    pub fn frame_number(&self) -> Result<u16, Unsupported> {
+        self.streaming_endpoint.frame_number()
+    }
+}
+
+/// Used for writing audio frames to the host.
+pub struct CaptureStream<'d, D: Driver<'d>> {
+    streaming_endpoint: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> CaptureStream<'d, D> {
+    /// Writes a single packet into the IN endpoint.
+    ///
+    /// The packet should contain the samples produced during one (micro)frame at the current sample rate,
+    /// so that the host can follow the clock of the device.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.streaming_endpoint.write(data).await
+    }
+
+    /// Waits for the USB host to enable this interface.
+    pub async fn wait_connection(&mut self) {
+        self.streaming_endpoint.wait_enabled().await;
+    }
+
+    /// Gets the current (micro)frame number, if supported by the driver.
+    pub fn frame_number(&self) -> Result<u16, Unsupported> {
+        self.streaming_endpoint.frame_number()
+    }
+}
+
+/// Used for writing sample rate information over the explicit feedback endpoint.
+///
+/// The feedback value is the number of samples per (micro)frame that the device consumes. It is a 10.14
+/// fixed point number sent in 3 bytes on full-speed USB, and a 16.16 fixed point number sent in 4 bytes on
+/// high-speed USB [USB 2.0 5.12.4.2].
+pub struct Feedback<'d, D: Driver<'d>> {
+    feedback_endpoint: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> Feedback<'d, D> {
+    /// Writes a single packet into the IN endpoint.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.feedback_endpoint.write(data).await
+    }
+
+    /// Writes a feedback value in 10.14 format, for full-speed USB.
+    pub async fn write_full_speed(&mut self, samples_per_frame_10q14: u32) -> Result<(), EndpointError> {
+        self.write_packet(&samples_per_frame_10q14.to_le_bytes()[..3]).await
+    }
+
+    /// Writes a feedback value in 16.16 format, for high-speed USB.
+    pub async fn write_high_speed(&mut self, samples_per_microframe_16q16: u32) -> Result<(), EndpointError> {
+        self.write_packet(&samples_per_microframe_16q16.to_le_bytes()).await
+    }
+
+    /// Waits for the USB host to enable this interface.
+    pub async fn wait_connection(&mut self) {
+        self.feedback_endpoint.wait_enabled().await;
+    }
+
+    /// Gets the current (micro)frame number, if supported by the driver.
+    pub fn frame_number(&self) -> Result<u16, Unsupported> {
+        self.feedback_endpoint.frame_number()
+    }
+}
+
+/// Control status change monitor
+///
+/// Await [`ControlMonitor::changed`] for being notified of configuration changes. Afterwards, the updated
+/// configuration settings can be read with [`ControlMonitor::playback_volume`],
+/// [`ControlMonitor::capture_volume`] and [`ControlMonitor::sample_rate_hz`].
+pub struct ControlMonitor<'d> {
+    shared: &'d SharedControl<'d>,
+}
+
+impl<'d> ControlMonitor<'d> {
+    /// Get the volume of a selected channel of the playback path.
+    ///
+    /// Returns `None` if the function has no playback path, or the channel is not part of it.
+    pub fn playback_volume(&self, channel: Channel) -> Option<Volume> {
+        self.shared.playback.volume(channel)
+    }
+
+    /// Get the volume of a selected channel of the capture path.
+    ///
+    /// Returns `None` if the function has no capture path, or the channel is not part of it.
+    pub fn capture_volume(&self, channel: Channel) -> Option<Volume> {
+        self.shared.capture.volume(channel)
+    }
+
+    /// Get the sample rate of the clock source in Hz.
+    pub fn sample_rate_hz(&self) -> u32 {
+        self.shared.sample_rate_hz.load(Ordering::Relaxed)
+    }
+
+    /// Return a future for when the control settings change.
+    pub async fn changed(&self) {
+        self.shared.changed().await;
+    }
+}
+
+impl<'d> Control<'d> {
+    fn changed(&mut self) {
+        self.shared.changed.store(true, Ordering::Relaxed);
+        self.shared.waker.borrow_mut().wake();
+    }
+
+    fn clock_source_set_request(&mut self, control_selector: u8, data: &[u8]) -> OutResponse {
+        if control_selector != CS_SAM_FREQ_CONTROL || data.len() != 4 {
+            debug!(
+                "Unsupported clock source set request for control selector {}",
+                control_selector
+            );
+            return OutResponse::Rejected;
+        }
+
+        let sample_rate_hz = u32::from_le_bytes(data.try_into().unwrap());
+        if !self.shared.sample_rates_hz.contains(&sample_rate_hz) {
+            debug!("Unsupported sample rate {} Hz", sample_rate_hz);
+            return OutResponse::Rejected;
+        }
+
+        self.shared.sample_rate_hz.store(sample_rate_hz, Ordering::Relaxed);
+        debug!("Set sample rate to {} Hz", sample_rate_hz);
+
+        OutResponse::Accepted
+    }
+
+    fn feature_unit_set_request(
+        &mut self,
+        feature_unit: &FeatureUnit<'d>,
+        control_selector: u8,
+        channel_index: u8,
+        data: &[u8],
+    ) -> OutResponse {
+        if channel_index == 0 || channel_index as usize > feature_unit.channels.len() {
+            debug!("Unsupported feature unit set request for channel {}", channel_index);
+            return OutResponse::Rejected;
+        }
+
+        let mut audio_settings = feature_unit.audio_settings.lock(|x| x.get());
+        match (control_selector, data.len()) {
+            (FU_MUTE_CONTROL, 1) => {
+                let mute_state = data[0] != 0;
+                audio_settings.muted[channel_index as usize] = mute_state;
+                debug!("Set channel {} mute state: {}", channel_index, mute_state);
+            }
+            (FU_VOLUME_CONTROL, 2) => {
+                let volume = i16::from_le_bytes(data.try_into().unwrap())
+                    .clamp(MIN_VOLUME_DB * VOLUME_STEPS_PER_DB, MAX_VOLUME_DB * VOLUME_STEPS_PER_DB);
+                audio_settings.volume_8q8_db[channel_index as usize] = volume;
+                debug!("Set channel {} volume: {}", channel_index, volume);
+            }
+            _ => {
+                debug!(
+                    "Unsupported feature unit set request for control selector {}",
+                    control_selector
+                );
+                return OutResponse::Rejected;
+            }
+        }
+
+        // Store updated settings
+        feature_unit.audio_settings.lock(|x| x.set(audio_settings));
+
+        OutResponse::Accepted
+    }
+
+    fn interface_set_request(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        let interface_number = req.index as u8;
+        let entity_index = (req.index >> 8) as u8;
+        let channel_index = req.value as u8;
+        let control_selector = (req.value >> 8) as u8;
+
+        if interface_number != self.control_interface_number.into() {
+            debug!("Unhandled interface set request for interface {}", interface_number);
+            return None;
+        }
+
+        if req.request != CUR {
+            debug!("Unsupported interface set request type {}", req.request);
+            return Some(OutResponse::Rejected);
+        }
+
+        let shared = self.shared;
+        let response = if entity_index == CLOCK_SOURCE_ID {
+            self.clock_source_set_request(control_selector, data)
+        } else if let Some(feature_unit) = shared.feature_unit(entity_index) {
+            self.feature_unit_set_request(feature_unit, control_selector, channel_index, data)
+        } else {
+            debug!("Unsupported interface set request for entity {}", entity_index);
+            OutResponse::Rejected
+        };
+
+        if response == OutResponse::Accepted {
+            self.changed();
+        }
+
+        Some(response)
+    }
+
+    fn clock_source_get_request<'r>(&self, request: u8, control_selector: u8, buf: &'r mut [u8]) -> InResponse<'r> {
+        match (request, control_selector) {
+            (CUR, CS_SAM_FREQ_CONTROL) => {
+                let sample_rate_hz = self.shared.sample_rate_hz.load(Ordering::Relaxed);
+                buf[..4].copy_from_slice(&sample_rate_hz.to_le_bytes());
+                InResponse::Accepted(&buf[..4])
+            }
+            (RANGE, CS_SAM_FREQ_CONTROL) => {
+                // Every discrete sample rate is a subrange with equal `MIN` and `MAX`, and no `RES`.
+                let sample_rates_hz = self.shared.sample_rates_hz;
+                buf[..2].copy_from_slice(&(sample_rates_hz.len() as u16).to_le_bytes());
+                for (subrange, sample_rate_hz) in buf[2..].chunks_exact_mut(12).zip(sample_rates_hz) {
+                    subrange[..4].copy_from_slice(&sample_rate_hz.to_le_bytes());
+                    subrange[4..8].copy_from_slice(&sample_rate_hz.to_le_bytes());
+                    subrange[8..].copy_from_slice(&0u32.to_le_bytes());
+                }
+                InResponse::Accepted(&buf[..2 + 12 * sample_rates_hz.len()])
+            }
+            (CUR, CS_CLOCK_VALID_CONTROL) => {
+                buf[0] = true.into();
+                InResponse::Accepted(&buf[..1])
+            }
+            _ => {
+                debug!(
+                    "Unsupported clock source get request for control selector {}.",
+                    control_selector
+                );
+                InResponse::Rejected
+            }
+        }
+    }
+
+    fn feature_unit_get_request<'r>(
+        &self,
+        feature_unit: &FeatureUnit<'d>,
+        request: u8,
+        control_selector: u8,
+        channel_index: u8,
+        buf: &'r mut [u8],
+    ) -> InResponse<'r> {
+        if channel_index == 0 || channel_index as usize > feature_unit.channels.len() {
+            debug!("Unsupported feature unit get request for channel {}.", channel_index);
+            return InResponse::Rejected;
+        }
+
+        let audio_settings = feature_unit.audio_settings.lock(|x| x.get());
+
+        match (request, control_selector) {
+            (CUR, FU_MUTE_CONTROL) => {
+                let mute_state = audio_settings.muted[channel_index as usize];
+                buf[0] = mute_state.into();
+                debug!("Got channel {} mute state: {}.", channel_index, mute_state);
+                InResponse::Accepted(&buf[..1])
+            }
+            (CUR, FU_VOLUME_CONTROL) => {
+                let volume = audio_settings.volume_8q8_db[channel_index as usize];
+                buf[..2].copy_from_slice(&volume.to_le_bytes());
+                debug!("Got channel {} volume: {}.", channel_index, volume);
+                InResponse::Accepted(&buf[..2])
+            }
+            (RANGE, FU_VOLUME_CONTROL) => {
+                // A single subrange.
+                buf[..2].copy_from_slice(&1u16.to_le_bytes());
+                buf[2..4].copy_from_slice(&(MIN_VOLUME_DB * VOLUME_STEPS_PER_DB).to_le_bytes());
+                buf[4..6].copy_from_slice(&(MAX_VOLUME_DB * VOLUME_STEPS_PER_DB).to_le_bytes());
+                buf[6..8].copy_from_slice(&VOLUME_STEPS_PER_DB.to_le_bytes());
+                InResponse::Accepted(&buf[..8])
+            }
+            _ => {
+                debug!(
+                    "Unsupported feature unit get request for control selector {}.",
+                    control_selector
+                );
+                InResponse::Rejected
+            }
+        }
+    }
+
+    fn interface_get_request<'r>(&'r mut self, req: Request, buf: &'r mut [u8]) -> Option<InResponse<'r>> {
+        let interface_number = req.index as u8;
+        let entity_index = (req.index >> 8) as u8;
+        let channel_index = req.value as u8;
+        let control_selector = (req.value >> 8) as u8;
+
+        if interface_number != self.control_interface_number.into() {
+            debug!("Unhandled interface get request for interface {}.", interface_number);
+            return None;
+        }
+
+        if entity_index == CLOCK_SOURCE_ID {
+            Some(self.clock_source_get_request(req.request, control_selector, buf))
+        } else if let Some(feature_unit) = self.shared.feature_unit(entity_index) {
+            Some(self.feature_unit_get_request(feature_unit, req.request, control_selector, channel_index, buf))
+        } else {
+            debug!("Unsupported interface get request for entity {}.", entity_index);
+            Some(InResponse::Rejected)
+        }
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    /// Called when a "set alternate setting" control request is done on the interface.
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        debug!(
+            "USB set interface number {} to alt setting {}.",
+            iface, alternate_setting
+        );
+    }
+
+    /// Called after a USB reset after the bus reset sequence is complete.
+    fn reset(&mut self) {
+        let shared = self.shared;
+        shared.playback.reset();
+        shared.capture.reset();
+        shared
+            .sample_rate_hz
+            .store(shared.sample_rates_hz[0], Ordering::Relaxed);
+
+        self.changed();
+    }
+
+    // Handle control set requests.
+    fn control_out(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        match (req.request_type, req.recipient) {
+            (RequestType::Class, Recipient::Interface) => self.interface_set_request(req, data),
+            _ => None,
+        }
+    }
+
+    // Handle control get requests.
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        match (req.request_type, req.recipient) {
+            (RequestType::Class, Recipient::Interface) => self.interface_get_request(req, buf),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::descriptor::descriptor_type;
+    use crate::driver::Direction;
+    use crate::test_driver::TestDriver;
+    use crate::Config;
+
+    const SAMPLE_RATES_HZ: [u32; 3] = [48_000, 44_100, 96_000];
+
+    /// Splits a configuration descriptor, and tags every descriptor with the subclass of its interface.
+    fn descriptors(config_descriptor: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut descriptors = Vec::new();
+        let mut subclass = 0;
+        let mut data = config_descriptor;
+        while !data.is_empty() {
+            let (descriptor, rest) = data.split_at(data[0] as usize);
+            if descriptor[1] == descriptor_type::INTERFACE {
+                subclass = descriptor[6];
+            }
+            descriptors.push((subclass, descriptor));
+            data = rest;
+        }
+        descriptors
+    }
+
+    fn interface_request(direction: Direction, request: u8, entity_id: u8, control_selector: u8) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value: (control_selector as u16) << 8,
+            index: (entity_id as u16) << 8,
+            length: 64,
+        }
+    }
+
+    #[test]
+    fn class_specific_descriptors() {
+        let mut config_descriptor = [0; 512];
+        let mut bos_descriptor = [0; 64];
+        let mut control_buf = [0; 64];
+        let mut state = State::new();
+        let mut builder = Builder::new(
+            TestDriver::default(),
+            Config::new(0xc0de, 0xcafe),
+            &mut config_descriptor,
+            &mut bos_descriptor,
+            &mut [],
+            &mut control_buf,
+        );
+
+        let playback = StreamConfig {
+            channels: &[Channel::FrontLeft, Channel::FrontRight],
+            resolution: SampleWidth::Width2Byte,
+            max_packet_size: 200,
+        };
+        let capture = StreamConfig {
+            channels: &[Channel::FrontCenter],
+            resolution: SampleWidth::Width3Byte,
+            max_packet_size: 150,
+        };
+        let _ = headset::Headset::new(&mut builder, &mut state, &SAMPLE_RATES_HZ, playback, capture);
+        let device = builder.build();
+        let descriptors = descriptors(device.inner.config_descriptor);
+
+        // The audio control interface: its header, the clock source, and both audio paths.
+        let control: Vec<&[u8]> = descriptors
+            .iter()
+            .filter(|(subclass, d)| *subclass == AUDIOCONTROL && d[1] == CS_INTERFACE)
+            .map(|(_, d)| *d)
+            .collect();
+        let layout: Vec<(u8, usize)> = control.iter().map(|d| (d[2], d.len())).collect();
+        assert_eq!(
+            layout,
+            [
+                (HEADER, 9),
+                (CLOCK_SOURCE, 8),
+                (INPUT_TERMINAL, 17),
+                (FEATURE_UNIT, 18),
+                (OUTPUT_TERMINAL, 12),
+                (INPUT_TERMINAL, 17),
+                (FEATURE_UNIT, 14),
+                (OUTPUT_TERMINAL, 12),
+            ]
+        );
+
+        // wTotalLength covers the header and all units and terminals.
+        let header = control[0];
+        assert_eq!(header[5], HEADSET);
+        let total_length = u16::from_le_bytes([header[6], header[7]]) as usize;
+        assert_eq!(total_length, control.iter().map(|d| d.len()).sum::<usize>());
+
+        // Both audio streaming interfaces have a general and a format type descriptor, and a
+        // class-specific endpoint descriptor for their data endpoint.
+        let streaming: Vec<&[u8]> = descriptors
+            .iter()
+            .filter(|(subclass, d)| *subclass == AUDIOSTREAMING && (d[1] == CS_INTERFACE || d[1] == CS_ENDPOINT))
+            .map(|(_, d)| *d)
+            .collect();
+        let layout: Vec<(u8, u8, usize)> = streaming.iter().map(|d| (d[1], d[2], d.len())).collect();
+        assert_eq!(
+            layout,
+            [
+                (CS_INTERFACE, AS_GENERAL, 16),
+                (CS_INTERFACE, FORMAT_TYPE, 6),
+                (CS_ENDPOINT, EP_GENERAL, 8),
+                (CS_INTERFACE, AS_GENERAL, 16),
+                (CS_INTERFACE, FORMAT_TYPE, 6),
+                (CS_ENDPOINT, EP_GENERAL, 8),
+            ]
+        );
+
+        // The streams link to their USB streaming terminals, with their channel count and resolution.
+        assert_eq!((streaming[0][3], streaming[0][10]), (PLAYBACK_INPUT_TERMINAL_ID, 2));
+        assert_eq!((streaming[1][4], streaming[1][5]), (2, 16));
+        assert_eq!((streaming[3][3], streaming[3][10]), (CAPTURE_OUTPUT_TERMINAL_ID, 1));
+        assert_eq!((streaming[4][4], streaming[4][5]), (3, 24));
+    }
+
+    #[test]
+    fn clock_source_requests() {
+        let shared = SharedControl {
+            sample_rates_hz: &SAMPLE_RATES_HZ,
+            sample_rate_hz: AtomicU32::new(SAMPLE_RATES_HZ[0]),
+            ..Default::default()
+        };
+        let mut control = Control {
+            control_interface_number: InterfaceNumber(0),
+            shared: &shared,
+        };
+        let mut buf = [0; 64];
+
+        let get_cur = interface_request(Direction::In, CUR, CLOCK_SOURCE_ID, CS_SAM_FREQ_CONTROL);
+        assert_eq!(
+            control.control_in(get_cur, &mut buf),
+            Some(InResponse::Accepted(&48_000u32.to_le_bytes()))
+        );
+
+        // Every rate is a subrange with equal MIN and MAX.
+        let get_range = interface_request(Direction::In, RANGE, CLOCK_SOURCE_ID, CS_SAM_FREQ_CONTROL);
+        let mut range = Vec::from(3u16.to_le_bytes());
+        for rate in SAMPLE_RATES_HZ {
+            range.extend_from_slice(&rate.to_le_bytes());
+            range.extend_from_slice(&rate.to_le_bytes());
+            range.extend_from_slice(&0u32.to_le_bytes());
+        }
+        assert_eq!(
+            control.control_in(get_range, &mut buf),
+            Some(InResponse::Accepted(&range))
+        );
+
+        let get_valid = interface_request(Direction::In, CUR, CLOCK_SOURCE_ID, CS_CLOCK_VALID_CONTROL);
+        assert_eq!(
+            control.control_in(get_valid, &mut buf),
+            Some(InResponse::Accepted(&[1]))
+        );
+
+        let set_cur = interface_request(Direction::Out, CUR, CLOCK_SOURCE_ID, CS_SAM_FREQ_CONTROL);
+        assert_eq!(
+            control.control_out(set_cur, &96_000u32.to_le_bytes()),
+            Some(OutResponse::Accepted)
+        );
+        assert_eq!(shared.sample_rate_hz.load(Ordering::Relaxed), 96_000);
+        assert!(shared.changed.swap(false, Ordering::Relaxed));
+
+        // Unsupported rates, malformed values and other requests are rejected, and keep the rate.
+        assert_eq!(
+            control.control_out(set_cur, &22_050u32.to_le_bytes()),
+            Some(OutResponse::Rejected)
+        );
+        assert_eq!(
+            control.control_out(set_cur, &[0x80, 0xbb, 0]),
+            Some(OutResponse::Rejected)
+        );
+        let set_range = interface_request(Direction::Out, RANGE, CLOCK_SOURCE_ID, CS_SAM_FREQ_CONTROL);
+        assert_eq!(
+            control.control_out(set_range, &48_000u32.to_le_bytes()),
+            Some(OutResponse::Rejected)
+        );
+        assert_eq!(shared.sample_rate_hz.load(Ordering::Relaxed), 96_000);
+        assert!(!shared.changed.load(Ordering::Relaxed));
+        assert_eq!(
+            control.control_in(get_cur, &mut buf),
+            Some(InResponse::Accepted(&96_000u32.to_le_bytes()))
+        );
+
+        // Requests for other interfaces are left to other handlers.
+        let other_interface = Request { index: 1, ..get_cur };
+        assert_eq!(control.control_in(other_interface, &mut buf), None);
+
+        // A bus reset restores the default rate.
+        control.reset();
+        assert_eq!(shared.sample_rate_hz.load(Ordering::Relaxed), 48_000);
+    }
+}
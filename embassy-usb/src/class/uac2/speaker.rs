@@ -0,0 +1,57 @@
+//! USB Audio Class 2.0 - Speaker device
+//!
+//! Provides a class with a single audio streaming interface (host to device),
+//! that advertises itself as a speaker. Includes explicit sample rate feedback.
+//!
+//! Various aspects of the audio stream can be configured, for example:
+//! - sample rate
+//! - sample resolution
+//! - audio channel count and assignment
+//!
+//! The class provides volume and mute controls for each channel.
+
+use core::marker::PhantomData;
+
+use super::class_codes::DESKTOP_SPEAKER;
+use super::terminal_type::TerminalType;
+use super::{AudioPath, ControlMonitor, Feedback, PlaybackStream, State, StreamConfig};
+use crate::driver::Driver;
+use crate::Builder;
+
+/// Implementation of a USB audio class 2.0 speaker.
+pub struct Speaker<'d, D: Driver<'d>> {
+    phantom: PhantomData<&'d D>,
+}
+
+impl<'d, D: Driver<'d>> Speaker<'d, D> {
+    /// Creates a new [`Speaker`] device, split into a stream, feedback, and a control change notifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `builder` - The builder for the class.
+    /// * `state` - The internal state of the class.
+    /// * `sample_rates_hz` - The supported sample rates in Hz (up to 10). The first one is the default.
+    /// * `stream` - The configuration of the audio stream.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        sample_rates_hz: &'d [u32],
+        stream: StreamConfig<'d>,
+    ) -> (PlaybackStream<'d, D>, Feedback<'d, D>, ControlMonitor<'d>) {
+        let playback = AudioPath {
+            stream,
+            terminal_type: TerminalType::OutSpeaker,
+        };
+
+        let (endpoints, control_monitor) =
+            super::build(builder, state, DESKTOP_SPEAKER, sample_rates_hz, Some(playback), None);
+        let (streaming_endpoint, feedback_endpoint) = endpoints.playback.unwrap();
+
+        (
+            PlaybackStream { streaming_endpoint },
+            Feedback { feedback_endpoint },
+            control_monitor,
+        )
+    }
+}
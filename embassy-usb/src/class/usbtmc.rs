@@ -0,0 +1,811 @@
+//! USB Test and Measurement Class (USBTMC) with the USB488 subclass, for instruments driven by
+//! VISA libraries such as NI-VISA or pyvisa.
+//!
+//! The class handles the USBTMC protocol itself:
+//!
+//! - the bulk message headers of `DEV_DEP_MSG_OUT`, `REQUEST_DEV_DEP_MSG_IN` and the USB488
+//!   `TRIGGER` message,
+//! - the `INITIATE_ABORT_BULK_OUT`, `INITIATE_ABORT_BULK_IN`, `INITIATE_CLEAR` and
+//!   `GET_CAPABILITIES` requests, and their status checks,
+//! - the USB488 `READ_STATUS_BYTE` request, and service requests (SRQ), on the interrupt endpoint.
+//!
+//! Messages, usually SCPI commands, are passed to an [`Instrument`], which also writes the
+//! responses to queries. The status byte is set with [`StatusByte`].
+
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::signal::Signal;
+
+use crate::control::{InResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointAddress, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`, or 0 to use the interface class.
+pub const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+
+const USBTMC_SUBCLASS: u8 = 0x03;
+const USBTMC_PROTOCOL_USB488: u8 = 0x01;
+
+// Class-specific requests [USBTMC 4.2.1, USB488 4.3]
+const REQ_INITIATE_ABORT_BULK_OUT: u8 = 1;
+const REQ_CHECK_ABORT_BULK_OUT_STATUS: u8 = 2;
+const REQ_INITIATE_ABORT_BULK_IN: u8 = 3;
+const REQ_CHECK_ABORT_BULK_IN_STATUS: u8 = 4;
+const REQ_INITIATE_CLEAR: u8 = 5;
+const REQ_CHECK_CLEAR_STATUS: u8 = 6;
+const REQ_GET_CAPABILITIES: u8 = 7;
+const REQ_READ_STATUS_BYTE: u8 = 128;
+
+// Status values of the request responses.
+const STATUS_SUCCESS: u8 = 0x01;
+const STATUS_PENDING: u8 = 0x02;
+const STATUS_FAILED: u8 = 0x80;
+const STATUS_TRANSFER_NOT_IN_PROGRESS: u8 = 0x81;
+const STATUS_INTERRUPT_IN_BUSY: u8 = 0x20;
+
+// Bulk message IDs [USBTMC 3.2, USB488 3.2]
+const DEV_DEP_MSG_OUT: u8 = 1;
+const REQUEST_DEV_DEP_MSG_IN: u8 = 2;
+const DEV_DEP_MSG_IN: u8 = 2;
+const TRIGGER: u8 = 128;
+
+/// `bmTransferAttributes` bit marking the last transfer of a message.
+const ATTR_EOM: u8 = 0x01;
+
+/// Size of the header of bulk transfers.
+const HEADER_LEN: usize = 12;
+
+// Interrupt IN notifications [USB488 3.4]
+const NOTIFY_READ_STATUS_BYTE: u8 = 0x80;
+const NOTIFY_SRQ: u8 = 0x81;
+
+const BCD_USBTMC: u16 = 0x0100;
+const BCD_USB488: u16 = 0x0100;
+/// USB488 interface capabilities: an IEEE 488.2 interface, accepting `TRIGGER`.
+const USB488_INTERFACE_CAPABILITIES: u8 = 0x05;
+/// USB488 device capabilities: SCPI, service requests (SR1) and device triggers (DT1).
+const USB488_DEVICE_CAPABILITIES: u8 = 0x0D;
+
+/// An instrument, to which the class passes the messages of the host.
+pub trait Instrument {
+    /// Process a message from the host, such as a SCPI command.
+    ///
+    /// Messages are passed whole when they fit in the buffer given to [`UsbtmcClass::run`], and
+    /// in parts otherwise, the last part with `end_of_message` set.
+    async fn write(&mut self, data: &[u8], end_of_message: bool);
+
+    /// Write the response to the last query to `buf`, waiting for it if needed.
+    ///
+    /// Returns the length of the response, and whether it ends the message. Longer responses are
+    /// read in parts, as the host requests them. The future is dropped if the host aborts the
+    /// transfer.
+    async fn read(&mut self, buf: &mut [u8]) -> (usize, bool);
+
+    /// Handle a device trigger, the USB488 equivalent of the IEEE 488.1 GET message.
+    async fn trigger(&mut self) {}
+
+    /// Handle a device clear: the class discarded the pending input, and the instrument must
+    /// discard pending output.
+    async fn clear(&mut self) {}
+}
+
+/// Bulk transfer in progress.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Activity {
+    Idle,
+    /// A `DEV_DEP_MSG_OUT` transfer with this tag.
+    Out(u8),
+    /// A `DEV_DEP_MSG_IN` transfer with this tag.
+    In(u8),
+}
+
+/// Abort requested by the host.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Abort {
+    BulkOut,
+    BulkIn,
+    Clear,
+}
+
+#[derive(Copy, Clone)]
+struct TransferState {
+    activity: Activity,
+    /// Bytes received by the current, or last aborted, OUT transfer.
+    received: u32,
+    /// Bytes sent by the current, or last aborted, IN transfer.
+    sent: u32,
+    /// Whether an abort or a clear is in progress.
+    aborting: bool,
+}
+
+impl TransferState {
+    const IDLE: Self = Self {
+        activity: Activity::Idle,
+        received: 0,
+        sent: 0,
+        aborting: false,
+    };
+}
+
+/// Internal state for the USBTMC class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared {
+                transfer: CriticalSectionMutex::new(Cell::new(TransferState::IDLE)),
+                abort: Signal::new(),
+                status_byte: AtomicU8::new(0),
+                interrupt_busy: AtomicBool::new(false),
+                status_request: Signal::new(),
+                service_request: Signal::new(),
+            },
+        }
+    }
+}
+
+/// Shared data between Control, StatusByte and UsbtmcClass
+struct ControlShared {
+    transfer: CriticalSectionMutex<Cell<TransferState>>,
+    abort: Signal<CriticalSectionRawMutex, Abort>,
+    status_byte: AtomicU8,
+    /// Whether a `READ_STATUS_BYTE` notification is waiting to be sent.
+    interrupt_busy: AtomicBool,
+    /// Tag of the `READ_STATUS_BYTE` request to notify.
+    status_request: Signal<CriticalSectionRawMutex, u8>,
+    /// Status byte of the service request to notify.
+    service_request: Signal<CriticalSectionRawMutex, u8>,
+}
+
+impl ControlShared {
+    fn transfer(&self) -> TransferState {
+        self.transfer.lock(Cell::get)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut TransferState)) {
+        self.transfer.lock(|t| {
+            let mut state = t.get();
+            f(&mut state);
+            t.set(state);
+        })
+    }
+}
+
+struct Control<'a> {
+    iface: InterfaceNumber,
+    bulk_out: EndpointAddress,
+    bulk_in: EndpointAddress,
+    shared: &'a ControlShared,
+}
+
+impl<'a> Control<'a> {
+    /// Start aborting the transfer with `tag` on the endpoint of `abort`, if it is in progress.
+    ///
+    /// Returns the status, and the tag of the transfer in progress.
+    fn initiate_abort(&mut self, tag: u8, abort: Abort) -> (u8, u8) {
+        let mut result = (STATUS_TRANSFER_NOT_IN_PROGRESS, tag);
+        self.shared.update(|t| match (t.activity, abort) {
+            (Activity::Out(current), Abort::BulkOut) | (Activity::In(current), Abort::BulkIn) => {
+                if current == tag {
+                    t.aborting = true;
+                    result = (STATUS_SUCCESS, tag);
+                } else {
+                    result = (STATUS_FAILED, current);
+                }
+            }
+            _ => {}
+        });
+        if result.0 == STATUS_SUCCESS {
+            debug!("USBTMC abort {:?}", abort);
+            self.shared.abort.signal(abort);
+        }
+        result
+    }
+
+    fn check_status(&self) -> (u8, TransferState) {
+        let transfer = self.shared.transfer();
+        let status = match transfer.aborting {
+            true => STATUS_PENDING,
+            false => STATUS_SUCCESS,
+        };
+        (status, transfer)
+    }
+
+    fn interface_request<'r>(&mut self, req: Request, buf: &'r mut [u8]) -> InResponse<'r> {
+        match req.request {
+            REQ_INITIATE_CLEAR if req.length >= 1 => {
+                debug!("USBTMC clear");
+                self.shared.update(|t| t.aborting = true);
+                self.shared.abort.signal(Abort::Clear);
+                buf[0] = STATUS_SUCCESS;
+                InResponse::Accepted(&buf[..1])
+            }
+            REQ_CHECK_CLEAR_STATUS if req.length >= 2 => {
+                // bmClear: the bulk IN FIFO is always empty.
+                buf[..2].copy_from_slice(&[self.check_status().0, 0]);
+                InResponse::Accepted(&buf[..2])
+            }
+            REQ_GET_CAPABILITIES if req.length >= 0x18 => {
+                buf[..0x18].fill(0);
+                buf[0] = STATUS_SUCCESS;
+                buf[2..4].copy_from_slice(&BCD_USBTMC.to_le_bytes());
+                // USBTMC interface and device capabilities: none of indicator pulse, talk-only,
+                // listen-only or termination characters.
+                buf[4] = 0x00;
+                buf[5] = 0x00;
+                buf[12..14].copy_from_slice(&BCD_USB488.to_le_bytes());
+                buf[14] = USB488_INTERFACE_CAPABILITIES;
+                buf[15] = USB488_DEVICE_CAPABILITIES;
+                InResponse::Accepted(&buf[..0x18])
+            }
+            REQ_READ_STATUS_BYTE if req.length >= 3 => {
+                let tag = req.value as u8;
+                let status = if !(2..=127).contains(&tag) {
+                    STATUS_FAILED
+                } else if self.shared.interrupt_busy.load(Ordering::Relaxed) {
+                    STATUS_INTERRUPT_IN_BUSY
+                } else {
+                    // The status byte is sent on the interrupt endpoint.
+                    self.shared.interrupt_busy.store(true, Ordering::Relaxed);
+                    self.shared.status_request.signal(tag);
+                    STATUS_SUCCESS
+                };
+                buf[..3].copy_from_slice(&[status, tag, 0]);
+                InResponse::Accepted(&buf[..3])
+            }
+            _ => InResponse::Rejected,
+        }
+    }
+
+    fn endpoint_request<'r>(&mut self, req: Request, buf: &'r mut [u8]) -> InResponse<'r> {
+        let ep = req.index as u8;
+        let tag = req.value as u8;
+        match req.request {
+            REQ_INITIATE_ABORT_BULK_OUT if ep == u8::from(self.bulk_out) && req.length >= 2 => {
+                let (status, tag) = self.initiate_abort(tag, Abort::BulkOut);
+                buf[..2].copy_from_slice(&[status, tag]);
+                InResponse::Accepted(&buf[..2])
+            }
+            REQ_CHECK_ABORT_BULK_OUT_STATUS if ep == u8::from(self.bulk_out) && req.length >= 8 => {
+                let (status, transfer) = self.check_status();
+                buf[..4].copy_from_slice(&[status, 0, 0, 0]);
+                buf[4..8].copy_from_slice(&transfer.received.to_le_bytes()); // NBYTES_RXD
+                InResponse::Accepted(&buf[..8])
+            }
+            REQ_INITIATE_ABORT_BULK_IN if ep == u8::from(self.bulk_in) && req.length >= 2 => {
+                let (status, tag) = self.initiate_abort(tag, Abort::BulkIn);
+                buf[..2].copy_from_slice(&[status, tag]);
+                InResponse::Accepted(&buf[..2])
+            }
+            REQ_CHECK_ABORT_BULK_IN_STATUS if ep == u8::from(self.bulk_in) && req.length >= 8 => {
+                let (status, transfer) = self.check_status();
+                // bmAbortBulkIn: the bulk IN FIFO is always empty.
+                buf[..4].copy_from_slice(&[status, 0, 0, 0]);
+                buf[4..8].copy_from_slice(&transfer.sent.to_le_bytes()); // NBYTES_TXD
+                InResponse::Accepted(&buf[..8])
+            }
+            _ => InResponse::Rejected,
+        }
+    }
+}
+
+impl<'a> Handler for Control<'a> {
+    fn reset(&mut self) {
+        self.shared.transfer.lock(|t| t.set(TransferState::IDLE));
+        self.shared.interrupt_busy.store(false, Ordering::Relaxed);
+    }
+
+    fn control_in<'r>(&'r mut self, req: Request, buf: &'r mut [u8]) -> Option<InResponse<'r>> {
+        if req.request_type != RequestType::Class {
+            return None;
+        }
+        match req.recipient {
+            Recipient::Interface if req.index == self.iface.0 as u16 => Some(self.interface_request(req, buf)),
+            Recipient::Endpoint
+                if req.index as u8 == u8::from(self.bulk_out) || req.index as u8 == u8::from(self.bulk_in) =>
+            {
+                Some(self.endpoint_request(req, buf))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Handle to set the status byte of a [`UsbtmcClass`], and to request service, from any task.
+#[derive(Copy, Clone)]
+pub struct StatusByte<'d> {
+    shared: &'d ControlShared,
+}
+
+impl<'d> StatusByte<'d> {
+    /// The status byte, returned by the USB488 `READ_STATUS_BYTE` request.
+    pub fn get(&self) -> u8 {
+        self.shared.status_byte.load(Ordering::Relaxed)
+    }
+
+    /// Set the status byte, returned by the USB488 `READ_STATUS_BYTE` request.
+    pub fn set(&self, status_byte: u8) {
+        self.shared.status_byte.store(status_byte, Ordering::Relaxed);
+    }
+
+    /// Set the status byte and request service from the host, as the IEEE 488.1 SRQ line does.
+    ///
+    /// The request service bit (RQS, bit 6) of `status_byte` should be set.
+    pub fn request_service(&self, status_byte: u8) {
+        self.set(status_byte);
+        self.shared.service_request.signal(status_byte);
+    }
+}
+
+/// Header of a bulk transfer.
+struct Header {
+    msg_id: u8,
+    tag: u8,
+    transfer_size: usize,
+    attributes: u8,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (msg_id, tag, tag_inverse) = (data[0], data[1], data[2]);
+        if tag == 0 || tag != !tag_inverse {
+            return None;
+        }
+        Some(Self {
+            msg_id,
+            tag,
+            transfer_size: u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize,
+            attributes: data[8],
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut data = [0; HEADER_LEN];
+        data[0] = self.msg_id;
+        data[1] = self.tag;
+        data[2] = !self.tag;
+        data[4..8].copy_from_slice(&(self.transfer_size as u32).to_le_bytes());
+        data[8] = self.attributes;
+        data
+    }
+}
+
+/// Length of a bulk transfer with `len` bytes of data, aligned to 4 bytes.
+fn transfer_len(len: usize) -> usize {
+    (HEADER_LEN + len + 3) & !3
+}
+
+/// USB Test and Measurement Class with the USB488 subclass.
+pub struct UsbtmcClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    notify_ep: D::EndpointIn,
+    shared: &'d ControlShared,
+    /// Length of the message being received, at the start of the buffer.
+    len: usize,
+}
+
+impl<'d, D: Driver<'d>> UsbtmcClass<'d, D> {
+    /// Creates a new UsbtmcClass with the provided `max_packet_size` in bytes. For full-speed
+    /// devices, `max_packet_size` has to be one of 8, 16, 32 or 64.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, max_packet_size: u16) -> Self {
+        assert!(max_packet_size as usize >= HEADER_LEN);
+
+        let mut func = builder.function(USB_CLASS_APPLICATION_SPECIFIC, USBTMC_SUBCLASS, USBTMC_PROTOCOL_USB488);
+        let mut iface = func.interface();
+        let iface_num = iface.interface_number();
+        let mut alt = iface.alt_setting(
+            USB_CLASS_APPLICATION_SPECIFIC,
+            USBTMC_SUBCLASS,
+            USBTMC_PROTOCOL_USB488,
+            None,
+        );
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        let notify_ep = alt.endpoint_interrupt_in(None, 2, 1);
+        drop(func);
+
+        let control = state.control.write(Control {
+            iface: iface_num,
+            bulk_out: read_ep.info().addr,
+            bulk_in: write_ep.info().addr,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        UsbtmcClass {
+            read_ep,
+            write_ep,
+            notify_ep,
+            shared: &state.shared,
+            len: 0,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Returns a handle to set the status byte, and to request service.
+    pub fn status_byte(&self) -> StatusByte<'d> {
+        StatusByte { shared: self.shared }
+    }
+
+    /// Serve the host, passing messages to `instrument`.
+    ///
+    /// `buf` holds the messages being received and the responses being sent. It must be at least
+    /// twice as long as the maximum packet size.
+    pub async fn run<I: Instrument>(&mut self, instrument: &mut I, buf: &mut [u8]) -> ! {
+        assert!(buf.len() >= 2 * self.max_packet_size() as usize);
+        let shared = self.shared;
+        loop {
+            self.read_ep.wait_enabled().await;
+            let e = match select(
+                Self::serve(
+                    &mut self.read_ep,
+                    &mut self.write_ep,
+                    shared,
+                    &mut self.len,
+                    instrument,
+                    buf,
+                ),
+                notify::<D>(&mut self.notify_ep, shared),
+            )
+            .await
+            {
+                Either::First(e) | Either::Second(e) => e,
+            };
+            debug!("USBTMC transfer ended: {:?}", e);
+            self.len = 0;
+            shared.transfer.lock(|t| t.set(TransferState::IDLE));
+        }
+    }
+
+    async fn serve<I: Instrument>(
+        read_ep: &mut D::EndpointOut,
+        write_ep: &mut D::EndpointIn,
+        shared: &ControlShared,
+        len: &mut usize,
+        instrument: &mut I,
+        buf: &mut [u8],
+    ) -> EndpointError {
+        let mut bulk = Bulk::<D> {
+            read_ep,
+            write_ep,
+            shared,
+            len,
+        };
+        loop {
+            let abort = match select(bulk.transfer(instrument, buf), shared.abort.wait()).await {
+                Either::First(Ok(())) => continue,
+                Either::First(Err(e)) => return e,
+                Either::Second(abort) => abort,
+            };
+
+            // The message being received is incomplete.
+            *bulk.len = 0;
+            let transfer = shared.transfer();
+            if abort == Abort::BulkIn && transfer.sent > 0 {
+                // Terminate the transfer with a short packet.
+                if let Err(e) = bulk.write_ep.write(&[]).await {
+                    return e;
+                }
+            }
+            if abort == Abort::Clear {
+                instrument.clear().await;
+            }
+            shared.update(|t| {
+                t.activity = Activity::Idle;
+                t.aborting = false;
+            });
+        }
+    }
+}
+
+/// Send the status byte and service requests on the interrupt endpoint.
+async fn notify<'d, D: Driver<'d>>(ep: &mut D::EndpointIn, shared: &ControlShared) -> EndpointError {
+    loop {
+        let result = match select(shared.status_request.wait(), shared.service_request.wait()).await {
+            Either::First(tag) => {
+                let status_byte = shared.status_byte.load(Ordering::Relaxed);
+                let result = ep.write(&[NOTIFY_READ_STATUS_BYTE | tag, status_byte]).await;
+                shared.interrupt_busy.store(false, Ordering::Relaxed);
+                result
+            }
+            Either::Second(status_byte) => ep.write(&[NOTIFY_SRQ, status_byte]).await,
+        };
+        if let Err(e) = result {
+            return e;
+        }
+    }
+}
+
+/// The bulk endpoints, and the message being received.
+struct Bulk<'a, 'd, D: Driver<'d>> {
+    read_ep: &'a mut D::EndpointOut,
+    write_ep: &'a mut D::EndpointIn,
+    shared: &'a ControlShared,
+    len: &'a mut usize,
+}
+
+impl<'a, 'd, D: Driver<'d>> Bulk<'a, 'd, D> {
+    /// Serve a bulk OUT transfer.
+    async fn transfer<I: Instrument>(&mut self, instrument: &mut I, buf: &mut [u8]) -> Result<(), EndpointError> {
+        let mps = self.read_ep.info().max_packet_size as usize;
+        // The transfer is read after the part of the message already received.
+        self.make_room(instrument, buf, mps).await;
+        let start = *self.len;
+        let n = self.read_ep.read(&mut buf[start..start + mps]).await?;
+
+        let Some(header) = (n >= HEADER_LEN).then(|| Header::parse(&buf[start..])).flatten() else {
+            warn!("Invalid USBTMC header");
+            return Ok(());
+        };
+        trace!(
+            "USBTMC message {}, tag {}, {} bytes",
+            header.msg_id,
+            header.tag,
+            header.transfer_size
+        );
+
+        match header.msg_id {
+            DEV_DEP_MSG_OUT => self.receive(instrument, buf, &header, n).await,
+            REQUEST_DEV_DEP_MSG_IN => {
+                if *self.len > 0 {
+                    warn!("USBTMC query interrupted");
+                    *self.len = 0;
+                }
+                self.send(instrument, buf, &header).await
+            }
+            TRIGGER => {
+                instrument.trigger().await;
+                Ok(())
+            }
+            _ => {
+                warn!("Unsupported USBTMC message {}", header.msg_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Pass the part of the message received so far to the instrument, unless a packet fits after it.
+    async fn make_room<I: Instrument>(&mut self, instrument: &mut I, buf: &[u8], mps: usize) {
+        if buf.len() - *self.len < mps {
+            instrument.write(&buf[..*self.len], false).await;
+            *self.len = 0;
+        }
+    }
+
+    /// Receive the data of a `DEV_DEP_MSG_OUT` transfer, whose first packet is `n` bytes long.
+    async fn receive<I: Instrument>(
+        &mut self,
+        instrument: &mut I,
+        buf: &mut [u8],
+        header: &Header,
+        n: usize,
+    ) -> Result<(), EndpointError> {
+        let mps = self.read_ep.info().max_packet_size as usize;
+        self.shared.update(|t| {
+            t.activity = Activity::Out(header.tag);
+            t.received = n as u32;
+        });
+
+        // The data of the first packet replaces the header.
+        let start = *self.len;
+        let total = transfer_len(header.transfer_size);
+        let mut data_left = header.transfer_size;
+        let data = (n - HEADER_LEN).min(data_left);
+        buf.copy_within(start + HEADER_LEN..start + HEADER_LEN + data, start);
+        *self.len += data;
+        data_left -= data;
+
+        // The transfer ends with a short packet, or once all the data and padding is received.
+        let mut received = n;
+        let mut last = n;
+        while received < total && last == mps {
+            self.make_room(instrument, buf, mps).await;
+            let start = *self.len;
+            last = self.read_ep.read(&mut buf[start..start + mps]).await?;
+            received += last;
+            let data = last.min(data_left);
+            *self.len += data;
+            data_left -= data;
+            self.shared.update(|t| t.received = received as u32);
+        }
+
+        if header.attributes & ATTR_EOM != 0 {
+            instrument.write(&buf[..*self.len], true).await;
+            *self.len = 0;
+        }
+        self.shared.update(|t| t.activity = Activity::Idle);
+        Ok(())
+    }
+
+    /// Send the response to a `REQUEST_DEV_DEP_MSG_IN` transfer.
+    async fn send<I: Instrument>(
+        &mut self,
+        instrument: &mut I,
+        buf: &mut [u8],
+        header: &Header,
+    ) -> Result<(), EndpointError> {
+        let mps = self.write_ep.info().max_packet_size as usize;
+        self.shared.update(|t| {
+            t.activity = Activity::In(header.tag);
+            t.sent = 0;
+        });
+
+        let max = header.transfer_size.min(buf.len() - HEADER_LEN - 3);
+        let (len, end_of_message) = instrument.read(&mut buf[HEADER_LEN..HEADER_LEN + max]).await;
+        let len = len.min(max);
+
+        let response = Header {
+            msg_id: DEV_DEP_MSG_IN,
+            tag: header.tag,
+            transfer_size: len,
+            attributes: if end_of_message { ATTR_EOM } else { 0 },
+        };
+        buf[..HEADER_LEN].copy_from_slice(&response.to_bytes());
+        let total = transfer_len(len);
+        buf[HEADER_LEN + len..total].fill(0);
+
+        for packet in buf[..total].chunks(mps) {
+            self.write_ep.write(packet).await?;
+            self.shared.update(|t| t.sent += packet.len() as u32);
+        }
+        // A full last packet ends the transfer only if the host expects no more data.
+        if total % mps == 0 && total < transfer_len(header.transfer_size) {
+            self.write_ep.write(&[]).await?;
+        }
+        self.shared.update(|t| t.activity = Activity::Idle);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::Direction;
+
+    fn control(shared: &ControlShared) -> Control<'_> {
+        Control {
+            iface: InterfaceNumber(0),
+            bulk_out: EndpointAddress::from_parts(1, Direction::Out),
+            bulk_in: EndpointAddress::from_parts(1, Direction::In),
+            shared,
+        }
+    }
+
+    fn interface_request(request: u8, value: u16, length: u16) -> Request {
+        Request {
+            direction: Direction::In,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value,
+            index: 0,
+            length,
+        }
+    }
+
+    #[test]
+    fn bulk_out_header() {
+        let data = [DEV_DEP_MSG_OUT, 5, !5, 0, 10, 1, 0, 0, ATTR_EOM, 0, 0, 0];
+        let header = Header::parse(&data).unwrap();
+        assert_eq!(
+            (header.msg_id, header.tag, header.transfer_size, header.attributes),
+            (DEV_DEP_MSG_OUT, 5, 266, ATTR_EOM)
+        );
+
+        let header = Header::parse(&[REQUEST_DEV_DEP_MSG_IN, 0xfe, 0x01, 0, 0, 2, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(
+            (
+                header.msg_id,
+                header.tag,
+                header.transfer_size,
+                header.attributes & ATTR_EOM
+            ),
+            (REQUEST_DEV_DEP_MSG_IN, 0xfe, 512, 0)
+        );
+
+        // bTagInverse must be the complement of bTag, and bTag 0 is invalid.
+        assert!(Header::parse(&[DEV_DEP_MSG_OUT, 5, 5, 0, 10, 0, 0, 0, 1, 0, 0, 0]).is_none());
+        assert!(Header::parse(&[DEV_DEP_MSG_OUT, 0, 0xff, 0, 10, 0, 0, 0, 1, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn bulk_in_header() {
+        let header = Header {
+            msg_id: DEV_DEP_MSG_IN,
+            tag: 0x42,
+            transfer_size: 0x0102_0304,
+            attributes: ATTR_EOM,
+        };
+        let data = header.to_bytes();
+        assert_eq!(data, [DEV_DEP_MSG_IN, 0x42, 0xbd, 0, 4, 3, 2, 1, ATTR_EOM, 0, 0, 0]);
+        let parsed = Header::parse(&data).unwrap();
+        assert_eq!((parsed.tag, parsed.transfer_size), (0x42, 0x0102_0304));
+    }
+
+    #[test]
+    fn padding() {
+        // The header and the data are padded to a multiple of 4 bytes.
+        assert_eq!(transfer_len(0), 12);
+        assert_eq!(transfer_len(1), 16);
+        assert_eq!(transfer_len(4), 16);
+        assert_eq!(transfer_len(5), 20);
+        assert_eq!(transfer_len(52), 64);
+    }
+
+    #[test]
+    fn get_capabilities() {
+        let state = State::new();
+        let mut control = control(&state.shared);
+        let mut buf = [0xff; 64];
+
+        let response = control.interface_request(interface_request(REQ_GET_CAPABILITIES, 0, 0x18), &mut buf);
+        let InResponse::Accepted(data) = response else {
+            panic!("rejected");
+        };
+        assert_eq!(data.len(), 0x18);
+        assert_eq!(data[..4], [STATUS_SUCCESS, 0, 0x00, 0x01]); // bcdUSBTMC 1.00
+        assert_eq!(
+            data[12..16],
+            [0x00, 0x01, USB488_INTERFACE_CAPABILITIES, USB488_DEVICE_CAPABILITIES]
+        );
+        assert!(data[4..12].iter().chain(&data[16..]).all(|&b| b == 0));
+
+        let response = control.interface_request(interface_request(REQ_GET_CAPABILITIES, 0, 0x17), &mut buf);
+        assert_eq!(response, InResponse::Rejected);
+    }
+
+    #[test]
+    fn read_status_byte() {
+        let state = State::new();
+        let mut control = control(&state.shared);
+        let mut buf = [0; 64];
+
+        // bTag must be between 2 and 127.
+        let response = control.interface_request(interface_request(REQ_READ_STATUS_BYTE, 1, 3), &mut buf);
+        assert_eq!(response, InResponse::Accepted(&[STATUS_FAILED, 1, 0]));
+        assert_eq!(state.shared.status_request.try_take(), None);
+
+        // The status byte is sent on the interrupt endpoint.
+        let response = control.interface_request(interface_request(REQ_READ_STATUS_BYTE, 2, 3), &mut buf);
+        assert_eq!(response, InResponse::Accepted(&[STATUS_SUCCESS, 2, 0]));
+        assert_eq!(state.shared.status_request.try_take(), Some(2));
+
+        // Until it is, further requests are rejected as busy.
+        let response = control.interface_request(interface_request(REQ_READ_STATUS_BYTE, 3, 3), &mut buf);
+        assert_eq!(response, InResponse::Accepted(&[STATUS_INTERRUPT_IN_BUSY, 3, 0]));
+        assert_eq!(state.shared.status_request.try_take(), None);
+
+        control.reset();
+        let response = control.interface_request(interface_request(REQ_READ_STATUS_BYTE, 4, 3), &mut buf);
+        assert_eq!(response, InResponse::Accepted(&[STATUS_SUCCESS, 4, 0]));
+
+        let response = control.interface_request(interface_request(REQ_READ_STATUS_BYTE, 5, 2), &mut buf);
+        assert_eq!(response, InResponse::Rejected);
+    }
+}
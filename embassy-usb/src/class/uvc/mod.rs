@@ -0,0 +1,1030 @@
+//! USB Video Class 1.1, for cameras and other video sources such as framebuffers.
+//!
+//! The class presents a camera with one video stream, in MJPEG or uncompressed YUY2 formats,
+//! each with a list of frame sizes and frame rates. The host selects them with the probe and
+//! commit controls, and the device streams frames in its choice over a bulk or an isochronous
+//! endpoint, with a payload header in each packet.
+//!
+//! UVC 1.1 is supported natively by Linux, macOS and Windows, and works with standard webcam
+//! applications.
+
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::signal::Signal;
+
+use self::class_codes::*;
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Driver, Endpoint, EndpointIn, EndpointType};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+mod class_codes;
+
+/// The maximum number of formats of a stream.
+pub const MAX_FORMAT_COUNT: usize = 8;
+
+/// The maximum number of discrete frame intervals of a frame size.
+pub const MAX_FRAME_INTERVAL_COUNT: usize = 16;
+
+// Arbitrary unique identifiers for the terminals: camera -> output terminal (sends the video stream).
+const CAMERA_TERMINAL_ID: u8 = 0x01;
+const OUTPUT_TERMINAL_ID: u8 = 0x02;
+
+/// Length of the probe and commit controls of UVC 1.1.
+const PROBE_LEN: usize = 34;
+/// Length of the probe and commit controls of UVC 1.0, which some hosts still use.
+const PROBE_LEN_UVC10: usize = 26;
+
+/// Length of the payload header of each packet, without presentation time stamp or clock reference.
+const PAYLOAD_HEADER_LEN: usize = 2;
+
+/// Frequency of the device clock. No time stamps are sent, so this is only informative.
+const CLOCK_FREQUENCY_HZ: u32 = 48_000_000;
+
+/// Frame intervals are in units of 100 ns.
+const FRAME_INTERVALS_PER_SECOND: u64 = 10_000_000;
+
+/// Format of the video frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Format {
+    /// Motion JPEG: each frame is a JPEG image.
+    Mjpeg,
+    /// Uncompressed YUV 4:2:2, with pixels packed as Y0 U0 Y1 V0.
+    Yuy2,
+}
+
+/// A frame size, and the frame rates at which it can be streamed.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame<'d> {
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// The supported frame intervals in units of 100 ns (up to 16), for example 333_333 for 30
+    /// frames per second. The first one is the default.
+    pub intervals: &'d [u32],
+}
+
+impl<'d> Frame<'d> {
+    /// Upper bound of the size of a frame in bytes, that of an uncompressed frame.
+    fn max_frame_size(&self) -> u32 {
+        self.width as u32 * self.height as u32 * 2
+    }
+
+    /// Bit rate at the frame interval `interval`.
+    fn bit_rate(&self, interval: u32) -> u32 {
+        let bits = self.max_frame_size() as u64 * 8;
+        (bits * FRAME_INTERVALS_PER_SECOND / interval.max(1) as u64).min(u32::MAX as u64) as u32
+    }
+}
+
+/// A video format and its frame sizes.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VideoFormat<'d> {
+    /// Format of the frames.
+    pub format: Format,
+    /// The supported frame sizes. The first one is the default.
+    pub frames: &'d [Frame<'d>],
+}
+
+/// Transport of the video stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Transport {
+    /// A bulk endpoint, which uses the bandwidth left by other devices. The host starts the stream
+    /// when it commits its settings.
+    Bulk,
+    /// An isochronous endpoint, with a packet in every (micro)frame. The host starts the stream by
+    /// selecting the alternate setting with the endpoint.
+    Isochronous,
+}
+
+/// Configuration of the video stream.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config<'d> {
+    /// The supported formats (up to 8). The first one is the default.
+    pub formats: &'d [VideoFormat<'d>],
+    /// Transport of the stream.
+    pub transport: Transport,
+}
+
+/// Stream settings, selected by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamSettings {
+    /// Index of the format in [`Config::formats`].
+    pub format_index: usize,
+    /// Index of the frame size in [`VideoFormat::frames`].
+    pub frame_index: usize,
+    /// Format of the frames.
+    pub format: Format,
+    /// Width of the frames in pixels.
+    pub width: u16,
+    /// Height of the frames in pixels.
+    pub height: u16,
+    /// Interval between frames in units of 100 ns.
+    pub frame_interval: u32,
+}
+
+/// Error of a video stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The host stopped the stream.
+    Stopped,
+    /// The host committed new stream settings.
+    Renegotiated,
+}
+
+/// Video probe and commit control [UVC 4.3.1.1].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Probe {
+    hint: u16,
+    format_index: u8,
+    frame_index: u8,
+    frame_interval: u32,
+    key_frame_rate: u16,
+    p_frame_rate: u16,
+    comp_quality: u16,
+    comp_window_size: u16,
+    delay: u16,
+    max_video_frame_size: u32,
+    max_payload_transfer_size: u32,
+}
+
+impl Probe {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < PROBE_LEN_UVC10 {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Some(Self {
+            hint: u16_at(0),
+            format_index: data[2],
+            frame_index: data[3],
+            frame_interval: u32_at(4),
+            key_frame_rate: u16_at(8),
+            p_frame_rate: u16_at(10),
+            comp_quality: u16_at(12),
+            comp_window_size: u16_at(14),
+            delay: u16_at(16),
+            max_video_frame_size: u32_at(18),
+            max_payload_transfer_size: u32_at(22),
+        })
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.hint.to_le_bytes());
+        buf[2] = self.format_index;
+        buf[3] = self.frame_index;
+        buf[4..8].copy_from_slice(&self.frame_interval.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.key_frame_rate.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.p_frame_rate.to_le_bytes());
+        buf[12..14].copy_from_slice(&self.comp_quality.to_le_bytes());
+        buf[14..16].copy_from_slice(&self.comp_window_size.to_le_bytes());
+        buf[16..18].copy_from_slice(&self.delay.to_le_bytes());
+        buf[18..22].copy_from_slice(&self.max_video_frame_size.to_le_bytes());
+        buf[22..26].copy_from_slice(&self.max_payload_transfer_size.to_le_bytes());
+        buf[26..30].copy_from_slice(&CLOCK_FREQUENCY_HZ.to_le_bytes());
+        buf[30] = 0x00; // bmFramingInfo
+        buf[31] = 0x00; // bPreferedVersion
+        buf[32] = 0x00; // bMinVersion
+        buf[33] = 0x00; // bMaxVersion
+    }
+}
+
+/// The settings closest to `request` that the device supports.
+fn negotiate(formats: &[VideoFormat], request: &Probe, max_payload_size: u32) -> Probe {
+    let format_index = match request.format_index as usize {
+        i if (1..=formats.len()).contains(&i) => i,
+        _ => 1,
+    };
+    let format = &formats[format_index - 1];
+    let frame_index = match request.frame_index as usize {
+        i if (1..=format.frames.len()).contains(&i) => i,
+        _ => 1,
+    };
+    let frame = &format.frames[frame_index - 1];
+    let default_interval = frame.intervals[0];
+    let frame_interval = match request.frame_interval {
+        0 => default_interval,
+        requested => frame
+            .intervals
+            .iter()
+            .copied()
+            .min_by_key(|interval| interval.abs_diff(requested))
+            .unwrap_or(default_interval),
+    };
+
+    Probe {
+        hint: request.hint,
+        format_index: format_index as u8,
+        frame_index: frame_index as u8,
+        frame_interval,
+        max_video_frame_size: frame.max_frame_size(),
+        max_payload_transfer_size: max_payload_size,
+        ..Probe::default()
+    }
+}
+
+/// The stream settings of negotiated `probe`.
+fn settings(formats: &[VideoFormat], probe: &Probe) -> StreamSettings {
+    let format_index = probe.format_index as usize - 1;
+    let frame_index = probe.frame_index as usize - 1;
+    let format = &formats[format_index];
+    let frame = &format.frames[frame_index];
+    StreamSettings {
+        format_index,
+        frame_index,
+        format: format.format,
+        width: frame.width,
+        height: frame.height,
+        frame_interval: probe.frame_interval,
+    }
+}
+
+/// Internal state for the video class.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: SharedControl,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: SharedControl {
+                stream: CriticalSectionMutex::new(Cell::new(StreamState {
+                    commit: None,
+                    streaming: false,
+                })),
+                changed: Signal::new(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct StreamState {
+    /// Settings committed by the host.
+    commit: Option<Probe>,
+    /// Whether the host started the stream.
+    streaming: bool,
+}
+
+/// Shared data between Control and VideoStream
+struct SharedControl {
+    stream: CriticalSectionMutex<Cell<StreamState>>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl SharedControl {
+    fn get(&self) -> StreamState {
+        self.stream.lock(Cell::get)
+    }
+
+    fn set(&self, state: StreamState) {
+        self.stream.lock(|s| s.set(state));
+        self.changed.signal(());
+    }
+}
+
+struct Control<'d> {
+    control_interface: InterfaceNumber,
+    streaming_interface: InterfaceNumber,
+    formats: &'d [VideoFormat<'d>],
+    transport: Transport,
+    max_payload_size: u32,
+    probe: Probe,
+    shared: &'d SharedControl,
+}
+
+impl<'d> Control<'d> {
+    fn default_probe(&self) -> Probe {
+        negotiate(self.formats, &Probe::default(), self.max_payload_size)
+    }
+
+    fn streaming_set_request(&mut self, req: Request, data: &[u8]) -> OutResponse {
+        let selector = (req.value >> 8) as u8;
+        let Some(request) = Probe::parse(data).filter(|_| req.request == SET_CUR) else {
+            return OutResponse::Rejected;
+        };
+        let probe = negotiate(self.formats, &request, self.max_payload_size);
+
+        match selector {
+            VS_PROBE_CONTROL => {
+                trace!(
+                    "Video probe: format {}, frame {}, interval {}",
+                    probe.format_index,
+                    probe.frame_index,
+                    probe.frame_interval
+                );
+                self.probe = probe;
+            }
+            VS_COMMIT_CONTROL => {
+                debug!(
+                    "Video commit: format {}, frame {}, interval {}",
+                    probe.format_index, probe.frame_index, probe.frame_interval
+                );
+                let state = self.shared.get();
+                self.shared.set(StreamState {
+                    commit: Some(probe),
+                    // Bulk streams start with the commit, isochronous ones with the alternate setting.
+                    streaming: match self.transport {
+                        Transport::Bulk => true,
+                        Transport::Isochronous => state.streaming,
+                    },
+                });
+            }
+            _ => return OutResponse::Rejected,
+        }
+        OutResponse::Accepted
+    }
+
+    fn streaming_get_request<'r>(&self, req: Request, buf: &'r mut [u8]) -> InResponse<'r> {
+        let selector = (req.value >> 8) as u8;
+        if selector != VS_PROBE_CONTROL && selector != VS_COMMIT_CONTROL {
+            return InResponse::Rejected;
+        }
+
+        let probe = match req.request {
+            GET_INFO => {
+                buf[0] = INFO_GET_SUPPORTED | INFO_SET_SUPPORTED;
+                return InResponse::Accepted(&buf[..1]);
+            }
+            GET_LEN => {
+                buf[..2].copy_from_slice(&(PROBE_LEN as u16).to_le_bytes());
+                return InResponse::Accepted(&buf[..2]);
+            }
+            GET_CUR if selector == VS_PROBE_CONTROL => self.probe,
+            GET_CUR => self.shared.get().commit.unwrap_or_else(|| self.default_probe()),
+            GET_MIN | GET_MAX | GET_DEF if selector == VS_PROBE_CONTROL => self.default_probe(),
+            _ => return InResponse::Rejected,
+        };
+
+        probe.write(&mut buf[..PROBE_LEN]);
+        InResponse::Accepted(&buf[..PROBE_LEN.min(req.length as usize)])
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface != self.streaming_interface {
+            return;
+        }
+        debug!("Video streaming interface set to alt setting {}", alternate_setting);
+        let state = self.shared.get();
+        // Hosts select the alternate setting 0 to stop bulk streams, and to (re)initialize them.
+        let streaming = match self.transport {
+            Transport::Bulk => false,
+            Transport::Isochronous => alternate_setting == 1 && state.commit.is_some(),
+        };
+        self.shared.set(StreamState { streaming, ..state });
+    }
+
+    fn reset(&mut self) {
+        self.probe = self.default_probe();
+        self.shared.set(StreamState {
+            commit: None,
+            streaming: false,
+        });
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+        // The video control interface has no controls.
+        match req.index {
+            i if i == self.streaming_interface.0 as u16 => Some(self.streaming_set_request(req, data)),
+            i if i & 0xff == self.control_interface.0 as u16 => Some(OutResponse::Rejected),
+            _ => None,
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+        match req.index {
+            i if i == self.streaming_interface.0 as u16 => Some(self.streaming_get_request(req, buf)),
+            i if i & 0xff == self.control_interface.0 as u16 => Some(InResponse::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// Frame descriptor of `frame` [UVC MJPEG 3.1.2, UVC uncompressed 3.1.2], returning its length.
+fn frame_descriptor(buf: &mut [u8], format: Format, index: u8, frame: &Frame) -> usize {
+    let intervals = frame.intervals;
+    let max_interval = intervals.iter().copied().max().unwrap_or(intervals[0]);
+    let min_interval = intervals.iter().copied().min().unwrap_or(intervals[0]);
+
+    buf[0] = match format {
+        Format::Mjpeg => VS_FRAME_MJPEG,
+        Format::Yuy2 => VS_FRAME_UNCOMPRESSED,
+    }; // bDescriptorSubtype
+    buf[1] = index; // bFrameIndex
+    buf[2] = 0x00; // bmCapabilities (no still images, fixed frame rate)
+    buf[3..5].copy_from_slice(&frame.width.to_le_bytes()); // wWidth
+    buf[5..7].copy_from_slice(&frame.height.to_le_bytes()); // wHeight
+    buf[7..11].copy_from_slice(&frame.bit_rate(max_interval).to_le_bytes()); // dwMinBitRate
+    buf[11..15].copy_from_slice(&frame.bit_rate(min_interval).to_le_bytes()); // dwMaxBitRate
+    buf[15..19].copy_from_slice(&frame.max_frame_size().to_le_bytes()); // dwMaxVideoFrameBufferSize
+    buf[19..23].copy_from_slice(&intervals[0].to_le_bytes()); // dwDefaultFrameInterval
+    buf[23] = intervals.len() as u8; // bFrameIntervalType (discrete intervals)
+    for (i, interval) in intervals.iter().enumerate() {
+        buf[24 + 4 * i..28 + 4 * i].copy_from_slice(&interval.to_le_bytes()); // dwFrameInterval
+    }
+    24 + 4 * intervals.len()
+}
+
+/// Format descriptor [UVC MJPEG 3.1.1, UVC uncompressed 3.1.1], returning its length.
+fn format_descriptor(buf: &mut [u8], format: &VideoFormat, index: u8) -> usize {
+    let num_frames = format.frames.len() as u8;
+    match format.format {
+        Format::Mjpeg => {
+            buf[..9].copy_from_slice(&[
+                VS_FORMAT_MJPEG, // bDescriptorSubtype
+                index,           // bFormatIndex
+                num_frames,      // bNumFrameDescriptors
+                0x00,            // bmFlags (no fixed size samples)
+                0x01,            // bDefaultFrameIndex
+                0x00,            // bAspectRatioX
+                0x00,            // bAspectRatioY
+                0x00,            // bmInterlaceFlags (progressive)
+                0x00,            // bCopyProtect
+            ]);
+            9
+        }
+        Format::Yuy2 => {
+            buf[..3].copy_from_slice(&[
+                VS_FORMAT_UNCOMPRESSED, // bDescriptorSubtype
+                index,                  // bFormatIndex
+                num_frames,             // bNumFrameDescriptors
+            ]);
+            buf[3..19].copy_from_slice(&GUID_YUY2); // guidFormat
+            buf[19..25].copy_from_slice(&[
+                16,   // bBitsPerPixel
+                0x01, // bDefaultFrameIndex
+                0x00, // bAspectRatioX
+                0x00, // bAspectRatioY
+                0x00, // bmInterlaceFlags (progressive)
+                0x00, // bCopyProtect
+            ]);
+            25
+        }
+    }
+}
+
+/// Color matching descriptor [UVC 3.9.2.6], with the default BT.709 primaries and SMPTE 170M matrix.
+const COLOR_MATCHING_DESCRIPTOR: [u8; 4] = [
+    VS_COLORFORMAT, // bDescriptorSubtype
+    0x01,           // bColorPrimaries (BT.709, sRGB)
+    0x01,           // bTransferCharacteristics (BT.709)
+    0x04,           // bMatrixCoefficients (SMPTE 170M)
+];
+
+/// A video stream, sending frames to the host in packets of up to `N` bytes.
+///
+/// `N` is the maximum packet size of the streaming endpoint. For full-speed devices, it has to be
+/// one of 8, 16, 32 or 64 for bulk streams, and up to 1023 for isochronous ones.
+pub struct VideoStream<'d, D: Driver<'d>, const N: usize> {
+    ep: D::EndpointIn,
+    formats: &'d [VideoFormat<'d>],
+    shared: &'d SharedControl,
+    fid: bool,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> VideoStream<'d, D, N> {
+    /// Creates a new video stream, with a video control and a video streaming interface.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        let formats = config.formats;
+        assert!(
+            !formats.is_empty() && formats.len() <= MAX_FORMAT_COUNT,
+            "Invalid format count {}.",
+            formats.len()
+        );
+        for format in formats {
+            assert!(!format.frames.is_empty() && format.frames.len() <= u8::MAX as usize);
+            for frame in format.frames {
+                assert!(!frame.intervals.is_empty() && frame.intervals.len() <= MAX_FRAME_INTERVAL_COUNT);
+            }
+        }
+        assert!(N > PAYLOAD_HEADER_LEN && N <= 1024);
+        assert!(
+            builder.control_buf_len() >= PROBE_LEN,
+            "Control buffer too small for the probe control."
+        );
+
+        let mut func = builder.function(CC_VIDEO, SC_VIDEO_INTERFACE_COLLECTION, PC_PROTOCOL_UNDEFINED);
+
+        // Video control interface [UVC 3.7]
+        let mut interface = func.interface();
+        let control_interface = interface.interface_number();
+        // The streaming interface is the next one of the function.
+        let streaming_interface = InterfaceNumber(control_interface.0 + 1);
+        let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOCONTROL, PC_PROTOCOL_UNDEFINED, None);
+
+        let camera_terminal = [
+            VC_INPUT_TERMINAL, // bDescriptorSubtype
+            CAMERA_TERMINAL_ID,
+            ITT_CAMERA as u8,
+            (ITT_CAMERA >> 8) as u8, // wTerminalType
+            0x00,                    // bAssocTerminal (none)
+            0x00,                    // iTerminal (none)
+            0x00,
+            0x00, // wObjectiveFocalLengthMin
+            0x00,
+            0x00, // wObjectiveFocalLengthMax
+            0x00,
+            0x00, // wOcularFocalLength
+            0x03, // bControlSize
+            0x00,
+            0x00,
+            0x00, // bmControls (none)
+        ];
+        let output_terminal = [
+            VC_OUTPUT_TERMINAL, // bDescriptorSubtype
+            OUTPUT_TERMINAL_ID,
+            TT_STREAMING as u8,
+            (TT_STREAMING >> 8) as u8, // wTerminalType
+            0x00,                      // bAssocTerminal (none)
+            CAMERA_TERMINAL_ID,        // bSourceID
+            0x00,                      // iTerminal (none)
+        ];
+
+        const HEADER_LEN: usize = 11;
+        let total_length = (HEADER_LEN + 2 + camera_terminal.len() + 2 + output_terminal.len() + 2) as u16;
+        let mut header = [0; HEADER_LEN];
+        header[0] = VC_HEADER; // bDescriptorSubtype
+        header[1..3].copy_from_slice(&UVC_VERSION.to_le_bytes()); // bcdUVC
+        header[3..5].copy_from_slice(&total_length.to_le_bytes()); // wTotalLength
+        header[5..9].copy_from_slice(&CLOCK_FREQUENCY_HZ.to_le_bytes()); // dwClockFrequency
+        header[9] = 0x01; // bInCollection
+        header[10] = streaming_interface.0; // baInterfaceNr(1)
+
+        alt.descriptor(CS_INTERFACE, &header);
+        alt.descriptor(CS_INTERFACE, &camera_terminal);
+        alt.descriptor(CS_INTERFACE, &output_terminal);
+
+        // Video streaming interface [UVC 3.9]
+        let mut interface = func.interface();
+        assert_eq!(interface.interface_number(), streaming_interface);
+        let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOSTREAMING, PC_PROTOCOL_UNDEFINED, None);
+
+        // The input header holds the address of the endpoint, which is allocated first.
+        let ep = match config.transport {
+            Transport::Bulk => alt.alloc_endpoint_in(EndpointType::Bulk, None, N as u16, 0),
+            Transport::Isochronous => alt.alloc_endpoint_in(EndpointType::Isochronous, None, N as u16, 1),
+        };
+
+        // Each format is followed by its frames and a color matching descriptor.
+        const INPUT_HEADER_LEN: usize = 11;
+        let mut total_length = INPUT_HEADER_LEN + formats.len() + 2;
+        let mut buf = [0; 24 + 4 * MAX_FRAME_INTERVAL_COUNT];
+        for (i, format) in formats.iter().enumerate() {
+            total_length += format_descriptor(&mut buf, format, i as u8 + 1) + 2;
+            for (j, frame) in format.frames.iter().enumerate() {
+                total_length += frame_descriptor(&mut buf, format.format, j as u8 + 1, frame) + 2;
+            }
+            total_length += COLOR_MATCHING_DESCRIPTOR.len() + 2;
+        }
+
+        let mut input_header = [0; INPUT_HEADER_LEN + MAX_FORMAT_COUNT];
+        input_header[0] = VS_INPUT_HEADER; // bDescriptorSubtype
+        input_header[1] = formats.len() as u8; // bNumFormats
+        input_header[2..4].copy_from_slice(&(total_length as u16).to_le_bytes()); // wTotalLength
+        input_header[4] = ep.info().addr.into(); // bEndpointAddress
+        input_header[5] = 0x00; // bmInfo (no dynamic format change)
+        input_header[6] = OUTPUT_TERMINAL_ID; // bTerminalLink
+        input_header[7] = 0x00; // bStillCaptureMethod (none)
+        input_header[8] = 0x00; // bTriggerSupport (none)
+        input_header[9] = 0x00; // bTriggerUsage
+        input_header[10] = 0x01; // bControlSize
+
+        // bmaControls are all zero, no format has controls.
+        alt.descriptor(CS_INTERFACE, &input_header[..INPUT_HEADER_LEN + formats.len()]);
+
+        for (i, format) in formats.iter().enumerate() {
+            let len = format_descriptor(&mut buf, format, i as u8 + 1);
+            alt.descriptor(CS_INTERFACE, &buf[..len]);
+            for (j, frame) in format.frames.iter().enumerate() {
+                let len = frame_descriptor(&mut buf, format.format, j as u8 + 1, frame);
+                alt.descriptor(CS_INTERFACE, &buf[..len]);
+            }
+            alt.descriptor(CS_INTERFACE, &COLOR_MATCHING_DESCRIPTOR);
+        }
+
+        match config.transport {
+            Transport::Bulk => alt.endpoint_descriptor(
+                ep.info(),
+                SynchronizationType::NoSynchronization,
+                UsageType::DataEndpoint,
+                &[],
+            ),
+            Transport::Isochronous => {
+                // The alternate setting 0 has no bandwidth, the stream runs in the alternate setting 1.
+                let mut alt = interface.alt_setting(CC_VIDEO, SC_VIDEOSTREAMING, PC_PROTOCOL_UNDEFINED, None);
+                alt.endpoint_descriptor(
+                    ep.info(),
+                    SynchronizationType::Asynchronous,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+            }
+        }
+        drop(func);
+
+        let mut control = Control {
+            control_interface,
+            streaming_interface,
+            formats,
+            transport: config.transport,
+            max_payload_size: N as u32,
+            probe: Probe::default(),
+            shared: &state.shared,
+        };
+        control.probe = control.default_probe();
+        builder.handler(state.control.write(control));
+
+        Self {
+            ep,
+            formats,
+            shared: &state.shared,
+            fid: false,
+        }
+    }
+
+    /// Waits for the host to start the stream, returning the settings it selected.
+    pub async fn wait_streaming(&mut self) -> StreamSettings {
+        loop {
+            self.shared.changed.reset();
+            let state = self.shared.get();
+            if let (true, Some(commit)) = (state.streaming, state.commit) {
+                match select(self.ep.wait_enabled(), self.shared.changed.wait()).await {
+                    Either::First(()) => return settings(self.formats, &commit),
+                    Either::Second(()) => continue,
+                }
+            }
+            self.shared.changed.wait().await;
+        }
+    }
+
+    /// Starts a new frame, to be written in parts.
+    pub fn begin_frame(&mut self) -> FrameWriter<'_, 'd, D, N> {
+        self.fid = !self.fid;
+        let mut packet = [0; N];
+        packet[0] = PAYLOAD_HEADER_LEN as u8;
+        FrameWriter {
+            stream: self,
+            packet,
+            len: PAYLOAD_HEADER_LEN,
+        }
+    }
+
+    /// Writes a whole frame: a JPEG image, or the pixels of an uncompressed frame.
+    pub async fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut frame = self.begin_frame();
+        frame.write(data).await?;
+        frame.finish().await
+    }
+
+    /// Writes a packet, unless the host stops the stream or changes its settings.
+    async fn write_packet(&mut self, packet: &[u8]) -> Result<(), Error> {
+        match select(self.ep.write(packet), self.shared.changed.wait()).await {
+            Either::First(Ok(())) => Ok(()),
+            Either::First(Err(_)) => Err(Error::Stopped),
+            Either::Second(()) => match self.shared.get().streaming {
+                true => Err(Error::Renegotiated),
+                false => Err(Error::Stopped),
+            },
+        }
+    }
+}
+
+/// Writer of a frame, in parts.
+///
+/// Every packet is a payload, with a header marking the frame it belongs to. Frames dropped
+/// before [`FrameWriter::finish`] are incomplete, and discarded by most hosts.
+pub struct FrameWriter<'a, 'd, D: Driver<'d>, const N: usize> {
+    stream: &'a mut VideoStream<'d, D, N>,
+    packet: [u8; N],
+    len: usize,
+}
+
+impl<'a, 'd, D: Driver<'d>, const N: usize> FrameWriter<'a, 'd, D, N> {
+    /// Writes the next part of the frame.
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            // A full packet is only sent once more data follows, as the last one ends the frame.
+            if self.len == N {
+                self.flush(false).await?;
+            }
+            let n = (N - self.len).min(data.len());
+            self.packet[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Ends the frame, sending its last packet.
+    pub async fn finish(mut self) -> Result<(), Error> {
+        self.flush(true).await
+    }
+
+    async fn flush(&mut self, end_of_frame: bool) -> Result<(), Error> {
+        let mut info = HEADER_EOH;
+        if self.stream.fid {
+            info |= HEADER_FID;
+        }
+        if end_of_frame {
+            info |= HEADER_EOF;
+        }
+        self.packet[1] = info;
+        let len = self.len;
+        self.len = PAYLOAD_HEADER_LEN;
+        self.stream.write_packet(&self.packet[..len]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::driver::{Direction, EndpointAddress};
+    use crate::test_driver::{TestDriver, TestEndpoint};
+
+    const FORMATS: [VideoFormat; 2] = [
+        VideoFormat {
+            format: Format::Mjpeg,
+            frames: &[
+                Frame {
+                    width: 640,
+                    height: 480,
+                    intervals: &[333_333, 666_666],
+                },
+                Frame {
+                    width: 320,
+                    height: 240,
+                    intervals: &[333_333],
+                },
+            ],
+        },
+        VideoFormat {
+            format: Format::Yuy2,
+            frames: &[Frame {
+                width: 160,
+                height: 120,
+                intervals: &[666_666, 1_000_000],
+            }],
+        },
+    ];
+
+    const MAX_PAYLOAD_SIZE: u32 = 512;
+
+    fn control(shared: &SharedControl) -> Control<'_> {
+        let mut control = Control {
+            control_interface: InterfaceNumber(0),
+            streaming_interface: InterfaceNumber(1),
+            formats: &FORMATS,
+            transport: Transport::Bulk,
+            max_payload_size: MAX_PAYLOAD_SIZE,
+            probe: Probe::default(),
+            shared,
+        };
+        control.probe = control.default_probe();
+        control
+    }
+
+    fn request(direction: Direction, request: u8, selector: u8) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value: (selector as u16) << 8,
+            index: 1,
+            length: PROBE_LEN as u16,
+        }
+    }
+
+    fn probe(format_index: u8, frame_index: u8, frame_interval: u32) -> Probe {
+        Probe {
+            format_index,
+            frame_index,
+            frame_interval,
+            ..Probe::default()
+        }
+    }
+
+    /// The probe the device negotiated for `probe`.
+    fn negotiated(format_index: u8, frame_index: u8, frame_interval: u32, frame_size: u32) -> Probe {
+        Probe {
+            max_video_frame_size: frame_size,
+            max_payload_transfer_size: MAX_PAYLOAD_SIZE,
+            ..probe(format_index, frame_index, frame_interval)
+        }
+    }
+
+    fn set_cur(control: &mut Control<'_>, selector: u8, probe: Probe) -> Option<OutResponse> {
+        let mut data = [0; PROBE_LEN];
+        probe.write(&mut data);
+        control.control_out(request(Direction::Out, SET_CUR, selector), &data)
+    }
+
+    fn get(control: &mut Control<'_>, request_code: u8, selector: u8) -> Option<Probe> {
+        let mut buf = [0; 64];
+        match control.control_in(request(Direction::In, request_code, selector), &mut buf) {
+            Some(InResponse::Accepted(data)) => {
+                assert_eq!(data.len(), PROBE_LEN);
+                Probe::parse(data)
+            }
+            Some(InResponse::Rejected) => None,
+            None => panic!("request not handled"),
+        }
+    }
+
+    #[test]
+    fn probe_and_commit() {
+        let state = State::new();
+        let mut control = control(&state.shared);
+        let default = negotiated(1, 1, 333_333, 640 * 480 * 2);
+
+        let mut buf = [0; 64];
+        let info = request(Direction::In, GET_INFO, VS_PROBE_CONTROL);
+        assert_eq!(
+            control.control_in(info, &mut buf),
+            Some(InResponse::Accepted(&[INFO_GET_SUPPORTED | INFO_SET_SUPPORTED]))
+        );
+        let len = request(Direction::In, GET_LEN, VS_COMMIT_CONTROL);
+        assert_eq!(
+            control.control_in(len, &mut buf),
+            Some(InResponse::Accepted(&[PROBE_LEN as u8, 0]))
+        );
+
+        // The probe starts with, and is bounded by, the default settings.
+        for request in [GET_CUR, GET_MIN, GET_MAX, GET_DEF] {
+            assert_eq!(get(&mut control, request, VS_PROBE_CONTROL), Some(default));
+        }
+        assert_eq!(get(&mut control, GET_RES, VS_PROBE_CONTROL), None);
+        assert_eq!(get(&mut control, GET_MIN, VS_COMMIT_CONTROL), None);
+
+        // Supported settings are taken, and the frame interval snaps to the closest supported one.
+        assert_eq!(
+            set_cur(&mut control, VS_PROBE_CONTROL, probe(2, 1, 900_000)),
+            Some(OutResponse::Accepted)
+        );
+        assert_eq!(
+            get(&mut control, GET_CUR, VS_PROBE_CONTROL),
+            Some(negotiated(2, 1, 1_000_000, 160 * 120 * 2))
+        );
+        assert_eq!(get(&mut control, GET_MIN, VS_PROBE_CONTROL), Some(default));
+        set_cur(&mut control, VS_PROBE_CONTROL, probe(1, 2, 0));
+        assert_eq!(
+            get(&mut control, GET_CUR, VS_PROBE_CONTROL),
+            Some(negotiated(1, 2, 333_333, 320 * 240 * 2))
+        );
+
+        // Out of range indices fall back to the first format and frame, with its default interval.
+        set_cur(&mut control, VS_PROBE_CONTROL, probe(3, 1, 666_666));
+        assert_eq!(
+            get(&mut control, GET_CUR, VS_PROBE_CONTROL),
+            Some(negotiated(1, 1, 666_666, 640 * 480 * 2))
+        );
+        set_cur(&mut control, VS_PROBE_CONTROL, probe(0, 0, 0));
+        assert_eq!(get(&mut control, GET_CUR, VS_PROBE_CONTROL), Some(default));
+        set_cur(&mut control, VS_PROBE_CONTROL, probe(2, 2, 1));
+        assert_eq!(
+            get(&mut control, GET_CUR, VS_PROBE_CONTROL),
+            Some(negotiated(2, 1, 666_666, 160 * 120 * 2))
+        );
+
+        // UVC 1.0 hosts send and read shorter controls, shorter ones are rejected.
+        let mut data = [0; PROBE_LEN];
+        probe(1, 2, 0).write(&mut data);
+        let set_probe = request(Direction::Out, SET_CUR, VS_PROBE_CONTROL);
+        assert_eq!(
+            control.control_out(set_probe, &data[..PROBE_LEN_UVC10]),
+            Some(OutResponse::Accepted)
+        );
+        assert_eq!(
+            control.control_out(set_probe, &data[..PROBE_LEN_UVC10 - 1]),
+            Some(OutResponse::Rejected)
+        );
+        let get_probe = Request {
+            length: PROBE_LEN_UVC10 as u16,
+            ..request(Direction::In, GET_CUR, VS_PROBE_CONTROL)
+        };
+        let Some(InResponse::Accepted(data)) = control.control_in(get_probe, &mut buf) else {
+            panic!("probe rejected");
+        };
+        assert_eq!(Probe::parse(data), Some(negotiated(1, 2, 333_333, 320 * 240 * 2)));
+
+        // The commit defaults to the default settings, and starts a bulk stream.
+        assert_eq!(get(&mut control, GET_CUR, VS_COMMIT_CONTROL), Some(default));
+        assert_eq!(
+            set_cur(&mut control, VS_COMMIT_CONTROL, probe(2, 1, 666_666)),
+            Some(OutResponse::Accepted)
+        );
+        let commit = negotiated(2, 1, 666_666, 160 * 120 * 2);
+        assert_eq!(get(&mut control, GET_CUR, VS_COMMIT_CONTROL), Some(commit));
+        let stream = state.shared.get();
+        assert_eq!((stream.commit, stream.streaming), (Some(commit), true));
+        assert_eq!(
+            settings(&FORMATS, &commit),
+            StreamSettings {
+                format_index: 1,
+                frame_index: 0,
+                format: Format::Yuy2,
+                width: 160,
+                height: 120,
+                frame_interval: 666_666,
+            }
+        );
+
+        // Hosts stop bulk streams with the alternate setting 0, and a reset forgets the settings.
+        control.set_alternate_setting(InterfaceNumber(1), 0);
+        assert!(!state.shared.get().streaming);
+        control.reset();
+        assert_eq!(state.shared.get().commit, None);
+        assert_eq!(get(&mut control, GET_CUR, VS_PROBE_CONTROL), Some(default));
+    }
+
+    /// The payload header info of each packet, and the data of the frames.
+    fn payloads(packets: &[Vec<u8>]) -> (Vec<u8>, Vec<u8>) {
+        let mut infos = Vec::new();
+        let mut data = Vec::new();
+        for packet in packets {
+            assert_eq!(packet[0], PAYLOAD_HEADER_LEN as u8);
+            infos.push(packet[1]);
+            data.extend_from_slice(&packet[PAYLOAD_HEADER_LEN..]);
+        }
+        (infos, data)
+    }
+
+    #[test]
+    fn payload_headers() {
+        let state = State::new();
+        let mut stream: VideoStream<'_, TestDriver, 16> = VideoStream {
+            ep: TestEndpoint::new(EndpointAddress::from_parts(1, Direction::In), EndpointType::Bulk, 16, 0),
+            formats: &FORMATS,
+            shared: &state.shared,
+            fid: false,
+        };
+        let frame: Vec<u8> = (0..30).collect();
+        const EOH: u8 = HEADER_EOH;
+        const FID: u8 = HEADER_FID;
+        const EOF: u8 = HEADER_EOF;
+
+        // Every packet holds 14 bytes of the frame, the last one ends it.
+        block_on(stream.write_frame(&frame)).unwrap();
+        let (infos, data) = payloads(&core::mem::take(&mut stream.ep.written));
+        assert_eq!(infos, [EOH | FID, EOH | FID, EOH | FID | EOF]);
+        assert_eq!(data, frame);
+
+        // The frame ID toggles with every frame. A frame filling its last packet isn't followed by
+        // an empty one.
+        block_on(stream.write_frame(&frame[..28])).unwrap();
+        let (infos, data) = payloads(&core::mem::take(&mut stream.ep.written));
+        assert_eq!(infos, [EOH, EOH | EOF]);
+        assert_eq!(data, frame[..28]);
+
+        // Frames can be written in parts, and be empty.
+        block_on(async {
+            let mut writer = stream.begin_frame();
+            writer.write(&frame[..5]).await?;
+            writer.write(&frame[5..20]).await?;
+            writer.finish().await?;
+            stream.begin_frame().finish().await
+        })
+        .unwrap();
+        let (infos, data) = payloads(&stream.ep.written);
+        assert_eq!(infos, [EOH | FID, EOH | FID | EOF, EOH | EOF]);
+        assert_eq!(data, frame[..20]);
+        assert_eq!(stream.ep.written[2].len(), PAYLOAD_HEADER_LEN);
+    }
+}
@@ -4,7 +4,6 @@ use embassy_usb_driver::EndpointType;
 use crate::builder::Config;
 use crate::driver::EndpointInfo;
 use crate::types::{InterfaceNumber, StringIndex};
-use crate::CONFIGURATION_VALUE;
 
 /// Standard descriptor types
 #[allow(missing_docs)]
@@ -77,6 +76,7 @@ pub enum UsageType {
 pub(crate) struct DescriptorWriter<'a> {
     pub buf: &'a mut [u8],
     position: usize,
+    configuration_mark: Option<usize>,
     num_interfaces_mark: Option<usize>,
     num_endpoints_mark: Option<usize>,
 }
@@ -86,6 +86,7 @@ impl<'a> DescriptorWriter<'a> {
         DescriptorWriter {
             buf,
             position: 0,
+            configuration_mark: None,
             num_interfaces_mark: None,
             num_endpoints_mark: None,
         }
@@ -122,7 +123,8 @@ impl<'a> DescriptorWriter<'a> {
         self.position = start + total_length;
     }
 
-    pub(crate) fn configuration(&mut self, config: &Config) {
+    pub(crate) fn configuration(&mut self, config: &Config, configuration_value: u8) {
+        self.configuration_mark = Some(self.position);
         self.num_interfaces_mark = Some(self.position + 4);
 
         self.write(
@@ -131,7 +133,7 @@ impl<'a> DescriptorWriter<'a> {
                 0,
                 0,                   // wTotalLength
                 0,                   // bNumInterfaces
-                CONFIGURATION_VALUE, // bConfigurationValue
+                configuration_value, // bConfigurationValue
                 0,                   // iConfiguration
                 0x80 | if config.self_powered { 0x40 } else { 0x00 }
                     | if config.supports_remote_wakeup { 0x20 } else { 0x00 }, // bmAttributes
@@ -147,8 +149,13 @@ impl<'a> DescriptorWriter<'a> {
     }
 
     pub(crate) fn end_configuration(&mut self) {
-        let position = self.position as u16;
-        self.buf[2..4].copy_from_slice(&position.to_le_bytes());
+        let Some(mark) = self.configuration_mark.take() else {
+            return;
+        };
+        let total_length = (self.position - mark) as u16;
+        self.buf[mark + 2..mark + 4].copy_from_slice(&total_length.to_le_bytes());
+        self.num_interfaces_mark = None;
+        self.num_endpoints_mark = None;
     }
 
     /// Writes a interface association descriptor. Call from `UsbClass::get_configuration_descriptors`
@@ -321,7 +328,7 @@ impl<'a> DescriptorWriter<'a> {
 ///
 /// All device descriptors are always 18 bytes, so there's no need for
 /// a variable-length buffer or DescriptorWriter.
-pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
+pub(crate) fn device_descriptor(config: &Config, num_configurations: u8) -> [u8; 18] {
     [
         18,   // bLength
         0x01, // bDescriptorType
@@ -340,7 +347,7 @@ pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
         config.manufacturer.map_or(0, |_| 1),  // iManufacturer
         config.product.map_or(0, |_| 2),       // iProduct
         config.serial_number.map_or(0, |_| 3), // iSerialNumber
-        1,                                     // bNumConfigurations
+        num_configurations,                    // bNumConfigurations
     ]
 }
 
@@ -348,7 +355,7 @@ pub(crate) fn device_descriptor(config: &Config) -> [u8; 18] {
 ///
 /// All device qualifier descriptors are always 10 bytes, so there's no need for
 /// a variable-length buffer or DescriptorWriter.
-pub(crate) fn device_qualifier_descriptor(config: &Config) -> [u8; 10] {
+pub(crate) fn device_qualifier_descriptor(config: &Config, num_configurations: u8) -> [u8; 10] {
     [
         10,   // bLength
         0x06, // bDescriptorType
@@ -358,7 +365,7 @@ pub(crate) fn device_qualifier_descriptor(config: &Config) -> [u8; 10] {
         config.device_sub_class,            // bDeviceSubClass
         config.device_protocol,             // bDeviceProtocol
         config.max_packet_size_0,           // bMaxPacketSize0
-        1,                                  // bNumConfigurations
+        num_configurations,                 // bNumConfigurations
         0,                                  // Reserved
     ]
 }
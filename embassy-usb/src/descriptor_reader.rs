@@ -109,3 +109,15 @@ pub fn foreach_endpoint(data: &[u8], mut f: impl FnMut(EndpointInfo)) -> Result<
     }
     Ok(())
 }
+
+/// Returns the full descriptor (including its interfaces and endpoints) of the configuration
+/// with the given zero-based `index`, from a buffer of back-to-back configuration descriptors.
+pub fn configuration_descriptor(data: &[u8], index: u8) -> Option<&[u8]> {
+    let mut data = data;
+    for _ in 0..index {
+        let total_length = u16::from_le_bytes(data.get(2..4)?.try_into().unwrap()) as usize;
+        data = data.get(total_length..)?;
+    }
+    let total_length = u16::from_le_bytes(data.get(2..4)?.try_into().unwrap()) as usize;
+    data.get(..total_length)
+}
@@ -1,4 +1,5 @@
 #![no_std]
+#![allow(async_fn_in_trait)]
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
@@ -13,6 +14,10 @@ pub mod control;
 pub mod descriptor;
 mod descriptor_reader;
 pub mod msos;
+#[cfg(feature = "time")]
+pub mod pd;
+#[cfg(test)]
+mod test_driver;
 pub mod types;
 
 mod config {
@@ -27,7 +32,7 @@ pub use crate::builder::{Builder, Config, FunctionBuilder, InterfaceAltBuilder,
 use crate::config::{MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
 use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
 use crate::descriptor::{descriptor_type, lang_id};
-use crate::descriptor_reader::foreach_endpoint;
+use crate::descriptor_reader::{configuration_descriptor, foreach_endpoint};
 use crate::driver::{Bus, ControlPipe, Direction, Driver, EndpointAddress, Event};
 use crate::types::{InterfaceNumber, StringIndex};
 
@@ -73,7 +78,9 @@ impl From<driver::Unsupported> for RemoteWakeupError {
 /// The bConfiguration value for the not configured state.
 pub const CONFIGURATION_NONE: u8 = 0;
 
-/// The bConfiguration value for the single configuration supported by this device.
+/// The bConfiguration value for the first (and usually only) configuration of this device.
+///
+/// Additional configurations added with [`Builder::next_configuration`] are numbered consecutively.
 pub const CONFIGURATION_VALUE: u8 = 1;
 
 const STRING_INDEX_MANUFACTURER: u8 = 1;
@@ -160,6 +167,7 @@ pub trait Handler {
 }
 
 struct Interface {
+    configuration: u8,
     current_alt_setting: u8,
     num_alt_settings: u8,
 }
@@ -207,8 +215,14 @@ struct Inner<'d, D: Driver<'d>> {
     /// instead of regular `accept()`.
     set_address_pending: bool,
 
+    /// The selected configuration, or the first one while the device is not configured.
+    configuration: u8,
+    num_configurations: u8,
+
     interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
     handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+    /// Configuration each handler in `handlers` belongs to.
+    handler_configurations: Vec<u8, MAX_HANDLER_COUNT>,
 }
 
 impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
@@ -216,6 +230,8 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         driver: D,
         config: Config<'d>,
         handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+        handler_configurations: Vec<u8, MAX_HANDLER_COUNT>,
+        num_configurations: u8,
         config_descriptor: &'d [u8],
         bos_descriptor: &'d [u8],
         msos_descriptor: crate::msos::MsOsDescriptorSet<'d>,
@@ -225,8 +241,8 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         // Start the USB bus.
         // This prevent further allocation by consuming the driver.
         let (bus, control) = driver.start(config.max_packet_size_0 as u16);
-        let device_descriptor = descriptor::device_descriptor(&config);
-        let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config);
+        let device_descriptor = descriptor::device_descriptor(&config, num_configurations);
+        let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config, num_configurations);
 
         Self {
             control_buf,
@@ -246,8 +262,11 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
                 self_powered: false,
                 address: 0,
                 set_address_pending: false,
+                configuration: CONFIGURATION_VALUE,
+                num_configurations,
                 interfaces,
                 handlers,
+                handler_configurations,
             },
         }
     }
@@ -454,12 +473,21 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     h.reset();
                 }
 
-                for (i, iface) in self.interfaces.iter_mut().enumerate() {
+                self.configuration = CONFIGURATION_VALUE;
+
+                let mut number = 0;
+                let mut configuration = CONFIGURATION_VALUE;
+                for iface in self.interfaces.iter_mut() {
+                    if iface.configuration != configuration {
+                        configuration = iface.configuration;
+                        number = 0;
+                    }
                     iface.current_alt_setting = 0;
 
-                    for h in &mut self.handlers {
-                        h.set_alternate_setting(InterfaceNumber::new(i as _), 0);
+                    for h in handlers_of(&mut self.handlers, &self.handler_configurations, configuration) {
+                        h.set_alternate_setting(InterfaceNumber::new(number), 0);
                     }
+                    number += 1;
                 }
             }
             Event::Resume => {
@@ -499,7 +527,6 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
 
     fn handle_control_out(&mut self, req: Request, data: &[u8]) -> OutResponse {
         const CONFIGURATION_NONE_U16: u16 = CONFIGURATION_NONE as u16;
-        const CONFIGURATION_VALUE_U16: u16 = CONFIGURATION_VALUE as u16;
 
         match (req.request_type, req.recipient) {
             (RequestType::Standard, Recipient::Device) => match (req.request, req.value) {
@@ -526,20 +553,33 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     }
                     OutResponse::Accepted
                 }
-                (Request::SET_CONFIGURATION, CONFIGURATION_VALUE_U16) => {
-                    debug!("SET_CONFIGURATION: configured");
+                (Request::SET_CONFIGURATION, value)
+                    if value != CONFIGURATION_NONE_U16 && value <= self.num_configurations as u16 =>
+                {
+                    let value = value as u8;
+                    debug!("SET_CONFIGURATION: configured {}", value);
+
+                    // Switching between configurations unconfigures the previous one.
+                    if self.device_state == UsbDeviceState::Configured && self.configuration != value {
+                        for h in handlers_of(&mut self.handlers, &self.handler_configurations, self.configuration) {
+                            h.configured(false);
+                        }
+                    }
+
                     self.device_state = UsbDeviceState::Configured;
+                    self.configuration = value;
 
-                    // Enable all endpoints of selected alt settings.
+                    // Enable all endpoints of selected alt settings, disable the ones of other configurations.
                     foreach_endpoint(self.config_descriptor, |ep| {
-                        let iface = &self.interfaces[ep.interface.0 as usize];
-                        self.bus
-                            .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
+                        let enabled = ep.configuration == value
+                            && interface_mut(&mut self.interfaces, ep.configuration, ep.interface)
+                                .is_some_and(|iface| iface.current_alt_setting == ep.interface_alt);
+                        self.bus.endpoint_set_enabled(ep.ep_address, enabled);
                     })
                     .unwrap();
 
                     // Notify handlers.
-                    for h in &mut self.handlers {
+                    for h in handlers_of(&mut self.handlers, &self.handler_configurations, value) {
                         h.configured(true);
                     }
 
@@ -557,9 +597,11 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                         .unwrap();
 
                         // Notify handlers.
-                        for h in &mut self.handlers {
+                        for h in handlers_of(&mut self.handlers, &self.handler_configurations, self.configuration) {
                             h.configured(false);
                         }
+
+                        self.configuration = CONFIGURATION_VALUE;
                     }
                     OutResponse::Accepted
                 }
@@ -567,7 +609,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             },
             (RequestType::Standard, Recipient::Interface) => {
                 let iface_num = InterfaceNumber::new(req.index as _);
-                let Some(iface) = self.interfaces.get_mut(iface_num.0 as usize) else {
+                let Some(iface) = interface_mut(&mut self.interfaces, self.configuration, iface_num) else {
                     return OutResponse::Rejected;
                 };
 
@@ -584,7 +626,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
 
                         // Enable/disable EPs of this interface as needed.
                         foreach_endpoint(self.config_descriptor, |ep| {
-                            if ep.interface == iface_num {
+                            if ep.configuration == iface.configuration && ep.interface == iface_num {
                                 self.bus
                                     .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
                             }
@@ -593,7 +635,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
 
                         // TODO check it is valid (not out of range)
 
-                        for h in &mut self.handlers {
+                        for h in handlers_of(&mut self.handlers, &self.handler_configurations, self.configuration) {
                             h.set_alternate_setting(iface_num, new_altsetting);
                         }
                         OutResponse::Accepted
@@ -635,7 +677,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 Request::GET_DESCRIPTOR => self.handle_get_descriptor(req, buf),
                 Request::GET_CONFIGURATION => {
                     let status = match self.device_state {
-                        UsbDeviceState::Configured => CONFIGURATION_VALUE,
+                        UsbDeviceState::Configured => self.configuration,
                         _ => CONFIGURATION_NONE,
                     };
                     buf[0] = status;
@@ -644,7 +686,8 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 _ => InResponse::Rejected,
             },
             (RequestType::Standard, Recipient::Interface) => {
-                let Some(iface) = self.interfaces.get_mut(req.index as usize) else {
+                let iface_num = InterfaceNumber::new(req.index as _);
+                let Some(iface) = interface_mut(&mut self.interfaces, self.configuration, iface_num) else {
                     return InResponse::Rejected;
                 };
 
@@ -690,7 +733,10 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
     }
 
     fn handle_control_out_delegated(&mut self, req: Request, data: &[u8]) -> OutResponse {
-        for h in &mut self.handlers {
+        for (h, configuration) in self.handlers.iter_mut().zip(&self.handler_configurations) {
+            if targets_configuration(req) && *configuration != self.configuration {
+                continue;
+            }
             if let Some(res) = h.control_out(req, data) {
                 return res;
             }
@@ -703,7 +749,10 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             core::mem::transmute(r)
         }
 
-        for h in &mut self.handlers {
+        for (h, configuration) in self.handlers.iter_mut().zip(&self.handler_configurations) {
+            if targets_configuration(req) && *configuration != self.configuration {
+                continue;
+            }
             if let Some(res) = h.control_in(req, buf) {
                 // safety: the borrow checker isn't smart enough to know this pattern (returning a
                 // borrowed value from inside the loop) is sound. Workaround by unsafely extending lifetime.
@@ -721,7 +770,10 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
         match dtype {
             descriptor_type::BOS => InResponse::Accepted(self.bos_descriptor),
             descriptor_type::DEVICE => InResponse::Accepted(&self.device_descriptor),
-            descriptor_type::CONFIGURATION => InResponse::Accepted(self.config_descriptor),
+            descriptor_type::CONFIGURATION => match configuration_descriptor(self.config_descriptor, index) {
+                Some(descriptor) => InResponse::Accepted(descriptor),
+                None => InResponse::Rejected,
+            },
             descriptor_type::STRING => {
                 if index == 0 {
                     buf[0] = 4; // len
@@ -773,6 +825,33 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
     }
 }
 
+/// Returns whether a control request addresses an interface or endpoint, and so must only be
+/// delivered to the handlers of the selected configuration.
+fn targets_configuration(req: Request) -> bool {
+    matches!(req.recipient, Recipient::Interface | Recipient::Endpoint)
+}
+
+/// Iterates over the handlers that belong to `configuration`.
+fn handlers_of<'a, 'd>(
+    handlers: &'a mut [&'d mut dyn Handler],
+    handler_configurations: &'a [u8],
+    configuration: u8,
+) -> impl Iterator<Item = &'a mut &'d mut dyn Handler> {
+    handlers
+        .iter_mut()
+        .zip(handler_configurations)
+        .filter(move |(_, c)| **c == configuration)
+        .map(|(h, _)| h)
+}
+
+/// Looks up an interface by its number within `configuration`.
+fn interface_mut(interfaces: &mut [Interface], configuration: u8, number: InterfaceNumber) -> Option<&mut Interface> {
+    interfaces
+        .iter_mut()
+        .filter(|iface| iface.configuration == configuration)
+        .nth(number.0 as usize)
+}
+
 fn first_last<T: Iterator>(iter: T) -> impl Iterator<Item = (bool, bool, T::Item)> {
     let mut iter = iter.peekable();
     let mut first = true;
@@ -784,3 +863,233 @@ fn first_last<T: Iterator>(iter: T) -> impl Iterator<Item = (bool, bool, T::Item
         Some((is_first, is_last, val))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::RefCell;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::test_driver::TestDriver;
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Configured(bool),
+        AltSetting(u8, u8),
+    }
+
+    /// Logs the calls it receives, and answers every class request to an interface with its id.
+    struct TestHandler<'a> {
+        id: u8,
+        log: &'a RefCell<Vec<(u8, Call)>>,
+    }
+
+    impl Handler for TestHandler<'_> {
+        fn configured(&mut self, configured: bool) {
+            self.log.borrow_mut().push((self.id, Call::Configured(configured)));
+        }
+
+        fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+            self.log
+                .borrow_mut()
+                .push((self.id, Call::AltSetting(iface.0, alternate_setting)));
+        }
+
+        fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+            if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+                return None;
+            }
+            buf[0] = self.id;
+            Some(InResponse::Accepted(&buf[..1]))
+        }
+    }
+
+    fn request(request_type: RequestType, recipient: Recipient, request: u8, value: u16, index: u16) -> Request {
+        Request {
+            direction: Direction::In,
+            request_type,
+            recipient,
+            request,
+            value,
+            index,
+            length: 64,
+        }
+    }
+
+    fn set_configuration(value: u16) -> Request {
+        Request {
+            direction: Direction::Out,
+            ..request(
+                RequestType::Standard,
+                Recipient::Device,
+                Request::SET_CONFIGURATION,
+                value,
+                0,
+            )
+        }
+    }
+
+    fn enabled_endpoints<'d>(inner: &Inner<'d, TestDriver>) -> Vec<u8> {
+        let mut enabled: Vec<u8> = inner.bus.enabled.iter().map(|&addr| addr.into()).collect();
+        enabled.sort();
+        enabled
+    }
+
+    #[test]
+    fn multiple_configurations() {
+        let log = RefCell::new(Vec::new());
+        let mut first = TestHandler { id: 1, log: &log };
+        let mut second = TestHandler { id: 2, log: &log };
+        let mut config_descriptor = [0; 256];
+        let mut bos_descriptor = [0; 64];
+        let mut control_buf = [0; 64];
+        let mut builder = Builder::new(
+            TestDriver::default(),
+            Config::new(0xc0de, 0xcafe),
+            &mut config_descriptor,
+            &mut bos_descriptor,
+            &mut [],
+            &mut control_buf,
+        );
+
+        // The first configuration has two interfaces with a pair of bulk endpoints each.
+        let mut func = builder.function(0xff, 0, 0);
+        for _ in 0..2 {
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xff, 0, 0, None);
+            alt.endpoint_bulk_out(None, 64);
+            alt.endpoint_bulk_in(None, 64);
+        }
+        drop(func);
+        builder.handler(&mut first);
+
+        // The second configuration has a single interface with an interrupt endpoint.
+        builder.next_configuration();
+        let mut func = builder.function(0xff, 0, 0);
+        func.interface()
+            .alt_setting(0xff, 0, 0, None)
+            .endpoint_interrupt_in(None, 8, 10);
+        drop(func);
+        builder.handler(&mut second);
+
+        let mut device = builder.build();
+        let inner = &mut device.inner;
+        let mut buf = [0; 64];
+
+        assert_eq!(inner.device_descriptor[17], 2); // bNumConfigurations
+
+        // Both configurations can be read by index, each with its own total length and interfaces.
+        let mut descriptors_len = 0;
+        for (index, num_interfaces) in [(0, 2), (1, 1)] {
+            let req = request(
+                RequestType::Standard,
+                Recipient::Device,
+                Request::GET_DESCRIPTOR,
+                (descriptor_type::CONFIGURATION as u16) << 8 | index,
+                0,
+            );
+            let InResponse::Accepted(descriptor) = inner.handle_control_in(req, &mut buf) else {
+                panic!("configuration {} rejected", index);
+            };
+            assert_eq!(descriptor[1], descriptor_type::CONFIGURATION);
+            assert_eq!(
+                u16::from_le_bytes([descriptor[2], descriptor[3]]) as usize,
+                descriptor.len()
+            );
+            assert_eq!(descriptor[4], num_interfaces); // bNumInterfaces
+            assert_eq!(descriptor[5], index as u8 + 1); // bConfigurationValue
+            descriptors_len += descriptor.len();
+        }
+        assert_eq!(descriptors_len, inner.config_descriptor.len());
+        let req = request(
+            RequestType::Standard,
+            Recipient::Device,
+            Request::GET_DESCRIPTOR,
+            (descriptor_type::CONFIGURATION as u16) << 8 | 2,
+            0,
+        );
+        assert_eq!(inner.handle_control_in(req, &mut buf), InResponse::Rejected);
+
+        // Selecting the second configuration enables only its endpoint, and notifies only its handler.
+        assert_eq!(
+            inner.handle_control_out(set_configuration(2), &[]),
+            OutResponse::Accepted
+        );
+        assert_eq!(log.take(), [(2, Call::Configured(true))]);
+        assert_eq!(enabled_endpoints(inner), [0x83]);
+        let req = request(
+            RequestType::Standard,
+            Recipient::Device,
+            Request::GET_CONFIGURATION,
+            0,
+            0,
+        );
+        assert_eq!(inner.handle_control_in(req, &mut buf), InResponse::Accepted(&[2]));
+
+        // Interface requests are scoped to the selected configuration, which has no interface 1.
+        let class_req = request(RequestType::Class, Recipient::Interface, 0, 0, 0);
+        assert_eq!(inner.handle_control_in(class_req, &mut buf), InResponse::Accepted(&[2]));
+        let get_interface = request(
+            RequestType::Standard,
+            Recipient::Interface,
+            Request::GET_INTERFACE,
+            0,
+            1,
+        );
+        assert_eq!(inner.handle_control_in(get_interface, &mut buf), InResponse::Rejected);
+        let set_interface = |iface: u16| Request {
+            direction: Direction::Out,
+            ..request(
+                RequestType::Standard,
+                Recipient::Interface,
+                Request::SET_INTERFACE,
+                0,
+                iface,
+            )
+        };
+        assert_eq!(inner.handle_control_out(set_interface(1), &[]), OutResponse::Rejected);
+        assert_eq!(inner.handle_control_out(set_interface(0), &[]), OutResponse::Accepted);
+        assert_eq!(log.take(), [(2, Call::AltSetting(0, 0))]);
+
+        // Switching back unconfigures the second configuration first.
+        assert_eq!(
+            inner.handle_control_out(set_configuration(1), &[]),
+            OutResponse::Accepted
+        );
+        assert_eq!(log.take(), [(2, Call::Configured(false)), (1, Call::Configured(true))]);
+        assert_eq!(enabled_endpoints(inner), [0x01, 0x02, 0x81, 0x82]);
+        assert_eq!(inner.handle_control_in(class_req, &mut buf), InResponse::Accepted(&[1]));
+        assert_eq!(
+            inner.handle_control_in(get_interface, &mut buf),
+            InResponse::Accepted(&[0])
+        );
+
+        assert_eq!(
+            inner.handle_control_out(set_configuration(3), &[]),
+            OutResponse::Rejected
+        );
+        assert!(log.borrow().is_empty());
+
+        assert_eq!(
+            inner.handle_control_out(set_configuration(CONFIGURATION_NONE as u16), &[]),
+            OutResponse::Accepted
+        );
+        assert_eq!(log.take(), [(1, Call::Configured(false))]);
+        assert!(enabled_endpoints(inner).is_empty());
+
+        // A bus reset resets the alternate settings of the interfaces of every configuration, through
+        // the handlers of that configuration.
+        embassy_futures::block_on(inner.handle_bus_event(Event::Reset));
+        assert_eq!(
+            log.take(),
+            [
+                (1, Call::AltSetting(0, 0)),
+                (1, Call::AltSetting(1, 0)),
+                (2, Call::AltSetting(0, 0)),
+            ]
+        );
+        assert_eq!(inner.configuration, CONFIGURATION_VALUE);
+    }
+}
@@ -85,6 +85,11 @@ impl<'d> MsOsDescriptorWriter<'d> {
         self.config_mark.is_some()
     }
 
+    /// Returns the configuration index of the configuration subset that has been started, if any
+    pub fn config_subset_index(&self) -> Option<u8> {
+        self.config_mark.map(|mark| self.buf[mark + 4])
+    }
+
     /// Returns `true` if a function subset header has been started and not yet ended
     pub fn is_in_function_subset(&self) -> bool {
         self.function_mark.is_some()
@@ -0,0 +1,474 @@
+//! Power Delivery messages, and the data objects used to negotiate power.
+
+// Control message types [USB PD 3.1, 6.3]
+pub(crate) const GOOD_CRC: u8 = 0x01;
+pub(crate) const ACCEPT: u8 = 0x03;
+pub(crate) const REJECT: u8 = 0x04;
+pub(crate) const PING: u8 = 0x05;
+pub(crate) const PS_RDY: u8 = 0x06;
+pub(crate) const GET_SINK_CAP: u8 = 0x08;
+pub(crate) const WAIT: u8 = 0x0C;
+pub(crate) const SOFT_RESET: u8 = 0x0D;
+pub(crate) const NOT_SUPPORTED: u8 = 0x10;
+
+// Data message types [USB PD 3.1, 6.4]
+pub(crate) const SOURCE_CAPABILITIES: u8 = 0x01;
+pub(crate) const REQUEST: u8 = 0x02;
+pub(crate) const SINK_CAPABILITIES: u8 = 0x04;
+pub(crate) const VENDOR_DEFINED: u8 = 0x0F;
+
+/// Maximum number of data objects in a message.
+pub(crate) const MAX_OBJECTS: usize = 7;
+
+/// Specification revision of messages.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SpecRevision {
+    /// Revision 1.0
+    R1_0,
+    /// Revision 2.0
+    R2_0,
+    /// Revision 3.0 and later
+    R3_0,
+}
+
+/// Message header, with the port roles of a sink and an upstream facing port.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct Header(pub u16);
+
+impl Header {
+    pub fn new(message_type: u8, object_count: usize, message_id: u8, revision: SpecRevision) -> Self {
+        Self(message_type as u16 | (revision as u16) << 6 | (message_id as u16) << 9 | (object_count as u16) << 12)
+    }
+
+    pub fn message_type(self) -> u8 {
+        (self.0 & 0x1F) as u8
+    }
+
+    pub fn message_id(self) -> u8 {
+        (self.0 >> 9 & 0x07) as u8
+    }
+
+    pub fn object_count(self) -> usize {
+        (self.0 >> 12 & 0x07) as usize
+    }
+
+    pub fn extended(self) -> bool {
+        self.0 & 0x8000 != 0
+    }
+
+    pub fn revision(self) -> SpecRevision {
+        match self.0 >> 6 & 0x03 {
+            0 => SpecRevision::R1_0,
+            1 => SpecRevision::R2_0,
+            _ => SpecRevision::R3_0,
+        }
+    }
+}
+
+/// A received message.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct Message {
+    pub header: Header,
+    objects: [u32; MAX_OBJECTS],
+}
+
+impl Message {
+    /// Parse a message, unless it is truncated.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let header = Header(u16::from_le_bytes([*data.first()?, *data.get(1)?]));
+        let mut objects = [0; MAX_OBJECTS];
+        if !header.extended() {
+            let data = data.get(2..2 + 4 * header.object_count())?;
+            for (object, data) in objects.iter_mut().zip(data.chunks_exact(4)) {
+                *object = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            }
+        }
+        Some(Self { header, objects })
+    }
+
+    /// Whether this is the control message `message_type`.
+    pub fn is_control(&self, message_type: u8) -> bool {
+        self.header.object_count() == 0 && !self.header.extended() && self.header.message_type() == message_type
+    }
+
+    /// Whether this is the data message `message_type`.
+    pub fn is_data(&self, message_type: u8) -> bool {
+        self.header.object_count() > 0 && !self.header.extended() && self.header.message_type() == message_type
+    }
+
+    /// The data objects of a data message.
+    pub fn objects(&self) -> &[u32] {
+        match self.header.extended() {
+            true => &[],
+            false => &self.objects[..self.header.object_count()],
+        }
+    }
+}
+
+/// Fixed supply, such as the 5 V, 9 V, 15 V and 20 V ones of chargers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FixedSupply {
+    /// Voltage, in mV.
+    pub voltage_mv: u16,
+    /// Maximum current for sources, or operational current for sinks, in mA.
+    pub max_current_ma: u16,
+    /// Whether the port can act as a source and as a sink.
+    pub dual_role_power: bool,
+    /// Whether the power does not come from a limited source, such as a battery.
+    pub unconstrained_power: bool,
+    /// Whether the port can communicate over USB.
+    pub usb_communications_capable: bool,
+    /// Whether the port can act as a USB host and as a USB device.
+    pub dual_role_data: bool,
+}
+
+impl FixedSupply {
+    /// Create a fixed supply with `voltage_mv` and `max_current_ma`, all flags being cleared.
+    pub const fn new(voltage_mv: u16, max_current_ma: u16) -> Self {
+        Self {
+            voltage_mv,
+            max_current_ma,
+            dual_role_power: false,
+            unconstrained_power: false,
+            usb_communications_capable: false,
+            dual_role_data: false,
+        }
+    }
+}
+
+/// Variable supply, with a voltage anywhere in a range.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VariableSupply {
+    /// Minimum voltage, in mV.
+    pub min_voltage_mv: u16,
+    /// Maximum voltage, in mV.
+    pub max_voltage_mv: u16,
+    /// Maximum current, in mA.
+    pub max_current_ma: u16,
+}
+
+/// Battery supply, with a voltage anywhere in a range.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Battery {
+    /// Minimum voltage, in mV.
+    pub min_voltage_mv: u16,
+    /// Maximum voltage, in mV.
+    pub max_voltage_mv: u16,
+    /// Maximum power, in mW.
+    pub max_power_mw: u32,
+}
+
+/// Programmable power supply (PPS), whose voltage is requested in steps of 20 mV.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pps {
+    /// Minimum voltage, in mV.
+    pub min_voltage_mv: u16,
+    /// Maximum voltage, in mV.
+    pub max_voltage_mv: u16,
+    /// Maximum current, in mA.
+    pub max_current_ma: u16,
+    /// Whether the source limits its power below the voltage times the current.
+    pub power_limited: bool,
+}
+
+/// Power data object (PDO), describing a supply of a source, or a power need of a sink.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerDataObject {
+    /// Fixed supply.
+    Fixed(FixedSupply),
+    /// Battery supply.
+    Battery(Battery),
+    /// Variable supply.
+    Variable(VariableSupply),
+    /// Programmable power supply.
+    Pps(Pps),
+    /// Other augmented power data objects, such as extended power range ones.
+    Unknown(u32),
+}
+
+impl PowerDataObject {
+    /// Decode a power data object.
+    pub fn from_raw(raw: u32) -> Self {
+        let bit = |n: u32| raw & 1 << n != 0;
+        let field = |shift: u32, bits: u32| (raw >> shift & ((1 << bits) - 1)) as u16;
+        match raw >> 30 {
+            0b00 => Self::Fixed(FixedSupply {
+                voltage_mv: field(10, 10) * 50,
+                max_current_ma: field(0, 10) * 10,
+                dual_role_power: bit(29),
+                unconstrained_power: bit(27),
+                usb_communications_capable: bit(26),
+                dual_role_data: bit(25),
+            }),
+            0b01 => Self::Battery(Battery {
+                min_voltage_mv: field(10, 10) * 50,
+                max_voltage_mv: field(20, 10) * 50,
+                max_power_mw: field(0, 10) as u32 * 250,
+            }),
+            0b10 => Self::Variable(VariableSupply {
+                min_voltage_mv: field(10, 10) * 50,
+                max_voltage_mv: field(20, 10) * 50,
+                max_current_ma: field(0, 10) * 10,
+            }),
+            _ if raw >> 28 & 0x03 == 0 => Self::Pps(Pps {
+                min_voltage_mv: field(8, 8) * 100,
+                max_voltage_mv: field(17, 8) * 100,
+                max_current_ma: field(0, 7) * 50,
+                power_limited: bit(27),
+            }),
+            _ => Self::Unknown(raw),
+        }
+    }
+
+    /// Encode the power data object.
+    pub fn to_raw(&self) -> u32 {
+        let flag = |set: bool, n: u32| (set as u32) << n;
+        match self {
+            Self::Fixed(f) => {
+                flag(f.dual_role_power, 29)
+                    | flag(f.unconstrained_power, 27)
+                    | flag(f.usb_communications_capable, 26)
+                    | flag(f.dual_role_data, 25)
+                    | ((f.voltage_mv as u32 / 50) << 10)
+                    | (f.max_current_ma as u32 / 10)
+            }
+            Self::Battery(b) => {
+                (0b01 << 30)
+                    | ((b.max_voltage_mv as u32 / 50) << 20)
+                    | ((b.min_voltage_mv as u32 / 50) << 10)
+                    | (b.max_power_mw / 250)
+            }
+            Self::Variable(v) => {
+                (0b10 << 30)
+                    | ((v.max_voltage_mv as u32 / 50) << 20)
+                    | ((v.min_voltage_mv as u32 / 50) << 10)
+                    | (v.max_current_ma as u32 / 10)
+            }
+            Self::Pps(p) => {
+                (0b11 << 30)
+                    | flag(p.power_limited, 27)
+                    | ((p.max_voltage_mv as u32 / 100) << 17)
+                    | ((p.min_voltage_mv as u32 / 100) << 8)
+                    | (p.max_current_ma as u32 / 50)
+            }
+            Self::Unknown(raw) => *raw,
+        }
+    }
+}
+
+/// Capabilities advertised by a source.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SourceCapabilities {
+    pdos: [u32; MAX_OBJECTS],
+    len: u8,
+    revision: SpecRevision,
+}
+
+impl SourceCapabilities {
+    pub(crate) fn new(pdos: &[u32], revision: SpecRevision) -> Self {
+        let mut caps = Self {
+            pdos: [0; MAX_OBJECTS],
+            len: pdos.len() as u8,
+            revision,
+        };
+        caps.pdos[..pdos.len()].copy_from_slice(pdos);
+        caps
+    }
+
+    /// Iterate over the power data objects, with their object positions, which start at 1.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, PowerDataObject)> + '_ {
+        (1..).zip(
+            self.pdos[..self.len as usize]
+                .iter()
+                .map(|&raw| PowerDataObject::from_raw(raw)),
+        )
+    }
+
+    /// The power data object at `position`, starting at 1.
+    pub fn get(&self, position: u8) -> Option<PowerDataObject> {
+        let index = (position as usize).checked_sub(1)?;
+        self.pdos[..self.len as usize]
+            .get(index)
+            .map(|&raw| PowerDataObject::from_raw(raw))
+    }
+
+    /// The fixed supply with `voltage_mv`, and its object position.
+    pub fn fixed(&self, voltage_mv: u16) -> Option<(u8, FixedSupply)> {
+        self.iter().find_map(|(position, pdo)| match pdo {
+            PowerDataObject::Fixed(f) if f.voltage_mv == voltage_mv => Some((position, f)),
+            _ => None,
+        })
+    }
+
+    /// The specification revision of the source.
+    pub fn revision(&self) -> SpecRevision {
+        self.revision
+    }
+}
+
+/// Request data object, asking a source for one of its supplies.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Request {
+    rdo: u32,
+    pps: bool,
+}
+
+impl Request {
+    /// Request the fixed or variable supply at `position`, drawing up to `current_ma`.
+    pub const fn fixed(position: u8, current_ma: u16) -> Self {
+        let current = (current_ma / 10) as u32;
+        Self {
+            rdo: (position as u32) << 28 | current << 10 | current,
+            pps: false,
+        }
+    }
+
+    /// Request the battery supply at `position`, drawing up to `power_mw`.
+    pub const fn battery(position: u8, power_mw: u32) -> Self {
+        let power = power_mw / 250;
+        Self {
+            rdo: (position as u32) << 28 | power << 10 | power,
+            pps: false,
+        }
+    }
+
+    /// Request `voltage_mv` from the programmable power supply at `position`, drawing up to
+    /// `current_ma`.
+    ///
+    /// The voltage is rounded down to 20 mV, and the current to 50 mA.
+    pub const fn pps(position: u8, voltage_mv: u16, current_ma: u16) -> Self {
+        Self {
+            rdo: (position as u32) << 28 | ((voltage_mv / 20) as u32) << 9 | (current_ma / 50) as u32,
+            pps: true,
+        }
+    }
+
+    /// Tell the source that none of its supplies meet the needs of the sink.
+    pub const fn with_capability_mismatch(self) -> Self {
+        Self {
+            rdo: self.rdo | 1 << 26,
+            pps: self.pps,
+        }
+    }
+
+    /// Tell the source that the sink communicates over USB.
+    pub const fn with_usb_communications(self) -> Self {
+        Self {
+            rdo: self.rdo | 1 << 25,
+            pps: self.pps,
+        }
+    }
+
+    /// Tell the source that the sink draws power while USB is suspended.
+    pub const fn with_no_usb_suspend(self) -> Self {
+        Self {
+            rdo: self.rdo | 1 << 24,
+            pps: self.pps,
+        }
+    }
+
+    /// The object position of the requested supply, starting at 1.
+    pub const fn object_position(&self) -> u8 {
+        (self.rdo >> 28) as u8
+    }
+
+    /// Whether a programmable power supply is requested.
+    pub const fn is_pps(&self) -> bool {
+        self.pps
+    }
+
+    /// Encode the request data object.
+    pub const fn to_raw(&self) -> u32 {
+        self.rdo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_data_objects() {
+        // 5 V 3 A, unconstrained, USB communications and dual-role data.
+        let pdo = PowerDataObject::from_raw(0x0E01_912C);
+        let mut fixed = FixedSupply::new(5000, 3000);
+        fixed.unconstrained_power = true;
+        fixed.usb_communications_capable = true;
+        fixed.dual_role_data = true;
+        assert_eq!(pdo, PowerDataObject::Fixed(fixed));
+        assert_eq!(pdo.to_raw(), 0x0E01_912C);
+
+        // 3.3 V to 11 V, 3 A.
+        let pdo = PowerDataObject::from_raw(0xC0DC_213C);
+        assert_eq!(
+            pdo,
+            PowerDataObject::Pps(Pps {
+                min_voltage_mv: 3300,
+                max_voltage_mv: 11000,
+                max_current_ma: 3000,
+                power_limited: false,
+            })
+        );
+        assert_eq!(pdo.to_raw(), 0xC0DC_213C);
+
+        let variable = PowerDataObject::Variable(VariableSupply {
+            min_voltage_mv: 5000,
+            max_voltage_mv: 12000,
+            max_current_ma: 1500,
+        });
+        assert_eq!(PowerDataObject::from_raw(variable.to_raw()), variable);
+        let battery = PowerDataObject::Battery(Battery {
+            min_voltage_mv: 9000,
+            max_voltage_mv: 20000,
+            max_power_mw: 45000,
+        });
+        assert_eq!(PowerDataObject::from_raw(battery.to_raw()), battery);
+        assert_eq!(
+            PowerDataObject::from_raw(0xD000_0000),
+            PowerDataObject::Unknown(0xD000_0000)
+        );
+    }
+
+    #[test]
+    fn requests() {
+        assert_eq!(Request::fixed(2, 3000).to_raw(), 0x2004_B12C);
+        assert_eq!(Request::fixed(1, 500).with_no_usb_suspend().to_raw(), 0x1100_C832);
+        assert_eq!(Request::battery(3, 15000).to_raw(), 0x3000_F03C);
+        // 9 V is 450 steps of 20 mV, 2 A is 40 steps of 50 mA.
+        let request = Request::pps(5, 9000, 2000);
+        assert_eq!(request.to_raw(), 5 << 28 | 450 << 9 | 40);
+        assert!(request.is_pps());
+        assert_eq!(request.object_position(), 5);
+    }
+
+    #[test]
+    fn messages() {
+        let header = Header::new(SOURCE_CAPABILITIES, 2, 5, SpecRevision::R3_0);
+        assert_eq!(header.0, 0x2A81);
+        let mut data = [0; 10];
+        data[..2].copy_from_slice(&header.0.to_le_bytes());
+        data[2..6].copy_from_slice(&0x0A01_912Cu32.to_le_bytes());
+        data[6..].copy_from_slice(&0x0002_D12Cu32.to_le_bytes());
+        let message = Message::parse(&data).unwrap();
+        assert!(message.is_data(SOURCE_CAPABILITIES));
+        assert_eq!(message.header.message_id(), 5);
+        assert_eq!(message.header.revision(), SpecRevision::R3_0);
+        assert_eq!(message.objects(), [0x0A01_912C, 0x0002_D12C]);
+        assert!(Message::parse(&data[..8]).is_none());
+
+        let caps = SourceCapabilities::new(message.objects(), SpecRevision::R3_0);
+        assert_eq!(caps.fixed(9000), Some((2, FixedSupply::new(9000, 3000))));
+        assert_eq!(caps.get(3), None);
+        assert_eq!(caps.iter().count(), 2);
+    }
+}
@@ -0,0 +1,17 @@
+//! USB Power Delivery.
+//!
+//! A [`Sink`] negotiates power with a USB Power Delivery source, such as a USB-C charger, over a
+//! [`PdPhy`]. The protocol layer acknowledges messages with `GoodCRC`, retries transmissions and
+//! tracks message IDs, while the policy engine requests the supply chosen by a
+//! [`DevicePolicyManager`] from the source capabilities, keeps programmable power supply contracts
+//! alive, and recovers from errors with soft and hard resets.
+//!
+//! Requires the `time` feature.
+
+pub mod message;
+mod protocol;
+mod sink;
+
+pub use sink::{DevicePolicyManager, Error, Sink};
+
+pub use crate::driver::pd::{PdPhy, RxError, TxError};
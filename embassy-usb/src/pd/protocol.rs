@@ -0,0 +1,162 @@
+//! Protocol layer: `GoodCRC` messages, retries and message IDs.
+
+use embassy_time::{with_timeout, Duration};
+
+use super::message::{Header, Message, SpecRevision, GOOD_CRC, MAX_OBJECTS, SOFT_RESET};
+use crate::driver::pd::{PdPhy, RxError, TxError};
+
+/// Time to wait for the `GoodCRC` message acknowledging a transmission (tReceive).
+const T_RECEIVE: Duration = Duration::from_micros(1100);
+
+/// Maximum length of a message, header included.
+const MAX_MESSAGE_LEN: usize = 2 + 4 * MAX_OBJECTS;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum ProtocolError {
+    /// A hard reset was received.
+    HardReset,
+    /// The message was not acknowledged, even after retries.
+    TransmitFailed,
+}
+
+pub(crate) struct Protocol<P> {
+    phy: P,
+    tx_message_id: u8,
+    rx_message_id: Option<u8>,
+    revision: SpecRevision,
+}
+
+impl<P: PdPhy> Protocol<P> {
+    pub fn new(phy: P) -> Self {
+        Self {
+            phy,
+            tx_message_id: 0,
+            rx_message_id: None,
+            revision: SpecRevision::R3_0,
+        }
+    }
+
+    /// Reset the message IDs, on soft and hard resets.
+    pub fn reset(&mut self) {
+        self.tx_message_id = 0;
+        self.rx_message_id = None;
+    }
+
+    pub fn revision(&self) -> SpecRevision {
+        self.revision
+    }
+
+    pub fn set_revision(&mut self, revision: SpecRevision) {
+        self.revision = revision;
+    }
+
+    /// Transmit a control message.
+    pub async fn transmit_control(&mut self, message_type: u8) -> Result<(), ProtocolError> {
+        self.transmit(message_type, &[]).await
+    }
+
+    /// Transmit a message, retrying until it is acknowledged.
+    pub async fn transmit(&mut self, message_type: u8, objects: &[u32]) -> Result<(), ProtocolError> {
+        let header = Header::new(message_type, objects.len(), self.tx_message_id, self.revision);
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        buf[..2].copy_from_slice(&header.0.to_le_bytes());
+        for (data, object) in buf[2..].chunks_exact_mut(4).zip(objects) {
+            data.copy_from_slice(&object.to_le_bytes());
+        }
+        let msg = &buf[..2 + 4 * objects.len()];
+
+        // nRetryCount
+        let retries = match self.revision {
+            SpecRevision::R3_0 => 2,
+            _ => 3,
+        };
+        let mut result = Err(ProtocolError::TransmitFailed);
+        for _ in 0..=retries {
+            match self.phy.transmit(msg).await {
+                Ok(()) => {}
+                Err(TxError::HardReset) => return Err(ProtocolError::HardReset),
+                Err(TxError::Discarded) => {
+                    trace!("pd: transmission discarded");
+                    continue;
+                }
+            }
+            if self.wait_good_crc().await? {
+                result = Ok(());
+                break;
+            }
+            trace!("pd: no GoodCRC for message {}", self.tx_message_id);
+        }
+        self.tx_message_id = (self.tx_message_id + 1) & 0x07;
+        result
+    }
+
+    async fn wait_good_crc(&mut self) -> Result<bool, ProtocolError> {
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        let received = with_timeout(T_RECEIVE, async {
+            loop {
+                match self.phy.receive(&mut buf).await {
+                    Ok(len) => match Message::parse(&buf[..len]) {
+                        Some(msg) if msg.is_control(GOOD_CRC) => return Ok(msg.header.message_id()),
+                        _ => {}
+                    },
+                    Err(RxError::HardReset) => return Err(ProtocolError::HardReset),
+                    Err(_) => {}
+                }
+            }
+        })
+        .await;
+        match received {
+            Ok(message_id) => Ok(message_id? == self.tx_message_id),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Receive a message, acknowledging it and dropping retransmissions.
+    pub async fn receive(&mut self) -> Result<Message, ProtocolError> {
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        loop {
+            let msg = match self.phy.receive(&mut buf).await {
+                Ok(len) => match Message::parse(&buf[..len]) {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                Err(RxError::HardReset) => return Err(ProtocolError::HardReset),
+                Err(e) => {
+                    trace!("pd: receive error {:?}", e);
+                    continue;
+                }
+            };
+            if msg.is_control(GOOD_CRC) {
+                continue;
+            }
+
+            let message_id = msg.header.message_id();
+            let revision = msg.header.revision().min(self.revision);
+            let good_crc = Header::new(GOOD_CRC, 0, message_id, revision);
+            match self.phy.transmit(&good_crc.0.to_le_bytes()).await {
+                Ok(()) => {}
+                Err(TxError::HardReset) => return Err(ProtocolError::HardReset),
+                // The partner retries the message.
+                Err(TxError::Discarded) => continue,
+            }
+
+            if msg.is_control(SOFT_RESET) {
+                self.reset();
+            } else if self.rx_message_id == Some(message_id) {
+                trace!("pd: dropping retransmitted message {}", message_id);
+                continue;
+            }
+            self.rx_message_id = Some(message_id);
+            return Ok(msg);
+        }
+    }
+
+    /// Transmit a hard reset signal, and reset the message IDs.
+    pub async fn hard_reset(&mut self) {
+        if let Err(e) = self.phy.transmit_hard_reset().await {
+            warn!("pd: hard reset failed: {:?}", e);
+        }
+        self.reset();
+    }
+}
@@ -0,0 +1,648 @@
+//! Sink policy engine.
+
+use core::future::pending;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_time::{with_timeout, Duration, Timer};
+
+use super::message::*;
+use super::protocol::{Protocol, ProtocolError};
+use crate::driver::pd::PdPhy;
+
+/// Time to wait for the first `Source_Capabilities` message (tTypeCSinkWaitCap).
+const T_TYPEC_SINK_WAIT_CAP: Duration = Duration::from_millis(620);
+/// Time for the source to restore VBUS after a hard reset (tSafe0V + tSrcRecover + tSrcTurnOn).
+const T_HARD_RESET_RECOVERY: Duration = Duration::from_millis(2000);
+/// Time to wait for the response to a request (tSenderResponse).
+const T_SENDER_RESPONSE: Duration = Duration::from_millis(30);
+/// Time to wait for the `PS_RDY` message after an accepted request (tPSTransition).
+const T_PS_TRANSITION: Duration = Duration::from_millis(550);
+/// Period of the requests keeping a programmable power supply contract (tPPSRequest).
+const T_PPS_REQUEST: Duration = Duration::from_secs(5);
+/// Number of hard resets sent before giving up on the source (nHardResetCount).
+const N_HARD_RESET_COUNT: u8 = 2;
+
+const DEFAULT_SINK_CAPABILITIES: [PowerDataObject; 1] = [PowerDataObject::Fixed(FixedSupply::new(5000, 100))];
+
+/// Error returned by [`Sink::run`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The source did not send its capabilities, even after hard resets.
+    SourceNotResponding,
+}
+
+/// Device policy manager, choosing the power drawn by the sink.
+pub trait DevicePolicyManager {
+    /// Choose the supply to request from the source capabilities.
+    ///
+    /// Object position 1 is always the 5 V fixed supply.
+    fn request(&mut self, capabilities: &SourceCapabilities) -> Request;
+
+    /// Called when the source is ready to supply the accepted `request`.
+    async fn transition_power(&mut self, _request: &Request) {}
+
+    /// Called when a hard reset is sent or received, the source returning to 5 V.
+    fn hard_reset(&mut self) {}
+
+    /// Capabilities of the sink, returned to `Get_Sink_Cap` messages.
+    ///
+    /// The first one must be a 5 V fixed supply. Defaults to 5 V at 100 mA.
+    fn sink_capabilities(&self) -> &[PowerDataObject] {
+        &DEFAULT_SINK_CAPABILITIES
+    }
+
+    /// Wait until the supply must be requested again, with a new call to [`request`](Self::request).
+    async fn wait_renegotiation(&mut self) {
+        pending().await
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum State {
+    WaitForCapabilities(Duration),
+    SelectCapability(Request),
+    TransitionSink(Request),
+    Ready,
+    SendNotSupported,
+    SoftResetReceived,
+    SendSoftReset,
+    HardReset,
+    TransitionToDefault,
+}
+
+/// USB Power Delivery sink.
+///
+/// Negotiates power with the source through the policy of a [`DevicePolicyManager`].
+pub struct Sink<P: PdPhy> {
+    protocol: Protocol<P>,
+    capabilities: Option<SourceCapabilities>,
+    contract: Option<Request>,
+    hard_reset_count: u8,
+}
+
+impl<P: PdPhy> Sink<P> {
+    /// Create a new sink, to run once the port is attached to a source.
+    pub fn new(phy: P) -> Self {
+        Self {
+            protocol: Protocol::new(phy),
+            capabilities: None,
+            contract: None,
+            hard_reset_count: 0,
+        }
+    }
+
+    /// The last capabilities received from the source.
+    pub fn source_capabilities(&self) -> Option<&SourceCapabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// The request accepted by the source.
+    pub fn contract(&self) -> Option<Request> {
+        self.contract
+    }
+
+    /// Run the policy engine.
+    ///
+    /// Returns when the source does not communicate, which is the case of sources without Power
+    /// Delivery support. The sink may then draw the current advertised on the CC lines.
+    pub async fn run<D: DevicePolicyManager>(&mut self, dpm: &mut D) -> Error {
+        self.protocol.reset();
+        self.capabilities = None;
+        self.contract = None;
+        self.hard_reset_count = 0;
+
+        let mut state = State::WaitForCapabilities(T_TYPEC_SINK_WAIT_CAP);
+        loop {
+            trace!("pd: {:?}", state);
+            state = match self.step(state, dpm).await {
+                Ok(Some(next)) => next,
+                Ok(None) => return Error::SourceNotResponding,
+                Err(ProtocolError::HardReset) => {
+                    debug!("pd: hard reset received");
+                    State::TransitionToDefault
+                }
+                Err(ProtocolError::TransmitFailed) => match state {
+                    State::SendSoftReset | State::SoftResetReceived => State::HardReset,
+                    _ => State::SendSoftReset,
+                },
+            };
+        }
+    }
+
+    async fn step<D: DevicePolicyManager>(
+        &mut self,
+        state: State,
+        dpm: &mut D,
+    ) -> Result<Option<State>, ProtocolError> {
+        let next = match state {
+            State::WaitForCapabilities(timeout) => match with_timeout(timeout, self.protocol.receive()).await {
+                Err(_) => State::HardReset,
+                Ok(msg) => {
+                    let msg = msg?;
+                    if msg.is_data(SOURCE_CAPABILITIES) {
+                        self.evaluate_capabilities(&msg, dpm)
+                    } else if msg.is_control(SOFT_RESET) {
+                        State::SoftResetReceived
+                    } else {
+                        State::WaitForCapabilities(timeout)
+                    }
+                }
+            },
+            State::SelectCapability(request) => {
+                self.protocol.transmit(REQUEST, &[request.to_raw()]).await?;
+                match with_timeout(T_SENDER_RESPONSE, self.protocol.receive()).await {
+                    Err(_) => State::HardReset,
+                    Ok(msg) => {
+                        let msg = msg?;
+                        if msg.is_control(ACCEPT) {
+                            State::TransitionSink(request)
+                        } else if msg.is_control(REJECT) || msg.is_control(WAIT) {
+                            warn!("pd: request rejected");
+                            match self.contract {
+                                Some(_) => State::Ready,
+                                None => State::WaitForCapabilities(T_TYPEC_SINK_WAIT_CAP),
+                            }
+                        } else if msg.is_control(SOFT_RESET) {
+                            State::SoftResetReceived
+                        } else {
+                            State::SendSoftReset
+                        }
+                    }
+                }
+            }
+            State::TransitionSink(request) => match with_timeout(T_PS_TRANSITION, self.protocol.receive()).await {
+                Ok(msg) if msg?.is_control(PS_RDY) => {
+                    debug!("pd: contract {:?}", request);
+                    self.contract = Some(request);
+                    self.hard_reset_count = 0;
+                    dpm.transition_power(&request).await;
+                    State::Ready
+                }
+                _ => State::HardReset,
+            },
+            State::Ready => {
+                let pps_request = match self.contract {
+                    Some(contract) if contract.is_pps() => Some(contract),
+                    _ => None,
+                };
+                let pps_timer = async {
+                    match pps_request {
+                        Some(_) => Timer::after(T_PPS_REQUEST).await,
+                        None => pending().await,
+                    }
+                };
+                match select3(self.protocol.receive(), dpm.wait_renegotiation(), pps_timer).await {
+                    Either3::First(msg) => self.ready_message(&msg?, dpm).await?,
+                    Either3::Second(()) => match &self.capabilities {
+                        Some(capabilities) => State::SelectCapability(dpm.request(capabilities)),
+                        None => State::Ready,
+                    },
+                    Either3::Third(()) => State::SelectCapability(unwrap!(pps_request)),
+                }
+            }
+            State::SendNotSupported => {
+                match self.protocol.revision() {
+                    SpecRevision::R3_0 => self.protocol.transmit_control(NOT_SUPPORTED).await?,
+                    _ => self.protocol.transmit_control(REJECT).await?,
+                }
+                State::Ready
+            }
+            State::SoftResetReceived => {
+                self.protocol.transmit_control(ACCEPT).await?;
+                State::WaitForCapabilities(T_TYPEC_SINK_WAIT_CAP)
+            }
+            State::SendSoftReset => {
+                self.protocol.reset();
+                self.protocol.transmit_control(SOFT_RESET).await?;
+                match with_timeout(T_SENDER_RESPONSE, self.protocol.receive()).await {
+                    Ok(msg) if msg?.is_control(ACCEPT) => State::WaitForCapabilities(T_TYPEC_SINK_WAIT_CAP),
+                    _ => State::HardReset,
+                }
+            }
+            State::HardReset => {
+                if self.hard_reset_count >= N_HARD_RESET_COUNT {
+                    return Ok(None);
+                }
+                self.hard_reset_count += 1;
+                debug!("pd: sending hard reset");
+                self.protocol.hard_reset().await;
+                State::TransitionToDefault
+            }
+            State::TransitionToDefault => {
+                self.protocol.reset();
+                self.capabilities = None;
+                self.contract = None;
+                dpm.hard_reset();
+                State::WaitForCapabilities(T_HARD_RESET_RECOVERY + T_TYPEC_SINK_WAIT_CAP)
+            }
+        };
+        Ok(Some(next))
+    }
+
+    fn evaluate_capabilities<D: DevicePolicyManager>(&mut self, msg: &Message, dpm: &mut D) -> State {
+        let revision = msg.header.revision().min(SpecRevision::R3_0);
+        self.protocol.set_revision(revision);
+        let capabilities = SourceCapabilities::new(msg.objects(), revision);
+        let request = dpm.request(&capabilities);
+        self.capabilities = Some(capabilities);
+        State::SelectCapability(request)
+    }
+
+    async fn ready_message<D: DevicePolicyManager>(
+        &mut self,
+        msg: &Message,
+        dpm: &mut D,
+    ) -> Result<State, ProtocolError> {
+        let state = if msg.is_data(SOURCE_CAPABILITIES) {
+            self.evaluate_capabilities(msg, dpm)
+        } else if msg.is_control(GET_SINK_CAP) {
+            let mut objects = [0; MAX_OBJECTS];
+            let pdos = dpm.sink_capabilities();
+            for (object, pdo) in objects.iter_mut().zip(pdos) {
+                *object = pdo.to_raw();
+            }
+            let len = pdos.len().min(MAX_OBJECTS);
+            self.protocol.transmit(SINK_CAPABILITIES, &objects[..len]).await?;
+            State::Ready
+        } else if msg.is_control(PING) {
+            State::Ready
+        } else if msg.is_control(SOFT_RESET) {
+            State::SoftResetReceived
+        } else if msg.is_control(ACCEPT) || msg.is_control(REJECT) || msg.is_control(WAIT) || msg.is_control(PS_RDY) {
+            // Unexpected messages of the power negotiation.
+            State::SendSoftReset
+        } else if msg.is_data(VENDOR_DEFINED) && self.protocol.revision() < SpecRevision::R3_0 {
+            // Unstructured vendor defined messages are ignored by revision 2.0 ports.
+            State::Ready
+        } else {
+            State::SendNotSupported
+        };
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::channel::Channel;
+    use embassy_sync::signal::Signal;
+    use embassy_time::Instant;
+
+    use super::*;
+    use crate::driver::pd::{RxError, TxError};
+
+    enum Event {
+        Message(Vec<u8>),
+        HardReset,
+    }
+
+    type Line = Channel<NoopRawMutex, Event, 16>;
+
+    /// PHY of the sink, connected to the simulated source.
+    struct Phy<'a> {
+        rx: &'a Line,
+        tx: &'a Line,
+    }
+
+    impl PdPhy for Phy<'_> {
+        async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, RxError> {
+            match self.rx.receive().await {
+                Event::Message(msg) => {
+                    buf[..msg.len()].copy_from_slice(&msg);
+                    Ok(msg.len())
+                }
+                Event::HardReset => Err(RxError::HardReset),
+            }
+        }
+
+        async fn transmit(&mut self, msg: &[u8]) -> Result<(), TxError> {
+            self.tx.send(Event::Message(msg.to_vec())).await;
+            Ok(())
+        }
+
+        async fn transmit_hard_reset(&mut self) -> Result<(), TxError> {
+            self.tx.send(Event::HardReset).await;
+            Ok(())
+        }
+    }
+
+    /// Simulated source, with its own protocol layer.
+    struct Source<'a> {
+        rx: &'a Line,
+        tx: &'a Line,
+        message_id: u8,
+    }
+
+    impl Source<'_> {
+        async fn transmit(&mut self, message_type: u8, objects: &[u32], message_id: u8) {
+            let header = Header::new(message_type, objects.len(), message_id, SpecRevision::R3_0);
+            let mut msg = header.0.to_le_bytes().to_vec();
+            for object in objects {
+                msg.extend_from_slice(&object.to_le_bytes());
+            }
+            self.tx.send(Event::Message(msg)).await;
+        }
+
+        /// Send a message with `message_id`, and check its acknowledgement.
+        async fn send_with_id(&mut self, message_type: u8, objects: &[u32], message_id: u8) {
+            self.transmit(message_type, objects, message_id).await;
+            let good_crc = self.next().await;
+            assert!(good_crc.is_control(GOOD_CRC));
+            assert_eq!(good_crc.header.message_id(), message_id);
+        }
+
+        async fn send(&mut self, message_type: u8, objects: &[u32]) {
+            self.send_with_id(message_type, objects, self.message_id).await;
+            self.message_id = (self.message_id + 1) & 0x07;
+        }
+
+        async fn next_event(&mut self) -> Event {
+            unwrap!(with_timeout(T_PPS_REQUEST * 2, self.rx.receive()).await)
+        }
+
+        /// Next message of the sink, without acknowledging it.
+        async fn next(&mut self) -> Message {
+            match self.next_event().await {
+                Event::Message(msg) => unwrap!(Message::parse(&msg)),
+                Event::HardReset => panic!("unexpected hard reset"),
+            }
+        }
+
+        async fn ack(&mut self, msg: &Message) {
+            self.transmit(GOOD_CRC, &[], msg.header.message_id()).await;
+        }
+
+        async fn receive(&mut self) -> Message {
+            let msg = self.next().await;
+            self.ack(&msg).await;
+            msg
+        }
+
+        async fn expect_hard_reset(&mut self) {
+            assert!(matches!(self.next_event().await, Event::HardReset));
+            self.message_id = 0;
+        }
+
+        /// Send the capabilities, accept the request and return it.
+        async fn negotiate(&mut self, capabilities: &[u32]) -> u32 {
+            self.send(SOURCE_CAPABILITIES, capabilities).await;
+            let request = self.receive().await;
+            assert!(request.is_data(REQUEST));
+            self.send(ACCEPT, &[]).await;
+            self.send(PS_RDY, &[]).await;
+            request.objects()[0]
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    enum Policy {
+        Fixed(u16),
+        Pps(u16, u16),
+    }
+
+    struct Dpm<'a> {
+        policy: Policy,
+        renegotiate: &'a Signal<NoopRawMutex, Policy>,
+        contracts: Vec<Request>,
+        hard_resets: usize,
+    }
+
+    impl DevicePolicyManager for Dpm<'_> {
+        fn request(&mut self, capabilities: &SourceCapabilities) -> Request {
+            let vsafe5v = Request::fixed(1, 3000);
+            match self.policy {
+                Policy::Fixed(voltage_mv) => match capabilities.fixed(voltage_mv) {
+                    Some((position, supply)) => Request::fixed(position, supply.max_current_ma),
+                    None => vsafe5v,
+                },
+                Policy::Pps(voltage_mv, current_ma) => capabilities
+                    .iter()
+                    .find(|(_, pdo)| matches!(pdo, PowerDataObject::Pps(_)))
+                    .map_or(vsafe5v, |(position, _)| Request::pps(position, voltage_mv, current_ma)),
+            }
+        }
+
+        async fn transition_power(&mut self, request: &Request) {
+            self.contracts.push(*request);
+        }
+
+        fn hard_reset(&mut self) {
+            self.hard_resets += 1;
+        }
+
+        async fn wait_renegotiation(&mut self) {
+            self.policy = self.renegotiate.wait().await;
+        }
+    }
+
+    const CAPABILITIES: [u32; 4] = [
+        0x0A01_912C, // 5 V, 3 A
+        0x0002_D12C, // 9 V, 3 A
+        0x0004_B12C, // 15 V, 3 A
+        0xC0DC_213C, // 3.3 V to 11 V, 3 A
+    ];
+
+    /// Outcome of a simulation: the contracts and hard resets seen by the device policy manager,
+    /// and the error returned by the sink.
+    struct Outcome {
+        contracts: Vec<Request>,
+        hard_resets: usize,
+        error: Option<Error>,
+    }
+
+    /// Run a sink against the source `script`, until the script ends or the sink gives up.
+    fn simulate(policy: Policy, script: impl AsyncFnOnce(&mut Source<'_>, &Signal<NoopRawMutex, Policy>)) -> Outcome {
+        let to_sink = Line::new();
+        let to_source = Line::new();
+        let renegotiate = Signal::new();
+        let mut sink = Sink::new(Phy {
+            rx: &to_sink,
+            tx: &to_source,
+        });
+        let mut source = Source {
+            rx: &to_source,
+            tx: &to_sink,
+            message_id: 0,
+        };
+        let mut dpm = Dpm {
+            policy,
+            renegotiate: &renegotiate,
+            contracts: Vec::new(),
+            hard_resets: 0,
+        };
+        let result = block_on(select(sink.run(&mut dpm), async {
+            script(&mut source, &renegotiate).await;
+            // The sink has nothing left to say.
+            assert!(to_source.try_receive().is_err());
+        }));
+        Outcome {
+            contracts: dpm.contracts,
+            hard_resets: dpm.hard_resets,
+            error: match result {
+                Either::First(error) => Some(error),
+                Either::Second(()) => None,
+            },
+        }
+    }
+
+    #[test]
+    fn negotiate_fixed() {
+        let outcome = simulate(Policy::Fixed(9000), async |source, _| {
+            let request = source.negotiate(&CAPABILITIES).await;
+            assert_eq!(request, Request::fixed(2, 3000).to_raw());
+
+            source.send(GET_SINK_CAP, &[]).await;
+            let sink_capabilities = source.receive().await;
+            assert!(sink_capabilities.is_data(SINK_CAPABILITIES));
+            assert_eq!(sink_capabilities.objects(), [0x0001_900A]);
+
+            // Ping is ignored, and DR_Swap is not supported.
+            source.send(PING, &[]).await;
+            source.send(0x09, &[]).await;
+            assert!(source.receive().await.is_control(NOT_SUPPORTED));
+
+            // New capabilities without 9 V.
+            let request = source.negotiate(&[CAPABILITIES[0], CAPABILITIES[2]]).await;
+            assert_eq!(request, Request::fixed(1, 3000).to_raw());
+        });
+        assert_eq!(outcome.error, None);
+        assert_eq!(outcome.contracts, [Request::fixed(2, 3000), Request::fixed(1, 3000)]);
+        assert_eq!(outcome.hard_resets, 0);
+    }
+
+    #[test]
+    fn retries_and_duplicates() {
+        let outcome = simulate(Policy::Fixed(15000), async |source, _| {
+            source.send(SOURCE_CAPABILITIES, &CAPABILITIES).await;
+
+            // The request is retried with the same message ID until acknowledged.
+            let request = source.next().await;
+            let retry = source.next().await;
+            assert!(request.is_data(REQUEST));
+            assert_eq!(retry.header, request.header);
+            assert_eq!(retry.objects(), [Request::fixed(3, 3000).to_raw()]);
+            source.ack(&retry).await;
+
+            // A retransmitted Accept is acknowledged and dropped.
+            source.send_with_id(ACCEPT, &[], 1).await;
+            source.send_with_id(ACCEPT, &[], 1).await;
+            source.message_id = 2;
+            source.send(PS_RDY, &[]).await;
+
+            source.send(GET_SINK_CAP, &[]).await;
+            let sink_capabilities = source.receive().await;
+            assert_eq!(sink_capabilities.header.message_id(), 1);
+        });
+        assert_eq!(outcome.error, None);
+        assert_eq!(outcome.contracts, [Request::fixed(3, 3000)]);
+    }
+
+    #[test]
+    fn soft_reset() {
+        let outcome = simulate(Policy::Fixed(9000), async |source, _| {
+            // Requests are never acknowledged: the sink soft resets.
+            source.send(SOURCE_CAPABILITIES, &CAPABILITIES).await;
+            for _ in 0..3 {
+                assert!(source.next().await.is_data(REQUEST));
+            }
+            let soft_reset = source.receive().await;
+            assert!(soft_reset.is_control(SOFT_RESET));
+            assert_eq!(soft_reset.header.message_id(), 0);
+            source.message_id = 0;
+            source.send(ACCEPT, &[]).await;
+            source.negotiate(&CAPABILITIES).await;
+
+            // Soft reset from the source.
+            source.message_id = 0;
+            source.send(SOFT_RESET, &[]).await;
+            let accept = source.receive().await;
+            assert!(accept.is_control(ACCEPT));
+            assert_eq!(accept.header.message_id(), 0);
+            source.negotiate(&CAPABILITIES).await;
+
+            // Unexpected message in the ready state.
+            source.send(PS_RDY, &[]).await;
+            assert!(source.receive().await.is_control(SOFT_RESET));
+            source.message_id = 0;
+            source.send(ACCEPT, &[]).await;
+            source.negotiate(&CAPABILITIES).await;
+        });
+        assert_eq!(outcome.error, None);
+        assert_eq!(outcome.contracts, [Request::fixed(2, 3000); 3]);
+        assert_eq!(outcome.hard_resets, 0);
+    }
+
+    #[test]
+    fn pps() {
+        let outcome = simulate(Policy::Pps(9000, 2000), async |source, renegotiate| {
+            let request = source.negotiate(&CAPABILITIES).await;
+            assert_eq!(request, Request::pps(4, 9000, 2000).to_raw());
+
+            // The request is repeated to keep the contract.
+            let start = Instant::now();
+            let request = source.receive().await;
+            assert!(start.elapsed() >= T_PPS_REQUEST - Duration::from_millis(100));
+            assert_eq!(request.objects(), [Request::pps(4, 9000, 2000).to_raw()]);
+            source.send(ACCEPT, &[]).await;
+            source.send(PS_RDY, &[]).await;
+
+            renegotiate.signal(Policy::Fixed(5000));
+            let request = source.receive().await;
+            assert_eq!(request.objects(), [Request::fixed(1, 3000).to_raw()]);
+            source.send(ACCEPT, &[]).await;
+            source.send(PS_RDY, &[]).await;
+        });
+        assert_eq!(outcome.error, None);
+        assert_eq!(
+            outcome.contracts,
+            [
+                Request::pps(4, 9000, 2000),
+                Request::pps(4, 9000, 2000),
+                Request::fixed(1, 3000)
+            ]
+        );
+    }
+
+    #[test]
+    fn hard_reset() {
+        let outcome = simulate(Policy::Fixed(9000), async |source, _| {
+            source.negotiate(&CAPABILITIES).await;
+
+            // Hard reset from the source.
+            source.tx.send(Event::HardReset).await;
+            source.message_id = 0;
+            source.send(SOURCE_CAPABILITIES, &CAPABILITIES).await;
+            assert_eq!(source.receive().await.header.message_id(), 0);
+
+            // No PS_RDY after the Accept: the sink hard resets.
+            source.send(ACCEPT, &[]).await;
+            source.expect_hard_reset().await;
+            source.negotiate(&CAPABILITIES).await;
+        });
+        assert_eq!(outcome.error, None);
+        assert_eq!(outcome.contracts, [Request::fixed(2, 3000); 2]);
+        assert_eq!(outcome.hard_resets, 2);
+    }
+
+    #[test]
+    fn source_not_responding() {
+        let outcome = simulate(Policy::Fixed(9000), async |source, _| {
+            let start = Instant::now();
+            source.expect_hard_reset().await;
+            assert!(start.elapsed() >= T_TYPEC_SINK_WAIT_CAP);
+            source.expect_hard_reset().await;
+            core::future::pending::<()>().await;
+        });
+        assert_eq!(outcome.error, Some(Error::SourceNotResponding));
+        assert!(outcome.contracts.is_empty());
+        assert_eq!(outcome.hard_resets, 2);
+    }
+}
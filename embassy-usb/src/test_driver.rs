@@ -0,0 +1,194 @@
+//! A driver for unit tests, which hands out endpoints and records the endpoint state set by the stack.
+//!
+//! OUT endpoints read the packets queued by the test, and IN endpoints collect the packets written
+//! to them. The control pipe doesn't transfer any data.
+extern crate std;
+
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use crate::driver::{
+    Bus, ControlPipe, Direction, Driver, Endpoint, EndpointAddress, EndpointAllocError, EndpointError, EndpointIn,
+    EndpointInfo, EndpointOut, EndpointType, Event, Unsupported,
+};
+
+/// Allocates endpoint numbers in order, from 1 for each direction.
+#[derive(Default)]
+pub(crate) struct TestDriver {
+    next_out: usize,
+    next_in: usize,
+}
+
+impl TestDriver {
+    fn alloc(
+        next: &mut usize,
+        direction: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> TestEndpoint {
+        *next += 1;
+        TestEndpoint::new(
+            EndpointAddress::from_parts(*next, direction),
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        )
+    }
+}
+
+impl<'d> Driver<'d> for TestDriver {
+    type EndpointOut = TestEndpoint;
+    type EndpointIn = TestEndpoint;
+    type ControlPipe = TestControlPipe;
+    type Bus = TestBus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        _ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<TestEndpoint, EndpointAllocError> {
+        Ok(Self::alloc(
+            &mut self.next_out,
+            Direction::Out,
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        ))
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        _ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<TestEndpoint, EndpointAllocError> {
+        Ok(Self::alloc(
+            &mut self.next_in,
+            Direction::In,
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        ))
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (TestBus, TestControlPipe) {
+        (
+            TestBus { enabled: Vec::new() },
+            TestControlPipe {
+                max_packet_size: control_max_packet_size as usize,
+            },
+        )
+    }
+}
+
+pub(crate) struct TestEndpoint {
+    info: EndpointInfo,
+    /// Packets to read from an OUT endpoint. Reads fail with `Disabled` once there are none left.
+    pub(crate) packets: VecDeque<Vec<u8>>,
+    /// Packets written to an IN endpoint.
+    pub(crate) written: Vec<Vec<u8>>,
+}
+
+impl TestEndpoint {
+    pub(crate) fn new(addr: EndpointAddress, ep_type: EndpointType, max_packet_size: u16, interval_ms: u8) -> Self {
+        Self {
+            info: EndpointInfo {
+                addr,
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+            packets: VecDeque::new(),
+            written: Vec::new(),
+        }
+    }
+}
+
+impl Endpoint for TestEndpoint {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl EndpointOut for TestEndpoint {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let packet = self.packets.pop_front().ok_or(EndpointError::Disabled)?;
+        assert!(packet.len() <= self.info.max_packet_size as usize);
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+}
+
+impl EndpointIn for TestEndpoint {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        assert!(buf.len() <= self.info.max_packet_size as usize);
+        self.written.push(buf.to_vec());
+        Ok(())
+    }
+}
+
+pub(crate) struct TestBus {
+    /// The endpoints currently enabled by the stack.
+    pub(crate) enabled: Vec<EndpointAddress>,
+}
+
+impl Bus for TestBus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        core::future::pending().await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        self.enabled.retain(|&addr| addr != ep_addr);
+        if enabled {
+            self.enabled.push(ep_addr);
+        }
+    }
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+pub(crate) struct TestControlPipe {
+    max_packet_size: usize,
+}
+
+impl ControlPipe for TestControlPipe {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        core::future::pending().await
+    }
+
+    async fn data_out(&mut self, _buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+
+    async fn data_in(&mut self, _data: &[u8], _first: bool, _last: bool) -> Result<(), EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+
+    async fn accept(&mut self) {}
+
+    async fn reject(&mut self) {}
+
+    async fn accept_set_address(&mut self, _addr: u8) {}
+}
@@ -0,0 +1,149 @@
+//! This example shows how to build a HID report descriptor with several report IDs, here a
+//! keyboard with media keys, and how to decode output reports with its layout.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::{Input, Pull};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_usb::class::hid::descriptor::{usage, usage_page, ReportDescriptor};
+use embassy_usb::class::hid::devices::{consumer_control, keyboard_nkro, ConsumerControlReport, KeyboardReport};
+use embassy_usb::class::hid::layout::{extended_usage, ReportLayout};
+use embassy_usb::class::hid::{HidReaderWriter, HidWriter, ReadError, State};
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+const KEYBOARD_ID: u8 = 1;
+const CONSUMER_ID: u8 = 2;
+
+static REPORT_DESCRIPTOR: ReportDescriptor<80> =
+    consumer_control(keyboard_nkro(ReportDescriptor::new(), KEYBOARD_ID), CONSUMER_ID);
+
+/// Play/Pause usage of the Consumer page.
+const PLAY_PAUSE: u16 = 0xCD;
+/// A key usage of the Keyboard page.
+const KEY_A: u8 = 0x04;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HID composite example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let config = embassy_usb::class::hid::Config {
+        report_descriptor: REPORT_DESCRIPTOR.as_bytes(),
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: 64,
+    };
+    let hid = HidReaderWriter::<_, 2, 32>::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Set up the signal pin that will be used to trigger the keyboard.
+    let mut signal_pin = Input::new(p.PIN_16, Pull::None);
+
+    // Enable the schmitt trigger to slightly debounce.
+    signal_pin.set_schmitt(true);
+
+    let (mut reader, mut writer) = hid.split();
+
+    // Do stuff with the class!
+    let in_fut = async {
+        let mut keyboard = KeyboardReport::default();
+        let mut consumer = ConsumerControlReport::default();
+        loop {
+            info!("Waiting for HIGH on pin 16");
+            signal_pin.wait_for_high().await;
+            info!("HIGH DETECTED, pressing A and Play/Pause");
+            keyboard.set_key(KEY_A, true);
+            consumer.usages[0] = PLAY_PAUSE;
+            send(&mut writer, &keyboard, &consumer).await;
+
+            signal_pin.wait_for_low().await;
+            info!("LOW DETECTED, releasing");
+            keyboard.set_key(KEY_A, false);
+            consumer.usages[0] = 0;
+            send(&mut writer, &keyboard, &consumer).await;
+        }
+    };
+
+    let out_fut = async {
+        // Derive the report layouts from the descriptor, to decode the keyboard LEDs.
+        let layout = unwrap!(ReportLayout::<16>::parse(REPORT_DESCRIPTOR.as_bytes()));
+        let caps_lock = extended_usage(usage_page::LED, usage::led::CAPS_LOCK);
+        loop {
+            let mut buf = [0; 2];
+            match reader.read_report(&layout, &mut buf).await {
+                Ok(report) => info!("Caps Lock: {}", report.value(caps_lock) == Some(1)),
+                Err(ReadError::Disabled) => reader.ready().await,
+                Err(e) => warn!("Failed to read report: {:?}", e),
+            }
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, join(in_fut, out_fut)).await;
+}
+
+/// Sends both input reports, each prefixed with its report ID.
+async fn send<'d>(
+    writer: &mut HidWriter<'d, Driver<'d, USB>, 32>,
+    keyboard: &KeyboardReport,
+    consumer: &ConsumerControlReport,
+) {
+    let mut buf = [0; 32];
+
+    buf[0] = KEYBOARD_ID;
+    buf[1..1 + KeyboardReport::LEN].copy_from_slice(&keyboard.to_bytes());
+    if let Err(e) = writer.write(&buf[..1 + KeyboardReport::LEN]).await {
+        warn!("Failed to send report: {:?}", e);
+    }
+
+    buf[0] = CONSUMER_ID;
+    buf[1..1 + ConsumerControlReport::LEN].copy_from_slice(&consumer.to_bytes());
+    if let Err(e) = writer.write(&buf[..1 + ConsumerControlReport::LEN]).await {
+        warn!("Failed to send report: {:?}", e);
+    }
+}
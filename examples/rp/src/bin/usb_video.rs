@@ -0,0 +1,139 @@
+//! This example shows how to use the USB Video Class to stream frames to a PC, here color bars
+//! with a moving line, which show up in webcam applications.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_time::{Duration, Ticker};
+use embassy_usb::class::uvc::{self, Format, Frame, State, Transport, VideoFormat, VideoStream};
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 120;
+
+/// 10 frames per second, in units of 100 ns.
+const FRAME_INTERVAL: u32 = 1_000_000;
+
+static FRAMES: [Frame; 1] = [Frame {
+    width: WIDTH as u16,
+    height: HEIGHT as u16,
+    intervals: &[FRAME_INTERVAL],
+}];
+
+static FORMATS: [VideoFormat; 1] = [VideoFormat {
+    format: Format::Yuy2,
+    frames: &FRAMES,
+}];
+
+/// YUV colors of the bars: white, yellow, cyan, green, magenta, red, blue and black.
+const BARS: [(u8, u8, u8); 8] = [
+    (235, 128, 128),
+    (210, 16, 146),
+    (170, 166, 16),
+    (145, 54, 34),
+    (106, 202, 222),
+    (81, 90, 240),
+    (41, 240, 110),
+    (16, 128, 128),
+];
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB video example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let config = uvc::Config {
+        formats: &FORMATS,
+        transport: Transport::Bulk,
+    };
+    let mut stream = VideoStream::<_, 64>::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Do stuff with the class!
+    let video_fut = async {
+        let mut line = [0; WIDTH * 2];
+        let mut position = 0;
+        loop {
+            let settings = stream.wait_streaming().await;
+            info!("Streaming {}x{}", settings.width, settings.height);
+            let mut ticker = Ticker::every(Duration::from_micros(FRAME_INTERVAL as u64 / 10));
+            'stream: loop {
+                // Frames are written line by line, there's no need for a frame buffer.
+                let mut frame = stream.begin_frame();
+                for y in 0..HEIGHT {
+                    color_bars(&mut line, y == position);
+                    if let Err(e) = frame.write(&line).await {
+                        info!("Stream ended: {:?}", e);
+                        break 'stream;
+                    }
+                }
+                if let Err(e) = frame.finish().await {
+                    info!("Stream ended: {:?}", e);
+                    break;
+                }
+                position = (position + 1) % HEIGHT;
+                ticker.next().await;
+            }
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, video_fut).await;
+}
+
+/// Fills a line of YUY2 pixels with color bars, or white.
+fn color_bars(line: &mut [u8; WIDTH * 2], white: bool) {
+    // Each group of 4 bytes holds two pixels, Y0 U Y1 V.
+    for (i, pixels) in line.chunks_exact_mut(4).enumerate() {
+        let (y, u, v) = match white {
+            true => BARS[0],
+            false => BARS[i * 2 * BARS.len() / WIDTH],
+        };
+        pixels.copy_from_slice(&[y, u, y, v]);
+    }
+}
@@ -0,0 +1,93 @@
+//! This example creates a virtual USB serial port that echos, exported over USB/IP.
+//!
+//! Attach it with:
+//!
+//! ```sh
+//! sudo modprobe vhci-hcd
+//! sudo usbip attach -r 127.0.0.1 -b 1-1
+//! ```
+//!
+//! It then shows up as `/dev/ttyACM0`.
+
+use std::net::TcpListener;
+
+use embassy_executor::{Executor, Spawner};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::UsbDevice;
+use embassy_usb_usbip::{Driver, Server, USBIP_PORT};
+use log::*;
+use static_cell::StaticCell;
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: UsbDevice<'static, Driver>) -> ! {
+    usb.run().await
+}
+
+#[embassy_executor::task]
+async fn usbip_task(mut server: Server) {
+    let listener = TcpListener::bind(("127.0.0.1", USBIP_PORT)).unwrap();
+    let e = server.run(listener).await;
+    error!("USB/IP server failed: {}", e);
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let (driver, server) = embassy_usb_usbip::new(Default::default());
+
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB/IP serial example");
+    config.serial_number = Some("12345678");
+    config.max_packet_size_0 = 64;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    let mut builder = embassy_usb::Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [], // no msos descriptors
+        CONTROL_BUF.init([0; 64]),
+    );
+
+    static STATE: StaticCell<State> = StaticCell::new();
+    let mut class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), 64);
+    let usb = builder.build();
+
+    spawner.spawn(usb_task(usb)).unwrap();
+    spawner.spawn(usbip_task(server)).unwrap();
+
+    loop {
+        class.wait_connection().await;
+        info!("Connected");
+        let _ = echo(&mut class).await;
+        info!("Disconnected");
+    }
+}
+
+async fn echo(class: &mut CdcAcmClass<'static, Driver>) -> Result<(), EndpointError> {
+    let mut buf = [0; 64];
+    loop {
+        let n = class.read_packet(&mut buf).await?;
+        info!("data: {:x?}", &buf[..n]);
+        class.write_packet(&buf[..n]).await?;
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
@@ -0,0 +1,234 @@
+#![no_std]
+#![no_main]
+
+use defmt::{panic, *};
+use embassy_executor::Spawner;
+use embassy_futures::join::join4;
+use embassy_stm32::time::Hertz;
+use embassy_stm32::{bind_interrupts, peripherals, usb, Config};
+use embassy_usb::class::uac2::headset::Headset;
+use embassy_usb::class::uac2::{self, CaptureStream, ControlMonitor, Feedback, PlaybackStream, StreamConfig};
+use embassy_usb::driver::EndpointError;
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    OTG_FS => usb::InterruptHandler<peripherals::USB_OTG_FS>;
+});
+
+type UsbDriver = usb::Driver<'static, peripherals::USB_OTG_FS>;
+
+// The sample rates that the host can select. The first one is the default.
+const SAMPLE_RATES_HZ: [u32; 2] = [48_000, 32_000];
+const MAX_SAMPLE_RATE_HZ: usize = 48_000;
+
+// 16 bit samples, stereo headphones and a mono microphone.
+const SAMPLE_WIDTH: uac2::SampleWidth = uac2::SampleWidth::Width2Byte;
+const SAMPLE_SIZE: usize = SAMPLE_WIDTH as usize;
+const PLAYBACK_CHANNELS: [uac2::Channel; 2] = [uac2::Channel::FrontLeft, uac2::Channel::FrontRight];
+const CAPTURE_CHANNELS: [uac2::Channel; 1] = [uac2::Channel::FrontCenter];
+
+// Size of audio samples per 1 ms - for the full-speed USB frame period of 1 ms - with room for one extra sample,
+// as the host sends more or less samples per frame depending on the feedback.
+const PLAYBACK_PACKET_SIZE: usize = (MAX_SAMPLE_RATE_HZ / 1000 + 1) * PLAYBACK_CHANNELS.len() * SAMPLE_SIZE;
+const CAPTURE_PACKET_SIZE: usize = (MAX_SAMPLE_RATE_HZ / 1000 + 1) * CAPTURE_CHANNELS.len() * SAMPLE_SIZE;
+
+// Feedback is provided in 10.14 format for full-speed endpoints.
+const FEEDBACK_SHIFT: usize = 14;
+
+struct Disconnected {}
+
+impl From<EndpointError> for Disconnected {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            EndpointError::Disabled => Disconnected {},
+        }
+    }
+}
+
+/// Receives audio samples from the host.
+async fn playback_handler(stream: &mut PlaybackStream<'static, UsbDriver>) -> Result<(), Disconnected> {
+    let mut packet = [0u8; PLAYBACK_PACKET_SIZE];
+    loop {
+        let _size = stream.read_packet(&mut packet).await?;
+        // Use the samples, for example play back via the SAI peripheral.
+    }
+}
+
+/// Sends sample rate feedback to the host, once per frame.
+///
+/// The feedback value is the number of samples per frame that this device plays back. This example does not have an
+/// audio clock, so it reports the nominal rate. A real device measures the rate of its audio clock (for example the
+/// MCLK of the SAI peripheral) against the USB frames, for example with a timer captured on every start-of-frame, or by
+/// counting played samples between two values of `Feedback::frame_number`.
+async fn feedback_handler(
+    feedback: &mut Feedback<'static, UsbDriver>,
+    control_monitor: &ControlMonitor<'static>,
+) -> Result<(), Disconnected> {
+    loop {
+        let samples_per_frame = (control_monitor.sample_rate_hz() << FEEDBACK_SHIFT) / 1000;
+        feedback.write_full_speed(samples_per_frame).await?;
+    }
+}
+
+/// Sends audio samples to the host, one packet per frame.
+async fn capture_handler(
+    stream: &mut CaptureStream<'static, UsbDriver>,
+    control_monitor: &ControlMonitor<'static>,
+) -> Result<(), Disconnected> {
+    // Silence. Record samples, for example via the SAI peripheral.
+    let packet = [0u8; CAPTURE_PACKET_SIZE];
+    loop {
+        let samples_per_frame = control_monitor.sample_rate_hz() as usize / 1000;
+        stream
+            .write_packet(&packet[..samples_per_frame * CAPTURE_CHANNELS.len() * SAMPLE_SIZE])
+            .await?;
+    }
+}
+
+/// Checks for changes on the control monitor of the class.
+async fn control_handler(control_monitor: &ControlMonitor<'static>) {
+    loop {
+        control_monitor.changed().await;
+
+        info!("Sample rate is {} Hz.", control_monitor.sample_rate_hz());
+        for channel in PLAYBACK_CHANNELS {
+            let volume = control_monitor.playback_volume(channel).unwrap();
+            info!("Headphones volume is {} on channel {}.", volume, channel);
+        }
+        for channel in CAPTURE_CHANNELS {
+            let volume = control_monitor.capture_volume(channel).unwrap();
+            info!("Microphone volume is {} on channel {}.", volume, channel);
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn usb_task(mut usb_device: embassy_usb::UsbDevice<'static, UsbDriver>) {
+    usb_device.run().await;
+}
+
+// If you are trying this and your USB device doesn't connect, the most
+// common issues are the RCC config and vbus_detection
+//
+// See https://embassy.dev/book/#_the_usb_examples_are_not_working_on_my_board_is_there_anything_else_i_need_to_configure
+// for more information.
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Hello World!");
+
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz(8_000_000),
+            mode: HseMode::Bypass,
+        });
+        config.rcc.pll_src = PllSource::HSE;
+        config.rcc.pll = Some(Pll {
+            prediv: PllPreDiv::DIV4,
+            mul: PllMul::MUL168,
+            divp: Some(PllPDiv::DIV2), // ((8 MHz / 4) * 168) / 2 = 168 Mhz.
+            divq: Some(PllQDiv::DIV7), // ((8 MHz / 4) * 168) / 7 = 48 Mhz.
+            divr: None,
+        });
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV4;
+        config.rcc.apb2_pre = APBPrescaler::DIV2;
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.mux.clk48sel = mux::Clk48sel::PLL1_Q;
+    }
+    let p = embassy_stm32::init(config);
+
+    // Configure all required buffers in a static way.
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 512]> = StaticCell::new();
+    let config_descriptor = CONFIG_DESCRIPTOR.init([0; 512]);
+
+    static BOS_DESCRIPTOR: StaticCell<[u8; 32]> = StaticCell::new();
+    let bos_descriptor = BOS_DESCRIPTOR.init([0; 32]);
+
+    const CONTROL_BUF_SIZE: usize = 64;
+    static CONTROL_BUF: StaticCell<[u8; CONTROL_BUF_SIZE]> = StaticCell::new();
+    let control_buf = CONTROL_BUF.init([0; CONTROL_BUF_SIZE]);
+
+    static EP_OUT_BUFFER: StaticCell<[u8; CONTROL_BUF_SIZE + PLAYBACK_PACKET_SIZE]> = StaticCell::new();
+    let ep_out_buffer = EP_OUT_BUFFER.init([0u8; CONTROL_BUF_SIZE + PLAYBACK_PACKET_SIZE]);
+
+    static STATE: StaticCell<uac2::State> = StaticCell::new();
+    let state = STATE.init(uac2::State::new());
+
+    // Create the driver, from the HAL.
+    let mut usb_config = usb::Config::default();
+
+    // Do not enable vbus_detection. This is a safe default that works in all boards.
+    // However, if your USB device is self-powered (can stay powered on if USB is unplugged), you need
+    // to enable vbus_detection to comply with the USB spec. If you enable it, the board
+    // has to support it or USB won't work at all. See docs on `vbus_detection` for details.
+    usb_config.vbus_detection = false;
+
+    let usb_driver = usb::Driver::new_fs(p.USB_OTG_FS, Irqs, p.PA12, p.PA11, ep_out_buffer, usb_config);
+
+    // Basic USB device configuration
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB-audio-headset example");
+    config.serial_number = Some("12345678");
+
+    let mut builder = embassy_usb::Builder::new(
+        usb_driver,
+        config,
+        config_descriptor,
+        bos_descriptor,
+        &mut [], // no msos descriptors
+        control_buf,
+    );
+
+    // Create the UAC2 Headset class components
+    let (mut playback, mut feedback, mut capture, control_monitor) = Headset::new(
+        &mut builder,
+        state,
+        &SAMPLE_RATES_HZ,
+        StreamConfig {
+            channels: &PLAYBACK_CHANNELS,
+            resolution: SAMPLE_WIDTH,
+            max_packet_size: PLAYBACK_PACKET_SIZE as u16,
+        },
+        StreamConfig {
+            channels: &CAPTURE_CHANNELS,
+            resolution: SAMPLE_WIDTH,
+            max_packet_size: CAPTURE_PACKET_SIZE as u16,
+        },
+    );
+
+    // Create the USB device
+    let usb_device = builder.build();
+    unwrap!(spawner.spawn(usb_task(usb_device)));
+
+    let playback_fut = async {
+        loop {
+            playback.wait_connection().await;
+            _ = playback_handler(&mut playback).await;
+        }
+    };
+    let feedback_fut = async {
+        loop {
+            feedback.wait_connection().await;
+            _ = feedback_handler(&mut feedback, &control_monitor).await;
+        }
+    };
+    let capture_fut = async {
+        loop {
+            capture.wait_connection().await;
+            _ = capture_handler(&mut capture, &control_monitor).await;
+        }
+    };
+
+    join4(
+        playback_fut,
+        feedback_fut,
+        capture_fut,
+        control_handler(&control_monitor),
+    )
+    .await;
+}
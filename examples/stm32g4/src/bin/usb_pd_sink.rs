@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_stm32::ucpd::{self, CcPhy, CcPull, CcSel, CcVState, Ucpd};
+use embassy_stm32::{bind_interrupts, peripherals, Config};
+use embassy_time::{with_timeout, Duration};
+use embassy_usb::pd::message::{PowerDataObject, Request, SourceCapabilities};
+use embassy_usb::pd::{DevicePolicyManager, Sink};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    UCPD1 => ucpd::InterruptHandler<peripherals::UCPD1>;
+});
+
+/// Returns the CC line of the source, once attached.
+async fn wait_attached<T: ucpd::Instance>(cc_phy: &mut CcPhy<'_, T>) -> CcSel {
+    loop {
+        let (cc1, cc2) = cc_phy.vstate();
+        if cc1 == CcVState::LOWEST && cc2 == CcVState::LOWEST {
+            // Detached, wait until attached by monitoring the CC lines.
+            cc_phy.wait_for_vstate_change().await;
+            continue;
+        }
+
+        // Attached, wait for CC lines to be stable for tCCDebounce (100..200ms).
+        if with_timeout(Duration::from_millis(100), cc_phy.wait_for_vstate_change())
+            .await
+            .is_ok()
+        {
+            // State has changed, restart detection procedure.
+            continue;
+        };
+
+        match (cc1, cc2) {
+            (_, CcVState::LOWEST) => return CcSel::CC1,
+            (CcVState::LOWEST, _) => return CcSel::CC2,
+            _ => warn!("No PD communication in debug accessory mode"),
+        }
+    }
+}
+
+/// Requests 9 V, falling back to 5 V.
+struct Policy;
+
+impl DevicePolicyManager for Policy {
+    fn request(&mut self, capabilities: &SourceCapabilities) -> Request {
+        for (position, pdo) in capabilities.iter() {
+            info!("Source PDO {}: {}", position, pdo);
+        }
+        match capabilities.fixed(9000) {
+            Some((position, supply)) => Request::fixed(position, supply.max_current_ma),
+            None => match capabilities.get(1) {
+                Some(PowerDataObject::Fixed(supply)) => Request::fixed(1, supply.max_current_ma),
+                _ => Request::fixed(1, 100).with_capability_mismatch(),
+            },
+        }
+    }
+
+    async fn transition_power(&mut self, request: &Request) {
+        info!("Power ready: supply {}", request.object_position());
+    }
+
+    fn hard_reset(&mut self) {
+        info!("Hard reset, back to 5 V");
+    }
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut config = Config::default();
+    config.enable_ucpd1_dead_battery = true;
+    let p = embassy_stm32::init(config);
+
+    let mut ucpd = Ucpd::new(p.UCPD1, Irqs {}, p.PB6, p.PB4, Default::default());
+    ucpd.cc_phy().set_pull(CcPull::Sink);
+
+    info!("Waiting for USB connection...");
+    let cc_sel = wait_attached(ucpd.cc_phy()).await;
+    info!("USB cable connected on {}", cc_sel);
+
+    let (_cc_phy, pd_phy) = ucpd.split_pd_phy(p.DMA1_CH1, p.DMA1_CH2, cc_sel);
+    let mut sink = Sink::new(pd_phy);
+    let error = sink.run(&mut Policy).await;
+    warn!("USB PD failed: {}, drawing USB default power", error);
+}